@@ -0,0 +1,35 @@
+//! Embeds the initial ramdisk and the kernel command line into the image.
+//!
+//! The bootloader can't pass either to the kernel, so they're taken from the `SKYOS_INITRD`
+//! (path to an archive) and `SKYOS_CMDLINE` environment variables at build time.
+//!
+//! Booting through Multiboot2 or Limine also needs the kernel linked with their linker script
+//! from `link/`, see src/boot.
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    println!("cargo:rerun-if-env-changed=SKYOS_INITRD");
+    println!("cargo:rerun-if-env-changed=SKYOS_CMDLINE");
+
+    let initrd = match env::var_os("SKYOS_INITRD") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+            fs::read(&path).expect("can't read SKYOS_INITRD")
+        }
+        None => Vec::new(),
+    };
+    fs::write(out_dir.join("initrd"), initrd).unwrap();
+
+    let cmdline = env::var("SKYOS_CMDLINE").unwrap_or_default();
+    fs::write(out_dir.join("cmdline"), cmdline).unwrap();
+
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    for (feature, script) in [("MULTIBOOT2", "multiboot2.ld"), ("LIMINE", "limine.ld")] {
+        if env::var_os(format!("CARGO_FEATURE_{}", feature)).is_some() {
+            let script = manifest_dir.join("link").join(script);
+            println!("cargo:rerun-if-changed={}", script.display());
+            println!("cargo:rustc-link-arg-bins=-T{}", script.display());
+        }
+    }
+}
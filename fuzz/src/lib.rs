@@ -0,0 +1,152 @@
+//! Fuzzes the ext2 driver of the kernel on the host.
+//!
+//! `src/ext` only reaches the rest of the kernel through `clock`, `config`, `fault` and `sync`.
+//! This crate builds it with `std`, with stand-ins for those modules, over the `MemDisk` of the
+//! kernel's ramdisk driver. `run` mounts an image and runs a script of operations on it, so
+//! broken superblocks, directories and block pointers show up as panics, hangs or overflows
+//! instead of taking down a kernel.
+//!
+//! The input is one byte with the length of the script, the script and then the image. Each
+//! byte of the script is an operation: the low 3 bits pick what to do, the others which path
+//! to do it on (see `step`). A good seed is a zero byte followed by `tests/ext2.img`:
+//!
+//! ```text
+//! cd fuzz
+//! mkdir -p corpus/ext2 && (printf '\0'; cat ../tests/ext2.img) > corpus/ext2/seed
+//! cargo +nightly fuzz run ext2
+//! ```
+#![feature(custom_test_frameworks)]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[path = "../../src/ext/mod.rs"]
+pub mod ext;
+#[path = "../../src/fault.rs"]
+pub mod fault;
+#[path = "../../src/drivers/ramdisk.rs"]
+pub mod ramdisk;
+
+use ext::{Ext2, RWS};
+use ramdisk::MemDisk;
+
+/// The wall clock stands still.
+pub mod clock {
+    pub fn unix_seconds() -> u32 {
+        0
+    }
+}
+
+/// The defaults of the kernel's `config`.
+pub mod config {
+    pub const READAHEAD_MIN: u64 = 16 * 1024;
+
+    pub struct Config {
+        pub readahead_cache_size: usize,
+        pub readahead_max: u64,
+    }
+
+    static CONFIG: Config = Config {
+        readahead_cache_size: 512 * 1024,
+        readahead_max: 128 * 1024,
+    };
+
+    pub fn get() -> &'static Config {
+        &CONFIG
+    }
+}
+
+/// Only one thread runs the file system, so nobody waits and there are no interrupts.
+pub mod sync {
+    pub struct WaitQueue;
+
+    impl WaitQueue {
+        pub const fn new() -> Self {
+            Self
+        }
+
+        /// A conflicting lock would never be released, fail loudly instead of hanging.
+        pub fn wait_until<F: FnMut() -> bool>(&self, mut cond: F) {
+            assert!(cond(), "waiting for a lock nobody can release");
+        }
+
+        pub fn wake_all(&self) {}
+    }
+
+    pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+        f()
+    }
+}
+
+/// Paths the script works on, besides the ones found in the root directory.
+const PATHS: [&str; 6] = ["/a", "/b", "/d", "/d/a", "/hello.txt", "/lost+found"];
+/// Reads of a file stop after this many bytes, broken sizes can be huge.
+const MAX_READ: usize = 1 << 20;
+
+/// Mounts the image in `data` and runs the script in front of it, see the crate docs.
+pub fn run(data: &[u8]) {
+    let Some((&len, rest)) = data.split_first() else {
+        return;
+    };
+    let (script, image) = rest.split_at((len as usize).min(rest.len()));
+    let Ok(mut ext2) = Ext2::new(MemDisk::new(image.to_vec())) else {
+        return;
+    };
+    for &op in script {
+        step(&mut ext2, op);
+    }
+    let _ = ext2.sync();
+}
+
+/// Runs one operation of the script. Errors are fine, only panics and hangs are bugs.
+fn step(ext2: &mut Ext2<MemDisk>, op: u8) {
+    let mut paths: Vec<String> = PATHS.iter().map(|path| String::from(*path)).collect();
+    if let Ok(entries) = ext2.read_dir("/") {
+        paths.extend(entries.iter().map(|entry| format!("/{}", entry.name())));
+    }
+    let target = (op >> 3) as usize;
+    let path = paths[target % paths.len()].clone();
+    let other = paths[(target + 1) % paths.len()].clone();
+    match op & 7 {
+        0 => {
+            let _ = ext2.read_dir(path);
+        }
+        1 => {
+            let Ok(mut file) = ext2.open(path) else {
+                return;
+            };
+            let mut buf = [0; 4096];
+            let mut total = 0;
+            while total < MAX_READ {
+                match file.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => total += read as usize,
+                }
+            }
+        }
+        2 => {
+            if let Ok(mut file) = ext2.create(path) {
+                let data: Vec<u8> = (0..(target + 1) * 331).map(|i| i as u8).collect();
+                let _ = file.write(&data);
+            }
+        }
+        3 => {
+            let _ = ext2.create_dir(path);
+        }
+        4 => {
+            let _ = ext2.remove_file(path);
+        }
+        5 => {
+            let _ = ext2.remove_dir(path);
+        }
+        6 => {
+            let _ = ext2.rename(path, other);
+        }
+        _ => {
+            let _ = ext2.stat(path);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Minimal ACPI table lookup.
+//!
+//! Only finds tables, nothing is interpreted here. The RSDP is searched in the first KiB of the
+//! EBDA and in the BIOS area at 0xE0000-0xFFFFF, tables are then found through the XSDT (or the
+//! RSDT on ACPI 1.0). Requires the physical memory mapping set up by `mem::init`.
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::PhysAddr;
+
+use crate::mem;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// FADT field offsets
+const FADT_DSDT: usize = 40;
+const FADT_X_DSDT: usize = 140;
+/// Size of the header every system description table starts with.
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// The header shared by all system description tables.
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    /// size of the whole table, header included
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+}
+
+/// A table found through the RSDT/XSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub address: PhysAddr,
+    pub header: SdtHeader,
+}
+
+impl Table {
+    /// Reads a value at `offset` bytes into the table, `None` if it is outside of the table.
+    pub fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + core::mem::size_of::<T>() > self.header.length as usize {
+            return None;
+        }
+        read_phys(self.address + offset as u64)
+    }
+
+    /// Copies the whole table, `None` if part of it isn't mapped.
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        let len = self.header.length as u64;
+        let mut bytes = Vec::with_capacity(len as usize);
+        let mut addr = self.address;
+        let end = self.address + len;
+        while addr < end {
+            let page_end = (addr + 1u64).align_up(mem::PAGE_SIZE as u64).min(end);
+            let virt = mem::phys_to_mapped_virt(addr)?;
+            let chunk = (page_end - addr) as usize;
+            bytes.extend_from_slice(unsafe { core::slice::from_raw_parts(virt.as_ptr(), chunk) });
+            addr = page_end;
+        }
+        Some(bytes)
+    }
+}
+
+/// Reads a value from physical memory, `None` if the memory isn't mapped.
+fn read_phys<T: Copy>(addr: PhysAddr) -> Option<T> {
+    let end = addr + (core::mem::size_of::<T>() as u64 - 1);
+    // the value could cross into an unmapped page
+    mem::phys_to_mapped_virt(end)?;
+    let virt = mem::phys_to_mapped_virt(addr)?;
+    Some(unsafe { ptr::read_unaligned(virt.as_ptr::<T>()) })
+}
+
+fn checksum_ok(addr: PhysAddr, len: usize) -> bool {
+    let mut sum = 0u8;
+    for i in 0..len as u64 {
+        match read_phys::<u8>(addr + i) {
+            Some(byte) => sum = sum.wrapping_add(byte),
+            None => return false,
+        }
+    }
+    sum == 0
+}
+
+fn read_header(addr: PhysAddr) -> Option<SdtHeader> {
+    let header = SdtHeader {
+        signature: read_phys(addr)?,
+        length: read_phys(addr + 4u64)?,
+        revision: read_phys(addr + 8u64)?,
+        oem_id: read_phys(addr + 10u64)?,
+    };
+    if (header.length as usize) < SDT_HEADER_SIZE || !checksum_ok(addr, header.length as usize) {
+        return None;
+    }
+    Some(header)
+}
+
+/// Searches `range` for the RSDP, which is always 16 byte aligned.
+fn search_rsdp(range: core::ops::Range<u64>) -> Option<PhysAddr> {
+    range.step_by(16).map(PhysAddr::new).find(|addr| {
+        read_phys::<[u8; 8]>(*addr) == Some(*RSDP_SIGNATURE) && checksum_ok(*addr, 20)
+    })
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    // the BIOS data area holds the EBDA segment at 0x40E
+    let ebda = read_phys::<u16>(PhysAddr::new(0x40e)).map(|segment| (segment as u64) << 4);
+    if let Some(ebda) = ebda.filter(|ebda| *ebda != 0) {
+        if let Some(rsdp) = search_rsdp(ebda..ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    search_rsdp(0xe0000..0x100000)
+}
+
+/// Returns the physical address of the RSDT or XSDT and the size of its entries.
+fn find_root_table() -> Option<(PhysAddr, u64)> {
+    let rsdp = find_rsdp()?;
+    let revision: u8 = read_phys(rsdp + 15u64)?;
+    if revision >= 2 {
+        let xsdt: u64 = read_phys(rsdp + 24u64)?;
+        if xsdt != 0 {
+            return Some((PhysAddr::new(xsdt), 8));
+        }
+    }
+    let rsdt: u32 = read_phys(rsdp + 16u64)?;
+    Some((PhysAddr::new(rsdt as u64), 4))
+}
+
+/// Returns the table at `address`, for tables pointed to by other tables like the DSDT.
+pub fn table_at(address: PhysAddr) -> Option<Table> {
+    let header = read_header(address)?;
+    Some(Table { address, header })
+}
+
+/// Every table listed in the RSDT/XSDT.
+fn tables() -> impl Iterator<Item = Table> {
+    let root = find_root_table().and_then(|(root, entry_size)| {
+        let header = read_header(root)?;
+        Some((root, entry_size, header.length as u64))
+    });
+    let (root, entry_size, length) = root.unwrap_or((PhysAddr::zero(), 1, 0));
+    let entries = length.saturating_sub(SDT_HEADER_SIZE as u64) / entry_size;
+    (0..entries).filter_map(move |i| {
+        let entry = root + SDT_HEADER_SIZE as u64 + i * entry_size;
+        let address = match entry_size {
+            8 => read_phys::<u64>(entry)?,
+            _ => read_phys::<u32>(entry)? as u64,
+        };
+        table_at(PhysAddr::new(address))
+    })
+}
+
+/// Finds the table with the given signature, e.g. `b"MCFG"`.
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    tables().find(|table| &table.header.signature == signature)
+}
+
+/// Finds every table with the given signature, for tables that can appear more than once like
+/// the SSDTs.
+pub fn find_tables(signature: &[u8; 4]) -> impl Iterator<Item = Table> + '_ {
+    tables().filter(move |table| &table.header.signature == signature)
+}
+
+/// The DSDT, found through the FADT.
+pub fn dsdt() -> Option<Table> {
+    let fadt = find_table(b"FACP")?;
+    let address = match fadt.read::<u64>(FADT_X_DSDT) {
+        Some(address) if address != 0 => address,
+        _ => fadt.read::<u32>(FADT_DSDT)? as u64,
+    };
+    table_at(PhysAddr::new(address))
+}
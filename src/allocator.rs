@@ -1,18 +1,26 @@
 use x86_64::{structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB}, VirtAddr};
 use linked_list_allocator::LockedHeap;
 
-use crate::mem::PAGE_SIZE;
+use crate::{config, memprotect};
+
+#[cfg(feature = "heap-tracking")]
+pub mod tracking;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 25 * PAGE_SIZE; // 100 KiB
+
+/// Bytes mapped for the heap, see `config::HEAP_SIZE`.
+pub fn heap_size() -> usize {
+    config::get().heap_size
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    let heap_size = heap_size();
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + heap_size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -20,18 +28,39 @@ pub fn init_heap(
 
     for page in page_range {
         let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | memprotect::no_execute();
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
         }
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        heap().lock().init(HEAP_START as *mut u8, heap_size);
     }
 
     Ok(())
 }
 
+/// Returns the number of used and free bytes on the heap.
+pub fn usage() -> (usize, usize) {
+    let heap = heap().lock();
+    (heap.used(), heap.free())
+}
+
+#[cfg(not(feature = "heap-tracking"))]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
\ No newline at end of file
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+#[cfg(feature = "heap-tracking")]
+#[global_allocator]
+static ALLOCATOR: tracking::TrackingHeap = tracking::TrackingHeap::empty();
+
+#[cfg(not(feature = "heap-tracking"))]
+fn heap() -> &'static LockedHeap {
+    &ALLOCATOR
+}
+
+#[cfg(feature = "heap-tracking")]
+fn heap() -> &'static LockedHeap {
+    ALLOCATOR.heap()
+}
\ No newline at end of file
@@ -0,0 +1,187 @@
+//! Allocation call site tracking, enabled with the `heap-tracking` feature.
+//!
+//! Every allocation gets a small header remembering the call site it was made from, so frees can
+//! be accounted to the same site. Call sites are found by walking the stack up to the first
+//! frame outside the allocator and the `alloc`/`core` crates, which needs the symbol table;
+//! without it a fixed number of frames is skipped instead.
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use linked_list_allocator::LockedHeap;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::ksyms;
+
+/// Maximum number of distinct call sites, allocations from further sites are counted as untracked.
+pub const MAX_SITES: usize = 512;
+const UNTRACKED: u64 = u64::MAX;
+const HEADER_SIZE: usize = 16;
+/// Frames skipped to find the caller when there's no symbol table.
+const FALLBACK_DEPTH: usize = 3;
+
+const INTERNAL_PREFIXES: &[&str] = &[
+    "skyos::allocator",
+    "<skyos::allocator",
+    "alloc::",
+    "<alloc::",
+    "core::",
+    "<core::",
+    "__rust",
+    "__rg_",
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    /// return address of the allocating call
+    pub addr: u64,
+    pub live: usize,
+    pub live_bytes: usize,
+    /// number of allocations ever made from this site
+    pub total: usize,
+}
+
+struct Sites {
+    sites: [Site; MAX_SITES],
+    used: usize,
+    untracked_live: usize,
+    untracked_bytes: usize,
+}
+
+impl Sites {
+    fn record(&mut self, addr: u64, size: usize) -> u64 {
+        let index = match self.sites[..self.used].iter().position(|site| site.addr == addr) {
+            Some(index) => index,
+            None if self.used < MAX_SITES => {
+                self.sites[self.used].addr = addr;
+                self.used += 1;
+                self.used - 1
+            }
+            None => {
+                self.untracked_live += 1;
+                self.untracked_bytes += size;
+                return UNTRACKED;
+            }
+        };
+        let site = &mut self.sites[index];
+        site.live += 1;
+        site.live_bytes += size;
+        site.total += 1;
+        index as u64
+    }
+
+    fn release(&mut self, index: u64, size: usize) {
+        let (live, bytes) = match self.sites.get_mut(index as usize) {
+            Some(site) => (&mut site.live, &mut site.live_bytes),
+            None => (&mut self.untracked_live, &mut self.untracked_bytes),
+        };
+        *live = live.saturating_sub(1);
+        *bytes = bytes.saturating_sub(size);
+    }
+}
+
+static SITES: Mutex<Sites> = Mutex::new(Sites {
+    sites: [Site {
+        addr: 0,
+        live: 0,
+        live_bytes: 0,
+        total: 0,
+    }; MAX_SITES],
+    used: 0,
+    untracked_live: 0,
+    untracked_bytes: 0,
+});
+
+fn is_internal(name: &str) -> bool {
+    INTERNAL_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+fn call_site() -> u64 {
+    let mut site = None;
+    let mut fallback = 0;
+    let mut depth = 0;
+    ksyms::walk_stack(|addr| {
+        if site.is_some() {
+            return;
+        }
+        depth += 1;
+        if depth == FALLBACK_DEPTH {
+            fallback = addr;
+        }
+        if matches!(ksyms::resolve(addr), Some(symbol) if !is_internal(symbol.name())) {
+            site = Some(addr);
+        }
+    });
+    site.unwrap_or(fallback)
+}
+
+fn header_size(layout: &Layout) -> usize {
+    layout.align().max(HEADER_SIZE)
+}
+
+/// `LockedHeap` that records the call site of every allocation.
+pub struct TrackingHeap {
+    heap: LockedHeap,
+}
+
+impl TrackingHeap {
+    pub const fn empty() -> Self {
+        Self {
+            heap: LockedHeap::empty(),
+        }
+    }
+
+    pub fn heap(&self) -> &LockedHeap {
+        &self.heap
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let header = header_size(&layout);
+        let Ok(full) = Layout::from_size_align(layout.size() + header, layout.align()) else {
+            return null_mut();
+        };
+        let base = self.heap.alloc(full);
+        if base.is_null() {
+            return base;
+        }
+        let site = call_site();
+        let index = without_interrupts(|| SITES.lock().record(site, layout.size()));
+        let ptr = base.add(header);
+        (ptr.sub(8) as *mut u64).write(index);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = header_size(&layout);
+        let index = (ptr.sub(8) as *const u64).read();
+        without_interrupts(|| SITES.lock().release(index, layout.size()));
+        let full = Layout::from_size_align_unchecked(layout.size() + header, layout.align());
+        self.heap.dealloc(ptr.sub(header), full);
+    }
+}
+
+/// Allocation statistics of all call sites.
+pub struct Report {
+    /// sites with live allocations, most live bytes first
+    pub sites: Vec<Site>,
+    pub untracked_live: usize,
+    pub untracked_bytes: usize,
+}
+
+pub fn report() -> Report {
+    // allocate up front, allocating while holding the lock would deadlock
+    let mut sites = Vec::with_capacity(MAX_SITES);
+    let (untracked_live, untracked_bytes) = without_interrupts(|| {
+        let table = SITES.lock();
+        sites.extend(table.sites[..table.used].iter().filter(|site| site.live > 0));
+        (table.untracked_live, table.untracked_bytes)
+    });
+    sites.sort_by(|a: &Site, b: &Site| b.live_bytes.cmp(&a.live_bytes));
+    Report {
+        sites,
+        untracked_live,
+        untracked_bytes,
+    }
+}
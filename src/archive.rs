@@ -0,0 +1,285 @@
+//! Reading cpio (newc) and ustar archives.
+//!
+//! Archives are read from any `RWS` source. Entries can be listed or extracted into a
+//! `FileSystem`.
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::{
+    ext::{Errno, RWS},
+    vfs::{self, FileSystem, VfsResult},
+};
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: u64 = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const TAR_BLOCK_SIZE: u64 = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Cpio,
+    Ustar,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// symbolic link to the contained path
+    Symlink(String),
+    /// devices, fifos and hard links, which are skipped on extraction
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// normalized absolute path inside the archive
+    pub path: String,
+    pub kind: EntryKind,
+    /// permission bits
+    pub mode: u32,
+    pub size: u64,
+    /// position of the data in the source
+    offset: u64,
+}
+
+pub struct Archive<R: RWS> {
+    source: R,
+    format: Format,
+    pos: u64,
+    done: bool,
+}
+
+fn read_exact<R: RWS>(source: &mut R, mut addr: u64, mut buf: &mut [u8]) -> VfsResult<()> {
+    while !buf.is_empty() {
+        let read = source.read_at(addr, buf)? as usize;
+        if read == 0 {
+            return Err(Errno::InvalidFileImage);
+        }
+        addr += read as u64;
+        buf = &mut buf[read..];
+    }
+    Ok(())
+}
+
+/// Reads `len` bytes at `addr`. The sizes come from the headers, so the last byte is read
+/// first: a broken or truncated archive fails instead of making the kernel allocate more than
+/// the archive holds.
+fn read_buffer<R: RWS>(source: &mut R, addr: u64, len: u64) -> VfsResult<Vec<u8>> {
+    if len > 0 {
+        let last = addr.checked_add(len - 1).ok_or(Errno::InvalidFileImage)?;
+        read_exact(source, last, &mut [0])?;
+    }
+    let mut buf = vec![0u8; usize::try_from(len).map_err(|_| Errno::InvalidFileImage)?];
+    read_exact(source, addr, &mut buf)?;
+    Ok(buf)
+}
+
+fn parse_number(field: &[u8], radix: u32) -> VfsResult<u64> {
+    let field = core::str::from_utf8(field).map_err(|_| Errno::InvalidFileImage)?;
+    let field = field.trim_matches(|c| c == '\0' || c == ' ');
+    if field.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(field, radix).map_err(|_| Errno::InvalidFileImage)
+}
+
+/// Interprets a nul padded header field as a string.
+fn parse_str(field: &[u8]) -> VfsResult<&str> {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| Errno::InvalidFileImage)
+}
+
+fn archive_path(name: &str) -> VfsResult<String> {
+    let mut path = String::from("/");
+    path.push_str(name.trim_start_matches("./").trim_start_matches('/'));
+    vfs::normalize(&path)
+}
+
+fn align(n: u64, to: u64) -> u64 {
+    (n + to - 1) / to * to
+}
+
+impl<R: RWS> Archive<R> {
+    /// Detects the format of the archive at the start of `source`.
+    pub fn new(mut source: R) -> VfsResult<Self> {
+        let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+        read_exact(&mut source, 0, &mut header[..CPIO_HEADER_SIZE as usize])?;
+        let format = if &header[..6] == CPIO_MAGIC {
+            Format::Cpio
+        } else {
+            read_exact(&mut source, 0, &mut header)?;
+            if &header[257..262] != TAR_MAGIC {
+                return Err(Errno::InvalidFileImage);
+            }
+            Format::Ustar
+        };
+        Ok(Self {
+            source,
+            format,
+            pos: 0,
+            done: false,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the next entry, `None` after the end of the archive.
+    pub fn next_entry(&mut self) -> VfsResult<Option<Entry>> {
+        while !self.done {
+            let entry = match self.format {
+                Format::Cpio => self.next_cpio()?,
+                Format::Ustar => self.next_ustar()?,
+            };
+            // the archive root itself isn't interesting
+            match entry {
+                Some(entry) if entry.path == "/" => continue,
+                entry => return Ok(entry),
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_cpio(&mut self) -> VfsResult<Option<Entry>> {
+        let mut header = [0u8; CPIO_HEADER_SIZE as usize];
+        read_exact(&mut self.source, self.pos, &mut header)?;
+        if &header[..6] != CPIO_MAGIC {
+            return Err(Errno::InvalidFileImage);
+        }
+        let field = |index: usize| parse_number(&header[6 + index * 8..6 + (index + 1) * 8], 16);
+        let mode = field(1)? as u32;
+        let size = field(6)?;
+        let name_size = field(11)? as usize;
+
+        let name = read_buffer(
+            &mut self.source,
+            self.pos + CPIO_HEADER_SIZE,
+            name_size as u64,
+        )?;
+        let name = String::from(parse_str(&name)?);
+        let offset = align(self.pos + CPIO_HEADER_SIZE + name_size as u64, 4);
+        self.pos = align(offset + size, 4);
+
+        if name == CPIO_TRAILER {
+            self.done = true;
+            return Ok(None);
+        }
+        let kind = match mode & MODE_TYPE_MASK {
+            MODE_REGULAR => EntryKind::File,
+            MODE_DIRECTORY => EntryKind::Directory,
+            MODE_SYMLINK => {
+                let target = read_buffer(&mut self.source, offset, size)?;
+                EntryKind::Symlink(String::from(parse_str(&target)?))
+            }
+            _ => EntryKind::Other,
+        };
+        Ok(Some(Entry {
+            path: archive_path(&name)?,
+            kind,
+            mode: mode & 0o7777,
+            size,
+            offset,
+        }))
+    }
+
+    fn next_ustar(&mut self) -> VfsResult<Option<Entry>> {
+        let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+        if read_exact(&mut self.source, self.pos, &mut header).is_err() {
+            // some writers leave out the end of archive blocks
+            self.done = true;
+            return Ok(None);
+        }
+        if header.iter().all(|b| *b == 0) {
+            self.done = true;
+            return Ok(None);
+        }
+        if &header[257..262] != TAR_MAGIC {
+            return Err(Errno::InvalidFileImage);
+        }
+        let mode = parse_number(&header[100..108], 8)? as u32;
+        let size = parse_number(&header[124..136], 8)?;
+        let mut name = String::from(parse_str(&header[345..500])?);
+        if !name.is_empty() {
+            name.push('/');
+        }
+        name.push_str(parse_str(&header[..100])?);
+
+        let kind = match header[156] {
+            b'0' | 0 | b'7' => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            b'2' => EntryKind::Symlink(String::from(parse_str(&header[157..257])?)),
+            _ => EntryKind::Other,
+        };
+        let offset = self.pos + TAR_BLOCK_SIZE;
+        // only regular files have data, the size of links is meaningless
+        let data_size = if kind == EntryKind::File { size } else { 0 };
+        self.pos = offset + align(data_size, TAR_BLOCK_SIZE);
+        Ok(Some(Entry {
+            path: archive_path(&name)?,
+            kind,
+            mode: mode & 0o7777,
+            size: data_size,
+            offset,
+        }))
+    }
+
+    /// Reads the contents of a file entry.
+    pub fn read(&mut self, entry: &Entry) -> VfsResult<Vec<u8>> {
+        read_buffer(&mut self.source, entry.offset, entry.size)
+    }
+
+    /// Returns all remaining entries.
+    pub fn entries(&mut self) -> VfsResult<Vec<Entry>> {
+        let mut entries = Vec::new();
+        while let Some(entry) = self.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+/// Creates `path` and every missing directory on the way to it.
+pub fn create_dir_all(fs: &dyn FileSystem, path: &str) -> VfsResult<()> {
+    if path == "/" || fs.metadata(path).is_ok() {
+        return Ok(());
+    }
+    create_dir_all(fs, vfs::split_parent(path).0)?;
+    fs.create_dir(path)
+}
+
+/// Extracts the remaining entries into `dest` on `fs`, returns the number of files and
+/// directories created. Symlinks and special files are skipped.
+pub fn extract<R: RWS>(
+    archive: &mut Archive<R>,
+    fs: &dyn FileSystem,
+    dest: &str,
+) -> VfsResult<usize> {
+    let dest = vfs::normalize(dest)?;
+    let mut count = 0;
+    while let Some(entry) = archive.next_entry()? {
+        let path = if dest == "/" {
+            entry.path.clone()
+        } else {
+            let mut path = dest.clone();
+            path.push_str(&entry.path);
+            path
+        };
+        match entry.kind {
+            EntryKind::Directory => create_dir_all(fs, &path)?,
+            EntryKind::File => {
+                create_dir_all(fs, vfs::split_parent(&path).0)?;
+                let data = archive.read(&entry)?;
+                fs.write(&path, &data)?;
+            }
+            EntryKind::Symlink(_) | EntryKind::Other => continue,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,486 @@
+//! Benchmarks run by the `fsbench` and `blkbench` commands.
+//!
+//! A file system benchmark works in a scratch directory it creates and removes again. A block
+//! device benchmark submits requests straight to the disk, so comparing the two tells the
+//! file system's overhead from the driver's. Every workload times each of its operations with
+//! the TSC and reports the throughput and the latency percentiles, so changes to the caches
+//! and the block allocator can be compared.
+use alloc::{collections::VecDeque, format, string::String, sync::Arc, vec, vec::Vec};
+use core::time::Duration;
+
+use crate::drivers::ahci_driver::{AhciDisk, AhciError, Op, Ticket, MAX_SECTORS, SECTOR_SIZE};
+use crate::tsc;
+use crate::vfs::{FileSystem, VfsResult};
+
+/// Name of the scratch directory.
+pub const SCRATCH_DIR: &str = "fsbench.tmp";
+/// Size of the reads and writes of the sequential workloads.
+pub const SEQUENTIAL_CHUNK: usize = 64 * 1024;
+/// Size of the reads and writes of the random workloads.
+pub const RANDOM_CHUNK: usize = 4096;
+/// Operations of a random workload at most.
+const MAX_RANDOM_OPS: u64 = 4096;
+
+/// Latencies of operations, in TSC cycles.
+#[derive(Debug, Default, Clone)]
+pub struct Latencies(Vec<u64>);
+
+impl Latencies {
+    /// Runs `f`, recording how long it took.
+    pub fn time<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let start = tsc::read();
+        let result = f();
+        self.record(tsc::read() - start);
+        result
+    }
+
+    pub fn record(&mut self, cycles: u64) {
+        self.0.push(cycles);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The latency `percent` percent of the operations didn't exceed, nearest rank.
+    pub fn percentile(&self, percent: u64) -> Duration {
+        let mut cycles = self.0.clone();
+        cycles.sort_unstable();
+        let rank = (cycles.len() as u64 * percent).div_ceil(100).max(1) as usize;
+        cycles
+            .get(rank - 1)
+            .map_or(Duration::ZERO, |&c| to_duration(c))
+    }
+
+    /// Time spent in all operations.
+    pub fn total(&self) -> Duration {
+        to_duration(self.0.iter().sum())
+    }
+
+    /// The number of latencies below 1 us, below 2 us, below 4 us and so on, up to the
+    /// bucket of the longest.
+    pub fn histogram(&self) -> Vec<usize> {
+        let mut buckets = Vec::new();
+        for &cycles in &self.0 {
+            let micros = to_duration(cycles).as_micros() as u64;
+            let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+            if buckets.len() <= bucket {
+                buckets.resize(bucket + 1, 0);
+            }
+            buckets[bucket] += 1;
+        }
+        buckets
+    }
+}
+
+/// Zero while the TSC isn't calibrated.
+fn to_duration(cycles: u64) -> Duration {
+    tsc::cycles_to_duration(cycles).unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    SequentialWrite,
+    SequentialRead,
+    RandomWrite,
+    RandomRead,
+    Create,
+    Delete,
+}
+
+impl Workload {
+    /// In the order they run by default, reads after the writes that fill the file.
+    pub const ALL: [Workload; 6] = [
+        Self::SequentialWrite,
+        Self::SequentialRead,
+        Self::RandomWrite,
+        Self::RandomRead,
+        Self::Create,
+        Self::Delete,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SequentialWrite => "seqwrite",
+            Self::SequentialRead => "seqread",
+            Self::RandomWrite => "randwrite",
+            Self::RandomRead => "randread",
+            Self::Create => "create",
+            Self::Delete => "delete",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|workload| workload.name() == name)
+    }
+}
+
+/// What a workload did.
+#[derive(Debug)]
+pub struct Report {
+    pub name: &'static str,
+    /// bytes read or written
+    pub bytes: u64,
+    /// from the start of the first operation to the end of the last one
+    pub elapsed: Duration,
+    pub latencies: Latencies,
+}
+
+impl Report {
+    pub fn ops_per_sec(&self) -> u64 {
+        per_sec(self.latencies.len() as u64, self.elapsed)
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        per_sec(self.bytes, self.elapsed)
+    }
+}
+
+fn per_sec(count: u64, elapsed: Duration) -> u64 {
+    match elapsed.as_nanos() {
+        0 => 0,
+        nanos => (count as u128 * 1_000_000_000 / nanos) as u64,
+    }
+}
+
+pub struct FsBench {
+    fs: Arc<dyn FileSystem>,
+    /// the scratch directory
+    dir: String,
+    file_size: u64,
+    files: usize,
+    /// xorshift state for the random offsets
+    seed: u64,
+}
+
+impl FsBench {
+    /// Creates the scratch directory in `dir` of `fs`. The sequential and random workloads use
+    /// a file of `file_size` bytes, at least one random chunk, the metadata workloads `files`
+    /// files.
+    pub fn new(
+        fs: Arc<dyn FileSystem>,
+        dir: &str,
+        file_size: u64,
+        files: usize,
+    ) -> VfsResult<Self> {
+        let dir = match dir.trim_end_matches('/') {
+            "" => format!("/{SCRATCH_DIR}"),
+            dir => format!("{dir}/{SCRATCH_DIR}"),
+        };
+        fs.create_dir(&dir)?;
+        Ok(Self {
+            fs,
+            dir,
+            file_size: file_size.max(RANDOM_CHUNK as u64),
+            files,
+            seed: tsc::read() | 1,
+        })
+    }
+
+    fn data_file(&self) -> String {
+        format!("{}/data", self.dir)
+    }
+
+    fn small_file(&self, i: usize) -> String {
+        format!("{}/f{i}", self.dir)
+    }
+
+    pub fn run(&mut self, workload: Workload) -> VfsResult<Report> {
+        let mut latencies = Latencies::default();
+        let bytes = match workload {
+            Workload::SequentialWrite => self.sequential_write(&mut latencies)?,
+            Workload::SequentialRead => self.sequential_read(&mut latencies)?,
+            Workload::RandomWrite => self.random(&mut latencies, true)?,
+            Workload::RandomRead => self.random(&mut latencies, false)?,
+            Workload::Create => self.create(&mut latencies)?,
+            Workload::Delete => self.delete(&mut latencies)?,
+        };
+        Ok(Report {
+            name: workload.name(),
+            bytes,
+            // one operation at a time, setting up a workload doesn't count
+            elapsed: latencies.total(),
+            latencies,
+        })
+    }
+
+    fn sequential_write(&mut self, latencies: &mut Latencies) -> VfsResult<u64> {
+        let path = self.data_file();
+        self.fs.write(&path, &[])?;
+        let chunk = pattern(SEQUENTIAL_CHUNK);
+        let mut offset = 0;
+        while offset < self.file_size {
+            let len = chunk.len().min((self.file_size - offset) as usize);
+            latencies.time(|| self.fs.write_at(&path, offset, &chunk[..len]))?;
+            offset += len as u64;
+        }
+        Ok(offset)
+    }
+
+    /// Writes the data file if an earlier workload didn't, untimed.
+    fn fill(&mut self) -> VfsResult<()> {
+        match self.fs.metadata(&self.data_file()) {
+            Ok(metadata) if metadata.size >= self.file_size => Ok(()),
+            _ => self.sequential_write(&mut Latencies::default()).map(|_| ()),
+        }
+    }
+
+    fn sequential_read(&mut self, latencies: &mut Latencies) -> VfsResult<u64> {
+        self.fill()?;
+        let path = self.data_file();
+        let mut buf = vec![0; SEQUENTIAL_CHUNK];
+        let mut offset = 0;
+        while offset < self.file_size {
+            match latencies.time(|| self.fs.read_at(&path, offset, &mut buf))? {
+                0 => break,
+                read => offset += read as u64,
+            }
+        }
+        Ok(offset)
+    }
+
+    fn random(&mut self, latencies: &mut Latencies, write: bool) -> VfsResult<u64> {
+        self.fill()?;
+        let path = self.data_file();
+        let chunks = self.file_size / RANDOM_CHUNK as u64;
+        let mut buf = pattern(RANDOM_CHUNK);
+        let ops = chunks.min(MAX_RANDOM_OPS);
+        for _ in 0..ops {
+            let offset = next_random(&mut self.seed) % chunks * RANDOM_CHUNK as u64;
+            if write {
+                latencies.time(|| self.fs.write_at(&path, offset, &buf))?;
+            } else {
+                latencies.time(|| self.fs.read_at(&path, offset, &mut buf))?;
+            }
+        }
+        Ok(ops * RANDOM_CHUNK as u64)
+    }
+
+    fn create(&mut self, latencies: &mut Latencies) -> VfsResult<u64> {
+        for i in 0..self.files {
+            let path = self.small_file(i);
+            latencies.time(|| self.fs.write(&path, &[]))?;
+        }
+        Ok(0)
+    }
+
+    fn delete(&mut self, latencies: &mut Latencies) -> VfsResult<u64> {
+        for i in 0..self.files {
+            let path = self.small_file(i);
+            if self.fs.metadata(&path).is_err() {
+                self.fs.write(&path, &[])?;
+            }
+        }
+        for i in 0..self.files {
+            let path = self.small_file(i);
+            latencies.time(|| self.fs.remove(&path))?;
+        }
+        Ok(0)
+    }
+
+    /// Removes the scratch directory and everything in it.
+    pub fn finish(self) -> VfsResult<()> {
+        for entry in self.fs.read_dir(&self.dir)? {
+            self.fs.remove(&format!("{}/{}", self.dir, entry.name))?;
+        }
+        self.fs.remove(&self.dir)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockWorkload {
+    SequentialRead,
+    RandomRead,
+    SequentialWrite,
+    RandomWrite,
+}
+
+impl BlockWorkload {
+    /// Reads first, the writes put back what they read.
+    pub const ALL: [BlockWorkload; 4] = [
+        Self::SequentialRead,
+        Self::RandomRead,
+        Self::SequentialWrite,
+        Self::RandomWrite,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SequentialRead => "seqread",
+            Self::RandomRead => "randread",
+            Self::SequentialWrite => "seqwrite",
+            Self::RandomWrite => "randwrite",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|workload| workload.name() == name)
+    }
+
+    fn is_write(self) -> bool {
+        matches!(self, Self::SequentialWrite | Self::RandomWrite)
+    }
+
+    fn is_random(self) -> bool {
+        matches!(self, Self::RandomRead | Self::RandomWrite)
+    }
+}
+
+/// Benchmarks a disk through [`AhciDisk::submit`], with no file system or cache in between.
+///
+/// Writes don't destroy anything: every block written is read first, untimed, and written
+/// back as it was. Nothing else should write to the disk meanwhile though.
+pub struct BlockBench {
+    disk: Arc<AhciDisk>,
+    /// sectors per request
+    sectors: usize,
+    requests: usize,
+    /// requests in flight at once
+    depth: usize,
+    seed: u64,
+}
+
+impl BlockBench {
+    /// Requests of `block_size` bytes, a multiple of the sector size up to the largest single
+    /// command, `depth` of them in flight at once, at most as many as the disk queues.
+    pub fn new(
+        disk: Arc<AhciDisk>,
+        block_size: usize,
+        requests: usize,
+        depth: usize,
+    ) -> Result<Self, AhciError> {
+        let sectors = block_size / SECTOR_SIZE;
+        if block_size % SECTOR_SIZE != 0 || !(1..=MAX_SECTORS).contains(&sectors) {
+            return Err(AhciError::InvalidRequest);
+        }
+        if disk.sectors() < sectors as u64 {
+            return Err(AhciError::InvalidRequest);
+        }
+        let depth = depth.clamp(1, disk.queue_depth());
+        Ok(Self {
+            disk,
+            sectors,
+            requests,
+            depth,
+            seed: tsc::read() | 1,
+        })
+    }
+
+    pub fn run(&mut self, workload: BlockWorkload) -> Result<Report, AhciError> {
+        let blocks = self.disk.sectors() / self.sectors as u64;
+        let op = match workload.is_write() {
+            true => Op::Write,
+            false => Op::Read,
+        };
+        let mut latencies = Latencies::default();
+        let mut pending: VecDeque<(Ticket, u64)> = VecDeque::new();
+        let mut result = Ok(());
+        let start = tsc::read();
+        for i in 0..self.requests as u64 {
+            if pending.len() == self.depth {
+                let (ticket, submitted) = pending.pop_front().unwrap();
+                result = self.finish(ticket, submitted, &mut latencies);
+            }
+            let block = match workload.is_random() {
+                true => next_random(&mut self.seed) % blocks,
+                false => i % blocks,
+            };
+            let lba = block * self.sectors as u64;
+            let submitted = result
+                .and_then(|_| self.buffer(op, lba))
+                .and_then(|buffer| {
+                    let submitted = tsc::read();
+                    Ok((self.disk.submit(op, lba, buffer)?, submitted))
+                });
+            match submitted {
+                Ok(ticket) => pending.push_back(ticket),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        // every ticket has to be waited for, even after an error, to free its slot
+        for (ticket, submitted) in pending {
+            result = result.and(self.finish(ticket, submitted, &mut latencies));
+        }
+        let elapsed = to_duration(tsc::read() - start);
+        result?;
+        Ok(Report {
+            name: workload.name(),
+            bytes: (latencies.len() * self.sectors * SECTOR_SIZE) as u64,
+            elapsed,
+            latencies,
+        })
+    }
+
+    /// What a request at `lba` transfers: the sectors there for a write, so the write puts
+    /// them back.
+    fn buffer(&self, op: Op, lba: u64) -> Result<Vec<u8>, AhciError> {
+        let mut buffer = vec![0; self.sectors * SECTOR_SIZE];
+        if op == Op::Write {
+            self.disk.read_sectors(lba, &mut buffer)?;
+        }
+        Ok(buffer)
+    }
+
+    fn finish(
+        &self,
+        ticket: Ticket,
+        submitted: u64,
+        latencies: &mut Latencies,
+    ) -> Result<(), AhciError> {
+        self.disk.wait(ticket)?;
+        latencies.record(tsc::read() - submitted);
+        Ok(())
+    }
+}
+
+fn next_random(seed: &mut u64) -> u64 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    *seed
+}
+
+/// Data that doesn't compress to nothing, in case a file system ever does.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_percentiles() {
+    let latencies = Latencies((1..=100).collect());
+    let cycles = |percent| tsc::cycles_to_duration(percent).unwrap_or_default();
+    assert_eq!(latencies.percentile(50), cycles(50));
+    assert_eq!(latencies.percentile(99), cycles(99));
+    assert_eq!(latencies.percentile(100), cycles(100));
+    assert_eq!(Latencies::default().percentile(50), Duration::ZERO);
+}
+
+#[test_case]
+fn test_histogram() {
+    let frequency = match tsc::frequency() {
+        Some(frequency) => frequency,
+        None => return,
+    };
+    let tenth_micros = |n: u64| n * frequency / 10_000_000;
+    let latencies = Latencies([0, 15, 35, 35, 400].map(tenth_micros).to_vec());
+    assert_eq!(latencies.histogram(), [1, 1, 2, 0, 0, 0, 1]);
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_workload_names() {
+    for workload in Workload::ALL {
+        assert_eq!(Workload::from_name(workload.name()), Some(workload));
+    }
+    assert_eq!(Workload::from_name("seek"), None);
+}
@@ -0,0 +1,217 @@
+//! Booting from Limine.
+//!
+//! Limine loads the kernel at the higher half address it's linked at and starts
+//! `skyos_limine_start` in long mode, with the first 4 GiB identity mapped and all physical
+//! memory mapped at the higher half direct map (HHDM) offset. It finds the requests below by
+//! their magic numbers and fills in their responses before that. The requests don't ask for a
+//! base revision, which keeps the identity mapping the VGA text console uses.
+//!
+//! The kernel has to be built with `-C code-model=kernel -C relocation-model=static` in
+//! `RUSTFLAGS` to run at the address `link/limine.ld` puts it at.
+// the structures mirror the protocol, not every field is read
+#![allow(dead_code)]
+use bootloader::bootinfo::MemoryRegionType;
+use core::cell::UnsafeCell;
+#[cfg(feature = "limine")]
+use x86_64::PhysAddr;
+
+#[cfg(feature = "limine")]
+use super::{BootString, Framebuffer, Handover, Module, Protocol, Regions};
+
+const COMMON_MAGIC: [u64; 2] = [0xc7b1_dd30_df4c_8b88, 0x0a82_e883_a194_f07b];
+
+// memory map entry types
+const MEMORY_USABLE: u64 = 0;
+const MEMORY_ACPI_RECLAIMABLE: u64 = 2;
+const MEMORY_ACPI_NVS: u64 = 3;
+const MEMORY_BAD: u64 = 4;
+const MEMORY_BOOTLOADER_RECLAIMABLE: u64 = 5;
+const MEMORY_KERNEL_AND_MODULES: u64 = 6;
+
+/// A request, with the response Limine writes into it.
+#[repr(C)]
+pub struct Request<T> {
+    id: [u64; 4],
+    revision: u64,
+    response: UnsafeCell<*const T>,
+}
+
+// only written by the loader, before the kernel runs
+unsafe impl<T> Sync for Request<T> {}
+
+impl<T> Request<T> {
+    const fn new(id: [u64; 2]) -> Self {
+        Self {
+            id: [COMMON_MAGIC[0], COMMON_MAGIC[1], id[0], id[1]],
+            revision: 0,
+            response: UnsafeCell::new(core::ptr::null()),
+        }
+    }
+
+    /// Returns the response, if Limine answered the request.
+    pub fn response(&self) -> Option<&'static T> {
+        unsafe { self.response.get().read_volatile().as_ref() }
+    }
+}
+
+#[repr(C)]
+pub struct MemoryMapEntry {
+    pub base: u64,
+    pub length: u64,
+    pub kind: u64,
+}
+
+#[repr(C)]
+pub struct MemoryMapResponse {
+    revision: u64,
+    count: u64,
+    entries: *const *const MemoryMapEntry,
+}
+
+#[repr(C)]
+pub struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+pub struct LimineFramebuffer {
+    address: *const u8,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+}
+
+#[repr(C)]
+pub struct FramebufferResponse {
+    revision: u64,
+    count: u64,
+    framebuffers: *const *const LimineFramebuffer,
+}
+
+#[repr(C)]
+pub struct File {
+    revision: u64,
+    address: *const u8,
+    size: u64,
+    path: *const u8,
+    cmdline: *const u8,
+}
+
+#[repr(C)]
+pub struct ModuleResponse {
+    revision: u64,
+    count: u64,
+    modules: *const *const File,
+}
+
+#[repr(C)]
+pub struct KernelFileResponse {
+    revision: u64,
+    kernel_file: *const File,
+}
+
+#[used]
+#[link_section = ".limine_requests"]
+static MEMORY_MAP: Request<MemoryMapResponse> =
+    Request::new([0x67cf_3d9d_378a_806f, 0xe304_acdf_c50c_3c62]);
+#[used]
+#[link_section = ".limine_requests"]
+static HHDM: Request<HhdmResponse> = Request::new([0x48dc_f1cb_8ad2_b852, 0x6398_4e95_9a98_244b]);
+#[used]
+#[link_section = ".limine_requests"]
+static FRAMEBUFFER: Request<FramebufferResponse> =
+    Request::new([0x9d58_27dc_d881_dd75, 0xa314_8604_f6fa_b11b]);
+#[used]
+#[link_section = ".limine_requests"]
+static MODULES: Request<ModuleResponse> =
+    Request::new([0x3e7e_2797_02be_32af, 0xca1c_4f3b_d128_0cee]);
+#[used]
+#[link_section = ".limine_requests"]
+static KERNEL_FILE: Request<KernelFileResponse> =
+    Request::new([0xad97_e90e_83f1_ed67, 0x31eb_5d1c_5ff2_3b69]);
+
+/// Translates a Limine memory map entry type.
+pub fn region_type(kind: u64) -> MemoryRegionType {
+    match kind {
+        MEMORY_USABLE => MemoryRegionType::Usable,
+        MEMORY_ACPI_RECLAIMABLE => MemoryRegionType::AcpiReclaimable,
+        MEMORY_ACPI_NVS => MemoryRegionType::AcpiNvs,
+        MEMORY_BAD => MemoryRegionType::BadMemory,
+        // Limine's page tables and the stack we're running on
+        MEMORY_BOOTLOADER_RECLAIMABLE => MemoryRegionType::InUse,
+        MEMORY_KERNEL_AND_MODULES => MemoryRegionType::Kernel,
+        _ => MemoryRegionType::Reserved,
+    }
+}
+
+/// Returns the pointers of a response's array.
+unsafe fn array<T: 'static>(ptr: *const *const T, count: u64) -> impl Iterator<Item = &'static T> {
+    (0..count as usize).filter_map(move |i| (*ptr.add(i)).as_ref())
+}
+
+#[cfg(feature = "limine")]
+#[no_mangle]
+extern "C" fn skyos_limine_start() -> ! {
+    let Some(hhdm) = HHDM.response().map(|hhdm| hhdm.offset) else {
+        crate::hlt_loop();
+    };
+    let mut handover = Handover::new(Protocol::Limine);
+    let mut regions = Regions::new();
+    unsafe {
+        if let Some(map) = MEMORY_MAP.response() {
+            for entry in array(map.entries, map.count) {
+                let end = entry.base.saturating_add(entry.length);
+                regions.add(entry.base, end, region_type(entry.kind));
+            }
+        }
+        // everything is HHDM addresses, the kernel file and modules are mapped there
+        if let Some(kernel) = KERNEL_FILE
+            .response()
+            .and_then(|kernel| kernel.kernel_file.as_ref())
+        {
+            handover.cmdline = Some(BootString::from_ptr(kernel.cmdline));
+        }
+        if let Some(modules) = MODULES.response() {
+            for module in array(modules.modules, modules.count) {
+                handover.add_module(Module {
+                    start: PhysAddr::new(module.address as u64 - hhdm),
+                    len: module.size,
+                    name: BootString::from_ptr(module.path),
+                });
+            }
+        }
+        if let Some(framebuffers) = FRAMEBUFFER.response() {
+            handover.framebuffer = array(framebuffers.framebuffers, framebuffers.count)
+                .next()
+                .map(|framebuffer| Framebuffer {
+                    addr: PhysAddr::new(framebuffer.address as u64 - hhdm),
+                    width: framebuffer.width as u32,
+                    height: framebuffer.height as u32,
+                    pitch: framebuffer.pitch as u32,
+                    bpp: framebuffer.bpp as u8,
+                });
+        }
+    }
+    regions.reserve(0, 4096, MemoryRegionType::FrameZero);
+
+    let boot_info = super::finish(handover, regions, hhdm);
+    super::start_kernel(boot_info)
+}
+
+#[test_case]
+fn test_region_types() {
+    assert_eq!(region_type(MEMORY_USABLE), MemoryRegionType::Usable);
+    assert_eq!(
+        region_type(MEMORY_BOOTLOADER_RECLAIMABLE),
+        MemoryRegionType::InUse
+    );
+    assert_eq!(
+        region_type(MEMORY_KERNEL_AND_MODULES),
+        MemoryRegionType::Kernel
+    );
+    // the framebuffer, and anything newer than this
+    assert_eq!(region_type(7), MemoryRegionType::Reserved);
+    assert_eq!(region_type(100), MemoryRegionType::Reserved);
+}
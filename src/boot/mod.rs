@@ -0,0 +1,371 @@
+//! Boot protocols.
+//!
+//! The kernel boots through the `bootloader` crate by default. With the `multiboot2` feature it
+//! boots from Multiboot2 loaders like GRUB instead, with the `limine` feature from Limine. Their
+//! entries translate what the loader hands over into the `bootloader::BootInfo` the rest of the
+//! kernel uses: a memory map with the kernel, the boot modules and the loader's own data marked
+//! as in use, and the offset all physical memory is mapped at. What `BootInfo` has no room for
+//! is kept here: the command line, the boot modules and the framebuffer.
+//!
+//! `kernel_entry!` declares the kernel's entry function for whichever protocol was built.
+//! Both protocols need their own linker script, which `build.rs` passes, and the console needs
+//! VGA text mode: GRUB keeps it unless told otherwise, Limine needs `textmode: yes` and BIOS.
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use bootloader::BootInfo;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::mem;
+
+pub mod limine;
+pub mod multiboot2;
+
+#[cfg(all(feature = "multiboot2", feature = "limine"))]
+compile_error!("the multiboot2 and limine features are mutually exclusive");
+
+/// Boot modules kept, more are ignored.
+pub const MAX_MODULES: usize = 8;
+/// Longest command line or module name kept, longer ones are cut off.
+pub const MAX_STRING: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Bootloader,
+    Multiboot2,
+    Limine,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bootloader => "bootloader",
+            Self::Multiboot2 => "multiboot2",
+            Self::Limine => "limine",
+        }
+    }
+}
+
+/// A string copied out of the loader's memory.
+#[derive(Debug, Clone, Copy)]
+pub struct BootString {
+    bytes: [u8; MAX_STRING],
+    len: usize,
+}
+
+impl BootString {
+    pub const fn empty() -> Self {
+        Self {
+            bytes: [0; MAX_STRING],
+            len: 0,
+        }
+    }
+
+    /// Copies `bytes` up to the first NUL.
+    pub fn new(bytes: &[u8]) -> Self {
+        let bytes = bytes.split(|byte| *byte == 0).next().unwrap_or_default();
+        let mut string = Self::empty();
+        string.len = bytes.len().min(MAX_STRING);
+        string.bytes[..string.len].copy_from_slice(&bytes[..string.len]);
+        string
+    }
+
+    /// Copies the NUL-terminated string at `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` has to point to a NUL-terminated string, or be null.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Self {
+        if ptr.is_null() {
+            return Self::empty();
+        }
+        let mut len = 0;
+        while len < MAX_STRING && *ptr.add(len) != 0 {
+            len += 1;
+        }
+        Self::new(core::slice::from_raw_parts(ptr, len))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // cut off in the middle of a character is still mostly readable
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(string) => string,
+            Err(e) => core::str::from_utf8(&self.bytes[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}
+
+/// A file the loader loaded next to the kernel, like the initrd.
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    pub start: PhysAddr,
+    pub len: u64,
+    /// the module's command line or path, whatever the loader has
+    pub name: BootString,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub addr: PhysAddr,
+    pub width: u32,
+    pub height: u32,
+    /// bytes per line
+    pub pitch: u32,
+    pub bpp: u8,
+}
+
+/// What the loader passed besides the memory map.
+#[derive(Debug, Clone, Copy)]
+pub struct Handover {
+    pub protocol: Protocol,
+    pub cmdline: Option<BootString>,
+    pub modules: [Option<Module>; MAX_MODULES],
+    pub framebuffer: Option<Framebuffer>,
+}
+
+impl Handover {
+    pub const fn new(protocol: Protocol) -> Self {
+        Self {
+            protocol,
+            cmdline: None,
+            modules: [None; MAX_MODULES],
+            framebuffer: None,
+        }
+    }
+
+    pub fn add_module(&mut self, module: Module) {
+        if let Some(slot) = self.modules.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(module);
+        }
+    }
+}
+
+static HANDOVER: Once<Handover> = Once::new();
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// Returns how the kernel was booted.
+pub fn protocol() -> Protocol {
+    HANDOVER
+        .r#try()
+        .map_or(Protocol::Bootloader, |handover| handover.protocol)
+}
+
+/// Returns the command line from the loader, if it passed one.
+pub fn cmdline() -> Option<&'static str> {
+    let cmdline = HANDOVER.r#try()?.cmdline.as_ref()?;
+    Some(cmdline.as_str())
+}
+
+/// Returns the boot modules, in the order the loader lists them.
+pub fn modules() -> impl Iterator<Item = &'static Module> {
+    HANDOVER
+        .r#try()
+        .into_iter()
+        .flat_map(|handover| handover.modules.iter().flatten())
+}
+
+/// Returns the contents of a boot module. Requires `mem::init`.
+pub fn module_data(module: &Module) -> Option<&'static [u8]> {
+    let start = mem::phys_to_virt(module.start)?;
+    Some(unsafe { core::slice::from_raw_parts(start.as_ptr(), module.len as usize) })
+}
+
+pub fn framebuffer() -> Option<Framebuffer> {
+    HANDOVER.r#try()?.framebuffer
+}
+
+/// Most regions a memory map can have.
+const MAX_REGIONS: usize = 64;
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    start: u64,
+    end: u64,
+    kind: MemoryRegionType,
+}
+
+/// A memory map being put together from what the loader reports.
+pub struct Regions {
+    list: [Region; MAX_REGIONS],
+    len: usize,
+}
+
+impl Regions {
+    pub const fn new() -> Self {
+        Self {
+            list: [Region {
+                start: 0,
+                end: 0,
+                kind: MemoryRegionType::Empty,
+            }; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, start: u64, end: u64, kind: MemoryRegionType) {
+        if start < end && self.len < MAX_REGIONS {
+            self.list[self.len] = Region { start, end, kind };
+            self.len += 1;
+        }
+    }
+
+    /// Adds a region of the loader's memory map. Usable regions shrink to whole pages, others
+    /// grow to them.
+    pub fn add(&mut self, start: u64, end: u64, kind: MemoryRegionType) {
+        if kind == MemoryRegionType::Usable {
+            self.push(align_up(start), end & !(PAGE_SIZE - 1), kind);
+        } else {
+            self.push(start & !(PAGE_SIZE - 1), align_up(end), kind);
+        }
+    }
+
+    /// Marks `start..end` as `kind`, cutting it out of the usable regions.
+    pub fn reserve(&mut self, start: u64, end: u64, kind: MemoryRegionType) {
+        let (start, end) = (start & !(PAGE_SIZE - 1), align_up(end));
+        if start >= end {
+            return;
+        }
+        let mut i = 0;
+        while i < self.len {
+            let region = self.list[i];
+            if region.kind != MemoryRegionType::Usable || region.end <= start || end <= region.start
+            {
+                i += 1;
+                continue;
+            }
+            // the part after the reserved range goes to the end, the part before stays
+            self.push(end, region.end, region.kind);
+            if region.start < start {
+                self.list[i].end = start;
+                i += 1;
+            } else {
+                self.len -= 1;
+                self.list[i] = self.list[self.len];
+            }
+        }
+        self.push(start, end, kind);
+    }
+
+    /// Drops usable memory at and above `end`, for memory the kernel can't reach.
+    pub fn clip_usable(&mut self, end: u64) {
+        for region in &mut self.list[..self.len] {
+            if region.kind == MemoryRegionType::Usable {
+                region.end = region.end.min(end);
+                region.start = region.start.min(region.end);
+            }
+        }
+    }
+
+    /// Merges usable regions that overlap or touch. Reserving afterwards keeps the usable
+    /// regions apart from everything else.
+    pub fn merge_usable(&mut self) {
+        self.sort();
+        let mut i = 1;
+        while i < self.len {
+            let (previous, region) = (self.list[i - 1], self.list[i]);
+            let usable = MemoryRegionType::Usable;
+            if previous.kind == usable && region.kind == usable && region.start <= previous.end {
+                self.list[i - 1].end = previous.end.max(region.end);
+                self.list.copy_within(i + 1..self.len, i);
+                self.len -= 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn sort(&mut self) {
+        let list = &mut self.list[..self.len];
+        for i in 1..list.len() {
+            let mut j = i;
+            while j > 0 && list[j - 1].start > list[j].start {
+                list.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+
+    pub fn into_memory_map(mut self) -> MemoryMap {
+        self.sort();
+        let mut map = MemoryMap::new();
+        for region in &self.list[..self.len] {
+            if region.start < region.end {
+                map.add_region(MemoryRegion {
+                    range: FrameRange::new(region.start, region.end),
+                    region_type: region.kind,
+                });
+            }
+        }
+        map
+    }
+}
+
+fn align_up(addr: u64) -> u64 {
+    addr.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Stores what a protocol entry collected, returns the `BootInfo` to start the kernel with.
+pub fn finish(
+    handover: Handover,
+    regions: Regions,
+    physical_memory_offset: u64,
+) -> &'static BootInfo {
+    HANDOVER.call_once(|| handover);
+    BOOT_INFO
+        .call_once(|| BootInfo::new(regions.into_memory_map(), None, 0, physical_memory_offset))
+}
+
+/// Declares the kernel's entry function, `fn(&'static BootInfo) -> !`, for the boot protocol
+/// the kernel was built for.
+#[macro_export]
+macro_rules! kernel_entry {
+    ($path:path) => {
+        #[cfg(not(any(feature = "multiboot2", feature = "limine")))]
+        bootloader::entry_point!($path);
+
+        /// Called by the entry of the boot protocol once it has a `BootInfo`.
+        #[cfg(any(feature = "multiboot2", feature = "limine"))]
+        #[export_name = "skyos_kernel_main"]
+        extern "C" fn __skyos_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+            let main: fn(&'static bootloader::BootInfo) -> ! = $path;
+            main(boot_info)
+        }
+    };
+}
+
+#[cfg(any(feature = "multiboot2", feature = "limine"))]
+extern "C" {
+    /// Defined by `kernel_entry!`.
+    fn skyos_kernel_main(boot_info: &'static BootInfo) -> !;
+}
+
+/// Starts the kernel with the `BootInfo` from `finish`.
+#[cfg(any(feature = "multiboot2", feature = "limine"))]
+fn start_kernel(boot_info: &'static BootInfo) -> ! {
+    unsafe { skyos_kernel_main(boot_info) }
+}
+
+#[test_case]
+fn test_reserve_splits_usable_regions() {
+    let mut regions = Regions::new();
+    regions.add(0x1000, 0x9_f800, MemoryRegionType::Usable);
+    regions.add(0x10_0000, 0x800_0000, MemoryRegionType::Usable);
+    regions.reserve(0x20_0000, 0x30_0123, MemoryRegionType::Kernel);
+    regions.reserve(0x7ff_f000, 0x900_0000, MemoryRegionType::InUse);
+    regions.clip_usable(0x400_0000);
+    regions.sort();
+    let list: [(u64, u64, MemoryRegionType); 5] = core::array::from_fn(|i| {
+        let region = regions.list[i];
+        (region.start, region.end, region.kind)
+    });
+    assert_eq!(regions.len, 5);
+    assert_eq!(
+        list,
+        [
+            (0x1000, 0x9_f000, MemoryRegionType::Usable),
+            (0x10_0000, 0x20_0000, MemoryRegionType::Usable),
+            (0x20_0000, 0x30_1000, MemoryRegionType::Kernel),
+            (0x30_1000, 0x400_0000, MemoryRegionType::Usable),
+            (0x7ff_f000, 0x900_0000, MemoryRegionType::InUse),
+        ]
+    );
+}
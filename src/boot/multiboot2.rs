@@ -0,0 +1,315 @@
+//! Booting from Multiboot2 loaders like GRUB.
+//!
+//! The loader starts `_start` in 32 bit protected mode without paging, with the magic value in
+//! eax and the physical address of the boot information in ebx. The entry maps the first 4 GiB
+//! twice with 2 MiB pages, identity mapped for the kernel, which is linked at its physical
+//! address, and at `PHYSICAL_MEMORY_OFFSET` as the mapping of all physical memory. It then
+//! enables long mode, NX and write protection and calls `skyos_multiboot2_main`, which turns the
+//! boot information into a `BootInfo`. Memory above 4 GiB isn't mapped and left unused.
+use bootloader::bootinfo::MemoryRegionType;
+use x86_64::PhysAddr;
+
+use super::{BootString, Framebuffer, Handover, Module, Regions};
+#[cfg(any(feature = "multiboot2", test))]
+use super::Protocol;
+
+/// What the loader passes in eax.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+/// Where the entry maps physical memory.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+/// End of the memory the entry maps.
+pub const MAPPED_MEMORY: u64 = 4 << 30;
+
+// boot information tags
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+
+// memory map entry types
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_ACPI_NVS: u32 = 4;
+const MEMORY_BAD: u32 = 5;
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Reads the boot information tags into `handover` and `regions`. Stops at the end tag or
+/// anything malformed.
+pub fn parse(info: &[u8], handover: &mut Handover, regions: &mut Regions) {
+    // total size and a reserved field come first, tags are 8 byte aligned
+    let mut offset = 8;
+    while let (Some(kind), Some(size)) = (read_u32(info, offset), read_u32(info, offset + 4)) {
+        let size = size as usize;
+        let Some(tag) = info.get(offset..offset + size).filter(|_| size >= 8) else {
+            return;
+        };
+        match kind {
+            TAG_END => return,
+            TAG_CMDLINE => handover.cmdline = Some(BootString::new(&tag[8..])),
+            TAG_MODULE => {
+                if let (Some(start), Some(end)) = (read_u32(tag, 8), read_u32(tag, 12)) {
+                    handover.add_module(Module {
+                        start: PhysAddr::new(start as u64),
+                        len: end.saturating_sub(start) as u64,
+                        name: BootString::new(tag.get(16..).unwrap_or_default()),
+                    });
+                }
+            }
+            TAG_MEMORY_MAP => parse_memory_map(tag, regions),
+            TAG_FRAMEBUFFER => {
+                let framebuffer = (|| {
+                    Some(Framebuffer {
+                        addr: PhysAddr::new(read_u64(tag, 8)?),
+                        pitch: read_u32(tag, 16)?,
+                        width: read_u32(tag, 20)?,
+                        height: read_u32(tag, 24)?,
+                        bpp: *tag.get(28)?,
+                    })
+                })();
+                handover.framebuffer = framebuffer;
+            }
+            _ => {}
+        }
+        offset += (size + 7) & !7;
+    }
+}
+
+fn parse_memory_map(tag: &[u8], regions: &mut Regions) {
+    let Some(entry_size) = read_u32(tag, 8).map(|size| size as usize) else {
+        return;
+    };
+    if entry_size < 24 {
+        return;
+    }
+    for entry in tag[16..].chunks_exact(entry_size) {
+        let (Some(base), Some(len), Some(kind)) =
+            (read_u64(entry, 0), read_u64(entry, 8), read_u32(entry, 16))
+        else {
+            continue;
+        };
+        let kind = match kind {
+            MEMORY_AVAILABLE => MemoryRegionType::Usable,
+            MEMORY_ACPI_RECLAIMABLE => MemoryRegionType::AcpiReclaimable,
+            MEMORY_ACPI_NVS => MemoryRegionType::AcpiNvs,
+            MEMORY_BAD => MemoryRegionType::BadMemory,
+            _ => MemoryRegionType::Reserved,
+        };
+        regions.add(base, base.saturating_add(len), kind);
+    }
+}
+
+#[cfg(feature = "multiboot2")]
+core::arch::global_asm!(
+    r#"
+.section .multiboot2, "a"
+.align 8
+skyos_mb2_header:
+    .long 0xe85250d6
+    .long 0
+    .long skyos_mb2_header_end - skyos_mb2_header
+    .long 0x100000000 - (0xe85250d6 + (skyos_mb2_header_end - skyos_mb2_header))
+    // end tag
+    .short 0
+    .short 0
+    .long 8
+skyos_mb2_header_end:
+
+.section .bss
+.align 4096
+skyos_mb2_pml4:
+    .skip 4096
+// identity mapping first, then the one at the offset
+skyos_mb2_pdpt:
+    .skip 4096 * 2
+skyos_mb2_pd:
+    .skip 4096 * 8
+skyos_mb2_stack:
+    .skip 4096 * 16
+skyos_mb2_stack_top:
+
+.section .rodata
+.align 8
+skyos_mb2_gdt:
+    .quad 0
+    // 64 bit code
+    .quad 0x00af9a000000ffff
+skyos_mb2_gdt_pointer:
+    .short skyos_mb2_gdt_pointer - skyos_mb2_gdt - 1
+    .quad skyos_mb2_gdt
+
+.section .text
+.code32
+.global _start
+_start:
+    cli
+    mov esp, offset skyos_mb2_stack_top
+    mov edi, eax
+    mov esi, ebx
+
+    // 2048 pages of 2 MiB, twice: present, writable, huge
+    xor ecx, ecx
+2:
+    mov eax, ecx
+    and eax, 2047
+    shl eax, 21
+    or eax, 0x83
+    mov [skyos_mb2_pd + ecx * 8], eax
+    mov dword ptr [skyos_mb2_pd + ecx * 8 + 4], 0
+    inc ecx
+    cmp ecx, 4096
+    jne 2b
+
+    // four directories each for the identity mapping and the one at the offset, separate so
+    // `memprotect` can make the latter non-executable without touching the kernel's code
+    mov eax, offset skyos_mb2_pd
+    or eax, 3
+    xor ecx, ecx
+3:
+    mov [skyos_mb2_pdpt + ecx * 8], eax
+    add eax, 4096
+    inc ecx
+    cmp ecx, 8
+    jne 3b
+
+    mov eax, offset skyos_mb2_pdpt
+    or eax, 3
+    mov [skyos_mb2_pml4], eax
+    add eax, 4096
+    mov [skyos_mb2_pml4 + 256 * 8], eax
+    mov eax, offset skyos_mb2_pml4
+    mov cr3, eax
+
+    // PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    // long mode and NX
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+    // paging and write protection
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    lgdt [skyos_mb2_gdt_pointer]
+    push 0x8
+    push offset skyos_mb2_long_mode
+    retf
+
+.code64
+skyos_mb2_long_mode:
+    xor eax, eax
+    mov ds, eax
+    mov es, eax
+    mov ss, eax
+    mov fs, eax
+    mov gs, eax
+    // the upper halves are undefined after the switch
+    mov edi, edi
+    mov esi, esi
+    call skyos_multiboot2_main
+4:
+    hlt
+    jmp 4b
+"#
+);
+
+#[cfg(feature = "multiboot2")]
+extern "C" {
+    // from the linker script
+    static __skyos_kernel_start: u8;
+    static __skyos_kernel_end: u8;
+}
+
+#[cfg(feature = "multiboot2")]
+#[no_mangle]
+extern "C" fn skyos_multiboot2_main(magic: u32, info_addr: u64) -> ! {
+    if magic != BOOTLOADER_MAGIC {
+        crate::hlt_loop();
+    }
+    // identity mapped by the entry
+    let info = unsafe {
+        let size = *(info_addr as *const u32) as usize;
+        core::slice::from_raw_parts(info_addr as *const u8, size)
+    };
+    let mut handover = Handover::new(Protocol::Multiboot2);
+    let mut regions = Regions::new();
+    parse(info, &mut handover, &mut regions);
+
+    let (kernel_start, kernel_end) = unsafe {
+        (
+            &__skyos_kernel_start as *const u8 as u64,
+            &__skyos_kernel_end as *const u8 as u64,
+        )
+    };
+    regions.reserve(0, 4096, MemoryRegionType::FrameZero);
+    regions.reserve(kernel_start, kernel_end, MemoryRegionType::Kernel);
+    regions.reserve(
+        info_addr,
+        info_addr + info.len() as u64,
+        MemoryRegionType::BootInfo,
+    );
+    for module in handover.modules.iter().flatten() {
+        let start = module.start.as_u64();
+        regions.reserve(start, start + module.len, MemoryRegionType::InUse);
+    }
+    regions.clip_usable(MAPPED_MEMORY);
+
+    let boot_info = super::finish(handover, regions, PHYSICAL_MEMORY_OFFSET);
+    super::start_kernel(boot_info)
+}
+
+#[test_case]
+fn test_parse_boot_information() {
+    let mut info = [0u8; 152];
+    let mut put =
+        |offset: usize, bytes: &[u8]| info[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(0, &152u32.to_le_bytes());
+    // command line
+    put(8, &TAG_CMDLINE.to_le_bytes());
+    put(12, &17u32.to_le_bytes());
+    put(16, b"quiet\0");
+    // a module
+    put(32, &TAG_MODULE.to_le_bytes());
+    put(36, &24u32.to_le_bytes());
+    put(40, &0x40_0000u32.to_le_bytes());
+    put(44, &0x40_2000u32.to_le_bytes());
+    put(48, b"initrd\0");
+    // memory map with one entry of each kind that matters
+    put(56, &TAG_MEMORY_MAP.to_le_bytes());
+    put(60, &64u32.to_le_bytes());
+    put(64, &24u32.to_le_bytes());
+    put(72, &0u64.to_le_bytes());
+    put(80, &0x9_fc00u64.to_le_bytes());
+    put(88, &MEMORY_AVAILABLE.to_le_bytes());
+    put(96, &0x10_0000u64.to_le_bytes());
+    put(104, &0x700_0000u64.to_le_bytes());
+    put(112, &2u32.to_le_bytes());
+    put(120, &TAG_END.to_le_bytes());
+    put(124, &8u32.to_le_bytes());
+
+    let mut handover = Handover::new(Protocol::Multiboot2);
+    let mut regions = Regions::new();
+    parse(&info, &mut handover, &mut regions);
+    assert_eq!(handover.cmdline.unwrap().as_str(), "quiet");
+    let module = handover.modules[0].unwrap();
+    assert_eq!((module.start.as_u64(), module.len), (0x40_0000, 0x2000));
+    assert_eq!(module.name.as_str(), "initrd");
+    assert_eq!(regions.len, 2);
+    assert_eq!(regions.list[0].end, 0x9_f000);
+    assert_eq!(regions.list[1].kind, MemoryRegionType::Reserved);
+}
@@ -0,0 +1,30 @@
+//! Kernel command line.
+//!
+//! Arguments are separated by spaces and are either flags (`quiet`) or `key=value` pairs.
+//! The command line comes from the loader when it passes one, see `boot`, and is otherwise set
+//! at build time through `SKYOS_CMDLINE`.
+use crate::boot;
+
+static CMDLINE: &str = include_str!(concat!(env!("OUT_DIR"), "/cmdline"));
+
+pub fn cmdline() -> &'static str {
+    boot::cmdline().unwrap_or(CMDLINE).trim()
+}
+
+fn args() -> impl Iterator<Item = &'static str> {
+    cmdline().split(' ').filter(|arg| !arg.is_empty())
+}
+
+/// Returns the value of the last `key=value` argument for `key`.
+pub fn get(key: &str) -> Option<&'static str> {
+    args()
+        .filter_map(|arg| arg.split_once('='))
+        .filter(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+        .last()
+}
+
+/// Returns whether `flag` was given on its own.
+pub fn has_flag(flag: &str) -> bool {
+    args().any(|arg| arg == flag)
+}
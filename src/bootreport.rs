@@ -0,0 +1,145 @@
+//! Boot time measurements.
+//!
+//! Init stages, init steps and driver probes record when they started and ended in TSC cycles.
+//! Recording starts before there is a heap, so entries live in a fixed table and anything past
+//! `MAX_ENTRIES` is dropped. The report is read through `/proc/bootinfo` and `bootchart`.
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::tsc;
+
+pub const MAX_ENTRIES: usize = 64;
+const NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Stage,
+    Step,
+    Probe,
+}
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub kind: Kind,
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Entry {
+    const EMPTY: Self = Self {
+        kind: Kind::Stage,
+        name: [0; NAME_LEN],
+        name_len: 0,
+        start: 0,
+        end: 0,
+    };
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("?")
+    }
+}
+
+struct Report {
+    entries: [Entry; MAX_ENTRIES],
+    len: usize,
+}
+
+static REPORT: Mutex<Report> = Mutex::new(Report {
+    entries: [Entry::EMPTY; MAX_ENTRIES],
+    len: 0,
+});
+/// TSC when the kernel started, everything before that is firmware and bootloader.
+static KERNEL_START: AtomicU64 = AtomicU64::new(0);
+
+/// Marks the start of the kernel. Call as early as possible.
+pub fn start() {
+    KERNEL_START.store(tsc::read(), Ordering::Relaxed);
+}
+
+/// Runs `f`, recording how long it took as an entry of `kind`.
+pub fn measure<R>(kind: Kind, name: &str, f: impl FnOnce() -> R) -> R {
+    let start = tsc::read();
+    let result = f();
+    record(kind, name, start, tsc::read());
+    result
+}
+
+fn record(kind: Kind, name: &str, start: u64, end: u64) {
+    let mut entry = Entry {
+        kind,
+        start,
+        end,
+        ..Entry::EMPTY
+    };
+    for c in name.chars() {
+        if entry.name_len + c.len_utf8() > NAME_LEN {
+            break;
+        }
+        c.encode_utf8(&mut entry.name[entry.name_len..]);
+        entry.name_len += c.len_utf8();
+    }
+    without_interrupts(|| {
+        let mut report = REPORT.lock();
+        if report.len < MAX_ENTRIES {
+            let len = report.len;
+            report.entries[len] = entry;
+            report.len += 1;
+        }
+    });
+}
+
+/// Returns the entries in the order they started, enclosing ones first.
+pub fn entries() -> Vec<Entry> {
+    let mut entries = without_interrupts(|| {
+        let report = REPORT.lock();
+        report.entries[..report.len].to_vec()
+    });
+    entries.sort_by_key(|entry| (entry.start, entry.kind));
+    entries
+}
+
+pub fn kernel_start() -> u64 {
+    KERNEL_START.load(Ordering::Relaxed)
+}
+
+/// Formats a number of cycles as milliseconds, or as cycles if the TSC isn't calibrated yet.
+pub fn format_cycles(cycles: u64) -> String {
+    match tsc::cycles_to_duration(cycles) {
+        Some(duration) => {
+            let micros = duration.as_micros();
+            format!("{}.{:03}ms", micros / 1000, micros % 1000)
+        }
+        None => format!("{}kcyc", cycles / 1000),
+    }
+}
+
+/// The report as a table of start and duration relative to the kernel start.
+pub fn report() -> String {
+    let start = kernel_start();
+    let mut out = String::new();
+    let _ = writeln!(out, "firmware and bootloader: {}", format_cycles(start));
+    if let Some(frequency) = tsc::frequency() {
+        let _ = writeln!(out, "tsc: {} kHz", frequency / 1000);
+    }
+    let _ = writeln!(out, "{:>14} {:>14}  name", "start", "duration");
+    for entry in entries() {
+        let indent = match entry.kind {
+            Kind::Stage => "",
+            Kind::Step => "  ",
+            Kind::Probe => "    ",
+        };
+        let _ = writeln!(
+            out,
+            "{:>14} {:>14}  {indent}{}",
+            format_cycles(entry.start.saturating_sub(start)),
+            format_cycles(entry.end - entry.start),
+            entry.name()
+        );
+    }
+    out
+}
@@ -0,0 +1,156 @@
+//! Wall clock.
+//!
+//! Nothing reads the CMOS clock, so the time is unknown until a time source such as SNTP sets
+//! it. The clock is then the Unix time of the last sample plus the uptime elapsed since, corrected
+//! by how fast the timer was measured to drift between samples.
+use core::fmt;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time;
+
+/// Largest drift believed, anything beyond is a bad sample rather than a bad timer.
+const MAX_DRIFT_PPM: i64 = 500;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    unix: Duration,
+    uptime: Duration,
+}
+
+struct Clock {
+    last: Option<Sample>,
+    /// parts per million the timer runs slow, negative if it runs fast
+    drift_ppm: i64,
+}
+
+impl Clock {
+    fn at(&self, uptime: Duration) -> Option<Duration> {
+        let last = self.last?;
+        let elapsed = uptime - last.uptime;
+        let correction = elapsed.as_nanos() as i64 / 1_000_000 * self.drift_ppm;
+        let elapsed = (elapsed.as_nanos() as i64 + correction).max(0);
+        Some(last.unix + Duration::from_nanos(elapsed as u64))
+    }
+}
+
+static CLOCK: Mutex<Clock> = Mutex::new(Clock {
+    last: None,
+    drift_ppm: 0,
+});
+
+/// The time since the Unix epoch, `None` if the clock was never set.
+pub fn now() -> Option<Duration> {
+    let uptime = time::uptime();
+    without_interrupts(|| CLOCK.lock().at(uptime))
+}
+
+/// Seconds since the Unix epoch as stored in ext2 inodes, 0 if the clock was never set.
+pub fn unix_seconds() -> u32 {
+    now().map_or(0, |now| now.as_secs() as u32)
+}
+
+/// Sets the clock without touching the drift estimate.
+pub fn set(unix: Duration) {
+    let sample = Sample {
+        unix,
+        uptime: time::uptime(),
+    };
+    without_interrupts(|| CLOCK.lock().last = Some(sample));
+}
+
+/// Sets the clock from a time source, and from how far it went off since the last sample
+/// estimates the drift of the timer. Returns the offset of the clock before, positive if it was
+/// behind.
+pub fn sync(unix: Duration) -> Option<i64> {
+    let uptime = time::uptime();
+    without_interrupts(|| {
+        let mut clock = CLOCK.lock();
+        let offset = clock
+            .at(uptime)
+            .map(|predicted| unix.as_nanos() as i64 - predicted.as_nanos() as i64);
+        if let (Some(offset), Some(last)) = (offset, clock.last) {
+            let elapsed = (uptime - last.uptime).as_nanos() as i64;
+            // too short to tell drift from the jitter of the samples
+            if elapsed >= 60_000_000_000 {
+                let drift = clock.drift_ppm + offset / (elapsed / 1_000_000);
+                clock.drift_ppm = drift.clamp(-MAX_DRIFT_PPM, MAX_DRIFT_PPM);
+            }
+        }
+        clock.last = Some(Sample { unix, uptime });
+        offset
+    })
+}
+
+/// The drift of the timer measured so far, in parts per million.
+pub fn drift_ppm() -> i64 {
+    without_interrupts(|| CLOCK.lock().drift_ppm)
+}
+
+/// A point in time split into its calendar date and time of day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86400) as i64;
+        let time = secs % 86400;
+        // Howard Hinnant's days_from_civil, inverted
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        Self {
+            year,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_date_time() {
+    let epoch = DateTime::from_unix(0);
+    assert_eq!((epoch.year, epoch.month, epoch.day), (1970, 1, 1));
+    let date = DateTime::from_unix(1_700_000_000);
+    assert_eq!(
+        (
+            date.year,
+            date.month,
+            date.day,
+            date.hour,
+            date.minute,
+            date.second
+        ),
+        (2023, 11, 14, 22, 13, 20)
+    );
+    // a leap day
+    let leap = DateTime::from_unix(951_782_400);
+    assert_eq!((leap.year, leap.month, leap.day), (2000, 2, 29));
+}
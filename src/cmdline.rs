@@ -1,27 +1,642 @@
+pub mod alias;
+pub mod args;
+pub mod calc;
+pub mod command;
+
 use core::fmt::Display;
+use core::ops::Range;
+use core::time::Duration;
 
-use alloc::{string::String, vec::Vec};
-use lazy_static::lazy_static;
-use pc_keyboard::DecodedKey;
-use spin::Mutex;
+use alloc::{collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
 use x86_64::instructions::interrupts::without_interrupts;
 
-use crate::{print, println, serial_println, vga_buffer::WRITER};
+use self::args::Token;
+use self::command::{ArgSpec, Command, Opt};
+use crate::{
+    allocator,
+    archive::{self, Archive, EntryKind},
+    bench::{BlockBench, BlockWorkload, FsBench, Report, Workload},
+    bootreport::{self, Kind},
+    clock::{self, DateTime},
+    compress, config,
+    cpufreq::{self, Policy},
+    crypto,
+    drivers::{
+        ahci_driver::{self, AhciDevice, AhciDisk},
+        ramdisk::RamDisk,
+        speaker,
+    },
+    editor,
+    error::KError,
+    exec,
+    fault::{self, Point},
+    fileshare::{self, Builder, Kind as ShareKind, Share},
+    hwdebug::{self, Width},
+    initd,
+    interrupts::{self, ExceptionAction},
+    font::{self, Font, FontError},
+    ext::{Errno, Ext2, FileType, QuotaKind, QuotaLimits, RWS},
+    jobs,
+    keyboard, keymap, klog, memprotect, module,
+    net::{
+        self, arp,
+        http::{self, HttpError},
+        icmp::{self, IcmpSocket},
+        ipv4, pcap,
+        stats::{self, SocketInfo},
+        tcp, udp, Ipv4Addr,
+    },
+    pci, print, print_error, println, profile, screenshot, selftest, signal::Signal, suspend,
+    syscall, sysconf,
+    task::{self, SignalError, TaskId},
+    theme, thermal, time, timer, tsc,
+    tty::{self, Settings},
+    vfs::{self, Ext2Fs},
+    vga_buffer::{self, Color, WRITER},
+};
 
 type CmdResult = Result<(), Error>;
-type Cmd = &'static dyn Fn(Vec<&str>) -> CmdResult;
 
-lazy_static! {
-    pub static ref CMD_LINE: Mutex<CommandLine> = Mutex::new(CommandLine::new());
-}
-const COMMANDS: &[(&'static str, &dyn Fn(Vec<&str>) -> CmdResult)] = &[
-    ("echo", &echo),
-    ("clear", &clear),
-    ("cls", &clear),
+/// No upper limit on the number of operands.
+const MANY: usize = usize::MAX;
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "echo",
+        synopsis: &["[args...]"],
+        args: ArgSpec::operands(0, MANY),
+        help: "Prints its arguments, separated by spaces.",
+        run: echo,
+    },
+    Command {
+        name: "calc",
+        synopsis: &["<expression>..."],
+        args: ArgSpec::operands(1, MANY),
+        help: "Evaluates an integer expression with the operators of C and prints the result in \
+               decimal, hex, octal and binary. Numbers can start with 0x, 0o or 0b. Quote \
+               expressions using | or &, the shell reads those itself.",
+        run: calc,
+    },
+    Command {
+        name: "clear",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Clears the screen.",
+        run: clear,
+    },
+    Command {
+        name: "cls",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Clears the screen, like clear.",
+        run: clear,
+    },
+    Command {
+        name: "beep",
+        synopsis: &["[frequency] [ms]"],
+        args: ArgSpec::operands(0, 2),
+        help: "Beeps the PC speaker, at 440 Hz for 200 ms by default.",
+        run: beep,
+    },
+    Command {
+        name: "dmesg",
+        synopsis: &["[-z <path>]"],
+        args: ArgSpec::NONE.options(&[Opt::Value("-z")]),
+        help: "Prints the kernel log, or with -z writes it compressed to a file.",
+        run: dmesg,
+    },
+    Command {
+        name: "strace",
+        synopsis: &["<cmd> [args...]"],
+        args: ArgSpec::operands(1, MANY),
+        help: "Runs a command and prints the system calls it makes.",
+        run: strace,
+    },
+    Command {
+        name: "profile",
+        synopsis: &["start [interval]", "stop", "report [count]"],
+        args: ArgSpec::operands(1, 2),
+        help: "Samples the running function every `interval` ticks and reports the `count` \
+               functions sampled most.",
+        run: profile,
+    },
+    Command {
+        name: "heapdump",
+        synopsis: &["[count]"],
+        args: ArgSpec::operands(0, 1),
+        help: "Shows how much of the heap is used, and with the heap-tracking feature the \
+               `count` call sites holding the most memory.",
+        run: heapdump,
+    },
+    Command {
+        name: "ls",
+        synopsis: &["[path]"],
+        args: ArgSpec::operands(0, 1),
+        help: "Lists a directory, / by default.",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        synopsis: &["[path...]"],
+        args: ArgSpec::operands(0, MANY),
+        help: "Prints files, or copies stdin to stdout without any.",
+        run: cat,
+    },
+    Command {
+        name: "edit",
+        synopsis: &["<path>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Opens a file in the editor.",
+        run: edit,
+    },
+    Command {
+        name: "tar",
+        synopsis: &["-t <archive>", "-x <archive> [dest]"],
+        args: ArgSpec::operands(0, 1).options(&[Opt::Value("-t"), Opt::Value("-x")]),
+        help: "Lists the entries of a tar archive, or extracts it to `dest`, / by default.",
+        run: tar,
+    },
+    Command {
+        name: "kill",
+        synopsis: &["[-SIGNAL] <id>"],
+        args: ArgSpec::operands(1, 1).options(&[Opt::Any]),
+        help: "Sends a signal to a task, TERM by default.",
+        run: kill,
+    },
+    Command {
+        name: "sleep",
+        synopsis: &["<ms>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Waits for a number of milliseconds.",
+        run: sleep,
+    },
+    Command {
+        name: "time",
+        synopsis: &["<cmd> [args...]"],
+        args: ArgSpec::operands(1, MANY),
+        help: "Runs a command and prints how long it took.",
+        run: time,
+    },
+    Command {
+        name: "date",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Prints the date and time, once the clock was set through NTP.",
+        run: date,
+    },
+    Command {
+        name: "watch",
+        synopsis: &["<ms> <cmd> [args...]"],
+        args: ArgSpec::operands(2, MANY),
+        help: "Clears the screen and runs a command every `ms` milliseconds, until interrupted \
+               with Ctrl+C.",
+        run: watch,
+    },
+    Command {
+        name: "theme",
+        synopsis: &["[<name> | <part> <color>]"],
+        args: ArgSpec::operands(0, 2),
+        help: "Shows the colors and themes, switches to a theme, or sets the color of one part: \
+               fg, bg, error, ok or prompt.",
+        run: set_theme,
+    },
+    Command {
+        name: "stty",
+        synopsis: &["[[-]<setting>...]"],
+        args: ArgSpec::operands(0, MANY),
+        help: "Shows or changes the console settings: raw, cooked, sane, icanon, echo and isig. \
+               A leading - turns a setting off.",
+        run: stty,
+    },
+    Command {
+        name: "bootchart",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Draws the boot report as bars on a timeline starting with the kernel.",
+        run: bootchart,
+    },
+    Command {
+        name: "config",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Shows the kernel configuration.",
+        run: show_config,
+    },
+    Command {
+        name: "sync",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Writes everything cached back to the disks.",
+        run: sync,
+    },
+    Command {
+        name: "dd",
+        synopsis: &["if=<path|/dev/sdX> of=<path|/dev/sdX> [bs=<n>] [count=<n>] [conv=sparse]"],
+        args: ArgSpec::operands(2, 5),
+        help: "Copies a file or disk block by block, 512 bytes at a time by default. With \
+               conv=sparse, blocks in holes of the input file aren't written to a disk.",
+        run: dd,
+    },
+    Command {
+        name: "mount",
+        synopsis: &["[/dev/sdX[N] <path> [ro]]"],
+        args: ArgSpec::operands(0, 3),
+        help: "Lists the mounted file systems, or mounts the ext2 file system of a disk or \
+               partition.",
+        run: mount,
+    },
+    Command {
+        name: "umount",
+        synopsis: &["<path>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Unmounts a file system.",
+        run: umount,
+    },
+    Command {
+        name: "df",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Shows the size, usage and free inodes of every mounted file system, sizes in KiB.",
+        run: df,
+    },
+    Command {
+        name: "quota",
+        synopsis: &[
+            "[on | off] [-g] <path>",
+            "set [-g] <id> <block-soft> <block-hard> <inode-soft> <inode-hard> <path>",
+        ],
+        args: ArgSpec::operands(1, 8).options(&[Opt::Flag("-g")]),
+        help: "Lists, sets and turns on or off the user quotas, or with -g the group quotas, of \
+               the ext2 file system holding a path. Block limits are in KiB, 0 means no limit.",
+        run: quota,
+    },
+    Command {
+        name: "fragstat",
+        synopsis: &["<path>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Shows in how many runs of consecutive blocks a file, or each file of a directory, \
+               is stored.",
+        run: fragstat,
+    },
+    Command {
+        name: "fsbench",
+        synopsis: &["<dir> [size=<n>] [files=<n>] [<workload>...]"],
+        args: ArgSpec::operands(1, MANY),
+        help: "Runs benchmark workloads in a scratch directory of `dir`, all of them by default. \
+               Workloads: seqwrite seqread randwrite randread create delete",
+        run: fsbench,
+    },
+    Command {
+        name: "blkbench",
+        synopsis: &["/dev/sdX [bs=<n>] [count=<n>] [qd=<n>] [<workload>...]"],
+        args: ArgSpec::operands(1, MANY),
+        help: "Times requests straight to a disk, all workloads by default. Writes put back what \
+               was read. Workloads: seqread randread seqwrite randwrite",
+        run: blkbench,
+    },
+    Command {
+        name: "selftest",
+        synopsis: &["[-l] [<prefix>]"],
+        args: ArgSpec::operands(0, 1).options(&[Opt::Flag("-l")]),
+        help: "Runs the unit tests that are safe to run on a booted kernel, or those whose names \
+               start with `prefix`. -l lists them instead.",
+        run: selftest,
+    },
+    Command {
+        name: "fault",
+        synopsis: &["[<point> <one-in> | seed <n> | off]"],
+        args: ArgSpec::operands(0, 2),
+        help: "Shows or sets how often failures are injected into the ext2 disk layer. Points: \
+               read, short-read, write, alloc",
+        run: fault,
+    },
+    Command {
+        name: "fileshare",
+        synopsis: &["ls", "import [dest]", "export <path>..."],
+        args: ArgSpec::operands(1, MANY),
+        help: "Lists, imports or exports the files on the share disk.",
+        run: fileshare,
+    },
+    Command {
+        name: "sha256sum",
+        synopsis: &["<path>..."],
+        args: ArgSpec::operands(1, MANY),
+        help: "Prints the SHA-256 hash of files.",
+        run: sha256sum,
+    },
+    Command {
+        name: "b2sum",
+        synopsis: &["<path>..."],
+        args: ArgSpec::operands(1, MANY),
+        help: "Prints the BLAKE2s hash of files.",
+        run: b2sum,
+    },
+    Command {
+        name: "crc32",
+        synopsis: &["<path>..."],
+        args: ArgSpec::operands(1, MANY),
+        help: "Prints the CRC-32 of files.",
+        run: crc32,
+    },
+    Command {
+        name: "lz4",
+        synopsis: &["[-d] <input> <output>"],
+        args: ArgSpec::operands(2, 2).options(&[Opt::Flag("-d")]),
+        help: "Compresses a file with LZ4, or decompresses it with -d.",
+        run: lz4,
+    },
+    Command {
+        name: "setfont",
+        synopsis: &["<font> [map]", "-m <map>", "-d"],
+        args: ArgSpec::operands(0, 2).options(&[Opt::Value("-m"), Opt::Flag("-d")]),
+        help: "Loads a console font with an optional unicode map, only loads a map with -m, or \
+               goes back to the default font with -d. Fonts given by name are looked up in \
+               /usr/share/consolefonts.",
+        run: setfont,
+    },
+    Command {
+        name: "loadkeys",
+        synopsis: &["[layout]"],
+        args: ArgSpec::operands(0, 1),
+        help: "Shows the keyboard layout or switches to another: us, which is built in, or one \
+               of /usr/share/keymaps.",
+        run: loadkeys,
+    },
+    Command {
+        name: "screenshot",
+        synopsis: &["<path>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Saves the screen to a file.",
+        run: screenshot,
+    },
+    Command {
+        name: "show",
+        synopsis: &["<path>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Shows a screenshot.",
+        run: show,
+    },
+    Command {
+        name: "insmod",
+        synopsis: &["<path>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Loads a kernel module.",
+        run: insmod,
+    },
+    Command {
+        name: "rmmod",
+        synopsis: &["<name>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Unloads a kernel module.",
+        run: rmmod,
+    },
+    Command {
+        name: "lsmod",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Lists the loaded kernel modules.",
+        run: lsmod,
+    },
+    Command {
+        name: "run",
+        synopsis: &["<path> [arg]..."],
+        args: ArgSpec::operands(1, MANY),
+        help: "Runs a program, a statically linked position independent executable like those \
+               in userspace/, with the arguments. Shows its exit status unless it is 0.",
+        run: run_program,
+    },
+    Command {
+        name: "services",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Lists the services started by initd.",
+        run: services,
+    },
+    Command {
+        name: "poweroff",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Stops the services and turns the machine off.",
+        run: poweroff,
+    },
+    Command {
+        name: "suspend",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Suspends to RAM, returns once the machine woke up again.",
+        run: suspend,
+    },
+    Command {
+        name: "cpufreq",
+        synopsis: &["[performance | powersave | ondemand]"],
+        args: ArgSpec::operands(0, 1),
+        help: "Shows /proc/cpufreq, or switches to another frequency policy.",
+        run: cpufreq,
+    },
+    Command {
+        name: "sensors",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Shows the temperature sensors, like /proc/thermal.",
+        run: sensors,
+    },
+    Command {
+        name: "irqstat",
+        synopsis: &["[millis]"],
+        args: ArgSpec::operands(0, 1),
+        help: "Shows how often every exception and IRQ fired and the uptime it last did, like \
+               /proc/interrupts. With a duration, counts for that long and shows the vectors \
+               that fired with their rate, to find interrupt storms and dead lines.",
+        run: irqstat,
+    },
+    Command {
+        name: "exceptions",
+        synopsis: &["[<exception> <action>]"],
+        args: ArgSpec::operands(0, 2),
+        help: "Shows or sets what each cpu exception does: log and go on, kill the task, panic \
+               or debug, which prints a backtrace and halts for a debugger. Exceptions go by \
+               name or vector.",
+        run: exceptions,
+    },
+    Command {
+        name: "memprotect",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Lists the mapped memory with its permissions, flagging pages that are writable \
+               and executable.",
+        run: memprotect,
+    },
+    Command {
+        name: "inb",
+        synopsis: &["<port>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Reads a byte from an I/O port. Needs the hwdebug boot flag.",
+        run: inb,
+    },
+    Command {
+        name: "inw",
+        synopsis: &["<port>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Reads a 16-bit word from an I/O port. Needs the hwdebug boot flag.",
+        run: inw,
+    },
+    Command {
+        name: "inl",
+        synopsis: &["<port>"],
+        args: ArgSpec::operands(1, 1),
+        help: "Reads a 32-bit word from an I/O port. Needs the hwdebug boot flag.",
+        run: inl,
+    },
+    Command {
+        name: "outb",
+        synopsis: &["<port> <value>"],
+        args: ArgSpec::operands(2, 2),
+        help: "Writes a byte to an I/O port. Needs the hwdebug boot flag.",
+        run: outb,
+    },
+    Command {
+        name: "outw",
+        synopsis: &["<port> <value>"],
+        args: ArgSpec::operands(2, 2),
+        help: "Writes a 16-bit word to an I/O port. Needs the hwdebug boot flag.",
+        run: outw,
+    },
+    Command {
+        name: "outl",
+        synopsis: &["<port> <value>"],
+        args: ArgSpec::operands(2, 2),
+        help: "Writes a 32-bit word to an I/O port. Needs the hwdebug boot flag.",
+        run: outl,
+    },
+    Command {
+        name: "peek",
+        synopsis: &["[-p] [-w <width>] <addr> [len]"],
+        args: ArgSpec::operands(1, 2).options(&[Opt::Flag("-p"), Opt::Value("-w")]),
+        help: "Dumps len bytes of memory, 64 by default, in hex and ASCII. -p takes a physical \
+               address, -w reads in accesses of 1, 2, 4 or 8 bytes for device registers. \
+               Every page must be mapped. Needs the hwdebug boot flag.",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        synopsis: &["[-p] [-w <width>] <addr> <value>..."],
+        args: ArgSpec::operands(2, MANY).options(&[Opt::Flag("-p"), Opt::Value("-w")]),
+        help: "Writes the values one after the other, each 1 byte wide or as wide as -w says. \
+               -p takes a physical address. Every page must be mapped and writable. Needs the \
+               hwdebug boot flag.",
+        run: poke,
+    },
+    Command {
+        name: "pciconfig",
+        synopsis: &["read <bdf> <offset> [len]", "write [-w <width>] <bdf> <offset> <value>"],
+        args: ArgSpec::operands(3, 6),
+        help: "Dumps len bytes, 64 by default, of the configuration space of a PCI function, \
+               or writes a value of 1, 2 or 4 bytes, 4 by default, to it. bdf is bus:device.\
+               function in hex, like 00:1f.2. Writing needs the hwdebug boot flag.",
+        run: pciconfig,
+    },
+    Command {
+        name: "ping",
+        synopsis: &["[-c <count>] <ip>"],
+        args: ArgSpec::operands(1, 1).options(&[Opt::Value("-c")]),
+        help: "Sends ICMP echo requests, 4 by default, and prints the round trip times.",
+        run: ping,
+    },
+    Command {
+        name: "traceroute",
+        synopsis: &["[-m <max_hops>] <ip>"],
+        args: ArgSpec::operands(1, 1).options(&[Opt::Value("-m")]),
+        help: "Prints the routers on the way to a host, up to 30 hops by default.",
+        run: traceroute,
+    },
+    Command {
+        name: "http",
+        synopsis: &["get <url> <dest>"],
+        args: ArgSpec::operands(3, 3),
+        help: "Fetches a file over HTTP into the VFS.",
+        run: http,
+    },
+    Command {
+        name: "arp",
+        synopsis: &["[-d <ip> | flush]"],
+        args: ArgSpec::operands(0, 1).options(&[Opt::Value("-d")]),
+        help: "Lists the neighbor cache, or removes one or all entries from it.",
+        run: arp,
+    },
+    Command {
+        name: "netstat",
+        synopsis: &["[-i | -s]"],
+        args: ArgSpec::NONE.options(&[Opt::Flag("-i"), Opt::Flag("-s")]),
+        help: "Lists the sockets, or with -i the interface counters, or with -s the protocol \
+               counters.",
+        run: netstat,
+    },
+    Command {
+        name: "pcap",
+        synopsis: &["[start <iface> [KiB] | stop | dump <file>]"],
+        args: ArgSpec::operands(0, 3),
+        help: "Captures the frames of an interface and writes them to a pcap file.",
+        run: capture,
+    },
+];
+/// Commands that manage the command line itself and run in place instead of as a job.
+const BUILTINS: &[Command] = &[
+    Command {
+        name: "jobs",
+        synopsis: &[],
+        args: ArgSpec::NONE,
+        help: "Lists the jobs started from the shell.",
+        run: list_jobs,
+    },
+    Command {
+        name: "fg",
+        synopsis: &["[number]"],
+        args: ArgSpec::operands(0, 1),
+        help: "Moves a job to the foreground, the last one by default.",
+        run: fg,
+    },
+    Command {
+        name: "help",
+        synopsis: &["[cmd]"],
+        args: ArgSpec::operands(0, 1),
+        help: "Lists the commands, or shows how to use one.",
+        run: help,
+    },
+    Command {
+        name: "alias",
+        synopsis: &["[-s] [<name>[=<expansion>]...]"],
+        args: ArgSpec::operands(0, MANY).options(&[Opt::Flag("-s")]),
+        help: "Lists the aliases, shows some of them or defines them. A command starting with an \
+               alias runs its expansion instead. -s also saves the definitions to /etc/profile.",
+        run: alias,
+    },
+    Command {
+        name: "unalias",
+        synopsis: &["[-s] <name>..."],
+        args: ArgSpec::operands(1, MANY).options(&[Opt::Flag("-s")]),
+        help: "Removes aliases, with -s from /etc/profile too.",
+        run: unalias,
+    },
 ];
 
 fn echo(args: Vec<&str>) -> CmdResult {
-    println!("{}", args.join(" "));
+    let mut line = args.join(" ");
+    line.push('\n');
+    let written = syscall::syscall(
+        syscall::SYS_WRITE,
+        [1, line.as_ptr() as u64, line.len() as u64, 0, 0, 0],
+    );
+    if written < 0 {
+        return Err(Error::Str(format!("write failed: {}", written)));
+    }
+
+    Ok(())
+}
+
+fn calc(args: Vec<&str>) -> CmdResult {
+    let expression = args.join(" ");
+    let value = calc::eval(&expression).map_err(|e| Error::Str(format!("{e}")))?;
+    println!("{}", calc::format(value));
 
     Ok(())
 }
@@ -32,83 +647,2011 @@ fn clear(_: Vec<&str>) -> CmdResult {
     Ok(())
 }
 
-pub enum Error {
-    StrSlice(&'static str),
-    Str(String),
+fn beep(args: Vec<&str>) -> CmdResult {
+    let freq = match args.get(0) {
+        Some(freq) => freq.parse().map_err(|_| Error::StrSlice("invalid frequency"))?,
+        None => 440,
+    };
+    let millis = match args.get(1) {
+        Some(millis) => millis.parse().map_err(|_| Error::StrSlice("invalid duration"))?,
+        None => 200,
+    };
+    speaker::beep(freq, Duration::from_millis(millis));
+
+    Ok(())
 }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+fn dmesg(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => print!("{}", klog::read_all()),
+        ["-z", path] => {
+            let log = compress::compress(klog::read_all().as_bytes());
+            vfs::write(path, &log).map_err(|e| fs_error(path, e))?;
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn strace(args: Vec<&str>) -> CmdResult {
+    let Some((cmd, args)) = args.split_first() else {
+        return Err(Error::Usage);
+    };
+    let command =
+        find_cmd(cmd).ok_or_else(|| Error::Str(format!("Could not find command {cmd}")))?;
+
+    let id = task::current_id();
+    let was_traced = task::is_traced(id);
+    let start = klog::position();
+    task::set_traced(id, true);
+    let result = command.call(args.to_vec());
+    task::set_traced(id, was_traced);
+    print!("{}", klog::read_since(start));
+
+    result
+}
+
+fn profile(args: Vec<&str>) -> CmdResult {
+    match args.first().copied() {
+        Some("start") => {
+            let interval = match args.get(1) {
+                Some(interval) => interval
+                    .parse()
+                    .map_err(|_| Error::StrSlice("invalid interval"))?,
+                None => 1,
+            };
+            profile::start(interval);
+            println!("profiling every {} tick(s)", interval.max(1));
+        }
+        Some("stop") => profile::stop(),
+        Some("report") => {
+            let count = match args.get(1) {
+                Some(count) => count.parse().map_err(|_| Error::StrSlice("invalid count"))?,
+                None => 10,
+            };
+            let report = profile::report();
+            let total: usize = report.iter().map(|entry| entry.samples).sum();
+            println!("{} samples", total);
+            for entry in report.iter().take(count) {
+                let percent = entry.samples * 100 / total;
+                println!("{:>6} {:>3}% {}", entry.samples, percent, entry.name);
+            }
+        }
+        _ => {
+            return Err(Error::Usage)
+        }
+    }
+
+    Ok(())
+}
+
+fn heapdump(args: Vec<&str>) -> CmdResult {
+    let (used, free) = without_interrupts(allocator::usage);
+    println!("heap: {} bytes used, {} bytes free", used, free);
+
+    #[cfg(feature = "heap-tracking")]
+    {
+        let count = match args.first() {
+            Some(count) => count.parse().map_err(|_| Error::StrSlice("invalid count"))?,
+            None => 10,
+        };
+        let report = allocator::tracking::report();
+        for site in report.sites.iter().take(count) {
+            print!(
+                "{:>8} bytes in {:>5} allocs ({} total) at ",
+                site.live_bytes, site.live, site.total
+            );
+            match crate::ksyms::resolve(site.addr) {
+                Some(symbol) => println!("{}", symbol),
+                None => println!("{:#x}", site.addr),
+            }
+        }
+        if report.untracked_live > 0 {
+            println!(
+                "{:>8} bytes in {:>5} allocs from untracked sites",
+                report.untracked_bytes, report.untracked_live
+            );
+        }
+    }
+    #[cfg(not(feature = "heap-tracking"))]
+    {
+        let _ = args;
+        println!("allocation tracking is disabled, build with --features heap-tracking");
+    }
+
+    Ok(())
+}
+
+fn ls(args: Vec<&str>) -> CmdResult {
+    let path = args.first().copied().unwrap_or("/");
+    let entries = vfs::read_dir(path).map_err(|e| fs_error(path, e))?;
+    for entry in entries {
+        match entry.metadata.file_type {
+            FileType::Directory => println!("{}/", entry.name),
+            _ => println!("{:<32} {:>8}", entry.name, entry.metadata.size),
+        }
+    }
+
+    Ok(())
+}
+
+fn sync(_: Vec<&str>) -> CmdResult {
+    vfs::sync().map_err(|e| Error::from(KError::from(e)))
+}
+
+/// One side of a `dd` copy. Files are read and written as a whole, the VFS has no partial
+/// reads or writes. The holes of an input file are looked up, for `conv=sparse`.
+enum DdEnd<'a> {
+    Disk(AhciDevice),
+    File {
+        path: &'a str,
+        data: Vec<u8>,
+        holes: Vec<Range<u64>>,
+    },
+}
+
+/// The Xth AHCI disk for `/dev/sdX`.
+fn find_disk(path: &str) -> Result<Arc<AhciDisk>, Error> {
+    let disk = match path.strip_prefix("/dev/sd").map(str::as_bytes) {
+        Some([c @ b'a'..=b'z']) => ahci_driver::disks().get((c - b'a') as usize).cloned(),
+        _ => None,
+    };
+    disk.ok_or_else(|| fs_error(path, Errno::NotFound))
+}
+
+/// Opens `/dev/sdX` as the Xth AHCI disk and `/dev/sdXN` as one of its partitions.
+fn open_disk(path: &str) -> Result<AhciDevice, Error> {
+    ahci_driver::open(path).map_err(|e| fs_error(path, e))
+}
+
+impl<'a> DdEnd<'a> {
+    /// Opens `/dev/sdX` and `/dev/sdXN` as an AHCI disk or partition, anything else as a file.
+    fn open(path: &'a str, input: bool) -> Result<Self, Error> {
+        if path.starts_with("/dev/sd") {
+            return Ok(Self::Disk(open_disk(path)?));
+        }
+        let (data, holes) = if input {
+            let data = vfs::read(path).map_err(|e| fs_error(path, e))?;
+            let holes = file_holes(path, data.len() as u64).map_err(|e| fs_error(path, e))?;
+            (data, holes)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        Ok(Self::File { path, data, holes })
+    }
+
+    fn path(&self) -> &str {
         match self {
-            Self::Str(s) => f.write_str(s),
-            Self::StrSlice(s) => f.write_str(s),
+            Self::Disk(_) => "disk",
+            Self::File { path, .. } => path,
+        }
+    }
+
+    /// True if `range` is in a hole of an input file, it reads as zeros.
+    fn is_hole(&self, range: Range<u64>) -> bool {
+        match self {
+            Self::Disk(_) => false,
+            Self::File { holes, .. } => holes
+                .iter()
+                .any(|hole| hole.start <= range.start && range.end <= hole.end),
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        match self {
+            Self::Disk(dev) => dev.read_at(offset, buf).map(|read| read as usize),
+            Self::File { data, .. } => {
+                let data = data.get(offset as usize..).unwrap_or(&[]);
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(len)
+            }
+        }
+    }
+
+    /// Writes `buf` at `offset`, files are only ever appended to.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), Errno> {
+        match self {
+            Self::Disk(dev) => dev.write_at(offset, buf).map(|_| ()),
+            Self::File { data, .. } => {
+                data.extend_from_slice(buf);
+                Ok(())
+            }
+        }
+    }
+
+    /// Leaves the next `len` bytes of a disk as they are. Files get zeros, they are written as
+    /// a whole.
+    fn skip(&mut self, len: usize) {
+        if let Self::File { data, .. } = self {
+            data.resize(data.len() + len, 0);
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Errno> {
+        match self {
+            Self::Disk(dev) => dev.flush(),
+            Self::File { path, data, .. } => vfs::write(path, data),
+        }
+    }
+}
+
+/// The holes of the file at `path`, found with `SEEK_HOLE` and `SEEK_DATA`.
+fn file_holes(path: &str, size: u64) -> Result<Vec<Range<u64>>, Errno> {
+    let mut holes = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let hole = vfs::seek_hole(path, offset)?;
+        if hole >= size {
+            break;
+        }
+        offset = match vfs::seek_data(path, hole) {
+            Err(Errno::PastEndOfFile) => size,
+            result => result?,
+        };
+        holes.push(hole..offset);
+    }
+    Ok(holes)
+}
+
+fn dd(args: Vec<&str>) -> CmdResult {
+    let (mut input, mut output, mut bs, mut count) = (None, None, 512, None);
+    let mut sparse = false;
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("if", path)) => input = Some(path),
+            Some(("of", path)) => output = Some(path),
+            Some(("bs", size)) => {
+                bs = config::parse_size(size)
+                    .filter(|bs| *bs != 0)
+                    .ok_or(Error::StrSlice("invalid block size"))?
+            }
+            Some(("count", n)) => {
+                count = Some(n.parse::<u64>().map_err(|_| Error::StrSlice("invalid count"))?)
+            }
+            Some(("conv", "sparse")) => sparse = true,
+            _ => return Err(Error::Usage),
+        }
+    }
+    let (Some(input), Some(output)) = (input, output) else {
+        return Err(Error::Usage);
+    };
+    let mut input = DdEnd::open(input, true)?;
+    let mut output = DdEnd::open(output, false)?;
+
+    let start = time::uptime();
+    let mut buf = alloc::vec![0; bs];
+    let (mut blocks, mut copied, mut skipped) = (0, 0, 0);
+    while count.map_or(true, |count| blocks < count) {
+        let offset = blocks * bs as u64;
+        let read = input
+            .read_at(offset, &mut buf)
+            .map_err(|e| fs_error(input.path(), e))?;
+        if read == 0 {
+            break;
+        }
+        if sparse && input.is_hole(offset..offset + read as u64) {
+            output.skip(read);
+            skipped += 1;
+        } else {
+            output
+                .write_at(offset, &buf[..read])
+                .map_err(|e| fs_error(output.path(), e))?;
+        }
+        blocks += 1;
+        copied += read as u64;
+        vga_buffer::set_status(&format!("dd: {} blocks, {} KiB", blocks, copied / 1024));
+        if read < bs {
+            break;
+        }
+    }
+    output.finish().map_err(|e| fs_error(output.path(), e))?;
+
+    let elapsed = time::uptime() - start;
+    println!(
+        "{} blocks ({} bytes) copied in {}.{:03}s",
+        blocks,
+        copied,
+        elapsed.as_secs(),
+        elapsed.subsec_millis()
+    );
+    if skipped != 0 {
+        println!("{} blocks in holes skipped", skipped);
+    }
+    Ok(())
+}
+
+fn mount(args: Vec<&str>) -> CmdResult {
+    let (device, path, read_only) = match args[..] {
+        [] => {
+            for (path, fs) in vfs::mounts() {
+                println!("{:<8} {}", fs, path);
+            }
+            return Ok(());
+        }
+        [device, path] => (device, path, false),
+        [device, path, "ro"] => (device, path, true),
+        _ => return Err(Error::Usage),
+    };
+    let disk = open_disk(device)?;
+    let ext2 = if read_only {
+        Ext2::new_read_only(disk)
+    } else {
+        Ext2::new(disk)
+    };
+    let ext2 = ext2.map_err(|e| fs_error(device, e))?;
+    vfs::mount(path, Arc::new(Ext2Fs::new(ext2))).map_err(|e| fs_error(path, e))
+}
+
+fn umount(args: Vec<&str>) -> CmdResult {
+    let [path] = args[..] else {
+        return Err(Error::Usage);
+    };
+    vfs::unmount(path).map_err(|e| fs_error(path, e))
+}
+
+fn df(_args: Vec<&str>) -> CmdResult {
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>4} {:>8} {:>8}  mounted on",
+        "type", "KiB", "used", "avail", "use%", "inodes", "ifree"
+    );
+    for (path, name) in vfs::mounts() {
+        let stat = vfs::resolve(&path).and_then(|(fs, _)| fs.statfs());
+        // file systems without statistics, like procfs, only get dashes
+        let columns = stat.map_or([None; 6], |stat| {
+            let kib = |blocks: u64| blocks * stat.block_size / 1024;
+            let used = stat.blocks - stat.free_blocks;
+            // like df, of what is available to users, rounded up
+            let usable = used + stat.available_blocks;
+            let percent = if usable == 0 {
+                0
+            } else {
+                (used * 100).div_ceil(usable)
+            };
+            [
+                kib(stat.blocks),
+                kib(used),
+                kib(stat.available_blocks),
+                percent,
+                stat.inodes,
+                stat.free_inodes,
+            ]
+            .map(Some)
+        });
+        let [size, used, avail, percent, inodes, ifree] =
+            columns.map(|column| column.map_or(String::from("-"), |n| format!("{n}")));
+        let percent = if percent == "-" { percent } else { percent + "%" };
+        println!(
+            "{:<8} {:>10} {:>10} {:>10} {:>4} {:>8} {:>8}  {}",
+            name, size, used, avail, percent, inodes, ifree, path
+        );
+    }
+    Ok(())
+}
+
+/// Shows in how many runs of consecutive blocks a file, or each file of a directory, is stored.
+fn fragstat(args: Vec<&str>) -> CmdResult {
+    let [path] = args[..] else {
+        return Err(Error::Usage);
+    };
+    let (fs, relative) = vfs::resolve(path).map_err(|e| fs_error(path, e))?;
+    let metadata = fs.metadata(&relative).map_err(|e| fs_error(path, e))?;
+    let files = match metadata.file_type {
+        FileType::Directory => {
+            let entries = fs.read_dir(&relative).map_err(|e| fs_error(path, e))?;
+            let dir = relative.trim_end_matches('/');
+            entries
+                .into_iter()
+                .filter(|entry| entry.metadata.file_type == FileType::RegularFile)
+                .map(|entry| (format!("{}/{}", dir, entry.name), entry.name))
+                .collect()
+        }
+        _ => alloc::vec![(relative, String::from(path))],
+    };
+
+    println!("{:>8} {:>8}  file", "extents", "blocks");
+    let (mut total_extents, mut total_blocks) = (0, 0);
+    for (file, name) in &files {
+        let extents = fs.extents(file).map_err(|e| fs_error(name, e))?;
+        let blocks: u64 = extents.iter().map(|extent| extent.len).sum();
+        println!("{:>8} {:>8}  {}", extents.len(), blocks, name);
+        total_extents += extents.len();
+        total_blocks += blocks;
+    }
+    if files.len() > 1 {
+        println!(
+            "{} files, {} blocks in {} extents",
+            files.len(),
+            total_blocks,
+            total_extents
+        );
+    }
+    Ok(())
+}
+
+/// Runs the benchmark workloads in a scratch directory of `dir`, all of them by default.
+fn fsbench(args: Vec<&str>) -> CmdResult {
+    let (mut dir, mut size, mut files, mut workloads) = (None, 4 << 20, 256, Vec::new());
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("size", n)) => {
+                size = config::parse_size(n).ok_or(Error::StrSlice("invalid size"))? as u64
+            }
+            Some(("files", n)) => {
+                files = n.parse().map_err(|_| Error::StrSlice("invalid file count"))?
+            }
+            Some(_) => return Err(Error::Usage),
+            None if dir.is_none() => dir = Some(arg),
+            None => match Workload::from_name(arg) {
+                Some(workload) => workloads.push(workload),
+                None => return Err(Error::Str(format!("{arg}: no such workload"))),
+            },
+        }
+    }
+    let Some(dir) = dir else {
+        return Err(Error::Usage);
+    };
+    if workloads.is_empty() {
+        workloads.extend(Workload::ALL);
+    }
+    if tsc::frequency().is_none() {
+        return Err(Error::StrSlice("fsbench: the TSC isn't calibrated yet"));
+    }
+
+    let (fs, relative) = vfs::resolve(dir).map_err(|e| fs_error(dir, e))?;
+    let mut bench = FsBench::new(fs, &relative, size, files).map_err(|e| fs_error(dir, e))?;
+    print_report_header();
+    let mut result = Ok(());
+    for workload in workloads {
+        match bench.run(workload) {
+            Ok(report) => print_report(&report),
+            Err(e) => {
+                result = Err(fs_error(workload.name(), e));
+                break;
+            }
+        }
+    }
+    // the scratch directory goes even if a workload failed
+    bench.finish().map_err(|e| fs_error(dir, e))?;
+    result
+}
+
+/// Times requests straight to a disk, all workloads by default. Writes put back what was read.
+fn blkbench(args: Vec<&str>) -> CmdResult {
+    let (mut device, mut bs, mut count, mut depth) = (None, 4096, 1024, 1);
+    let mut workloads = Vec::new();
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("bs", n)) => bs = config::parse_size(n).ok_or(Error::StrSlice("invalid size"))?,
+            Some(("count", n)) => count = n.parse().map_err(|_| Error::StrSlice("invalid count"))?,
+            Some(("qd", n)) => depth = n.parse().map_err(|_| Error::StrSlice("invalid depth"))?,
+            Some(_) => return Err(Error::Usage),
+            None if device.is_none() => device = Some(arg),
+            None => match BlockWorkload::from_name(arg) {
+                Some(workload) => workloads.push(workload),
+                None => return Err(Error::Str(format!("{arg}: no such workload"))),
+            },
         }
     }
+    let Some(device) = device else {
+        return Err(Error::Usage);
+    };
+    if workloads.is_empty() {
+        workloads.extend(BlockWorkload::ALL);
+    }
+    if tsc::frequency().is_none() {
+        return Err(Error::StrSlice("blkbench: the TSC isn't calibrated yet"));
+    }
+    let disk = find_disk(device)?;
+    let block_error = |e| Error::Str(format!("{device}: {}", KError::from(e)));
+    let mut bench = BlockBench::new(disk, bs, count, depth).map_err(block_error)?;
+
+    let mut reports = Vec::new();
+    print_report_header();
+    for workload in workloads {
+        let report = bench.run(workload).map_err(block_error)?;
+        print_report(&report);
+        reports.push(report);
+    }
+    for report in reports {
+        println!("\n{} latency:", report.name);
+        print_histogram(&report.latencies.histogram());
+    }
+    Ok(())
+}
+
+fn print_report_header() {
+    println!(
+        "{:<10} {:>6} {:>10} {:>8} {:>9} {:>9} {:>9} {:>9}",
+        "workload", "ops", "KiB/s", "ops/s", "p50", "p90", "p99", "max"
+    );
+}
+
+fn print_report(report: &Report) {
+    let latencies = &report.latencies;
+    println!(
+        "{:<10} {:>6} {:>10} {:>8} {:>9} {:>9} {:>9} {:>9}",
+        report.name,
+        latencies.len(),
+        report.bytes_per_sec() / 1024,
+        report.ops_per_sec(),
+        format_latency(latencies.percentile(50)),
+        format_latency(latencies.percentile(90)),
+        format_latency(latencies.percentile(99)),
+        format_latency(latencies.percentile(100)),
+    );
+}
+
+/// Prints the buckets of a latency histogram as bars.
+fn print_histogram(buckets: &[usize]) {
+    const WIDTH: usize = 40;
+    let most = buckets.iter().copied().max().unwrap_or(0).max(1);
+    for (i, &count) in buckets.iter().enumerate() {
+        let below = format_latency(Duration::from_micros(1 << i));
+        let bar = "#".repeat((count * WIDTH).div_ceil(most));
+        println!("  < {:>8} {:>7} {}", below, count, bar);
+    }
 }
 
-pub struct CommandLine {
-    buffer: String,
+fn format_latency(latency: Duration) -> String {
+    match latency.as_micros() {
+        micros @ 0..=9999 => format!("{micros} us"),
+        micros => format!("{}.{} ms", micros / 1000, micros % 1000 / 100),
+    }
 }
 
-impl CommandLine {
-    fn new() -> Self {
-        Self { buffer: String::with_capacity(100) }
+/// Runs the unit tests that are safe to run on a booted kernel, or those whose names start
+/// with `prefix`. `-l` lists them instead.
+fn selftest(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        ["-l"] => selftest::names().for_each(|name| println!("{name}")),
+        [] | [_] => {
+            let prefix = args.first().copied().unwrap_or("");
+            if selftest::run(prefix) == 0 {
+                return Err(Error::StrSlice("selftest: no test matches"));
+            }
+        }
+        _ => return Err(Error::Usage),
     }
 
-    pub fn init(&self) {
-        print!("$ ");
+    Ok(())
+}
+
+/// Shows or sets how often failures are injected into the ext2 disk layer, see `fault`.
+fn fault(args: Vec<&str>) -> CmdResult {
+    if !cfg!(feature = "fault-injection") {
+        println!("fault injection is disabled, build with --features fault-injection");
+        return Ok(());
+    }
+    match args[..] {
+        [] => {
+            for point in Point::ALL {
+                match fault::rate(point) {
+                    0 => print!("{:<10} never", point.name()),
+                    one_in => print!("{:<10} 1 in {one_in}", point.name()),
+                }
+                println!(", {} injected", fault::injected(point));
+            }
+        }
+        ["off"] => fault::clear(),
+        ["seed", seed] => fault::seed(seed.parse().map_err(|_| Error::Usage)?),
+        [point, one_in] => {
+            let point = Point::from_name(point).ok_or(Error::Usage)?;
+            fault::set(point, one_in.parse().map_err(|_| Error::Usage)?);
+        }
+        _ => return Err(Error::Usage),
     }
 
-    pub fn process_key(&mut self, key: DecodedKey) {
-        match key {
-            DecodedKey::RawKey(k) => serial_println!("{:?}", k),
-            DecodedKey::Unicode(char) => {
-                match char {
-                    char @ ('\x20'..='\x7e') => {
-                        print!("{}", char);
-                        self.buffer.push(char);
-                    },
-                    '\n' => {
-                        print!("\n");
-                        self.process_cmd();
+    Ok(())
+}
+
+/// Lists, sets and turns on or off the disk quotas of the ext2 file system holding a path.
+/// Block limits are in KiB, 0 means no limit.
+fn quota(mut args: Vec<&str>) -> CmdResult {
+    let mut kind = QuotaKind::User;
+    if let Some(pos) = args.iter().position(|&arg| arg == "-g") {
+        args.remove(pos);
+        kind = QuotaKind::Group;
+    }
+    let path = *args.last().ok_or(Error::Usage)?;
+    let (fs, _) = vfs::resolve(path).map_err(|e| fs_error(path, e))?;
+    match args[..] {
+        [_] => {
+            let quotas = fs.quotas(kind).map_err(|e| fs_error(path, e))?;
+            let id_name = match kind {
+                QuotaKind::User => "user",
+                QuotaKind::Group => "group",
+            };
+            println!(
+                "{:>6} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8}  grace",
+                id_name, "KiB", "soft", "hard", "inodes", "soft", "hard"
+            );
+            let now = clock::unix_seconds();
+            for quota in quotas {
+                let limits = quota.limits;
+                // the earlier of the two grace periods that are running
+                let grace = [quota.block_grace, quota.inode_grace]
+                    .into_iter()
+                    .filter(|&grace| grace != 0)
+                    .min();
+                let grace = match grace {
+                    None => String::new(),
+                    Some(grace) if grace <= now => String::from("expired"),
+                    Some(grace) => {
+                        let left = grace - now;
+                        let (days, hours) = (left / 86400, left % 86400 / 3600);
+                        format!("{}d{:02}h{:02}m", days, hours, left % 3600 / 60)
                     }
-                    '\x08' => {
-                        if self.buffer.len() > 0 {
-			    print!("\x08");
-                            self.buffer.pop();
-			}
-                    },
-                    
-                    _ => {}
+                };
+                println!(
+                    "{:>6} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8}  {}",
+                    quota.id,
+                    quota.blocks,
+                    limits.block_soft,
+                    limits.block_hard,
+                    quota.inodes,
+                    limits.inode_soft,
+                    limits.inode_hard,
+                    grace
+                );
+            }
+        }
+        ["on", _] => fs.set_quota_tracking(true).map_err(|e| fs_error(path, e))?,
+        ["off", _] => fs.set_quota_tracking(false).map_err(|e| fs_error(path, e))?,
+        ["set", id, block_soft, block_hard, inode_soft, inode_hard, _] => {
+            let id = id.parse::<u16>().map_err(|_| Error::Usage)?;
+            let limit = |value: &str| value.parse::<u64>().map_err(|_| Error::Usage);
+            let limits = QuotaLimits {
+                block_soft: limit(block_soft)?,
+                block_hard: limit(block_hard)?,
+                inode_soft: limit(inode_soft)?,
+                inode_hard: limit(inode_hard)?,
+            };
+            fs.set_quota(kind, id, limits).map_err(|e| fs_error(path, e))?;
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+/// Returns the first AHCI disk holding a share.
+fn share_disk() -> Result<AhciDevice, Error> {
+    ahci_driver::disks()
+        .into_iter()
+        .map(AhciDevice::new)
+        .find_map(|mut dev| fileshare::is_share(&mut dev).then_some(dev))
+        .ok_or(Error::StrSlice("no shared disk, create one with tools/fileshare.py"))
+}
+
+fn open_share() -> Result<Share<AhciDevice>, Error> {
+    Share::open(share_disk()?).map_err(|e| fs_error("share", e))
+}
+
+fn fileshare(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        ["ls"] => {
+            let records = open_share()?
+                .records()
+                .map_err(|e| fs_error("share", e))?;
+            for record in records {
+                match record.kind {
+                    ShareKind::Directory => println!("{}/", record.path),
+                    ShareKind::File => println!("{:<32} {:>8}", record.path, record.size),
                 }
             }
         }
+        ["import"] | ["import", _] => {
+            let dest = args.get(1).copied().unwrap_or("/");
+            let count = fileshare::import(&mut open_share()?, dest)
+                .map_err(|e| fs_error(dest, e))?;
+            println!("imported {} entries", count);
+        }
+        ["export", ref paths @ ..] if !paths.is_empty() => {
+            // replaces whatever the share held before
+            let mut builder = Builder::new();
+            for path in paths {
+                let normalized = vfs::normalize(path).map_err(|e| fs_error(path, e))?;
+                let name = vfs::split_parent(&normalized).1;
+                builder
+                    .add_tree(&normalized, name)
+                    .map_err(|e| fs_error(path, e))?;
+            }
+            let count = builder.count();
+            fileshare::write(&mut share_disk()?, &builder.finish())
+                .map_err(|e| fs_error("share", e))?;
+            println!("exported {} entries", count);
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn cat(args: Vec<&str>) -> CmdResult {
+    if args.is_empty() {
+        return copy_stdin();
+    }
+    for path in args {
+        let data = vfs::read(path).map_err(|e| fs_error(path, e))?;
+        print!("{}", String::from_utf8_lossy(&data));
+    }
+
+    Ok(())
+}
+
+/// Prints `hash(data)` for every file in `args`, like the coreutils `*sum` commands.
+fn checksum(args: Vec<&str>, hash: fn(&[u8]) -> String) -> CmdResult {
+    if args.is_empty() {
+        return Err(Error::Usage);
+    }
+    for path in args {
+        let data = vfs::read(path).map_err(|e| fs_error(path, e))?;
+        println!("{}  {}", hash(&data), path);
+    }
+
+    Ok(())
+}
+
+/// Copies stdin to stdout until end of file, like `cat` in a pipeline.
+fn copy_stdin() -> CmdResult {
+    let mut buf = [0u8; 512];
+    loop {
+        let read = syscall::syscall(
+            syscall::SYS_READ,
+            [0, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0],
+        );
+        if read == 0 {
+            return Ok(());
+        }
+        let read = u64::try_from(read).map_err(|_| Error::Str(format!("read failed: {}", read)))?;
+        let written = syscall::syscall(syscall::SYS_WRITE, [1, buf.as_ptr() as u64, read, 0, 0, 0]);
+        if written < 0 {
+            return Err(Error::Str(format!("write failed: {}", written)));
+        }
     }
+}
+
+fn sha256sum(args: Vec<&str>) -> CmdResult {
+    checksum(args, |data| {
+        crypto::hex(&crypto::sha256(data))
+    })
+}
+
+fn b2sum(args: Vec<&str>) -> CmdResult {
+    checksum(args, |data| {
+        crypto::hex(&crypto::blake2s(data))
+    })
+}
+
+fn crc32(args: Vec<&str>) -> CmdResult {
+    checksum(args, |data| {
+        format!("{:08x}", crypto::crc32(data))
+    })
+}
+
+fn lz4(args: Vec<&str>) -> CmdResult {
+    let (decompress, input, output) = match args[..] {
+        [input, output] => (false, input, output),
+        ["-d", input, output] => (true, input, output),
+        _ => return Err(Error::Usage),
+    };
+    let data = vfs::read(input).map_err(|e| fs_error(input, e))?;
+    let result = if decompress {
+        compress::decompress(&data, allocator::heap_size() / 2)
+            .map_err(|e| Error::Str(format!("{input}: {}", KError::from(e))))?
+    } else {
+        compress::compress(&data)
+    };
+    vfs::write(output, &result).map_err(|e| fs_error(output, e))?;
+    println!("{}: {} -> {} bytes", input, data.len(), result.len());
+
+    Ok(())
+}
+
+fn edit(args: Vec<&str>) -> CmdResult {
+    let [path] = args[..] else {
+        return Err(Error::Usage);
+    };
+    editor::edit(path).map_err(|e| fs_error(path, e))
+}
 
-    fn process_cmd(&mut self) {
-        let mut args = self.buffer.split(' ');
-        if let Some(cmd) = args.next() {
-            if let Some(func) = find_cmd(cmd) {
-                let args: Vec<&str> = args.collect();
+fn tar(args: Vec<&str>) -> CmdResult {
+    let (extract, path, dest) = match args[..] {
+        ["-t", path] => (false, path, "/"),
+        ["-x", path] => (true, path, "/"),
+        ["-x", path, dest] => (true, path, dest),
+        _ => return Err(Error::Usage),
+    };
+    let data = vfs::read(path).map_err(|e| fs_error(path, e))?;
+    let mut archive = Archive::new(RamDisk::new(&data))
+        .map_err(|e| fs_error(path, e))?;
 
-                if let Err(e) = func(args) {
-                    println!("Failed to run {cmd}:\n{}", e);
+    if extract {
+        let (fs, dest) = vfs::resolve(dest).map_err(|e| fs_error(dest, e))?;
+        let count = archive::extract(&mut archive, &*fs, &dest)
+            .map_err(|e| Error::Str(format!("extraction failed: {}", KError::from(e))))?;
+        println!("extracted {} entries", count);
+    } else {
+        let entries = archive
+            .entries()
+            .map_err(|e| fs_error(path, e))?;
+        for entry in entries {
+            match entry.kind {
+                EntryKind::Directory => println!("{:04o} {:>8} {}/", entry.mode, "", entry.path),
+                EntryKind::Symlink(target) => {
+                    println!("{:04o} {:>8} {} -> {}", entry.mode, "", entry.path, target)
                 }
-            } else {
-                println!("Could not find command {cmd}");
+                _ => println!("{:04o} {:>8} {}", entry.mode, entry.size, entry.path),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn kill(args: Vec<&str>) -> CmdResult {
+    let (signal, id) = match args[..] {
+        [id] => (Signal::Term, id),
+        [signal, id] if signal.starts_with('-') => {
+            let signal = Signal::parse(&signal[1..])
+                .ok_or_else(|| Error::Str(format!("unknown signal {}", &signal[1..])))?;
+            (signal, id)
+        }
+        _ => return Err(Error::Usage),
+    };
+    let id = TaskId(id.parse().map_err(|_| Error::StrSlice("invalid task id"))?);
+    match task::send_signal(id, signal) {
+        Ok(()) => Ok(()),
+        Err(SignalError::NoSuchTask) => Err(Error::Str(format!("no task with id {}", id.0))),
+        Err(SignalError::BootTask) => Err(Error::StrSlice("the kernel task can't be signaled")),
+    }
+}
+
+fn list_jobs(_: Vec<&str>) -> CmdResult {
+    for job in jobs::list() {
+        let state = match task::state_of(job.task) {
+            Some(task::TaskState::Stopped) => "Stopped",
+            _ => "Running",
+        };
+        println!("[{}] {:<8} {}", job.number, state, job.command);
+    }
+
+    Ok(())
+}
+
+fn fg(args: Vec<&str>) -> CmdResult {
+    let number = match args.first() {
+        Some(number) => Some(number.parse().map_err(|_| Error::StrSlice("invalid job number"))?),
+        None => None,
+    };
+    let job = jobs::foreground(number).ok_or(Error::StrSlice("no such job"))?;
+    println!("{}", job.command);
+
+    Ok(())
+}
+
+/// Lists the commands with their synopsis, or shows the usage and help of one.
+fn help(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => {
+            for command in COMMANDS.iter().chain(BUILTINS) {
+                println!("{:<12} {}", command.name, command.synopsis.join(" | "));
             }
         }
-        self.buffer.clear();
-        self.init();
+        [name] => {
+            let command = COMMANDS
+                .iter()
+                .chain(BUILTINS)
+                .find(|command| command.name == name)
+                .ok_or_else(|| Error::Str(format!("Could not find command {name}")))?;
+            println!("{}\n\n{}", command.usage(), command.help);
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+/// Lists, shows or defines aliases, saving the definitions to the profile with `-s`.
+fn alias(args: Vec<&str>) -> CmdResult {
+    let save = args.first() == Some(&"-s");
+    let args = &args[save as usize..];
+    if args.is_empty() && !save {
+        for (name, expansion) in alias::list() {
+            println!("{}", alias::definition(&name, &expansion));
+        }
+    }
+    for arg in args {
+        let Some((name, expansion)) = arg.split_once('=') else {
+            let expansion = alias::get(arg).ok_or_else(|| Error::Str(format!("{arg}: no alias")))?;
+            println!("{}", alias::definition(arg, &expansion));
+            continue;
+        };
+        if !alias::is_valid_name(name) {
+            return Err(Error::Str(format!("{name}: invalid alias name")));
+        }
+        args::tokenize(expansion).map_err(|e| Error::Str(format!("{name}: {e}")))?;
+        alias::set(name, expansion);
+        if save {
+            alias::save(name, Some(expansion)).map_err(|e| fs_error(alias::PROFILE, e))?;
+        }
     }
+
+    Ok(())
 }
 
-fn find_cmd(cmd: &str) -> Option<Cmd> {
-    for (name, func) in COMMANDS {
-        if *name == cmd {
-            return Some(func);
+/// Removes aliases, from the profile too with `-s`.
+fn unalias(args: Vec<&str>) -> CmdResult {
+    let save = args.first() == Some(&"-s");
+    for name in &args[save as usize..] {
+        let removed = alias::remove(name);
+        if save {
+            alias::save(name, None).map_err(|e| fs_error(alias::PROFILE, e))?;
+        } else if !removed {
+            return Err(Error::Str(format!("{name}: no alias")));
         }
     }
 
-    None
+    Ok(())
+}
+
+fn sleep(args: Vec<&str>) -> CmdResult {
+    let [millis] = args[..] else {
+        return Err(Error::Usage);
+    };
+    let millis = millis.parse().map_err(|_| Error::StrSlice("invalid duration"))?;
+    timer::sleep(Duration::from_millis(millis));
+
+    Ok(())
+}
+
+fn time(args: Vec<&str>) -> CmdResult {
+    let Some((cmd, args)) = args.split_first() else {
+        return Err(Error::Usage);
+    };
+    let command =
+        find_cmd(cmd).ok_or_else(|| Error::Str(format!("Could not find command {cmd}")))?;
+
+    let start = time::uptime();
+    let result = command.call(args.to_vec());
+    let elapsed = time::uptime() - start;
+    println!("real {}.{:03}s", elapsed.as_secs(), elapsed.subsec_millis());
+
+    result
+}
+
+fn date(_: Vec<&str>) -> CmdResult {
+    let now = clock::now().ok_or(Error::StrSlice("the clock isn't set, configure ntp=<ip>"))?;
+    println!("{}", DateTime::from_unix(now.as_secs()));
+
+    Ok(())
+}
+
+fn font_error(path: &str, error: FontError) -> Error {
+    match error {
+        FontError::BadFormat => Error::Str(format!("{path}: not a font")),
+        FontError::Unsupported => {
+            Error::Str(format!("{path}: only up to 512 glyphs of up to 8x16 are supported"))
+        }
+        FontError::BadMap(line) => Error::Str(format!("{path}:{line}: invalid mapping")),
+        FontError::NoDevice => Error::StrSlice("font memory isn't accessible"),
+    }
+}
+
+fn read_map(path: &str) -> Result<BTreeMap<char, u16>, Error> {
+    let data = vfs::read(path).map_err(|e| fs_error(path, e))?;
+    font::parse_map(&String::from_utf8_lossy(&data)).map_err(|e| font_error(path, e))
+}
+
+fn setfont(args: Vec<&str>) -> CmdResult {
+    let (path, map) = match args[..] {
+        ["-d"] => return font::reset().map_err(|e| font_error("", e)),
+        ["-m", map] => {
+            font::set_map(Some(read_map(map)?));
+            return Ok(());
+        }
+        [path] => (path, None),
+        [path, map] => (path, Some(read_map(map)?)),
+        _ => return Err(Error::Usage),
+    };
+    let path = &font::path(path);
+    let data = vfs::read(path).map_err(|e| fs_error(path, e))?;
+    let font = Font::parse(&data).map_err(|e| font_error(path, e))?;
+    font::load(&font, map).map_err(|e| font_error(path, e))
+}
+
+fn loadkeys(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => {
+            println!("{}", keyboard::layout());
+            Ok(())
+        }
+        [name] => keyboard::set_layout(name)
+            .map_err(|e| Error::Str(format!("{}: {}", keymap::path(name), e))),
+        _ => Err(Error::Usage),
+    }
+}
+
+fn screenshot(args: Vec<&str>) -> CmdResult {
+    let [path] = args[..] else {
+        return Err(Error::Usage);
+    };
+    screenshot::save(path).map_err(|e| fs_error(path, e))
+}
+
+fn show(args: Vec<&str>) -> CmdResult {
+    let [path] = args[..] else {
+        return Err(Error::Usage);
+    };
+    screenshot::show(path).map_err(|e| fs_error(path, e))
+}
+
+fn insmod(args: Vec<&str>) -> CmdResult {
+    let [path] = args[..] else {
+        return Err(Error::Usage);
+    };
+    module::load(path).map_err(|e| Error::Str(format!("{path}: {e}")))
+}
+
+fn rmmod(args: Vec<&str>) -> CmdResult {
+    let [name] = args[..] else {
+        return Err(Error::Usage);
+    };
+    module::unload(name).map_err(|e| Error::Str(format!("{name}: {e}")))
+}
+
+fn lsmod(_: Vec<&str>) -> CmdResult {
+    for module in module::list() {
+        println!("{:<24} {:#x} {:>8}", module.name, module.addr, module.size);
+    }
+
+    Ok(())
+}
+
+fn run_program(args: Vec<&str>) -> CmdResult {
+    let path = args[0];
+    let name = vfs::split_parent(path).1;
+    let mut program_args = args.clone();
+    program_args[0] = name;
+    let status = exec::run(path, &program_args).map_err(|e| Error::Str(format!("{path}: {e}")))?;
+    if status != 0 {
+        println!("{name}: exit status {status}");
+    }
+    Ok(())
+}
+
+fn set_theme(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => {
+            let theme = theme::current();
+            println!(
+                "fg={} bg={} error={} ok={} prompt={}",
+                theme.fg.name(),
+                theme.bg.name(),
+                theme.error.name(),
+                theme.ok.name(),
+                theme.prompt.name()
+            );
+            let names: Vec<&str> = theme::THEMES.iter().map(|(name, _)| *name).collect();
+            println!("available: {}", names.join(" "));
+        }
+        [name] => {
+            let theme = theme::find(name)
+                .ok_or_else(|| Error::Str(format!("no theme called {name}")))?;
+            theme::set(theme);
+        }
+        [part, color] => {
+            let color = Color::parse(color).ok_or(Error::StrSlice("invalid color"))?;
+            let mut theme = theme::current();
+            if !theme.set(part, color) {
+                return Err(Error::Str(format!("{part}: not one of fg, bg, error, ok, prompt")));
+            }
+            theme::set(theme);
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn show_config(_: Vec<&str>) -> CmdResult {
+    let config = config::get();
+    println!("heap={}K", config.heap_size / 1024);
+    println!("loglevel={}", config.log_level.name());
+    println!("quantum={}", config.quantum_ticks);
+    println!("racache={}K", config.readahead_cache_size / 1024);
+    println!("readahead={}K", config.readahead_max / 1024);
+    println!("bell={}", if config.bell { "on" } else { "off" });
+    println!("bellfreq={}", config.bell_frequency);
+    println!("cpufreq={}", config.cpufreq.name());
+    match config.thermal_critical {
+        Some(celsius) => println!("thermcrit={celsius}"),
+        None => println!("thermcrit=auto"),
+    }
+
+    Ok(())
+}
+
+/// Draws the boot report as bars on a timeline starting with the kernel.
+fn stty(args: Vec<&str>) -> CmdResult {
+    let mut settings = tty::CONSOLE.settings();
+    if args.is_empty() {
+        let flag = |set: bool, name: &str| format!("{}{}", if set { "" } else { "-" }, name);
+        println!(
+            "{} {} {}",
+            flag(settings.canonical, "icanon"),
+            flag(settings.echo, "echo"),
+            flag(settings.signals, "isig")
+        );
+        return Ok(());
+    }
+    for arg in args {
+        let (name, set) = match arg.strip_prefix('-') {
+            Some(name) => (name, false),
+            None => (arg, true),
+        };
+        match name {
+            "raw" if set => settings = Settings::RAW,
+            "raw" | "cooked" | "sane" => settings = Settings::COOKED,
+            "icanon" => settings.canonical = set,
+            "echo" => settings.echo = set,
+            "isig" => settings.signals = set,
+            _ => return Err(Error::Str(format!("unknown setting {arg}"))),
+        }
+    }
+    tty::CONSOLE.set_settings(settings);
+
+    Ok(())
+}
+
+fn services(_: Vec<&str>) -> CmdResult {
+    for service in initd::list() {
+        let state = match service.state {
+            initd::State::Running => "running",
+            initd::State::Backoff => "backoff",
+            initd::State::Stopped => "stopped",
+            initd::State::Failed => "failed",
+        };
+        let task = service.task.map_or(0, |task| task.0);
+        println!(
+            "{:<16} {:<8} {:>5} {:>3} {:<10} {}",
+            service.name,
+            state,
+            task,
+            service.restarts,
+            service.restart.name(),
+            service.command
+        );
+    }
+
+    Ok(())
+}
+
+/// Shows `/proc/cpufreq`, or switches to another frequency policy.
+fn cpufreq(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => print!("{}", cpufreq::cpufreq_file()),
+        [name] => cpufreq::set_policy(Policy::parse(name).ok_or(Error::Usage)?),
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+/// Shows the temperature sensors, like `/proc/thermal`.
+fn sensors(_: Vec<&str>) -> CmdResult {
+    let readings = thermal::readings();
+    if readings.is_empty() {
+        return Err(Error::StrSlice("no sensors found"));
+    }
+    print!("{}", thermal::thermal_file());
+
+    Ok(())
+}
+
+fn irqstat(args: Vec<&str>) -> CmdResult {
+    let Some(millis) = args.first() else {
+        print!("{}", interrupts::interrupts_file());
+        return Ok(());
+    };
+    let millis: u64 = millis.parse().map_err(|_| Error::StrSlice("invalid duration"))?;
+    if millis == 0 {
+        return Err(Error::StrSlice("invalid duration"));
+    }
+    let before = interrupts::vector_stats();
+    timer::sleep(Duration::from_millis(millis));
+    let after = interrupts::vector_stats();
+
+    println!("VEC      COUNT     PER SEC NAME");
+    for (old, new) in before.iter().zip(&after) {
+        let count = new.count - old.count;
+        if count > 0 {
+            let rate = count * 1000 / millis;
+            println!("{:>3} {:>10} {:>11} {}", new.vector, count, rate, new.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn exceptions(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => {
+            for &(vector, name) in interrupts::EXCEPTIONS {
+                let action = interrupts::exception_action(vector);
+                println!("{vector:>3} {name:<28} {}", action.name());
+            }
+        }
+        [exception, action] => {
+            let vector = interrupts::exception_vector(exception)
+                .ok_or_else(|| Error::Str(format!("unknown exception {exception}")))?;
+            let action = ExceptionAction::from_name(action)
+                .ok_or_else(|| Error::Str(format!("unknown action {action}")))?;
+            interrupts::set_action(vector, action)
+                .map_err(|_| Error::Str(format!("{exception} can't {}", action.name())))?;
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn poweroff(_: Vec<&str>) -> CmdResult {
+    initd::poweroff();
+
+    Ok(())
+}
+
+/// Suspends to RAM, returns once the machine woke up again.
+fn suspend(_: Vec<&str>) -> CmdResult {
+    println!("Suspending...");
+    suspend::suspend()?;
+    println!("Woke up");
+
+    Ok(())
+}
+
+/// Lists the mapped memory with its permissions, flagging pages that are writable and
+/// executable.
+fn memprotect(_: Vec<&str>) -> CmdResult {
+    let on_off = |on: bool| if on { "on" } else { "off" };
+    println!(
+        "NX {}, WP {}",
+        on_off(memprotect::nx_enabled()),
+        on_off(memprotect::wp_enabled())
+    );
+    let mut exposed = 0;
+    for region in memprotect::regions() {
+        let wx = region.writable && region.executable;
+        if wx {
+            exposed += region.size;
+        }
+        println!(
+            "{:016x}-{:016x} {:>9}K {} {:<16}{}",
+            region.start,
+            region.start + region.size,
+            region.size / 1024,
+            region.permissions().iter().collect::<String>(),
+            region.kind.name(),
+            if wx { " W+X" } else { "" }
+        );
+    }
+    println!("{}K writable and executable", exposed / 1024);
+
+    Ok(())
+}
+
+fn require_hwdebug() -> CmdResult {
+    if !hwdebug::is_enabled() {
+        return Err(Error::StrSlice("raw hardware access needs the hwdebug boot flag"));
+    }
+    Ok(())
+}
+
+/// Parses a number or `calc` expression, up to `max`.
+fn parse_value(arg: &str, max: u64) -> Result<u64, Error> {
+    match calc::eval(arg) {
+        Ok(value) if value <= max => Ok(value),
+        Ok(value) => Err(Error::Str(format!("{value:#x} is larger than {max:#x}"))),
+        Err(e) => Err(Error::Str(format!("{arg}: {e}"))),
+    }
+}
+
+fn port_in(args: Vec<&str>, width: Width) -> CmdResult {
+    require_hwdebug()?;
+    let port = parse_value(args[0], u16::MAX as u64)? as u16;
+    let value = hwdebug::port_read(port, width);
+    println!("{:#0digits$x}", value, digits = 2 + 2 * width.bytes());
+
+    Ok(())
+}
+
+fn port_out(args: Vec<&str>, width: Width) -> CmdResult {
+    require_hwdebug()?;
+    let port = parse_value(args[0], u16::MAX as u64)? as u16;
+    let value = parse_value(args[1], width.max())? as u32;
+    hwdebug::port_write(port, width, value);
+
+    Ok(())
+}
+
+fn inb(args: Vec<&str>) -> CmdResult {
+    port_in(args, Width::Byte)
+}
+
+fn inw(args: Vec<&str>) -> CmdResult {
+    port_in(args, Width::Word)
+}
+
+fn inl(args: Vec<&str>) -> CmdResult {
+    port_in(args, Width::Dword)
+}
+
+fn outb(args: Vec<&str>) -> CmdResult {
+    port_out(args, Width::Byte)
+}
+
+fn outw(args: Vec<&str>) -> CmdResult {
+    port_out(args, Width::Word)
+}
+
+fn outl(args: Vec<&str>) -> CmdResult {
+    port_out(args, Width::Dword)
+}
+
+/// Splits the `-p` and `-w` options of `peek` and `poke` off, returning the address from the
+/// first operand, the virtual address it is at, the access width and the remaining operands.
+fn memory_args<'a>(args: &[&'a str]) -> Result<(u64, u64, Width, Vec<&'a str>), Error> {
+    let mut physical = false;
+    let mut width = Width::Byte;
+    let mut i = 0;
+    while let Some(&arg) = args.get(i) {
+        match arg {
+            "-p" => physical = true,
+            "-w" => {
+                i += 1;
+                width = Width::from_bytes(parse_value(args[i], 8)?)
+                    .ok_or(Error::StrSlice("width must be 1, 2, 4 or 8"))?;
+            }
+            _ => break,
+        }
+        i += 1;
+    }
+    let addr = parse_value(args[i], u64::MAX)?;
+    let virt = if physical {
+        hwdebug::phys_to_virt(addr).map_err(|e| Error::Str(format!("{e}")))?
+    } else {
+        addr
+    };
+    Ok((addr, virt, width, args[i + 1..].to_vec()))
+}
+
+fn peek(args: Vec<&str>) -> CmdResult {
+    require_hwdebug()?;
+    let (addr, virt, width, rest) = memory_args(&args)?;
+    let len = match rest.first() {
+        Some(len) => parse_value(len, hwdebug::MAX_READ as u64)? as usize,
+        None => 64,
+    };
+    let data = hwdebug::read(virt, len, width).map_err(|e| Error::Str(format!("{e}")))?;
+    print!("{}", hwdebug::hexdump(addr, &data));
+
+    Ok(())
+}
+
+fn poke(args: Vec<&str>) -> CmdResult {
+    require_hwdebug()?;
+    let (_, virt, width, rest) = memory_args(&args)?;
+    let values = rest
+        .iter()
+        .map(|value| parse_value(value, width.max()))
+        .collect::<Result<Vec<_>, _>>()?;
+    hwdebug::write(virt, width, &values).map_err(|e| Error::Str(format!("{e}")))
+}
+
+/// Parses the function address and offset of `pciconfig`, checking the function exists and
+/// `len` bytes from the offset on are in its configuration space.
+fn pci_register(bdf: &str, offset: &str, len: u64) -> Result<(u8, u8, u8, u16), Error> {
+    let (bus, device, func) =
+        pci::parse_bdf(bdf).ok_or_else(|| Error::Str(format!("invalid function {bdf}")))?;
+    if !pci::function_exists(bus, device, func) {
+        return Err(Error::Str(format!("no function at {bdf}")));
+    }
+    let size = pci::config_space_size() as u64;
+    let offset = parse_value(offset, size - 1)?;
+    if len == 0 || offset + len > size {
+        return Err(Error::Str(format!("the configuration space ends at {size:#x}")));
+    }
+    Ok((bus, device, func, offset as u16))
+}
+
+fn pciconfig(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        ["read", bdf, offset] | ["read", bdf, offset, _] => {
+            let len = match args.get(3) {
+                Some(len) => parse_value(len, pci::CONFIG_SPACE_SIZE as u64)?,
+                None => 64,
+            };
+            let (bus, device, func, offset) = pci_register(bdf, offset, len)?;
+            let data = pci::read_config_bytes(bus, device, func, offset, len as u16);
+            print!("{}", hwdebug::hexdump(offset as u64, &data));
+        }
+        ["write", bdf, offset, value] => pci_write(bdf, offset, value, "4")?,
+        ["write", "-w", width, bdf, offset, value] => pci_write(bdf, offset, value, width)?,
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+fn pci_write(bdf: &str, offset: &str, value: &str, width: &str) -> CmdResult {
+    require_hwdebug()?;
+    let width = match Width::from_bytes(parse_value(width, 8)?) {
+        Some(width) if width != Width::Qword => width,
+        _ => return Err(Error::StrSlice("width must be 1, 2 or 4")),
+    };
+    let (bus, device, func, offset) = pci_register(bdf, offset, width.bytes() as u64)?;
+    if offset as usize % width.bytes() != 0 {
+        return Err(Error::Str(format!("{offset:#x} isn't aligned to the width")));
+    }
+    let value = parse_value(value, width.max())? as u32;
+    pci::write_config_value(bus, device, func, offset, width.bytes() as u16, value);
+
+    Ok(())
+}
+
+fn bootchart(_: Vec<&str>) -> CmdResult {
+    const NAME_WIDTH: usize = 22;
+    const BAR_WIDTH: usize = 44;
+
+    let entries = bootreport::entries();
+    let start = bootreport::kernel_start();
+    let span = entries.iter().map(|entry| entry.end).max().unwrap_or(start) - start;
+    println!("firmware and bootloader: {}", bootreport::format_cycles(start));
+    if span == 0 {
+        return Ok(());
+    }
+    for entry in entries {
+        let indent = match entry.kind {
+            Kind::Stage => 0,
+            Kind::Step => 1,
+            Kind::Probe => 2,
+        };
+        let from = ((entry.start - start) as u128 * BAR_WIDTH as u128 / span as u128) as usize;
+        let to = ((entry.end - start) as u128 * BAR_WIDTH as u128 / span as u128) as usize;
+        let mut bar = String::with_capacity(BAR_WIDTH);
+        for col in 0..BAR_WIDTH {
+            bar.push(if col >= from && (col < to || col == from) { '#' } else { '.' });
+        }
+        let name: String = "  "
+            .repeat(indent)
+            .chars()
+            .chain(entry.name().chars())
+            .take(NAME_WIDTH)
+            .collect();
+        println!(
+            "{name:<NAME_WIDTH$} {bar} {}",
+            bootreport::format_cycles(entry.end - entry.start)
+        );
+    }
+
+    Ok(())
+}
+
+fn watch(args: Vec<&str>) -> CmdResult {
+    let [interval, cmd, ref args @ ..] = args[..] else {
+        return Err(Error::Usage);
+    };
+    let interval = interval.parse().map_err(|_| Error::StrSlice("invalid interval"))?;
+    let interval = Duration::from_millis(interval);
+    let command =
+        find_cmd(cmd).ok_or_else(|| Error::Str(format!("Could not find command {cmd}")))?;
+
+    // runs until interrupted with Ctrl+C
+    loop {
+        without_interrupts(|| WRITER.lock().clear_screen());
+        println!("Every {}ms: {} {}\n", interval.as_millis(), cmd, args.join(" "));
+        if let Err(e) = command.call(args.to_vec()) {
+            print_error!("Failed to run {cmd}:\n{}", e);
+        }
+        timer::sleep(interval);
+    }
+}
+
+/// How long to wait for the answer to an ICMP probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const PING_PAYLOAD: usize = 56;
+const TRACEROUTE_PROBES: usize = 3;
+
+/// Measures round trips with the TSC, or with the timer tick until the TSC is calibrated.
+struct Stopwatch {
+    cycles: u64,
+    uptime: Duration,
+}
+
+impl Stopwatch {
+    fn start() -> Self {
+        Self {
+            cycles: tsc::read(),
+            uptime: time::uptime(),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        tsc::cycles_to_duration(tsc::read() - self.cycles)
+            .unwrap_or_else(|| time::uptime() - self.uptime)
+    }
+}
+
+fn format_rtt(rtt: Duration) -> String {
+    let micros = rtt.as_micros();
+    format!("{}.{:03} ms", micros / 1000, micros % 1000)
+}
+
+fn parse_addr(host: &str) -> Result<Ipv4Addr, Error> {
+    host.parse().map_err(|_| Error::Str(format!("{host}: not an IPv4 address")))
+}
+
+/// Waits for the reply to, or an error about, echo request `seq` of `socket`, skipping every
+/// other message.
+fn wait_for_echo(socket: &IcmpSocket, seq: u16) -> Option<icmp::Message> {
+    let probe = Some((socket.echo_identifier(), seq));
+    let deadline = time::uptime() + PROBE_TIMEOUT;
+    loop {
+        let remaining = deadline.checked_sub(time::uptime())?;
+        let message = socket.recv(Some(remaining)).ok()?;
+        if (message.kind == icmp::ECHO_REPLY && message.echo_id() == probe)
+            || message.original_echo_id() == probe
+        {
+            return Some(message);
+        }
+    }
+}
+
+fn icmp_error(message: &icmp::Message) -> &'static str {
+    match (message.kind, message.code) {
+        (icmp::TIME_EXCEEDED, _) => "time to live exceeded",
+        (icmp::DEST_UNREACHABLE, 0) => "network unreachable",
+        (icmp::DEST_UNREACHABLE, 1) => "host unreachable",
+        (icmp::DEST_UNREACHABLE, 3) => "port unreachable",
+        (icmp::DEST_UNREACHABLE, _) => "destination unreachable",
+        _ => "unexpected answer",
+    }
+}
+
+fn ping(args: Vec<&str>) -> CmdResult {
+    let (count, host) = match args[..] {
+        [host] => (4, host),
+        ["-c", count, host] => {
+            let count: u16 = count.parse().map_err(|_| Error::StrSlice("invalid count"))?;
+            (count, host)
+        }
+        _ => return Err(Error::Usage),
+    };
+    let dst = parse_addr(host)?;
+    let socket = IcmpSocket::open();
+    let payload = [0x5a; PING_PAYLOAD];
+
+    println!("PING {} {} bytes of data", dst, PING_PAYLOAD);
+    let mut rtts = Vec::new();
+    for seq in 1..=count {
+        let request = icmp::echo_request(socket.echo_identifier(), seq, &payload);
+        let stopwatch = Stopwatch::start();
+        socket.send_to(&request, dst, ipv4::DEFAULT_TTL)?;
+        match wait_for_echo(&socket, seq) {
+            Some(reply) if reply.kind == icmp::ECHO_REPLY => {
+                let rtt = stopwatch.elapsed();
+                println!(
+                    "{} bytes from {}: icmp_seq={} ttl={} time={}",
+                    icmp::HEADER_SIZE + reply.data.len(),
+                    reply.from,
+                    seq,
+                    reply.ttl,
+                    format_rtt(rtt)
+                );
+                rtts.push(rtt);
+            }
+            Some(error) => println!("From {}: icmp_seq={} {}", error.from, seq, icmp_error(&error)),
+            None => println!("Request timeout for icmp_seq={}", seq),
+        }
+        if seq < count {
+            timer::sleep(PING_INTERVAL.saturating_sub(stopwatch.elapsed()));
+        }
+    }
+
+    println!("--- {} ping statistics ---", dst);
+    let lost = count as usize - rtts.len();
+    println!(
+        "{} packets transmitted, {} received, {}% packet loss",
+        count,
+        rtts.len(),
+        lost * 100 / (count as usize).max(1)
+    );
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        println!(
+            "rtt min/avg/max = {} / {} / {}",
+            format_rtt(*min),
+            format_rtt(avg),
+            format_rtt(*max)
+        );
+    }
+
+    Ok(())
+}
+
+fn traceroute(args: Vec<&str>) -> CmdResult {
+    let (max_hops, host) = match args[..] {
+        [host] => (30, host),
+        ["-m", hops, host] => {
+            let hops: u8 = hops.parse().map_err(|_| Error::StrSlice("invalid hop count"))?;
+            (hops, host)
+        }
+        _ => return Err(Error::Usage),
+    };
+    let dst = parse_addr(host)?;
+    let socket = IcmpSocket::open();
+
+    println!("traceroute to {}, {} hops max", dst, max_hops);
+    let mut seq = 0u16;
+    for ttl in 1..=max_hops {
+        print!("{:2} ", ttl);
+        let mut hop = None;
+        let mut reached = false;
+        for _ in 0..TRACEROUTE_PROBES {
+            seq = seq.wrapping_add(1);
+            let request = icmp::echo_request(socket.echo_identifier(), seq, &[0; 32]);
+            let stopwatch = Stopwatch::start();
+            socket.send_to(&request, dst, ttl)?;
+            match wait_for_echo(&socket, seq) {
+                Some(answer) => {
+                    let rtt = stopwatch.elapsed();
+                    if hop != Some(answer.from) {
+                        print!(" {}", answer.from);
+                        hop = Some(answer.from);
+                    }
+                    print!("  {}", format_rtt(rtt));
+                    // only routers on the way answer with time exceeded
+                    reached |= answer.kind != icmp::TIME_EXCEEDED;
+                }
+                None => print!("  *"),
+            }
+        }
+        println!();
+        if reached {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn http_error(url: &str, error: HttpError) -> Error {
+    match error {
+        HttpError::BadUrl => Error::Str(format!("{url}: expected http://<ip>[:port]/<path>")),
+        HttpError::Net(e) => Error::Str(format!("{url}: {e}")),
+        HttpError::BadResponse => Error::Str(format!("{url}: malformed response")),
+        HttpError::Status(status, reason) => Error::Str(format!("{url}: {status} {reason}")),
+        HttpError::TooBig => Error::Str(format!("{url}: response too large")),
+    }
+}
+
+fn http(args: Vec<&str>) -> CmdResult {
+    let ["get", url, dest] = args[..] else {
+        return Err(Error::Usage);
+    };
+    let body = http::get(url).map_err(|e| http_error(url, e))?;
+    vfs::write(dest, &body).map_err(|e| fs_error(dest, e))?;
+    println!("{}: {} bytes", dest, body.len());
+
+    Ok(())
+}
+
+/// Lists the neighbor cache, or removes entries from it.
+fn arp(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => {
+            println!(
+                "{:<16} {:<18} {:<11} {:<6} {}",
+                "Address", "HWaddress", "State", "Iface", "Expires"
+            );
+            for neighbor in arp::neighbors() {
+                let mac = neighbor.mac.map_or(String::from("(incomplete)"), |mac| format!("{mac}"));
+                let state = match neighbor.state {
+                    arp::State::Incomplete => "incomplete",
+                    arp::State::Reachable => "reachable",
+                };
+                println!(
+                    "{:<16} {:<18} {:<11} {:<6} {}s",
+                    format!("{}", neighbor.addr),
+                    mac,
+                    state,
+                    neighbor.device,
+                    neighbor.expires_in.as_secs()
+                );
+            }
+        }
+        ["-d", addr] => {
+            let addr = parse_addr(addr)?;
+            if !arp::remove(addr) {
+                return Err(Error::Str(format!("{addr}: no entry")));
+            }
+        }
+        ["flush"] => println!("{} entries flushed", arp::flush()),
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+/// Lists the sockets, or with `-i` the interface counters, or with `-s` the protocol counters.
+fn netstat(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => {
+            println!(
+                "{:<5} {:>6} {:>6} {:<21} {:<21} {}",
+                "Proto", "Recv-Q", "Send-Q", "Local Address", "Foreign Address", "State"
+            );
+            let row = |proto: &str, socket: SocketInfo| {
+                println!(
+                    "{:<5} {:>6} {:>6} {:<21} {:<21} {}",
+                    proto,
+                    socket.recv_queue,
+                    socket.send_queue,
+                    format!("{}", socket.local),
+                    format!("{}", socket.remote),
+                    socket.state.map_or("", tcp::State::name)
+                );
+            };
+            tcp::sockets().into_iter().for_each(|socket| row("tcp", socket));
+            udp::sockets().into_iter().for_each(|socket| row("udp", socket));
+            icmp::sockets().into_iter().for_each(|socket| row("raw", socket));
+        }
+        ["-i"] => {
+            println!(
+                "{:<6} {:<18} {:>5} {:>8} {:>6} {:>6} {:>8} {:>6}",
+                "Iface", "Address", "MTU", "RX-OK", "RX-ERR", "RX-DRP", "TX-OK", "TX-ERR"
+            );
+            for interface in net::interfaces() {
+                let stats = &interface.stats;
+                println!(
+                    "{:<6} {:<18} {:>5} {:>8} {:>6} {:>6} {:>8} {:>6}",
+                    interface.name(),
+                    format!("{}/{}", interface.addr, interface.prefix),
+                    interface.device.mtu(),
+                    stats.rx_packets.get(),
+                    stats.rx_errors.get(),
+                    stats.rx_dropped.get(),
+                    stats.tx_packets.get(),
+                    stats.tx_errors.get()
+                );
+            }
+        }
+        ["-s"] => {
+            for (protocol, fields) in stats::protocols() {
+                println!("{protocol}:");
+                for (name, value) in fields {
+                    println!("    {value:>10} {name}");
+                }
+            }
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+/// Captures the frames of an interface and writes them to a pcap file.
+fn capture(args: Vec<&str>) -> CmdResult {
+    match args[..] {
+        [] => match pcap::status() {
+            Some(status) => println!(
+                "{}: {}, {} frames, {}/{} bytes, {} dropped",
+                status.device,
+                if status.running { "running" } else { "stopped" },
+                status.frames,
+                status.bytes,
+                status.limit,
+                status.dropped
+            ),
+            None => println!("no capture"),
+        },
+        ["start", name] | ["start", name, _] => {
+            let limit = match args.get(2) {
+                Some(kib) => kib.parse::<usize>().map_err(|_| Error::Usage)? * 1024,
+                None => pcap::DEFAULT_LIMIT,
+            };
+            let interface = net::interfaces()
+                .into_iter()
+                .find(|interface| interface.name() == name)
+                .ok_or_else(|| Error::Str(format!("{name}: no such interface")))?;
+            pcap::start(&interface, limit).map_err(|_| Error::StrSlice("already capturing"))?;
+        }
+        ["stop"] => {
+            if !pcap::stop() {
+                return Err(Error::StrSlice("not capturing"));
+            }
+        }
+        ["dump", path] => {
+            let file = pcap::dump().ok_or(Error::StrSlice("no capture"))?;
+            vfs::write(path, &file).map_err(|e| fs_error(path, e))?;
+            println!("{}: {} bytes", path, file.len());
+        }
+        _ => return Err(Error::Usage),
+    }
+
+    Ok(())
+}
+
+pub enum Error {
+    StrSlice(&'static str),
+    Str(String),
+    /// the arguments don't fit the command, shown as its usage
+    Usage,
+}
+
+impl From<KError> for Error {
+    fn from(error: KError) -> Self {
+        Self::Str(format!("{error}"))
+    }
+}
+
+/// Error of a file system operation on `path`.
+fn fs_error(path: &str, errno: Errno) -> Error {
+    Error::Str(format!("{path}: {}", KError::from(errno)))
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Str(s) => f.write_str(s),
+            Self::StrSlice(s) => f.write_str(s),
+            Self::Usage => f.write_str("invalid arguments"),
+        }
+    }
+}
+
+/// Prints the prompt.
+pub fn prompt() {
+    let hostname = sysconf::hostname();
+    vga_buffer::_print_colored(theme::current().prompt_color(), format_args!("{hostname}$ "));
+}
+
+/// Starts the shell, a task running the profile and then reading command lines from the
+/// console TTY.
+pub fn start() {
+    task::spawn("shell", || {
+        run_profile();
+        loop {
+            prompt();
+            // ^C without a foreground job discards the line and shows a new prompt
+            if let Ok(line) = tty::CONSOLE.read_line() {
+                process_line(&line);
+                jobs::wait_foreground();
+            }
+        }
+    });
+}
+
+/// Runs every line of `/etc/profile` like a line typed into the shell, if there is one. Empty
+/// lines and lines starting with `#` are skipped.
+fn run_profile() {
+    let Ok(data) = vfs::read(alias::PROFILE) else {
+        return;
+    };
+    for line in String::from_utf8_lossy(&data).lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        process_line(line);
+        jobs::wait_foreground();
+    }
+}
+
+/// Runs a command line: a builtin, or a command or pipeline started as a job.
+fn process_line(line: &str) {
+    let mut tokens = match args::tokenize(line).and_then(alias::expand) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            print_error!("{e}");
+            return;
+        }
+    };
+    let background = tokens.last() == Some(&Token::Background);
+    if background {
+        tokens.pop();
+    }
+    if tokens.is_empty() {
+        return;
+    }
+    let stages: Vec<Vec<&str>> = tokens
+        .split(|token| *token == Token::Pipe)
+        .map(|stage| {
+            stage
+                .iter()
+                .filter_map(|token| match token {
+                    Token::Word(word) => Some(word.as_str()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+    if stages.len() > 1 {
+        start_pipeline(stages, background);
+    } else if let Some((cmd, args)) = stages[0].split_first() {
+        let (cmd, args) = (*cmd, args.to_vec());
+        if let Some(builtin) = BUILTINS.iter().find(|builtin| builtin.name == cmd) {
+            if let Err(e) = builtin.call(args) {
+                print_error!("Failed to run {cmd}:\n{}", e);
+            }
+        } else if find_cmd(cmd).is_some() {
+            let job = jobs::start(cmd, args, background);
+            if background {
+                println!("[{}] {}", job.number, job.task.0);
+            }
+        } else {
+            print_error!("Could not find command {cmd}");
+        }
+    }
+}
+
+/// Starts `a | b | ...` as a job. Only commands that run as jobs can be part of a pipeline.
+fn start_pipeline(stages: Vec<Vec<&str>>, background: bool) {
+    let mut commands = Vec::new();
+    for stage in stages {
+        let Some((cmd, args)) = stage.split_first() else {
+            print_error!("Missing command in pipeline");
+            return;
+        };
+        if find_cmd(cmd).is_none() {
+            print_error!("Could not find command {cmd}");
+            return;
+        }
+        commands.push((*cmd, args.to_vec()));
+    }
+    let job = jobs::start_pipeline(commands, background);
+    if background {
+        println!("[{}] {}", job.number, job.task.0);
+    }
+}
+
+fn find_cmd(cmd: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == cmd)
+}
+
+/// Splits `line` into words and runs it with `run_cmd`. Returns whether it succeeded, an empty
+/// line does.
+pub fn run_line(line: &str) -> bool {
+    let words = match args::split(line) {
+        Ok(words) => words,
+        Err(e) => {
+            print_error!("{line}: {e}");
+            return false;
+        }
+    };
+    let mut args = words.iter().map(String::as_str);
+    match args.next() {
+        Some(cmd) => run_cmd(cmd, args.collect()),
+        None => true,
+    }
+}
+
+/// Runs a command in the current task, printing its error if it fails. Returns whether it
+/// succeeded.
+pub fn run_cmd(cmd: &str, args: Vec<&str>) -> bool {
+    match find_cmd(cmd) {
+        Some(command) => match command.call(args) {
+            Ok(()) => true,
+            Err(e) => {
+                print_error!("Failed to run {cmd}:\n{}", e);
+                false
+            }
+        },
+        None => {
+            print_error!("Could not find command {cmd}");
+            false
+        }
+    }
 }
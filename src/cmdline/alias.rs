@@ -0,0 +1,168 @@
+//! Shell aliases.
+//!
+//! `alias name=expansion` makes the shell replace `name` with `expansion` where a command
+//! starts: at the beginning of the line and after every `|`. An expansion isn't expanded again,
+//! so an alias can run a command of the same name. With `-s` the definition is also saved to
+//! `/etc/profile`, which the shell runs when it starts.
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::args::{self, ParseError, Token};
+use crate::{
+    ext::Errno,
+    vfs::{self, VfsResult},
+};
+
+/// Shell script the shell runs when it starts.
+pub const PROFILE: &str = "/etc/profile";
+
+static ALIASES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Whether `name` can be an alias: a word that doesn't need quoting and has no `=`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.contains('=') && args::quote(name) == name
+}
+
+pub fn set(name: &str, expansion: &str) {
+    without_interrupts(|| ALIASES.lock().insert(name.to_string(), expansion.to_string()));
+}
+
+/// Removes an alias, returns whether there was one.
+pub fn remove(name: &str) -> bool {
+    without_interrupts(|| ALIASES.lock().remove(name).is_some())
+}
+
+pub fn get(name: &str) -> Option<String> {
+    without_interrupts(|| ALIASES.lock().get(name).cloned())
+}
+
+/// The aliases, sorted by name.
+pub fn list() -> Vec<(String, String)> {
+    without_interrupts(|| {
+        ALIASES
+            .lock()
+            .iter()
+            .map(|(name, expansion)| (name.clone(), expansion.clone()))
+            .collect()
+    })
+}
+
+/// Replaces the words in command position that are aliases with their expansion.
+pub fn expand(tokens: Vec<Token>) -> Result<Vec<Token>, ParseError> {
+    expand_with(tokens, get)
+}
+
+fn expand_with(
+    tokens: Vec<Token>,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<Token>, ParseError> {
+    let mut expanded = Vec::with_capacity(tokens.len());
+    let mut command_position = true;
+    for token in tokens {
+        match token {
+            Token::Word(word) if command_position => {
+                command_position = false;
+                match lookup(&word) {
+                    Some(expansion) => expanded.extend(args::tokenize(&expansion)?),
+                    None => expanded.push(Token::Word(word)),
+                }
+            }
+            token => {
+                command_position = token == Token::Pipe;
+                expanded.push(token);
+            }
+        }
+    }
+    if let Some(pos) = expanded.iter().position(|token| *token == Token::Background) {
+        if pos + 1 != expanded.len() {
+            return Err(ParseError::MisplacedBackground);
+        }
+    }
+    Ok(expanded)
+}
+
+/// The line defining `name` in a profile.
+pub fn definition(name: &str, expansion: &str) -> String {
+    format!("alias {}={}", name, args::quote(expansion))
+}
+
+/// Whether `line` of a profile defines `name`.
+fn defines(line: &str, name: &str) -> bool {
+    line.trim()
+        .strip_prefix("alias ")
+        .and_then(|rest| rest.trim_start().strip_prefix(name))
+        .is_some_and(|rest| rest.starts_with('='))
+}
+
+/// Rewrites `profile` with the definition of `name` replaced by `expansion`, or removed.
+fn update_profile(profile: &str, name: &str, expansion: Option<&str>) -> String {
+    let mut lines: Vec<String> = profile
+        .lines()
+        .filter(|line| !defines(line, name))
+        .map(String::from)
+        .collect();
+    if let Some(expansion) = expansion {
+        lines.push(definition(name, expansion));
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+/// Saves the definition of `name` to the profile, or removes it with `None`.
+pub fn save(name: &str, expansion: Option<&str>) -> VfsResult<()> {
+    let profile = match vfs::read(PROFILE) {
+        Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+        Err(Errno::NotFound | Errno::NoEntry) => String::new(),
+        Err(e) => return Err(e),
+    };
+    vfs::write(PROFILE, update_profile(&profile, name, expansion).as_bytes())
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_expand() {
+    let lookup = |name: &str| match name {
+        "ll" => Some(String::from("ls -l")),
+        "ls" => Some(String::from("ls /")),
+        "bg" => Some(String::from("sleep 10 &")),
+        _ => None,
+    };
+    let expand = |line: &str| {
+        let tokens = args::tokenize(line).unwrap();
+        expand_with(tokens, lookup).map(|tokens| {
+            tokens
+                .into_iter()
+                .map(|token| match token {
+                    Token::Word(word) => word,
+                    Token::Pipe => String::from("|"),
+                    Token::Background => String::from("&"),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+    };
+
+    assert_eq!(expand("ll /etc"), Ok(String::from("ls -l /etc")));
+    // expansions aren't expanded again, arguments never are
+    assert_eq!(expand("ls ll"), Ok(String::from("ls / ll")));
+    assert_eq!(expand("cat x | ll"), Ok(String::from("cat x | ls -l")));
+    assert_eq!(expand("bg"), Ok(String::from("sleep 10 &")));
+    assert_eq!(expand("bg | cat"), Err(ParseError::MisplacedBackground));
+
+    assert!(is_valid_name("ll"));
+    assert!(!is_valid_name("a=b"));
+    assert!(!is_valid_name("two words"));
+
+    let profile = "# shell\nalias ll='ls -l'\nalias l=ls\n";
+    assert_eq!(
+        update_profile(profile, "ll", Some("ls -l /")),
+        "# shell\nalias l=ls\nalias ll='ls -l /'\n"
+    );
+    assert_eq!(update_profile(profile, "l", None), "# shell\nalias ll='ls -l'\n");
+}
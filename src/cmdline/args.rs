@@ -0,0 +1,188 @@
+//! Splitting command lines into words.
+//!
+//! Words are separated by spaces and tabs. Inside single quotes every character stands for
+//! itself, inside double quotes a backslash escapes `"` and `\`, and outside quotes a backslash
+//! escapes any character. Unquoted `|` separates the commands of a pipeline and an unquoted `&`
+//! at the end runs the line in the background, anywhere else it's an error.
+use alloc::{borrow::Cow, format, string::String, vec::Vec};
+use core::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    /// `|`
+    Pipe,
+    /// `&`
+    Background,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// a quote of this kind isn't closed
+    UnterminatedQuote(char),
+    /// the line ends in a backslash
+    TrailingBackslash,
+    /// `&` isn't the last token
+    MisplacedBackground,
+    /// `|` or `&` where only a single command can run
+    UnexpectedOperator,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnterminatedQuote(quote) => write!(f, "unterminated {quote}"),
+            Self::TrailingBackslash => f.write_str("trailing backslash"),
+            Self::MisplacedBackground => f.write_str("& can only end the line"),
+            Self::UnexpectedOperator => f.write_str("| and & only work in the shell"),
+        }
+    }
+}
+
+/// Splits `line` into words, pipes and the background marker.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    // the word being read, `None` between words so `""` still makes an empty one
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '|' | '&' => {
+                if let Some(word) = word.take() {
+                    tokens.push(Token::Word(word));
+                }
+                match c {
+                    '|' => tokens.push(Token::Pipe),
+                    '&' => tokens.push(Token::Background),
+                    _ => {}
+                }
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('\'')),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(ParseError::UnterminatedQuote('"')),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(ParseError::UnterminatedQuote('"')),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err(ParseError::TrailingBackslash),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(word) = word {
+        tokens.push(Token::Word(word));
+    }
+    let background = tokens.iter().position(|token| *token == Token::Background);
+    if background.is_some_and(|pos| pos + 1 != tokens.len()) {
+        return Err(ParseError::MisplacedBackground);
+    }
+    Ok(tokens)
+}
+
+/// Splits `line` into words, for places that run a single command.
+pub fn split(line: &str) -> Result<Vec<String>, ParseError> {
+    tokenize(line)?
+        .into_iter()
+        .map(|token| match token {
+            Token::Word(word) => Ok(word),
+            Token::Pipe | Token::Background => Err(ParseError::UnexpectedOperator),
+        })
+        .collect()
+}
+
+/// Splits `args` into the options in front and the operands after them. Options start with
+/// `-`. `--` ends the options and is dropped, so operands after it can start with `-` too.
+pub fn split_options<'a>(args: &[&'a str]) -> (Vec<&'a str>, Vec<&'a str>) {
+    let mut options = Vec::new();
+    for (i, &arg) in args.iter().enumerate() {
+        if arg == "--" {
+            return (options, args[i + 1..].to_vec());
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            return (options, args[i..].to_vec());
+        }
+        options.push(arg);
+    }
+    (options, Vec::new())
+}
+
+/// Quotes `word` if `tokenize` wouldn't read it back as one word.
+pub fn quote(word: &str) -> Cow<'_, str> {
+    let special = |c: char| matches!(c, ' ' | '\t' | '|' | '&' | '\'' | '"' | '\\');
+    if !word.is_empty() && !word.contains(special) {
+        return Cow::Borrowed(word);
+    }
+    if !word.contains('\'') {
+        return Cow::Owned(format!("'{word}'"));
+    }
+    let mut quoted = String::from("\"");
+    for c in word.chars() {
+        if matches!(c, '"' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_tokenize() {
+    use alloc::string::ToString;
+    let word = |w: &str| Token::Word(w.to_string());
+
+    assert_eq!(
+        tokenize(r#"cat "my file" 'it''s' a\ b"#),
+        Ok(alloc::vec![word("cat"), word("my file"), word("its"), word("a b")])
+    );
+    assert_eq!(
+        tokenize(r#"echo "a \"b\" \n" '' """#),
+        Ok(alloc::vec![word("echo"), word(r#"a "b" \n"#), word(""), word("")])
+    );
+    assert_eq!(
+        tokenize("ls|grep 'a|b' &"),
+        Ok(alloc::vec![word("ls"), Token::Pipe, word("grep"), word("a|b"), Token::Background])
+    );
+    assert_eq!(tokenize("echo 'a"), Err(ParseError::UnterminatedQuote('\'')));
+    assert_eq!(tokenize("echo \"a\\"), Err(ParseError::UnterminatedQuote('"')));
+    assert_eq!(tokenize("echo a\\"), Err(ParseError::TrailingBackslash));
+    assert_eq!(tokenize("a & b"), Err(ParseError::MisplacedBackground));
+    assert_eq!(
+        split("echo 'a|b' c\\ d"),
+        Ok(alloc::vec!["echo".to_string(), "a|b".to_string(), "c d".to_string()])
+    );
+    assert_eq!(split("echo a|b"), Err(ParseError::UnexpectedOperator));
+
+    assert_eq!(
+        split_options(&["-g", "--", "-x", "y"]),
+        (alloc::vec!["-g"], alloc::vec!["-x", "y"])
+    );
+    assert_eq!(split_options(&["-g", "x", "-y"]), (alloc::vec!["-g"], alloc::vec!["x", "-y"]));
+
+    for w in ["plain", "two words", "it's", "a\"b\\c", "", "a|b&"] {
+        assert_eq!(tokenize(&quote(w)), Ok(alloc::vec![word(w)]));
+    }
+}
@@ -0,0 +1,191 @@
+//! Integer expressions for the `calc` command.
+//!
+//! Values are 64 bits wide and wrap around, so `-1` is `0xffffffffffffffff`. Numbers are
+//! decimal or start with `0x`, `0o` or `0b`, and may contain `_` between digits. The operators
+//! are those of C, from the loosest binding: `|`, `^`, `&`, `<<` and `>>`, `+` and `-`, `*`, `/`
+//! and `%`, then the unary `-` and `~`. Shifts take the shift count modulo 64.
+use alloc::{format, string::String};
+use core::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalcError {
+    /// something that isn't a number, operator or parenthesis, at this byte offset
+    Unexpected(usize),
+    /// the expression ends where a number or `(` was expected
+    UnexpectedEnd,
+    /// a `(` without `)`
+    MissingParen,
+    /// a number that doesn't fit in 64 bits or has invalid digits
+    InvalidNumber(String),
+    DivisionByZero,
+}
+
+impl Display for CalcError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Unexpected(at) => write!(f, "unexpected character at {at}"),
+            Self::UnexpectedEnd => f.write_str("unexpected end of expression"),
+            Self::MissingParen => f.write_str("missing )"),
+            Self::InvalidNumber(number) => write!(f, "invalid number {number}"),
+            Self::DivisionByZero => f.write_str("division by zero"),
+        }
+    }
+}
+
+type CalcResult = Result<u64, CalcError>;
+
+/// Binary operators by how tightly they bind, loosest first.
+const LEVELS: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn rest(&mut self) -> &'a str {
+        self.skip_spaces();
+        &self.text[self.pos..]
+    }
+
+    /// Consumes `token` if it comes next.
+    fn eat(&mut self, token: &str) -> bool {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> CalcResult {
+        let Some(operators) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut value = self.binary(level + 1)?;
+        while let Some(op) = operators.iter().copied().find(|op| self.eat(op)) {
+            let rhs = self.binary(level + 1)?;
+            value = match op {
+                "|" => value | rhs,
+                "^" => value ^ rhs,
+                "&" => value & rhs,
+                "<<" => value.wrapping_shl(rhs as u32),
+                ">>" => value.wrapping_shr(rhs as u32),
+                "+" => value.wrapping_add(rhs),
+                "-" => value.wrapping_sub(rhs),
+                "*" => value.wrapping_mul(rhs),
+                "/" => value.checked_div(rhs).ok_or(CalcError::DivisionByZero)?,
+                _ => value.checked_rem(rhs).ok_or(CalcError::DivisionByZero)?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> CalcResult {
+        if self.eat("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("~") {
+            Ok(!self.unary()?)
+        } else if self.eat("(") {
+            let value = self.binary(0)?;
+            if !self.eat(")") {
+                return Err(CalcError::MissingParen);
+            }
+            Ok(value)
+        } else {
+            self.number()
+        }
+    }
+
+    fn number(&mut self) -> CalcResult {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        if rest.is_empty() {
+            return Err(CalcError::UnexpectedEnd);
+        }
+        if len == 0 {
+            return Err(CalcError::Unexpected(self.pos));
+        }
+        self.pos += len;
+        parse_number(&rest[..len])
+    }
+}
+
+/// Parses a number with an optional `0x`, `0o` or `0b` prefix.
+pub fn parse_number(number: &str) -> CalcResult {
+    let invalid = || CalcError::InvalidNumber(String::from(number));
+    let lower = number.to_ascii_lowercase();
+    let (digits, radix) = match lower.get(..2) {
+        Some("0x") => (&lower[2..], 16),
+        Some("0o") => (&lower[2..], 8),
+        Some("0b") => (&lower[2..], 2),
+        _ => (&lower[..], 10),
+    };
+    let digits: String = digits.chars().filter(|&c| c != '_').collect();
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+    u64::from_str_radix(&digits, radix).map_err(|_| invalid())
+}
+
+/// Evaluates `expression`.
+pub fn eval(expression: &str) -> CalcResult {
+    let mut parser = Parser {
+        text: expression,
+        pos: 0,
+    };
+    let value = parser.binary(0)?;
+    if !parser.rest().is_empty() {
+        return Err(CalcError::Unexpected(parser.pos));
+    }
+    Ok(value)
+}
+
+/// `value` in decimal, as a signed number too if the top bit is set, then in hex, octal and
+/// binary.
+pub fn format(value: u64) -> String {
+    let signed = match value as i64 {
+        negative if negative < 0 => format!(" ({negative})"),
+        _ => String::new(),
+    };
+    format!("{value}{signed} = {value:#x} = {value:#o} = {value:#b}")
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_eval() {
+    assert_eq!(eval("1 + 2 * 3"), Ok(7));
+    assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+    assert_eq!(eval("0xff & ~0xf | 0b1"), Ok(0xf1));
+    assert_eq!(eval("1 << 4 + 1"), Ok(32));
+    assert_eq!(eval("0x1000_0000 >> 0o4"), Ok(0x100_0000));
+    assert_eq!(eval("10 - 3 - 2"), Ok(5));
+    assert_eq!(eval("17 % 5 ^ 3"), Ok(1));
+    assert_eq!(eval("-1"), Ok(u64::MAX));
+    assert_eq!(eval("0xffffffffffffffff + 2"), Ok(1));
+    assert_eq!(eval("1 / 0"), Err(CalcError::DivisionByZero));
+    assert_eq!(eval("(1 + 2"), Err(CalcError::MissingParen));
+    assert_eq!(eval("1 +"), Err(CalcError::UnexpectedEnd));
+    assert_eq!(eval("1 2"), Err(CalcError::Unexpected(2)));
+    assert_eq!(eval("1 $ 2"), Err(CalcError::Unexpected(2)));
+    assert_eq!(eval("0x"), Err(CalcError::InvalidNumber(String::from("0x"))));
+    assert_eq!(eval("0b102"), Err(CalcError::InvalidNumber(String::from("0b102"))));
+    let too_big = "0x1_0000_0000_0000_0000";
+    assert_eq!(eval(too_big), Err(CalcError::InvalidNumber(String::from(too_big))));
+
+    assert_eq!(format(10), "10 = 0xa = 0o12 = 0b1010");
+    assert_eq!(format(u64::MAX).split(" = ").next(), Some("18446744073709551615 (-1)"));
+}
@@ -0,0 +1,157 @@
+//! Shell commands and the arguments they take.
+//!
+//! Every command declares its options and how many operands it takes. `Command::call` checks
+//! the arguments against that before running the command and turns `Error::Usage`, from the
+//! check or from the command itself, into the usage lines built from the synopsis.
+use alloc::{format, string::String, vec::Vec};
+
+use super::{CmdResult, Error};
+
+/// An option a command accepts in front of its operands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opt {
+    /// an option on its own, like `-l`
+    Flag(&'static str),
+    /// an option followed by a value, like `-c <count>`
+    Value(&'static str),
+    /// any option, the command checks them itself
+    Any,
+}
+
+/// The arguments a command takes: options, then between `min` and `max` operands. `--` ends
+/// the options, so operands after it can start with `-`. Commands without options get every
+/// argument as an operand, `--` included.
+#[derive(Debug, Clone, Copy)]
+pub struct ArgSpec {
+    pub options: &'static [Opt],
+    pub min: usize,
+    pub max: usize,
+}
+
+impl ArgSpec {
+    pub const NONE: Self = Self::operands(0, 0);
+
+    pub const fn operands(min: usize, max: usize) -> Self {
+        Self {
+            options: &[],
+            min,
+            max,
+        }
+    }
+
+    pub const fn options(self, options: &'static [Opt]) -> Self {
+        Self { options, ..self }
+    }
+
+    fn find(&self, arg: &str) -> Option<Opt> {
+        self.options
+            .iter()
+            .copied()
+            .find(|opt| match opt {
+                Opt::Flag(name) | Opt::Value(name) => *name == arg,
+                Opt::Any => true,
+            })
+    }
+
+    /// Checks `args`, returning them without the `--` ending the options.
+    pub fn check<'a>(&self, args: Vec<&'a str>) -> Result<Vec<&'a str>, Error> {
+        let mut checked = Vec::with_capacity(args.len());
+        let mut i = 0;
+        if !self.options.is_empty() {
+            while let Some(&arg) = args.get(i) {
+                if arg == "--" {
+                    i += 1;
+                    break;
+                }
+                if !arg.starts_with('-') || arg == "-" {
+                    break;
+                }
+                match self.find(arg) {
+                    Some(Opt::Value(_)) => {
+                        let value = args.get(i + 1).ok_or(Error::Usage)?;
+                        checked.extend([arg, value]);
+                        i += 2;
+                    }
+                    Some(_) => {
+                        checked.push(arg);
+                        i += 1;
+                    }
+                    None => return Err(Error::Str(format!("unknown option {arg}"))),
+                }
+            }
+        }
+        let operands = &args[i.min(args.len())..];
+        if operands.len() < self.min || operands.len() > self.max {
+            return Err(Error::Usage);
+        }
+        checked.extend_from_slice(operands);
+        Ok(checked)
+    }
+}
+
+/// A shell command.
+pub struct Command {
+    pub name: &'static str,
+    /// the forms of its arguments, one usage line each
+    pub synopsis: &'static [&'static str],
+    pub args: ArgSpec,
+    /// what it does, for `help <cmd>`
+    pub help: &'static str,
+    pub run: fn(Vec<&str>) -> CmdResult,
+}
+
+impl Command {
+    /// The usage lines, `usage: <name> <form>` for every form of the synopsis.
+    pub fn usage(&self) -> String {
+        let mut usage = String::new();
+        let forms = if self.synopsis.is_empty() {
+            &[""][..]
+        } else {
+            self.synopsis
+        };
+        for (i, form) in forms.iter().enumerate() {
+            let prefix = if i == 0 { "usage:" } else { "\n      " };
+            usage.push_str(&format!("{prefix} {} {form}", self.name));
+        }
+        String::from(usage.trim_end())
+    }
+
+    /// Checks `args` and runs the command with them.
+    pub fn call(&self, args: Vec<&str>) -> CmdResult {
+        let result = self.args.check(args).and_then(|args| (self.run)(args));
+        match result {
+            Err(Error::Usage) => Err(Error::Str(self.usage())),
+            result => result,
+        }
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_check_args() {
+    let spec = ArgSpec::operands(1, 2).options(&[Opt::Flag("-d"), Opt::Value("-c")]);
+    let check = |args: &[&'static str]| spec.check(args.to_vec()).ok();
+
+    assert_eq!(check(&["a"]), Some(alloc::vec!["a"]));
+    assert_eq!(check(&["-d", "-c", "4", "a", "b"]), Some(alloc::vec!["-d", "-c", "4", "a", "b"]));
+    assert_eq!(check(&["-d", "--", "-a"]), Some(alloc::vec!["-d", "-a"]));
+    assert_eq!(check(&["a", "-d"]), Some(alloc::vec!["a", "-d"]));
+    assert_eq!(check(&[]), None);
+    assert_eq!(check(&["a", "b", "c"]), None);
+    assert_eq!(check(&["-c"]), None);
+    assert_eq!(check(&["-x", "a"]), None);
+
+    let plain = ArgSpec::operands(0, 2);
+    assert_eq!(plain.check(alloc::vec!["-echo", "--"]).ok(), Some(alloc::vec!["-echo", "--"]));
+
+    let command = Command {
+        name: "tar",
+        synopsis: &["-t <archive>", "-x <archive> [dest]"],
+        args: ArgSpec::NONE,
+        help: "",
+        run: |_| Ok(()),
+    };
+    assert_eq!(
+        command.usage(),
+        "usage: tar -t <archive>\n       tar -x <archive> [dest]"
+    );
+}
@@ -0,0 +1,21 @@
+//! Data compression.
+//!
+//! LZ4 is used to keep crash dumps and saved logs small, and for compressed initrds. Its frame
+//! format is the one of the `lz4` tool, so data can be compressed and checked on the host.
+pub(crate) mod lz4;
+
+pub use lz4::{compress, decompress, is_lz4, xxh32};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressError {
+    /// The data doesn't start with a known magic number.
+    BadMagic,
+    /// A frame uses a version or feature that isn't implemented, like dictionaries.
+    Unsupported,
+    /// The compressed data is truncated or damaged.
+    Corrupted,
+    /// A checksum in the frame didn't match.
+    Checksum,
+    /// The decompressed data would exceed the given limit.
+    TooBig,
+}
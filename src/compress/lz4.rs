@@ -0,0 +1,355 @@
+//! LZ4 frames and blocks.
+//!
+//! Compression is greedy with a small hash table, fast and good enough for logs and dumps.
+//! Decompression handles everything `lz4` produces except dictionaries: the frame format with
+//! optional checksums and content size, linked blocks and the legacy format (`lz4 -l`) used for
+//! Linux initrds.
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::CompressError;
+
+const MAGIC: u32 = 0x184d_2204;
+const LEGACY_MAGIC: u32 = 0x184c_2102;
+/// Skippable frames have magic numbers `0x184d2a50` to `0x184d2a5f`.
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+const SKIPPABLE_MASK: u32 = 0xffff_fff0;
+const LEGACY_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+const BLOCK_SIZE: usize = 64 * 1024;
+/// Block maximum size id 4 means 64 KiB.
+const BLOCK_SIZE_ID: u8 = 4;
+
+const MIN_MATCH: usize = 4;
+/// A match has to start at least this far from the end of a block.
+const MF_LIMIT: usize = 12;
+/// The last bytes of a block are always literals.
+const LAST_LITERALS: usize = 5;
+const HASH_BITS: u32 = 12;
+
+/// Whether `data` starts like a LZ4 frame.
+pub fn is_lz4(data: &[u8]) -> bool {
+    matches!(read_u32(data, 0), Some(MAGIC | LEGACY_MAGIC))
+}
+
+/// Compresses `data` into a single frame with a content checksum.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    out.extend_from_slice(&MAGIC.to_le_bytes());
+    // version 1, independent blocks, content checksum
+    let descriptor = [0x64, BLOCK_SIZE_ID << 4];
+    out.extend_from_slice(&descriptor);
+    out.push((xxh32(&descriptor, 0) >> 8) as u8);
+
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    for chunk in data.chunks(BLOCK_SIZE) {
+        block.clear();
+        compress_block(chunk, &mut block);
+        if block.len() < chunk.len() {
+            out.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            out.extend_from_slice(&block);
+        } else {
+            // incompressible, stored as is
+            out.extend_from_slice(&(chunk.len() as u32 | 1 << 31).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+    }
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&xxh32(data, 0).to_le_bytes());
+    out
+}
+
+/// Decompresses all frames in `data`. Fails with `TooBig` instead of producing more than
+/// `limit` bytes.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, CompressError> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let magic = read_u32(data, pos).ok_or(CompressError::Corrupted)?;
+        pos += 4;
+        pos = match magic {
+            MAGIC => decompress_frame(data, pos, &mut out, limit)?,
+            LEGACY_MAGIC => decompress_legacy(data, pos, &mut out, limit)?,
+            _ if magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC => {
+                let len = read_u32(data, pos).ok_or(CompressError::Corrupted)? as usize;
+                pos + 4 + len
+            }
+            _ if pos == 4 => return Err(CompressError::BadMagic),
+            _ => return Err(CompressError::Corrupted),
+        };
+    }
+    Ok(out)
+}
+
+/// Decompresses the frame whose descriptor starts at `pos`, returns where the frame ends.
+fn decompress_frame(
+    data: &[u8],
+    mut pos: usize,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<usize, CompressError> {
+    let start = pos;
+    let [flags, _block_size] = *take::<2>(data, &mut pos)?;
+    if flags >> 6 != 1 || flags & 0x01 != 0 {
+        return Err(CompressError::Unsupported);
+    }
+    let block_checksum = flags & 0x10 != 0;
+    let content_size = flags & 0x08 != 0;
+    let content_checksum = flags & 0x04 != 0;
+    if content_size {
+        pos += 8;
+    }
+    let header_checksum = *take::<1>(data, &mut pos)?;
+    if header_checksum[0] != (xxh32(&data[start..pos - 1], 0) >> 8) as u8 {
+        return Err(CompressError::Checksum);
+    }
+
+    let frame_start = out.len();
+    loop {
+        let size = u32::from_le_bytes(*take::<4>(data, &mut pos)?);
+        if size == 0 {
+            break;
+        }
+        let len = (size & !(1 << 31)) as usize;
+        let block = data.get(pos..pos + len).ok_or(CompressError::Corrupted)?;
+        pos += len;
+        if block_checksum {
+            let checksum = u32::from_le_bytes(*take::<4>(data, &mut pos)?);
+            if checksum != xxh32(block, 0) {
+                return Err(CompressError::Checksum);
+            }
+        }
+        if size & 1 << 31 != 0 {
+            if out.len() + block.len() > limit {
+                return Err(CompressError::TooBig);
+            }
+            out.extend_from_slice(block);
+        } else {
+            // linked blocks may refer back into earlier blocks of the frame, which
+            // `decompress_block` allows as they are in `out` as well
+            decompress_block(block, out, frame_start, limit)?;
+        }
+    }
+    if content_checksum {
+        let checksum = u32::from_le_bytes(*take::<4>(data, &mut pos)?);
+        if checksum != xxh32(&out[frame_start..], 0) {
+            return Err(CompressError::Checksum);
+        }
+    }
+    Ok(pos)
+}
+
+/// Decompresses legacy blocks starting at `pos` until the data ends or another frame starts.
+fn decompress_legacy(
+    data: &[u8],
+    mut pos: usize,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<usize, CompressError> {
+    while let Some(len) = read_u32(data, pos) {
+        if len == MAGIC || len == LEGACY_MAGIC || len & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+            break;
+        }
+        pos += 4;
+        let len = len as usize;
+        let block = data.get(pos..pos + len).ok_or(CompressError::Corrupted)?;
+        pos += len;
+        let start = out.len();
+        decompress_block(block, out, start, limit)?;
+        if out.len() - start > LEGACY_BLOCK_SIZE {
+            return Err(CompressError::Corrupted);
+        }
+    }
+    Ok(pos)
+}
+
+/// Decompresses a block, appending to `out`. Matches may reach back to `out[window_start..]`.
+fn decompress_block(
+    block: &[u8],
+    out: &mut Vec<u8>,
+    window_start: usize,
+    limit: usize,
+) -> Result<(), CompressError> {
+    let mut pos = 0;
+    loop {
+        let token = *block.get(pos).ok_or(CompressError::Corrupted)?;
+        pos += 1;
+
+        let literals = read_length(block, &mut pos, (token >> 4) as usize)?;
+        let literals = block
+            .get(pos..pos + literals)
+            .ok_or(CompressError::Corrupted)?;
+        if out.len() + literals.len() > limit {
+            return Err(CompressError::TooBig);
+        }
+        out.extend_from_slice(literals);
+        pos += literals.len();
+        if pos == block.len() {
+            return Ok(());
+        }
+
+        let offset = u16::from_le_bytes(*take::<2>(block, &mut pos)?) as usize;
+        let len = read_length(block, &mut pos, (token & 0xf) as usize)? + MIN_MATCH;
+        if offset == 0 || offset > out.len() - window_start {
+            return Err(CompressError::Corrupted);
+        }
+        if out.len() + len > limit {
+            return Err(CompressError::TooBig);
+        }
+        // matches may overlap the bytes they produce, so copy one at a time
+        let from = out.len() - offset;
+        for i in 0..len {
+            out.push(out[from + i]);
+        }
+    }
+}
+
+/// Reads a literal or match length: the value in the token, continued by bytes while 255.
+fn read_length(block: &[u8], pos: &mut usize, token: usize) -> Result<usize, CompressError> {
+    let mut len = token;
+    if token == 15 {
+        loop {
+            let byte = *block.get(*pos).ok_or(CompressError::Corrupted)?;
+            *pos += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], offset_and_len: Option<(u16, usize)>) {
+    let match_len = offset_and_len.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((literals.len().min(15) << 4 | match_len.min(15)) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = offset_and_len {
+        out.extend_from_slice(&offset.to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compresses `input` into a single block appended to `out`.
+fn compress_block(input: &[u8], out: &mut Vec<u8>) {
+    // positions of the last occurrence of a hash, `usize::MAX` for none yet
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MF_LIMIT < input.len() {
+        let sequence = read_u32(input, pos).unwrap();
+        let candidate = core::mem::replace(&mut table[hash(sequence)], pos);
+        let found = candidate != usize::MAX
+            && pos - candidate <= u16::MAX as usize
+            && read_u32(input, candidate) == Some(sequence);
+        if !found {
+            pos += 1;
+            continue;
+        }
+
+        let max_len = input.len() - LAST_LITERALS - pos;
+        let mut len = MIN_MATCH;
+        while len < max_len && input[candidate + len] == input[pos + len] {
+            len += 1;
+        }
+        write_sequence(out, &input[anchor..pos], Some(((pos - candidate) as u16, len)));
+        pos += len;
+        anchor = pos;
+    }
+    write_sequence(out, &input[anchor..], None);
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn take<'a, const N: usize>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8; N], CompressError> {
+    let bytes = data.get(*pos..*pos + N).ok_or(CompressError::Corrupted)?;
+    *pos += N;
+    Ok(bytes.try_into().unwrap())
+}
+
+const PRIME1: u32 = 2_654_435_761;
+const PRIME2: u32 = 2_246_822_519;
+const PRIME3: u32 = 3_266_489_917;
+const PRIME4: u32 = 668_265_263;
+const PRIME5: u32 = 374_761_393;
+
+/// xxHash32, the checksum of LZ4 frames.
+pub fn xxh32(data: &[u8], seed: u32) -> u32 {
+    fn round(acc: u32, input: u32) -> u32 {
+        acc.wrapping_add(input.wrapping_mul(PRIME2))
+            .rotate_left(13)
+            .wrapping_mul(PRIME1)
+    }
+
+    let mut chunks = data.chunks_exact(16);
+    let mut hash = if data.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+            seed.wrapping_add(PRIME2),
+            seed,
+            seed.wrapping_sub(PRIME1),
+        ];
+        for chunk in &mut chunks {
+            for (i, acc) in v.iter_mut().enumerate() {
+                *acc = round(*acc, read_u32(chunk, i * 4).unwrap());
+            }
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(PRIME5)
+    };
+    hash = hash.wrapping_add(data.len() as u32);
+
+    let mut words = chunks.remainder().chunks_exact(4);
+    for word in &mut words {
+        hash = hash.wrapping_add(read_u32(word, 0).unwrap().wrapping_mul(PRIME3));
+        hash = hash.rotate_left(17).wrapping_mul(PRIME4);
+    }
+    for byte in words.remainder() {
+        hash = hash.wrapping_add((*byte as u32).wrapping_mul(PRIME5));
+        hash = hash.rotate_left(11).wrapping_mul(PRIME1);
+    }
+
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(PRIME2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(PRIME3);
+    hash ^= hash >> 16;
+    hash
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_lz4_roundtrip() {
+    let mut data = Vec::new();
+    for i in 0..20_000u32 {
+        data.extend_from_slice(b"kernel log line ");
+        data.extend_from_slice(&(i % 251).to_le_bytes());
+    }
+    let compressed = compress(&data);
+    assert!(compressed.len() < data.len() / 4);
+    assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    assert_eq!(decompress(&compressed, 100), Err(CompressError::TooBig));
+    assert_eq!(decompress(&compress(b""), 0).unwrap(), b"");
+}
@@ -0,0 +1,133 @@
+//! Kernel tunables.
+//!
+//! Every tunable has a compile-time default below, some of which change with cargo features.
+//! The defaults can be overridden on the kernel command line, e.g. `heap=4M quantum=10
+//! loglevel=debug`. Invalid values are ignored. Sizes that have to be known at compile time,
+//! like the kernel log ring, can't be overridden.
+use core::time::Duration;
+
+use spin::Once;
+
+use crate::{bootargs, cpufreq::Policy, klog::Level, mem::PAGE_SIZE};
+
+/// Default heap size, 4 MiB with the `large-heap` feature and 1 MiB otherwise.
+pub const HEAP_SIZE: usize = if cfg!(feature = "large-heap") {
+    1024 * PAGE_SIZE
+} else {
+    256 * PAGE_SIZE
+};
+/// Size of the kernel log ring.
+pub const LOG_SIZE: usize = 16 * 1024;
+/// Default number of timer ticks a task may run before it gets preempted.
+pub const QUANTUM_TICKS: u64 = 5;
+/// Default number of bytes kept in ext2 read-ahead windows.
+pub const READAHEAD_CACHE_SIZE: usize = 512 * 1024;
+/// Smallest and default largest number of bytes read ahead of a sequentially read file.
+pub const READAHEAD_MIN: u64 = 16 * 1024;
+pub const READAHEAD_MAX: u64 = 128 * 1024;
+pub const BELL_FREQUENCY: u32 = 880;
+pub const BELL_DURATION: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// bytes mapped for the heap, a multiple of the page size (`heap=`)
+    pub heap_size: usize,
+    /// messages above this level aren't logged (`loglevel=`)
+    pub log_level: Level,
+    /// timer ticks per time slice (`quantum=`)
+    pub quantum_ticks: u64,
+    /// bytes kept in ext2 read-ahead windows (`racache=`)
+    pub readahead_cache_size: usize,
+    /// largest read-ahead window (`readahead=`)
+    pub readahead_max: u64,
+    /// whether `\x07` beeps (`bell=on|off`)
+    pub bell: bool,
+    /// bell frequency in Hz (`bellfreq=`)
+    pub bell_frequency: u32,
+    /// CPU frequency policy at boot (`cpufreq=performance|powersave|ondemand`)
+    pub cpufreq: Policy,
+    /// degrees Celsius at which every sensor shuts the machine down, instead of the sensors'
+    /// own critical temperatures (`thermcrit=`)
+    pub thermal_critical: Option<i32>,
+}
+
+impl Config {
+    pub const DEFAULT: Self = Self {
+        heap_size: HEAP_SIZE,
+        log_level: Level::Info,
+        quantum_ticks: QUANTUM_TICKS,
+        readahead_cache_size: READAHEAD_CACHE_SIZE,
+        readahead_max: READAHEAD_MAX,
+        bell: true,
+        bell_frequency: BELL_FREQUENCY,
+        cpufreq: Policy::Ondemand,
+        thermal_critical: None,
+    };
+
+    /// Applies the overrides on the kernel command line to the defaults.
+    fn from_bootargs() -> Self {
+        let mut config = Self::DEFAULT;
+        if let Some(size) = bootargs::get("heap").and_then(parse_size) {
+            config.heap_size = size.max(PAGE_SIZE).next_multiple_of(PAGE_SIZE);
+        }
+        if let Some(level) = bootargs::get("loglevel").and_then(Level::parse) {
+            config.log_level = level;
+        }
+        if let Some(ticks) = bootargs::get("quantum").and_then(|ticks| ticks.parse().ok()) {
+            config.quantum_ticks = ticks;
+        }
+        if let Some(size) = bootargs::get("racache").and_then(parse_size) {
+            config.readahead_cache_size = size;
+        }
+        if let Some(size) = bootargs::get("readahead").and_then(parse_size) {
+            config.readahead_max = (size as u64).max(READAHEAD_MIN);
+        }
+        match bootargs::get("bell") {
+            Some("on") => config.bell = true,
+            Some("off") => config.bell = false,
+            _ => {}
+        }
+        if let Some(freq) = bootargs::get("bellfreq").and_then(|freq| freq.parse().ok()) {
+            config.bell_frequency = freq;
+        }
+        if let Some(policy) = bootargs::get("cpufreq").and_then(Policy::parse) {
+            config.cpufreq = policy;
+        }
+        if let Some(celsius) = bootargs::get("thermcrit").and_then(|c| c.parse().ok()) {
+            config.thermal_critical = Some(celsius);
+        }
+        config
+    }
+}
+
+static CONFIG: Once<Config> = Once::new();
+
+/// Reads the overrides from the kernel command line. Runs first thing during boot, so that
+/// `get` never has to parse them from an interrupt handler.
+pub fn init() {
+    CONFIG.call_once(Config::from_bootargs);
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.call_once(Config::from_bootargs)
+}
+
+/// Parses a size like `512`, `4K` or `1M`.
+pub fn parse_size(size: &str) -> Option<usize> {
+    let (number, unit) = match size.strip_suffix(['K', 'k']) {
+        Some(number) => (number, 1024),
+        None => match size.strip_suffix('M') {
+            Some(number) => (number, 1024 * 1024),
+            None => (size, 1),
+        },
+    };
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse_size() {
+    assert_eq!(parse_size("512"), Some(512));
+    assert_eq!(parse_size("4K"), Some(4096));
+    assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+    assert_eq!(parse_size("M"), None);
+}
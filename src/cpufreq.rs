@@ -0,0 +1,334 @@
+//! CPU frequency scaling and idle states.
+//!
+//! What the CPU supports is read from CPUID. Frequencies are only changed on Intel CPUs with
+//! Enhanced SpeedStep, by writing the wanted bus ratio to `IA32_PERF_CTL`; the range of ratios
+//! comes from `MSR_PLATFORM_INFO`, which every such CPU since Nehalem has. Elsewhere, QEMU
+//! without KVM for one, the policy is kept but changes nothing.
+//!
+//! Every `SAMPLE_TICKS` the timer interrupt lets the policy pick a ratio: the highest for
+//! `performance`, the lowest for `powersave`, and for `ondemand` the highest while the CPU was
+//! busy for most of the last sample and the lowest while it was mostly idle. The actual
+//! frequency over the sample is measured with `APERF`/`MPERF` where they exist. While idle,
+//! `powersave` also asks `mwait` for the deepest C-state the CPU has.
+use alloc::{format, string::String};
+use core::arch::x86_64::__cpuid;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::{Mutex, Once};
+use x86_64::registers::model_specific::Msr;
+
+use crate::{config, idle, tsc};
+
+const IA32_MPERF: u32 = 0xe7;
+const IA32_APERF: u32 = 0xe8;
+const IA32_PERF_STATUS: u32 = 0x198;
+const IA32_PERF_CTL: u32 = 0x199;
+const MSR_PLATFORM_INFO: u32 = 0xce;
+
+/// Bus clock the ratios are multiples of, on every CPU with `MSR_PLATFORM_INFO`.
+const BUS_MHZ: u64 = 100;
+/// Ticks between decisions of the policy.
+const SAMPLE_TICKS: u64 = 100;
+/// Busy percentage above which `ondemand` goes to the highest frequency.
+const UP_THRESHOLD: u64 = 80;
+/// Busy percentage below which `ondemand` goes to the lowest frequency.
+const DOWN_THRESHOLD: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Performance,
+    Powersave,
+    Ondemand,
+}
+
+impl Policy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Performance => "performance",
+            Self::Powersave => "powersave",
+            Self::Ondemand => "ondemand",
+        }
+    }
+
+    const ALL: [Policy; 3] = [Self::Performance, Self::Powersave, Self::Ondemand];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|policy| policy.name() == name)
+    }
+}
+
+/// What the CPU can do, from CPUID.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    /// a GenuineIntel CPU, the model specific registers used here are Intel's
+    pub intel: bool,
+    /// Enhanced SpeedStep, P-states set through `IA32_PERF_CTL`
+    pub speedstep: bool,
+    pub turbo: bool,
+    /// `APERF` and `MPERF`, counting actual and nominal cycles
+    pub aperf_mperf: bool,
+    /// digital thermal sensors of the core and of the package
+    pub thermal_sensor: bool,
+    pub package_thermal_sensor: bool,
+    /// lowest and highest non-turbo bus ratio, if they could be read
+    pub ratios: Option<(u8, u8)>,
+    /// base and highest frequency in MHz from CPUID leaf 0x16
+    pub base_mhz: Option<u32>,
+    pub max_mhz: Option<u32>,
+    pub mwait: bool,
+    /// number of `mwait` sub-states of C0 to C7, zero for the ones that don't exist
+    pub c_states: [u8; 8],
+}
+
+impl Capabilities {
+    fn detect() -> Self {
+        let vendor = unsafe { __cpuid(0) };
+        let max_leaf = vendor.eax;
+        // "GenuineIntel"
+        let intel = (vendor.ebx, vendor.edx, vendor.ecx) == (0x756e_6547, 0x4965_6e69, 0x6c65_746e);
+        let features = unsafe { __cpuid(1) };
+        let mut caps = Self {
+            intel,
+            speedstep: features.ecx & 1 << 7 != 0,
+            mwait: features.ecx & 1 << 3 != 0,
+            ..Self::default()
+        };
+        if max_leaf >= 5 && caps.mwait {
+            let substates = unsafe { __cpuid(5) }.edx;
+            for (state, count) in caps.c_states.iter_mut().enumerate() {
+                *count = (substates >> (state * 4) & 0xf) as u8;
+            }
+        }
+        if max_leaf >= 6 {
+            let power = unsafe { __cpuid(6) };
+            caps.thermal_sensor = power.eax & 1 != 0;
+            caps.turbo = power.eax & 1 << 1 != 0;
+            caps.package_thermal_sensor = power.eax & 1 << 6 != 0;
+            caps.aperf_mperf = power.ecx & 1 != 0;
+        }
+        if max_leaf >= 0x16 {
+            let frequency = unsafe { __cpuid(0x16) };
+            caps.base_mhz = Some(frequency.eax & 0xffff).filter(|mhz| *mhz != 0);
+            caps.max_mhz = Some(frequency.ebx & 0xffff).filter(|mhz| *mhz != 0);
+        }
+        // leaf 0xb came with Nehalem, like the platform info MSR
+        if intel && caps.speedstep && max_leaf >= 0xb {
+            let info = unsafe { Msr::new(MSR_PLATFORM_INFO).read() };
+            let (min, max) = ((info >> 40) as u8, (info >> 8) as u8);
+            caps.ratios = Some((min, max)).filter(|_| min != 0 && min <= max);
+        }
+        caps
+    }
+
+    /// The deepest C-state `mwait` can enter, C1 if there is no deeper one.
+    pub fn deepest_c_state(&self) -> usize {
+        (1..8)
+            .rev()
+            .find(|&state| self.c_states[state] != 0)
+            .unwrap_or(1)
+    }
+}
+
+static CAPABILITIES: Once<Capabilities> = Once::new();
+/// Index of the policy in `Policy::ALL`, read from the timer interrupt.
+static POLICY: AtomicU8 = AtomicU8::new(Policy::Ondemand as u8);
+/// The ratio last written to `IA32_PERF_CTL`, zero before the first.
+static REQUESTED_RATIO: AtomicU8 = AtomicU8::new(0);
+/// Average frequency over the last sample, in kHz.
+static MEASURED_KHZ: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The idle statistics and `APERF`/`MPERF` at the start of the current sample.
+struct Sample {
+    idle_ticks: u64,
+    busy_ticks: u64,
+    aperf: u64,
+    mperf: u64,
+}
+
+static SAMPLE: Mutex<Sample> = Mutex::new(Sample {
+    idle_ticks: 0,
+    busy_ticks: 0,
+    aperf: 0,
+    mperf: 0,
+});
+
+/// Reads the capabilities of the CPU and applies the policy from the `cpufreq=` boot argument.
+pub fn init() {
+    CAPABILITIES.call_once(Capabilities::detect);
+    set_policy(config::get().cpufreq);
+}
+
+pub fn capabilities() -> Capabilities {
+    *CAPABILITIES.call_once(Capabilities::detect)
+}
+
+pub fn policy() -> Policy {
+    Policy::ALL[POLICY.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_policy(policy: Policy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+    match policy {
+        Policy::Performance => request_highest(),
+        Policy::Powersave => request_lowest(),
+        // decided on the next sample
+        Policy::Ondemand => {}
+    }
+}
+
+/// Requests the policy's frequency again, after waking up from S3 reset it.
+pub fn resume() {
+    REQUESTED_RATIO.store(0, Ordering::Relaxed);
+    set_policy(policy());
+}
+
+fn request_ratio(ratio: u8) {
+    if capabilities().ratios.is_none() || REQUESTED_RATIO.load(Ordering::Relaxed) == ratio {
+        return;
+    }
+    REQUESTED_RATIO.store(ratio, Ordering::Relaxed);
+    unsafe { Msr::new(IA32_PERF_CTL).write((ratio as u64) << 8) };
+}
+
+fn request_highest() {
+    if let Some((_, max)) = capabilities().ratios {
+        request_ratio(max);
+    }
+}
+
+fn request_lowest() {
+    if let Some((min, _)) = capabilities().ratios {
+        request_ratio(min);
+    }
+}
+
+/// The `mwait` hint for an idle CPU: the deepest C-state under `powersave`, C1 otherwise.
+pub fn mwait_hint() -> u32 {
+    match policy() {
+        Policy::Powersave => ((capabilities().deepest_c_state() as u32 - 1) & 0xf) << 4,
+        _ => 0,
+    }
+}
+
+/// Called from the timer interrupt, ends a sample every `SAMPLE_TICKS`.
+pub fn tick() {
+    if TICKS.fetch_add(1, Ordering::Relaxed) % SAMPLE_TICKS != SAMPLE_TICKS - 1 {
+        return;
+    }
+    let caps = capabilities();
+    let stats = idle::stats();
+    let (aperf, mperf) = match caps.aperf_mperf {
+        true => unsafe { (Msr::new(IA32_APERF).read(), Msr::new(IA32_MPERF).read()) },
+        false => (0, 0),
+    };
+    // only ever used here, in the timer interrupt
+    let mut sample = SAMPLE.lock();
+    let idle = stats.idle_ticks - sample.idle_ticks;
+    let busy = stats.busy_ticks - sample.busy_ticks;
+    let delta_aperf = aperf.wrapping_sub(sample.aperf);
+    let delta_mperf = mperf.wrapping_sub(sample.mperf);
+    *sample = Sample {
+        idle_ticks: stats.idle_ticks,
+        busy_ticks: stats.busy_ticks,
+        aperf,
+        mperf,
+    };
+    drop(sample);
+
+    // MPERF counts at the TSC's rate, APERF at the actual one
+    if let (Some(tsc_hz), true) = (tsc::frequency(), delta_mperf != 0) {
+        let khz = tsc_hz as u128 / 1000 * delta_aperf as u128 / delta_mperf as u128;
+        MEASURED_KHZ.store(khz as u64, Ordering::Relaxed);
+    }
+    if policy() != Policy::Ondemand || idle + busy == 0 {
+        return;
+    }
+    let load = busy * 100 / (idle + busy);
+    if load >= UP_THRESHOLD {
+        request_highest();
+    } else if load <= DOWN_THRESHOLD {
+        request_lowest();
+    }
+}
+
+/// The frequency the CPU runs at in kHz, as far as it can be told.
+pub fn current_khz() -> Option<u64> {
+    match MEASURED_KHZ.load(Ordering::Relaxed) {
+        0 if capabilities().ratios.is_some() => {
+            let status = unsafe { Msr::new(IA32_PERF_STATUS).read() };
+            Some((status >> 8 & 0xff) * BUS_MHZ * 1000)
+        }
+        0 => tsc::frequency().map(|hz| hz / 1000),
+        khz => Some(khz),
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Contents of `/proc/cpufreq`.
+pub fn cpufreq_file() -> String {
+    let caps = capabilities();
+    let mut out = String::new();
+    let _ = writeln!(out, "policy: {}", policy().name());
+    if let Some(khz) = current_khz() {
+        let _ = writeln!(out, "current: {} MHz", khz / 1000);
+    }
+    if let Some((min, max)) = caps.ratios {
+        let min_mhz = min as u64 * BUS_MHZ;
+        let max_mhz = max as u64 * BUS_MHZ;
+        let _ = writeln!(out, "range: {min_mhz}-{max_mhz} MHz");
+        let requested = REQUESTED_RATIO.load(Ordering::Relaxed) as u64 * BUS_MHZ;
+        if requested != 0 {
+            let _ = writeln!(out, "requested: {requested} MHz");
+        }
+    }
+    if let Some(mhz) = caps.base_mhz {
+        let _ = writeln!(out, "base: {mhz} MHz");
+    }
+    if let Some(mhz) = caps.max_mhz {
+        let _ = writeln!(out, "max: {mhz} MHz");
+    }
+    let _ = writeln!(out, "speedstep: {}", yes_no(caps.speedstep));
+    let _ = writeln!(out, "turbo: {}", yes_no(caps.turbo));
+    let _ = writeln!(out, "aperf/mperf: {}", yes_no(caps.aperf_mperf));
+    let _ = writeln!(out, "mwait: {}", yes_no(caps.mwait));
+    let states: String = (0..8)
+        .filter(|&state| caps.c_states[state] != 0)
+        .map(|state| format!(" C{state}({})", caps.c_states[state]))
+        .collect();
+    let states = if states.is_empty() {
+        " C1(hlt)"
+    } else {
+        &states
+    };
+    let _ = writeln!(out, "c-states:{states}");
+    let idle_state = match (idle::mwait_supported(), mwait_hint()) {
+        (false, _) => String::from("hlt"),
+        (true, hint) => format!("mwait C{}", (hint >> 4) + 1),
+    };
+    let _ = writeln!(out, "idle: {idle_state}");
+    out
+}
+
+#[test_case]
+fn test_policy_names() {
+    for policy in [Policy::Performance, Policy::Powersave, Policy::Ondemand] {
+        assert_eq!(Policy::parse(policy.name()), Some(policy));
+    }
+    assert_eq!(Policy::parse("turbo"), None);
+}
+
+#[test_case]
+fn test_deepest_c_state() {
+    let mut caps = Capabilities::default();
+    assert_eq!(caps.deepest_c_state(), 1);
+    caps.c_states = [0, 2, 1, 0, 0, 0, 0, 0];
+    assert_eq!(caps.deepest_c_state(), 2);
+}
@@ -0,0 +1,23 @@
+//! Checksums and hash functions, to verify data like files copied onto a disk.
+//!
+//! Every hash can be computed at once with its function, or incrementally by feeding the data
+//! in pieces to `update` and calling `finish`.
+pub(crate) mod blake2;
+pub(crate) mod crc32;
+pub(crate) mod sha256;
+
+pub use blake2::{blake2s, Blake2s};
+pub use crc32::{crc32, Crc32};
+pub use sha256::{sha256, Sha256};
+
+use alloc::string::String;
+use core::fmt::Write;
+
+/// Formats a digest as lowercase hex, like `sha256sum` prints it.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out
+}
@@ -0,0 +1,127 @@
+//! BLAKE2s with a 256 bit digest and no key (RFC 7693).
+use super::sha256::H0 as IV;
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+const DIGEST_LEN: u32 = 32;
+
+#[derive(Debug, Clone)]
+pub struct Blake2s {
+    state: [u32; 8],
+    /// The last block is compressed differently, so a full block is only compressed once
+    /// more data follows.
+    block: [u8; 64],
+    block_len: usize,
+    /// bytes compressed so far
+    len: u64,
+}
+
+impl Blake2s {
+    pub const fn new() -> Self {
+        let mut state = IV;
+        state[0] ^= 0x0101_0000 ^ DIGEST_LEN;
+        Self {
+            state,
+            block: [0; 64],
+            block_len: 0,
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.block_len == 64 {
+                self.len += 64;
+                compress(&mut self.state, &self.block, self.len, false);
+                self.block_len = 0;
+            }
+            let n = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        self.len += self.block_len as u64;
+        self.block[self.block_len..].fill(0);
+        compress(&mut self.state, &self.block, self.len, true);
+
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+fn g(v: &mut [u32; 16], [a, b, c, d]: [usize; 4], x: u32, y: u32) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(12);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(8);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(7);
+}
+
+/// `len` counts every byte up to the end of `block`, padding excluded.
+fn compress(state: &mut [u32; 8], block: &[u8; 64], len: u64, last: bool) {
+    let mut m = [0u32; 16];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        m[i] = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    let mut v = [0u32; 16];
+    v[..8].copy_from_slice(state);
+    v[8..].copy_from_slice(&IV);
+    v[12] ^= len as u32;
+    v[13] ^= (len >> 32) as u32;
+    if last {
+        v[14] = !v[14];
+    }
+
+    for s in &SIGMA {
+        g(&mut v, [0, 4, 8, 12], m[s[0]], m[s[1]]);
+        g(&mut v, [1, 5, 9, 13], m[s[2]], m[s[3]]);
+        g(&mut v, [2, 6, 10, 14], m[s[4]], m[s[5]]);
+        g(&mut v, [3, 7, 11, 15], m[s[6]], m[s[7]]);
+        g(&mut v, [0, 5, 10, 15], m[s[8]], m[s[9]]);
+        g(&mut v, [1, 6, 11, 12], m[s[10]], m[s[11]]);
+        g(&mut v, [2, 7, 8, 13], m[s[12]], m[s[13]]);
+        g(&mut v, [3, 4, 9, 14], m[s[14]], m[s[15]]);
+    }
+    for i in 0..8 {
+        state[i] ^= v[i] ^ v[i + 8];
+    }
+}
+
+pub fn blake2s(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2s::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_blake2s() {
+    use super::hex;
+    assert_eq!(
+        hex(&blake2s(b"")),
+        "69217a3079908094e11121d042354a7c1f55b6482ca1a51e1b250dfd1ed0eef9"
+    );
+    assert_eq!(
+        hex(&blake2s(b"abc")),
+        "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+    );
+}
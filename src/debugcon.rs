@@ -0,0 +1,50 @@
+//! Output to the Bochs/QEMU debug console on port 0xE9.
+//!
+//! Enabled with the `debugcon` boot flag. Everything printed to the screen, the serial port and
+//! the kernel log is mirrored, so output can be captured with `-debugcon file:out.txt` without
+//! setting up a serial device.
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::bootargs;
+
+const DEBUGCON_PORT: u16 = 0xe9;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the console if the boot flag is set and the port exists. Reading the port returns
+/// 0xE9 when an emulator provides it.
+pub fn init() {
+    let present = unsafe { Port::<u8>::new(DEBUGCON_PORT).read() } == DEBUGCON_PORT as u8;
+    ENABLED.store(present && bootargs::has_flag("debugcon"), Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+struct DebugCon;
+
+impl Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut port = Port::<u8>::new(DEBUGCON_PORT);
+        for byte in s.bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+/// Writes to the debug console if it's enabled. Writes are single port accesses, so no lock
+/// is needed.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if is_enabled() {
+        let _ = DebugCon.write_fmt(args);
+    }
+}
@@ -0,0 +1,226 @@
+//! The devices the kernel found, as a tree.
+//!
+//! Buses register the devices they find below the device they are reached through: PCI
+//! functions below the bridge in front of their bus, USB devices below their host controller.
+//! Devices on no hardware bus, like the loopback interface, are on the `virtual` bus. Drivers
+//! are still attached by the managers in `drivers`, which record here which driver took a
+//! device. The tree can be browsed in `/sys/devices`, see `vfs::SysFs`.
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Pci,
+    Usb,
+    Virtual,
+}
+
+impl Bus {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pci => "pci",
+            Self::Usb => "usb",
+            Self::Virtual => "virtual",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// no driver took the device
+    Unbound,
+    Bound,
+    /// the driver stopped the device while the machine sleeps
+    Suspended,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unbound => "unbound",
+            Self::Bound => "bound",
+            Self::Suspended => "suspended",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub id: DeviceId,
+    /// unique among the children of the parent
+    pub name: String,
+    pub bus: Bus,
+    pub parent: Option<DeviceId>,
+    pub driver: Option<String>,
+    pub state: State,
+    /// what the bus knows about the device, like `vendor`
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+impl Device {
+    /// The attributes of the bus, and `bus`, `state` and `driver` if there is one.
+    pub fn all_attributes(&self) -> Vec<(&'static str, String)> {
+        let mut attributes = Vec::from([
+            ("bus", String::from(self.bus.name())),
+            ("state", String::from(self.state.name())),
+        ]);
+        if let Some(driver) = &self.driver {
+            attributes.push(("driver", driver.clone()));
+        }
+        attributes.extend(self.attributes.iter().cloned());
+        attributes
+    }
+}
+
+static DEVICES: Mutex<BTreeMap<DeviceId, Device>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+fn with_devices<R>(f: impl FnOnce(&mut BTreeMap<DeviceId, Device>) -> R) -> R {
+    without_interrupts(|| f(&mut DEVICES.lock()))
+}
+
+/// Adds a device below `parent`, or at the top. A name already taken by a sibling gets a
+/// number appended.
+pub fn register(
+    name: &str,
+    bus: Bus,
+    parent: Option<DeviceId>,
+    attributes: Vec<(&'static str, String)>,
+) -> DeviceId {
+    let id = DeviceId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    with_devices(|devices| {
+        let taken = |name: &str| {
+            devices
+                .values()
+                .any(|device| device.parent == parent && device.name == name)
+        };
+        let mut unique = String::from(name);
+        let mut n = 1;
+        while taken(&unique) {
+            unique = format!("{name}.{n}");
+            n += 1;
+        }
+        let device = Device {
+            id,
+            name: unique,
+            bus,
+            parent,
+            driver: None,
+            state: State::Unbound,
+            attributes,
+        };
+        devices.insert(id, device);
+    });
+    id
+}
+
+/// Removes a device and everything below it.
+pub fn unregister(id: DeviceId) {
+    with_devices(|devices| {
+        let mut doomed = Vec::from([id]);
+        while let Some(id) = doomed.pop() {
+            devices.remove(&id);
+            let children = devices.values().filter(|device| device.parent == Some(id));
+            doomed.extend(children.map(|device| device.id));
+        }
+    })
+}
+
+/// Records that `driver` took the device.
+pub fn bind(id: DeviceId, driver: &str) {
+    with_devices(|devices| {
+        if let Some(device) = devices.get_mut(&id) {
+            device.driver = Some(String::from(driver));
+            device.state = State::Bound;
+        }
+    })
+}
+
+pub fn unbind(id: DeviceId) {
+    with_devices(|devices| {
+        if let Some(device) = devices.get_mut(&id) {
+            device.driver = None;
+            device.state = State::Unbound;
+        }
+    })
+}
+
+/// Records a state change of a bound device, like `Suspended`.
+pub fn set_state(id: DeviceId, state: State) {
+    with_devices(|devices| {
+        if let Some(device) = devices
+            .get_mut(&id)
+            .filter(|device| device.driver.is_some())
+        {
+            device.state = state;
+        }
+    })
+}
+
+pub fn get(id: DeviceId) -> Option<Device> {
+    with_devices(|devices| devices.get(&id).cloned())
+}
+
+/// The devices below `parent`, or at the top, in the order they were registered.
+pub fn children(parent: Option<DeviceId>) -> Vec<Device> {
+    with_devices(|devices| {
+        devices
+            .values()
+            .filter(|device| device.parent == parent)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Finds a device of `bus` by name, wherever it is in the tree.
+pub fn find(bus: Bus, name: &str) -> Option<DeviceId> {
+    with_devices(|devices| {
+        devices
+            .values()
+            .find(|device| device.bus == bus && device.name == name)
+            .map(|device| device.id)
+    })
+}
+
+/// Follows the names in `path` down from the top.
+pub fn lookup<'a>(path: impl IntoIterator<Item = &'a str>) -> Option<Device> {
+    let mut device: Option<Device> = None;
+    for name in path {
+        let parent = device.map(|device| device.id);
+        let child = children(parent)
+            .into_iter()
+            .find(|child| child.name == name)?;
+        device = Some(child);
+    }
+    device
+}
+
+#[test_case]
+fn test_tree() {
+    let top = register("test-tree", Bus::Virtual, None, Vec::new());
+    let child = register("child", Bus::Virtual, Some(top), Vec::new());
+    let twin = register("child", Bus::Virtual, Some(top), Vec::new());
+    assert_eq!(get(twin).unwrap().name, "child.1");
+    assert_eq!(lookup(["test-tree", "child"]).unwrap().id, child);
+
+    bind(child, "test");
+    let device = get(child).unwrap();
+    assert_eq!(device.state, State::Bound);
+    assert!(device
+        .all_attributes()
+        .contains(&("driver", String::from("test"))));
+    set_state(child, State::Suspended);
+    assert_eq!(get(child).unwrap().state, State::Suspended);
+    // only drivers suspend devices
+    set_state(twin, State::Suspended);
+    assert_eq!(get(twin).unwrap().state, State::Unbound);
+
+    unregister(top);
+    assert!(get(child).is_none());
+    assert!(lookup(["test-tree"]).is_none());
+}
@@ -1,22 +1,870 @@
-use crate::pci::BAR;
+//! Driver for AHCI SATA controllers.
+//!
+//! Every port with a SATA disk becomes an [`AhciDisk`]. Commands are submitted into free command
+//! slots and complete in any order: a submission returns a [`Ticket`] which is waited on later,
+//! so a caller can keep as many commands in flight as the disk has slots. Disks supporting NCQ
+//! get tagged (FPDMA QUEUED) commands, others plain DMA commands, which the controller still
+//! queues. Completions are picked up by the port interrupt, or by polling if the controller
+//! has no usable interrupt line.
+//!
+//! See [osdev](https://wiki.osdev.org/AHCI).
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec, vec::Vec};
+use core::ptr;
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, PhysAddr};
 
-use super::DriverManager;
+use super::{
+    dma::{self, DmaPage},
+    mbr::{self, Partition},
+    Driver, DriverManager, PhysicalDevice,
+};
+use crate::{
+    error::KResult,
+    ext::{Errno, RWS},
+    interrupts, mem,
+    pci::BAR,
+    println,
+    sync::WaitQueue,
+    task, time,
+};
+
+pub const SECTOR_SIZE: usize = 512;
+/// Largest transfer of a single command, chosen so the PRDT of any buffer fits `MAX_PRDS`.
+pub const MAX_SECTORS: usize = 64;
+
+const REG_CAP: usize = 0x00;
+const REG_GHC: usize = 0x04;
+const REG_IS: usize = 0x08;
+const REG_PI: usize = 0x0c;
+
+const CAP_NCQ: u32 = 1 << 30;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+
+const PORT_REGS: usize = 0x100;
+const PORT_REGS_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+
+const CMD_START: u32 = 1 << 0;
+const CMD_FIS_RECEIVE: u32 = 1 << 4;
+const CMD_FIS_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+
+const IS_D2H_FIS: u32 = 1 << 0;
+const IS_PIO_SETUP: u32 = 1 << 1;
+const IS_SET_DEVICE_BITS: u32 = 1 << 3;
+const IS_INTERFACE_FATAL: u32 = 1 << 27;
+const IS_HOST_DATA: u32 = 1 << 28;
+const IS_HOST_FATAL: u32 = 1 << 29;
+const IS_TASK_FILE_ERROR: u32 = 1 << 30;
+const IS_ERRORS: u32 = IS_INTERFACE_FATAL | IS_HOST_DATA | IS_HOST_FATAL | IS_TASK_FILE_ERROR;
+
+const TFD_ERROR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BUSY: u32 = 1 << 7;
+
+const SIG_SATA: u32 = 0x0000_0101;
+const SSTS_PRESENT: u32 = 0x3;
+const SSTS_ACTIVE: u32 = 0x1;
+
+const FIS_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 0x80;
+const DEVICE_LBA: u8 = 1 << 6;
+
+const ATA_IDENTIFY: u8 = 0xec;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+// Layout of the command list page: 32 command headers, then the received FIS area.
+const COMMAND_HEADER_SIZE: usize = 32;
+const RECEIVED_FIS: usize = 0x400;
+// Command tables hold the command FIS and are followed by the PRDT.
+const COMMAND_TABLE_SIZE: usize = 0x80 + MAX_PRDS * 16;
+const TABLES_PER_PAGE: usize = mem::PAGE_SIZE / COMMAND_TABLE_SIZE;
+const MAX_PRDS: usize = 16;
+const MAX_SLOTS: usize = 32;
+
+const TIMEOUT: core::time::Duration = core::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    /// The buffer is empty, not a multiple of the sector size, too large, misaligned, or the
+    /// sectors are outside of the disk.
+    InvalidRequest,
+    /// The disk reported an error or the port hit a fatal error.
+    Device,
+    /// The port didn't react in time.
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+/// A submitted command, redeemed with [`AhciDisk::wait`].
+#[derive(Debug)]
+#[must_use = "the command's buffer is only returned by waiting on it"]
+pub struct Ticket {
+    slot: usize,
+}
+
+/// Controllers found so far, checked by the interrupt handler.
+static CONTROLLERS: Mutex<Vec<Arc<Controller>>> = Mutex::new(Vec::new());
+static DISKS: Mutex<Vec<Arc<AhciDisk>>> = Mutex::new(Vec::new());
+/// IRQ lines the interrupt handler was registered on.
+static IRQS: Mutex<u16> = Mutex::new(0);
+
+/// Returns all disks found on AHCI controllers.
+pub fn disks() -> Vec<Arc<AhciDisk>> {
+    without_interrupts(|| DISKS.lock().clone())
+}
+
+/// Opens `/dev/sdX` as the Xth disk and `/dev/sdXN` as partition N of its MBR.
+pub fn open(path: &str) -> Result<AhciDevice, Errno> {
+    let name = path.strip_prefix("/dev/sd").ok_or(Errno::NotFound)?;
+    let disk = match name.as_bytes().first() {
+        Some(c @ b'a'..=b'z') => disks().get((c - b'a') as usize).cloned(),
+        _ => None,
+    };
+    let disk = disk.ok_or(Errno::NotFound)?;
+    let number = &name[1..];
+    if number.is_empty() {
+        return Ok(AhciDevice::new(disk));
+    }
+    let number: u8 = number.parse().map_err(|_| Errno::NotFound)?;
+    let mut sector = vec![0; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).map_err(|_| Errno::UnknownIO)?;
+    let partition = mbr::parse(&sector)
+        .find(|partition| partition.number == number)
+        .ok_or(Errno::NotFound)?;
+    if partition.first + partition.sectors > disk.sectors {
+        return Err(Errno::InvalidFileImage);
+    }
+    Ok(AhciDevice::partition(disk, &partition))
+}
 
 pub struct AhciDriverManager;
 
 impl DriverManager for AhciDriverManager {
-    fn on_plug(
-        &self,
-        dev: &dyn super::PhysicalDevice,
-    ) -> Option<alloc::boxed::Box<dyn super::Driver>> {
-        if dev.get_class() == 0x1 && dev.get_subclass() == 0x6 && dev.get_prog_if() == 0x1 {
-            // AHCI Device
+    fn on_plug(&self, dev: &dyn PhysicalDevice) -> Option<Box<dyn Driver>> {
+        if dev.get_class() != 0x1 || dev.get_subclass() != 0x6 || dev.get_prog_if() != 0x1 {
+            return None;
         }
+        let abar = match dev.get_bars().get(5) {
+            Some(Some(BAR::MemorySpace { address, .. })) => *address,
+            _ => return None,
+        };
+        let Some(base) = mem::phys_to_mapped_virt(PhysAddr::new(abar)) else {
+            println!("ahci: registers at {:#x} are not mapped", abar);
+            return None;
+        };
+        // memory space and bus master
+        let command = dev.read_config(0x04);
+        dev.write_config(0x04, command | 1 << 1 | 1 << 2);
 
-        None
+        let irq = dev.get_interrupt_line().filter(|line| (3..16).contains(line));
+        let controller = Arc::new(Controller::new(base.as_u64(), irq.is_some()));
+        for disk in &controller.disks {
+            println!(
+                "ahci: port {}: {} sectors, {} slots{}",
+                disk.port,
+                disk.sectors,
+                disk.slots,
+                if disk.ncq { ", NCQ" } else { "" }
+            );
+        }
+        without_interrupts(|| {
+            CONTROLLERS.lock().push(controller.clone());
+            DISKS.lock().extend(controller.disks.iter().cloned());
+        });
+        if let Some(irq) = irq {
+            let registered = without_interrupts(|| {
+                let mut irqs = IRQS.lock();
+                let registered = *irqs & 1 << irq != 0;
+                *irqs |= 1 << irq;
+                registered
+            });
+            if !registered {
+                interrupts::register_irq(irq, handle_irq);
+            }
+            controller.enable_interrupts();
+        }
+        Some(Box::new(AhciDriver { controller }))
     }
 }
 
 pub struct AhciDriver {
-    bars: [Option<BAR>; 1],
+    controller: Arc<Controller>,
+}
+
+impl Driver for AhciDriver {
+    fn get_name(&self) -> &str {
+        "ahci"
+    }
+
+    fn on_unplug(&self, _dev: &dyn PhysicalDevice) -> bool {
+        false
+    }
+
+    fn suspend(&self) -> KResult<()> {
+        for disk in &self.controller.disks {
+            disk.suspend()?;
+        }
+        Ok(())
+    }
+
+    fn resume(&self) -> KResult<()> {
+        let controller = &self.controller;
+        controller.set_reg(REG_GHC, controller.reg(REG_GHC) | GHC_AHCI_ENABLE);
+        for disk in &controller.disks {
+            disk.resume()?;
+        }
+        if controller.interrupts {
+            controller.enable_interrupts();
+        }
+        Ok(())
+    }
+}
+
+/// Called on the controllers' IRQ lines, which might be shared with other devices.
+fn handle_irq() {
+    for controller in CONTROLLERS.lock().iter() {
+        let pending = controller.reg(REG_IS);
+        if pending == 0 {
+            continue;
+        }
+        for disk in &controller.disks {
+            if pending & 1 << disk.port != 0 {
+                disk.complete();
+            }
+        }
+        controller.set_reg(REG_IS, pending);
+    }
+}
+
+fn read_reg(addr: u64) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: u64, value: u32) {
+    unsafe { ptr::write_volatile(addr as *mut u32, value) }
+}
+
+struct Controller {
+    base: u64,
+    /// the controller has an IRQ line, its disks don't poll
+    interrupts: bool,
+    disks: Vec<Arc<AhciDisk>>,
+}
+
+impl Controller {
+    fn new(base: u64, interrupts: bool) -> Self {
+        let mut controller = Self {
+            base,
+            interrupts,
+            disks: Vec::new(),
+        };
+        controller.set_reg(REG_GHC, controller.reg(REG_GHC) | GHC_AHCI_ENABLE);
+        let cap = controller.reg(REG_CAP);
+        let slots = ((cap >> 8) & 0x1f) as usize + 1;
+        let implemented = controller.reg(REG_PI);
+        for port in 0..32 {
+            if implemented & 1 << port == 0 {
+                continue;
+            }
+            let regs = base + (PORT_REGS + port * PORT_REGS_SIZE) as u64;
+            let ssts = read_reg(regs + PX_SSTS as u64);
+            if ssts & 0xf != SSTS_PRESENT || (ssts >> 8) & 0xf != SSTS_ACTIVE {
+                continue;
+            }
+            if read_reg(regs + PX_SIG as u64) != SIG_SATA {
+                continue;
+            }
+            let disk = AhciDisk::new(port as u8, regs, slots, cap & CAP_NCQ != 0, !interrupts);
+            match disk {
+                Ok(disk) => controller.disks.push(Arc::new(disk)),
+                Err(e) => println!("ahci: port {} failed: {:?}", port, e),
+            }
+        }
+        controller
+    }
+
+    fn enable_interrupts(&self) {
+        self.set_reg(REG_GHC, self.reg(REG_GHC) | GHC_INTERRUPT_ENABLE);
+    }
+
+    fn reg(&self, reg: usize) -> u32 {
+        read_reg(self.base + reg as u64)
+    }
+
+    fn set_reg(&self, reg: usize, value: u32) {
+        write_reg(self.base + reg as u64, value)
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    /// commands issued to the port
+    in_flight: u32,
+    /// finished commands nobody waited for yet
+    done: u32,
+    /// finished commands that failed
+    failed: u32,
+    buffers: [Option<Vec<u8>>; MAX_SLOTS],
+}
+
+/// A SATA disk on an AHCI port.
+pub struct AhciDisk {
+    port: u8,
+    regs: u64,
+    command_list: DmaPage,
+    tables: Vec<DmaPage>,
+    slots: usize,
+    ncq: bool,
+    sectors: u64,
+    /// completions have to be checked for as there's no interrupt
+    polling: bool,
+    state: Mutex<Slots>,
+    waiters: WaitQueue,
+}
+
+impl AhciDisk {
+    fn new(port: u8, regs: u64, slots: usize, ncq: bool, polling: bool) -> Result<Self, AhciError> {
+        let page = || DmaPage::new().ok_or(AhciError::Device);
+        let tables: Result<Vec<_>, _> =
+            (0..slots.div_ceil(TABLES_PER_PAGE)).map(|_| page()).collect();
+        let mut disk = Self {
+            port,
+            regs,
+            command_list: page()?,
+            tables: tables?,
+            slots,
+            ncq: false,
+            sectors: 0,
+            polling,
+            state: Mutex::new(Slots::default()),
+            waiters: WaitQueue::new(),
+        };
+        disk.setup()?;
+
+        let identify = disk.identify()?;
+        let word = |n: usize| u16::from_le_bytes([identify[n * 2], identify[n * 2 + 1]]);
+        disk.sectors = if word(83) & 1 << 10 != 0 {
+            (0..4).map(|i| (word(100 + i) as u64) << (16 * i)).sum()
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+        if ncq && word(76) & 1 << 8 != 0 {
+            disk.ncq = true;
+            disk.slots = slots.min((word(75) & 0x1f) as usize + 1);
+        }
+        disk.enable_interrupts();
+        Ok(disk)
+    }
+
+    /// Points the port at the command list and received FIS area and starts it.
+    fn setup(&self) -> Result<(), AhciError> {
+        self.stop()?;
+        self.set_reg(PX_CLB, self.command_list.phys(0));
+        self.set_reg(PX_CLBU, 0);
+        self.set_reg(PX_FB, self.command_list.phys(RECEIVED_FIS));
+        self.set_reg(PX_FBU, 0);
+        self.set_reg(PX_SERR, !0);
+        self.set_reg(PX_IS, !0);
+        self.start()
+    }
+
+    fn enable_interrupts(&self) {
+        if !self.polling {
+            self.set_reg(PX_IE, IS_D2H_FIS | IS_PIO_SETUP | IS_SET_DEVICE_BITS | IS_ERRORS);
+        }
+    }
+
+    /// Writes back the disk's cache and stops the port, nothing may be submitted afterwards
+    /// until `resume`.
+    fn suspend(&self) -> Result<(), AhciError> {
+        self.flush()?;
+        self.set_reg(PX_IE, 0);
+        self.stop()
+    }
+
+    /// Sets the port up again after `suspend`, the controller may have been reset meanwhile.
+    fn resume(&self) -> Result<(), AhciError> {
+        self.setup()?;
+        self.enable_interrupts();
+        Ok(())
+    }
+
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    /// Number of commands that can be in flight at once.
+    pub fn queue_depth(&self) -> usize {
+        self.slots
+    }
+
+    pub fn has_ncq(&self) -> bool {
+        self.ncq
+    }
+
+    fn reg(&self, reg: usize) -> u32 {
+        read_reg(self.regs + reg as u64)
+    }
+
+    fn set_reg(&self, reg: usize, value: u32) {
+        write_reg(self.regs + reg as u64, value)
+    }
+
+    fn wait_while(&self, reg: usize, mask: u32) -> Result<(), AhciError> {
+        let deadline = time::uptime() + TIMEOUT;
+        while self.reg(reg) & mask != 0 {
+            if time::uptime() >= deadline {
+                return Err(AhciError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), AhciError> {
+        self.set_reg(PX_CMD, self.reg(PX_CMD) & !CMD_START);
+        self.wait_while(PX_CMD, CMD_LIST_RUNNING)?;
+        self.set_reg(PX_CMD, self.reg(PX_CMD) & !CMD_FIS_RECEIVE);
+        self.wait_while(PX_CMD, CMD_FIS_RUNNING)
+    }
+
+    fn start(&self) -> Result<(), AhciError> {
+        self.wait_while(PX_CMD, CMD_LIST_RUNNING)?;
+        self.set_reg(PX_CMD, self.reg(PX_CMD) | CMD_FIS_RECEIVE);
+        self.set_reg(PX_CMD, self.reg(PX_CMD) | CMD_START);
+        Ok(())
+    }
+
+    fn table(&self, slot: usize) -> (&DmaPage, usize) {
+        let page = &self.tables[slot / TABLES_PER_PAGE];
+        (page, slot % TABLES_PER_PAGE * COMMAND_TABLE_SIZE)
+    }
+
+    /// Fills in the command header and table of `slot`.
+    fn prepare(
+        &self,
+        slot: usize,
+        fis: &[u8; 20],
+        buffer: &[u8],
+        write: bool,
+    ) -> Result<(), AhciError> {
+        let segments = dma::phys_segments(buffer).ok_or(AhciError::InvalidRequest)?;
+        if segments.len() > MAX_PRDS
+            || segments.iter().any(|(addr, len)| addr % 2 != 0 || len % 2 != 0)
+        {
+            return Err(AhciError::InvalidRequest);
+        }
+
+        let (page, table) = self.table(slot);
+        for i in (0..0x80).step_by(4) {
+            page.write_u32(table + i, 0);
+        }
+        for (i, chunk) in fis.chunks(4).enumerate() {
+            let bytes = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            page.write_u32(table + i * 4, u32::from_le_bytes(bytes));
+        }
+        for (i, (addr, len)) in segments.iter().enumerate() {
+            let prd = table + 0x80 + i * 16;
+            page.write_u32(prd, *addr as u32);
+            page.write_u32(prd + 4, (*addr >> 32) as u32);
+            page.write_u32(prd + 8, 0);
+            page.write_u32(prd + 12, (*len - 1) as u32);
+        }
+
+        let header = slot * COMMAND_HEADER_SIZE;
+        // FIS length in dwords, direction and PRDT length
+        let flags = 5 | (write as u32) << 6 | (segments.len() as u32) << 16;
+        self.command_list.write_u32(header, flags);
+        self.command_list.write_u32(header + 4, 0);
+        self.command_list.write_u32(header + 8, page.phys(table));
+        self.command_list.write_u32(header + 12, 0);
+        Ok(())
+    }
+
+    /// Issues IDENTIFY DEVICE on slot 0 and waits for it by polling.
+    fn identify(&self) -> Result<Vec<u8>, AhciError> {
+        let buffer = vec![0u8; SECTOR_SIZE];
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_H2D;
+        fis[1] = FIS_COMMAND;
+        fis[2] = ATA_IDENTIFY;
+        self.prepare(0, &fis, &buffer, false)?;
+        self.wait_while(PX_TFD, TFD_BUSY | TFD_DRQ)?;
+        self.set_reg(PX_CI, 1);
+        self.wait_while(PX_CI, 1)?;
+        if self.reg(PX_TFD) & TFD_ERROR != 0 || self.reg(PX_IS) & IS_ERRORS != 0 {
+            return Err(AhciError::Device);
+        }
+        self.set_reg(PX_IS, !0);
+        Ok(buffer)
+    }
+
+    fn command_fis(&self, op: Op, lba: u64, sectors: usize, slot: usize) -> [u8; 20] {
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_H2D;
+        fis[1] = FIS_COMMAND;
+        fis[7] = DEVICE_LBA;
+        for i in 0..3 {
+            fis[4 + i] = (lba >> (8 * i)) as u8;
+            fis[8 + i] = (lba >> (8 * (i + 3))) as u8;
+        }
+        let count = (sectors as u16).to_le_bytes();
+        if self.ncq {
+            fis[2] = match op {
+                Op::Read => ATA_READ_FPDMA_QUEUED,
+                Op::Write => ATA_WRITE_FPDMA_QUEUED,
+            };
+            // the sector count moves to the feature field, the count field holds the tag
+            fis[3] = count[0];
+            fis[11] = count[1];
+            fis[12] = (slot as u8) << 3;
+        } else {
+            fis[2] = match op {
+                Op::Read => ATA_READ_DMA_EXT,
+                Op::Write => ATA_WRITE_DMA_EXT,
+            };
+            fis[12] = count[0];
+            fis[13] = count[1];
+        }
+        fis
+    }
+
+    /// Waits until `cond` holds for the slots, checking for completions itself if the port
+    /// has no interrupt.
+    fn wait_for<F>(&self, mut cond: F)
+    where
+        F: FnMut(&mut Slots) -> bool,
+    {
+        if self.polling {
+            while !without_interrupts(|| {
+                self.complete();
+                cond(&mut self.state.lock())
+            }) {
+                task::yield_now();
+            }
+        } else {
+            self.waiters.wait_until(|| cond(&mut self.state.lock()));
+        }
+    }
+
+    /// Queues a transfer of `buffer.len()` bytes starting at sector `lba`. A write sends the
+    /// buffer's contents, a read overwrites them; either way the buffer is handed back by
+    /// `wait`. Blocks while all command slots are busy.
+    pub fn submit(&self, op: Op, lba: u64, buffer: Vec<u8>) -> Result<Ticket, AhciError> {
+        let sectors = buffer.len() / SECTOR_SIZE;
+        if buffer.is_empty() || buffer.len() % SECTOR_SIZE != 0 || sectors > MAX_SECTORS {
+            return Err(AhciError::InvalidRequest);
+        }
+        if lba.checked_add(sectors as u64).map_or(true, |end| end > self.sectors) {
+            return Err(AhciError::InvalidRequest);
+        }
+
+        let mut slot = 0;
+        self.wait_for(|state| {
+            let busy = state.in_flight | state.done;
+            match (0..self.slots).find(|slot| busy & 1 << slot == 0) {
+                Some(free) => {
+                    slot = free;
+                    // reserved until it is issued below
+                    state.done |= 1 << free;
+                    true
+                }
+                None => false,
+            }
+        });
+
+        let fis = self.command_fis(op, lba, sectors, slot);
+        if let Err(e) = self.prepare(slot, &fis, &buffer, op == Op::Write) {
+            without_interrupts(|| self.state.lock().done &= !(1 << slot));
+            self.waiters.wake_all();
+            return Err(e);
+        }
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.done &= !(1 << slot);
+            state.failed &= !(1 << slot);
+            state.in_flight |= 1 << slot;
+            state.buffers[slot] = Some(buffer);
+            if self.ncq {
+                self.set_reg(PX_SACT, 1 << slot);
+            }
+            self.set_reg(PX_CI, 1 << slot);
+        });
+        Ok(Ticket { slot })
+    }
+
+    /// Waits for a submitted command and returns its buffer.
+    pub fn wait(&self, ticket: Ticket) -> Result<Vec<u8>, AhciError> {
+        let bit = 1 << ticket.slot;
+        let mut result = Err(AhciError::Device);
+        self.wait_for(|state| {
+            if state.done & bit == 0 {
+                return false;
+            }
+            let buffer = state.buffers[ticket.slot].take().unwrap_or_default();
+            result = if state.failed & bit == 0 { Ok(buffer) } else { Err(AhciError::Device) };
+            state.done &= !bit;
+            true
+        });
+        // a slot was freed
+        self.waiters.wake_all();
+        result
+    }
+
+    /// Picks up finished commands. Called from the interrupt handler, or while waiting if the
+    /// port has no interrupt.
+    fn complete(&self) {
+        let status = self.reg(PX_IS);
+        self.set_reg(PX_IS, status);
+        let mut state = self.state.lock();
+        if status & IS_ERRORS != 0 {
+            // Which of the queued commands failed is hard to tell, fail all of them and
+            // restart the port.
+            println!("ahci: port {} error {:#x}, tfd {:#x}", self.port, status, self.reg(PX_TFD));
+            let _ = self.stop();
+            self.set_reg(PX_SERR, !0);
+            self.set_reg(PX_IS, !0);
+            let _ = self.start();
+            state.failed |= state.in_flight;
+            state.done |= state.in_flight;
+            state.in_flight = 0;
+        } else {
+            let active = self.reg(PX_SACT) | self.reg(PX_CI);
+            let finished = state.in_flight & !active;
+            state.in_flight &= !finished;
+            state.done |= finished;
+        }
+        drop(state);
+        self.waiters.wake_all();
+    }
+
+    /// Makes the disk write its volatile cache to stable media.
+    ///
+    /// This is a barrier: it waits until every submitted command was waited for, and new
+    /// submissions wait until the flush is done. So don't hold on to tickets while flushing.
+    pub fn flush(&self) -> Result<(), AhciError> {
+        let all = if self.slots == MAX_SLOTS { !0 } else { (1 << self.slots) - 1 };
+        self.wait_for(|state| {
+            if state.in_flight | state.done != 0 {
+                return false;
+            }
+            // reserve every slot, the flush can't be queued together with NCQ commands
+            state.done = all;
+            true
+        });
+
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_H2D;
+        fis[1] = FIS_COMMAND;
+        fis[2] = ATA_FLUSH_CACHE_EXT;
+        fis[7] = DEVICE_LBA;
+        let mut result = self.prepare(0, &fis, &[], false);
+        if result.is_ok() {
+            without_interrupts(|| {
+                let mut state = self.state.lock();
+                state.done &= !1;
+                state.failed &= !1;
+                state.in_flight |= 1;
+                self.set_reg(PX_CI, 1);
+            });
+            result = self.wait(Ticket { slot: 0 }).map(|_| ());
+        }
+        without_interrupts(|| self.state.lock().done &= !all);
+        self.waiters.wake_all();
+        result
+    }
+
+    /// Reads sectors starting at `lba` into `buf`, keeping up to a full queue of commands in
+    /// flight.
+    pub fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        let chunk = MAX_SECTORS * SECTOR_SIZE;
+        let mut pending = VecDeque::new();
+        let mut result = Ok(());
+        for (i, offset) in (0..buf.len()).step_by(chunk).enumerate() {
+            if pending.len() == self.slots {
+                let (offset, ticket) = pending.pop_front().unwrap();
+                result = self.finish_read(ticket, &mut buf[offset..]);
+            }
+            let len = chunk.min(buf.len() - offset);
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            match result.and_then(|_| self.submit(Op::Read, lba, vec![0; len])) {
+                Ok(ticket) => pending.push_back((offset, ticket)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        // every ticket has to be waited for, even after an error, to free its slot
+        for (offset, ticket) in pending {
+            let finished = self.finish_read(ticket, &mut buf[offset..]);
+            result = result.and(finished);
+        }
+        result
+    }
+
+    fn finish_read(&self, ticket: Ticket, buf: &mut [u8]) -> Result<(), AhciError> {
+        let data = self.wait(ticket)?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    /// Writes `data` to the sectors starting at `lba`, keeping up to a full queue of commands
+    /// in flight.
+    pub fn write_sectors(&self, lba: u64, data: &[u8]) -> Result<(), AhciError> {
+        let mut pending = VecDeque::new();
+        let mut result = Ok(());
+        for (i, part) in data.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            if pending.len() == self.slots {
+                result = self.wait(pending.pop_front().unwrap()).map(|_| ());
+            }
+            let lba = lba + (i * MAX_SECTORS) as u64;
+            match result.and_then(|_| self.submit(Op::Write, lba, part.to_vec())) {
+                Ok(ticket) => pending.push_back(ticket),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        for ticket in pending {
+            result = result.and(self.wait(ticket).map(|_| ()));
+        }
+        result
+    }
+}
+
+/// Byte addressed access to an [`AhciDisk`] or one of its partitions, so a filesystem can live
+/// on it. Accesses not covering whole sectors read the surrounding sectors first.
+pub struct AhciDevice {
+    disk: Arc<AhciDisk>,
+    /// the first sector of the partition, 0 for the whole disk
+    start: u64,
+    sectors: u64,
+    pos: u64,
+}
+
+impl AhciDevice {
+    pub fn new(disk: Arc<AhciDisk>) -> Self {
+        let sectors = disk.sectors;
+        Self {
+            disk,
+            start: 0,
+            sectors,
+            pos: 0,
+        }
+    }
+
+    /// Access to `partition` of `disk` only.
+    pub fn partition(disk: Arc<AhciDisk>, partition: &Partition) -> Self {
+        Self {
+            disk,
+            start: partition.first,
+            sectors: partition.sectors,
+            pos: 0,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.sectors * SECTOR_SIZE as u64
+    }
+
+    /// Reads the sectors around `addr..addr + len` into a buffer, returning it and the offset
+    /// of `addr` in it.
+    fn read_around(&self, addr: u64, len: usize) -> Result<(Vec<u8>, usize), Errno> {
+        let first = addr / SECTOR_SIZE as u64;
+        let last = (addr + len as u64).div_ceil(SECTOR_SIZE as u64);
+        let mut buffer = vec![0; ((last - first) as usize) * SECTOR_SIZE];
+        self.disk
+            .read_sectors(self.start + first, &mut buffer)
+            .map_err(|_| Errno::UnknownIO)?;
+        Ok((buffer, (addr % SECTOR_SIZE as u64) as usize))
+    }
+}
+
+impl RWS for AhciDevice {
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, Errno> {
+        let read = self.read_at(self.pos, buf)?;
+        self.pos += read;
+        Ok(read)
+    }
+
+    fn read_at(&mut self, addr: u64, buf: &mut [u8]) -> Result<u64, Errno> {
+        let len = (buf.len() as u64).min(self.len().saturating_sub(addr)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let (data, offset) = self.read_around(addr, len)?;
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len as u64)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, Errno> {
+        let written = self.write_at(self.pos, buf)?;
+        self.pos += written;
+        Ok(written)
+    }
+
+    fn write_at(&mut self, addr: u64, buf: &[u8]) -> Result<u64, Errno> {
+        if addr + buf.len() as u64 > self.len() {
+            return Err(Errno::OutOfSpace);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let aligned = addr % SECTOR_SIZE as u64 == 0 && buf.len() % SECTOR_SIZE == 0;
+        let lba = self.start + addr / SECTOR_SIZE as u64;
+        let result = if aligned {
+            self.disk.write_sectors(lba, buf)
+        } else {
+            let (mut data, offset) = self.read_around(addr, buf.len())?;
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            self.disk.write_sectors(lba, &data)
+        };
+        result.map_err(|_| Errno::UnknownIO)?;
+        Ok(buf.len() as u64)
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), Errno> {
+        self.seek_absolute(self.pos + offset)
+    }
+
+    fn seek_absolute(&mut self, to: u64) -> Result<(), Errno> {
+        if to > self.len() {
+            return Err(Errno::OutOfSpace);
+        }
+        self.pos = to;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Errno> {
+        self.disk.flush().map_err(|_| Errno::UnknownIO)
+    }
 }
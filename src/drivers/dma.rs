@@ -0,0 +1,102 @@
+//! Memory shared with devices doing DMA.
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, Layout},
+    vec::Vec,
+};
+use core::ptr::{self, NonNull};
+use x86_64::VirtAddr;
+
+use crate::mem::{self, PAGE_SIZE};
+
+/// A zeroed, page aligned page of heap memory for structures a device reads and writes. It is
+/// kept below 4 GiB for devices without 64 bit addressing. `contiguous` allocates several
+/// physically contiguous pages for structures larger than a page.
+pub struct DmaPage {
+    ptr: NonNull<u8>,
+    phys: u64,
+    pages: usize,
+}
+
+// The page is only accessed through volatile reads and writes.
+unsafe impl Send for DmaPage {}
+unsafe impl Sync for DmaPage {}
+
+impl DmaPage {
+    fn layout(pages: usize) -> Layout {
+        Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    /// Allocates a page, `None` if the heap is full or the page lies above 4 GiB.
+    pub fn new() -> Option<Self> {
+        Self::contiguous(1)
+    }
+
+    /// Allocates `pages` pages, `None` if the heap is full, they aren't physically contiguous
+    /// or lie above 4 GiB.
+    pub fn contiguous(pages: usize) -> Option<Self> {
+        let ptr = NonNull::new(unsafe { alloc_zeroed(Self::layout(pages)) })?;
+        // freed again by `drop` if it can't be used
+        let mut page = Self {
+            ptr,
+            phys: 0,
+            pages,
+        };
+        let buf = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), page.size()) };
+        let [(phys, _)] = phys_segments(buf)?[..] else {
+            return None;
+        };
+        page.phys = phys;
+        if page.phys + page.size() as u64 > u32::MAX as u64 {
+            return None;
+        }
+        Some(page)
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// Returns a pointer to the byte at `offset`.
+    pub fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size());
+        unsafe { self.ptr.as_ptr().add(offset) as *mut T }
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.ptr(offset)) }
+    }
+
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.ptr(offset), value) }
+    }
+
+    /// Returns the physical address of the byte at `offset`.
+    pub fn phys(&self, offset: usize) -> u32 {
+        (self.phys + offset as u64) as u32
+    }
+}
+
+impl Drop for DmaPage {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout(self.pages)) };
+    }
+}
+
+/// Splits a buffer into physically contiguous pieces, as physical address and length. `None`
+/// if part of it isn't mapped.
+pub fn phys_segments(buf: &[u8]) -> Option<Vec<(u64, usize)>> {
+    let mut segments: Vec<(u64, usize)> = Vec::new();
+    let mut addr = buf.as_ptr() as u64;
+    let end = addr + buf.len() as u64;
+    while addr < end {
+        let len = (PAGE_SIZE as u64 - addr % PAGE_SIZE as u64).min(end - addr);
+        let phys = mem::virt_to_phys(VirtAddr::new(addr))?.as_u64();
+        match segments.last_mut() {
+            Some((start, seg_len)) if *start + *seg_len as u64 == phys => *seg_len += len as usize,
+            _ => segments.push((phys, len as usize)),
+        }
+        addr += len;
+    }
+    Some(segments)
+}
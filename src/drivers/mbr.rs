@@ -0,0 +1,67 @@
+//! MBR partition tables.
+//!
+//! Only the four primary partitions are read, extended partitions are listed like any other
+//! and not followed. The disk images built by `tools/mkimage.py` put the boot loader and the
+//! kernel in front of the first partition, which holds the root file system.
+
+const SIGNATURE_OFFSET: usize = 510;
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+
+/// A primary partition, in sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// 1 to 4, as in `/dev/sda1`
+    pub number: u8,
+    pub kind: u8,
+    pub first: u64,
+    pub sectors: u64,
+}
+
+/// Returns the partitions in the first sector of a disk. Empty if it has no partition table.
+pub fn parse(sector: &[u8]) -> impl Iterator<Item = Partition> + '_ {
+    let valid = sector.get(SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2) == Some(&[0x55, 0xaa]);
+    let table = if valid {
+        &sector[TABLE_OFFSET..SIGNATURE_OFFSET]
+    } else {
+        &[]
+    };
+    table
+        .chunks_exact(ENTRY_SIZE)
+        .enumerate()
+        .filter_map(|(i, entry)| {
+            let field = |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+            let partition = Partition {
+                number: i as u8 + 1,
+                kind: entry[4],
+                first: field(8) as u64,
+                sectors: field(12) as u64,
+            };
+            (partition.kind != 0 && partition.sectors != 0).then_some(partition)
+        })
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse() {
+    let mut sector = [0u8; 512];
+    assert_eq!(parse(&sector).count(), 0);
+
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    // partition 2, Linux, 4 MiB at 1 MiB
+    let entry = &mut sector[TABLE_OFFSET + ENTRY_SIZE..TABLE_OFFSET + 2 * ENTRY_SIZE];
+    entry[4] = 0x83;
+    entry[8..12].copy_from_slice(&2048u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&8192u32.to_le_bytes());
+    let partitions: alloc::vec::Vec<_> = parse(&sector).collect();
+    assert_eq!(
+        partitions,
+        [Partition {
+            number: 2,
+            kind: 0x83,
+            first: 2048,
+            sectors: 8192,
+        }]
+    );
+    assert_eq!(parse(&sector[..100]).count(), 0);
+}
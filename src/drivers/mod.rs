@@ -1,8 +1,19 @@
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, format, vec::Vec};
 use spin::Mutex;
 
+use crate::bootreport::{self, Kind};
+use crate::device::{self, DeviceId, State};
+use crate::error::KResult;
 use crate::pci::BAR;
-mod ahci_driver;
+use crate::println;
+pub mod ahci_driver;
+pub mod dma;
+pub mod mbr;
+pub mod ps2;
+pub mod ramdisk;
+pub mod speaker;
+pub mod usb;
+pub mod virtio;
 
 pub trait PhysicalDevice {
     fn get_device_id(&self) -> u16;
@@ -17,6 +28,9 @@ pub trait PhysicalDevice {
     fn get_interrupt_line(&self) -> Option<u8>;
     fn get_interrupt_pin(&self) -> Option<u8>;
     fn unique_identifier(&self) -> &str;
+    /// Reads the 32 bit configuration register at byte `offset`.
+    fn read_config(&self, offset: u16) -> u32;
+    fn write_config(&self, offset: u16, value: u32);
 }
 
 pub trait DriverManager: Send + Sync {
@@ -26,30 +40,93 @@ pub trait DriverManager: Send + Sync {
 pub trait Driver: Send + Sync {
     fn get_name(&self) -> &str;
     fn on_unplug(&self, dev: &dyn PhysicalDevice) -> bool;
+
+    /// Finishes outstanding work and stops the device before the machine sleeps. An error
+    /// cancels the suspend.
+    fn suspend(&self) -> KResult<()> {
+        Ok(())
+    }
+
+    /// Sets the device up again after the machine woke up, or after a cancelled suspend. The
+    /// PCI configuration space is restored before.
+    fn resume(&self) -> KResult<()> {
+        Ok(())
+    }
 }
 
 static DRIVER_MANAGERS: Mutex<Vec<Box<dyn DriverManager>>> = Mutex::new(Vec::new());
-static DRIVERS: Mutex<Vec<Box<dyn Driver>>> = Mutex::new(Vec::new());
+/// The drivers with the device they took.
+static DRIVERS: Mutex<Vec<(DeviceId, Box<dyn Driver>)>> = Mutex::new(Vec::new());
+
+/// Registers the drivers for PCI devices. Call before the PCI scan.
+pub fn init() {
+    register_manager(Box::new(ahci_driver::AhciDriverManager));
+    usb::init();
+    virtio::init();
+}
+
+/// Registers a driver manager, which is offered every device plugged in afterwards.
+pub fn register_manager(manager: Box<dyn DriverManager>) {
+    DRIVER_MANAGERS.lock().push(manager);
+}
 
-pub fn on_plug(dev: &dyn PhysicalDevice) {
+/// Offers a device, registered as `id`, to every driver manager.
+pub fn on_plug(dev: &dyn PhysicalDevice, id: DeviceId) {
     let driver_managers = DRIVER_MANAGERS.lock();
     let mut drivers = DRIVERS.lock();
 
-    for i in 0..driver_managers.len() {
-        if let Some(driver) = driver_managers[i].on_plug(dev) {
-            drivers.push(driver);
+    let name = format!("{:04x}:{:04x}", dev.get_vendor_id(), dev.get_device_id());
+    bootreport::measure(Kind::Probe, &name, || {
+        for i in 0..driver_managers.len() {
+            if let Some(driver) = driver_managers[i].on_plug(dev) {
+                device::bind(id, driver.get_name());
+                drivers.push((id, driver));
+            }
         }
-    }
+    });
 }
 
-pub fn on_unplug(dev: &dyn PhysicalDevice) {
+pub fn on_unplug(dev: &dyn PhysicalDevice, id: DeviceId) {
     let mut drivers = DRIVERS.lock();
 
     let max = drivers.len();
     for i in 1..=max {
         let i = max - i;
-        if drivers[i].on_unplug(dev) {
+        if drivers[i].1.on_unplug(dev) {
             drivers.remove(i);
         }
     }
+    device::unregister(id);
+}
+
+/// Suspends every driver, the last one bound first so devices go before the buses they are on.
+/// If a driver fails, the ones already suspended are resumed and its error is returned.
+pub fn suspend_all() -> KResult<()> {
+    let drivers = DRIVERS.lock();
+    for (i, (id, driver)) in drivers.iter().enumerate().rev() {
+        if let Err(e) = driver.suspend() {
+            println!("{}: suspend failed: {}", driver.get_name(), e);
+            for (id, driver) in &drivers[i + 1..] {
+                resume(*id, driver.as_ref());
+            }
+            return Err(e);
+        }
+        device::set_state(*id, State::Suspended);
+    }
+    Ok(())
+}
+
+/// Resumes every driver in the order they were bound.
+pub fn resume_all() {
+    for (id, driver) in DRIVERS.lock().iter() {
+        resume(*id, driver.as_ref());
+    }
+}
+
+/// A driver that fails to resume keeps its device marked as suspended.
+fn resume(id: DeviceId, driver: &dyn Driver) {
+    match driver.resume() {
+        Ok(()) => device::set_state(id, State::Bound),
+        Err(e) => println!("{}: resume failed: {}", driver.get_name(), e),
+    }
 }
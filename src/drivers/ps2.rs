@@ -0,0 +1,261 @@
+//! Driver for the 8042 PS/2 controller.
+//!
+//! See [osdev](https://wiki.osdev.org/%228042%22_PS/2_Controller) for the initialization sequence.
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xa7;
+const CMD_ENABLE_PORT2: u8 = 0xa8;
+const CMD_TEST_PORT2: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_PORT1: u8 = 0xab;
+const CMD_DISABLE_PORT1: u8 = 0xad;
+const CMD_ENABLE_PORT1: u8 = 0xae;
+const CMD_WRITE_PORT2: u8 = 0xd4;
+
+const DEV_IDENTIFY: u8 = 0xf2;
+const DEV_ENABLE_SCANNING: u8 = 0xf4;
+const DEV_DISABLE_SCANNING: u8 = 0xf5;
+const DEV_RESET: u8 = 0xff;
+const DEV_ACK: u8 = 0xfa;
+const DEV_SELF_TEST_PASSED: u8 = 0xaa;
+
+/// Number of status polls before a controller operation is considered timed out.
+const TIMEOUT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// There is no 8042, like on machines with only USB and no legacy emulation.
+    NotPresent,
+    /// The controller didn't answer in time.
+    Timeout,
+    /// The controller self test failed.
+    SelfTestFailed(u8),
+    /// No port passed its interface test.
+    NoUsablePort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Device {
+    /// An AT keyboard with translation, which doesn't send identification bytes
+    AtKeyboard,
+    Mf2Keyboard,
+    StandardMouse,
+    ScrollMouse,
+    FiveButtonMouse,
+    Unknown(u8, u8),
+}
+
+impl Ps2Device {
+    fn from_id(id: &[u8]) -> Self {
+        match id {
+            [] => Self::AtKeyboard,
+            [0x00] => Self::StandardMouse,
+            [0x03] => Self::ScrollMouse,
+            [0x04] => Self::FiveButtonMouse,
+            [0xab, 0x41] | [0xab, 0xc1] | [0xab, 0x83] => Self::Mf2Keyboard,
+            [a] => Self::Unknown(*a, 0),
+            [a, b, ..] => Self::Unknown(*a, *b),
+        }
+    }
+
+    pub fn is_keyboard(&self) -> bool {
+        matches!(self, Self::AtKeyboard | Self::Mf2Keyboard)
+    }
+}
+
+/// State of the controller after initialization.
+#[derive(Debug, Clone, Copy)]
+pub struct Ps2Controller {
+    pub dual_channel: bool,
+    /// Device detected on each port, `None` if the port is missing, broken or empty.
+    pub devices: [Option<Ps2Device>; 2],
+}
+
+static CONTROLLER: Mutex<Option<Ps2Controller>> = Mutex::new(None);
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_PORT).read() }
+}
+
+fn wait_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn wait_output_full() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn command(cmd: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(cmd) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(data) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, Ps2Error> {
+    wait_output_full()?;
+    Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+fn flush_output() {
+    for _ in 0..16 {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        unsafe { Port::<u8>::new(DATA_PORT).read() };
+    }
+}
+
+fn command_with_response(cmd: u8) -> Result<u8, Ps2Error> {
+    command(cmd)?;
+    read_data()
+}
+
+/// Sends a byte to the device on `port` (0 or 1).
+fn send_to_device(port: usize, byte: u8) -> Result<(), Ps2Error> {
+    if port == 1 {
+        command(CMD_WRITE_PORT2)?;
+    }
+    write_data(byte)
+}
+
+/// Sends a command to a device and waits for its acknowledgement.
+fn device_command(port: usize, byte: u8) -> Result<(), Ps2Error> {
+    send_to_device(port, byte)?;
+    match read_data()? {
+        DEV_ACK => Ok(()),
+        _ => Err(Ps2Error::Timeout),
+    }
+}
+
+/// Resets and identifies the device on `port`, leaving scanning enabled.
+fn probe_device(port: usize) -> Result<Ps2Device, Ps2Error> {
+    device_command(port, DEV_RESET)?;
+    if read_data()? != DEV_SELF_TEST_PASSED {
+        return Err(Ps2Error::Timeout);
+    }
+    // mice send their id after the self test result
+    flush_output();
+
+    device_command(port, DEV_DISABLE_SCANNING)?;
+    device_command(port, DEV_IDENTIFY)?;
+    let mut id = [0u8; 2];
+    let mut len = 0;
+    while len < id.len() {
+        match read_data() {
+            Ok(byte) => {
+                id[len] = byte;
+                len += 1;
+            }
+            Err(_) => break,
+        }
+    }
+    device_command(port, DEV_ENABLE_SCANNING)?;
+    Ok(Ps2Device::from_id(&id[..len]))
+}
+
+fn init_controller() -> Result<Ps2Controller, Ps2Error> {
+    // nothing drives the bus without a controller, reads float high
+    if status() == 0xff {
+        return Err(Ps2Error::NotPresent);
+    }
+    command(CMD_DISABLE_PORT1).map_err(|_| Ps2Error::NotPresent)?;
+    command(CMD_DISABLE_PORT2)?;
+    flush_output();
+
+    let mut config = command_with_response(CMD_READ_CONFIG)?;
+    config &= !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ | CONFIG_TRANSLATION);
+    command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    let result = command_with_response(CMD_SELF_TEST)?;
+    if result != 0x55 {
+        return Err(Ps2Error::SelfTestFailed(result));
+    }
+    // some controllers reset themselves during the self test
+    command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    command(CMD_ENABLE_PORT2)?;
+    let dual_channel = command_with_response(CMD_READ_CONFIG)? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+    if dual_channel {
+        command(CMD_DISABLE_PORT2)?;
+    }
+
+    let port1_ok = command_with_response(CMD_TEST_PORT1)? == 0;
+    let port2_ok = dual_channel && command_with_response(CMD_TEST_PORT2)? == 0;
+    if !port1_ok && !port2_ok {
+        return Err(Ps2Error::NoUsablePort);
+    }
+
+    let mut devices = [None, None];
+    if port1_ok {
+        command(CMD_ENABLE_PORT1)?;
+        devices[0] = probe_device(0).ok();
+    }
+    if port2_ok {
+        command(CMD_ENABLE_PORT2)?;
+        devices[1] = probe_device(1).ok();
+    }
+    flush_output();
+
+    // The keyboard handler decodes scancode set 1, so let the controller translate.
+    // Only the first port gets an interrupt, there is no mouse handler yet.
+    if devices[0].is_some() {
+        config |= CONFIG_PORT1_IRQ | CONFIG_TRANSLATION;
+    }
+    command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+
+    Ok(Ps2Controller {
+        dual_channel,
+        devices,
+    })
+}
+
+/// Initializes the controller and the attached devices. `Ps2Error::NotPresent` if there is
+/// none, which is fine as far as the rest of the kernel is concerned.
+///
+/// Can be called again at any time to re-initialize a controller left in a bad state.
+pub fn init() -> Result<Ps2Controller, Ps2Error> {
+    without_interrupts(|| {
+        let result = init_controller();
+        *CONTROLLER.lock() = result.ok();
+        result
+    })
+}
+
+/// Returns the controller state, `None` if it wasn't (successfully) initialized.
+pub fn controller() -> Option<Ps2Controller> {
+    without_interrupts(|| *CONTROLLER.lock())
+}
@@ -0,0 +1,116 @@
+//! Block devices backed by memory: a read-only one borrowing its data and a writable one
+//! owning it.
+use alloc::vec::Vec;
+
+use crate::ext::{Errno, RWS};
+
+pub struct RamDisk<'a> {
+    data: &'a [u8],
+    pos: u64,
+}
+
+impl<'a> RamDisk<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl RWS for RamDisk<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, Errno> {
+        let read = self.read_at(self.pos, buf)?;
+        self.pos += read;
+        Ok(read)
+    }
+
+    fn read_at(&mut self, addr: u64, buf: &mut [u8]) -> Result<u64, Errno> {
+        let start = addr.min(self.len()) as usize;
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        Ok(len as u64)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<u64, Errno> {
+        Err(Errno::AccessError)
+    }
+
+    fn write_at(&mut self, _addr: u64, _buf: &[u8]) -> Result<u64, Errno> {
+        Err(Errno::AccessError)
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), Errno> {
+        self.seek_absolute(self.pos + offset)
+    }
+
+    fn seek_absolute(&mut self, to: u64) -> Result<(), Errno> {
+        if to > self.len() {
+            return Err(Errno::OutOfSpace);
+        }
+        self.pos = to;
+        Ok(())
+    }
+}
+
+/// Writable block device backed by memory, like a file system image loaded into memory.
+pub struct MemDisk {
+    data: Vec<u8>,
+    pos: u64,
+}
+
+impl MemDisk {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl RWS for MemDisk {
+    fn read(&mut self, buf: &mut [u8]) -> Result<u64, Errno> {
+        let read = self.read_at(self.pos, buf)?;
+        self.pos += read;
+        Ok(read)
+    }
+
+    fn read_at(&mut self, addr: u64, buf: &mut [u8]) -> Result<u64, Errno> {
+        RamDisk::new(&self.data).read_at(addr, buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<u64, Errno> {
+        let written = self.write_at(self.pos, buf)?;
+        self.pos += written;
+        Ok(written)
+    }
+
+    /// Writes past the end fail, the disk doesn't grow.
+    fn write_at(&mut self, addr: u64, buf: &[u8]) -> Result<u64, Errno> {
+        let start = addr.min(self.data.len() as u64) as usize;
+        let len = buf.len().min(self.data.len() - start);
+        if len < buf.len() {
+            return Err(Errno::OutOfSpace);
+        }
+        self.data[start..start + len].copy_from_slice(buf);
+        Ok(len as u64)
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), Errno> {
+        self.seek_absolute(self.pos + offset)
+    }
+
+    fn seek_absolute(&mut self, to: u64) -> Result<(), Errno> {
+        if to > self.data.len() as u64 {
+            return Err(Errno::OutOfSpace);
+        }
+        self.pos = to;
+        Ok(())
+    }
+}
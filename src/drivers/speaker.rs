@@ -0,0 +1,57 @@
+//! PC speaker driven by PIT channel 2.
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::time::PIT_FREQUENCY;
+use crate::timer::{self, TimerHandle};
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const SPEAKER_PORT: u16 = 0x61;
+
+/// Timer that turns off the currently playing tone.
+static STOP_TIMER: Mutex<Option<TimerHandle>> = Mutex::new(None);
+
+fn start_tone(freq: u32) {
+    let divisor = (PIT_FREQUENCY / freq.max(20) as u64).min(u16::MAX as u64) as u16;
+    unsafe {
+        // channel 2, lobyte/hibyte, mode 3 (square wave)
+        Port::<u8>::new(PIT_COMMAND).write(0xb6);
+        let mut data = Port::<u8>::new(PIT_CHANNEL2);
+        data.write((divisor & 0xff) as u8);
+        data.write((divisor >> 8) as u8);
+
+        let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+        let value = speaker.read();
+        if value & 3 != 3 {
+            speaker.write(value | 3);
+        }
+    }
+}
+
+/// Silences the speaker.
+pub fn stop() {
+    unsafe {
+        let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+        let value = speaker.read();
+        speaker.write(value & !3);
+    }
+}
+
+/// Plays a tone of `freq` Hz for `duration` without blocking.
+///
+/// A new beep replaces the one that is currently playing.
+pub fn beep(freq: u32, duration: Duration) {
+    without_interrupts(|| {
+        if let Some(handle) = STOP_TIMER.lock().take() {
+            handle.cancel();
+        }
+        start_tone(freq);
+    });
+    let handle = timer::schedule_in(duration, || {
+        STOP_TIMER.lock().take();
+        stop();
+    });
+    without_interrupts(|| *STOP_TIMER.lock() = Some(handle));
+}
@@ -0,0 +1,163 @@
+//! HID boot protocol keyboards.
+//!
+//! Boot protocol reports are 8 bytes: a modifier bitmap, a reserved byte and up to six usage
+//! codes of the keys held down. Reports are turned into the key events a PS/2 keyboard would
+//! produce, so both end up in `keyboard::process_event`. USB keyboards don't repeat keys on
+//! their own, repeating held keys is done here.
+use core::time::Duration;
+use pc_keyboard::{HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    keyboard::{self, Layout},
+    time,
+};
+
+pub const CLASS_HID: u8 = 3;
+pub const SUBCLASS_BOOT: u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+
+pub const REQ_SET_IDLE: u8 = 0x0a;
+pub const REQ_SET_PROTOCOL: u8 = 0x0b;
+
+pub const REPORT_SIZE: usize = 8;
+
+/// Usage code sent in every key slot when too many keys are pressed.
+const USAGE_ROLLOVER: u8 = 0x01;
+
+const REPEAT_DELAY: Duration = Duration::from_millis(500);
+const REPEAT_INTERVAL: Duration = Duration::from_millis(33);
+
+/// The modifier bitmap, from bit 0 on.
+const MODIFIERS: [KeyCode; 8] = [
+    KeyCode::ControlLeft,
+    KeyCode::ShiftLeft,
+    KeyCode::AltLeft,
+    KeyCode::WindowsLeft,
+    KeyCode::ControlRight,
+    KeyCode::ShiftRight,
+    KeyCode::AltRight,
+    KeyCode::WindowsRight,
+];
+
+pub struct BootKeyboard {
+    keyboard: Keyboard<Layout, ScancodeSet1>,
+    last: [u8; REPORT_SIZE],
+    /// the key that was pressed last and is still held, with when it repeats next
+    repeat: Option<(KeyCode, Duration)>,
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+        Self {
+            keyboard: Keyboard::new(Layout, ScancodeSet1, HandleControl::MapLettersToUnicode),
+            last: [0; REPORT_SIZE],
+            repeat: None,
+        }
+    }
+
+    /// Handles a new report from the keyboard.
+    pub fn report(&mut self, report: &[u8; REPORT_SIZE]) {
+        if report[2..].contains(&USAGE_ROLLOVER) {
+            return;
+        }
+        let (old, new) = (self.last, *report);
+        self.last = new;
+
+        for (bit, code) in MODIFIERS.iter().enumerate() {
+            let (was, is) = (old[0] & 1 << bit != 0, new[0] & 1 << bit != 0);
+            if was != is {
+                self.send(*code, if is { KeyState::Down } else { KeyState::Up });
+            }
+        }
+        for usage in old[2..].iter().filter(|usage| !new[2..].contains(usage)) {
+            if let Some(code) = usage_to_keycode(*usage) {
+                if matches!(self.repeat, Some((held, _)) if held == code) {
+                    self.repeat = None;
+                }
+                self.send(code, KeyState::Up);
+            }
+        }
+        for usage in new[2..].iter().filter(|usage| !old[2..].contains(usage)) {
+            if let Some(code) = usage_to_keycode(*usage) {
+                self.repeat = Some((code, time::uptime() + REPEAT_DELAY));
+                self.send(code, KeyState::Down);
+            }
+        }
+    }
+
+    /// Repeats the held key if it is due. Call regularly, even without new reports.
+    pub fn tick(&mut self) {
+        let now = time::uptime();
+        if let Some((code, due)) = self.repeat {
+            if now >= due {
+                self.repeat = Some((code, now + REPEAT_INTERVAL));
+                self.send(code, KeyState::Down);
+            }
+        }
+    }
+
+    fn send(&mut self, code: KeyCode, state: KeyState) {
+        let keyboard = &mut self.keyboard;
+        without_interrupts(|| keyboard::process_event(keyboard, KeyEvent::new(code, state)));
+    }
+}
+
+/// Translates a usage code of the keyboard page.
+fn usage_to_keycode(usage: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    const LETTERS: [KeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0];
+    const FUNCTION: [KeyCode; 12] = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+    const NUMPAD: [KeyCode; 10] = [
+        Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9, Numpad0,
+    ];
+
+    Some(match usage {
+        0x04..=0x1d => LETTERS[(usage - 0x04) as usize],
+        0x1e..=0x27 => DIGITS[(usage - 0x1e) as usize],
+        0x28 => Enter,
+        0x29 => Escape,
+        0x2a => Backspace,
+        0x2b => Tab,
+        0x2c => Spacebar,
+        0x2d => Minus,
+        0x2e => Equals,
+        0x2f => BracketSquareLeft,
+        0x30 => BracketSquareRight,
+        0x31 => BackSlash,
+        0x32 => HashTilde,
+        0x33 => SemiColon,
+        0x34 => Quote,
+        0x35 => BackTick,
+        0x36 => Comma,
+        0x37 => Fullstop,
+        0x38 => Slash,
+        0x39 => CapsLock,
+        0x3a..=0x45 => FUNCTION[(usage - 0x3a) as usize],
+        0x46 => PrintScreen,
+        0x47 => ScrollLock,
+        0x48 => PauseBreak,
+        0x49 => Insert,
+        0x4a => Home,
+        0x4b => PageUp,
+        0x4c => Delete,
+        0x4d => End,
+        0x4e => PageDown,
+        0x4f => ArrowRight,
+        0x50 => ArrowLeft,
+        0x51 => ArrowDown,
+        0x52 => ArrowUp,
+        0x53 => NumpadLock,
+        0x54 => NumpadSlash,
+        0x55 => NumpadStar,
+        0x56 => NumpadMinus,
+        0x57 => NumpadPlus,
+        0x58 => NumpadEnter,
+        0x59..=0x62 => NUMPAD[(usage - 0x59) as usize],
+        0x63 => NumpadPeriod,
+        _ => return None,
+    })
+}
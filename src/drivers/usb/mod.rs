@@ -0,0 +1,147 @@
+//! USB host controller support.
+//!
+//! Only a UHCI driver exists, and only boot protocol keyboards are driven. Devices are
+//! enumerated once when the controller is found, hubs and hotplug aren't supported.
+use alloc::{boxed::Box, vec::Vec};
+
+pub mod hid;
+pub mod uhci;
+
+pub const DESC_DEVICE: u8 = 1;
+pub const DESC_CONFIGURATION: u8 = 2;
+pub const DESC_INTERFACE: u8 = 4;
+pub const DESC_ENDPOINT: u8 = 5;
+
+const REQ_SET_ADDRESS: u8 = 5;
+const REQ_GET_DESCRIPTOR: u8 = 6;
+const REQ_SET_CONFIGURATION: u8 = 9;
+
+/// Registers the USB host controller drivers.
+pub fn init() {
+    super::register_manager(Box::new(uhci::UhciManager));
+}
+
+/// The 8 byte packet starting every control transfer.
+#[derive(Debug, Clone, Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn get_descriptor(typ: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: 0x80,
+            request: REQ_GET_DESCRIPTOR,
+            value: (typ as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_address(address: u8) -> Self {
+        Self {
+            request_type: 0x00,
+            request: REQ_SET_ADDRESS,
+            value: address as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: 0x00,
+            request: REQ_SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Whether the data stage goes from the device to the host.
+    pub fn is_in(&self) -> bool {
+        self.request_type & 0x80 != 0
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let value = self.value.to_le_bytes();
+        let index = self.index.to_le_bytes();
+        let length = self.length.to_le_bytes();
+        [
+            self.request_type,
+            self.request,
+            value[0],
+            value[1],
+            index[0],
+            index[1],
+            length[0],
+            length[1],
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// The device answered with a STALL handshake.
+    Stalled,
+    /// The transfer failed with a CRC, timeout, babble or buffer error.
+    Transfer,
+    /// The controller didn't finish the transfer in time.
+    Timeout,
+    /// A descriptor was shorter than its header claims.
+    BadDescriptor,
+}
+
+/// The interesting parts of an interface descriptor and its first interrupt IN endpoint.
+#[derive(Debug, Clone, Copy)]
+pub struct InterfaceInfo {
+    pub configuration: u8,
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// endpoint number and max packet size of the interrupt IN endpoint
+    pub interrupt_in: Option<(u8, u16)>,
+}
+
+/// Walks a full configuration descriptor and returns its interfaces.
+pub fn parse_configuration(data: &[u8]) -> Result<Vec<InterfaceInfo>, UsbError> {
+    let mut interfaces = Vec::new();
+    let mut configuration = 0;
+    let mut i = 0;
+    while i + 2 <= data.len() {
+        let len = data[i] as usize;
+        if len < 2 || i + len > data.len() {
+            return Err(UsbError::BadDescriptor);
+        }
+        let desc = &data[i..i + len];
+        match desc[1] {
+            DESC_CONFIGURATION if len >= 9 => configuration = desc[5],
+            DESC_INTERFACE if len >= 9 => interfaces.push(InterfaceInfo {
+                configuration,
+                number: desc[2],
+                class: desc[5],
+                subclass: desc[6],
+                protocol: desc[7],
+                interrupt_in: None,
+            }),
+            DESC_ENDPOINT if len >= 7 => {
+                let is_in = desc[2] & 0x80 != 0;
+                let is_interrupt = desc[3] & 3 == 3;
+                if let Some(interface) = interfaces.last_mut() {
+                    if is_in && is_interrupt && interface.interrupt_in.is_none() {
+                        let max_packet = u16::from_le_bytes([desc[4], desc[5]]) & 0x7ff;
+                        interface.interrupt_in = Some((desc[2] & 0xf, max_packet));
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += len;
+    }
+    Ok(interfaces)
+}
@@ -0,0 +1,482 @@
+//! Driver for UHCI (USB 1.1) host controllers.
+//!
+//! The controller runs a fixed schedule: every frame points at the interrupt queue, which is
+//! followed by the control queue. Control transfers are put on the control queue one at a time
+//! and waited for, the keyboard's interrupt transfer stays on the interrupt queue and is polled
+//! by the controller's task.
+//!
+//! See [osdev](https://wiki.osdev.org/Universal_Host_Controller_Interface).
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::ptr;
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+use super::{
+    hid::{self, BootKeyboard},
+    parse_configuration, SetupPacket, UsbError, DESC_CONFIGURATION, DESC_DEVICE,
+};
+use crate::{
+    device::{self, Bus, DeviceId},
+    drivers::{dma::DmaPage, Driver, DriverManager, PhysicalDevice},
+    pci::BAR,
+    println, task, time, timer,
+};
+
+const REG_USBCMD: u16 = 0x00;
+const REG_USBSTS: u16 = 0x02;
+const REG_USBINTR: u16 = 0x04;
+const REG_FRNUM: u16 = 0x06;
+const REG_FRBASEADD: u16 = 0x08;
+const REG_SOFMOD: u16 = 0x0c;
+const REG_PORTSC: u16 = 0x10;
+
+const CMD_RUN: u16 = 1 << 0;
+const CMD_HCRESET: u16 = 1 << 1;
+const CMD_GRESET: u16 = 1 << 2;
+const CMD_CONFIGURED: u16 = 1 << 6;
+const CMD_MAX_PACKET_64: u16 = 1 << 7;
+
+const PORT_CONNECTED: u16 = 1 << 0;
+const PORT_CONNECT_CHANGE: u16 = 1 << 1;
+const PORT_ENABLED: u16 = 1 << 2;
+const PORT_ENABLE_CHANGE: u16 = 1 << 3;
+const PORT_LOW_SPEED: u16 = 1 << 8;
+const PORT_RESET: u16 = 1 << 9;
+/// bits that are cleared by writing a 1
+const PORT_WRITE_CLEAR: u16 = PORT_CONNECT_CHANGE | PORT_ENABLE_CHANGE;
+
+/// PCI configuration register controlling the BIOS' legacy keyboard emulation.
+const PCI_LEGSUP: u16 = 0xc0;
+const LEGSUP_DEFAULT: u32 = 0x8f00;
+const PCI_COMMAND: u16 = 0x04;
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+const LINK_TERMINATE: u32 = 1 << 0;
+const LINK_QH: u32 = 1 << 1;
+const LINK_DEPTH_FIRST: u32 = 1 << 2;
+
+const TD_ACTIVE: u32 = 1 << 23;
+const TD_STALLED: u32 = 1 << 22;
+const TD_BUFFER_ERROR: u32 = 1 << 21;
+const TD_BABBLE: u32 = 1 << 20;
+const TD_CRC_TIMEOUT: u32 = 1 << 18;
+const TD_BITSTUFF: u32 = 1 << 17;
+const TD_ERRORS: u32 = TD_BUFFER_ERROR | TD_BABBLE | TD_CRC_TIMEOUT | TD_BITSTUFF;
+const TD_LOW_SPEED: u32 = 1 << 26;
+const TD_ERROR_LIMIT: u32 = 3 << 27;
+
+const PID_SETUP: u32 = 0x2d;
+const PID_IN: u32 = 0x69;
+const PID_OUT: u32 = 0xe1;
+
+/// Root hub ports of a UHCI controller.
+const PORTS: u16 = 2;
+const FRAMES: usize = 1024;
+const TRANSFER_TIMEOUT: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Layout of the schedule page. Queue heads and transfer descriptors are 16 byte aligned.
+const INTERRUPT_QH: usize = 0x000;
+const CONTROL_QH: usize = 0x010;
+const KEYBOARD_TD: usize = 0x020;
+const CONTROL_TDS: usize = 0x040;
+const MAX_CONTROL_TDS: usize = 40;
+const SETUP_BUFFER: usize = 0x600;
+const REPORT_BUFFER: usize = 0x608;
+const DATA_BUFFER: usize = 0x800;
+const DATA_BUFFER_SIZE: usize = 0x800;
+
+pub struct UhciManager;
+
+impl DriverManager for UhciManager {
+    fn on_plug(&self, dev: &dyn PhysicalDevice) -> Option<Box<dyn Driver>> {
+        if dev.get_class() != 0x0c || dev.get_subclass() != 0x03 || dev.get_prog_if() != 0x00 {
+            return None;
+        }
+        let io_base = match dev.get_bars().get(4) {
+            Some(Some(BAR::IOSpace { address, .. })) => *address as u16,
+            _ => return None,
+        };
+
+        // hand the controller over from the BIOS and let it do DMA
+        dev.write_config(PCI_LEGSUP, LEGSUP_DEFAULT);
+        let command = dev.read_config(PCI_COMMAND);
+        dev.write_config(PCI_COMMAND, command | COMMAND_IO_SPACE | COMMAND_BUS_MASTER);
+
+        let parent = device::find(Bus::Pci, dev.unique_identifier());
+        let controller = Controller::new(io_base, parent)?;
+        let name = format!("uhci@{}", dev.unique_identifier());
+        task::spawn(&name, move || controller.run());
+        Some(Box::new(UhciDriver { name }))
+    }
+}
+
+pub struct UhciDriver {
+    name: String,
+}
+
+impl Driver for UhciDriver {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_unplug(&self, _dev: &dyn PhysicalDevice) -> bool {
+        false
+    }
+}
+
+/// A device found on a root hub port.
+#[derive(Debug, Clone, Copy)]
+struct Device {
+    address: u8,
+    low_speed: bool,
+    max_packet: u16,
+}
+
+/// The keyboard driven by the controller.
+struct KeyboardEndpoint {
+    device: Device,
+    endpoint: u8,
+    toggle: bool,
+    keyboard: BootKeyboard,
+}
+
+struct Controller {
+    io_base: u16,
+    /// the controller in the device tree
+    parent: Option<DeviceId>,
+    frame_list: DmaPage,
+    schedule: DmaPage,
+    keyboard: Option<KeyboardEndpoint>,
+}
+
+impl Controller {
+    fn new(io_base: u16, parent: Option<DeviceId>) -> Option<Self> {
+        let controller = Self {
+            io_base,
+            parent,
+            frame_list: DmaPage::new()?,
+            schedule: DmaPage::new()?,
+            keyboard: None,
+        };
+        let interrupt_qh = controller.schedule.phys(INTERRUPT_QH) | LINK_QH;
+        for frame in 0..FRAMES {
+            controller.frame_list.write_u32(frame * 4, interrupt_qh);
+        }
+        let control_qh = controller.schedule.phys(CONTROL_QH) | LINK_QH;
+        controller.schedule.write_u32(INTERRUPT_QH, control_qh);
+        controller.schedule.write_u32(INTERRUPT_QH + 4, LINK_TERMINATE);
+        controller.schedule.write_u32(CONTROL_QH, LINK_TERMINATE);
+        controller.schedule.write_u32(CONTROL_QH + 4, LINK_TERMINATE);
+        Some(controller)
+    }
+
+    fn read_reg(&self, reg: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.io_base + reg).read() }
+    }
+
+    fn write_reg(&self, reg: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.io_base + reg).write(value) }
+    }
+
+    fn run(mut self) {
+        self.reset();
+        for port in 0..PORTS {
+            let address = port as u8 + 1;
+            let Some(device) = self.reset_port(port, address) else {
+                continue;
+            };
+            let id = self.register(device, port);
+            if let Err(e) = self.configure(device, id) {
+                println!("uhci: device on port {} failed: {:?}", port, e);
+            }
+        }
+        if self.keyboard.is_none() {
+            return;
+        }
+        loop {
+            timer::sleep(POLL_INTERVAL);
+            self.poll_keyboard();
+        }
+    }
+
+    fn reset(&self) {
+        self.write_reg(REG_USBCMD, CMD_GRESET);
+        timer::sleep(Duration::from_millis(50));
+        self.write_reg(REG_USBCMD, 0);
+        self.write_reg(REG_USBCMD, CMD_HCRESET);
+        let deadline = time::uptime() + Duration::from_millis(50);
+        while self.read_reg(REG_USBCMD) & CMD_HCRESET != 0 && time::uptime() < deadline {
+            task::yield_now();
+        }
+
+        self.write_reg(REG_USBINTR, 0);
+        self.write_reg(REG_FRNUM, 0);
+        unsafe {
+            Port::<u32>::new(self.io_base + REG_FRBASEADD).write(self.frame_list.phys(0));
+            Port::<u8>::new(self.io_base + REG_SOFMOD).write(0x40);
+        }
+        self.write_reg(REG_USBSTS, 0xffff);
+        self.write_reg(REG_USBCMD, CMD_RUN | CMD_CONFIGURED | CMD_MAX_PACKET_64);
+    }
+
+    /// Resets and enables a port, returns the device behind it after moving it to `address`.
+    fn reset_port(&self, port: u16, address: u8) -> Option<Device> {
+        let reg = REG_PORTSC + port * 2;
+        let status = self.read_reg(reg);
+        if status & PORT_CONNECTED == 0 {
+            return None;
+        }
+        self.write_reg(reg, (status & !PORT_WRITE_CLEAR) | PORT_RESET);
+        timer::sleep(Duration::from_millis(50));
+        let status = self.read_reg(reg);
+        self.write_reg(reg, status & !(PORT_WRITE_CLEAR | PORT_RESET));
+        timer::sleep(Duration::from_millis(10));
+
+        // enabling can take a few tries while the device recovers from the reset
+        for _ in 0..10 {
+            let status = self.read_reg(reg);
+            if status & PORT_CONNECTED == 0 {
+                return None;
+            }
+            if status & PORT_ENABLED != 0 {
+                let low_speed = status & PORT_LOW_SPEED != 0;
+                let device = Device {
+                    address: 0,
+                    low_speed,
+                    max_packet: 8,
+                };
+                return self.set_address(device, address).ok();
+            }
+            self.write_reg(reg, status | PORT_WRITE_CLEAR | PORT_ENABLED);
+            timer::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    fn set_address(&self, mut device: Device, address: u8) -> Result<Device, UsbError> {
+        let mut header = [0u8; 8];
+        let request = SetupPacket::get_descriptor(DESC_DEVICE, 0, header.len() as u16);
+        self.control(device, request, &mut header)?;
+        device.max_packet = header[7].max(8) as u16;
+        self.control(device, SetupPacket::set_address(address), &mut [])?;
+        timer::sleep(Duration::from_millis(2));
+        device.address = address;
+        Ok(device)
+    }
+
+    /// Adds the device on `port` to the device tree, with the IDs from its device descriptor
+    /// if it can be read.
+    fn register(&self, device: Device, port: u16) -> DeviceId {
+        let speed = if device.low_speed { "low" } else { "full" };
+        let mut attributes = Vec::from([("speed", String::from(speed))]);
+        let mut descriptor = [0u8; 18];
+        let request = SetupPacket::get_descriptor(DESC_DEVICE, 0, descriptor.len() as u16);
+        if self.control(device, request, &mut descriptor).is_ok() {
+            let word = |n: usize| u16::from_le_bytes([descriptor[n], descriptor[n + 1]]);
+            attributes.extend([
+                ("vendor", format!("0x{:04x}", word(8))),
+                ("product", format!("0x{:04x}", word(10))),
+                ("class", format!("0x{:02x}", descriptor[4])),
+            ]);
+        }
+        let name = format!("port{}", port + 1);
+        device::register(&name, Bus::Usb, self.parent, attributes)
+    }
+
+    /// Picks the device's first configuration and sets it up if it is a keyboard, the device
+    /// registered as `id`.
+    fn configure(&mut self, device: Device, id: DeviceId) -> Result<(), UsbError> {
+        let mut header = [0u8; 9];
+        let request = SetupPacket::get_descriptor(DESC_CONFIGURATION, 0, header.len() as u16);
+        self.control(device, request, &mut header)?;
+        let total = u16::from_le_bytes([header[2], header[3]]).min(DATA_BUFFER_SIZE as u16);
+        let mut config = vec![0; total as usize];
+        let request = SetupPacket::get_descriptor(DESC_CONFIGURATION, 0, total);
+        self.control(device, request, &mut config)?;
+
+        let keyboard = parse_configuration(&config)?.into_iter().find(|interface| {
+            interface.class == hid::CLASS_HID
+                && interface.subclass == hid::SUBCLASS_BOOT
+                && interface.protocol == hid::PROTOCOL_KEYBOARD
+                && interface.interrupt_in.is_some()
+        });
+        let Some(interface) = keyboard else {
+            println!("uhci: ignoring device {} (not a keyboard)", device.address);
+            return Ok(());
+        };
+        if self.keyboard.is_some() {
+            println!("uhci: ignoring keyboard {}, only one is supported", device.address);
+            return Ok(());
+        }
+
+        let request = SetupPacket::set_configuration(interface.configuration);
+        self.control(device, request, &mut [])?;
+        let class_request = |request, value| SetupPacket {
+            request_type: 0x21,
+            request,
+            value,
+            index: interface.number as u16,
+            length: 0,
+        };
+        self.control(device, class_request(hid::REQ_SET_PROTOCOL, 0), &mut [])?;
+        // only report changes, not every poll; keyboards may refuse this
+        let _ = self.control(device, class_request(hid::REQ_SET_IDLE, 0), &mut []);
+
+        let (endpoint, _) = interface.interrupt_in.unwrap();
+        self.keyboard = Some(KeyboardEndpoint {
+            device,
+            endpoint,
+            toggle: false,
+            keyboard: BootKeyboard::new(),
+        });
+        self.queue_keyboard_td();
+        device::bind(id, "usb-keyboard");
+        println!("uhci: keyboard on address {}", device.address);
+        Ok(())
+    }
+
+    fn write_td(&self, offset: usize, link: u32, device: Device, token: u32, buffer: u32) {
+        let mut status = TD_ACTIVE | TD_ERROR_LIMIT;
+        if device.low_speed {
+            status |= TD_LOW_SPEED;
+        }
+        self.schedule.write_u32(offset, link);
+        self.schedule.write_u32(offset + 4, status);
+        self.schedule.write_u32(offset + 8, token);
+        self.schedule.write_u32(offset + 12, buffer);
+    }
+
+    /// Runs a control transfer, `data` is sent or filled depending on the direction of
+    /// `request`.
+    fn control(
+        &self,
+        device: Device,
+        request: SetupPacket,
+        data: &mut [u8],
+    ) -> Result<(), UsbError> {
+        let len = data.len().min(DATA_BUFFER_SIZE);
+        let setup = request.to_bytes();
+        unsafe {
+            ptr::copy_nonoverlapping(setup.as_ptr(), self.schedule.ptr(SETUP_BUFFER), 8);
+            if !request.is_in() {
+                ptr::copy_nonoverlapping(data.as_ptr(), self.schedule.ptr(DATA_BUFFER), len);
+            }
+        }
+
+        let mut tds = Vec::new();
+        tds.push((PID_SETUP, false, SETUP_BUFFER, 8));
+        let data_pid = if request.is_in() { PID_IN } else { PID_OUT };
+        let mut toggle = true;
+        for offset in (0..len).step_by(device.max_packet as usize) {
+            let size = (len - offset).min(device.max_packet as usize);
+            tds.push((data_pid, toggle, DATA_BUFFER + offset, size));
+            toggle = !toggle;
+        }
+        // the status stage goes the other way and always uses DATA1
+        let status_pid = if request.is_in() && len > 0 { PID_OUT } else { PID_IN };
+        tds.push((status_pid, true, 0, 0));
+        if tds.len() > MAX_CONTROL_TDS {
+            return Err(UsbError::Transfer);
+        }
+
+        for (i, (pid, toggle, buffer, size)) in tds.iter().enumerate() {
+            let offset = CONTROL_TDS + i * 32;
+            let link = if i + 1 == tds.len() {
+                LINK_TERMINATE
+            } else {
+                self.schedule.phys(offset + 32) | LINK_DEPTH_FIRST
+            };
+            let token = token(*pid, device.address, 0, *toggle, *size);
+            let buffer = if *size == 0 { 0 } else { self.schedule.phys(*buffer) };
+            self.write_td(offset, link, device, token, buffer);
+        }
+        self.schedule.write_u32(CONTROL_QH + 4, self.schedule.phys(CONTROL_TDS));
+
+        let result = self.wait_for_tds(tds.len());
+        self.schedule.write_u32(CONTROL_QH + 4, LINK_TERMINATE);
+        result?;
+
+        if request.is_in() {
+            unsafe {
+                ptr::copy_nonoverlapping(self.schedule.ptr(DATA_BUFFER), data.as_mut_ptr(), len)
+            };
+        }
+        Ok(())
+    }
+
+    fn wait_for_tds(&self, count: usize) -> Result<(), UsbError> {
+        let deadline = time::uptime() + TRANSFER_TIMEOUT;
+        loop {
+            let mut done = true;
+            for i in 0..count {
+                let status = self.schedule.read_u32(CONTROL_TDS + i * 32 + 4);
+                if status & TD_STALLED != 0 {
+                    return Err(UsbError::Stalled);
+                }
+                if status & TD_ERRORS != 0 {
+                    return Err(UsbError::Transfer);
+                }
+                done &= status & TD_ACTIVE == 0;
+            }
+            if done {
+                return Ok(());
+            }
+            if time::uptime() >= deadline {
+                return Err(UsbError::Timeout);
+            }
+            task::yield_now();
+        }
+    }
+
+    fn queue_keyboard_td(&self) {
+        let Some(keyboard) = &self.keyboard else {
+            return;
+        };
+        let token = token(
+            PID_IN,
+            keyboard.device.address,
+            keyboard.endpoint,
+            keyboard.toggle,
+            hid::REPORT_SIZE,
+        );
+        let buffer = self.schedule.phys(REPORT_BUFFER);
+        self.write_td(KEYBOARD_TD, LINK_TERMINATE, keyboard.device, token, buffer);
+        let td = self.schedule.phys(KEYBOARD_TD);
+        self.schedule.write_u32(INTERRUPT_QH + 4, td);
+    }
+
+    fn poll_keyboard(&mut self) {
+        let status = self.schedule.read_u32(KEYBOARD_TD + 4);
+        if status & TD_ACTIVE != 0 {
+            if let Some(keyboard) = &mut self.keyboard {
+                keyboard.keyboard.tick();
+            }
+            return;
+        }
+        let mut report = [0u8; hid::REPORT_SIZE];
+        unsafe {
+            ptr::copy_nonoverlapping(
+                self.schedule.ptr(REPORT_BUFFER),
+                report.as_mut_ptr(),
+                report.len(),
+            )
+        };
+        if let Some(keyboard) = &mut self.keyboard {
+            if status & (TD_STALLED | TD_ERRORS) == 0 {
+                keyboard.toggle = !keyboard.toggle;
+                keyboard.keyboard.report(&report);
+            }
+            keyboard.keyboard.tick();
+        }
+        self.queue_keyboard_td();
+    }
+}
+
+/// Builds the token of a transfer descriptor.
+fn token(pid: u32, address: u8, endpoint: u8, toggle: bool, len: usize) -> u32 {
+    // the length is encoded as n - 1, 0x7ff meaning no data
+    let max_len = (len as u32).wrapping_sub(1) & 0x7ff;
+    pid | (address as u32) << 8 | (endpoint as u32) << 15 | (toggle as u32) << 19 | max_len << 21
+}
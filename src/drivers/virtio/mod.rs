@@ -0,0 +1,286 @@
+//! Virtio devices over the legacy PCI interface.
+//!
+//! Only the legacy (virtio 0.9.5) register layout in I/O space is used, which QEMU's transitional
+//! devices offer next to the modern one. Queues are polled, the device is told not to interrupt.
+//!
+//! See [the specification](https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html),
+//! section 4.1.4.8 for the legacy interface.
+use alloc::boxed::Box;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    drivers::{dma::DmaPage, PhysicalDevice},
+    mem::PAGE_SIZE,
+    pci::BAR,
+    task, time,
+};
+
+pub mod net;
+pub mod ninep;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+/// Start of the device specific configuration, without MSI-X.
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+const PCI_COMMAND: u16 = 0x04;
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+/// How long a request may take, the other side could be a slow host file system.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Registers the virtio drivers.
+pub fn init() {
+    super::register_manager(Box::new(ninep::Virtio9pManager));
+    super::register_manager(Box::new(net::VirtioNetManager));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The queue doesn't exist or its ring couldn't be allocated.
+    NoQueue,
+    /// More buffers than the queue has descriptors.
+    TooManyBuffers,
+    /// The device didn't finish the request in time.
+    Timeout,
+}
+
+/// The registers of a device.
+pub struct Transport {
+    io_base: u16,
+}
+
+impl Transport {
+    /// Resets a legacy device and acknowledges it. `None` if it has no I/O BAR.
+    pub fn new(dev: &dyn PhysicalDevice) -> Option<Self> {
+        let io_base = match dev.get_bars().first() {
+            Some(Some(BAR::IOSpace { address, .. })) => *address as u16,
+            _ => return None,
+        };
+        let command = dev.read_config(PCI_COMMAND);
+        dev.write_config(PCI_COMMAND, command | COMMAND_IO_SPACE | COMMAND_BUS_MASTER);
+
+        let transport = Self { io_base };
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Some(transport)
+    }
+
+    fn port<T>(&self, reg: u16) -> Port<T> {
+        Port::new(self.io_base + reg)
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe { self.port(REG_DEVICE_STATUS).write(status) }
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { self.port(REG_DEVICE_STATUS).read() }
+    }
+
+    /// Accepts the features in `wanted` the device offers, returns them.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        let features = unsafe { self.port::<u32>(REG_DEVICE_FEATURES).read() } & wanted;
+        unsafe { self.port(REG_GUEST_FEATURES).write(features) };
+        features
+    }
+
+    /// Tells the device the driver is ready, after its queues are set up.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Reads a byte of the device specific configuration.
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        unsafe { self.port(REG_DEVICE_CONFIG + offset).read() }
+    }
+
+    pub fn config_u16(&self, offset: u16) -> u16 {
+        u16::from_le_bytes([self.config_u8(offset), self.config_u8(offset + 1)])
+    }
+
+    /// Sets up queue `index`.
+    pub fn queue(&self, index: u16) -> Result<VirtQueue, VirtioError> {
+        unsafe { self.port(REG_QUEUE_SELECT).write(index) };
+        let size: u16 = unsafe { self.port(REG_QUEUE_SIZE).read() };
+        if size == 0 {
+            return Err(VirtioError::NoQueue);
+        }
+        let layout = RingLayout::new(size);
+        let ring = DmaPage::contiguous(layout.size / PAGE_SIZE).ok_or(VirtioError::NoQueue)?;
+        unsafe {
+            ptr::write_volatile(ring.ptr(layout.avail), AVAIL_NO_INTERRUPT);
+            self.port(REG_QUEUE_ADDRESS)
+                .write(ring.phys(0) / PAGE_SIZE as u32);
+        }
+        Ok(VirtQueue {
+            index,
+            size,
+            layout,
+            ring,
+            avail_idx: 0,
+            used_idx: 0,
+        })
+    }
+
+    fn notify(&self, queue: u16) {
+        unsafe { self.port(REG_QUEUE_NOTIFY).write(queue) }
+    }
+}
+
+/// Where the parts of a legacy ring lie, it has to be physically contiguous.
+#[derive(Debug, Clone, Copy)]
+struct RingLayout {
+    avail: usize,
+    used: usize,
+    size: usize,
+}
+
+impl RingLayout {
+    fn new(queue_size: u16) -> Self {
+        let queue_size = queue_size as usize;
+        // descriptors, then the available ring, the used ring on the next page
+        let avail = 16 * queue_size;
+        let used = (avail + 6 + 2 * queue_size).next_multiple_of(PAGE_SIZE);
+        let size = (used + 6 + 8 * queue_size).next_multiple_of(PAGE_SIZE);
+        Self { avail, used, size }
+    }
+}
+
+/// A buffer handed to the device.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub phys: u64,
+    pub len: u32,
+    /// whether the device writes to it instead of reading it
+    pub writable: bool,
+}
+
+/// A queue, used either for one request at a time with `transfer`, or for several chains the
+/// device holds on to, like receive buffers, with `submit` and `next_used`.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    layout: RingLayout,
+    ring: DmaPage,
+    avail_idx: u16,
+    used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Number of descriptors.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Hands `buffers` to the device as one chain and waits until it is done with them. Returns
+    /// how many bytes the device wrote.
+    pub fn transfer(
+        &mut self,
+        transport: &Transport,
+        buffers: &[Buffer],
+    ) -> Result<u32, VirtioError> {
+        self.submit(transport, 0, buffers)?;
+        let deadline = time::uptime() + TRANSFER_TIMEOUT;
+        loop {
+            if let Some((_, written)) = self.next_used() {
+                return Ok(written);
+            }
+            if time::uptime() >= deadline {
+                return Err(VirtioError::Timeout);
+            }
+            task::yield_now();
+        }
+    }
+
+    /// Hands `buffers` to the device as one chain in the descriptors from `first` on, without
+    /// waiting. The descriptors must not be in use by another chain.
+    pub fn submit(
+        &mut self,
+        transport: &Transport,
+        first: u16,
+        buffers: &[Buffer],
+    ) -> Result<(), VirtioError> {
+        if buffers.is_empty() || first as usize + buffers.len() > self.size as usize {
+            return Err(VirtioError::TooManyBuffers);
+        }
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = first + i as u16;
+            let desc = index as usize * 16;
+            let mut flags = if buffer.writable { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_NEXT;
+            }
+            unsafe {
+                ptr::write_volatile(self.ring.ptr(desc), buffer.phys);
+                ptr::write_volatile(self.ring.ptr(desc + 8), buffer.len);
+                ptr::write_volatile(self.ring.ptr(desc + 12), flags);
+                ptr::write_volatile(self.ring.ptr(desc + 14), index + 1);
+            }
+        }
+
+        let slot = self.layout.avail + 4 + 2 * (self.avail_idx % self.size) as usize;
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe {
+            ptr::write_volatile(self.ring.ptr::<u16>(slot), first);
+            // the descriptors have to be visible before the index
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(self.ring.ptr(self.layout.avail + 2), self.avail_idx);
+            fence(Ordering::SeqCst);
+        }
+        transport.notify(self.index);
+        Ok(())
+    }
+
+    /// Returns the next chain the device is done with, as its first descriptor and how many
+    /// bytes the device wrote. `None` if the device hasn't finished one since the last call.
+    pub fn next_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = self.ring.ptr::<u16>(self.layout.used + 2);
+        if unsafe { ptr::read_volatile(used_idx) } == self.used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = self.layout.used + 4 + 8 * (self.used_idx % self.size) as usize;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        let id: u32 = unsafe { ptr::read_volatile(self.ring.ptr(element)) };
+        let written = unsafe { ptr::read_volatile(self.ring.ptr(element + 4)) };
+        Some((id as u16, written))
+    }
+}
+
+#[test_case]
+fn test_ring_layout() {
+    // QEMU's 9p and block devices
+    let layout = RingLayout::new(128);
+    assert_eq!((layout.avail, layout.used, layout.size), (2048, 4096, 8192));
+    let layout = RingLayout::new(256);
+    assert_eq!(
+        (layout.avail, layout.used, layout.size),
+        (4096, 8192, 12288)
+    );
+}
@@ -0,0 +1,384 @@
+//! Driver for virtio network cards, QEMU's `-device virtio-net-pci`.
+//!
+//! Cards become the interfaces `eth0`, `eth1` and so on. `eth<n>` takes its address from the
+//! kernel argument `ip<n>=<address>/<prefix>[,<gateway>]`, `eth0` from `ip=` and otherwise the
+//! one QEMU's user networking hands out, 10.0.2.15/24 via 10.0.2.2. A task polls the receive
+//! queue.
+//!
+//! Checksum offload is negotiated: with `VIRTIO_NET_F_CSUM` the card completes the TCP and UDP
+//! checksums of sent frames, with `VIRTIO_NET_F_GUEST_CSUM` it marks received frames whose
+//! checksums it checked. Neither covers the IPv4 header checksum.
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+use super::{Buffer, Transport, VirtQueue, VENDOR_ID};
+use crate::{
+    bootargs,
+    drivers::{dma::DmaPage, Driver, DriverManager, PhysicalDevice},
+    error::{KError, KResult},
+    mem::PAGE_SIZE,
+    net::{
+        self,
+        ethernet::{self, MacAddr},
+        ipv4, ChecksumOffload, Checksums, Ipv4Addr, NetDevice,
+    },
+    println, task, timer,
+};
+
+/// Device id of the transitional network card.
+const DEVICE_ID: u16 = 0x1000;
+const FEATURE_CSUM: u32 = 1 << 0;
+const FEATURE_GUEST_CSUM: u32 = 1 << 1;
+const FEATURE_MAC: u32 = 1 << 5;
+
+const QUEUE_RX: u16 = 0;
+const QUEUE_TX: u16 = 1;
+
+/// The header before every frame, without mergeable receive buffers.
+const HEADER_SIZE: usize = 10;
+const HEADER_NEEDS_CSUM: u8 = 1;
+const HEADER_DATA_VALID: u8 = 2;
+
+const MTU: usize = 1500;
+const FRAME_SIZE: usize = MTU + ethernet::HEADER_SIZE;
+/// Receive buffers, each a header and a frame in half a page.
+const RX_BUFFERS: usize = 32;
+const RX_SLOT_SIZE: usize = PAGE_SIZE / 2;
+/// Where the frame starts in the transmit page, after the header.
+const TX_FRAME_OFFSET: usize = 64;
+/// How long the receive task sleeps once the queue is empty.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// QEMU's user networking.
+const DEFAULT_ADDRESS: (Ipv4Addr, u8, Option<Ipv4Addr>) =
+    (Ipv4Addr([10, 0, 2, 15]), 24, Some(Ipv4Addr([10, 0, 2, 2])));
+
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+pub struct VirtioNetManager;
+
+impl DriverManager for VirtioNetManager {
+    fn on_plug(&self, dev: &dyn PhysicalDevice) -> Option<Box<dyn Driver>> {
+        if dev.get_vendor_id() != VENDOR_ID || dev.get_device_id() != DEVICE_ID {
+            return None;
+        }
+        let transport = Transport::new(dev)?;
+        let features = transport.negotiate(FEATURE_MAC | FEATURE_CSUM | FEATURE_GUEST_CSUM);
+        let mac = match features & FEATURE_MAC {
+            0 => MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+            _ => MacAddr(core::array::from_fn(|i| transport.config_u8(i as u16))),
+        };
+        let tx = features & FEATURE_CSUM != 0;
+        let rx = features & FEATURE_GUEST_CSUM != 0;
+        let offload = ChecksumOffload {
+            tx: Checksums {
+                ipv4: false,
+                tcp: tx,
+                udp: tx,
+            },
+            rx: Checksums {
+                ipv4: false,
+                tcp: rx,
+                udp: rx,
+            },
+        };
+
+        let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+        let name = format!("eth{}", index);
+        let Some(card) = VirtioNet::new(transport, name.clone(), mac, offload) else {
+            println!("virtio-net: can't set up the queues of {}", name);
+            return None;
+        };
+        let card = Arc::new(card);
+        let key = match index {
+            0 => String::from("ip"),
+            index => format!("ip{}", index),
+        };
+        let address = match bootargs::get(&key) {
+            Some(arg) => parse_address(arg),
+            None if index == 0 => Some(DEFAULT_ADDRESS),
+            None => None,
+        };
+        match address {
+            Some((addr, prefix, gateway)) => {
+                net::add_interface(card.clone(), addr, prefix, gateway);
+                println!("virtio-net: {} ({}) is {}/{}", name, mac, addr, prefix);
+            }
+            None => println!("virtio-net: no address for {}, set {}=", name, key),
+        }
+        task::spawn(&name, move || card.run());
+        Some(Box::new(VirtioNetDriver { name }))
+    }
+}
+
+pub struct VirtioNetDriver {
+    name: String,
+}
+
+impl Driver for VirtioNetDriver {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_unplug(&self, _dev: &dyn PhysicalDevice) -> bool {
+        false
+    }
+}
+
+/// Parses `<address>/<prefix>[,<gateway>]`.
+fn parse_address(arg: &str) -> Option<(Ipv4Addr, u8, Option<Ipv4Addr>)> {
+    let (net, gateway) = match arg.split_once(',') {
+        Some((net, gateway)) => (net, Some(gateway.parse().ok()?)),
+        None => (arg, None),
+    };
+    let (addr, prefix) = net.split_once('/')?;
+    let prefix = prefix.parse().ok().filter(|prefix| *prefix <= 32)?;
+    Some((addr.parse().ok()?, prefix, gateway))
+}
+
+struct Rx {
+    queue: VirtQueue,
+    pages: Vec<DmaPage>,
+}
+
+impl Rx {
+    /// The page and offset of receive buffer `slot`.
+    fn slot(&self, slot: usize) -> (&DmaPage, usize) {
+        (&self.pages[slot / 2], slot % 2 * RX_SLOT_SIZE)
+    }
+
+    /// Hands receive buffer `slot` to the card, in descriptors `2 * slot` and the next.
+    fn post(&mut self, transport: &Transport, slot: usize) -> KResult<()> {
+        let (page, offset) = self.slot(slot);
+        let buffers = [
+            Buffer {
+                phys: page.phys(offset) as u64,
+                len: HEADER_SIZE as u32,
+                writable: true,
+            },
+            Buffer {
+                phys: page.phys(offset + HEADER_SIZE) as u64,
+                len: FRAME_SIZE as u32,
+                writable: true,
+            },
+        ];
+        self.queue
+            .submit(transport, 2 * slot as u16, &buffers)
+            .map_err(|_| KError::Io)
+    }
+}
+
+struct Tx {
+    queue: VirtQueue,
+    page: DmaPage,
+}
+
+pub struct VirtioNet {
+    name: String,
+    mac: MacAddr,
+    offload: ChecksumOffload,
+    transport: Transport,
+    rx: Mutex<Rx>,
+    tx: Mutex<Tx>,
+}
+
+impl VirtioNet {
+    fn new(
+        transport: Transport,
+        name: String,
+        mac: MacAddr,
+        offload: ChecksumOffload,
+    ) -> Option<Self> {
+        let rx_queue = transport.queue(QUEUE_RX).ok();
+        let tx_queue = transport.queue(QUEUE_TX).ok();
+        let tx_page = DmaPage::new();
+        let (Some(rx_queue), Some(tx_queue), Some(tx_page)) = (rx_queue, tx_queue, tx_page) else {
+            transport.fail();
+            return None;
+        };
+        let slots = RX_BUFFERS.min(rx_queue.size() as usize / 2);
+        let pages: Option<Vec<_>> = (0..slots.div_ceil(2)).map(|_| DmaPage::new()).collect();
+        let Some(pages) = pages else {
+            transport.fail();
+            return None;
+        };
+        let mut rx = Rx {
+            queue: rx_queue,
+            pages,
+        };
+        for slot in 0..slots {
+            if rx.post(&transport, slot).is_err() {
+                transport.fail();
+                return None;
+            }
+        }
+        transport.driver_ok();
+        Some(Self {
+            name,
+            mac,
+            offload,
+            transport,
+            rx: Mutex::new(rx),
+            tx: Mutex::new(Tx {
+                queue: tx_queue,
+                page: tx_page,
+            }),
+        })
+    }
+
+    /// Hands every received frame to the stack and the buffers back to the card. Returns whether
+    /// there were any.
+    fn receive(&self) -> bool {
+        let mut rx = self.rx.lock();
+        let mut received = false;
+        while let Some((desc, written)) = rx.queue.next_used() {
+            let slot = desc as usize / 2;
+            let (page, offset) = rx.slot(slot);
+            let flags: u8 = unsafe { ptr::read_volatile(page.ptr(offset)) };
+            let len = (written as usize)
+                .saturating_sub(HEADER_SIZE)
+                .min(FRAME_SIZE);
+            let mut frame = vec![0; len];
+            unsafe {
+                ptr::copy_nonoverlapping(page.ptr(offset + HEADER_SIZE), frame.as_mut_ptr(), len);
+            }
+            let verified = match flags & HEADER_DATA_VALID {
+                0 => Checksums::NONE,
+                _ => Checksums {
+                    ipv4: false,
+                    tcp: true,
+                    udp: true,
+                },
+            };
+            net::receive_checked(&self.name, frame, verified);
+            // a buffer that can't be posted again is lost, the others keep working
+            let _ = rx.post(&self.transport, slot);
+            received = true;
+        }
+        received
+    }
+
+    fn run(&self) -> ! {
+        loop {
+            if !self.receive() {
+                timer::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn mac(&self) -> Option<MacAddr> {
+        Some(self.mac)
+    }
+
+    fn checksum_offload(&self) -> ChecksumOffload {
+        self.offload
+    }
+
+    fn transmit(&self, frame: &[u8]) -> KResult<()> {
+        if frame.len() > FRAME_SIZE {
+            return Err(KError::MessageTooLong);
+        }
+        let header = tx_header(self.offload.tx, frame);
+        let mut tx = self.tx.lock();
+        let tx = &mut *tx;
+        unsafe {
+            ptr::copy_nonoverlapping(header.as_ptr(), tx.page.ptr(0), HEADER_SIZE);
+            ptr::copy_nonoverlapping(frame.as_ptr(), tx.page.ptr(TX_FRAME_OFFSET), frame.len());
+        }
+        let buffers = [
+            Buffer {
+                phys: tx.page.phys(0) as u64,
+                len: HEADER_SIZE as u32,
+                writable: false,
+            },
+            Buffer {
+                phys: tx.page.phys(TX_FRAME_OFFSET) as u64,
+                len: frame.len() as u32,
+                writable: false,
+            },
+        ];
+        tx.queue
+            .transfer(&self.transport, &buffers)
+            .map(|_| ())
+            .map_err(|_| KError::Io)
+    }
+}
+
+/// The header for sending `frame`, asking the card to complete the checksum if the stack left
+/// it partial, which it does for the protocols in `offload`.
+fn tx_header(offload: Checksums, frame: &[u8]) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    let Some((_, _, ethertype, packet)) = ethernet::parse(frame) else {
+        return header;
+    };
+    if ethertype != ethernet::ETHERTYPE_IPV4 || packet.len() < ipv4::HEADER_SIZE {
+        return header;
+    }
+    let protocol = packet[9];
+    let Some(offset) = net::checksum_offset(protocol).filter(|_| offload.covers(protocol)) else {
+        return header;
+    };
+    let start = ethernet::HEADER_SIZE + (packet[0] & 0xf) as usize * 4;
+    header[0] = HEADER_NEEDS_CSUM;
+    header[6..8].copy_from_slice(&(start as u16).to_le_bytes());
+    header[8..10].copy_from_slice(&(offset as u16).to_le_bytes());
+    header
+}
+
+#[test_case]
+fn test_parse_address() {
+    assert_eq!(
+        parse_address("192.168.1.5/24,192.168.1.1"),
+        Some((
+            Ipv4Addr([192, 168, 1, 5]),
+            24,
+            Some(Ipv4Addr([192, 168, 1, 1]))
+        ))
+    );
+    assert_eq!(
+        parse_address("10.1.0.2/16"),
+        Some((Ipv4Addr([10, 1, 0, 2]), 16, None))
+    );
+    assert_eq!(parse_address("10.1.0.2"), None);
+    assert_eq!(parse_address("10.1.0.2/33"), None);
+}
+
+#[test_case]
+fn test_tx_header() {
+    let mut packet = vec![0; ipv4::HEADER_SIZE + 8];
+    packet[0] = 0x45;
+    packet[9] = ipv4::PROTOCOL_UDP;
+    let frame = ethernet::build(
+        MacAddr::BROADCAST,
+        MacAddr::ZERO,
+        ethernet::ETHERTYPE_IPV4,
+        &packet,
+    );
+    let header = tx_header(Checksums::ALL, &frame);
+    assert_eq!(header[0], HEADER_NEEDS_CSUM);
+    assert_eq!(header[6..8], 34u16.to_le_bytes());
+    assert_eq!(header[8..10], 6u16.to_le_bytes());
+    // left to software, or not a protocol with a partial checksum
+    assert_eq!(tx_header(Checksums::NONE, &frame), [0; HEADER_SIZE]);
+    packet[9] = ipv4::PROTOCOL_ICMP;
+    let frame = ethernet::build(
+        MacAddr::BROADCAST,
+        MacAddr::ZERO,
+        ethernet::ETHERTYPE_IPV4,
+        &packet,
+    );
+    assert_eq!(tx_header(Checksums::ALL, &frame), [0; HEADER_SIZE]);
+}
@@ -0,0 +1,143 @@
+//! Driver for virtio 9P transports, the devices behind QEMU's `-virtfs`.
+//!
+//! Every share is mounted at `/<mount tag>` once found, so
+//! `-virtfs local,path=.,mount_tag=host,security_model=none` shows up as `/host`. The file system
+//! itself is `vfs::NinePFs`.
+use alloc::{boxed::Box, format, string::String, sync::Arc};
+use core::ptr;
+use spin::Mutex;
+
+use super::{Buffer, Transport, VirtQueue, VENDOR_ID};
+use crate::{
+    drivers::{dma::DmaPage, Driver, DriverManager, PhysicalDevice},
+    ext::Errno,
+    mem::PAGE_SIZE,
+    println, task,
+    vfs::{self, Channel, NinePFs, VfsResult},
+};
+
+/// Device id of the transitional 9P transport.
+const DEVICE_ID: u16 = 0x1009;
+const FEATURE_MOUNT_TAG: u32 = 1 << 0;
+/// Pages of the request and the response buffer, which limit the size of messages.
+const MESSAGE_PAGES: usize = 4;
+
+pub struct Virtio9pManager;
+
+impl DriverManager for Virtio9pManager {
+    fn on_plug(&self, dev: &dyn PhysicalDevice) -> Option<Box<dyn Driver>> {
+        if dev.get_vendor_id() != VENDOR_ID || dev.get_device_id() != DEVICE_ID {
+            return None;
+        }
+        let transport = Transport::new(dev)?;
+        if transport.negotiate(FEATURE_MOUNT_TAG) & FEATURE_MOUNT_TAG == 0 {
+            transport.fail();
+            return None;
+        }
+        let tag: String = (0..transport.config_u16(0))
+            .map(|i| transport.config_u8(2 + i) as char)
+            .collect();
+        let channel = match VirtioChannel::new(transport) {
+            Some(channel) => channel,
+            None => {
+                println!("9p: can't set up the queue of {}", tag);
+                return None;
+            }
+        };
+
+        // attaching waits for the host, which can't be done while devices are probed
+        let name = format!("9p@{}", dev.unique_identifier());
+        task::spawn(&name, move || mount(channel, &format!("/{}", tag)));
+        Some(Box::new(Virtio9pDriver { name }))
+    }
+}
+
+pub struct Virtio9pDriver {
+    name: String,
+}
+
+impl Driver for Virtio9pDriver {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_unplug(&self, _dev: &dyn PhysicalDevice) -> bool {
+        false
+    }
+}
+
+fn mount(channel: VirtioChannel, path: &str) {
+    let result = NinePFs::attach(channel).and_then(|fs| vfs::mount(path, Arc::new(fs)));
+    match result {
+        Ok(()) => println!("9p: mounted the host's share at {}", path),
+        Err(e) => println!("9p: can't mount {}: {:?}", path, e),
+    }
+}
+
+struct Queue {
+    transport: Transport,
+    queue: VirtQueue,
+    request: DmaPage,
+    response: DmaPage,
+}
+
+/// Carries 9P messages over the device's request queue.
+struct VirtioChannel(Mutex<Queue>);
+
+impl VirtioChannel {
+    fn new(transport: Transport) -> Option<Self> {
+        let queue = transport.queue(0).ok();
+        let request = DmaPage::contiguous(MESSAGE_PAGES);
+        let response = DmaPage::contiguous(MESSAGE_PAGES);
+        let (Some(queue), Some(request), Some(response)) = (queue, request, response) else {
+            transport.fail();
+            return None;
+        };
+        transport.driver_ok();
+        Some(Self(Mutex::new(Queue {
+            transport,
+            queue,
+            request,
+            response,
+        })))
+    }
+}
+
+impl Channel for VirtioChannel {
+    fn max_message(&self) -> usize {
+        MESSAGE_PAGES * PAGE_SIZE
+    }
+
+    fn transact(&self, request: &[u8], response: &mut [u8]) -> VfsResult<usize> {
+        let mut queue = self.0.lock();
+        let queue = &mut *queue;
+        if request.len() > queue.request.size() {
+            return Err(Errno::FileTooBig);
+        }
+        let response_len = response.len().min(queue.response.size());
+        unsafe {
+            ptr::copy_nonoverlapping(request.as_ptr(), queue.request.ptr(0), request.len());
+        }
+        let buffers = [
+            Buffer {
+                phys: queue.request.phys(0) as u64,
+                len: request.len() as u32,
+                writable: false,
+            },
+            Buffer {
+                phys: queue.response.phys(0) as u64,
+                len: response_len as u32,
+                writable: true,
+            },
+        ];
+        let written = queue
+            .queue
+            .transfer(&queue.transport, &buffers)
+            .map_err(|_| Errno::UnknownIO)?;
+        let len = (written as usize).min(response_len);
+        unsafe {
+            ptr::copy_nonoverlapping(queue.response.ptr(0), response.as_mut_ptr(), len);
+        }
+        Ok(len)
+    }
+}
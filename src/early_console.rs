@@ -0,0 +1,67 @@
+//! Console used by `print!` until `vga_buffer::init` switches to `WRITER`.
+//!
+//! Works from the first instruction of `kernel_main`: nothing is allocated or lazily
+//! initialized and no lock is taken. Text goes straight to video memory at the hardware cursor
+//! and to COM1, which is written by polling its status register.
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::vga_buffer::{self, DirectWriter, BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::{debugcon, theme};
+
+const COM1: u16 = 0x3f8;
+/// Line status register, bit 5 is set while the transmitter can take a byte.
+const COM1_LINE_STATUS: u16 = COM1 + 5;
+/// Polls of the line status before a byte is dropped, in case there is no serial port.
+const SERIAL_TIMEOUT: usize = 10_000;
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Clears the screen the bootloader left behind and sets up COM1 for 115200 8N1.
+fn start() {
+    let blank = (theme::current().normal().bits() as u16) << 8 | b' ' as u16;
+    let buffer = 0xb8000 as *mut u16;
+    for i in 0..BUFFER_WIDTH * BUFFER_HEIGHT {
+        unsafe { buffer.add(i).write_volatile(blank) };
+    }
+    vga_buffer::set_cursor(0, 0);
+
+    unsafe {
+        // interrupts off, divisor 1, 8 data bits, no parity, one stop bit, FIFO on
+        Port::<u8>::new(COM1 + 1).write(0x00);
+        Port::<u8>::new(COM1 + 3).write(0x80);
+        Port::<u8>::new(COM1).write(0x01);
+        Port::<u8>::new(COM1 + 1).write(0x00);
+        Port::<u8>::new(COM1 + 3).write(0x03);
+        Port::<u8>::new(COM1 + 2).write(0xc7);
+    }
+}
+
+struct Serial;
+
+impl Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut status = Port::<u8>::new(COM1_LINE_STATUS);
+        let mut data = Port::<u8>::new(COM1);
+        for byte in s.bytes() {
+            for _ in 0..SERIAL_TIMEOUT {
+                if unsafe { status.read() } & 0x20 != 0 {
+                    break;
+                }
+            }
+            unsafe { data.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if !STARTED.swap(true, Ordering::Relaxed) {
+        start();
+    }
+    let _ = DirectWriter(theme::current().normal()).write_fmt(args);
+    let _ = Serial.write_fmt(args);
+    debugcon::_print(args);
+}
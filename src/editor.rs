@@ -0,0 +1,255 @@
+//! Minimal full-screen text editor.
+//!
+//! Runs in the calling task with the keyboard grabbed. ^S saves, ^Q quits.
+use alloc::{format, string::String, vec, vec::Vec};
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    error::KError,
+    ext::{AccessFlags, Credentials, Errno, FileType},
+    keyboard, vfs,
+    vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER},
+};
+
+const CTRL_Q: char = '\x11';
+const CTRL_S: char = '\x13';
+const TEXT_ROWS: usize = BUFFER_HEIGHT - 1;
+const TAB_WIDTH: usize = 4;
+
+struct Editor {
+    path: String,
+    lines: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+    /// first line and column shown on screen
+    top: usize,
+    left: usize,
+    modified: bool,
+    /// set after ^Q with unsaved changes, a second ^Q quits anyway
+    confirm_quit: bool,
+    message: String,
+}
+
+impl Editor {
+    fn open(path: String) -> Self {
+        let (lines, message) = match vfs::read(&path) {
+            Ok(data) => {
+                let text = String::from_utf8_lossy(&data);
+                let mut lines: Vec<Vec<char>> =
+                    text.split('\n').map(|line| line.chars().collect()).collect();
+                // a trailing newline doesn't start another line
+                if lines.len() > 1 && lines.last().is_some_and(|line| line.is_empty()) {
+                    lines.pop();
+                }
+                // tasks have no credentials yet, so this only catches read-only mounts
+                let message = match vfs::access(&path, AccessFlags::WRITE, &Credentials::ROOT) {
+                    Ok(()) => String::new(),
+                    Err(e) => format!("can't write file: {}", KError::from(e)),
+                };
+                (lines, message)
+            }
+            Err(Errno::NotFound | Errno::NoEntry) => (vec![Vec::new()], String::from("new file")),
+            Err(e) => (vec![Vec::new()], format!("can't read file: {:?}", e)),
+        };
+        Self {
+            path,
+            lines,
+            row: 0,
+            col: 0,
+            top: 0,
+            left: 0,
+            modified: false,
+            confirm_quit: false,
+            message,
+        }
+    }
+
+    fn save(&mut self) {
+        let mut text = String::new();
+        for line in &self.lines {
+            text.extend(line.iter());
+            text.push('\n');
+        }
+        match vfs::write(&self.path, text.as_bytes()) {
+            Ok(()) => {
+                self.modified = false;
+                self.message = format!("wrote {} bytes", text.len());
+            }
+            Err(e) => self.message = format!("can't save: {:?}", e),
+        }
+    }
+
+    fn insert(&mut self, char: char) {
+        self.lines[self.row].insert(self.col, char);
+        self.col += 1;
+        self.modified = true;
+    }
+
+    fn newline(&mut self) {
+        let rest = self.lines[self.row].split_off(self.col);
+        self.lines.insert(self.row + 1, rest);
+        self.row += 1;
+        self.col = 0;
+        self.modified = true;
+    }
+
+    fn backspace(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+            self.lines[self.row].remove(self.col);
+        } else if self.row > 0 {
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.col = self.lines[self.row].len();
+            self.lines[self.row].extend(line);
+        } else {
+            return;
+        }
+        self.modified = true;
+    }
+
+    fn delete(&mut self) {
+        if self.col < self.lines[self.row].len() {
+            self.lines[self.row].remove(self.col);
+        } else if self.row + 1 < self.lines.len() {
+            let next = self.lines.remove(self.row + 1);
+            self.lines[self.row].extend(next);
+        } else {
+            return;
+        }
+        self.modified = true;
+    }
+
+    fn move_to(&mut self, row: usize, col: usize) {
+        self.row = row.min(self.lines.len() - 1);
+        self.col = col.min(self.lines[self.row].len());
+    }
+
+    /// Handles a key, returns false once the editor should close.
+    fn handle_key(&mut self, key: DecodedKey) -> bool {
+        if key != DecodedKey::Unicode(CTRL_Q) {
+            self.confirm_quit = false;
+        }
+        match key {
+            DecodedKey::Unicode(CTRL_Q) => {
+                if !self.modified || self.confirm_quit {
+                    return false;
+                }
+                self.confirm_quit = true;
+                self.message = String::from("unsaved changes, press ^Q again to quit");
+            }
+            DecodedKey::Unicode(CTRL_S) => self.save(),
+            DecodedKey::Unicode('\n') => self.newline(),
+            DecodedKey::Unicode('\x08') => self.backspace(),
+            DecodedKey::Unicode('\x7f') => self.delete(),
+            DecodedKey::Unicode('\t') => {
+                for _ in 0..TAB_WIDTH - self.col % TAB_WIDTH {
+                    self.insert(' ');
+                }
+            }
+            DecodedKey::Unicode(char) if !char.is_control() => self.insert(char),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => {
+                self.move_to(self.row.saturating_sub(1), self.col)
+            }
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.move_to(self.row + 1, self.col),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => {
+                if self.col > 0 {
+                    self.col -= 1;
+                } else if self.row > 0 {
+                    self.move_to(self.row - 1, usize::MAX);
+                }
+            }
+            DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                if self.col < self.lines[self.row].len() {
+                    self.col += 1;
+                } else if self.row + 1 < self.lines.len() {
+                    self.move_to(self.row + 1, 0);
+                }
+            }
+            DecodedKey::RawKey(KeyCode::Home) => self.col = 0,
+            DecodedKey::RawKey(KeyCode::End) => self.col = self.lines[self.row].len(),
+            DecodedKey::RawKey(KeyCode::PageUp) => {
+                self.move_to(self.row.saturating_sub(TEXT_ROWS), self.col)
+            }
+            DecodedKey::RawKey(KeyCode::PageDown) => self.move_to(self.row + TEXT_ROWS, self.col),
+            _ => {}
+        }
+        true
+    }
+
+    fn scroll(&mut self) {
+        if self.row < self.top {
+            self.top = self.row;
+        } else if self.row >= self.top + TEXT_ROWS {
+            self.top = self.row + 1 - TEXT_ROWS;
+        }
+        if self.col < self.left {
+            self.left = self.col;
+        } else if self.col >= self.left + BUFFER_WIDTH {
+            self.left = self.col + 1 - BUFFER_WIDTH;
+        }
+    }
+
+    fn render(&mut self) {
+        self.scroll();
+        let mut text = String::with_capacity(BUFFER_WIDTH);
+        let status = format!(
+            "{}{} | {}:{} | ^S save ^Q quit | {}",
+            self.path,
+            if self.modified { " [modified]" } else { "" },
+            self.row + 1,
+            self.col + 1,
+            self.message
+        );
+        without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            for screen_row in 0..TEXT_ROWS {
+                text.clear();
+                if let Some(line) = self.lines.get(self.top + screen_row) {
+                    text.extend(line.iter().skip(self.left).take(BUFFER_WIDTH));
+                }
+                pad(&mut text);
+                writer.write_at(screen_row, 0, &text);
+            }
+            text.clear();
+            text.extend(status.chars().take(BUFFER_WIDTH));
+            pad(&mut text);
+            writer.write_at(TEXT_ROWS, 0, &text);
+        });
+        vga_buffer::set_cursor(self.col - self.left, self.row - self.top);
+    }
+}
+
+fn pad(text: &mut String) {
+    while text.chars().count() < BUFFER_WIDTH {
+        text.push(' ');
+    }
+}
+
+fn run(path: String) {
+    keyboard::grab();
+    let mut editor = Editor::open(path);
+    loop {
+        editor.render();
+        let key = keyboard::read_key();
+        if !editor.handle_key(key) {
+            break;
+        }
+    }
+
+    without_interrupts(|| WRITER.lock().clear_screen());
+    keyboard::release();
+}
+
+/// Opens `path` in the editor. Takes over the screen and keyboard until the editor is closed.
+pub fn edit(path: &str) -> Result<(), Errno> {
+    let path = vfs::normalize(path)?;
+    if let Ok(metadata) = vfs::metadata(&path) {
+        if metadata.file_type == FileType::Directory {
+            return Err(Errno::IsDirectory);
+        }
+    }
+    run(path);
+    Ok(())
+}
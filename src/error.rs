@@ -0,0 +1,271 @@
+//! Kernel-wide error type.
+//!
+//! Subsystems keep their own error types and convert them to `KError` where errors leave the
+//! subsystem, like the shell and the system call layer. Every `KError` has a fixed error number,
+//! the same as on Linux, which system calls return negated.
+use core::fmt;
+use x86_64::structures::paging::{mapper::MapToError, PageSize};
+
+use crate::compress::CompressError;
+use crate::drivers::{ahci_driver::AhciError, ps2::Ps2Error, usb::UsbError};
+use crate::exec::ExecError;
+use crate::ext::Errno;
+use crate::font::FontError;
+use crate::module::ModuleError;
+use crate::task::SignalError;
+
+pub type KResult<T> = Result<T, KError>;
+
+/// The discriminant is the error number, numbers must never change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum KError {
+    /// The operation isn't allowed for the caller.
+    NotPermitted = 1,
+    NotFound = 2,
+    NoSuchTask = 3,
+    /// A blocking call was interrupted, like a read by ^C.
+    Interrupted = 4,
+    Io = 5,
+    /// Seeking data or a hole past the end of a file.
+    NoDeviceOrAddress = 6,
+    /// The file isn't a program that can be run.
+    NotExecutable = 8,
+    BadFileDescriptor = 9,
+    /// The resource is temporarily unavailable, like a file locked by someone else.
+    WouldBlock = 11,
+    OutOfMemory = 12,
+    /// Missing permissions to access a file.
+    AccessDenied = 13,
+    /// A pointer passed in points to memory that can't be accessed.
+    BadAddress = 14,
+    Busy = 16,
+    AlreadyExists = 17,
+    NoDevice = 19,
+    NotDirectory = 20,
+    IsDirectory = 21,
+    InvalidArgument = 22,
+    /// Too many open files in the task.
+    TooManyFiles = 24,
+    FileTooBig = 27,
+    NoSpace = 28,
+    /// Seeking on a pipe or TTY.
+    IllegalSeek = 29,
+    ReadOnlyFs = 30,
+    /// Write to a pipe without readers.
+    BrokenPipe = 32,
+    NameTooLong = 36,
+    /// The system call doesn't exist.
+    NotImplemented = 38,
+    /// A datagram larger than the network can carry.
+    MessageTooLong = 90,
+    /// The operation or feature isn't supported.
+    Unsupported = 95,
+    /// The port is already bound by another socket.
+    AddressInUse = 98,
+    /// No interface reaches the address.
+    NetworkUnreachable = 101,
+    /// The peer aborted the connection.
+    ConnectionReset = 104,
+    /// The socket isn't connected.
+    NotConnected = 107,
+    TimedOut = 110,
+    /// Nothing is listening on the port.
+    ConnectionRefused = 111,
+    /// On-disk structures are damaged.
+    Corrupted = 117,
+    /// A user or group used up its disk quota.
+    QuotaExceeded = 122,
+}
+
+/// Every error with its message.
+const ERRORS: &[(KError, &str)] = &[
+    (KError::NotPermitted, "operation not permitted"),
+    (KError::NotFound, "no such file or directory"),
+    (KError::NoSuchTask, "no such task"),
+    (KError::Interrupted, "interrupted system call"),
+    (KError::Io, "input/output error"),
+    (KError::NoDeviceOrAddress, "no such device or address"),
+    (KError::NotExecutable, "exec format error"),
+    (KError::BadFileDescriptor, "bad file descriptor"),
+    (KError::WouldBlock, "resource temporarily unavailable"),
+    (KError::OutOfMemory, "out of memory"),
+    (KError::AccessDenied, "permission denied"),
+    (KError::BadAddress, "bad address"),
+    (KError::Busy, "device or resource busy"),
+    (KError::AlreadyExists, "file exists"),
+    (KError::NoDevice, "no such device"),
+    (KError::NotDirectory, "not a directory"),
+    (KError::IsDirectory, "is a directory"),
+    (KError::InvalidArgument, "invalid argument"),
+    (KError::TooManyFiles, "too many open files"),
+    (KError::FileTooBig, "file too large"),
+    (KError::NoSpace, "no space left on device"),
+    (KError::IllegalSeek, "illegal seek"),
+    (KError::ReadOnlyFs, "read-only file system"),
+    (KError::BrokenPipe, "broken pipe"),
+    (KError::NameTooLong, "file name too long"),
+    (KError::NotImplemented, "function not implemented"),
+    (KError::MessageTooLong, "message too long"),
+    (KError::Unsupported, "operation not supported"),
+    (KError::AddressInUse, "address already in use"),
+    (KError::NetworkUnreachable, "network is unreachable"),
+    (KError::ConnectionReset, "connection reset by peer"),
+    (KError::NotConnected, "socket is not connected"),
+    (KError::TimedOut, "timed out"),
+    (KError::ConnectionRefused, "connection refused"),
+    (KError::Corrupted, "structure needs cleaning"),
+    (KError::QuotaExceeded, "disk quota exceeded"),
+];
+
+impl KError {
+    pub const fn errno(self) -> i64 {
+        self as i64
+    }
+
+    pub fn from_errno(errno: i64) -> Option<Self> {
+        ERRORS
+            .iter()
+            .map(|(error, _)| *error)
+            .find(|error| error.errno() == errno)
+    }
+
+    pub fn message(self) -> &'static str {
+        ERRORS
+            .iter()
+            .find(|(error, _)| *error == self)
+            .map_or("unknown error", |(_, message)| message)
+    }
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl From<Errno> for KError {
+    fn from(errno: Errno) -> Self {
+        match errno {
+            Errno::UnknownIO | Errno::BadBlock => Self::Io,
+            Errno::OutOfSpace => Self::NoSpace,
+            Errno::NotFound | Errno::NoEntry | Errno::StringEmpty => Self::NotFound,
+            Errno::IllegalCharacter | Errno::InvalidEntryType | Errno::Unaligned => {
+                Self::InvalidArgument
+            }
+            Errno::NameTooLong => Self::NameTooLong,
+            Errno::AccessError => Self::AccessDenied,
+            Errno::IsDirectory => Self::IsDirectory,
+            Errno::NotDirectory => Self::NotDirectory,
+            Errno::Unsupported => Self::Unsupported,
+            Errno::AlreadyExists => Self::AlreadyExists,
+            Errno::InvalidFileImage => Self::Corrupted,
+            Errno::FileTooBig => Self::FileTooBig,
+            Errno::Locked => Self::WouldBlock,
+            Errno::ReadOnlyFs => Self::ReadOnlyFs,
+            Errno::QuotaExceeded => Self::QuotaExceeded,
+            Errno::PastEndOfFile => Self::NoDeviceOrAddress,
+        }
+    }
+}
+
+impl From<SignalError> for KError {
+    fn from(error: SignalError) -> Self {
+        match error {
+            SignalError::NoSuchTask => Self::NoSuchTask,
+            SignalError::BootTask => Self::NotPermitted,
+        }
+    }
+}
+
+impl From<AhciError> for KError {
+    fn from(error: AhciError) -> Self {
+        match error {
+            AhciError::InvalidRequest => Self::InvalidArgument,
+            AhciError::Device => Self::Io,
+            AhciError::Timeout => Self::TimedOut,
+        }
+    }
+}
+
+impl From<UsbError> for KError {
+    fn from(error: UsbError) -> Self {
+        match error {
+            UsbError::Stalled | UsbError::Transfer | UsbError::BadDescriptor => Self::Io,
+            UsbError::Timeout => Self::TimedOut,
+        }
+    }
+}
+
+impl From<Ps2Error> for KError {
+    fn from(error: Ps2Error) -> Self {
+        match error {
+            Ps2Error::NotPresent => Self::NoDevice,
+            Ps2Error::Timeout => Self::TimedOut,
+            Ps2Error::SelfTestFailed(_) => Self::Io,
+            Ps2Error::NoUsablePort => Self::NoDevice,
+        }
+    }
+}
+
+impl From<CompressError> for KError {
+    fn from(error: CompressError) -> Self {
+        match error {
+            CompressError::BadMagic => Self::InvalidArgument,
+            CompressError::Unsupported => Self::Unsupported,
+            CompressError::Corrupted | CompressError::Checksum => Self::Corrupted,
+            CompressError::TooBig => Self::FileTooBig,
+        }
+    }
+}
+
+impl From<ModuleError> for KError {
+    fn from(error: ModuleError) -> Self {
+        match error {
+            ModuleError::BadFormat | ModuleError::NoInit => Self::InvalidArgument,
+            ModuleError::UnsupportedRelocation(_) | ModuleError::Overflow => Self::Unsupported,
+            ModuleError::UndefinedSymbol => Self::NotFound,
+            ModuleError::InitFailed(errno) => Self::from_errno(-errno).unwrap_or(Self::Io),
+            ModuleError::AlreadyLoaded => Self::AlreadyExists,
+            ModuleError::NotLoaded => Self::NotFound,
+            ModuleError::OutOfMemory => Self::OutOfMemory,
+        }
+    }
+}
+
+impl From<ExecError> for KError {
+    fn from(error: ExecError) -> Self {
+        match error {
+            ExecError::BadFormat | ExecError::UnsupportedRelocation(_) => Self::NotExecutable,
+            ExecError::OutOfMemory => Self::OutOfMemory,
+        }
+    }
+}
+
+impl From<FontError> for KError {
+    fn from(error: FontError) -> Self {
+        match error {
+            FontError::BadFormat | FontError::BadMap(_) => Self::InvalidArgument,
+            FontError::Unsupported => Self::Unsupported,
+            FontError::NoDevice => Self::NoDevice,
+        }
+    }
+}
+
+impl<S: PageSize> From<MapToError<S>> for KError {
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => Self::OutOfMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => Self::Busy,
+        }
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_errno_roundtrip() {
+    for (error, _) in ERRORS {
+        assert_eq!(KError::from_errno(error.errno()), Some(*error));
+    }
+    assert_eq!(KError::NotFound.errno(), 2);
+    assert_eq!(KError::from_errno(0), None);
+}
@@ -0,0 +1,273 @@
+//! Runs programs, statically linked position independent ELF executables.
+//!
+//! There is no user mode yet. A program is loaded into page-aligned heap memory and runs in the
+//! task that starts it, making system calls with `int 0x80` like it would from ring 3. Its
+//! segments are copied to their addresses relative to the start of that memory and the
+//! relocations of its dynamic section are applied. A static PIE only has
+//! `R_X86_64_RELATIVE` ones. The read-only segments come first and are then mapped read-only,
+//! which also makes them executable.
+//!
+//! The entry point is called as `extern "C" fn(argc: usize, argv: *const *const u8) -> i64`,
+//! with `argv` holding NUL-terminated arguments followed by a null pointer. It returns the exit
+//! status, or the program ends its task with the `exit` system call. Either way its memory is
+//! freed, see `release_task`. `userspace/rt` provides such an entry point.
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    error::KResult,
+    mem::PAGE_SIZE,
+    module::Region,
+    task::{self, TaskId},
+    vfs,
+};
+
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const R_X86_64_RELATIVE: u32 = 8;
+
+const PROGRAM_HEADER_SIZE: usize = 56;
+const DYNAMIC_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+
+/// The most memory a program may need.
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// not a position independent x86_64 ELF executable, or a damaged one
+    BadFormat,
+    /// a relocation a static PIE doesn't have, the program is linked dynamically
+    UnsupportedRelocation(u32),
+    OutOfMemory,
+}
+
+type Result<T> = core::result::Result<T, ExecError>;
+
+fn read<const N: usize>(data: &[u8], at: usize) -> Result<[u8; N]> {
+    data.get(at..at.checked_add(N).ok_or(ExecError::BadFormat)?)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(ExecError::BadFormat)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16> {
+    read(data, at).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32> {
+    read(data, at).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], at: usize) -> Result<u64> {
+    read(data, at).map(u64::from_le_bytes)
+}
+
+/// Returns `data[at..at + len]`.
+fn range(data: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+    at.checked_add(len)
+        .and_then(|end| data.get(at..end))
+        .ok_or(ExecError::BadFormat)
+}
+
+struct Segment {
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize,
+}
+
+impl Segment {
+    fn parse(data: &[u8], at: usize) -> Result<Self> {
+        Ok(Self {
+            kind: read_u32(data, at)?,
+            flags: read_u32(data, at + 4)?,
+            offset: read_u64(data, at + 8)? as usize,
+            vaddr: read_u64(data, at + 16)? as usize,
+            filesz: read_u64(data, at + 32)? as usize,
+            memsz: read_u64(data, at + 40)? as usize,
+        })
+    }
+
+    fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+}
+
+/// Loads the executable in `file` into new memory. Returns the memory and the address of the
+/// entry point.
+fn load(file: &[u8]) -> Result<(Region, u64)> {
+    if !file.starts_with(b"\x7fELF\x02\x01")
+        || read_u16(file, 16)? != ET_DYN
+        || read_u16(file, 18)? != EM_X86_64
+    {
+        return Err(ExecError::BadFormat);
+    }
+    let entry = read_u64(file, 0x18)? as usize;
+    let table = read_u64(file, 0x20)? as usize;
+    let count = read_u16(file, 0x38)? as usize;
+    let segments = (0..count)
+        .map(|i| Segment::parse(file, table + i * PROGRAM_HEADER_SIZE))
+        .collect::<Result<Vec<_>>>()?;
+    let loaded = || segments.iter().filter(|segment| segment.kind == PT_LOAD);
+
+    let (mut size, mut protected) = (0, 0);
+    for segment in loaded() {
+        let end = segment
+            .vaddr
+            .checked_add(segment.memsz)
+            .filter(|_| segment.filesz <= segment.memsz)
+            .ok_or(ExecError::BadFormat)?;
+        size = size.max(end);
+        if !segment.is_writable() {
+            protected = protected.max(end.next_multiple_of(PAGE_SIZE));
+        }
+    }
+    // writable data can't share a page with the read-only segments
+    if size == 0
+        || size > MAX_SIZE
+        || entry >= size
+        || loaded().any(|segment| segment.is_writable() && segment.vaddr < protected)
+    {
+        return Err(ExecError::BadFormat);
+    }
+
+    let mut region =
+        Region::new(size.next_multiple_of(PAGE_SIZE)).map_err(|_| ExecError::OutOfMemory)?;
+    for segment in loaded() {
+        let data = range(file, segment.offset, segment.filesz)?;
+        region.bytes()[segment.vaddr..segment.vaddr + data.len()].copy_from_slice(data);
+    }
+    if let Some(dynamic) = segments.iter().find(|segment| segment.kind == PT_DYNAMIC) {
+        relocate(&mut region, dynamic)?;
+    }
+    region
+        .protect(protected)
+        .map_err(|_| ExecError::OutOfMemory)?;
+    let entry = region.addr() + entry as u64;
+    Ok((region, entry))
+}
+
+/// Applies the relocations listed in the dynamic section, which is read from the loaded
+/// program since it only gives addresses.
+fn relocate(region: &mut Region, dynamic: &Segment) -> Result<()> {
+    let base = region.addr();
+    let image = region.bytes();
+    let (mut rela, mut rela_size, mut rela_entry) = (None, 0, RELA_SIZE);
+    for entry in range(image, dynamic.vaddr, dynamic.filesz)?.chunks_exact(DYNAMIC_SIZE) {
+        let value = read_u64(entry, 8)? as usize;
+        match read_u64(entry, 0)? {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => rela_size = value,
+            DT_RELAENT => rela_entry = value,
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Ok(());
+    };
+    if rela_entry != RELA_SIZE {
+        return Err(ExecError::BadFormat);
+    }
+    let relocations = range(image, rela, rela_size)?.to_vec();
+    for entry in relocations.chunks_exact(RELA_SIZE) {
+        let offset = read_u64(entry, 0)? as usize;
+        let kind = read_u64(entry, 8)? as u32;
+        let addend = read_u64(entry, 16)?;
+        if kind != R_X86_64_RELATIVE {
+            return Err(ExecError::UnsupportedRelocation(kind));
+        }
+        range(image, offset, 8)?;
+        image[offset..offset + 8].copy_from_slice(&base.wrapping_add(addend).to_le_bytes());
+    }
+    Ok(())
+}
+
+/// A program running in a task, with its arguments.
+struct Image {
+    owner: TaskId,
+    /// only held to be freed with the image
+    #[allow(dead_code)]
+    region: Region,
+    /// the arguments one after the other, each terminated by NUL
+    args: Vec<u8>,
+    /// pointers into `args`, then 0
+    argv: Vec<u64>,
+}
+
+static IMAGES: Mutex<Vec<Image>> = Mutex::new(Vec::new());
+
+/// Runs the program at `path` in the current task and returns its exit status. The first of
+/// `args` is the name of the program.
+pub fn run(path: &str, args: &[&str]) -> KResult<i64> {
+    let file = vfs::read(path)?;
+    let (region, entry) = load(&file)?;
+    drop(file);
+
+    let mut image = Image {
+        owner: task::current_id(),
+        region,
+        args: Vec::new(),
+        argv: Vec::with_capacity(args.len() + 1),
+    };
+    for arg in args {
+        image.args.extend_from_slice(arg.as_bytes());
+        image.args.push(0);
+    }
+    let mut at = image.args.as_ptr() as u64;
+    for arg in args {
+        image.argv.push(at);
+        at += arg.len() as u64 + 1;
+    }
+    image.argv.push(0);
+    let argv = image.argv.as_ptr() as *const *const u8;
+    let owner = image.owner;
+    without_interrupts(|| IMAGES.lock().push(image));
+
+    let entry: extern "C" fn(usize, *const *const u8) -> i64 =
+        unsafe { core::mem::transmute(entry) };
+    let status = entry(args.len(), argv);
+    release_task(owner);
+    Ok(status)
+}
+
+/// Frees the programs run by a task. Called when it exits.
+pub fn release_task(owner: TaskId) {
+    let released = without_interrupts(|| {
+        let mut images = IMAGES.lock();
+        let mut released = Vec::new();
+        while let Some(pos) = images.iter().position(|image| image.owner == owner) {
+            released.push(images.swap_remove(pos));
+        }
+        released
+    });
+    // after unlocking, freeing makes the pages writable again
+    drop(released);
+}
+
+#[test_case]
+fn test_rejects_non_executables() {
+    assert_eq!(load(b"not an elf file").err(), Some(ExecError::BadFormat));
+    let mut header = [0u8; 64];
+    header[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    // ET_EXEC, linked to a fixed address
+    header[16] = 2;
+    header[18] = EM_X86_64 as u8;
+    assert_eq!(load(&header).err(), Some(ExecError::BadFormat));
+    // no segments
+    header[16] = ET_DYN as u8;
+    assert_eq!(load(&header).err(), Some(ExecError::BadFormat));
+}
@@ -1,37 +1,96 @@
+mod allocator;
 mod body;
 mod disk;
 mod header;
+mod htree;
+mod quota;
 mod syscall;
 mod tools;
 
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use alloc::vec;
 use crate::ext::Errno;
+use crate::fault::{self, Point};
 pub use self::disk::RWS;
 
 use super::IoResult;
+use allocator::{FileBlock, Reservations};
 use disk::Disk;
 use header::{BlockGroupDescriptor, SuperBlock};
-
-pub use body::{DirectoryEntry, DirectoryEntryType, Entry, Inode, TypePerm};
+use htree::DirHash;
+use quota::{Owner, Quotas};
+
+pub use allocator::Extent;
+pub use body::{
+    DirectoryEntry, DirectoryEntryType, Entry, Inode, PermissionClass, TypePerm, FILENAME_MAX,
+};
+pub use quota::{Quota, QuotaKind, QuotaLimits};
 pub use tools::div_rounded_up;
 
 use tools::{align_next, err_if_zero, u32_align_next, Block};
 
-use core::cell::RefCell;
 use core::fmt;
 use core::mem::size_of;
+use core::ops::Range;
+use spin::Mutex;
+
+/// Statistics of the whole filesystem, from the superblock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    /// bytes per block
+    pub block_size: u64,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    /// free blocks that aren't reserved for the superuser
+    pub available_blocks: u64,
+    pub inodes: u64,
+    pub free_inodes: u64,
+    /// the longest file name, in bytes
+    pub name_max: u64,
+}
 
 /// Global structure of ext2Filesystem, such as disk partition.
+///
+/// Reading only needs `&self`: the disk and the block pointer cache have their own locks, so
+/// several readers can share the filesystem.
 pub struct Ext2Filesystem<T: RWS> {
     superblock: SuperBlock,
     superblock_addr: u64,
-    disk: RefCell<Disk<T>>,
+    /// Spin locks aren't re-entrant, so a method holding the disk guard must drop it before
+    /// calling another method of the filesystem, which may lock the disk again.
+    disk: Mutex<Disk<T>>,
     nbr_block_grp: u32,
     block_size: u32,
     block_mask: u32,
     block_shift: u32,
-    cache: Cache<u64, Block>,
+    /// Locked before `disk` when both are needed, never while holding it.
+    cache: Mutex<Cache<u64, Block>>,
+    /// Set while mounted read-only, every modification fails with `Errno::ReadOnlyFs`.
+    read_only: bool,
+    batch: Option<Batch>,
+    /// `None` while quotas are off
+    quotas: Option<Quotas>,
+    /// `None` if directories aren't indexed
+    dir_hash: Option<DirHash>,
+    reservations: Reservations,
+}
+
+/// Metadata changed by a write, kept in memory until the write is done.
+#[derive(Default)]
+struct Batch {
+    /// block groups allocated from: descriptor, its address and the block bitmap
+    groups: BTreeMap<u32, (BlockGroupDescriptor, u64, [u8; 1024])>,
+}
+
+impl<T: RWS> Drop for Ext2Filesystem<T> {
+    /// Runs once the filesystem is unmounted and its last file closed.
+    fn drop(&mut self) {
+        if !self.read_only {
+            let _ = self.save_quotas();
+            let _ = self.disk.lock().dev.flush();
+        }
+    }
 }
 
 impl<T: RWS> fmt::Debug for Ext2Filesystem<T> {
@@ -43,6 +102,7 @@ impl<T: RWS> fmt::Debug for Ext2Filesystem<T> {
             .field("block_size", &self.block_size)
             .field("block_mask", &self.block_mask)
             .field("block_shift", &self.block_shift)
+            .field("read_only", &self.read_only)
             .field("cache", &self.cache)
             // Not include disk in debug output.
             .finish()
@@ -62,19 +122,19 @@ struct EntryIter<'a, T: RWS> {
 impl<'a, T: RWS> Iterator for EntryIter<'a, T> {
     type Item = (DirectoryEntry, u32);
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(d) = self
-            .filesystem
-            .find_entry((&mut self.inode.0, self.inode.1), self.curr_offset as u64)
-        {
+        loop {
+            let d = self
+                .filesystem
+                .find_entry((&mut self.inode.0, self.inode.1), self.curr_offset as u64)?;
+            // an entry is at least as big as its 8 byte header, a broken directory ends here
+            if d.get_size() < 8 {
+                return None;
+            }
             let curr_offset = self.curr_offset;
             self.curr_offset += d.get_size() as u32;
-            if d.get_inode() == 0 {
-                self.next()
-            } else {
-                Some((d, curr_offset))
+            if d.get_inode() != 0 {
+                return Some((d, curr_offset));
             }
-        } else {
-            None
         }
     }
 }
@@ -85,9 +145,9 @@ type InodeNbr = u32;
 
 impl<T: RWS> Ext2Filesystem<T> {
     /// Invocation of a new FileSystem instance: take a FD and his reader as parameter
-    pub fn new(disk: T) -> IoResult<Self> {
+    pub fn new(disk: T, read_only: bool) -> IoResult<Self> {
         let superblock_addr = 1024;
-        let mut disk = Disk(disk);
+        let mut disk = Disk::new(disk, read_only);
         let superblock: SuperBlock = disk.read_struct(superblock_addr)?;
 
         let signature = superblock.get_ext2_signature();
@@ -96,25 +156,102 @@ impl<T: RWS> Ext2Filesystem<T> {
         }
 
         // consistency check
+        if !superblock.is_consistent() {
+            return Err(Errno::InvalidFileImage);
+        }
         let nbr_block_grp = superblock.get_nbr_block_grp();
-        assert_eq!(nbr_block_grp, superblock.get_inode_block_grp());
 
         let block_size = 1024 << superblock.get_log2_block_size();
-        // Check block_size constraints
-        assert!(block_size != 0 && (block_size & (block_size - 1)) == 0);
         let block_mask = block_size - 1;
         let block_shift = u32::trailing_zeros(block_size);
+        let dir_hash =
+            DirHash::read(&mut disk, superblock_addr, superblock.get_optional_features())?;
 
-        Ok(Self {
+        let mut filesystem = Self {
             block_size,
             block_mask,
             block_shift,
             superblock,
             superblock_addr,
             nbr_block_grp,
-            disk: RefCell::new(disk),
-            cache: Cache::new(block_size as usize / size_of::<Block>()),
-        })
+            read_only,
+            batch: None,
+            quotas: None,
+            dir_hash,
+            reservations: Reservations::default(),
+            disk: Mutex::new(disk),
+            cache: Mutex::new(Cache::new(block_size as usize / size_of::<Block>())),
+        };
+        filesystem.load_quotas()?;
+        Ok(filesystem)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Switches between read-only and read-write.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        self.disk.lock().read_only = read_only;
+    }
+
+    /// Reads up to `len` bytes of the file `inode_nbr` from `file_offset` on into the
+    /// read-ahead cache. Stops at the end of the file, holes and data already read ahead.
+    pub fn read_ahead(&self, inode_nbr: u32, file_offset: u64, len: u64) -> IoResult<()> {
+        let (inode, _) = self.get_inode(inode_nbr)?;
+        let block_size = self.block_size as u64;
+        let mut offset = file_offset & !(block_size - 1);
+        let end = (file_offset + len).min(inode.get_size());
+        // the current run of consecutive blocks: its disk address and length
+        let mut run: Option<(u64, u64)> = None;
+        while offset < end {
+            let Ok(Some(addr)) = self.lookup_data(&inode, offset) else {
+                break;
+            };
+            if self.disk.lock().is_read_ahead(addr) {
+                break;
+            }
+            run = match run {
+                Some((start, len)) if start + len == addr => Some((start, len + block_size)),
+                Some((start, len)) => {
+                    self.disk.lock().read_ahead(start, len as usize)?;
+                    Some((addr, block_size))
+                }
+                None => Some((addr, block_size)),
+            };
+            offset += block_size;
+        }
+        if let Some((start, len)) = run {
+            self.disk.lock().read_ahead(start, len as usize)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the disk's write cache.
+    pub fn sync(&self) -> IoResult<()> {
+        self.disk.lock().dev.flush()
+    }
+
+    pub fn statfs(&self) -> StatFs {
+        let superblock = &self.superblock;
+        let (blocks, free_blocks) = (superblock.nbr_blocks, superblock.nbr_free_blocks);
+        StatFs {
+            block_size: self.block_size as u64,
+            blocks: blocks as u64,
+            free_blocks: free_blocks as u64,
+            available_blocks: free_blocks.saturating_sub(superblock.get_reserved_blocks()) as u64,
+            inodes: superblock.nbr_inode as u64,
+            free_inodes: superblock.nbr_free_inodes as u64,
+            name_max: FILENAME_MAX as u64,
+        }
+    }
+
+    fn check_writable(&self) -> IoResult<()> {
+        if self.read_only {
+            return Err(Errno::ReadOnlyFs);
+        }
+        Ok(())
     }
 
     fn find_entry_in_inode(
@@ -122,34 +259,82 @@ impl<T: RWS> Ext2Filesystem<T> {
         inode_nbr: u32,
         filename: &str,
     ) -> IoResult<(DirectoryEntry, OffsetDirEntry)> {
+        if let Some(found) = self.find_indexed_entry(inode_nbr, filename)? {
+            return Ok(found);
+        }
         Ok(self
             .iter_entries(inode_nbr)?
             .find(|(x, _)| unsafe { x.get_filename() } == filename)
             .ok_or(Errno::NoEntry)?)
     }
 
-    /// truncate inode to the size `new_size` deleting all data blocks above
+    /// Sets the size of the inode to `new_size`. Growing leaves a hole and allocates nothing.
+    /// Shrinking frees the data blocks above the new end and zeroes the rest of the last
+    /// block, so growing again reads zeros there.
     fn truncate_inode(
         &mut self,
         (inode, inode_addr): (&mut Inode, InodeAddr),
         new_size: u64,
     ) -> IoResult<()> {
         let size = inode.get_size();
-        assert!(new_size <= size);
-        if size == 0 {
+        self.reservations.remove(inode_addr);
+        if new_size < size {
+            // the block holding the new end stays
+            let first = self.to_block(new_size);
+            // size - 1 to get the previous block addr
+            let curr_size = self.to_block_addr(size - 1);
+            let mut freed = 0;
+            for block_off in (first.0..=curr_size.0).rev() {
+                freed += self.inode_free_block((inode, inode_addr), Block(block_off))?;
+            }
+            inode.nbr_disk_sectors = inode
+                .nbr_disk_sectors
+                .saturating_sub(freed * self.block_sectors());
+            let tail = new_size & self.block_mask as u64;
+            if tail != 0 && !self.is_hole(inode, new_size)? {
+                let end = self.inode_data(inode, new_size)?;
+                let zeros = vec![0; (self.block_size as u64 - tail) as usize];
+                self.disk.lock().write_buffer(end, &zeros)?;
+            }
+        }
+        inode.update_size(new_size);
+        self.disk.lock().write_struct(inode_addr, inode)?;
+        Ok(())
+    }
+
+    /// Allocates the blocks of `offset..offset + len` in the inode that are holes, and grows
+    /// the inode to `offset + len` if it is smaller. The new blocks read as zeros.
+    fn allocate_inode(
+        &mut self,
+        (inode, inode_addr): (&mut Inode, InodeAddr),
+        offset: u64,
+        len: u64,
+    ) -> IoResult<()> {
+        let end = offset.checked_add(len).ok_or(Errno::FileTooBig)?;
+        if len == 0 {
             return Ok(());
         }
-        let new_size_block = self.to_block_addr(new_size);
-        // let curr_size = self.to_block_addr(align_next(size, self.block_size as u64));
-        // size - 1 to get the previous block addr
-        let curr_size = self.to_block_addr(size - 1);
-        for block_off in (new_size_block.0..=curr_size.0).rev() {
-            self.inode_free_block((inode, inode_addr), Block(block_off))
-                .unwrap();
+        let (first, last) = (offset >> self.block_shift, (end - 1) >> self.block_shift);
+        // like a write, the metadata is written once at the end
+        self.begin_batch();
+        let (mut result, mut allocated_to) = (Ok(()), 0);
+        for block_off in first..=last {
+            let block_offset = block_off << self.block_shift;
+            if let Err(e) = self.inode_data_alloc((&mut *inode, inode_addr), block_offset) {
+                result = Err(e);
+                break;
+            }
+            allocated_to = ((block_off + 1) << self.block_shift).min(end);
         }
-        inode.update_size(new_size, self.block_size);
-        self.disk.borrow_mut().write_struct(inode_addr, inode)?;
-        Ok(())
+        let end_batch = self.end_batch();
+        // what was allocated before running out of space stays, like the data of a short
+        // write, and no block may be past the end
+        if inode.get_size() < allocated_to {
+            inode.update_size(allocated_to);
+        }
+        self.disk.lock().write_struct(inode_addr, inode)?;
+        result?;
+        end_batch
     }
 
     /// delete inode `inode_nbr`
@@ -164,7 +349,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         if !(inode.type_and_perm.is_symlink()
             && inode.get_size() <= Inode::FAST_SYMLINK_SIZE_MAX as u64)
         {
-            self.truncate_inode((inode, inode_addr), 0).unwrap();
+            self.truncate_inode((inode, inode_addr), 0)?;
         }
         /* Unset Inode bitmap */
         let block_grp = (inode_nbr - 1) / self.superblock.inodes_per_block_grp;
@@ -172,9 +357,12 @@ impl<T: RWS> Ext2Filesystem<T> {
         let (mut block_dtr, block_dtr_addr) = self.get_block_grp_descriptor(block_grp)?;
         let bitmap_addr = self.to_addr(block_dtr.inode_usage_bitmap);
 
-        let mut disk = self.disk.borrow_mut();
+        let mut disk = self.disk.lock();
         let mut bitmap: u8 = disk.read_struct(bitmap_addr + index / 8)?;
-        assert!(get_bit(bitmap, (index % 8) as u8));
+        // freeing a free inode means the bitmap is broken
+        if !get_bit(bitmap, (index % 8) as u8) {
+            return Err(Errno::InvalidFileImage);
+        }
         set_bit(&mut bitmap, (index % 8) as u8, false);
         disk.write_struct(bitmap_addr + index / 8, &bitmap)?;
 
@@ -185,6 +373,8 @@ impl<T: RWS> Ext2Filesystem<T> {
         block_dtr.nbr_free_inodes;
         disk.write_struct(self.superblock_addr, &self.superblock)?;
         disk.write_struct(block_dtr_addr, &block_dtr)?;
+        drop(disk);
+        self.release_quota(inode.owner(), 0, 1);
         Ok(())
     }
 
@@ -199,24 +389,25 @@ impl<T: RWS> Ext2Filesystem<T> {
             return self.free_inode((&mut inode, inode_addr), inode_nbr);
         }
         inode.nbr_hard_links -= 1;
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
         Ok(())
     }
 
     /// delete the entry at entry_off of the parent_inode nbr
     fn delete_entry(&mut self, parent_inode_nbr: u32, entry_off: u32) -> IoResult<()> {
+        self.drop_dir_index(parent_inode_nbr)?;
         let (mut inode, inode_addr) = self.get_inode(parent_inode_nbr)?;
         let curr_offset = entry_off;
         let entry = self
             .find_entry((&mut inode, inode_addr), curr_offset as u64)
-            .unwrap();
+            .ok_or(Errno::InvalidFileImage)?;
 
+        // `.` always comes before the entry
         let (mut previous, previous_offset) = self
-            .iter_entries(parent_inode_nbr)
-            .unwrap()
+            .iter_entries(parent_inode_nbr)?
             .take_while(|(_, off)| *off < entry_off)
             .last()
-            .unwrap();
+            .ok_or(Errno::InvalidFileImage)?;
         /* if it is the last entry */
         if self
             .find_entry(
@@ -231,11 +422,10 @@ impl<T: RWS> Ext2Filesystem<T> {
         this creates a Hole which will be filled in push_entry */
         else {
             let next_entry_off = curr_offset as u64 + entry.get_size() as u64;
-            let previous_entry_addr = self
-                .inode_data_may_alloc((&mut inode, inode_addr), previous_offset as u64)
-                .unwrap();
+            let previous_entry_addr =
+                self.inode_data_alloc((&mut inode, inode_addr), previous_offset as u64)?;
             previous.set_size((next_entry_off - previous_offset as u64) as u16);
-            previous.write_on_disk(previous_entry_addr, &mut self.disk.borrow_mut())?;
+            previous.write_on_disk(previous_entry_addr, &mut self.disk.lock())?;
             Ok(())
         }
     }
@@ -259,7 +449,10 @@ impl<T: RWS> Ext2Filesystem<T> {
 
     /// get inode nbr inode and return the Inode and it's address
     fn get_inode(&self, inode: u32) -> IoResult<(Inode, InodeAddr)> {
-        assert!(inode >= 1);
+        // directory entries may point anywhere
+        if inode == 0 || inode > self.superblock.nbr_inode {
+            return Err(Errno::InvalidFileImage);
+        }
         let block_grp = (inode - 1) / self.superblock.inodes_per_block_grp;
         let index = (inode as u64 - 1) % self.superblock.inodes_per_block_grp as u64;
         let inode_offset = index as u64 * self.superblock.get_size_inode() as u64;
@@ -268,7 +461,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         let bitmap_addr = self.to_addr(block_dtr.inode_usage_bitmap);
         let bitmap: u8 = self
             .disk
-            .borrow_mut()
+            .lock()
             .read_struct(bitmap_addr + index / 8)?;
         if !get_bit(bitmap, (index % 8) as u8) {
             return Err(Errno::NoEntry);
@@ -276,7 +469,7 @@ impl<T: RWS> Ext2Filesystem<T> {
 
         let inode_addr = self.to_addr(block_dtr.inode_table) + inode_offset;
 
-        Ok((self.disk.borrow_mut().read_struct(inode_addr)?, inode_addr))
+        Ok((self.disk.lock().read_struct(inode_addr)?, inode_addr))
     }
 
     //TODO: better handle disk error
@@ -286,18 +479,19 @@ impl<T: RWS> Ext2Filesystem<T> {
         if block_dtr.nbr_free_inodes == 0 {
             return None;
         }
-        let mut disk = self.disk.borrow_mut();
+        let mut disk = self.disk.lock();
 
         // TODO: dynamic alloc ?
         let bitmap_addr = self.to_addr(block_dtr.inode_usage_bitmap);
         let mut bitmap: [u8; 1024] = disk.read_struct(bitmap_addr).ok()?;
-        for i in 0..self.superblock.inodes_per_block_grp {
+        let per_group = self.superblock.inodes_per_block_grp.min(8 * bitmap.len() as u32);
+        for i in 0..per_group {
             if !get_bit(bitmap[(i as usize) / 8], (i % 8) as u8) {
                 set_bit(&mut bitmap[(i as usize) / 8], (i % 8) as u8, true);
                 disk.write_struct(bitmap_addr + i as u64 / 8, &bitmap[(i / 8) as usize])
                     .ok()?;
                 block_dtr.nbr_free_inodes -= 1;
-                self.superblock.nbr_free_inodes -= 1;
+                self.superblock.nbr_free_inodes = self.superblock.nbr_free_inodes.saturating_sub(1);
                 block_dtr.nbr_free_inodes;
                 disk.write_struct(self.superblock_addr, &self.superblock)
                     .ok()?;
@@ -309,14 +503,20 @@ impl<T: RWS> Ext2Filesystem<T> {
         None
     }
 
-    /// try to allocate a new inode anywhere on the filesystem and return the inode number
-    fn alloc_inode(&mut self) -> Option<InodeNbr> {
+    /// try to allocate a new inode anywhere on the filesystem, charged to `owner`, and return
+    /// the inode number
+    fn alloc_inode(&mut self, owner: Owner) -> IoResult<InodeNbr> {
+        if fault::fail(Point::Alloc) {
+            return Err(Errno::OutOfSpace);
+        }
+        self.charge_quota(owner, 0, 1)?;
         for n in 0..self.nbr_block_grp {
             if let Some(n) = self.alloc_inode_on_grp(n) {
-                return Some(n);
+                return Ok(n);
             }
         }
-        None
+        self.release_quota(owner, 0, 1);
+        Err(Errno::OutOfSpace)
     }
 
     /// the the entry at offset entry_offset the last entry of the directory
@@ -329,14 +529,14 @@ impl<T: RWS> Ext2Filesystem<T> {
 
         // =(the offset to the next block)
         entry.set_size((u32_align_next(entry_offset + 1, self.block_size) - entry_offset) as u16);
-        entry.write_on_disk(entry_addr, &mut self.disk.borrow_mut())?;
+        entry.write_on_disk(entry_addr, &mut self.disk.lock())?;
         /* Update inode size */
         let new_size = entry_offset as u64 + entry.get_size() as u64;
         if new_size < inode.get_size() {
             self.truncate_inode((inode, inode_addr), new_size)?;
         } else {
-            inode.update_size(new_size, self.block_size);
-            self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+            inode.update_size(new_size);
+            self.disk.lock().write_struct(inode_addr, inode)?;
         }
         Ok(())
     }
@@ -347,13 +547,14 @@ impl<T: RWS> Ext2Filesystem<T> {
         parent_inode_nbr: u32,
         new_entry: &mut DirectoryEntry,
     ) -> IoResult<()> {
+        self.drop_dir_index(parent_inode_nbr)?;
         let (mut inode, inode_addr) = self.get_inode(parent_inode_nbr)?;
         // Get the last entry of the Directory
         match self.iter_entries(parent_inode_nbr)?.last() {
             Some((mut entry, offset)) => {
                 let offset = offset as u64;
 
-                let entry_addr = self.inode_data_xxx(&mut inode, offset).unwrap();
+                let entry_addr = self.inode_data_xxx(&mut inode, offset)?;
                 // debug_assert_eq!(self.disk.read_struct::<DirectoryEntry>(entry_addr), entry)?;
                 let entry_size = entry.size() as u64;
 
@@ -362,7 +563,7 @@ impl<T: RWS> Ext2Filesystem<T> {
                     // if we do not cross a Block
                     if self.to_block(new_offset) == self.to_block(new_offset + new_entry.size() as u64)
                     // and the block is already allocated
-                        && self.inode_data_may_alloc((&mut inode, inode_addr), new_offset).is_ok()
+                        && self.inode_data_alloc((&mut inode, inode_addr), new_offset).is_ok()
                     //self.to_block( as u32) == self.to_block(offset)
                     {
                         new_offset
@@ -372,7 +573,7 @@ impl<T: RWS> Ext2Filesystem<T> {
                 };
                 /* Update previous entry size */
                 entry.set_size((new_offset - offset) as u16);
-                entry.write_on_disk(entry_addr, &mut self.disk.borrow_mut())?;
+                entry.write_on_disk(entry_addr, &mut self.disk.lock())?;
 
                 self.set_as_last_entry((&mut inode, inode_addr), (new_entry, new_offset as u32))
             }
@@ -386,7 +587,7 @@ impl<T: RWS> Ext2Filesystem<T> {
             return None;
         }
         let base_addr = self.inode_data_xxx(inode.0, offset).ok()? as u64;
-        let dir_header: DirectoryEntry = self.disk.borrow_mut().read_struct(base_addr).ok()?;
+        let dir_header: DirectoryEntry = self.disk.lock().read_struct(base_addr).ok()?;
         Some(dir_header)
     }
 
@@ -412,7 +613,6 @@ impl<T: RWS> Ext2Filesystem<T> {
         // size, it will begin at block 1. Remember that blocks are
         // numbered starting at 0, and that block numbers don't
         // usually correspond to physical block addresses.
-        assert!(n <= self.nbr_block_grp);
         let offset = if self.block_size == 1024 { 2 } else { 1 };
 
         self.to_addr(Block(offset)) + n as u64 * size_of::<BlockGroupDescriptor>() as u64
@@ -420,71 +620,137 @@ impl<T: RWS> Ext2Filesystem<T> {
 
     /// read the block group descriptor from the block group number starting at 0
     fn get_block_grp_descriptor(&self, n: u32) -> IoResult<(BlockGroupDescriptor, u64)> {
+        if n >= self.nbr_block_grp {
+            return Err(Errno::BadBlock);
+        }
         let block_grp_addr = self.block_grp_descriptor_addr(n);
-        let block_grp: BlockGroupDescriptor = self.disk.borrow_mut().read_struct(block_grp_addr)?;
+        let block_grp: BlockGroupDescriptor = self.disk.lock().read_struct(block_grp_addr)?;
         Ok((block_grp, block_grp_addr))
     }
 
-    /// try to allocate a new block on block grp number `n`
-    fn alloc_block_on_grp(&mut self, n: u32) -> Option<Block> {
-        let (mut block_dtr, block_dtr_addr) = self.get_block_grp_descriptor(n).ok()?;
-        if block_dtr.nbr_free_blocks == 0 {
-            return None;
-        }
-        // TODO: dynamic alloc ?
+    /// try to allocate a new block on block grp number `n`, the first free one of `bits` that
+    /// isn't in one of the windows in `avoid`
+    ///
+    /// During a batch the group's descriptor and bitmap are only updated in memory.
+    fn alloc_block_on_grp(
+        &mut self,
+        n: u32,
+        mut bits: Range<u32>,
+        avoid: &[Range<u32>],
+    ) -> Option<Block> {
+        let batched = self.batch.as_mut().and_then(|batch| batch.groups.remove(&n));
+        let was_batched = batched.is_some();
+        let (mut block_dtr, block_dtr_addr, mut bitmap) = match batched {
+            Some(group) => group,
+            None => {
+                let (block_dtr, block_dtr_addr) = self.get_block_grp_descriptor(n).ok()?;
+                let bitmap_addr = self.to_addr(block_dtr.block_usage_bitmap);
+                let bitmap: [u8; 1024] = self.disk.lock().read_struct(bitmap_addr).ok()?;
+                (block_dtr, block_dtr_addr, bitmap)
+            }
+        };
         let bitmap_addr = self.to_addr(block_dtr.block_usage_bitmap);
-        let mut bitmap: [u8; 1024] = self.disk.borrow_mut().read_struct(bitmap_addr).ok()?;
-        for i in 0..self.superblock.get_block_per_block_grp().0 {
-            if !get_bit(bitmap[(i as usize) / 8], (i % 8) as u8) {
-                set_bit(&mut bitmap[(i as usize) / 8], (i%8) as u8, true);
-                self.disk
-                    .borrow_mut()
-                    .write_struct(bitmap_addr + i as u64 / 8, &bitmap[(i / 8) as usize])
-                    .ok()?;
+        let per_group = self.superblock.get_block_per_block_grp().0;
+        // groups can't be bigger than one bitmap block
+        bits.end = bits.end.min(8 * bitmap.len() as u32);
+        let free = if block_dtr.nbr_free_blocks == 0 {
+            None
+        } else {
+            bits.find(|i| {
+                let block = n * per_group + i + 1;
+                !get_bit(bitmap[(*i as usize) / 8], (i % 8) as u8)
+                    && !avoid.iter().any(|window| window.contains(&block))
+            })
+        };
+        if let Some(i) = free {
+            set_bit(&mut bitmap[(i as usize) / 8], (i % 8) as u8, true);
+            block_dtr.nbr_free_blocks -= 1;
+            self.superblock.nbr_free_blocks = self.superblock.nbr_free_blocks.saturating_sub(1);
+        }
 
-                block_dtr.nbr_free_blocks -= 1;
-                self.disk
-                    .borrow_mut()
-                    .write_struct(block_dtr_addr, &block_dtr)
-                    .ok()?;
-                self.superblock.nbr_free_blocks -= 1;
-                self.disk
-                    .borrow_mut()
-                    .write_struct(self.superblock_addr, &self.superblock)
+        match self.batch.as_mut() {
+            Some(batch) => {
+                if free.is_some() || was_batched {
+                    batch.groups.insert(n, (block_dtr, block_dtr_addr, bitmap));
+                }
+            }
+            None => {
+                let i = free?;
+                let mut disk = self.disk.lock();
+                disk.write_struct(bitmap_addr + i as u64 / 8, &bitmap[(i / 8) as usize])
                     .ok()?;
-                // TODO: Check the + 1
-                return Some(self.superblock.get_block_per_block_grp() * n + Block(i + 1));
+                disk.write_struct(block_dtr_addr, &block_dtr).ok()?;
+                disk.write_struct(self.superblock_addr, &self.superblock).ok()?;
             }
         }
-        None
+        // TODO: Check the + 1
+        free.map(|i| self.superblock.get_block_per_block_grp() * n + Block(i + 1))
     }
 
-    /// try to allocate a new block anywhere on the filesystem
-    fn alloc_block(&mut self) -> Option<Block> {
-        for n in 0..self.nbr_block_grp {
-            if let Some(addr) = self.alloc_block_on_grp(n) {
-                // TODO: dynamic alloc ?
-                let _res = self
-                    .disk
-                    .borrow_mut()
-                    .write_buffer(self.to_addr(addr), &[0; 1024]);
-                return Some(addr);
-            }
+    /// Starts collecting allocation metadata and inode updates in memory instead of writing
+    /// them for every block.
+    fn begin_batch(&mut self) {
+        self.batch = Some(Batch::default());
+    }
+
+    /// Writes out what changed during the batch, once per block group.
+    fn end_batch(&mut self) -> IoResult<()> {
+        let Some(batch) = self.batch.take() else {
+            return Ok(());
+        };
+        if batch.groups.is_empty() {
+            return Ok(());
         }
-        None
+        let mut disk = self.disk.lock();
+        for (block_dtr, block_dtr_addr, bitmap) in batch.groups.values() {
+            disk.write_struct(self.to_addr(block_dtr.block_usage_bitmap), bitmap)?;
+            disk.write_struct(*block_dtr_addr, block_dtr)?;
+        }
+        disk.write_struct(self.superblock_addr, &self.superblock)?;
+        Ok(())
+    }
+
+    /// Writes an inode back, unless a batch is running, which writes it once at the end.
+    fn store_inode(&self, inode_addr: InodeAddr, inode: &Inode) -> IoResult<()> {
+        if self.batch.is_none() {
+            self.disk.lock().write_struct(inode_addr, inode)?;
+        }
+        Ok(())
     }
 
-    /// try to free the block block_nbr
-    fn free_block(&mut self, block_nbr: Block) -> IoResult<()> {
+    /// try to allocate a new block for the inode at `inode_addr` near `goal`, charged to `owner`
+    fn alloc_block(&mut self, owner: Owner, inode_addr: InodeAddr, goal: Block) -> IoResult<Block> {
+        if fault::fail(Point::Alloc) {
+            return Err(Errno::OutOfSpace);
+        }
+        self.charge_quota(owner, self.block_kib(), 0)?;
+        let Some(addr) = self.find_block(inode_addr, goal) else {
+            self.release_quota(owner, self.block_kib(), 0);
+            return Err(Errno::OutOfSpace);
+        };
+        let zeros = vec![0; self.block_size as usize];
+        let block_addr = self.to_addr(addr);
+        let _res = self.disk.lock().write_buffer(block_addr, &zeros);
+        Ok(addr)
+    }
+
+    /// try to free the block block_nbr, which was charged to `owner`
+    fn free_block(&mut self, block_nbr: Block, owner: Owner) -> IoResult<()> {
+        if block_nbr.0 == 0 {
+            return Err(Errno::BadBlock);
+        }
         let block_grp = (block_nbr.0 - 1) / self.superblock.get_block_per_block_grp().0;
         let index = (block_nbr.0 as u64 - 1) % self.superblock.get_block_per_block_grp().0 as u64;
 
         let (mut block_dtr, block_dtr_addr) = self.get_block_grp_descriptor(block_grp)?;
         let bitmap_addr = self.to_addr(block_dtr.block_usage_bitmap);
 
-        let mut disk = self.disk.borrow_mut();
+        let mut disk = self.disk.lock();
         let mut bitmap: u8 = disk.read_struct(bitmap_addr + index / 8)?;
-        assert!(get_bit(bitmap, (index % 8) as u8));
+        // freeing a free block means the bitmap is broken
+        if !get_bit(bitmap, (index % 8) as u8) {
+            return Err(Errno::InvalidFileImage);
+        }
         set_bit(&mut bitmap, (index % 8) as u8, false);
 
         disk.write_struct(bitmap_addr + index / 8, &bitmap)?;
@@ -492,22 +758,47 @@ impl<T: RWS> Ext2Filesystem<T> {
         disk.write_struct(block_dtr_addr, &block_dtr)?;
         self.superblock.nbr_free_blocks += 1;
         disk.write_struct(self.superblock_addr, &self.superblock)?;
+        drop(disk);
+        self.release_quota(owner, self.block_kib(), 0);
         Ok(())
     }
 
     /// get the data of inode at offset `offset`, and allocate the data block if necessary
-    fn inode_data_alloc(&mut self, inode: (&mut Inode, u64), offset: u64) -> IoResult<u64> {
-        self.inode_data_may_alloc(inode, offset)
+    fn inode_data_alloc(
+        &mut self,
+        (inode, inode_addr): (&mut Inode, u64),
+        offset: u64,
+    ) -> IoResult<u64> {
+        let mut allocated = 0;
+        let result = self.inode_data_may_alloc((&mut *inode, inode_addr), offset, &mut allocated);
+        // data and pointer blocks alike
+        if allocated != 0 {
+            inode.nbr_disk_sectors += allocated * self.block_sectors();
+            self.store_inode(inode_addr, inode)?;
+        }
+        result
     }
 
-    /// alloc a pointer (used by the function inode_data_alloc)
-    fn alloc_pointer(&mut self, pointer_addr: u64) -> IoResult<Block> {
+    /// Disk sectors in a block, the unit of `Inode::nbr_disk_sectors`.
+    fn block_sectors(&self) -> u32 {
+        self.block_size / 512
+    }
+
+    /// alloc a pointer (used by the function inode_data_alloc), counting new blocks in
+    /// `allocated`
+    fn alloc_pointer(
+        &mut self,
+        pointer_addr: u64,
+        file: &FileBlock,
+        allocated: &mut u32,
+    ) -> IoResult<Block> {
         err_if_zero({
-            let pointer = self.disk.borrow_mut().read_struct(pointer_addr)?;
+            let pointer = self.disk.lock().read_struct(pointer_addr)?;
             if pointer == Block(0) {
-                let new_block = self.alloc_block().ok_or(Errno::OutOfSpace)?;
+                let new_block = self.alloc_file_block(file)?;
+                *allocated += 1;
                 self.disk
-                    .borrow_mut()
+                    .lock()
                     .write_struct(pointer_addr, &new_block)?;
                 new_block
             } else {
@@ -518,42 +809,49 @@ impl<T: RWS> Ext2Filesystem<T> {
 
     fn pointer(&self, pointer_addr: u64) -> IoResult<Block> {
         err_if_zero({
-            let pointer = self.disk.borrow_mut().read_struct(pointer_addr)?;
+            let pointer = self.disk.lock().read_struct(pointer_addr)?;
             pointer
         })
     }
 
-    /// free a pointer (used by the function inode_data_alloc)
-    fn free_pointer(&mut self, pointer_addr: u64) -> IoResult<()> {
-        let pointer = self.disk.borrow_mut().read_struct(pointer_addr)?;
+    /// free a pointer (used by the function inode_free_block). Returns the blocks freed, none
+    /// for a hole.
+    fn free_pointer(&mut self, pointer_addr: u64, owner: Owner) -> IoResult<u32> {
+        let pointer = self.disk.lock().read_struct(pointer_addr)?;
         if pointer == Block(0) {
-            panic!("free pointer null");
+            Ok(0)
         } else {
             self.disk
-                .borrow_mut()
+                .lock()
                 .write_struct(pointer_addr, &Block(0))?;
-            self.free_block(pointer)
+            self.free_block(pointer, owner)?;
+            Ok(1)
         }
     }
 
-    /// Get the file location at offset 'offset'
+    /// Frees the block `block_off` of the inode, and the blocks of pointers that only pointed
+    /// to it. Returns the blocks freed, holes have none.
     fn inode_free_block(
         &mut self,
         (inode, inode_addr): (&mut Inode, InodeAddr),
         block_off: Block,
-    ) -> IoResult<()> {
+    ) -> IoResult<u32> {
         let blocknumber_per_block = (self.block_size as usize / size_of::<Block>()) as u32;
         let block_off = block_off.0 as u64;
+        let owner = inode.owner();
 
         // SIMPLE ADDRESSING
         let mut offset_start = 0;
         let mut offset_end = 12;
         if block_off >= offset_start && block_off < offset_end {
-            let pointer = err_if_zero(inode.direct_block_pointers[block_off as usize])?;
-            self.free_block(pointer)?;
+            let pointer = inode.direct_block_pointers[block_off as usize];
+            if pointer == Block(0) {
+                return Ok(0);
+            }
+            self.free_block(pointer, owner)?;
             inode.direct_block_pointers[block_off as usize] = Block(0);
-            self.disk.borrow_mut().write_struct(inode_addr, inode)?;
-            return Ok(());
+            self.disk.lock().write_struct(inode_addr, inode)?;
+            return Ok(1);
         }
 
         // SINGLY INDIRECT ADDRESSING
@@ -562,49 +860,61 @@ impl<T: RWS> Ext2Filesystem<T> {
         offset_end += blocknumber_per_block as u64;
         if block_off >= offset_start && block_off < offset_end {
             let off = (block_off - offset_start) as u64;
-            let pointer = err_if_zero(inode.singly_indirect_block_pointers)?;
+            let pointer = inode.singly_indirect_block_pointers;
+            // the whole range is a hole
+            if pointer == Block(0) {
+                return Ok(0);
+            }
 
-            self.free_pointer(self.to_addr(pointer) + off * size_of::<Block>() as u64)?;
+            let mut freed = self.free_pointer(
+                self.to_addr(pointer) + off * size_of::<Block>() as u64,
+                owner,
+            )?;
 
             if block_off == offset_start {
-                let pointer = err_if_zero(inode.singly_indirect_block_pointers)?;
-                self.free_block(pointer)?;
+                self.free_block(pointer, owner)?;
                 inode.singly_indirect_block_pointers = Block(0);
-                self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+                self.disk.lock().write_struct(inode_addr, inode)?;
+                freed += 1;
             }
-            return Ok(());
+            return Ok(freed);
         }
 
         // DOUBLY INDIRECT ADDRESSING
         offset_start = offset_end;
         offset_end += (blocknumber_per_block * blocknumber_per_block) as u64;
         if block_off >= offset_start && block_off < offset_end {
-            let doubly_indirect = err_if_zero(inode.doubly_indirect_block_pointers)?;
+            let doubly_indirect = inode.doubly_indirect_block_pointers;
+            if doubly_indirect == Block(0) {
+                return Ok(0);
+            }
 
             let off_doubly = (block_off - offset_start) / blocknumber_per_block as u64;
             let addr_pointer_to_pointer =
                 self.to_addr(doubly_indirect) + off_doubly * size_of::<Block>() as u64;
 
-            let pointer_to_pointer: Block = err_if_zero(
-                self.disk
-                    .borrow_mut()
-                    .read_struct(addr_pointer_to_pointer)?,
-            )?;
+            let pointer_to_pointer: Block = self.disk.lock().read_struct(addr_pointer_to_pointer)?;
             let off = (block_off - offset_start) % blocknumber_per_block as u64;
 
-            self.free_pointer(self.to_addr(pointer_to_pointer) + off * size_of::<Block>() as u64)?;
+            let mut freed = 0;
+            if pointer_to_pointer != Block(0) {
+                freed += self.free_pointer(
+                    self.to_addr(pointer_to_pointer) + off * size_of::<Block>() as u64,
+                    owner,
+                )?;
+            }
 
             if off == 0 {
-                self.free_pointer(addr_pointer_to_pointer)?;
+                freed += self.free_pointer(addr_pointer_to_pointer, owner)?;
             }
 
             if block_off == offset_start {
-                let pointer = err_if_zero(inode.doubly_indirect_block_pointers)?;
-                self.free_block(pointer)?;
+                self.free_block(doubly_indirect, owner)?;
                 inode.doubly_indirect_block_pointers = Block(0);
-                self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+                self.disk.lock().write_struct(inode_addr, inode)?;
+                freed += 1;
             }
-            return Ok(());
+            return Ok(freed);
         }
 
         // TRIPLY INDIRECT ADDRESSING
@@ -615,71 +925,85 @@ impl<T: RWS> Ext2Filesystem<T> {
             let off_triply =
                 (block_off - offset_start) / (blocknumber_per_block * blocknumber_per_block) as u64;
 
-            let tripply_indirect = err_if_zero(inode.triply_indirect_block_pointers)?;
+            let tripply_indirect = inode.triply_indirect_block_pointers;
+            if tripply_indirect == Block(0) {
+                return Ok(0);
+            }
 
             let addr_pointer_to_pointer_to_pointer =
                 self.to_addr(tripply_indirect) + off_triply * size_of::<Block>() as u64;
-            let pointer_to_pointer_to_pointer: Block = err_if_zero(
-                self.disk
-                    .borrow_mut()
-                    .read_struct(addr_pointer_to_pointer_to_pointer)?,
-            )?;
+            let pointer_to_pointer_to_pointer: Block = self
+                .disk
+                .lock()
+                .read_struct(addr_pointer_to_pointer_to_pointer)?;
 
             let off_doubly = (((block_off - offset_start)
                 % (blocknumber_per_block * blocknumber_per_block) as u64)
                 / blocknumber_per_block as u64) as u64;
 
-            let addr_pointer_to_pointer = self.to_addr(pointer_to_pointer_to_pointer)
-                + off_doubly * size_of::<Block>() as u64;
-
-            let pointer_to_pointer: Block = err_if_zero(
-                self.disk
-                    .borrow_mut()
-                    .read_struct(addr_pointer_to_pointer)?,
-            )?;
-
             let off = (((block_off - offset_start)
                 % (blocknumber_per_block * blocknumber_per_block) as u64)
                 % blocknumber_per_block as u64) as u64;
 
-            self.free_pointer(self.to_addr(pointer_to_pointer) + off * size_of::<Block>() as u64)?;
+            let mut freed = 0;
+            if pointer_to_pointer_to_pointer != Block(0) {
+                let addr_pointer_to_pointer = self.to_addr(pointer_to_pointer_to_pointer)
+                    + off_doubly * size_of::<Block>() as u64;
 
-            if off == 0 {
-                self.free_pointer(addr_pointer_to_pointer)?;
+                let pointer_to_pointer: Block =
+                    self.disk.lock().read_struct(addr_pointer_to_pointer)?;
+
+                if pointer_to_pointer != Block(0) {
+                    freed += self.free_pointer(
+                        self.to_addr(pointer_to_pointer) + off * size_of::<Block>() as u64,
+                        owner,
+                    )?;
+                }
+
+                if off == 0 {
+                    freed += self.free_pointer(addr_pointer_to_pointer, owner)?;
+                }
             }
 
-            if off_doubly == 0 {
-                self.free_pointer(addr_pointer_to_pointer_to_pointer)?;
+            if off == 0 && off_doubly == 0 {
+                freed += self.free_pointer(addr_pointer_to_pointer_to_pointer, owner)?;
             }
 
             if block_off == offset_start {
-                let pointer = err_if_zero(inode.triply_indirect_block_pointers)?;
-                self.free_block(pointer)?;
+                self.free_block(tripply_indirect, owner)?;
                 inode.triply_indirect_block_pointers = Block(0);
-                self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+                self.disk.lock().write_struct(inode_addr, inode)?;
+                freed += 1;
             }
-            return Ok(());
+            return Ok(freed);
         }
         Err(Errno::FileTooBig)
     }
 
-    /// Get the file location at offset 'offset'
+    /// Get the file location at offset 'offset', counting the blocks allocated for it in
+    /// `allocated`
     fn inode_data_may_alloc(
         &mut self,
         (inode, inode_addr): (&mut Inode, InodeAddr),
         offset: u64,
+        allocated: &mut u32,
     ) -> IoResult<u64> {
         let block_off = offset / self.block_size as u64;
         let blocknumber_per_block = self.block_size as usize / size_of::<Block>();
+        let file = FileBlock {
+            inode: *inode,
+            inode_addr,
+            offset,
+        };
 
         // SIMPLE ADDRESSING
         let mut offset_start = 0;
         let mut offset_end = 12;
         if block_off >= offset_start && block_off < offset_end {
             if inode.direct_block_pointers[block_off as usize] == Block(0) {
-                inode.direct_block_pointers[block_off as usize] =
-                    self.alloc_block().ok_or(Errno::OutOfSpace)?;
-                self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+                inode.direct_block_pointers[block_off as usize] = self.alloc_file_block(&file)?;
+                *allocated += 1;
+                self.store_inode(inode_addr, inode)?;
             }
             return Ok(self.to_addr(err_if_zero(
                 inode.direct_block_pointers[block_off as usize],
@@ -695,15 +1019,18 @@ impl<T: RWS> Ext2Filesystem<T> {
 
             let singly_indirect = err_if_zero({
                 if inode.singly_indirect_block_pointers == Block(0) {
-                    inode.singly_indirect_block_pointers =
-                        self.alloc_block().ok_or(Errno::OutOfSpace)?;
-                    self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+                    inode.singly_indirect_block_pointers = self.alloc_file_block(&file)?;
+                    *allocated += 1;
+                    self.store_inode(inode_addr, inode)?;
                 }
                 inode.singly_indirect_block_pointers
             })?;
 
-            let pointer: Block = self
-                .alloc_pointer(self.to_addr(singly_indirect) + off * size_of::<Block>() as u64)?;
+            let pointer: Block = self.alloc_pointer(
+                self.to_addr(singly_indirect) + off * size_of::<Block>() as u64,
+                &file,
+                allocated,
+            )?;
 
             return Ok(self.to_addr(pointer) + offset % self.block_size as u64);
         }
@@ -715,17 +1042,22 @@ impl<T: RWS> Ext2Filesystem<T> {
             let off = (block_off - offset_start) / blocknumber_per_block as u64;
             let doubly_indirect = err_if_zero({
                 if inode.doubly_indirect_block_pointers == Block(0) {
-                    inode.doubly_indirect_block_pointers =
-                        self.alloc_block().ok_or(Errno::OutOfSpace)?;
-                    self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+                    inode.doubly_indirect_block_pointers = self.alloc_file_block(&file)?;
+                    *allocated += 1;
+                    self.store_inode(inode_addr, inode)?;
                 }
                 inode.doubly_indirect_block_pointers
             })?;
-            let pointer_to_pointer: Block = self
-                .alloc_pointer(self.to_addr(doubly_indirect) + off * size_of::<Block>() as u64)?;
+            let pointer_to_pointer: Block = self.alloc_pointer(
+                self.to_addr(doubly_indirect) + off * size_of::<Block>() as u64,
+                &file,
+                allocated,
+            )?;
             let off = (block_off - offset_start) % blocknumber_per_block as u64;
             let pointer: Block = self.alloc_pointer(
                 self.to_addr(pointer_to_pointer) + off * size_of::<Block>() as u64,
+                &file,
+                allocated,
             )?;
             return Ok(self.to_addr(pointer) + offset % self.block_size as u64);
         }
@@ -740,20 +1072,25 @@ impl<T: RWS> Ext2Filesystem<T> {
 
             let tripply_indirect = err_if_zero({
                 if inode.triply_indirect_block_pointers == Block(0) {
-                    inode.triply_indirect_block_pointers =
-                        self.alloc_block().ok_or(Errno::OutOfSpace)?;
-                    self.disk.borrow_mut().write_struct(inode_addr, inode)?;
+                    inode.triply_indirect_block_pointers = self.alloc_file_block(&file)?;
+                    *allocated += 1;
+                    self.store_inode(inode_addr, inode)?;
                 }
                 inode.triply_indirect_block_pointers
             })?;
-            let pointer_to_pointer_to_pointer: Block = self
-                .alloc_pointer(self.to_addr(tripply_indirect) + off * size_of::<Block>() as u64)?;
+            let pointer_to_pointer_to_pointer: Block = self.alloc_pointer(
+                self.to_addr(tripply_indirect) + off * size_of::<Block>() as u64,
+                &file,
+                allocated,
+            )?;
 
             let off = (((block_off - offset_start)
                 % (blocknumber_per_block * blocknumber_per_block) as u64)
                 / blocknumber_per_block as u64) as u64;
             let pointer_to_pointer: Block = self.alloc_pointer(
                 self.to_addr(pointer_to_pointer_to_pointer) + off * size_of::<Block>() as u64,
+                &file,
+                allocated,
             )?;
 
             let off = (((block_off - offset_start)
@@ -761,6 +1098,8 @@ impl<T: RWS> Ext2Filesystem<T> {
                 % blocknumber_per_block as u64) as u64;
             let pointer: Block = self.alloc_pointer(
                 self.to_addr(pointer_to_pointer) + off * size_of::<Block>() as u64,
+                &file,
+                allocated,
             )?;
 
             return Ok(self.to_addr(pointer) + offset % self.block_size as u64);
@@ -845,20 +1184,31 @@ impl<T: RWS> Ext2Filesystem<T> {
 
     /// Get the file location at offset 'offset'
     /// Return which block store the file data at offset T
-    /// Simple Read
-    fn inode_data(&mut self, inode: &Inode, offset: u64) -> IoResult<u64> {
+    /// Simple Read. Fails with `Errno::BadBlock` for holes, see `lookup_data` to tell them
+    /// apart.
+    fn inode_data(&self, inode: &Inode, offset: u64) -> IoResult<u64> {
+        self.lookup_data(inode, offset)?.ok_or(Errno::BadBlock)
+    }
+
+    /// Like `inode_data`, but `None` if the block at `offset` is a hole, a zero pointer at any
+    /// level.
+    fn lookup_data(&self, inode: &Inode, offset: u64) -> IoResult<Option<u64>> {
         let block_off = offset >> self.block_shift as u64;
         let blocknumber_per_block = self.block_size as usize / size_of::<Block>();
         let blocknumber_per_block_mask = blocknumber_per_block - 1;
         let blocknumber_per_block_shift = usize::trailing_zeros(blocknumber_per_block);
+        // the address of `offset` in its block
+        let in_block = |pointer: Block| self.to_addr(pointer) + (offset & self.block_mask as u64);
 
         // SIMPLE ADDRESSING
         let mut offset_start = 0;
         let mut offset_end = 12;
         if block_off >= offset_start && block_off < offset_end {
-            return Ok(self.to_addr(err_if_zero(
-                inode.direct_block_pointers[block_off as usize],
-            )?) + (offset & self.block_mask as u64));
+            let Some(pointer) = self.mapped(inode.direct_block_pointers[block_off as usize])?
+            else {
+                return Ok(None);
+            };
+            return Ok(Some(in_block(pointer)));
         }
 
         // SINGLY INDIRECT ADDRESSING
@@ -867,11 +1217,15 @@ impl<T: RWS> Ext2Filesystem<T> {
         offset_end += blocknumber_per_block as u64;
         if block_off >= offset_start && block_off < offset_end {
             let off = block_off - offset_start;
-            let singly_indirect = err_if_zero(inode.singly_indirect_block_pointers)?;
+            let Some(singly_indirect) = self.mapped(inode.singly_indirect_block_pointers)? else {
+                return Ok(None);
+            };
 
             let addr = self.to_addr(singly_indirect);
-            let pointer = self.get_pointer(addr, off, Level::L1)?;
-            return Ok(self.to_addr(pointer) + (offset & self.block_mask as u64));
+            let Some(pointer) = self.mapped(self.get_pointer(addr, off, Level::L1)?)? else {
+                return Ok(None);
+            };
+            return Ok(Some(in_block(pointer)));
         }
 
         // DOUBLY INDIRECT ADDRESSING
@@ -879,17 +1233,25 @@ impl<T: RWS> Ext2Filesystem<T> {
         offset_end += (blocknumber_per_block * blocknumber_per_block) as u64;
         if block_off >= offset_start && block_off < offset_end {
             let off = (block_off - offset_start) >> blocknumber_per_block_shift as u64;
-            let doubly_indirect = err_if_zero(inode.doubly_indirect_block_pointers)?;
+            let Some(doubly_indirect) = self.mapped(inode.doubly_indirect_block_pointers)? else {
+                return Ok(None);
+            };
 
             let addr = self.to_addr(doubly_indirect);
-            let pointer_to_pointer = self.get_pointer(addr, off, Level::L1)?;
+            let Some(pointer_to_pointer) =
+                self.mapped(self.get_pointer(addr, off, Level::L1)?)?
+            else {
+                return Ok(None);
+            };
 
             let off = (block_off - offset_start) & blocknumber_per_block_mask as u64;
 
             let addr = self.to_addr(pointer_to_pointer);
-            let pointer = self.get_pointer(addr, off, Level::L2)?;
+            let Some(pointer) = self.mapped(self.get_pointer(addr, off, Level::L2)?)? else {
+                return Ok(None);
+            };
 
-            return Ok(self.to_addr(pointer) + (offset & self.block_mask as u64));
+            return Ok(Some(in_block(pointer)));
         }
 
         // TRIPLY INDIRECT ADDRESSING
@@ -899,39 +1261,67 @@ impl<T: RWS> Ext2Filesystem<T> {
         if block_off >= offset_start && block_off < offset_end {
             let off =
                 (block_off - offset_start) / (blocknumber_per_block * blocknumber_per_block) as u64;
-            let tripply_indirect = err_if_zero(inode.triply_indirect_block_pointers)?;
+            let Some(tripply_indirect) = self.mapped(inode.triply_indirect_block_pointers)? else {
+                return Ok(None);
+            };
 
             let addr = self.to_addr(tripply_indirect);
-            let pointer_to_pointer_to_pointer = self.get_pointer(addr, off, Level::L1)?;
+            let Some(pointer_to_pointer_to_pointer) =
+                self.mapped(self.get_pointer(addr, off, Level::L1)?)?
+            else {
+                return Ok(None);
+            };
 
             let off = (((block_off - offset_start)
                 % (blocknumber_per_block * blocknumber_per_block) as u64)
                 >> blocknumber_per_block_shift as u64) as u64;
 
             let addr = self.to_addr(pointer_to_pointer_to_pointer);
-            let pointer_to_pointer = self.get_pointer(addr, off, Level::L2)?;
+            let Some(pointer_to_pointer) =
+                self.mapped(self.get_pointer(addr, off, Level::L2)?)?
+            else {
+                return Ok(None);
+            };
 
             let off = (((block_off - offset_start)
                 % (blocknumber_per_block * blocknumber_per_block) as u64)
                 & blocknumber_per_block_mask as u64) as u64;
 
             let addr = self.to_addr(pointer_to_pointer);
-            let pointer = self.get_pointer(addr, off, Level::L3)?;
+            let Some(pointer) = self.mapped(self.get_pointer(addr, off, Level::L3)?)? else {
+                return Ok(None);
+            };
 
-            return Ok(self.to_addr(pointer) + (offset & self.block_mask as u64));
+            return Ok(Some(in_block(pointer)));
         }
         Err(Errno::FileTooBig)
     }
 
+    /// Checks a block pointer of a file: `None` for 0, a hole, and `Errno::BadBlock` if it
+    /// points past the end of the filesystem.
+    fn mapped(&self, pointer: Block) -> IoResult<Option<Block>> {
+        match pointer {
+            Block(0) => Ok(None),
+            Block(n) if n >= self.superblock.nbr_blocks => Err(Errno::BadBlock),
+            _ => Ok(Some(pointer)),
+        }
+    }
+
+    /// True if the block at `offset` of a file isn't allocated, so it reads as zeros.
+    fn is_hole(&self, inode: &Inode, offset: u64) -> IoResult<bool> {
+        Ok(self.lookup_data(inode, offset)?.is_none())
+    }
+
     /// Get a inode pointer
     #[inline(always)]
-    fn get_pointer(&mut self, addr: u64, off: u64, level: Level) -> IoResult<Block> {
-        Ok(*match self.cache.get(addr, off as usize, level) {
+    fn get_pointer(&self, addr: u64, off: u64, level: Level) -> IoResult<Block> {
+        let mut cache = self.cache.lock();
+        Ok(*match cache.get(addr, off as usize, level) {
             Some(p) => p,
             None => {
-                let v = self.cache.update_layer(addr, level);
+                let v = cache.update_layer(addr, level);
                 unsafe {
-                    self.disk.borrow_mut().read_buffer(
+                    self.disk.lock().read_buffer(
                         addr,
                         core::slice::from_raw_parts_mut(
                             v.as_mut_ptr() as *mut u8,
@@ -939,7 +1329,7 @@ impl<T: RWS> Ext2Filesystem<T> {
                         ),
                     )?
                 };
-                self.cache
+                cache
                     .get(addr, off as usize, level)
                     .expect("Must be founded !")
             }
@@ -1033,7 +1423,7 @@ impl<K: Eq + PartialEq + Copy, T: Clone + Default> CacheEntry<K, T> {
 }
 
 pub fn get_bit(val: u8, idx: u8) -> bool {
-    val & (1 << idx) == 1
+    val & (1 << idx) != 0
 }
 
 pub fn set_bit(val: &mut u8, idx: u8, value: bool) {
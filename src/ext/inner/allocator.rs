@@ -0,0 +1,209 @@
+//! Where new blocks of a file go.
+//!
+//! The search for a block starts at the one after the block in front of it in the file, or at
+//! the inode's block group for the first block, so files that grow stay contiguous. A file that
+//! had to look further reserves a window of the next `RESERVATION_BLOCKS` blocks behind the one
+//! it got, which the searches of other files skip. Windows only live in memory: a file keeps
+//! its window until it is closed, truncated or deleted, and once nothing else is free the blocks
+//! in windows are handed out like any others.
+use alloc::vec::Vec;
+use core::iter;
+use core::ops::Range;
+
+use super::{Ext2Filesystem, Inode, InodeAddr, RWS};
+use crate::ext::inner::tools::Block;
+use crate::ext::IoResult;
+
+/// Blocks a file reserves behind a block it allocated away from its goal.
+const RESERVATION_BLOCKS: u32 = 8;
+/// Windows kept at most, the oldest file's goes first.
+const MAX_RESERVATIONS: usize = 64;
+
+/// A block of a file about to be allocated.
+#[derive(Clone, Copy)]
+pub(super) struct FileBlock {
+    /// the inode as it was before the allocation
+    pub inode: Inode,
+    pub inode_addr: InodeAddr,
+    /// offset of the block in the file
+    pub offset: u64,
+}
+
+/// The windows of the files being written, by the address of their inodes.
+#[derive(Debug, Default)]
+pub(super) struct Reservations(Vec<(InodeAddr, Range<u32>)>);
+
+impl Reservations {
+    fn get(&self, inode_addr: InodeAddr) -> Option<Range<u32>> {
+        self.0
+            .iter()
+            .find(|(addr, _)| *addr == inode_addr)
+            .map(|(_, window)| window.clone())
+    }
+
+    fn set(&mut self, inode_addr: InodeAddr, window: Range<u32>) {
+        self.remove(inode_addr);
+        if window.is_empty() {
+            return;
+        }
+        if self.0.len() == MAX_RESERVATIONS {
+            self.0.remove(0);
+        }
+        self.0.push((inode_addr, window));
+    }
+
+    pub fn remove(&mut self, inode_addr: InodeAddr) {
+        self.0.retain(|(addr, _)| *addr != inode_addr);
+    }
+
+    /// The windows of every file but `inode_addr`.
+    fn others(&self, inode_addr: InodeAddr) -> Vec<Range<u32>> {
+        self.0
+            .iter()
+            .filter(|(addr, _)| *addr != inode_addr)
+            .map(|(_, window)| window.clone())
+            .collect()
+    }
+}
+
+impl<T: RWS> Ext2Filesystem<T> {
+    /// The block group of a block and its bit in the group's bitmap.
+    fn block_position(&self, block: Block) -> (u32, u32) {
+        let per_group = self.superblock.get_block_per_block_grp().0;
+        // TODO: Check the + 1, as in `alloc_block_on_grp`
+        let index = block.0.saturating_sub(1);
+        (
+            (index / per_group).min(self.nbr_block_grp - 1),
+            index % per_group,
+        )
+    }
+
+    /// The block the search for `file` starts at.
+    fn goal(&self, file: &FileBlock) -> Block {
+        let block_off = file.offset >> self.block_shift;
+        let mut inode = file.inode;
+        let previous = match block_off {
+            0 => None,
+            _ => self
+                .inode_data_xxx(&mut inode, (block_off - 1) << self.block_shift)
+                .ok(),
+        };
+        match previous {
+            Some(addr) => Block((addr >> self.block_shift) as u32 + 1),
+            // the inode table is in the inode's group, followed by the group's data blocks
+            None => Block((file.inode_addr >> self.block_shift) as u32),
+        }
+    }
+
+    /// Allocates the block at `file.offset` of a file, charged to its owner.
+    pub(super) fn alloc_file_block(&mut self, file: &FileBlock) -> IoResult<Block> {
+        let goal = self.goal(file);
+        self.alloc_block(file.inode.owner(), file.inode_addr, goal)
+    }
+
+    /// Takes a free block for the file of `inode_addr`, as close after `goal` as possible.
+    pub(super) fn find_block(&mut self, inode_addr: InodeAddr, goal: Block) -> Option<Block> {
+        let per_group = self.superblock.get_block_per_block_grp().0;
+        let (goal_group, goal_bit) = self.block_position(goal);
+
+        if let Some(window) = self.reservations.get(inode_addr) {
+            if window.contains(&goal.0) {
+                let (_, last_bit) = self.block_position(Block(window.end - 1));
+                if let Some(block) =
+                    self.alloc_block_on_grp(goal_group, goal_bit..last_bit + 1, &[])
+                {
+                    self.reservations.set(inode_addr, block.0 + 1..window.end);
+                    return Some(block);
+                }
+            }
+        }
+
+        let others = self.reservations.others(inode_addr);
+        let groups = self.nbr_block_grp;
+        let searches = iter::once((goal_group, goal_bit..per_group))
+            .chain((1..groups).map(|i| ((goal_group + i) % groups, 0..per_group)))
+            .chain(iter::once((goal_group, 0..goal_bit)));
+        for (group, bits) in searches {
+            if let Some(block) = self.alloc_block_on_grp(group, bits, &others) {
+                let group_end = (group + 1) * per_group + 1;
+                let window_end = (block.0 + 1 + RESERVATION_BLOCKS).min(group_end);
+                self.reservations.set(inode_addr, block.0 + 1..window_end);
+                return Some(block);
+            }
+        }
+
+        // only the windows of other files are left
+        (0..groups).find_map(|group| self.alloc_block_on_grp(group, 0..per_group, &[]))
+    }
+
+    /// Gives up the window of the file `inode_nbr`, once it is closed.
+    pub fn discard_reservation(&mut self, inode_nbr: u32) -> IoResult<()> {
+        let (_, inode_addr) = self.get_inode(inode_nbr)?;
+        self.reservations.remove(inode_addr);
+        Ok(())
+    }
+
+    /// The runs of consecutive blocks holding the file `inode_nbr`, holes left out.
+    pub fn extents(&self, inode_nbr: u32) -> IoResult<Vec<Extent>> {
+        let (mut inode, _) = self.get_inode(inode_nbr)?;
+        if inode.type_and_perm.is_symlink()
+            && inode.get_size() <= Inode::FAST_SYMLINK_SIZE_MAX as u64
+        {
+            // the target is in the block pointers
+            return Ok(Vec::new());
+        }
+        let block_size = self.block_size as u64;
+        let blocks = inode.get_size().div_ceil(block_size);
+        let mut extents: Vec<Extent> = Vec::new();
+        for logical in 0..blocks {
+            let Ok(addr) = self.inode_data_xxx(&mut inode, logical * block_size) else {
+                continue;
+            };
+            let physical = addr / block_size;
+            match extents.last_mut() {
+                Some(extent)
+                    if extent.logical + extent.len == logical
+                        && extent.physical + extent.len == physical =>
+                {
+                    extent.len += 1
+                }
+                _ => extents.push(Extent {
+                    logical,
+                    physical,
+                    len: 1,
+                }),
+            }
+        }
+        Ok(extents)
+    }
+}
+
+/// Consecutive blocks of a file that are consecutive on disk too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// first block in the file
+    pub logical: u64,
+    /// first block on disk
+    pub physical: u64,
+    /// blocks
+    pub len: u64,
+}
+
+#[test_case]
+fn test_reservations() {
+    let mut reservations = Reservations::default();
+    reservations.set(1, 10..18);
+    reservations.set(2, 20..28);
+    assert_eq!(reservations.get(1), Some(10..18));
+    assert_eq!(reservations.others(1), alloc::vec![20..28]);
+
+    // a used up window is dropped
+    reservations.set(1, 18..18);
+    assert_eq!(reservations.get(1), None);
+
+    for inode_addr in 0..MAX_RESERVATIONS as u64 {
+        reservations.set(100 + inode_addr, 0..1);
+    }
+    assert_eq!(reservations.get(2), None);
+    assert_eq!(reservations.others(0).len(), MAX_RESERVATIONS);
+}
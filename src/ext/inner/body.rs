@@ -5,9 +5,9 @@ mod typeperm;
 
 use core::{borrow::Borrow, cmp::Ordering};
 
-pub use directory_entry::{DirectoryEntry, DirectoryEntryType};
-pub use inode::Inode;
-pub use typeperm::{TypePerm, PERMISSIONS_MASK, SPECIAL_BITS};
+pub use directory_entry::{DirectoryEntry, DirectoryEntryType, FILENAME_MAX};
+pub use inode::{Inode, InodeFlag};
+pub use typeperm::{PermissionClass, TypePerm, PERMISSIONS_MASK, SPECIAL_BITS};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(align(512))]
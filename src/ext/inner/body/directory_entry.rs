@@ -22,7 +22,8 @@ use core::mem::size_of;
 // record by increasing its record length to include the empty space. Empty space may also be
 // equivalently marked by a separate directory entry with an inode number of zero, indicating that directory entry should be skipped.
 
-const FILENAME_MAX: usize = 255;
+/// The longest name of a directory entry, in bytes
+pub const FILENAME_MAX: usize = 255;
 
 /// Directory Entry base structure
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -115,7 +116,6 @@ impl DirectoryEntry {
     /// Set the file name
     pub fn set_filename(&mut self, filename: &str) -> IoResult<()> {
         let filenamelen = filename.len();
-        assert!(filenamelen <= FILENAME_MAX as usize);
         self.filename = filename.try_into()?;
         self.header.name_length = filenamelen as u8;
         Ok(())
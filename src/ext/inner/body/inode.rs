@@ -1,7 +1,6 @@
 //! This file describe all the Inode model
 use super::TypePerm;
 use crate::ext::inner::Block;
-use core::mem::size_of;
 
 // Like blocks, each inode has a numerical address. It is extremely important to note that unlike block addresses, inode addresses start at 1.
 
@@ -124,6 +123,11 @@ impl Inode {
         self
     }
 
+    /// The user and group charged for the inode and its blocks.
+    pub fn owner(&self) -> (u16, u16) {
+        (self.user_id, self.group_id)
+    }
+
     pub fn is_a_directory(&self) -> bool {
         self.type_and_perm.is_directory()
     }
@@ -140,58 +144,11 @@ impl Inode {
         }
     }
 
-    pub fn update_size(&mut self, new_size: u64, block_size: u32) {
+    /// Sets the size. `nbr_disk_sectors` follows the blocks allocated and freed instead, holes
+    /// and preallocated blocks make it differ from the size.
+    pub fn update_size(&mut self, new_size: u64) {
         self.low_size = new_size as u32;
         self.upper_size = (new_size >> 32) as u32;
-
-        let block_size = block_size as u64;
-        let multiplier = block_size / 512;
-        let block_off = if new_size == 0 {
-            0
-        } else {
-            (new_size - 1) / block_size as u64
-        };
-        let blocknumber_per_block = block_size as u64 / size_of::<Block>() as u64;
-
-        /* Very complex calcul to compute the number of disk_sector use by the data of the inode */
-        let block_data = if new_size == 0 {
-            0
-        } else {
-            /* SIMPLE ADDRESSING */
-            let mut offset_start = 0;
-            let mut offset_end = 12;
-            let mut block_data = 0;
-
-            if block_off >= offset_start {
-                block_data = (block_off + 1) * multiplier;
-            }
-            /* SINGLY INDIRECT ADDRESSING */
-            offset_start = offset_end;
-            offset_end += blocknumber_per_block;
-            if block_off >= offset_start {
-                block_data += multiplier
-            }
-            /* DOUBLY INDIRECT ADDRESSING */
-            offset_start = offset_end;
-            offset_end += blocknumber_per_block * blocknumber_per_block;
-            if block_off >= offset_start {
-                block_data += multiplier
-                    + ((block_off - offset_start) / blocknumber_per_block + 1) * multiplier
-            }
-
-            // Triply Indirect Addressing
-            offset_start = offset_end;
-            //offset_end += blocknumber_per_block * blocknumber_per_block * blocknumber_per_block;
-            if block_off >= offset_start {
-                block_data += multiplier
-                    + (((block_off - offset_start)
-                        / (blocknumber_per_block * blocknumber_per_block))
-                        + 1)
-                        * multiplier
-            }
-            block_data
-        };
-        self.nbr_disk_sectors = block_data as u32;
     }
     // /// read the fast symlink on the inode if it exist, return None
     // /// otherwise
@@ -225,6 +182,10 @@ impl InodeFlags {
     pub fn has_flag(&self, flag: InodeFlag) -> bool {
         (self.0 & flag as u32) == flag as u32
     }
+
+    pub fn remove_flag(&mut self, flag: InodeFlag) {
+        self.0 &= !(flag as u32);
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -237,7 +198,7 @@ pub enum InodeFlag {
     AppendOnly = 0x00000020,
     FileNotIncludedInDumpCommand = 0x00000040,
     DontUpdateLastAccessTime = 0x00000080,
-    HashIndexedDirectory = 0x00010000,
+    HashIndexedDirectory = 0x00001000,
     AfsDirectory = 0x00020000,
     JournalFileData = 0x00040000,
 }
\ No newline at end of file
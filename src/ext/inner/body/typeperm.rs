@@ -100,20 +100,20 @@ impl TypePerm {
 
     /// returns the owner rights on the file, in a bitflags Amode
     pub fn owner_access(&self) -> AccessFlags {
-        let mask = FilePerms::UserRWX;
-        AccessFlags::from(mask as u8 >> 6)
+        let mask = FilePerms::UserRWX as u16;
+        AccessFlags::from(((self.0 & mask) >> 6) as u8)
     }
 
     /// returns the group rights on the file, in a bitflags Amode
     pub fn group_access(&self) -> AccessFlags {
-        let mask = FilePerms::GroupRWX;
-        AccessFlags::from(mask as u8 >> 3)
+        let mask = FilePerms::GroupRWX as u16;
+        AccessFlags::from(((self.0 & mask) >> 3) as u8)
     }
 
     /// returns the other rights on the file, in a bitflags Amode
     pub fn other_access(&self) -> AccessFlags {
-        let mask = FilePerms::OtherRWX;
-        AccessFlags::from(mask as u8)
+        let mask = FilePerms::OtherRWX as u16;
+        AccessFlags::from((self.0 & mask) as u8)
     }
 
     pub fn class_access(&self, class: PermissionClass) -> AccessFlags {
@@ -145,7 +145,6 @@ impl TryFrom<(FilePerms, FileType)> for TypePerm {
 
 /// Also known as File Classes in POSIX-2018.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-#[allow(unused)]
 pub enum PermissionClass {
     Owner,
     Group,
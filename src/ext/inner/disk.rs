@@ -1,4 +1,9 @@
+use crate::config;
+use crate::fault::{self, Point};
 use crate::ext::{Errno, IoResult};
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::mem::{size_of, MaybeUninit};
 
 pub trait RWS {
@@ -11,19 +16,104 @@ pub trait RWS {
     fn rewind(&mut self) -> IoResult<()> {
         self.seek_absolute(0)
     }
+    /// Makes sure everything written so far reached stable media, for devices with a volatile
+    /// write cache.
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
 }
 
-pub struct Disk<T: RWS>(pub T);
+/// The device the filesystem lives on. Writes fail while `read_only` is set, so nothing can
+/// reach a read-only mounted filesystem even if a check above was missed.
+///
+/// Data read ahead is kept in windows of consecutive bytes. Reads falling entirely into a
+/// window are served from it, writes drop the windows they overlap.
+pub struct Disk<T: RWS> {
+    pub dev: T,
+    pub read_only: bool,
+    /// read-ahead windows with their disk address, oldest first
+    windows: VecDeque<(u64, Vec<u8>)>,
+}
 
 impl<T: RWS> Disk<T> {
+    pub fn new(dev: T, read_only: bool) -> Self {
+        Self {
+            dev,
+            read_only,
+            windows: VecDeque::new(),
+        }
+    }
+
     pub fn write_buffer(&mut self, offset: u64, buf: &[u8]) -> IoResult<u64> {
-        let _r = self.0.seek_absolute(offset);
-        self.0.write(buf)
+        if self.read_only {
+            return Err(Errno::ReadOnlyFs);
+        }
+        let end = offset + buf.len() as u64;
+        self.windows
+            .retain(|(start, data)| end <= *start || offset >= *start + data.len() as u64);
+        if fault::fail(Point::Write) {
+            return Err(Errno::UnknownIO);
+        }
+        let _r = self.dev.seek_absolute(offset);
+        self.dev.write(buf)
     }
 
     pub fn read_buffer(&mut self, offset: u64, buf: &mut [u8]) -> IoResult<u64> {
-        let _r = self.0.seek_absolute(offset);
-        self.0.read(buf)
+        let end = offset + buf.len() as u64;
+        let window = self
+            .windows
+            .iter()
+            .find(|(start, data)| offset >= *start && end <= *start + data.len() as u64);
+        if let Some((start, data)) = window {
+            let from = (offset - start) as usize;
+            buf.copy_from_slice(&data[from..from + buf.len()]);
+            return Ok(buf.len() as u64);
+        }
+        self.read_direct(offset, buf)
+    }
+
+    /// Reads straight from the device, ignoring the read-ahead windows.
+    pub fn read_direct(&mut self, offset: u64, buf: &mut [u8]) -> IoResult<u64> {
+        let _r = self.dev.seek_absolute(offset);
+        self.read_dev(buf)
+    }
+
+    /// Reads from the current position of the device, unless a fault is injected.
+    fn read_dev(&mut self, buf: &mut [u8]) -> IoResult<u64> {
+        if fault::fail(Point::Read) {
+            return Err(Errno::UnknownIO);
+        }
+        if fault::fail(Point::ShortRead) {
+            let half = buf.len() / 2;
+            return self.dev.read(&mut buf[..half]);
+        }
+        self.dev.read(buf)
+    }
+
+    /// Whether the byte at `offset` was already read ahead.
+    pub fn is_read_ahead(&self, offset: u64) -> bool {
+        self.windows
+            .iter()
+            .any(|(start, data)| offset >= *start && offset < *start + data.len() as u64)
+    }
+
+    /// Reads `len` bytes at `offset` into a new read-ahead window.
+    pub fn read_ahead(&mut self, offset: u64, len: usize) -> IoResult<()> {
+        let mut data = vec![0; len];
+        let _r = self.dev.seek_absolute(offset);
+        let read = self.read_dev(&mut data)? as usize;
+        data.truncate(read);
+        // the oldest windows are dropped once the cache is full
+        let max = config::get().readahead_cache_size;
+        let mut cached: usize = self.windows.iter().map(|(_, data)| data.len()).sum();
+        while cached + data.len() > max {
+            match self.windows.pop_front() {
+                Some((_, old)) => cached -= old.len(),
+                None => return Ok(()),
+            }
+        }
+        self.windows.push_back((offset, data));
+        Ok(())
     }
 
     /// Write a particulary struct inside file object
@@ -136,6 +136,11 @@ pub struct SuperBlock {
 }
 
 impl SuperBlock {
+    /// Get the number of blocks reserved for the superuser
+    pub fn get_reserved_blocks(&self) -> u32 {
+        self.nbr_blocks_reserved
+    }
+
     /// Get ext2 signature
     pub fn get_ext2_signature(&self) -> u16 {
         self.ext2_signature
@@ -151,6 +156,16 @@ impl SuperBlock {
         div_rounded_up(self.nbr_inode as u64, self.inodes_per_block_grp as u64) as u32
     }
 
+    /// True if the filesystem can be laid out from the superblock: no empty block groups,
+    /// blocks of at most 64 KiB and as many groups of inodes as of blocks
+    pub fn is_consistent(&self) -> bool {
+        let (blocks, inodes) = (self.block_per_block_grp, self.inodes_per_block_grp);
+        blocks != 0
+            && inodes != 0
+            && self.get_log2_block_size() <= 6
+            && self.get_nbr_block_grp() == self.get_inode_block_grp()
+    }
+
     /// Get the superblock official block per block group
     pub fn get_block_per_block_grp(&self) -> Block {
         Block(self.block_per_block_grp)
@@ -166,6 +181,26 @@ impl SuperBlock {
         self.size_inode
     }
 
+    /// First inode not reserved for the filesystem itself, fixed in versions < 1.0
+    pub fn get_first_inode(&self) -> u32 {
+        let major_version = self.major_version;
+        if major_version < 1 {
+            11
+        } else {
+            self.first_non_reserved_inode
+        }
+    }
+
+    /// Features that don't have to be supported to read or write the filesystem
+    pub fn get_optional_features(&self) -> u32 {
+        self.optional_features_flag
+    }
+
+    /// Features that have to be supported to write to the filesystem
+    pub fn get_read_only_features(&self) -> u32 {
+        self.feature_must_read_only
+    }
+
     /// True if directories entry have file type
     pub fn directory_entry_contain_type_field(&self) -> bool {
         let flag = self.required_features_flag;
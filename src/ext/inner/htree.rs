@@ -0,0 +1,409 @@
+//! Lookups in hash-indexed directories (htree), as written by Linux with `dir_index`.
+//!
+//! The first block of an indexed directory holds `.` and `..`, followed by the root of a tree of
+//! hashes of the names, pointing to leaf blocks in the usual directory format. The blocks of
+//! the tree look like empty directory entries to everything else, so listing a directory and
+//! the linear lookup still work on them.
+//!
+//! Only lookups use the index. Changing a directory drops it, Linux then sees a plain directory
+//! and `e2fsck -D` can build the index again. A directory whose index doesn't look right is
+//! searched linearly.
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::body::InodeFlag;
+use super::disk::Disk;
+use super::{DirectoryEntry, Ext2Filesystem, Inode, OffsetDirEntry, RWS};
+use crate::ext::{Errno, IoResult};
+
+/// The optional feature of indexed directories
+const COMPAT_DIR_INDEX: u32 = 0x20;
+/// Where the superblock keeps the hash seed and its flags, past what `SuperBlock` covers
+const HASH_SEED_OFFSET: u64 = 0xec;
+const FLAGS_OFFSET: u64 = 0x160;
+/// Names are hashed as unsigned bytes, not signed ones
+const FLAG_UNSIGNED_HASH: u32 = 0x2;
+
+/// `.` and `..`, in front of the root info
+const ROOT_INFO_OFFSET: usize = 24;
+/// The empty directory entry in front of the entries of an inner node
+const NODE_HEADER_SIZE: usize = 8;
+const DX_ENTRY_SIZE: usize = 8;
+/// Levels of inner nodes below the root, ext2 and ext3 have at most one
+const MAX_INDIRECT_LEVELS: u8 = 1;
+
+/// The seed and the signedness names are hashed with, from the superblock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirHash {
+    seed: [u32; 4],
+    unsigned: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashVersion {
+    Legacy,
+    HalfMd4,
+    Tea,
+}
+
+impl HashVersion {
+    fn from_u8(version: u8) -> Option<Self> {
+        match version {
+            0 => Some(Self::Legacy),
+            1 => Some(Self::HalfMd4),
+            2 => Some(Self::Tea),
+            _ => None,
+        }
+    }
+}
+
+impl DirHash {
+    /// Reads the hash parameters, `None` if the filesystem doesn't index directories.
+    pub(super) fn read<T: RWS>(
+        disk: &mut Disk<T>,
+        superblock_addr: u64,
+        optional_features: u32,
+    ) -> IoResult<Option<Self>> {
+        if optional_features & COMPAT_DIR_INDEX == 0 {
+            return Ok(None);
+        }
+        let seed: [u32; 4] = disk.read_struct(superblock_addr + HASH_SEED_OFFSET)?;
+        let flags: u32 = disk.read_struct(superblock_addr + FLAGS_OFFSET)?;
+        Ok(Some(Self {
+            seed,
+            unsigned: flags & FLAG_UNSIGNED_HASH != 0,
+        }))
+    }
+
+    /// The hash of `name`, with the lowest bit cleared as the tree stores it.
+    fn hash(&self, version: HashVersion, name: &[u8]) -> u32 {
+        let mut buf = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+        if self.seed != [0; 4] {
+            buf = self.seed;
+        }
+        let hash = match version {
+            HashVersion::Legacy => legacy_hash(name, self.unsigned),
+            HashVersion::HalfMd4 => {
+                for chunk in chunks(name, 32) {
+                    half_md4_transform(&mut buf, &str_to_hash_buf::<8>(chunk, self.unsigned));
+                }
+                buf[1]
+            }
+            HashVersion::Tea => {
+                for chunk in chunks(name, 16) {
+                    tea_transform(&mut buf, &str_to_hash_buf::<4>(chunk, self.unsigned));
+                }
+                buf[0]
+            }
+        };
+        match hash & !1 {
+            // the largest hash marks the end of a directory for `telldir`
+            0xffff_fffe => 0xffff_fffc,
+            hash => hash,
+        }
+    }
+}
+
+/// Splits `name` into pieces hashed one after another, the length of each counts the rest of
+/// the name.
+fn chunks(name: &[u8], size: usize) -> impl Iterator<Item = &[u8]> {
+    let count = name.len().div_ceil(size);
+    (0..count).map(move |i| &name[i * size..])
+}
+
+/// A byte of a name as the hash sees it.
+fn byte(byte: u8, unsigned: bool) -> u32 {
+    match unsigned {
+        true => byte as u32,
+        false => byte as i8 as i32 as u32,
+    }
+}
+
+fn legacy_hash(name: &[u8], unsigned: bool) -> u32 {
+    let (mut hash0, mut hash1): (u32, u32) = (0x12a3fe2d, 0x37abe8f9);
+    for &c in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ byte(c, unsigned).wrapping_mul(7152373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Packs up to `N` words of `name` for the transforms, padded with its length.
+fn str_to_hash_buf<const N: usize>(name: &[u8], unsigned: bool) -> [u32; N] {
+    let len = name.len() as u32;
+    let mut pad = len | (len << 8);
+    pad |= pad << 16;
+    let mut buf = [pad; N];
+    let mut val = pad;
+    let mut words = 0;
+    for (i, &c) in name.iter().take(N * 4).enumerate() {
+        val = byte(c, unsigned).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[words] = val;
+            words += 1;
+            val = pad;
+        }
+    }
+    if words < N {
+        buf[words] = val;
+    }
+    buf
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9e3779b9;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    let mut sum: u32 = 0;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// MD4 cut down to three rounds of eight steps, as the ext2 tools do.
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let rounds: [(&dyn Fn(u32, u32, u32) -> u32, u32, [usize; 8], [u32; 4]); 3] = [
+        (&f, 0, [0, 1, 2, 3, 4, 5, 6, 7], [3, 7, 11, 19]),
+        (&g, K2, [1, 3, 5, 7, 0, 2, 4, 6], [3, 5, 9, 13]),
+        (&h, K3, [3, 7, 2, 6, 1, 5, 0, 4], [3, 9, 11, 15]),
+    ];
+    let mut state = *buf;
+    for (function, k, order, shifts) in rounds {
+        for (step, &word) in order.iter().enumerate() {
+            // every step updates the next word of a, d, c, b in turn
+            let target = (4 - step % 4) % 4;
+            let [x, y, z] = [1, 2, 3].map(|i| state[(target + i) % 4]);
+            state[target] = state[target]
+                .wrapping_add(function(x, y, z))
+                .wrapping_add(input[word].wrapping_add(k))
+                .rotate_left(shifts[step % 4]);
+        }
+    }
+    for (word, value) in buf.iter_mut().zip(state) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+/// The entries of an index node: the lowest hash of every child and its block in the
+/// directory. The first entry holds the count instead of a hash and covers everything below
+/// the second.
+fn dx_entries(block: &[u8], offset: usize, block_size: usize) -> Option<Vec<(u32, u32)>> {
+    let u16_at = |off: usize| {
+        Some(u16::from_le_bytes(
+            block.get(off..off + 2)?.try_into().ok()?,
+        ))
+    };
+    let u32_at = |off: usize| {
+        Some(u32::from_le_bytes(
+            block.get(off..off + 4)?.try_into().ok()?,
+        ))
+    };
+    let limit = u16_at(offset)? as usize;
+    let count = u16_at(offset + 2)? as usize;
+    if count == 0 || count > limit || limit != (block_size - offset) / DX_ENTRY_SIZE {
+        return None;
+    }
+    (0..count)
+        .map(|i| {
+            let entry = offset + i * DX_ENTRY_SIZE;
+            let hash = if i == 0 { 0 } else { u32_at(entry)? };
+            Some((hash, u32_at(entry + 4)?))
+        })
+        .collect()
+}
+
+/// Where the lookup is in a node of the index.
+struct Frame {
+    entries: Vec<(u32, u32)>,
+    index: usize,
+}
+
+impl Frame {
+    /// Positions the frame on the last entry whose hash isn't above `hash`.
+    fn new(entries: Vec<(u32, u32)>, hash: u32) -> Self {
+        let index = entries
+            .partition_point(|&(low, _)| low <= hash)
+            .saturating_sub(1);
+        Self { entries, index }
+    }
+
+    fn block(&self) -> u32 {
+        self.entries[self.index].1
+    }
+}
+
+impl<T: RWS> Ext2Filesystem<T> {
+    /// Drops the index of a directory about to change, leaving a plain directory.
+    pub(super) fn drop_dir_index(&mut self, inode_nbr: u32) -> IoResult<()> {
+        let (mut inode, inode_addr) = self.get_inode(inode_nbr)?;
+        if inode.flags.has_flag(InodeFlag::HashIndexedDirectory) {
+            inode.flags.remove_flag(InodeFlag::HashIndexedDirectory);
+            self.disk.lock().write_struct(inode_addr, &inode)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir_block(&self, inode: &Inode, block: u32) -> IoResult<Vec<u8>> {
+        let addr = self.inode_data(inode, block as u64 * self.block_size as u64)?;
+        let mut data = vec![0; self.block_size as usize];
+        self.disk.lock().read_buffer(addr, &mut data)?;
+        Ok(data)
+    }
+
+    /// Looks `filename` up through the index of the directory `inode_nbr`. `None` if the
+    /// directory has no index, or one that can't be used, and has to be searched linearly.
+    pub(super) fn find_indexed_entry(
+        &self,
+        inode_nbr: u32,
+        filename: &str,
+    ) -> IoResult<Option<(DirectoryEntry, OffsetDirEntry)>> {
+        let Some(dir_hash) = self.dir_hash else {
+            return Ok(None);
+        };
+        let (inode, _) = self.get_inode(inode_nbr)?;
+        if !inode.is_a_directory() || !inode.flags.has_flag(InodeFlag::HashIndexedDirectory) {
+            return Ok(None);
+        }
+        let block_size = self.block_size as usize;
+        let root = self.read_dir_block(&inode, 0)?;
+        let Some(&[_, _, _, _, version, info_length, levels, flags]) =
+            root.get(ROOT_INFO_OFFSET..ROOT_INFO_OFFSET + 8)
+        else {
+            return Ok(None);
+        };
+        let Some(version) = HashVersion::from_u8(version) else {
+            return Ok(None);
+        };
+        if levels > MAX_INDIRECT_LEVELS || flags & 1 != 0 {
+            return Ok(None);
+        }
+        let offset = ROOT_INFO_OFFSET + info_length as usize;
+        let Some(entries) = dx_entries(&root, offset, block_size) else {
+            return Ok(None);
+        };
+        let hash = dir_hash.hash(version, filename.as_bytes());
+
+        let mut frames = vec![Frame::new(entries, hash)];
+        for _ in 0..levels {
+            let node = self.read_dir_block(&inode, frames.last().unwrap().block())?;
+            let Some(entries) = dx_entries(&node, NODE_HEADER_SIZE, block_size) else {
+                return Ok(None);
+            };
+            frames.push(Frame::new(entries, hash));
+        }
+
+        loop {
+            let block = frames.last().unwrap().block();
+            if let Some(found) = self.search_leaf(&inode, block, filename)? {
+                return Ok(Some(found));
+            }
+            if !self.next_leaf(&inode, &mut frames, hash)? {
+                return Err(Errno::NoEntry);
+            }
+        }
+    }
+
+    /// Searches the leaf block `block` of a directory for `filename`.
+    fn search_leaf(
+        &self,
+        inode: &Inode,
+        block: u32,
+        filename: &str,
+    ) -> IoResult<Option<(DirectoryEntry, OffsetDirEntry)>> {
+        let data = self.read_dir_block(inode, block)?;
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let entry_inode = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+            let size = u16::from_le_bytes([data[offset + 4], data[offset + 5]]) as usize;
+            let name_length = data[offset + 6] as usize;
+            if size < 8 {
+                break;
+            }
+            let name = data.get(offset + 8..offset + 8 + name_length);
+            if entry_inode != 0 && name == Some(filename.as_bytes()) {
+                let entry_offset = block * self.block_size + offset as u32;
+                let mut inode = *inode;
+                let entry = self
+                    .find_entry((&mut inode, 0), entry_offset as u64)
+                    .ok_or(Errno::BadBlock)?;
+                return Ok(Some((entry, entry_offset)));
+            }
+            offset += size;
+        }
+        Ok(None)
+    }
+
+    /// Moves on to the next leaf if names with the same hash continue there, which the index
+    /// marks by setting the lowest bit of the next hash.
+    fn next_leaf(&self, inode: &Inode, frames: &mut Vec<Frame>, hash: u32) -> IoResult<bool> {
+        let Some(depth) = frames
+            .iter()
+            .rposition(|frame| frame.index + 1 < frame.entries.len())
+        else {
+            return Ok(false);
+        };
+        let frame = &mut frames[depth];
+        let (next_hash, _) = frame.entries[frame.index + 1];
+        if next_hash & 1 == 0 || next_hash & !1 != hash {
+            return Ok(false);
+        }
+        frame.index += 1;
+        let block_size = self.block_size as usize;
+        for level in depth + 1..frames.len() {
+            let node = self.read_dir_block(inode, frames[level - 1].block())?;
+            let entries =
+                dx_entries(&node, NODE_HEADER_SIZE, block_size).ok_or(Errno::InvalidFileImage)?;
+            frames[level] = Frame { entries, index: 0 };
+        }
+        Ok(true)
+    }
+}
+
+#[test_case]
+fn test_dir_hash() {
+    let unseeded = DirHash::default();
+    let seeded = DirHash {
+        seed: [0x67452301, 0xefcdab89, 0x67452301, 0xefcdab89],
+        unsigned: false,
+    };
+    let unsigned = DirHash {
+        unsigned: true,
+        ..unseeded
+    };
+    let long: &[u8] = b"a_rather_long_file_name_that_spans_more_than_32_bytes.txt";
+    let e_acute = "\u{e9}".as_bytes();
+    // as computed by debugfs' dx_hash
+    let cases: [(DirHash, HashVersion, &[u8], u32); 11] = [
+        (unseeded, HashVersion::Legacy, b"hello_world", 0xf42d112e),
+        (unseeded, HashVersion::HalfMd4, b"hello_world", 0x657dfe96),
+        (unseeded, HashVersion::Tea, b"hello_world", 0x6cf0d90e),
+        (unseeded, HashVersion::HalfMd4, b"file-0042", 0x71458164),
+        (seeded, HashVersion::HalfMd4, b"file-0042", 0x1c3f3ec2),
+        (seeded, HashVersion::HalfMd4, long, 0x3e3f6d20),
+        (seeded, HashVersion::Tea, long, 0xe1cc1898),
+        (unseeded, HashVersion::HalfMd4, e_acute, 0x89d4704e),
+        (unsigned, HashVersion::Legacy, e_acute, 0x878ca486),
+        (unsigned, HashVersion::HalfMd4, e_acute, 0xfda9f3f8),
+        (unsigned, HashVersion::Tea, e_acute, 0x6daf7c00),
+    ];
+    for (dir_hash, version, name, hash) in cases {
+        assert_eq!(dir_hash.hash(version, name), hash);
+    }
+}
@@ -0,0 +1,531 @@
+//! Disk quotas: blocks and inodes used per user and per group, with soft and hard limits.
+//!
+//! The tables live in the reserved inodes 3 (users) and 4 (groups), the ones ext4 uses for its
+//! quota files, but in a format of their own: a header followed by one record per id. Quotas
+//! are on while those inodes hold tables. Usage is charged to the owner of an inode as blocks
+//! and inodes are allocated and released, going over a hard limit fails with
+//! `Errno::QuotaExceeded`, as does staying over a soft limit longer than the grace period.
+//! Limits apply to every user, there are no credentials to exempt anyone yet.
+//!
+//! The tables are kept in memory and written back when the filesystem is synced, a crash
+//! loses the usage changed since, which turning quotas on again counts anew.
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{get_bit, Ext2Filesystem, Inode, TypePerm, RWS};
+use crate::clock;
+use crate::ext::{Errno, FilePerms, FileType, IoResult};
+
+const USER_QUOTA_INODE: u32 = 3;
+const GROUP_QUOTA_INODE: u32 = 4;
+const ROOT_INODE: u32 = 2;
+/// ext4's quota feature, with tables of its own in the same inodes
+const RO_COMPAT_QUOTA: u32 = 0x100;
+
+const MAGIC: [u8; 4] = *b"QUOT";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 64;
+/// The largest table, with a record for every id.
+const MAX_TABLE_SIZE: u64 = (HEADER_SIZE + (u16::MAX as usize + 1) * RECORD_SIZE) as u64;
+/// How long usage may stay over a soft limit, unless the table says otherwise.
+const DEFAULT_GRACE: u32 = 7 * 24 * 60 * 60;
+
+/// The owner of an inode, as user and group.
+pub type Owner = (u16, u16);
+
+/// Whose usage a quota limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKind {
+    User,
+    Group,
+}
+
+/// Limits on usage, 0 for none. Blocks are counted in KiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QuotaLimits {
+    pub block_soft: u64,
+    pub block_hard: u64,
+    pub inode_soft: u64,
+    pub inode_hard: u64,
+}
+
+/// Usage and limits of a user or a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    pub id: u16,
+    /// KiB of blocks in use
+    pub blocks: u64,
+    pub inodes: u64,
+    pub limits: QuotaLimits,
+    /// Unix time the block usage has to be back under the soft limit, 0 while it is
+    pub block_grace: u32,
+    /// the same for inodes
+    pub inode_grace: u32,
+}
+
+impl Quota {
+    fn is_empty(&self) -> bool {
+        self.blocks == 0 && self.inodes == 0 && self.limits == QuotaLimits::default()
+    }
+}
+
+/// Whether `used` may grow by `more` under `soft` and `hard`.
+fn within(used: u64, more: u64, soft: u64, hard: u64, grace: u32, now: u32) -> bool {
+    let after = used + more;
+    if more == 0 {
+        return true;
+    }
+    if hard != 0 && after > hard {
+        return false;
+    }
+    // going over the soft limit starts the grace period, it only fails once that ran out
+    soft == 0 || after <= soft || grace == 0 || now < grace
+}
+
+/// Starts or ends the grace period after usage changed.
+fn update_grace(used: u64, soft: u64, grace: &mut u32, period: u32, now: u32) {
+    if soft == 0 || used <= soft {
+        *grace = 0;
+    } else if *grace == 0 {
+        *grace = now.saturating_add(period);
+    }
+}
+
+/// The quotas of all users or all groups.
+#[derive(Debug, Default)]
+struct QuotaTable {
+    /// seconds usage may stay over a soft limit
+    grace: u32,
+    quotas: BTreeMap<u16, Quota>,
+}
+
+impl QuotaTable {
+    fn new() -> Self {
+        Self {
+            grace: DEFAULT_GRACE,
+            quotas: BTreeMap::new(),
+        }
+    }
+
+    /// Checks that `id` may use `blocks` KiB and `inodes` inodes more.
+    fn check(&self, id: u16, blocks: u64, inodes: u64, now: u32) -> IoResult<()> {
+        let Some(quota) = self.quotas.get(&id) else {
+            return Ok(());
+        };
+        let limits = quota.limits;
+        let blocks_ok = within(
+            quota.blocks,
+            blocks,
+            limits.block_soft,
+            limits.block_hard,
+            quota.block_grace,
+            now,
+        );
+        let inodes_ok = within(
+            quota.inodes,
+            inodes,
+            limits.inode_soft,
+            limits.inode_hard,
+            quota.inode_grace,
+            now,
+        );
+        match blocks_ok && inodes_ok {
+            true => Ok(()),
+            false => Err(Errno::QuotaExceeded),
+        }
+    }
+
+    /// Adds to the usage of `id`, negative amounts release it.
+    fn add(&mut self, id: u16, blocks: i64, inodes: i64, now: u32) {
+        let period = self.grace;
+        let quota = self.quotas.entry(id).or_insert(Quota {
+            id,
+            ..Quota::default()
+        });
+        quota.blocks = quota.blocks.saturating_add_signed(blocks);
+        quota.inodes = quota.inodes.saturating_add_signed(inodes);
+        let limits = quota.limits;
+        update_grace(
+            quota.blocks,
+            limits.block_soft,
+            &mut quota.block_grace,
+            period,
+            now,
+        );
+        update_grace(
+            quota.inodes,
+            limits.inode_soft,
+            &mut quota.inode_grace,
+            period,
+            now,
+        );
+        if quota.is_empty() {
+            self.quotas.remove(&id);
+        }
+    }
+
+    fn set_limits(&mut self, id: u16, limits: QuotaLimits, now: u32) {
+        let quota = self.quotas.entry(id).or_insert(Quota {
+            id,
+            ..Quota::default()
+        });
+        quota.limits = limits;
+        self.add(id, 0, 0, now);
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.quotas.len() * RECORD_SIZE);
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&self.grace.to_le_bytes());
+        data.extend_from_slice(&(self.quotas.len() as u32).to_le_bytes());
+        for quota in self.quotas.values() {
+            data.extend_from_slice(&(quota.id as u32).to_le_bytes());
+            data.extend_from_slice(&quota.block_grace.to_le_bytes());
+            data.extend_from_slice(&quota.inode_grace.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            let limits = quota.limits;
+            for value in [
+                quota.blocks,
+                quota.inodes,
+                limits.block_soft,
+                limits.block_hard,
+                limits.inode_soft,
+                limits.inode_hard,
+            ] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    /// Parses a table, `None` if `data` doesn't hold one.
+    fn decode(data: &[u8]) -> Option<Self> {
+        let u32_at =
+            |off: usize| Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?));
+        let u64_at =
+            |off: usize| Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?));
+        if data.get(..4)? != MAGIC || u32_at(4)? != VERSION {
+            return None;
+        }
+        let mut table = Self {
+            grace: u32_at(8)?,
+            quotas: BTreeMap::new(),
+        };
+        for i in 0..u32_at(12)? as usize {
+            let record = HEADER_SIZE + i * RECORD_SIZE;
+            let quota = Quota {
+                id: u32_at(record)? as u16,
+                block_grace: u32_at(record + 4)?,
+                inode_grace: u32_at(record + 8)?,
+                blocks: u64_at(record + 16)?,
+                inodes: u64_at(record + 24)?,
+                limits: QuotaLimits {
+                    block_soft: u64_at(record + 32)?,
+                    block_hard: u64_at(record + 40)?,
+                    inode_soft: u64_at(record + 48)?,
+                    inode_hard: u64_at(record + 56)?,
+                },
+            };
+            table.quotas.insert(quota.id, quota);
+        }
+        Some(table)
+    }
+}
+
+/// The quota tables of a filesystem with quotas on.
+#[derive(Debug)]
+pub struct Quotas {
+    user: QuotaTable,
+    group: QuotaTable,
+    /// changed since the tables were written
+    dirty: bool,
+}
+
+impl Quotas {
+    fn table(&self, kind: QuotaKind) -> &QuotaTable {
+        match kind {
+            QuotaKind::User => &self.user,
+            QuotaKind::Group => &self.group,
+        }
+    }
+
+    fn table_mut(&mut self, kind: QuotaKind) -> &mut QuotaTable {
+        match kind {
+            QuotaKind::User => &mut self.user,
+            QuotaKind::Group => &mut self.group,
+        }
+    }
+}
+
+impl<T: RWS> Ext2Filesystem<T> {
+    /// Reads the quota tables, if quotas are on.
+    pub(super) fn load_quotas(&mut self) -> IoResult<()> {
+        if self.superblock.get_first_inode() <= GROUP_QUOTA_INODE {
+            return Ok(());
+        }
+        let tables = self.read_quota_table(USER_QUOTA_INODE).and_then(|user| {
+            let group = self.read_quota_table(GROUP_QUOTA_INODE)?;
+            Ok((user, group))
+        });
+        match tables {
+            Ok((Some(user), Some(group))) => {
+                self.quotas = Some(Quotas {
+                    user,
+                    group,
+                    dirty: false,
+                })
+            }
+            // a broken table leaves quotas off instead of failing the mount
+            Ok(_) | Err(Errno::BadBlock) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Fails with `Errno::BadBlock` if the size of the table's inode is more than the format
+    /// or the inode's blocks can hold.
+    fn read_quota_table(&self, inode_nbr: u32) -> IoResult<Option<QuotaTable>> {
+        let inode = self.read_inode(inode_nbr)?;
+        let size = inode.get_size();
+        if !inode.is_a_regular_file() || size < HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        if size > MAX_TABLE_SIZE || size > inode.nbr_disk_sectors as u64 * 512 {
+            return Err(Errno::BadBlock);
+        }
+        let mut data = vec![0; size as usize];
+        self.read(inode_nbr, &mut 0, &mut data)?;
+        Ok(QuotaTable::decode(&data))
+    }
+
+    /// Writes the quota tables back if they changed.
+    pub fn save_quotas(&mut self) -> IoResult<()> {
+        if !self.quotas.as_ref().is_some_and(|quotas| quotas.dirty) {
+            return Ok(());
+        }
+        // taken out, nobody is charged for the blocks of the tables themselves
+        let mut quotas = self.quotas.take().unwrap();
+        let result = self
+            .write_quota_table(USER_QUOTA_INODE, &quotas.user)
+            .and_then(|_| self.write_quota_table(GROUP_QUOTA_INODE, &quotas.group));
+        quotas.dirty = result.is_err();
+        self.quotas = Some(quotas);
+        result
+    }
+
+    /// Overwrites the table in `inode_nbr`. A table that shrank leaves old records behind the
+    /// ones its header counts.
+    fn write_quota_table(&mut self, inode_nbr: u32, table: &QuotaTable) -> IoResult<()> {
+        let data = table.encode();
+        let mut offset = 0;
+        self.write(inode_nbr, &mut offset, &data)?;
+        if offset != data.len() as u64 {
+            return Err(Errno::OutOfSpace);
+        }
+        Ok(())
+    }
+
+    /// Turns quotas on, or counts the usage anew if they are, keeping the limits.
+    pub fn quota_on(&mut self) -> IoResult<()> {
+        self.check_writable()?;
+        if self.superblock.get_first_inode() <= GROUP_QUOTA_INODE
+            || self.superblock.get_read_only_features() & RO_COMPAT_QUOTA != 0
+        {
+            return Err(Errno::Unsupported);
+        }
+        let mut quotas = match self.quotas.take() {
+            Some(quotas) => quotas,
+            None => {
+                for inode_nbr in [USER_QUOTA_INODE, GROUP_QUOTA_INODE] {
+                    let (inode, inode_addr) = self.get_inode(inode_nbr)?;
+                    // the inodes may be used by something else
+                    if inode.get_size() != 0 {
+                        return Err(Errno::Unsupported);
+                    }
+                    let mode = FilePerms::UserRW as u16 | FileType::RegularFile as u16;
+                    self.disk
+                        .lock()
+                        .write_struct(inode_addr, &Inode::new(TypePerm(mode)))?;
+                }
+                Quotas {
+                    user: QuotaTable::new(),
+                    group: QuotaTable::new(),
+                    dirty: true,
+                }
+            }
+        };
+        let counted = self.count_usage(&mut quotas);
+        quotas.dirty = true;
+        self.quotas = Some(quotas);
+        counted?;
+        self.save_quotas()
+    }
+
+    /// Turns quotas off and throws the tables away.
+    pub fn quota_off(&mut self) -> IoResult<()> {
+        self.check_writable()?;
+        if self.quotas.take().is_none() {
+            return Ok(());
+        }
+        for inode_nbr in [USER_QUOTA_INODE, GROUP_QUOTA_INODE] {
+            let (mut inode, inode_addr) = self.get_inode(inode_nbr)?;
+            self.truncate_inode((&mut inode, inode_addr), 0)?;
+            self.disk
+                .lock()
+                .write_struct(inode_addr, &Inode::default())?;
+        }
+        Ok(())
+    }
+
+    /// Sets the usage in `quotas` to what the inodes of the filesystem hold.
+    fn count_usage(&self, quotas: &mut Quotas) -> IoResult<()> {
+        let now = clock::unix_seconds();
+        for kind in [QuotaKind::User, QuotaKind::Group] {
+            let table = quotas.table_mut(kind);
+            let ids: Vec<u16> = table.quotas.keys().copied().collect();
+            for id in ids {
+                let quota = table.quotas[&id];
+                table.add(id, -(quota.blocks as i64), -(quota.inodes as i64), now);
+            }
+        }
+        let per_group = self.superblock.inodes_per_block_grp;
+        let first_inode = self.superblock.get_first_inode();
+        let inode_size = self.superblock.get_size_inode() as u64;
+        for group in 0..self.nbr_block_grp {
+            let (block_dtr, _) = self.get_block_grp_descriptor(group)?;
+            let mut bitmap = vec![0; per_group.div_ceil(8) as usize];
+            let bitmap_addr = self.to_addr(block_dtr.inode_usage_bitmap);
+            self.disk.lock().read_buffer(bitmap_addr, &mut bitmap)?;
+            for index in 0..per_group {
+                let inode_nbr = group * per_group + index + 1;
+                if !get_bit(bitmap[index as usize / 8], (index % 8) as u8)
+                    || (inode_nbr < first_inode && inode_nbr != ROOT_INODE)
+                {
+                    continue;
+                }
+                let inode_addr = self.to_addr(block_dtr.inode_table) + index as u64 * inode_size;
+                let inode: Inode = self.disk.lock().read_struct(inode_addr)?;
+                let (user, group) = inode.owner();
+                let blocks = inode.nbr_disk_sectors as i64 / 2;
+                quotas.user.add(user, blocks, 1, now);
+                quotas.group.add(group, blocks, 1, now);
+            }
+        }
+        Ok(())
+    }
+
+    /// The quotas of all users or groups with usage or limits.
+    pub fn quotas(&self, kind: QuotaKind) -> IoResult<Vec<Quota>> {
+        let quotas = self.quotas.as_ref().ok_or(Errno::Unsupported)?;
+        Ok(quotas.table(kind).quotas.values().copied().collect())
+    }
+
+    /// Sets the limits of user or group `id` and writes the table.
+    pub fn set_quota_limits(
+        &mut self,
+        kind: QuotaKind,
+        id: u16,
+        limits: QuotaLimits,
+    ) -> IoResult<()> {
+        self.check_writable()?;
+        let quotas = self.quotas.as_mut().ok_or(Errno::Unsupported)?;
+        quotas
+            .table_mut(kind)
+            .set_limits(id, limits, clock::unix_seconds());
+        quotas.dirty = true;
+        self.save_quotas()
+    }
+
+    /// KiB charged for a block.
+    pub(super) fn block_kib(&self) -> u64 {
+        self.block_size as u64 / 1024
+    }
+
+    /// Charges `owner` for `blocks` KiB and `inodes` inodes, unless that goes over a limit.
+    pub(super) fn charge_quota(&mut self, owner: Owner, blocks: u64, inodes: u64) -> IoResult<()> {
+        self.transfer_quota(None, owner, blocks, inodes)
+    }
+
+    /// Gives `owner` back `blocks` KiB and `inodes` inodes.
+    pub(super) fn release_quota(&mut self, (user, group): Owner, blocks: u64, inodes: u64) {
+        let Some(quotas) = self.quotas.as_mut() else {
+            return;
+        };
+        let now = clock::unix_seconds();
+        quotas
+            .user
+            .add(user, -(blocks as i64), -(inodes as i64), now);
+        quotas
+            .group
+            .add(group, -(blocks as i64), -(inodes as i64), now);
+        quotas.dirty = true;
+    }
+
+    /// Moves usage from `from`, or from nobody, to `to`. Only the user or the group that
+    /// changes is checked against its limits.
+    pub(super) fn transfer_quota(
+        &mut self,
+        from: Option<Owner>,
+        to: Owner,
+        blocks: u64,
+        inodes: u64,
+    ) -> IoResult<()> {
+        let Some(quotas) = self.quotas.as_mut() else {
+            return Ok(());
+        };
+        let now = clock::unix_seconds();
+        let moves = [
+            (QuotaKind::User, from.map(|from| from.0), to.0),
+            (QuotaKind::Group, from.map(|from| from.1), to.1),
+        ];
+        for (kind, from, to) in moves {
+            if from != Some(to) {
+                quotas.table(kind).check(to, blocks, inodes, now)?;
+            }
+        }
+        for (kind, from, to) in moves {
+            if from == Some(to) {
+                continue;
+            }
+            let table = quotas.table_mut(kind);
+            table.add(to, blocks as i64, inodes as i64, now);
+            if let Some(from) = from {
+                table.add(from, -(blocks as i64), -(inodes as i64), now);
+            }
+        }
+        quotas.dirty = true;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_quota_table() {
+    let mut table = QuotaTable::new();
+    let limits = QuotaLimits {
+        block_soft: 8,
+        block_hard: 16,
+        inode_soft: 0,
+        inode_hard: 2,
+    };
+    table.set_limits(1000, limits, 100);
+    table.add(1000, 12, 1, 100);
+    // over the soft limit, within the grace period
+    assert!(table.check(1000, 1, 0, 100).is_ok());
+    assert_eq!(table.quotas[&1000].block_grace, 100 + DEFAULT_GRACE);
+    assert!(table.check(1000, 1, 0, 100 + DEFAULT_GRACE).is_err());
+    assert!(table.check(1000, 5, 0, 100).is_err());
+    assert!(table.check(1000, 0, 2, 100).is_err());
+    assert!(table.check(7, 1 << 20, 1 << 20, 100).is_ok());
+
+    table.add(42, 4, 1, 100);
+    let decoded = QuotaTable::decode(&table.encode()).unwrap();
+    assert_eq!(decoded.grace, table.grace);
+    assert_eq!(decoded.quotas, table.quotas);
+    assert!(QuotaTable::decode(&[0; HEADER_SIZE]).is_none());
+
+    table.add(42, -4, -1, 100);
+    assert!(!table.quotas.contains_key(&42));
+}
@@ -30,6 +30,13 @@ impl<T: RWS> Ext2Filesystem<T> {
             }))
     }
 
+    /// return the entry called `filename` in the directory inode_nbr
+    pub fn lookup(&self, inode_nbr: u32, filename: &str) -> IoResult<Entry> {
+        let (directory, _) = self.find_entry_in_inode(inode_nbr, filename)?;
+        let (inode, _) = self.get_inode(directory.get_inode())?;
+        Ok(Entry { directory, inode })
+    }
+
     pub fn read_inode(&self, inode_number: u32) -> IoResult<Inode> {
         Ok(self.get_inode(inode_number)?.0)
     }
@@ -54,6 +61,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         times: Option<&UtimeBuffer>,
         current_time: u32,
     ) -> IoResult<()> {
+        self.check_writable()?;
         let (mut inode, inode_addr) = self.get_inode(inode_number)?;
 
         if let Some(times) = times {
@@ -64,14 +72,16 @@ impl<T: RWS> Ext2Filesystem<T> {
             inode.last_modification_time = current_time;
         }
 
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
         Ok(())
     }
 
     /// The chown() function shall change the user and group ownership
     /// of a file.
     pub fn chown(&mut self, inode_nbr: u32, owner: u16, group: u16) -> IoResult<()> {
+        self.check_writable()?;
         let (mut inode, inode_addr) = self.get_inode(inode_nbr)?;
+        let old_owner = inode.owner();
 
         if owner != u16::max_value() {
             inode.user_id = owner;
@@ -81,7 +91,9 @@ impl<T: RWS> Ext2Filesystem<T> {
             inode.group_id = group;
         }
 
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        let blocks = inode.nbr_disk_sectors as u64 / 2;
+        self.transfer_quota(Some(old_owner), inode.owner(), blocks, 1)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
         Ok(())
     }
 
@@ -96,6 +108,7 @@ impl<T: RWS> Ext2Filesystem<T> {
     /// [Option Start] S_ISVTX, [Option End] and the file permission
     /// bits of the file
     pub fn chmod(&mut self, inode_nbr: u32, mode: u16) -> IoResult<()> {
+        self.check_writable()?;
         // Ensure that only the file permission bits and special bits are modified.
         let mut mode = mode as u16;
         let mask = *SPECIAL_BITS | *PERMISSIONS_MASK;
@@ -105,13 +118,15 @@ impl<T: RWS> Ext2Filesystem<T> {
         inode.type_and_perm.remove_mode(mask);
         inode.type_and_perm.insert_mode(mode);
 
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
         Ok(())
     }
 
     /// The Truncate() Function Shall cause the regular file named by
     /// path to have a size which shall be equal to length bytes.
+    /// Growing the file leaves a hole, no blocks are allocated.
     pub fn truncate(&mut self, inode_nbr: u32, new_size: u64) -> IoResult<()> {
+        self.check_writable()?;
         let (mut inode, inode_addr) = self.get_inode(inode_nbr)?;
         if !inode.is_a_regular_file() {
             return Err(Errno::IsDirectory);
@@ -119,6 +134,18 @@ impl<T: RWS> Ext2Filesystem<T> {
         self.truncate_inode((&mut inode, inode_addr), new_size)
     }
 
+    /// Like `posix_fallocate`: allocates the blocks of `offset..offset + len` of a regular
+    /// file that aren't yet, and grows it to `offset + len` if it is smaller. Writes to the
+    /// range can't run out of space afterwards.
+    pub fn fallocate(&mut self, inode_nbr: u32, offset: u64, len: u64) -> IoResult<()> {
+        self.check_writable()?;
+        let (mut inode, inode_addr) = self.get_inode(inode_nbr)?;
+        if !inode.is_a_regular_file() {
+            return Err(Errno::IsDirectory);
+        }
+        self.allocate_inode((&mut inode, inode_addr), offset, len)
+    }
+
     pub fn create(
         &mut self,
         filename: &str,
@@ -127,8 +154,9 @@ impl<T: RWS> Ext2Filesystem<T> {
         type_perm: TypePerm,
         (owner, group): (u16, u16),
     ) -> IoResult<Entry> {
+        self.check_writable()?;
         let direntry_type = DirectoryEntryType::try_from(type_perm)?;
-        let inode_nbr = self.alloc_inode().ok_or(Errno::OutOfSpace)?;
+        let inode_nbr = self.alloc_inode((owner, group))?;
         let (_, inode_addr) = self.get_inode(inode_nbr)?;
         let mut inode = Inode::new(type_perm);
 
@@ -138,7 +166,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         inode.creation_time = timestamp;
         inode.last_modification_time = timestamp;
 
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
 
         let mut new_entry = DirectoryEntry::new(filename, direntry_type, inode_nbr)?;
         self.push_entry(parent_inode_nbr, &mut new_entry)?;
@@ -155,9 +183,10 @@ impl<T: RWS> Ext2Filesystem<T> {
         filename: &str,
         free_inode_data: bool,
     ) -> IoResult<()> {
+        self.check_writable()?;
         let entry = self.find_entry_in_inode(parent_inode_nbr, filename)?;
         self.unlink_inode(entry.0.get_inode(), free_inode_data)?;
-        self.delete_entry(parent_inode_nbr, entry.1).expect("WTF");
+        self.delete_entry(parent_inode_nbr, entry.1)?;
         Ok(())
     }
 
@@ -171,7 +200,8 @@ impl<T: RWS> Ext2Filesystem<T> {
         mode: u16,
         (owner, group): (u16, u16),
     ) -> IoResult<Entry> {
-        let inode_nbr = self.alloc_inode().ok_or(Errno::OutOfSpace)?;
+        self.check_writable()?;
+        let inode_nbr = self.alloc_inode((owner, group))?;
         let (_, inode_addr) = self.get_inode(inode_nbr)?;
         let mut inode = Inode::new(TypePerm(mode | FileType::Directory as u16));
         inode.nbr_hard_links = 2;
@@ -182,7 +212,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         inode.last_modification_time = timestamp;
         inode.low_size = 1024 << self.superblock.get_log2_block_size();
 
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
         let mut new_entry =
             DirectoryEntry::new(filename, DirectoryEntryType::Directory, inode_nbr)?;
         self.push_entry(parent_inode_nbr, &mut new_entry)?;
@@ -202,6 +232,7 @@ impl<T: RWS> Ext2Filesystem<T> {
     /// filename in the parent directory corresponding to
     /// parent_inode_nbr
     pub fn rmdir(&mut self, parent_inode_nbr: u32, filename: &str) -> IoResult<()> {
+        self.check_writable()?;
         let entry = self.find_entry_in_inode(parent_inode_nbr, filename)?;
         let inode_nbr = entry.0.get_inode();
         let (mut inode, inode_addr) = self.get_inode(inode_nbr)?;
@@ -221,54 +252,94 @@ impl<T: RWS> Ext2Filesystem<T> {
         file_offset: &mut u64,
         buf: &[u8],
     ) -> IoResult<(u64, Inode)> {
+        self.check_writable()?;
         let (mut inode, inode_addr) = self.get_inode(inode_nbr)?;
+        // writing past the end leaves a hole
         let file_curr_offset_start = *file_offset;
-        if *file_offset > inode.get_size() {
-            // panic!("file_offset > inode.get_size()");
-            return Ok((0, inode));
-        }
         if buf.len() == 0 {
             return Ok((0, inode));
         }
-        let data_address = self.inode_data_alloc((&mut inode, inode_addr), *file_offset)?;
-        let offset = min(
+        // Block allocations and size changes only touch memory until the whole buffer is
+        // written, then the bitmaps, descriptors and the inode are written once.
+        self.begin_batch();
+        let result = self.write_blocks((&mut inode, inode_addr), file_offset, buf);
+        let end = self.end_batch();
+        self.disk.lock().write_struct(inode_addr, &inode)?;
+        result?;
+        end?;
+        Ok((*file_offset - file_curr_offset_start, inode))
+    }
+
+    /// Writes `buf` at `file_offset`, allocating blocks as needed. Stops after a short write.
+    fn write_blocks(
+        &mut self,
+        (inode, inode_addr): (&mut Inode, u64),
+        file_offset: &mut u64,
+        buf: &[u8],
+    ) -> IoResult<()> {
+        let first = min(
             self.block_size as u64 - *file_offset % self.block_size as u64,
             buf.len() as u64,
-        );
-        let data_write = self
-            .disk
-            .borrow_mut()
-            .write_buffer(data_address, &buf[0..offset as usize])?;
-        *file_offset += data_write as u64;
-        if inode.get_size() < *file_offset {
-            inode.update_size(*file_offset, self.block_size);
-            self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
-        }
-        if data_write < offset {
-            return Ok((*file_offset - file_curr_offset_start, inode));
-        }
-
-        for chunk in buf[offset as usize..].chunks(self.block_size as usize) {
-            let data_address = self.inode_data_alloc((&mut inode, inode_addr), *file_offset)?;
-            let data_write = self.disk.borrow_mut().write_buffer(data_address, &chunk)?;
+        ) as usize;
+        let chunks = core::iter::once(&buf[..first])
+            .chain(buf[first..].chunks(self.block_size as usize));
+        for chunk in chunks {
+            let data_address = self.inode_data_alloc((&mut *inode, inode_addr), *file_offset)?;
+            let data_write = self.disk.lock().write_buffer(data_address, chunk)?;
             *file_offset += data_write as u64;
             if inode.get_size() < *file_offset {
-                inode.update_size(*file_offset, self.block_size);
-                self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+                inode.update_size(*file_offset);
             }
             if data_write < chunk.len() as u64 {
-                return Ok((*file_offset - file_curr_offset_start, inode));
+                break;
             }
         }
-        Ok((*file_offset - file_curr_offset_start, inode))
+        Ok(())
     }
 
     /// for read syscall
-    pub fn read(
+    pub fn read(&self, inode_nbr: u32, file_offset: &mut u64, buf: &mut [u8]) -> IoResult<u64> {
+        self.read_inner(inode_nbr, file_offset, buf, false)
+    }
+
+    /// Like `read`, but straight from the disk into `buf`, skipping the read-ahead windows.
+    /// The offset and the length of `buf` have to be multiples of the block size.
+    pub fn read_direct(
+        &self,
+        inode_nbr: u32,
+        file_offset: &mut u64,
+        buf: &mut [u8],
+    ) -> IoResult<u64> {
+        self.check_aligned(*file_offset, buf.len())?;
+        self.read_inner(inode_nbr, file_offset, buf, true)
+    }
+
+    /// Like `write`, but the offset and the length of `buf` have to be multiples of the block
+    /// size.
+    pub fn write_direct(
         &mut self,
         inode_nbr: u32,
         file_offset: &mut u64,
+        buf: &[u8],
+    ) -> IoResult<(u64, Inode)> {
+        self.check_aligned(*file_offset, buf.len())?;
+        self.write(inode_nbr, file_offset, buf)
+    }
+
+    fn check_aligned(&self, offset: u64, len: usize) -> IoResult<()> {
+        let mask = (self.block_size - 1) as u64;
+        if offset & mask != 0 || len as u64 & mask != 0 {
+            return Err(Errno::Unaligned);
+        }
+        Ok(())
+    }
+
+    fn read_inner(
+        &self,
+        inode_nbr: u32,
+        file_offset: &mut u64,
         mut buf: &mut [u8],
+        direct: bool,
     ) -> IoResult<u64> {
         let (mut inode, _inode_addr) = self.get_inode(inode_nbr)?;
 
@@ -283,18 +354,30 @@ impl<T: RWS> Ext2Filesystem<T> {
         }
 
         // Invalidate the cache used after
-        self.cache.invalidate();
+        self.cache.lock().invalidate();
 
         let file_curr_offset_start = *file_offset;
         let block_mask = (self.block_size - 1) as u64;
 
         while buf.len() != 0 {
+            // a hole reads as zeros, up to the end of its block
+            if self.is_hole(&inode, *file_offset)? {
+                let in_block = self.block_size as u64 - (*file_offset & block_mask);
+                let len = min(in_block, buf.len() as u64) as usize;
+                buf[..len].fill(0);
+                *file_offset += len as u64;
+                buf = &mut buf[len..];
+                continue;
+            }
             let mut bytes_to_read = 0;
 
             let mut start_data_address = None;
             let mut last_data_address: Option<u64> = None;
             loop {
-                let data_address = self.inode_data(&mut inode, *file_offset).expect("WTF");
+                // the run ends at a hole
+                let Some(data_address) = self.lookup_data(&inode, *file_offset)? else {
+                    break;
+                };
                 if let Some(last_address) = last_data_address {
                     if data_address != last_address + self.block_size as u64 {
                         break;
@@ -315,16 +398,55 @@ impl<T: RWS> Ext2Filesystem<T> {
                 }
                 last_data_address = Some(data_address);
             }
-            let data_read = self.disk.borrow_mut().read_buffer(
-                start_data_address.expect("WOOT"),
-                &mut buf[0..bytes_to_read as usize],
-            )?;
-            assert!(data_read == bytes_to_read);
+            let start_data_address = start_data_address.ok_or(Errno::BadBlock)?;
+            let mut disk = self.disk.lock();
+            let data_read = if direct {
+                disk.read_direct(start_data_address, &mut buf[0..bytes_to_read as usize])?
+            } else {
+                disk.read_buffer(start_data_address, &mut buf[0..bytes_to_read as usize])?
+            };
+            drop(disk);
+            // the device ended early
+            if data_read != bytes_to_read {
+                return Err(Errno::UnknownIO);
+            }
             buf = &mut buf[bytes_to_read as usize..];
         }
         Ok(*file_offset - file_curr_offset_start)
     }
 
+    /// The first offset from `offset` on that is in an allocated block of the file, like
+    /// `lseek` with `SEEK_DATA`. Fails with `Errno::PastEndOfFile` if only holes follow.
+    pub fn seek_data(&self, inode_nbr: u32, offset: u64) -> IoResult<u64> {
+        self.seek_block(inode_nbr, offset, false)
+    }
+
+    /// The first offset from `offset` on that is in a hole, like `lseek` with `SEEK_HOLE`. The
+    /// end of the file counts as a hole.
+    pub fn seek_hole(&self, inode_nbr: u32, offset: u64) -> IoResult<u64> {
+        self.seek_block(inode_nbr, offset, true)
+    }
+
+    /// Walks the block pointers of a file from `offset` on to the first hole, or the first
+    /// allocated block if `hole` is false.
+    fn seek_block(&self, inode_nbr: u32, offset: u64, hole: bool) -> IoResult<u64> {
+        let (inode, _) = self.get_inode(inode_nbr)?;
+        let size = inode.get_size();
+        if offset >= size {
+            return Err(Errno::PastEndOfFile);
+        }
+        let last = (size - 1) >> self.block_shift;
+        for block in (offset >> self.block_shift)..=last {
+            if self.is_hole(&inode, block << self.block_shift)? == hole {
+                return Ok((block << self.block_shift).max(offset));
+            }
+        }
+        match hole {
+            true => Ok(size),
+            false => Err(Errno::PastEndOfFile),
+        }
+    }
+
     pub fn symlink(
         &mut self,
         parent_inode_nbr: u32,
@@ -332,8 +454,10 @@ impl<T: RWS> Ext2Filesystem<T> {
         filename: &str,
         timestamp: u32,
     ) -> IoResult<Entry> {
+        self.check_writable()?;
         let direntry_type = DirectoryEntryType::SymbolicLink;
-        let inode_nbr = self.alloc_inode().ok_or(Errno::OutOfSpace)?;
+        // symbolic links are created without an owner, leaving them to root
+        let inode_nbr = self.alloc_inode((0, 0))?;
         let (_, inode_addr) = self.get_inode(inode_nbr)?;
         // user: rwx
         // group: rwx
@@ -351,7 +475,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         inode.creation_time = timestamp;
         inode.last_modification_time = timestamp;
 
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
         if target.len() > Inode::FAST_SYMLINK_SIZE_MAX {
             // Else write on the inode data after writing the empty
             // inode on the disk
@@ -376,6 +500,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         target_inode_nbr: u32, // link target
         filename: &str,        // hard link filename
     ) -> IoResult<Entry> {
+        self.check_writable()?;
         let (mut inode, inode_addr) = self.get_inode(target_inode_nbr)?;
         if !inode.is_a_regular_file() {
             return Err(Errno::AccessError);
@@ -385,7 +510,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         self.push_entry(parent_inode_nbr, &mut new_entry)?;
 
         inode.nbr_hard_links += 1;
-        self.disk.borrow_mut().write_struct(inode_addr, &inode)?;
+        self.disk.lock().write_struct(inode_addr, &inode)?;
         Ok(Entry {
             directory: new_entry,
             inode,
@@ -399,6 +524,7 @@ impl<T: RWS> Ext2Filesystem<T> {
         new_parent_inode_nbr: u32,
         new_filename: &str,
     ) -> IoResult<()> {
+        self.check_writable()?;
         let (mut entry, entry_offset) = self.find_entry_in_inode(parent_inode_nbr, filename)?;
         self.delete_entry(parent_inode_nbr, entry_offset)?;
         entry.set_filename(new_filename)?;
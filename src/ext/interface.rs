@@ -1,4 +1,4 @@
-use alloc::string::String;
+use alloc::{string::String, vec::Vec};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u16)]
@@ -51,6 +51,25 @@ impl DirEntry {
             offset,
         }
     }
+
+    pub fn inode(&self) -> u32 {
+        self.inode
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// The name up to its NUL terminator, invalid UTF-8 replaced.
+    pub fn name(&self) -> String {
+        let bytes: Vec<u8> = self
+            .file_name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
 }
 
 #[repr(u16)]
@@ -93,13 +112,26 @@ pub enum FilePerms {
     AllAllowed = 0o777,
 }
 
+/// Kinds of access to a file, combined like the `R_OK`, `W_OK` and `X_OK` of `access(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct AccessFlags(u8);
 
 impl AccessFlags {
+    /// no access, only whether the file exists
+    pub const EXISTS: Self = Self(0);
+    pub const EXECUTE: Self = Self(1);
+    pub const WRITE: Self = Self(2);
+    pub const READ: Self = Self(4);
+
     pub fn from(flags: u8) -> Self {
         Self(flags & 0b111)
     }
 
+    /// True if every access in `self` is in `allowed`.
+    pub fn is_allowed_by(&self, allowed: AccessFlags) -> bool {
+        self.0 & !allowed.0 == 0
+    }
+
     pub fn execute_ok(&self) -> bool {
         (self.0 & 1) > 0
     }
@@ -113,6 +145,32 @@ impl AccessFlags {
     }
 }
 
+/// Who asks for access to a file, see `Ext2::access`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u16,
+    pub gid: u16,
+    /// supplementary groups
+    pub groups: Vec<u16>,
+}
+
+impl Credentials {
+    /// The superuser, who may read and write any file.
+    pub const ROOT: Self = Self {
+        uid: 0,
+        gid: 0,
+        groups: Vec::new(),
+    };
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid: u16) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
 pub struct Stat {
     pub device_id: u64,
     pub inode_id: u32,
@@ -0,0 +1,262 @@
+//! Advisory byte-range locks on inodes.
+//!
+//! Locks only exist in memory and are only checked by other lockers, reading and writing
+//! ignores them. Every open [`File`](super::File) is its own owner: its locks never conflict
+//! with each other and are released when the file is dropped.
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::{Errno, IoResult};
+use crate::sync::{without_interrupts, WaitQueue};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// any number of owners can hold a shared lock on the same bytes
+    Shared,
+    /// excludes every other lock on the same bytes
+    Exclusive,
+}
+
+/// Identifies the holder of a lock.
+pub type LockOwner = u64;
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(1);
+
+pub fn new_owner() -> LockOwner {
+    NEXT_OWNER.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone)]
+struct RangeLock {
+    owner: LockOwner,
+    kind: LockKind,
+    range: Range<u64>,
+}
+
+impl RangeLock {
+    fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.range.start < range.end && range.start < self.range.end
+    }
+}
+
+/// The locks of one filesystem, by inode number.
+pub struct LockTable {
+    locks: Mutex<BTreeMap<u32, Vec<RangeLock>>>,
+    waiters: WaitQueue,
+}
+
+impl fmt::Debug for LockTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockTable").field("locks", &self.locks).finish()
+    }
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self {
+            locks: Mutex::new(BTreeMap::new()),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a lock without waiting, fails with `Errno::Locked` if another owner holds a
+    /// conflicting one.
+    pub fn try_lock(
+        &self,
+        inode: u32,
+        owner: LockOwner,
+        kind: LockKind,
+        range: Range<u64>,
+    ) -> IoResult<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        without_interrupts(|| {
+            let mut locks = self.locks.lock();
+            let conflict = locks.get(&inode).is_some_and(|inode_locks| {
+                inode_locks.iter().any(|lock| {
+                    lock.owner != owner
+                        && lock.overlaps(&range)
+                        && (kind == LockKind::Exclusive || lock.kind == LockKind::Exclusive)
+                })
+            });
+            if conflict {
+                return Err(Errno::Locked);
+            }
+            // only granted locks get an entry
+            let inode_locks = locks.entry(inode).or_default();
+            // taking a lock on bytes the owner already has locked changes its kind
+            remove_range(inode_locks, owner, &range);
+            inode_locks.push(RangeLock { owner, kind, range });
+            Ok(())
+        })
+    }
+
+    /// Takes a lock, waiting until conflicting locks of other owners are released.
+    pub fn lock(
+        &self,
+        inode: u32,
+        owner: LockOwner,
+        kind: LockKind,
+        range: Range<u64>,
+    ) -> IoResult<()> {
+        let mut result = Ok(());
+        self.waiters.wait_until(|| {
+            result = self.try_lock(inode, owner, kind, range.clone());
+            !matches!(result, Err(Errno::Locked))
+        });
+        result
+    }
+
+    /// Releases the owner's locks on `range`, parts of a lock outside of it stay locked.
+    pub fn unlock(&self, inode: u32, owner: LockOwner, range: Range<u64>) {
+        without_interrupts(|| {
+            let mut locks = self.locks.lock();
+            if let Some(inode_locks) = locks.get_mut(&inode) {
+                remove_range(inode_locks, owner, &range);
+                if inode_locks.is_empty() {
+                    locks.remove(&inode);
+                }
+            }
+        });
+        self.waiters.wake_all();
+    }
+
+    /// Releases every lock of an owner on the inode.
+    pub fn unlock_all(&self, inode: u32, owner: LockOwner) {
+        self.unlock(inode, owner, 0..u64::MAX);
+    }
+}
+
+/// Cuts `range` out of the owner's locks.
+fn remove_range(locks: &mut Vec<RangeLock>, owner: LockOwner, range: &Range<u64>) {
+    let mut i = 0;
+    while i < locks.len() {
+        let lock = &locks[i];
+        if lock.owner != owner || !lock.overlaps(range) {
+            i += 1;
+            continue;
+        }
+        let lock = locks.remove(i);
+        if lock.range.start < range.start {
+            locks.push(RangeLock {
+                range: lock.range.start..range.start,
+                ..lock.clone()
+            });
+        }
+        if range.end < lock.range.end {
+            locks.push(RangeLock {
+                range: range.end..lock.range.end,
+                ..lock
+            });
+        }
+    }
+}
+
+/// The locks of `owner` on `inode`, by start.
+#[cfg(test)]
+fn held(table: &LockTable, inode: u32, owner: LockOwner) -> Vec<(LockKind, Range<u64>)> {
+    let locks = table.locks.lock();
+    let mut held: Vec<_> = locks
+        .get(&inode)
+        .into_iter()
+        .flatten()
+        .filter(|lock| lock.owner == owner)
+        .map(|lock| (lock.kind, lock.range.clone()))
+        .collect();
+    held.sort_by_key(|(_, range)| range.start);
+    held
+}
+
+#[test_case]
+fn test_overlapping_locks() {
+    use LockKind::{Exclusive, Shared};
+    let table = LockTable::new();
+    let (a, b) = (new_owner(), new_owner());
+
+    table.try_lock(1, a, Exclusive, 0..10).unwrap();
+    assert!(matches!(
+        table.try_lock(1, b, Shared, 5..15),
+        Err(Errno::Locked)
+    ));
+    // the owner's own locks never conflict, relocking changes the kind of the overlap
+    table.try_lock(1, a, Shared, 5..15).unwrap();
+    assert_eq!(held(&table, 1, a), [(Exclusive, 0..5), (Shared, 5..15)]);
+    table.try_lock(1, b, Shared, 5..20).unwrap();
+    assert!(matches!(
+        table.try_lock(1, b, Shared, 4..5),
+        Err(Errno::Locked)
+    ));
+    // other inodes are apart
+    table.try_lock(2, b, Exclusive, 0..10).unwrap();
+
+    table.unlock_all(1, a);
+    table.unlock_all(1, b);
+    table.unlock_all(2, b);
+    assert!(table.locks.lock().is_empty());
+}
+
+#[test_case]
+fn test_adjacent_locks() {
+    use LockKind::Exclusive;
+    let table = LockTable::new();
+    let (a, b) = (new_owner(), new_owner());
+
+    table.try_lock(1, a, Exclusive, 0..10).unwrap();
+    table.try_lock(1, b, Exclusive, 10..20).unwrap();
+    table.try_lock(1, a, Exclusive, 20..30).unwrap();
+    assert!(matches!(
+        table.try_lock(1, b, Exclusive, 9..10),
+        Err(Errno::Locked)
+    ));
+    // empty ranges lock nothing
+    table.try_lock(1, b, Exclusive, 5..5).unwrap();
+    assert_eq!(
+        held(&table, 1, a),
+        [(Exclusive, 0..10), (Exclusive, 20..30)]
+    );
+    assert_eq!(held(&table, 1, b), [(Exclusive, 10..20)]);
+
+    // nothing is left of a refused lock once the holder unlocks
+    table.unlock_all(1, b);
+    assert!(matches!(
+        table.try_lock(1, b, Exclusive, 0..30),
+        Err(Errno::Locked)
+    ));
+    table.unlock_all(1, a);
+    assert!(table.locks.lock().is_empty());
+}
+
+#[test_case]
+fn test_partial_unlock() {
+    use LockKind::{Exclusive, Shared};
+    let table = LockTable::new();
+    let (a, b) = (new_owner(), new_owner());
+
+    table.try_lock(1, a, Exclusive, 0..100).unwrap();
+    table.unlock(1, a, 20..30);
+    assert_eq!(
+        held(&table, 1, a),
+        [(Exclusive, 0..20), (Exclusive, 30..100)]
+    );
+    table.try_lock(1, b, Exclusive, 20..30).unwrap();
+    assert!(matches!(
+        table.try_lock(1, b, Shared, 19..21),
+        Err(Errno::Locked)
+    ));
+
+    // unlocking the ends and across a gap
+    table.unlock(1, a, 0..5);
+    table.unlock(1, a, 90..200);
+    table.unlock(1, a, 15..35);
+    assert_eq!(
+        held(&table, 1, a),
+        [(Exclusive, 5..15), (Exclusive, 35..90)]
+    );
+    table.unlock(1, a, 0..u64::MAX);
+    assert!(held(&table, 1, a).is_empty());
+    assert_eq!(held(&table, 1, b), [(Exclusive, 20..30)]);
+}
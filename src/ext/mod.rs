@@ -16,6 +16,7 @@
 //! - **rename :** Rename a file or directory to a new name, it cannot replace the original file if to already exists.
 //! - **link :** Make a new name for a file. It is also called “hard-link”.
 //! - **symlink :** Make a new name for a file. It is symbolic links.
+//! - **open_at, create_at, unlink_at, ... :** The same operations relative to a [`Dir`] handle.
 //!
 //! Additionally, the crate also has its own implementation of OpenOptions.
 //!
@@ -38,12 +39,17 @@
 
 mod inner;
 mod interface;
+mod lock;
 pub use interface::*;
+pub use lock::LockKind;
 
 use alloc::string::String;
 use alloc::vec::Vec;
-pub use inner::RWS;
-use inner::{Ext2Filesystem, Inode, TypePerm};
+pub use inner::{Extent, Quota, QuotaKind, QuotaLimits, StatFs, RWS};
+use inner::{Ext2Filesystem, Inode, PermissionClass, TypePerm};
+use lock::{LockOwner, LockTable};
+
+use crate::{clock, config};
 
 #[derive(Debug, Clone, Copy)]
 /// Errors
@@ -80,25 +86,39 @@ pub enum Errno {
     BadBlock,
     /// file is too big
     FileTooBig,
+    /// a conflicting lock is held by another file
+    Locked,
+    /// the filesystem is mounted read-only
+    ReadOnlyFs,
+    /// direct I/O that isn't aligned to the block size
+    Unaligned,
+    /// a user or group went over its disk quota
+    QuotaExceeded,
+    /// seeking data or a hole from the end of the file on
+    PastEndOfFile,
 }
 
 type IoResult<T> = core::result::Result<T, Errno>;
 
 use core::mem::MaybeUninit;
-use spin::Mutex;
+use core::ops::Range;
+use spin::RwLock;
 extern crate alloc;
 use alloc::sync::Arc;
 
 /// This structure represents an entire ext2 filesystem.
+///
+/// Operations that only read take the filesystem lock shared, so tasks can read different
+/// files at the same time. Anything that modifies the filesystem takes it exclusively.
 #[derive(Debug)]
-pub struct Ext2<T: RWS>(Arc<Mutex<Ext2Filesystem<T>>>);
+pub struct Ext2<T: RWS>(Arc<RwLock<Ext2Filesystem<T>>>, Arc<LockTable>);
 
 impl<T> Clone for Ext2<T>
 where
     T: RWS,
 {
     fn clone(&self) -> Self {
-        Ext2(self.0.clone())
+        Ext2(self.0.clone(), self.1.clone())
     }
 }
 
@@ -132,7 +152,82 @@ where
     /// let ext2 = open_ext2_drive(f).unwrap();
     /// ```
     pub fn new(disk: T) -> IoResult<Self> {
-        Ok(Self(Arc::new(Mutex::new(Ext2Filesystem::new(disk)?))))
+        Self::with_read_only(disk, false)
+    }
+
+    /// Like [`Ext2::new`], but every modification fails with [`Errno::ReadOnlyFs`] until
+    /// remounted read-write.
+    pub fn new_read_only(disk: T) -> IoResult<Self> {
+        Self::with_read_only(disk, true)
+    }
+
+    fn with_read_only(disk: T, read_only: bool) -> IoResult<Self> {
+        Ok(Self(
+            Arc::new(RwLock::new(Ext2Filesystem::new(disk, read_only)?)),
+            Arc::new(LockTable::new()),
+        ))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.0.read().is_read_only()
+    }
+
+    /// Makes sure everything written so far reached stable media, the quota tables included.
+    pub fn sync(&self) -> IoResult<()> {
+        let mut ext2 = self.0.write();
+        ext2.save_quotas()?;
+        ext2.sync()
+    }
+
+    /// Switches the filesystem between read-only and read-write. Files opened for writing stay
+    /// open, but their writes fail while the filesystem is read-only.
+    pub fn remount(&self, read_only: bool) -> IoResult<()> {
+        let mut ext2 = self.0.write();
+        if read_only && !ext2.is_read_only() {
+            ext2.save_quotas()?;
+            ext2.sync()?;
+        }
+        ext2.set_read_only(read_only);
+        Ok(())
+    }
+
+    /// Turns quota tracking on, counting what every user and group uses. If it is already on,
+    /// the usage is counted anew and the limits kept.
+    pub fn quota_on(&self) -> IoResult<()> {
+        self.0.write().quota_on()
+    }
+
+    /// Turns quota tracking off, dropping all limits.
+    pub fn quota_off(&self) -> IoResult<()> {
+        self.0.write().quota_off()
+    }
+
+    /// The runs of blocks a file is stored in, in the order of the file.
+    pub fn extents<P: Into<String>>(&self, path: P) -> IoResult<Vec<Extent>> {
+        let path = Path::new(path);
+        let path = get_path(&path)?;
+        let ext2 = self.0.read();
+
+        match _find_entry(&ext2, path)? {
+            Some(entry) => ext2.extents(entry.directory.get_inode()),
+            None => Err(Errno::NotFound),
+        }
+    }
+
+    /// The size of the filesystem and how much of it is free.
+    pub fn statfs(&self) -> StatFs {
+        self.0.read().statfs()
+    }
+
+    /// The usage and limits of every user or group that has any. Fails with
+    /// [`Errno::Unsupported`] while quotas are off.
+    pub fn quotas(&self, kind: QuotaKind) -> IoResult<Vec<Quota>> {
+        self.0.read().quotas(kind)
+    }
+
+    /// Sets the limits of a user or group, 0 removing a limit.
+    pub fn set_quota(&self, kind: QuotaKind, id: u16, limits: QuotaLimits) -> IoResult<()> {
+        self.0.write().set_quota_limits(kind, id, limits)
     }
 
     /// Opens a file in write-only mode.
@@ -176,33 +271,9 @@ where
     pub fn read_dir<P: Into<String>>(&self, path: P) -> IoResult<Vec<DirEntry>> {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        let ext2 = self.0.lock();
+        let ext2 = self.0.read();
         let iter = _lookup_directory(&ext2, path)?;
-
-        let type_field = ext2.get_superblock().directory_entry_contain_type_field();
-        use inner::DirectoryEntryType::*;
-        Ok(iter
-            .enumerate()
-            .map(move |(i, entry)| {
-                DirEntry::new(
-                    entry.directory.header.inode,
-                    i as u64,
-                    match type_field {
-                        true => match entry.directory.header.type_indicator {
-                            BlockDevice => FileType::BlockDevice,
-                            Directory => FileType::Directory,
-                            CharacterDevice => FileType::CharacterDevice,
-                            Fifo => FileType::FiFo,
-                            Socket => FileType::Socket,
-                            SymbolicLink => FileType::Symlink,
-                            RegularFile => FileType::RegularFile,
-                        },
-                        false => FileType::Unknown,
-                    },
-                    entry.directory.filename.0,
-                )
-            })
-            .collect())
+        Ok(_dir_entries(&ext2, iter))
     }
 
     /// Creates a new, empty directory at the provided path.
@@ -212,13 +283,13 @@ where
     pub fn create_dir<P: Into<String>>(&mut self, path: P) -> IoResult<()> {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        let timestamp = 0; // TODO: timestamp
+        let timestamp = clock::unix_seconds();
         let parent = path.parent().ok_or(Errno::AccessError)?;
         let filename: &str = path.file_name().as_str();
-        let mut ext2 = self.0.lock();
+        let mut ext2 = self.0.write();
         let iter = _lookup_directory(&ext2, &parent)?;
         let parent = iter.fold(Ok(None), |res, entry| {
-            if entry.directory.filename == filename.try_into().unwrap() {
+            if unsafe { entry.directory.get_filename() } == filename {
                 return Err(Errno::AlreadyExists);
             }
             res.map(|opt| {
@@ -231,11 +302,12 @@ where
                 })
             })
         })?;
-        let parent_inode_nbr = parent.unwrap().directory.header.inode;
+        // every directory has a `.`
+        let parent_inode_nbr = parent.ok_or(Errno::InvalidFileImage)?.directory.header.inode;
         ext2.create_dir(
             parent_inode_nbr,
             filename,
-            timestamp as u32,
+            timestamp,
             def_mode() as u16 | FilePerms::AllExec as u16,
             (0, 0),
         )?;
@@ -249,7 +321,7 @@ where
     pub fn remove_dir<P: Into<String>>(&mut self, path: P) -> IoResult<()> {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        let mut ext2 = self.0.lock();
+        let mut ext2 = self.0.write();
         let iter = _lookup_directory(&ext2, path)?;
         let parent = iter.enumerate().fold(Ok(None), |res, (idx, entry)| {
             if idx > 1 {
@@ -265,7 +337,8 @@ where
                 })
             })
         })?;
-        ext2.rmdir(parent.unwrap().directory.get_inode(), path.file_name())
+        let parent = parent.ok_or(Errno::InvalidFileImage)?;
+        ext2.rmdir(parent.directory.get_inode(), path.file_name())
     }
 
     /// Change the file permission bits of the specified file.
@@ -276,7 +349,7 @@ where
     pub fn chmod<P: Into<String>>(&mut self, path: P, mode: u16) -> IoResult<()> {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        let mut ext2 = self.0.lock();
+        let mut ext2 = self.0.write();
 
         match _find_entry(&ext2, path)? {
             Some(entry) => Ok(ext2.chmod(entry.directory.get_inode(), mode)?),
@@ -293,7 +366,7 @@ where
     pub fn chown<P: Into<String>>(&mut self, path: P, owner: u16, group: u16) -> IoResult<()> {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        let mut ext2 = self.0.lock();
+        let mut ext2 = self.0.write();
 
         match _find_entry(&ext2, path)? {
             Some(entry) => {
@@ -310,7 +383,7 @@ where
     pub fn stat<P: Into<String>>(&self, path: P) -> IoResult<Stat> {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        let ext2 = self.0.lock();
+        let ext2 = self.0.read();
 
         match _find_entry(&ext2, path)? {
             Some(entry) => Ok(_stat(&ext2, entry.directory.get_inode(), entry.inode)?),
@@ -318,6 +391,53 @@ where
         }
     }
 
+    /// Checks whether `credentials` allow the accesses in `mode` to the file at `path`, without
+    /// opening it, like `access(2)`. Every directory on the way has to be searchable. Fails
+    /// with [`Errno::AccessError`] if the permission bits forbid any of them, and with
+    /// [`Errno::ReadOnlyFs`] for writes while the filesystem is read-only.
+    /// ```rust,ignore
+    /// let mode = AccessFlags::READ;
+    /// ext2.access("/bananes/toto.txt", mode, &Credentials::ROOT).unwrap();
+    /// ```
+    pub fn access<P: Into<String>>(
+        &self,
+        path: P,
+        mode: AccessFlags,
+        credentials: &Credentials,
+    ) -> IoResult<()> {
+        let path = Path::new(path);
+        let path = get_path(&path)?;
+        let ext2 = self.0.read();
+
+        let (mut inode_nbr, mut inode) = (ROOT_INODE, ext2.read_inode(ROOT_INODE)?);
+        for name in path.components().filter(|name| !name.is_empty()) {
+            if !inode.is_a_directory() {
+                return Err(Errno::NotDirectory);
+            }
+            _check_access(&ext2, &inode, AccessFlags::EXECUTE, credentials)?;
+            let entry = ext2.lookup(inode_nbr, name)?;
+            (inode_nbr, inode) = (entry.directory.get_inode(), entry.inode);
+        }
+        _check_access(&ext2, &inode, mode, credentials)
+    }
+
+    /// Like [`Ext2::access`] for the entry `name` in `dir`, as `faccessat(2)` does relative to
+    /// a directory. Only `dir` itself has to be searchable.
+    pub fn access_at(
+        &self,
+        dir: &Dir,
+        name: &str,
+        mode: AccessFlags,
+        credentials: &Credentials,
+    ) -> IoResult<()> {
+        check_name(name)?;
+        let ext2 = self.0.read();
+        let dir_inode = ext2.read_inode(dir.inode)?;
+        _check_access(&ext2, &dir_inode, AccessFlags::EXECUTE, credentials)?;
+        let entry = ext2.lookup(dir.inode, name)?;
+        _check_access(&ext2, &entry.inode, mode, credentials)
+    }
+
     /// Removes a file from the filesystem.
     ///
     /// # Platform-specific behavior
@@ -331,11 +451,11 @@ where
     pub fn remove_file<P: Into<String>>(&mut self, path: P) -> IoResult<()> {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        path.parent().ok_or(Errno::AccessError)?;
-        let mut ext2 = self.0.lock();
+        let parent = path.parent().ok_or(Errno::AccessError)?;
+        let mut ext2 = self.0.write();
 
-        let parent = _find_entry(&ext2, &path.parent().unwrap())?;
-        let parent_inode_nbr = parent.unwrap().directory.header.inode;
+        let parent = _find_entry(&ext2, &parent)?.ok_or(Errno::NotFound)?;
+        let parent_inode_nbr = parent.directory.header.inode;
         Ok(ext2.unlink(parent_inode_nbr, path.file_name().as_str(), true)?)
     }
 
@@ -347,12 +467,12 @@ where
     /// })).unwrap();
     /// ```
     pub fn utime<P: Into<String>>(&mut self, path: P, time: Option<&UtimeBuffer>) -> IoResult<()> {
-        let timestamp = 0; // TODO: time
+        let timestamp = clock::unix_seconds();
         let path = Path::new(path);
         let path = get_path(&path)?;
-        let mut ext2 = self.0.lock();
+        let mut ext2 = self.0.write();
         match _find_entry(&ext2, path)? {
-            Some(entry) => Ok(ext2.utime(entry.directory.get_inode(), time, timestamp as u32)?),
+            Some(entry) => Ok(ext2.utime(entry.directory.get_inode(), time, timestamp)?),
             None => Err(Errno::NotFound),
         }
     }
@@ -369,18 +489,19 @@ where
         let new_path = get_path(&new_path)?;
         match (path.parent(), new_path.parent()) {
             (Some(parent), Some(new_parent)) => {
-                let mut ext2 = self.0.lock();
+                let mut ext2 = self.0.write();
                 if let Ok(Some(_)) = _find_entry(&ext2, new_path) {
                     return Err(Errno::AlreadyExists);
                 }
                 let child = _find_entry(&ext2, &parent)?;
                 match child {
                     Some(child) => {
-                        let new_parent = _find_entry(&ext2, &new_parent)?;
+                        let new_parent =
+                            _find_entry(&ext2, &new_parent)?.ok_or(Errno::NotFound)?;
                         Ok(ext2.rename(
                             child.directory.get_inode(),
                             path.file_name(),
-                            new_parent.unwrap().directory.get_inode(),
+                            new_parent.directory.get_inode(),
                             new_path.file_name(),
                         )?)
                     }
@@ -402,16 +523,17 @@ where
         let link_path = get_path(&link_path)?;
         match link_path.parent() {
             Some(link_parent) => {
-                let mut ext2 = self.0.lock();
+                let mut ext2 = self.0.write();
                 if let Ok(Some(_)) = _find_entry(&ext2, link_path) {
                     return Err(Errno::AlreadyExists);
                 }
                 let target_entry = _find_entry(&ext2, target_path)?;
                 match target_entry {
                     Some(target_entry) => {
-                        let parent_link = _find_entry(&ext2, &link_parent)?;
+                        let parent_link =
+                            _find_entry(&ext2, &link_parent)?.ok_or(Errno::NotFound)?;
                         ext2.link(
-                            parent_link.unwrap().directory.get_inode(),
+                            parent_link.directory.get_inode(),
                             target_entry.directory.get_inode(),
                             link_path.file_name(),
                         )?;
@@ -431,19 +553,20 @@ where
     pub fn symlink<P: Into<String>>(&mut self, target_path: P, link_path: P) -> IoResult<()> {
         let link_path = Path::new(link_path);
         let link_path = get_path(&link_path)?;
-        let timestamp = 0; // TODO: time
+        let timestamp = clock::unix_seconds();
         match link_path.parent() {
             Some(link_parent) => {
-                let mut ext2 = self.0.lock();
+                let mut ext2 = self.0.write();
                 if let Ok(Some(_)) = _find_entry(&ext2, link_path) {
                     return Err(Errno::AlreadyExists);
                 }
-                let parent_link_entry = _find_entry(&ext2, &link_parent)?;
+                let parent_link_entry =
+                    _find_entry(&ext2, &link_parent)?.ok_or(Errno::NotFound)?;
                 ext2.symlink(
-                    parent_link_entry.unwrap().directory.get_inode(),
+                    parent_link_entry.directory.get_inode(),
                     &target_path.into(),
                     link_path.file_name(),
-                    timestamp as u32,
+                    timestamp,
                 )?;
                 Ok(())
             }
@@ -452,6 +575,196 @@ where
     }
 }
 
+/// Operations on a [`Dir`] handle, which take a single name instead of an absolute path.
+impl<T> Ext2<T>
+where
+    T: RWS,
+{
+    /// Returns a handle to the root directory.
+    pub fn root_dir(&self) -> Dir {
+        Dir { inode: ROOT_INODE }
+    }
+
+    /// Resolves the directory at `path` once and returns a handle to it.
+    /// ```rust,ignore
+    /// let dir = ext2.open_dir("/bananes").unwrap();
+    /// let file = ext2.open_at(&dir, "toto.txt").unwrap();
+    /// ```
+    pub fn open_dir<P: Into<String>>(&self, path: P) -> IoResult<Dir> {
+        let path = Path::new(path);
+        let path = get_path(&path)?;
+        _open_dir(&self.0.read(), path)
+    }
+
+    /// Returns a handle to the directory `name` in `dir`.
+    pub fn open_dir_at(&self, dir: &Dir, name: &str) -> IoResult<Dir> {
+        check_name(name)?;
+        let entry = self.0.read().lookup(dir.inode, name)?;
+        if !entry.inode.is_a_directory() {
+            return Err(Errno::NotDirectory);
+        }
+        Ok(Dir {
+            inode: entry.directory.get_inode(),
+        })
+    }
+
+    /// Opens the file `name` in `dir` in read-only mode, see [`Ext2::open`].
+    pub fn open_at(&mut self, dir: &Dir, name: &str) -> IoResult<File<T>> {
+        OpenOptions::new().read(true).open_at(dir, name, self.clone())
+    }
+
+    /// Creates or truncates the file `name` in `dir`, see [`Ext2::create`].
+    pub fn create_at(&mut self, dir: &Dir, name: &str) -> IoResult<File<T>> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open_at(dir, name, self.clone())
+    }
+
+    /// Returns the entries of `dir`.
+    pub fn read_dir_at(&self, dir: &Dir) -> IoResult<Vec<DirEntry>> {
+        let ext2 = self.0.read();
+        let iter = ext2.lookup_directory(dir.inode)?;
+        Ok(_dir_entries(&ext2, iter))
+    }
+
+    /// Returns information about the entry `name` in `dir`.
+    pub fn stat_at(&self, dir: &Dir, name: &str) -> IoResult<Stat> {
+        check_name(name)?;
+        let ext2 = self.0.read();
+        let entry = ext2.lookup(dir.inode, name)?;
+        _stat(&ext2, entry.directory.get_inode(), entry.inode)
+    }
+
+    /// Creates the empty directory `name` in `dir` and returns a handle to it.
+    pub fn create_dir_at(&mut self, dir: &Dir, name: &str) -> IoResult<Dir> {
+        check_name(name)?;
+        let timestamp = clock::unix_seconds();
+        let mut ext2 = self.0.write();
+        match ext2.lookup(dir.inode, name) {
+            Ok(_) => return Err(Errno::AlreadyExists),
+            Err(Errno::NoEntry) => {}
+            Err(e) => return Err(e),
+        }
+        let entry = ext2.create_dir(
+            dir.inode,
+            name,
+            timestamp,
+            def_mode() as u16 | FilePerms::AllExec as u16,
+            (0, 0),
+        )?;
+        Ok(Dir {
+            inode: entry.directory.get_inode(),
+        })
+    }
+
+    /// Removes the empty directory `name` from `dir`.
+    pub fn remove_dir_at(&mut self, dir: &Dir, name: &str) -> IoResult<()> {
+        check_name(name)?;
+        if name == "." || name == ".." {
+            return Err(Errno::AccessError);
+        }
+        let mut ext2 = self.0.write();
+        let entry = ext2.lookup(dir.inode, name)?;
+        if !entry.inode.is_a_directory() {
+            return Err(Errno::NotDirectory);
+        }
+        // only `.` and `..` may be left
+        if ext2.lookup_directory(entry.directory.get_inode())?.count() > 2 {
+            return Err(Errno::AccessError);
+        }
+        ext2.rmdir(dir.inode, name)
+    }
+
+    /// Removes the file `name` from `dir`.
+    pub fn unlink_at(&mut self, dir: &Dir, name: &str) -> IoResult<()> {
+        check_name(name)?;
+        let mut ext2 = self.0.write();
+        if ext2.lookup(dir.inode, name)?.inode.is_a_directory() {
+            return Err(Errno::IsDirectory);
+        }
+        ext2.unlink(dir.inode, name, true)
+    }
+}
+
+/// A handle to a directory of an [`Ext2`] filesystem.
+///
+/// The path is resolved once when the handle is created, operations through it look names up
+/// directly in the directory instead of walking the path from `/` again. The handle only
+/// stores the inode number: it must be used with the filesystem it came from and goes stale
+/// when the directory is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dir {
+    inode: u32,
+}
+
+impl Dir {
+    /// Inode number of the directory.
+    pub fn inode(&self) -> u32 {
+        self.inode
+    }
+}
+
+/// Inode number of the root directory.
+const ROOT_INODE: u32 = 2;
+
+/// Names used with a [`Dir`] are a single path component.
+fn check_name(name: &str) -> IoResult<()> {
+    if name.is_empty() {
+        Err(Errno::StringEmpty)
+    } else if name.contains('/') {
+        Err(Errno::IllegalCharacter)
+    } else {
+        Ok(())
+    }
+}
+
+fn _open_dir<T>(ext2: &Ext2Filesystem<T>, path: &Path) -> IoResult<Dir>
+where
+    T: RWS,
+{
+    let entry = _find_entry(ext2, path)?.ok_or(Errno::NoEntry)?;
+    if !entry.inode.is_a_directory() {
+        return Err(Errno::NotDirectory);
+    }
+    Ok(Dir {
+        inode: entry.directory.get_inode(),
+    })
+}
+
+fn _dir_entries<T>(
+    ext2: &Ext2Filesystem<T>,
+    iter: impl Iterator<Item = inner::Entry>,
+) -> Vec<DirEntry>
+where
+    T: RWS,
+{
+    let type_field = ext2.get_superblock().directory_entry_contain_type_field();
+    use inner::DirectoryEntryType::*;
+    iter.enumerate()
+        .map(move |(i, entry)| {
+            DirEntry::new(
+                entry.directory.header.inode,
+                i as u64,
+                match type_field {
+                    true => match entry.directory.header.type_indicator {
+                        BlockDevice => FileType::BlockDevice,
+                        Directory => FileType::Directory,
+                        CharacterDevice => FileType::CharacterDevice,
+                        Fifo => FileType::FiFo,
+                        Socket => FileType::Socket,
+                        SymbolicLink => FileType::Symlink,
+                        RegularFile => FileType::RegularFile,
+                    },
+                    false => FileType::Unknown,
+                },
+                entry.directory.filename.0,
+            )
+        })
+        .collect()
+}
+
 fn def_mode() -> u16 {
     FilePerms::UserWrite as u16 | FilePerms::AllRead as u16
 }
@@ -491,7 +804,7 @@ where
     T: RWS,
 {
     debug_assert_eq!(path.is_absolute(), true);
-    let mut iter = ext2.lookup_directory(2).expect("Root mut be a directory");
+    let mut iter = ext2.lookup_directory(ROOT_INODE)?;
     for directory in path.components() {
         if directory == ""
         /* ROOT DIRECTORY */
@@ -519,6 +832,39 @@ where
     }
     Ok(iter)
 }
+
+/// Checks the permission bits of `inode` against `mode`, see [`Ext2::access`]. The superuser
+/// may read and write anything, but only execute files someone may execute.
+fn _check_access<T>(
+    ext2: &Ext2Filesystem<T>,
+    inode: &Inode,
+    mode: AccessFlags,
+    credentials: &Credentials,
+) -> IoResult<()>
+where
+    T: RWS,
+{
+    if mode.write_ok() && ext2.is_read_only() {
+        return Err(Errno::ReadOnlyFs);
+    }
+    let perms = inode.type_and_perm;
+    let allowed = if credentials.is_root() {
+        let executable = inode.is_a_directory() || perms.0 & FilePerms::AllExec as u16 != 0;
+        AccessFlags::from(if executable { 0o7 } else { 0o6 })
+    } else if inode.user_id == credentials.uid {
+        perms.class_access(PermissionClass::Owner)
+    } else if credentials.in_group(inode.group_id) {
+        perms.class_access(PermissionClass::Group)
+    } else {
+        perms.class_access(PermissionClass::Other)
+    };
+    if mode.is_allowed_by(allowed) {
+        Ok(())
+    } else {
+        Err(Errno::AccessError)
+    }
+}
+
 fn _stat<T>(ext2: &Ext2Filesystem<T>, inode_nbr: u32, inode: Inode) -> IoResult<Stat>
 where
     T: RWS,
@@ -565,6 +911,7 @@ pub struct OpenOptions {
     create: bool,
     append: bool,
     truncate: bool,
+    direct: bool,
 }
 
 impl OpenOptions {
@@ -594,6 +941,7 @@ impl OpenOptions {
             create: false,
             append: false,
             truncate: false,
+            direct: false,
         }
     }
 
@@ -749,6 +1097,24 @@ impl OpenOptions {
         self
     }
 
+    /// Sets the option for unbuffered I/O.
+    ///
+    /// Reads go straight from the disk into the caller's buffer, without read-ahead. Reads and
+    /// writes have to start at a multiple of the block size and have a length that's a
+    /// multiple of it, otherwise they fail with [`Errno::Unaligned`]. Meant for large
+    /// sequential transfers like copying disk images, which would only push everything else
+    /// out of the caches.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,ignore
+    /// let file = OpenOptions::new().read(true).direct(true).open("/disk.img", ext2);
+    /// ```
+    pub fn direct(&mut self, direct: bool) -> &mut Self {
+        self.direct = direct;
+        self
+    }
+
     /// Opens a file at `path` with the options specified by `self`.
     ///
     /// # Errors
@@ -758,9 +1124,9 @@ impl OpenOptions {
     /// with their [`std::io::Errno`]. The mapping to [`std::io::Errno`]s is not
     /// part of the compatibility contract of the function.
     ///
-    /// * [`NotFound`]: The specified file does not exist and neither `create`
+    /// * [`NoEntry`]: The specified file does not exist and neither `create`
     ///   or `create_new` is set.
-    /// * [`NotFound`]: One of the directory components of the file path does
+    /// * [`NoEntry`]: One of the directory components of the file path does
     ///   not exist.
     /// * [`InvalidInput`]: Invalid combinations of open options (truncate
     ///   without write access, no access mode set, etc.).
@@ -789,19 +1155,32 @@ impl OpenOptions {
     /// ```
     ///
     /// [`InvalidInput`]: std::io::Errno::InvalidInput
-    /// [`NotFound`]: std::io::Errno::NotFound
+    /// [`NoEntry`]: Errno::NoEntry
     pub fn open<T, P: Into<String>>(&mut self, path: P, ext2_clone: Ext2<T>) -> IoResult<File<T>>
     where
         T: RWS,
     {
         let path = Path::new(path);
         let path = get_path(&path)?;
-        path.parent().ok_or(Errno::AccessError)?;
-        let mut ext2 = ext2_clone.0.lock();
+        let parent = path.parent().ok_or(Errno::AccessError)?;
+        let dir = _open_dir(&ext2_clone.0.read(), &parent)?;
+        self.open_at(&dir, path.file_name(), ext2_clone)
+    }
+
+    /// Opens the file `name` in `dir` with the options specified by `self`, see
+    /// [`OpenOptions::open`].
+    pub fn open_at<T>(&mut self, dir: &Dir, name: &str, ext2_clone: Ext2<T>) -> IoResult<File<T>>
+    where
+        T: RWS,
+    {
+        check_name(name)?;
+        let mut ext2 = ext2_clone.0.write();
+        if self.write && ext2.is_read_only() {
+            return Err(Errno::ReadOnlyFs);
+        }
 
-        let file = _find_entry(&ext2, path)?;
-        match file {
-            Some(file) => {
+        match ext2.lookup(dir.inode, name) {
+            Ok(file) => {
                 if file.inode.is_a_directory() {
                     // TODO Must be a regular file
                     Err(Errno::AccessError)
@@ -820,17 +1199,18 @@ impl OpenOptions {
                         curr_offset: curr_offset as u64,
                         ext2: ext2_clone,
                         options: *self,
+                        lock_owner: lock::new_owner(),
+                        readahead: ReadAhead::default(),
                     })
                 }
             }
-            None => {
+            Err(Errno::NoEntry) => {
                 if self.create && self.write {
-                    let timestamp = 0; // TODO: time
-                    let parent = _find_entry(&ext2, &path.parent().unwrap())?;
+                    let timestamp = clock::unix_seconds();
                     let entry = ext2.create(
-                        &path.file_name(),
-                        parent.unwrap().directory.get_inode(),
-                        timestamp as u32,
+                        name,
+                        dir.inode,
+                        timestamp,
                         TypePerm(def_mode() | FileType::RegularFile as u16),
                         (0, 0),
                     )?;
@@ -840,11 +1220,14 @@ impl OpenOptions {
                         curr_offset: 0,
                         ext2: ext2_clone,
                         options: *self,
+                        lock_owner: lock::new_owner(),
+                        readahead: ReadAhead::default(),
                     })
                 } else {
-                    Err(Errno::NotFound)
+                    Err(Errno::NoEntry)
                 }
             }
+            Err(e) => Err(e),
         }
     }
 }
@@ -866,6 +1249,32 @@ where
     curr_offset: u64,
     ext2: Ext2<T>,
     options: OpenOptions,
+    /// identifies the advisory locks taken through this file
+    lock_owner: LockOwner,
+    readahead: ReadAhead,
+}
+
+/// Detects sequential reads of a file. The read-ahead window starts small and doubles with
+/// every sequential read, any other access turns it off again.
+#[derive(Debug, Default, Clone, Copy)]
+struct ReadAhead {
+    /// where the next read starts if the file is read sequentially
+    next: u64,
+    /// bytes to read ahead, 0 while reads aren't sequential
+    window: u64,
+}
+
+impl ReadAhead {
+    /// Records a read of `len` bytes at `offset`. Returns what to read ahead, if anything.
+    fn update(&mut self, offset: u64, len: u64) -> Option<(u64, u64)> {
+        self.window = if offset == self.next && len != 0 {
+            (self.window * 2).clamp(config::READAHEAD_MIN, config::get().readahead_max)
+        } else {
+            0
+        };
+        self.next = offset + len;
+        (self.window != 0).then_some((self.next, self.window))
+    }
 }
 
 impl<T> File<T>
@@ -881,6 +1290,99 @@ where
     pub fn metadata() {
         unimplemented!();
     }
+
+    /// Moves the cursor to the first byte from `offset` on that is stored on disk, skipping
+    /// holes, like `lseek` with `SEEK_DATA`, and returns it. Fails with
+    /// [`Errno::PastEndOfFile`] if only holes follow.
+    /// ```rust,ignore
+    /// let start = file.seek_data(0)?;
+    /// let end = file.seek_hole(start)?;
+    /// // start..end is stored on disk, a hole or the end of the file follows
+    /// ```
+    pub fn seek_data(&mut self, offset: u64) -> IoResult<u64> {
+        self.curr_offset = self.ext2.0.read().seek_data(self.inode, offset)?;
+        Ok(self.curr_offset)
+    }
+
+    /// Moves the cursor to the first byte from `offset` on that is in a hole, or to the end of
+    /// the file, like `lseek` with `SEEK_HOLE`, and returns it.
+    pub fn seek_hole(&mut self, offset: u64) -> IoResult<u64> {
+        self.curr_offset = self.ext2.0.read().seek_hole(self.inode, offset)?;
+        Ok(self.curr_offset)
+    }
+
+    /// Truncates or extends the file to `size` bytes. Extending allocates no blocks, the new
+    /// bytes are a hole that reads as zeros. The cursor stays where it is.
+    /// ```rust,ignore
+    /// let mut file = OpenOptions::new().write(true).open("/log.txt", ext2)?;
+    /// file.set_len(1 << 20)?;
+    /// ```
+    pub fn set_len(&mut self, size: u64) -> IoResult<()> {
+        if !self.options.write {
+            return Err(Errno::AccessError);
+        }
+        self.ext2.0.write().truncate(self.inode, size)
+    }
+
+    /// Allocates the blocks of `offset..offset + len` that are holes and extends the file to
+    /// `offset + len` if it is shorter, like `posix_fallocate`. Writes to the range then can't
+    /// fail for lack of space, and files written bit by bit, like logs, stay contiguous.
+    pub fn fallocate(&mut self, offset: u64, len: u64) -> IoResult<()> {
+        if !self.options.write {
+            return Err(Errno::AccessError);
+        }
+        self.ext2.0.write().fallocate(self.inode, offset, len)
+    }
+
+    /// Locks the whole file, waiting for conflicting locks to be released.
+    ///
+    /// Locks are advisory: they only keep other lockers out, not readers or writers. Locking
+    /// again through the same `File` changes the kind of the lock.
+    /// ```rust,ignore
+    /// file.lock(LockKind::Exclusive).unwrap();
+    /// file.write(b"bananes").unwrap();
+    /// file.unlock();
+    /// ```
+    pub fn lock(&self, kind: LockKind) -> IoResult<()> {
+        self.lock_range(kind, 0..u64::MAX)
+    }
+
+    /// Locks the whole file, fails with [`Errno::Locked`] instead of waiting.
+    pub fn try_lock(&self, kind: LockKind) -> IoResult<()> {
+        self.try_lock_range(kind, 0..u64::MAX)
+    }
+
+    /// Releases all locks held through this file.
+    pub fn unlock(&self) {
+        self.unlock_range(0..u64::MAX)
+    }
+
+    /// Locks the bytes in `range`, waiting for conflicting locks to be released.
+    pub fn lock_range(&self, kind: LockKind, range: Range<u64>) -> IoResult<()> {
+        self.ext2.1.lock(self.inode, self.lock_owner, kind, range)
+    }
+
+    /// Locks the bytes in `range`, fails with [`Errno::Locked`] instead of waiting.
+    pub fn try_lock_range(&self, kind: LockKind, range: Range<u64>) -> IoResult<()> {
+        self.ext2.1.try_lock(self.inode, self.lock_owner, kind, range)
+    }
+
+    /// Releases the locks held through this file on the bytes in `range`.
+    pub fn unlock_range(&self, range: Range<u64>) {
+        self.ext2.1.unlock(self.inode, self.lock_owner, range)
+    }
+}
+
+impl<T> Drop for File<T>
+where
+    T: RWS,
+{
+    fn drop(&mut self) {
+        self.ext2.1.unlock_all(self.inode, self.lock_owner);
+        if self.options.write {
+            let _ = self.ext2.0.write().discard_reservation(self.inode);
+        }
+    }
 }
 
 impl<T> RWS for File<T>
@@ -891,7 +1393,10 @@ where
         if !self.options.write {
             return Err(Errno::AccessError);
         }
-        let mut ext2 = self.ext2.0.lock();
+        let mut ext2 = self.ext2.0.write();
+        if self.options.direct {
+            return Ok(ext2.write_direct(self.inode, &mut self.curr_offset, buf)?.0);
+        }
         Ok(ext2
             .write(self.inode, &mut self.curr_offset, buf)
             .map(|s| s.0 as u64)?)
@@ -901,15 +1406,27 @@ where
         if !self.options.read {
             return Err(Errno::AccessError);
         }
-        let mut ext2 = self.ext2.0.lock();
-        Ok(ext2.read(self.inode, &mut self.curr_offset, buf)?)
+        let ext2 = self.ext2.0.read();
+        if self.options.direct {
+            return ext2.read_direct(self.inode, &mut self.curr_offset, buf);
+        }
+        let offset = self.curr_offset;
+        let read = ext2.read(self.inode, &mut self.curr_offset, buf)?;
+        if let Some((from, len)) = self.readahead.update(offset, read) {
+            // only an optimization, the read itself succeeded
+            let _ = ext2.read_ahead(self.inode, from, len);
+        }
+        Ok(read)
     }
 
     fn write_at(&mut self, mut addr: u64, buf: &[u8]) -> IoResult<u64> {
         if !self.options.write {
             return Err(Errno::AccessError);
         }
-        let mut ext2 = self.ext2.0.lock();
+        let mut ext2 = self.ext2.0.write();
+        if self.options.direct {
+            return Ok(ext2.write_direct(self.inode, &mut addr, buf)?.0);
+        }
         Ok(ext2.write(self.inode, &mut addr, buf).map(|s| s.0)?)
     }
 
@@ -917,12 +1434,20 @@ where
         if !self.options.read {
             return Err(Errno::AccessError);
         }
-        let mut ext2 = self.ext2.0.lock();
-        Ok(ext2.read(self.inode, &mut addr, buf)?)
+        let ext2 = self.ext2.0.read();
+        if self.options.direct {
+            return ext2.read_direct(self.inode, &mut addr, buf);
+        }
+        let offset = addr;
+        let read = ext2.read(self.inode, &mut addr, buf)?;
+        if let Some((from, len)) = self.readahead.update(offset, read) {
+            let _ = ext2.read_ahead(self.inode, from, len);
+        }
+        Ok(read)
     }
 
     fn seek(&mut self, pos: u64) -> IoResult<()> {
-        let ext2 = self.ext2.0.lock();
+        let ext2 = self.ext2.0.read();
         let file_len = ext2.read_inode(self.inode)?.get_size();
         let new_curr_offset = self.curr_offset + pos;
         if new_curr_offset < 0 || new_curr_offset > file_len {
@@ -933,7 +1458,7 @@ where
     }
 
     fn seek_absolute(&mut self, pos: u64) -> IoResult<()> {
-        let ext2 = self.ext2.0.lock();
+        let ext2 = self.ext2.0.read();
         let file_len = ext2.read_inode(self.inode)?.get_size();
         if pos < 0 || pos > file_len {
             return Err(Errno::OutOfSpace);
@@ -941,4 +1466,8 @@ where
         self.curr_offset = pos;
         Ok(())
     }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.ext2.sync()
+    }
 }
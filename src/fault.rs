@@ -0,0 +1,100 @@
+//! Failures injected on purpose, to test how the file system copes with a failing disk.
+//!
+//! Built with the `fault-injection` feature, the ext2 disk layer asks `fail` before every read
+//! and write of the device, and the allocator before taking a block or an inode. Each `Point`
+//! fails once in as many tries as set with `set`, picked by a pseudo random generator that
+//! `seed` makes repeatable. Without the feature `fail` is always false, whatever is set.
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Point {
+    /// a read of the device fails with `Errno::UnknownIO`
+    Read,
+    /// a read of the device returns only half the bytes asked for
+    ShortRead,
+    /// a write to the device fails with `Errno::UnknownIO`
+    Write,
+    /// allocating a block or an inode fails with `Errno::OutOfSpace`
+    Alloc,
+}
+
+impl Point {
+    pub const ALL: [Point; 4] = [Self::Read, Self::ShortRead, Self::Write, Self::Alloc];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::ShortRead => "short-read",
+            Self::Write => "write",
+            Self::Alloc => "alloc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|point| point.name() == name)
+    }
+}
+
+static RATES: [AtomicU32; 4] = [const { AtomicU32::new(0) }; 4];
+static INJECTED: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+static STATE: AtomicU64 = AtomicU64::new(0x2545_f491_4f6c_dd1d);
+
+/// Makes `point` fail once in `one_in` tries, never if 0.
+pub fn set(point: Point, one_in: u32) {
+    RATES[point as usize].store(one_in, Ordering::Relaxed);
+}
+
+/// How often `point` fails, see `set`.
+pub fn rate(point: Point) -> u32 {
+    RATES[point as usize].load(Ordering::Relaxed)
+}
+
+/// How many failures were injected at `point` so far.
+pub fn injected(point: Point) -> u64 {
+    INJECTED[point as usize].load(Ordering::Relaxed)
+}
+
+/// Stops injecting failures and resets the counts.
+pub fn clear() {
+    for point in Point::ALL {
+        set(point, 0);
+        INJECTED[point as usize].store(0, Ordering::Relaxed);
+    }
+}
+
+/// Restarts the random generator, so the same operations fail at the same tries again.
+pub fn seed(seed: u64) {
+    // xorshift would stay at 0
+    STATE.store(seed.max(1), Ordering::Relaxed);
+}
+
+/// Whether the operation at `point` should fail this time.
+pub fn fail(point: Point) -> bool {
+    let one_in = rate(point);
+    if !cfg!(feature = "fault-injection") || one_in == 0 {
+        return false;
+    }
+    let failed = one_in == 1 || next_random() % one_in as u64 == 0;
+    if failed {
+        INJECTED[point as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    failed
+}
+
+/// xorshift64, good enough to spread failures
+fn next_random() -> u64 {
+    let mut x = STATE.load(Ordering::Relaxed);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x, Ordering::Relaxed);
+    x
+}
+
+#[test_case]
+fn test_point_names() {
+    for point in Point::ALL {
+        assert_eq!(Point::from_name(point.name()), Some(point));
+    }
+    assert_eq!(Point::from_name("seek"), None);
+}
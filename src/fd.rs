@@ -0,0 +1,257 @@
+//! File descriptors.
+//!
+//! Every task has a table of open files, indexed by file descriptor. A spawned task gets a copy
+//! of its parent's table, like after a fork. Tasks of nobody in particular start out with the
+//! console TTY as stdin, stdout and stderr. The table is created on first use and closed when
+//! the task exits.
+//!
+//! Duplicated descriptors share the open file, including its offset.
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    error::{KError, KResult},
+    ext::FileType,
+    pipe::{PipeReader, PipeWriter},
+    task::{self, TaskId},
+    tty::{self, Tty},
+    vfs,
+};
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// Files a task can have open at once.
+pub const MAX_FILES: usize = 64;
+
+// flags of `open` and `lseek`
+pub use crate::syscall_table::{
+    O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, SEEK_CUR, SEEK_DATA, SEEK_END,
+    SEEK_HOLE, SEEK_SET,
+};
+const O_ACCMODE: u64 = 3;
+
+/// An open file.
+pub enum File {
+    Tty(&'static Tty),
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    Vfs(VfsFile),
+}
+
+impl File {
+    pub fn read(&self, buf: &mut [u8]) -> KResult<usize> {
+        match self {
+            Self::Tty(tty) => tty.read(buf),
+            Self::PipeReader(reader) => reader.read(buf),
+            Self::PipeWriter(_) => Err(KError::BadFileDescriptor),
+            Self::Vfs(file) => file.read(buf),
+        }
+    }
+
+    pub fn write(&self, buf: &[u8]) -> KResult<usize> {
+        match self {
+            Self::Tty(tty) => Ok(tty.write(buf)),
+            Self::PipeReader(_) => Err(KError::BadFileDescriptor),
+            Self::PipeWriter(writer) => writer.write(buf),
+            Self::Vfs(file) => file.write(buf),
+        }
+    }
+
+    /// Moves the offset of a file, returns the new offset. Pipes and TTYs can't seek.
+    pub fn seek(&self, offset: i64, whence: u64) -> KResult<u64> {
+        match self {
+            Self::Vfs(file) => file.seek(offset, whence),
+            _ => Err(KError::IllegalSeek),
+        }
+    }
+}
+
+/// A file opened through the VFS.
+///
+/// File systems read and write whole files, so every read fetches the file and every write
+/// replaces it. Fine for the small files this kernel deals with.
+pub struct VfsFile {
+    path: String,
+    flags: u64,
+    offset: Mutex<u64>,
+}
+
+impl VfsFile {
+    /// Opens `path` with the `O_*` flags in `flags`.
+    pub fn open(path: &str, flags: u64) -> KResult<Self> {
+        let path = vfs::normalize(path)?;
+        let writable = flags & O_ACCMODE != O_RDONLY;
+        match vfs::metadata(&path) {
+            Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => {
+                return Err(KError::AlreadyExists)
+            }
+            Ok(metadata) if metadata.file_type == FileType::Directory && writable => {
+                return Err(KError::IsDirectory)
+            }
+            Ok(_) if flags & O_TRUNC != 0 && writable => vfs::write(&path, &[])?,
+            Ok(_) => {}
+            Err(e) => match KError::from(e) {
+                KError::NotFound if flags & O_CREAT != 0 => vfs::write(&path, &[])?,
+                e => return Err(e),
+            },
+        }
+        Ok(Self {
+            path,
+            flags,
+            offset: Mutex::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn read(&self, buf: &mut [u8]) -> KResult<usize> {
+        if !self.is_readable() {
+            return Err(KError::BadFileDescriptor);
+        }
+        let data = vfs::read(&self.path)?;
+        without_interrupts(|| {
+            let mut offset = self.offset.lock();
+            let start = (*offset as usize).min(data.len());
+            let read = buf.len().min(data.len() - start);
+            buf[..read].copy_from_slice(&data[start..start + read]);
+            *offset += read as u64;
+            Ok(read)
+        })
+    }
+
+    fn write(&self, buf: &[u8]) -> KResult<usize> {
+        if self.flags & O_ACCMODE == O_RDONLY {
+            return Err(KError::BadFileDescriptor);
+        }
+        let mut data = vfs::read(&self.path)?;
+        let start = if self.flags & O_APPEND != 0 {
+            data.len()
+        } else {
+            without_interrupts(|| *self.offset.lock()) as usize
+        };
+        // writing past the end leaves a hole of zeros
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        vfs::write(&self.path, &data)?;
+        without_interrupts(|| *self.offset.lock() = (start + buf.len()) as u64);
+        Ok(buf.len())
+    }
+
+    fn seek(&self, offset: i64, whence: u64) -> KResult<u64> {
+        let base = match whence {
+            SEEK_SET | SEEK_DATA | SEEK_HOLE => 0,
+            SEEK_CUR => without_interrupts(|| *self.offset.lock()),
+            SEEK_END => vfs::metadata(&self.path)?.size,
+            _ => return Err(KError::InvalidArgument),
+        };
+        let new = base
+            .checked_add_signed(offset)
+            .ok_or(KError::InvalidArgument)?;
+        // data and holes are looked for from the offset on
+        let new = match whence {
+            SEEK_DATA => vfs::seek_data(&self.path, new)?,
+            SEEK_HOLE => vfs::seek_hole(&self.path, new)?,
+            _ => new,
+        };
+        without_interrupts(|| *self.offset.lock() = new);
+        Ok(new)
+    }
+}
+
+type Table = Vec<Option<Arc<File>>>;
+
+static TABLES: Mutex<BTreeMap<TaskId, Table>> = Mutex::new(BTreeMap::new());
+
+fn default_table() -> Table {
+    let console = Arc::new(File::Tty(&tty::CONSOLE));
+    vec![Some(console.clone()), Some(console.clone()), Some(console)]
+}
+
+/// Runs `f` on the file table of `task`.
+fn with_table<T>(task: TaskId, f: impl FnOnce(&mut Table) -> T) -> T {
+    without_interrupts(|| f(TABLES.lock().entry(task).or_insert_with(default_table)))
+}
+
+/// Returns the file open as `fd` in the current task.
+pub fn get(fd: usize) -> KResult<Arc<File>> {
+    with_table(task::current_id(), |table| table.get(fd).cloned().flatten())
+        .ok_or(KError::BadFileDescriptor)
+}
+
+/// Opens `file` in the current task as the lowest free file descriptor.
+pub fn install(file: File) -> KResult<usize> {
+    let file = Arc::new(file);
+    with_table(task::current_id(), |table| {
+        match table.iter().position(Option::is_none) {
+            Some(fd) => {
+                table[fd] = Some(file);
+                Ok(fd)
+            }
+            None if table.len() < MAX_FILES => {
+                table.push(Some(file));
+                Ok(table.len() - 1)
+            }
+            None => Err(KError::TooManyFiles),
+        }
+    })
+}
+
+/// Opens `file` as `fd` in `task`, closing what was open there. Used to set up the stdio of a
+/// task before it runs.
+pub fn set(task: TaskId, fd: usize, file: Arc<File>) -> KResult<()> {
+    if fd >= MAX_FILES {
+        return Err(KError::BadFileDescriptor);
+    }
+    let old = with_table(task, |table| {
+        if table.len() <= fd {
+            table.resize(fd + 1, None);
+        }
+        table[fd].replace(file)
+    });
+    // dropping the last reference to a pipe end wakes the other side, not under the lock
+    drop(old);
+    Ok(())
+}
+
+/// Makes `new` refer to the same open file as `old` in the current task, closing what was
+/// open as `new`.
+pub fn dup2(old: usize, new: usize) -> KResult<usize> {
+    let file = get(old)?;
+    if old != new {
+        set(task::current_id(), new, file)?;
+    }
+    Ok(new)
+}
+
+/// Closes `fd` in the current task.
+pub fn close(fd: usize) -> KResult<()> {
+    let file = with_table(task::current_id(), |table| table.get_mut(fd)?.take());
+    file.map(drop).ok_or(KError::BadFileDescriptor)
+}
+
+/// Gives `child` a copy of the file table of `parent`. Called while spawning `child`.
+pub fn inherit(parent: TaskId, child: TaskId) {
+    without_interrupts(|| {
+        let mut tables = TABLES.lock();
+        if let Some(table) = tables.get(&parent).cloned() {
+            tables.insert(child, table);
+        }
+    });
+}
+
+/// Closes every file of an exiting task.
+pub fn release_task(task: TaskId) {
+    let table = without_interrupts(|| TABLES.lock().remove(&task));
+    drop(table);
+}
@@ -0,0 +1,297 @@
+//! Moving files between the host and the guest through a shared disk.
+//!
+//! During development a raw disk image is attached next to the system disk, see the
+//! `fileshare` command and `tools/fileshare.py`, which packs a host directory into such an
+//! image and unpacks one. The format is simple enough to be written on either side:
+//!
+//! - a header sector: the magic `SKYSHARE`, a version and the number of records, all little
+//!   endian `u32`s after the magic
+//! - records, each starting on a sector boundary: the magic `SREC`, the kind (1 file,
+//!   2 directory) as a `u32`, the length of the path as a `u32`, the CRC-32 of the data as a
+//!   `u32` and the length of the data as a `u64`, followed by the path and the data
+//!
+//! Paths are relative, separated by `/` and never contain `.` or `..`. Directories come before
+//! what's inside them.
+use alloc::{format, string::String, vec, vec::Vec};
+
+use crate::{
+    archive, crypto,
+    ext::{Errno, FileType, RWS},
+    vfs::{self, VfsResult},
+};
+
+pub const MAGIC: &[u8; 8] = b"SKYSHARE";
+const RECORD_MAGIC: &[u8; 4] = b"SREC";
+pub const VERSION: u32 = 1;
+const SECTOR_SIZE: u64 = 512;
+const RECORD_HEADER_SIZE: usize = 24;
+/// Longest path accepted, anything longer is a broken record.
+const MAX_PATH: usize = 4096;
+
+const KIND_FILE: u32 = 1;
+const KIND_DIRECTORY: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// relative path, like `etc/system.conf`
+    pub path: String,
+    pub kind: Kind,
+    pub size: u64,
+    crc: u32,
+    /// position of the data on the disk
+    offset: u64,
+}
+
+fn read_exact<R: RWS>(dev: &mut R, mut addr: u64, mut buf: &mut [u8]) -> VfsResult<()> {
+    while !buf.is_empty() {
+        let read = dev.read_at(addr, buf)? as usize;
+        if read == 0 {
+            return Err(Errno::InvalidFileImage);
+        }
+        addr += read as u64;
+        buf = &mut buf[read..];
+    }
+    Ok(())
+}
+
+fn align(n: u64) -> u64 {
+    n.div_ceil(SECTOR_SIZE) * SECTOR_SIZE
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Checks that `path` is relative and stays below where it's put.
+fn valid_path(path: &str) -> bool {
+    !path.is_empty()
+        && path
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+/// Returns whether `dev` holds a share, without reading further than the header.
+pub fn is_share<R: RWS>(dev: &mut R) -> bool {
+    let mut magic = [0u8; 8];
+    read_exact(dev, 0, &mut magic).is_ok() && &magic == MAGIC
+}
+
+/// A share on a disk, read record by record.
+pub struct Share<R: RWS> {
+    dev: R,
+    remaining: u32,
+    pos: u64,
+}
+
+impl<R: RWS> Share<R> {
+    pub fn open(mut dev: R) -> VfsResult<Self> {
+        let mut header = [0u8; 16];
+        read_exact(&mut dev, 0, &mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(Errno::InvalidFileImage);
+        }
+        if u32_at(&header, 8) != VERSION {
+            return Err(Errno::Unsupported);
+        }
+        Ok(Self {
+            dev,
+            remaining: u32_at(&header, 12),
+            pos: SECTOR_SIZE,
+        })
+    }
+
+    /// Returns the next record, `None` after the last one.
+    pub fn next_record(&mut self) -> VfsResult<Option<Record>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        read_exact(&mut self.dev, self.pos, &mut header)?;
+        let path_len = u32_at(&header, 8) as usize;
+        if &header[..4] != RECORD_MAGIC || path_len > MAX_PATH {
+            return Err(Errno::InvalidFileImage);
+        }
+        let kind = match u32_at(&header, 4) {
+            KIND_FILE => Kind::File,
+            KIND_DIRECTORY => Kind::Directory,
+            _ => return Err(Errno::InvalidFileImage),
+        };
+        let size = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let mut path = vec![0u8; path_len];
+        read_exact(
+            &mut self.dev,
+            self.pos + RECORD_HEADER_SIZE as u64,
+            &mut path,
+        )?;
+        let path = String::from_utf8(path).map_err(|_| Errno::InvalidFileImage)?;
+        if !valid_path(&path) {
+            return Err(Errno::InvalidFileImage);
+        }
+
+        let offset = self.pos + (RECORD_HEADER_SIZE + path_len) as u64;
+        self.pos = align(offset + size);
+        self.remaining -= 1;
+        Ok(Some(Record {
+            path,
+            kind,
+            size,
+            crc: u32_at(&header, 12),
+            offset,
+        }))
+    }
+
+    /// Reads the data of a file record, checking it against its CRC.
+    pub fn read(&mut self, record: &Record) -> VfsResult<Vec<u8>> {
+        let mut data = vec![0u8; record.size as usize];
+        read_exact(&mut self.dev, record.offset, &mut data)?;
+        if crypto::crc32(&data) != record.crc {
+            return Err(Errno::InvalidFileImage);
+        }
+        Ok(data)
+    }
+
+    /// Returns all remaining records.
+    pub fn records(&mut self) -> VfsResult<Vec<Record>> {
+        let mut records = Vec::new();
+        while let Some(record) = self.next_record()? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
+
+/// Builds a share image in memory.
+pub struct Builder {
+    image: Vec<u8>,
+    count: u32,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        let mut image = vec![0u8; SECTOR_SIZE as usize];
+        image[..8].copy_from_slice(MAGIC);
+        image[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        Self { image, count: 0 }
+    }
+
+    /// Appends a record. Fails for paths that aren't relative or leave their directory.
+    pub fn add(&mut self, path: &str, kind: Kind, data: &[u8]) -> VfsResult<()> {
+        if !valid_path(path) || path.len() > MAX_PATH {
+            return Err(Errno::IllegalCharacter);
+        }
+        let kind = match kind {
+            Kind::File => KIND_FILE,
+            Kind::Directory => KIND_DIRECTORY,
+        };
+        let image = &mut self.image;
+        image.extend_from_slice(RECORD_MAGIC);
+        image.extend_from_slice(&kind.to_le_bytes());
+        image.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        image.extend_from_slice(&crypto::crc32(data).to_le_bytes());
+        image.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        image.extend_from_slice(path.as_bytes());
+        image.extend_from_slice(data);
+        image.resize(align(image.len() as u64) as usize, 0);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Adds the file or directory tree at the VFS path `path` as `name`.
+    pub fn add_tree(&mut self, path: &str, name: &str) -> VfsResult<()> {
+        if vfs::metadata(path)?.file_type != FileType::Directory {
+            return self.add(name, Kind::File, &vfs::read(path)?);
+        }
+        self.add(name, Kind::Directory, &[])?;
+        for entry in vfs::read_dir(path)? {
+            let child = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+            self.add_tree(&child, &format!("{}/{}", name, entry.name))?;
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.image[12..16].copy_from_slice(&self.count.to_le_bytes());
+        self.image
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes a share image to the start of `dev`.
+pub fn write<R: RWS>(dev: &mut R, image: &[u8]) -> VfsResult<()> {
+    dev.write_at(0, image)?;
+    dev.flush()
+}
+
+/// Copies every record of `share` below the VFS directory `dest`, returns how many.
+pub fn import<R: RWS>(share: &mut Share<R>, dest: &str) -> VfsResult<usize> {
+    let (fs, dest) = vfs::resolve(dest)?;
+    let mut count = 0;
+    while let Some(record) = share.next_record()? {
+        let path = vfs::normalize(&format!("{}/{}", dest, record.path))?;
+        match record.kind {
+            Kind::Directory => archive::create_dir_all(&*fs, &path)?,
+            Kind::File => {
+                archive::create_dir_all(&*fs, vfs::split_parent(&path).0)?;
+                fs.write(&path, &share.read(&record)?)?;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[test_case]
+fn test_round_trip() {
+    use crate::drivers::ramdisk::RamDisk;
+
+    let mut builder = Builder::new();
+    builder.add("etc", Kind::Directory, &[]).unwrap();
+    builder
+        .add("etc/motd", Kind::File, b"hello from the host\n")
+        .unwrap();
+    assert!(matches!(
+        builder.add("../escape", Kind::File, b""),
+        Err(Errno::IllegalCharacter)
+    ));
+    let image = builder.finish();
+    assert_eq!(image.len(), 3 * SECTOR_SIZE as usize);
+
+    assert!(is_share(&mut RamDisk::new(&image)));
+    let mut share = Share::open(RamDisk::new(&image)).unwrap();
+    let records = share.records().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        (records[0].path.as_str(), records[0].kind),
+        ("etc", Kind::Directory)
+    );
+    assert_eq!(
+        (records[1].path.as_str(), records[1].size),
+        ("etc/motd", 20)
+    );
+    assert_eq!(share.read(&records[1]).unwrap(), b"hello from the host\n");
+
+    let mut corrupt = image.clone();
+    // the first byte of the file's data
+    corrupt[2 * SECTOR_SIZE as usize + RECORD_HEADER_SIZE + 8] ^= 1;
+    let mut share = Share::open(RamDisk::new(&corrupt)).unwrap();
+    let records = share.records().unwrap();
+    assert!(matches!(
+        share.read(&records[1]),
+        Err(Errno::InvalidFileImage)
+    ));
+}
@@ -0,0 +1,363 @@
+//! Console fonts and code page translation.
+//!
+//! Fonts are loaded into the VGA font memory (plane 2). PSF1 and PSF2 fonts as used by the
+//! Linux console are supported, as well as raw fonts of 256 glyphs. Fonts with more than 256
+//! glyphs switch the VGA into 512 glyph mode: bit 3 of the attribute byte selects the second
+//! half of the glyphs instead of the bright colors, so only the 8 dark colors are left.
+//!
+//! Which glyph a character is displayed with comes from the font's unicode table or a
+//! translation table loaded with it, characters not in there fall back to code page 437.
+//! Translation tables are text files in the format of `setfont -u`, one glyph per line:
+//! `0x80 U+00C7 U+0106`, `#` starts a comment.
+//!
+//! No font is built in: the system starts with the font of the VGA BIOS, and others are loaded
+//! from `/usr/share/consolefonts` on the root file system, see `path`.
+use alloc::{collections::BTreeMap, format, string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::RwLock;
+use x86_64::{instructions::interrupts::without_interrupts, instructions::port::Port, PhysAddr};
+
+use crate::{
+    error::KResult,
+    mem, vfs,
+    vga_buffer::{transform_char, WRITER},
+};
+
+pub const DIR: &str = "/usr/share/consolefonts";
+
+/// VGA text mode character cells are 16 scan lines high, each glyph slot is 32 bytes.
+const CELL_HEIGHT: usize = 16;
+const GLYPH_SLOT: usize = 32;
+/// Where the second set of 256 glyphs goes in font memory (character map 1).
+const SECOND_MAP: usize = 0x4000;
+const FONT_MEMORY: u64 = 0xa0000;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// Not a PSF font and not the size of a raw font.
+    BadFormat,
+    /// The glyphs are wider than 8 or higher than 16 pixels, or there are more than 512.
+    Unsupported,
+    /// A line of a translation table couldn't be parsed, starting at 1.
+    BadMap(usize),
+    /// The font memory isn't mapped.
+    NoDevice,
+}
+
+pub struct Font {
+    /// bitmaps of `height` bytes per glyph
+    glyphs: Vec<u8>,
+    count: usize,
+    height: usize,
+    map: BTreeMap<char, u16>,
+}
+
+impl Font {
+    pub fn parse(data: &[u8]) -> Result<Self, FontError> {
+        if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else if !data.is_empty() && data.len() % 256 == 0 {
+            Self::new(data.to_vec(), 256, data.len() / 256)
+        } else {
+            Err(FontError::BadFormat)
+        }
+    }
+
+    fn new(glyphs: Vec<u8>, count: usize, height: usize) -> Result<Self, FontError> {
+        if count > 512 || height == 0 || height > CELL_HEIGHT {
+            return Err(FontError::Unsupported);
+        }
+        Ok(Self {
+            glyphs,
+            count,
+            height,
+            map: BTreeMap::new(),
+        })
+    }
+
+    fn parse_psf1(data: &[u8]) -> Result<Self, FontError> {
+        let (mode, height) = match data.get(2..4) {
+            Some(&[mode, height]) => (mode, height as usize),
+            _ => return Err(FontError::BadFormat),
+        };
+        let count = if mode & 0x01 != 0 { 512 } else { 256 };
+        let end = 4 + count * height;
+        let glyphs = data.get(4..end).ok_or(FontError::BadFormat)?;
+        let mut font = Self::new(glyphs.to_vec(), count, height)?;
+        if mode & 0x06 != 0 {
+            // one list of UCS-2 characters per glyph ending with 0xffff, sequences of
+            // combining characters after 0xfffe aren't used
+            let table = data[end..]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]));
+            let (mut glyph, mut in_sequence) = (0, false);
+            for c in table {
+                match c {
+                    0xffff => (glyph, in_sequence) = (glyph + 1, false),
+                    0xfffe => in_sequence = true,
+                    _ if in_sequence => {}
+                    _ => font.add(char::from_u32(c as u32), glyph),
+                }
+            }
+        }
+        Ok(font)
+    }
+
+    fn parse_psf2(data: &[u8]) -> Result<Self, FontError> {
+        let field = |i: usize| {
+            data.get(4 + i * 4..8 + i * 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                .ok_or(FontError::BadFormat)
+        };
+        let (header_size, flags, count) = (field(1)?, field(2)?, field(3)?);
+        let (glyph_size, height, width) = (field(4)?, field(5)?, field(6)?);
+        if width > 8 || glyph_size != height {
+            return Err(FontError::Unsupported);
+        }
+        let end = header_size + count * glyph_size;
+        let glyphs = data.get(header_size..end).ok_or(FontError::BadFormat)?;
+        let mut font = Self::new(glyphs.to_vec(), count, height)?;
+        if flags & 0x01 != 0 {
+            // UTF-8 per glyph ending with 0xff, sequences after 0xfe aren't used
+            for (glyph, entry) in data[end..].split(|b| *b == 0xff).enumerate() {
+                let single = entry.split(|b| *b == 0xfe).next().unwrap_or(&[]);
+                for c in core::str::from_utf8(single).unwrap_or("").chars() {
+                    font.add(Some(c), glyph as u16);
+                }
+            }
+        }
+        Ok(font)
+    }
+
+    fn add(&mut self, c: Option<char>, glyph: u16) {
+        if let Some(c) = c {
+            if (glyph as usize) < self.count {
+                self.map.entry(c).or_insert(glyph);
+            }
+        }
+    }
+}
+
+/// Returns the file of the font called `name`. Names without a `/` are files in `DIR`, where
+/// `.psf` can be left out.
+pub fn path(name: &str) -> String {
+    if name.contains('/') {
+        String::from(name)
+    } else if name.contains('.') {
+        format!("{DIR}/{name}")
+    } else {
+        format!("{DIR}/{name}.psf")
+    }
+}
+
+/// Parses a translation table, see the module documentation.
+pub fn parse_map(text: &str) -> Result<BTreeMap<char, u16>, FontError> {
+    let mut map = BTreeMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut words = line.split_whitespace();
+        let Some(glyph) = words.next() else {
+            continue;
+        };
+        let error = FontError::BadMap(i + 1);
+        let glyph = parse_number(glyph).filter(|g| *g < 512).ok_or(error)?;
+        for word in words {
+            let c = word
+                .strip_prefix("U+")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32)
+                .ok_or(error)?;
+            map.insert(c, glyph as u16);
+        }
+    }
+    Ok(map)
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Characters with a glyph other than their code page 437 one, `None` for plain CP437.
+static MAP: RwLock<Option<BTreeMap<char, u16>>> = RwLock::new(None);
+static MODE_512: AtomicBool = AtomicBool::new(false);
+/// The font the VGA BIOS loaded, saved before it is first replaced.
+static DEFAULT: RwLock<Option<Vec<u8>>> = RwLock::new(None);
+
+/// Returns the glyph `c` is displayed with, above 255 only in 512 glyph mode.
+///
+/// Never waits, so it can be used while printing from anywhere.
+pub fn glyph(c: char) -> u16 {
+    if matches!(c, '\n' | '\t' | '\x08') {
+        return c as u16;
+    }
+    if let Some(map) = MAP.try_read() {
+        if let Some(glyph) = map.as_ref().and_then(|map| map.get(&c)) {
+            return *glyph;
+        }
+    }
+    transform_char(c) as u16
+}
+
+/// Whether bit 3 of the attribute selects the glyph set instead of bright colors.
+pub fn is_512() -> bool {
+    MODE_512.load(Ordering::Relaxed)
+}
+
+/// Loads `font` and uses its unicode table, or `map` if given.
+pub fn load(font: &Font, map: Option<BTreeMap<char, u16>>) -> Result<(), FontError> {
+    let mut memory = vec![0; SECOND_MAP + 256 * GLYPH_SLOT];
+    for (i, glyph) in font.glyphs.chunks_exact(font.height).enumerate() {
+        let slot = if i < 256 {
+            i * GLYPH_SLOT
+        } else {
+            SECOND_MAP + (i - 256) * GLYPH_SLOT
+        };
+        memory[slot..slot + font.height].copy_from_slice(glyph);
+    }
+    let len = if font.count > 256 {
+        memory.len()
+    } else {
+        256 * GLYPH_SLOT
+    };
+    let map = map.unwrap_or_else(|| font.map.clone());
+    install(
+        &memory[..len],
+        font.count > 256,
+        (!map.is_empty()).then_some(map),
+    )
+}
+
+/// Loads the font file at `path` with its own unicode table.
+pub fn load_file(path: &str) -> KResult<()> {
+    let font = Font::parse(&vfs::read(path)?)?;
+    Ok(load(&font, None)?)
+}
+
+/// Replaces only the translation table, `None` goes back to code page 437.
+pub fn set_map(map: Option<BTreeMap<char, u16>>) {
+    without_interrupts(|| *MAP.write() = map);
+}
+
+/// Puts back the font the system booted with and code page 437.
+pub fn reset() -> Result<(), FontError> {
+    let default = without_interrupts(|| DEFAULT.read().clone());
+    match default {
+        Some(default) => install(&default, false, None),
+        None => {
+            set_map(None);
+            Ok(())
+        }
+    }
+}
+
+/// Writes `memory` to the start of font memory and switches the glyph mode.
+fn install(
+    memory: &[u8],
+    mode_512: bool,
+    map: Option<BTreeMap<char, u16>>,
+) -> Result<(), FontError> {
+    let base = mem::phys_to_mapped_virt(PhysAddr::new(FONT_MEMORY))
+        .ok_or(FontError::NoDevice)?
+        .as_mut_ptr::<u8>();
+    without_interrupts(|| {
+        // nothing may print while the text buffer is unmapped
+        let _writer = WRITER.lock();
+        unsafe {
+            with_font_memory(|| {
+                let mut default = DEFAULT.write();
+                if default.is_none() {
+                    let saved = (0..256 * GLYPH_SLOT).map(|i| base.add(i).read_volatile());
+                    *default = Some(saved.collect());
+                }
+                for (i, byte) in memory.iter().enumerate() {
+                    base.add(i).write_volatile(*byte);
+                }
+            });
+            set_512(mode_512);
+        }
+        MODE_512.store(mode_512, Ordering::Relaxed);
+        *MAP.write() = map;
+    });
+    Ok(())
+}
+
+fn write_register(index_port: u16, index: u8, value: u8) {
+    unsafe {
+        Port::new(index_port).write(index);
+        Port::new(index_port + 1).write(value);
+    }
+}
+
+/// Maps plane 2 at 0xa0000 for `f`, then goes back to text mode.
+///
+/// Safety: nothing else may access video memory meanwhile.
+unsafe fn with_font_memory(f: impl FnOnce()) {
+    const SEQUENCER: u16 = 0x3c4;
+    const GRAPHICS: u16 = 0x3ce;
+
+    write_register(SEQUENCER, 0x00, 0x01); // synchronous reset
+    write_register(SEQUENCER, 0x02, 0x04); // write plane 2 only
+    write_register(SEQUENCER, 0x04, 0x07); // sequential addressing
+    write_register(SEQUENCER, 0x00, 0x03);
+    write_register(GRAPHICS, 0x04, 0x02); // read plane 2
+    write_register(GRAPHICS, 0x05, 0x00); // no odd/even
+    write_register(GRAPHICS, 0x06, 0x04); // 64K at 0xa0000
+    f();
+    write_register(SEQUENCER, 0x00, 0x01);
+    write_register(SEQUENCER, 0x02, 0x03);
+    write_register(SEQUENCER, 0x04, 0x03);
+    write_register(SEQUENCER, 0x00, 0x03);
+    write_register(GRAPHICS, 0x04, 0x00);
+    write_register(GRAPHICS, 0x05, 0x10);
+    write_register(GRAPHICS, 0x06, 0x0e); // 32K at 0xb8000
+}
+
+/// Lets attribute bit 3 select character map 1 instead of the bright colors, or undoes that.
+unsafe fn set_512(enabled: bool) {
+    // map A (bit 3 set) and map B (bit 3 clear)
+    write_register(0x3c4, 0x03, if enabled { 0x01 } else { 0x00 });
+    // reading the input status register resets the attribute controller to index mode
+    let _: u8 = Port::new(0x3da).read();
+    let mut attribute = Port::<u8>::new(0x3c0);
+    // color plane enable, 0x20 keeps the display on
+    attribute.write(0x12 | 0x20);
+    attribute.write(if enabled { 0x07 } else { 0x0f });
+}
+
+#[test_case]
+fn test_parse_psf2_unicode_table() {
+    let mut data = Vec::from(PSF2_MAGIC);
+    for field in [0u32, 32, 1, 2, 16, 16, 8] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    data.extend_from_slice(&[0xaa; 32]);
+    for (text, end) in [("A\u{391}", 0xff), ("é", 0xfe), ("e\u{301}", 0xff)] {
+        data.extend_from_slice(text.as_bytes());
+        data.push(end);
+    }
+    let font = Font::parse(&data).unwrap();
+    assert_eq!((font.count, font.height), (2, 16));
+    assert_eq!(font.map.get(&'Α'), Some(&0));
+    assert_eq!(font.map.get(&'é'), Some(&1));
+    assert_eq!(font.map.get(&'e'), None);
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse_map() {
+    let map = parse_map("# comment\n0x80 U+00C7 U+0106\n\n200 U+2500 # line\n").unwrap();
+    assert_eq!(map.get(&'Ç'), Some(&0x80));
+    assert_eq!(map.get(&'Ć'), Some(&0x80));
+    assert_eq!(map.get(&'─'), Some(&200));
+    assert_eq!(parse_map("0x80 C7"), Err(FontError::BadMap(1)));
+    assert_eq!(path("lat9w-16"), "/usr/share/consolefonts/lat9w-16.psf");
+    assert_eq!(path("lat9w-16.psfu"), "/usr/share/consolefonts/lat9w-16.psfu");
+    assert_eq!(path("/fonts/x.psf"), "/fonts/x.psf");
+}
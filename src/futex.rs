@@ -0,0 +1,129 @@
+//! Futexes, waiting on a 32 bit word in memory.
+//!
+//! `wait` sleeps as long as the word still holds the expected value, `wake` wakes tasks waiting
+//! on a word. Locks and condition variables can be built on top without spinning: only the
+//! contended case makes a system call.
+//!
+//! Every address somebody waits on has a bucket with a `WaitQueue`. Waking hands out tokens,
+//! one per woken task, so a wake right between a waiter checking the word and going to sleep
+//! isn't lost. Like on Linux, waiters can wake up spuriously and have to check the word again.
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    error::{KError, KResult},
+    sync::WaitQueue,
+    timer,
+};
+
+pub use crate::syscall_table::{FUTEX_WAIT, FUTEX_WAKE};
+
+#[derive(Default)]
+struct Counts {
+    /// tasks waiting on the address
+    waiting: usize,
+    /// wake-ups not picked up by a waiter yet
+    tokens: usize,
+}
+
+struct Bucket {
+    counts: Mutex<Counts>,
+    queue: WaitQueue,
+}
+
+static BUCKETS: Mutex<BTreeMap<u64, Arc<Bucket>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the word at `addr`. Fails for null and unaligned addresses.
+fn word(addr: u64) -> KResult<&'static AtomicU32> {
+    if addr == 0 {
+        return Err(KError::BadAddress);
+    }
+    if addr % 4 != 0 {
+        return Err(KError::InvalidArgument);
+    }
+    Ok(unsafe { &*(addr as *const AtomicU32) })
+}
+
+/// Blocks while the word at `addr` holds `val`, until woken by `wake` or `timeout` elapsed.
+/// Fails with `WouldBlock` if the word holds something else and with `TimedOut` on timeout.
+pub fn wait(addr: u64, val: u32, timeout: Option<Duration>) -> KResult<()> {
+    let word = word(addr)?;
+    // registering first makes a wake after the check below hand out a token for us
+    let bucket = without_interrupts(|| {
+        let mut buckets = BUCKETS.lock();
+        let bucket = buckets.entry(addr).or_insert_with(|| {
+            Arc::new(Bucket {
+                counts: Mutex::new(Counts::default()),
+                queue: WaitQueue::new(),
+            })
+        });
+        bucket.counts.lock().waiting += 1;
+        bucket.clone()
+    });
+
+    let result = if word.load(Ordering::SeqCst) != val {
+        Err(KError::WouldBlock)
+    } else {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let timer = timeout.map(|timeout| {
+            let (bucket, timed_out) = (bucket.clone(), timed_out.clone());
+            timer::schedule_in(timeout, move || {
+                timed_out.store(true, Ordering::Release);
+                bucket.queue.wake_all();
+            })
+        });
+        let mut woken = false;
+        bucket.queue.wait_until(|| {
+            let mut counts = bucket.counts.lock();
+            if counts.tokens > 0 {
+                counts.tokens -= 1;
+                woken = true;
+            }
+            woken || timed_out.load(Ordering::Acquire)
+        });
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
+        if woken {
+            Ok(())
+        } else {
+            Err(KError::TimedOut)
+        }
+    };
+
+    without_interrupts(|| {
+        let mut buckets = BUCKETS.lock();
+        let mut counts = bucket.counts.lock();
+        counts.waiting -= 1;
+        if counts.waiting == 0 {
+            counts.tokens = 0;
+            buckets.remove(&addr);
+        }
+    });
+    result
+}
+
+/// Wakes up to `count` tasks waiting on `addr`. Returns how many were woken.
+pub fn wake(addr: u64, count: usize) -> KResult<usize> {
+    word(addr)?;
+    let woken = without_interrupts(|| {
+        let buckets = BUCKETS.lock();
+        let bucket = buckets.get(&addr)?;
+        let mut counts = bucket.counts.lock();
+        let woken = count.min(counts.waiting - counts.tokens);
+        counts.tokens += woken;
+        Some((bucket.clone(), woken))
+    });
+    match woken {
+        Some((bucket, woken)) => {
+            if woken > 0 {
+                bucket.queue.wake_all();
+            }
+            Ok(woken)
+        }
+        None => Ok(0),
+    }
+}
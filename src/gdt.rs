@@ -1,47 +1,190 @@
+//! Global descriptor table and task state segment.
+//!
+//! Every cpu needs its own GDT and TSS, the TSS holds the stacks the cpu switches to and is
+//! marked busy once loaded. All GDTs have the same layout, so the selectors are the same on
+//! every cpu: kernel code and data, then user data and code in the order `sysret` expects,
+//! then the TSS. The boot cpu uses `init` with statically allocated stacks, the SMP code
+//! builds the tables of the other cpus with `alloc_cpu_tables` and loads them there.
+use alloc::{boxed::Box, vec};
+use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
-use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
-use lazy_static::lazy_static;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
+/// Size of every interrupt stack and of the stack for interrupts arriving in ring 3.
+const STACK_SIZE: usize = 4096 * 5;
+
+/// The selectors of every GDT built here.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+pub const SELECTORS: Selectors = Selectors {
+    kernel_code: SegmentSelector::new(1, PrivilegeLevel::Ring0),
+    kernel_data: SegmentSelector::new(2, PrivilegeLevel::Ring0),
+    user_data: SegmentSelector::new(3, PrivilegeLevel::Ring3),
+    user_code: SegmentSelector::new(4, PrivilegeLevel::Ring3),
+    tss: SegmentSelector::new(5, PrivilegeLevel::Ring0),
+};
+
+/// The tops of the stacks of a cpu's TSS.
+#[derive(Debug, Clone, Copy)]
+pub struct Stacks {
+    pub double_fault: VirtAddr,
+    pub nmi: VirtAddr,
+    pub machine_check: VirtAddr,
+    /// where interrupts and system calls from ring 3 start
+    pub kernel: VirtAddr,
+}
+
+impl Stacks {
+    /// Allocates the stacks on the heap. They are never freed.
+    pub fn alloc() -> Self {
+        let stack = || {
+            let stack = Box::leak(vec![0u8; STACK_SIZE].into_boxed_slice());
+            VirtAddr::from_ptr(stack.as_ptr()) + STACK_SIZE
         };
-        tss
-    };
+        Self {
+            double_fault: stack(),
+            nmi: stack(),
+            machine_check: stack(),
+            kernel: stack(),
+        }
+    }
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+/// A TSS with `stacks`.
+pub fn new_tss(stacks: &Stacks) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stacks.double_fault;
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stacks.nmi;
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = stacks.machine_check;
+    tss.privilege_stack_table[0] = stacks.kernel;
+    tss
+}
+
+/// The GDT and TSS of a cpu.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    tss: &'static TaskStateSegment,
+}
+
+impl CpuTables {
+    /// A GDT with the layout of `SELECTORS`, pointing at `tss`.
+    pub fn new(tss: &'static TaskStateSegment) -> Self {
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        debug_assert_eq!(
+            [kernel_code, kernel_data, user_data, user_code, tss_selector],
+            [
+                SELECTORS.kernel_code,
+                SELECTORS.kernel_data,
+                SELECTORS.user_data,
+                SELECTORS.user_code,
+                SELECTORS.tss,
+            ]
+        );
+        Self { gdt, tss }
+    }
 
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+    pub fn tss(&self) -> &'static TaskStateSegment {
+        self.tss
+    }
+
+    /// Loads the GDT and TSS on the running cpu and points the segment registers at them.
+    pub fn load(&'static self) {
+        use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+        use x86_64::instructions::tables::load_tss;
+
+        self.gdt.load();
+        unsafe {
+            CS::set_reg(SELECTORS.kernel_code);
+            SS::set_reg(SELECTORS.kernel_data);
+            DS::set_reg(SELECTORS.kernel_data);
+            ES::set_reg(SELECTORS.kernel_data);
+            load_tss(SELECTORS.tss);
+        }
+    }
+}
+
+/// Builds the tables of another cpu, with stacks from the heap, for the SMP code to `load`
+/// on that cpu. They live as long as the kernel.
+pub fn alloc_cpu_tables() -> &'static CpuTables {
+    let tss = Box::leak(Box::new(new_tss(&Stacks::alloc())));
+    Box::leak(Box::new(CpuTables::new(tss)))
+}
+
+/// Returns the top of a static stack, for the boot cpu which sets up its tables before the
+/// heap exists.
+macro_rules! static_stack {
+    () => {{
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        VirtAddr::from_ptr(core::ptr::addr_of!(STACK)) + STACK_SIZE
+    }};
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+lazy_static! {
+    static ref BOOT_TSS: TaskStateSegment = new_tss(&Stacks {
+        double_fault: static_stack!(),
+        nmi: static_stack!(),
+        machine_check: static_stack!(),
+        kernel: static_stack!(),
+    });
+    static ref BOOT_TABLES: CpuTables = CpuTables::new(&BOOT_TSS);
 }
 
+/// Loads the tables of the boot cpu.
 pub fn init() {
-    use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    BOOT_TABLES.load();
+}
+
+/// Loads the GDT and TSS again after the CPU lost them, when waking up from S3.
+pub fn reload() {
+    use x86_64::instructions::tables::sgdt;
+
+    BOOT_TABLES.gdt.load();
+    // `ltr` refuses a TSS marked busy, which the one loaded by `init` still is
+    let gdtr = sgdt();
+    let entry = gdtr
+        .base
+        .as_mut_ptr::<u64>()
+        .wrapping_add(SELECTORS.tss.index() as usize);
+    unsafe { *entry &= !(1 << 41) };
+    init();
+}
+
+#[test_case]
+fn test_boot_tables() {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
 
-    GDT.0.load();
-    unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+    assert_eq!(CS::get_reg(), SELECTORS.kernel_code);
+    assert_eq!(SS::get_reg(), SELECTORS.kernel_data);
+    assert_eq!(SELECTORS.user_code.rpl(), PrivilegeLevel::Ring3);
+    // sysret loads SS from the selector before the user code segment
+    assert_eq!(SELECTORS.user_data.index() + 1, SELECTORS.user_code.index());
+
+    let tss = BOOT_TABLES.tss();
+    let mut stacks = [
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize],
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize],
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize],
+        tss.privilege_stack_table[0],
+    ];
+    stacks.sort();
+    for pair in stacks.windows(2) {
+        assert!(pair[1] - pair[0] >= STACK_SIZE as u64, "stacks overlap");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,259 @@
+//! Raw port and memory access for hardware bring-up.
+//!
+//! Backs the `inb`, `inw`, `inl`, `outb`, `outw`, `outl`, `peek` and `poke` commands. Those can
+//! hang the machine or corrupt any memory, so they only work when booted with the `hwdebug`
+//! flag. Memory accesses are volatile and naturally aligned, so they can reach device
+//! registers, and every page of a range is checked to be mapped, and writable for writes,
+//! before the first byte is touched.
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Display;
+use x86_64::{
+    instructions::port::Port,
+    structures::paging::PageTableFlags,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{bootargs, mem};
+
+/// The most `peek` reads at once.
+pub const MAX_READ: usize = 64 * 1024;
+
+pub fn is_enabled() -> bool {
+    bootargs::has_flag("hwdebug")
+}
+
+/// The size of a single access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Width {
+    pub fn from_bytes(bytes: u64) -> Option<Self> {
+        match bytes {
+            1 => Some(Self::Byte),
+            2 => Some(Self::Word),
+            4 => Some(Self::Dword),
+            8 => Some(Self::Qword),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::Byte => 1,
+            Self::Word => 2,
+            Self::Dword => 4,
+            Self::Qword => 8,
+        }
+    }
+
+    /// The largest value an access of this width holds.
+    pub fn max(self) -> u64 {
+        u64::MAX >> (64 - 8 * self.bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessError {
+    /// the range is empty or longer than `MAX_READ`
+    InvalidLength,
+    /// the range doesn't fit in the address space or isn't canonical
+    InvalidAddress(u64),
+    /// the address isn't a multiple of the access width
+    Misaligned(u64),
+    /// the page at this address isn't mapped
+    NotMapped(u64),
+    /// the page at this address is read-only
+    ReadOnly(u64),
+    /// the physical address isn't in the physical memory mapping
+    NotInPhysicalMapping(u64),
+}
+
+impl Display for AccessError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidLength => write!(f, "length must be between 1 and {MAX_READ}"),
+            Self::InvalidAddress(addr) => write!(f, "invalid address {addr:#x}"),
+            Self::Misaligned(addr) => write!(f, "{addr:#x} isn't aligned to the access width"),
+            Self::NotMapped(addr) => write!(f, "{addr:#x} isn't mapped"),
+            Self::ReadOnly(addr) => write!(f, "{addr:#x} is read-only"),
+            Self::NotInPhysicalMapping(addr) => {
+                write!(f, "physical address {addr:#x} isn't mapped by the kernel")
+            }
+        }
+    }
+}
+
+/// Reads an I/O port.
+pub fn port_read(port: u16, width: Width) -> u32 {
+    unsafe {
+        match width {
+            Width::Byte => Port::<u8>::new(port).read() as u32,
+            Width::Word => Port::<u16>::new(port).read() as u32,
+            _ => Port::<u32>::new(port).read(),
+        }
+    }
+}
+
+/// Writes an I/O port, `value` is truncated to `width`.
+pub fn port_write(port: u16, width: Width, value: u32) {
+    unsafe {
+        match width {
+            Width::Byte => Port::<u8>::new(port).write(value as u8),
+            Width::Word => Port::<u16>::new(port).write(value as u16),
+            _ => Port::<u32>::new(port).write(value),
+        }
+    }
+}
+
+/// Returns the virtual address of physical address `addr` in the physical memory mapping.
+pub fn phys_to_virt(addr: u64) -> Result<u64, AccessError> {
+    let phys = PhysAddr::try_new(addr).map_err(|_| AccessError::InvalidAddress(addr))?;
+    mem::phys_to_mapped_virt(phys)
+        .map(VirtAddr::as_u64)
+        .ok_or(AccessError::NotInPhysicalMapping(addr))
+}
+
+/// Checks `addr..addr + len` for accesses of `width`, with `flags_of` returning the flags of
+/// the page containing an address.
+fn check_range(
+    addr: u64,
+    len: usize,
+    width: Width,
+    write: bool,
+    flags_of: impl Fn(u64) -> Option<PageTableFlags>,
+) -> Result<(), AccessError> {
+    if len == 0 || len > MAX_READ || len % width.bytes() != 0 {
+        return Err(AccessError::InvalidLength);
+    }
+    if addr % width.bytes() as u64 != 0 {
+        return Err(AccessError::Misaligned(addr));
+    }
+    let last = addr
+        .checked_add(len as u64 - 1)
+        .ok_or(AccessError::InvalidAddress(addr))?;
+    for bound in [addr, last] {
+        VirtAddr::try_new(bound).map_err(|_| AccessError::InvalidAddress(bound))?;
+    }
+    let page_size = mem::PAGE_SIZE as u64;
+    let mut page = addr & !(page_size - 1);
+    while page <= last {
+        let at = page.max(addr);
+        let flags = flags_of(page).ok_or(AccessError::NotMapped(at))?;
+        if write && !flags.contains(PageTableFlags::WRITABLE) {
+            return Err(AccessError::ReadOnly(at));
+        }
+        match page.checked_add(page_size) {
+            Some(next) => page = next,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+fn page_flags(addr: u64) -> Option<PageTableFlags> {
+    mem::page_flags(VirtAddr::new(addr))
+}
+
+/// Reads `len` bytes at virtual address `addr` in accesses of `width`.
+pub fn read(addr: u64, len: usize, width: Width) -> Result<Vec<u8>, AccessError> {
+    check_range(addr, len, width, false, page_flags)?;
+    let mut data = Vec::with_capacity(len);
+    for at in (addr..addr + len as u64).step_by(width.bytes()) {
+        let value = unsafe {
+            match width {
+                Width::Byte => (at as *const u8).read_volatile() as u64,
+                Width::Word => (at as *const u16).read_volatile() as u64,
+                Width::Dword => (at as *const u32).read_volatile() as u64,
+                Width::Qword => (at as *const u64).read_volatile(),
+            }
+        };
+        data.extend_from_slice(&value.to_le_bytes()[..width.bytes()]);
+    }
+    Ok(data)
+}
+
+/// Writes `values` one after the other at virtual address `addr`, each truncated to `width`.
+pub fn write(addr: u64, width: Width, values: &[u64]) -> Result<(), AccessError> {
+    check_range(addr, values.len() * width.bytes(), width, true, page_flags)?;
+    for (i, &value) in values.iter().enumerate() {
+        let at = addr + (i * width.bytes()) as u64;
+        unsafe {
+            match width {
+                Width::Byte => (at as *mut u8).write_volatile(value as u8),
+                Width::Word => (at as *mut u16).write_volatile(value as u16),
+                Width::Dword => (at as *mut u32).write_volatile(value as u32),
+                Width::Qword => (at as *mut u64).write_volatile(value),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Formats `data` read from `addr` as 16 bytes per line, in hex and as ASCII.
+pub fn hexdump(addr: u64, data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        dump.push_str(&format!("{:016x} ", addr.wrapping_add(16 * i as u64)));
+        for col in 0..16 {
+            if col == 8 {
+                dump.push(' ');
+            }
+            match line.get(col) {
+                Some(byte) => dump.push_str(&format!(" {byte:02x}")),
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str("  |");
+        for &byte in line {
+            let printable = byte.is_ascii_graphic() || byte == b' ';
+            dump.push(if printable { byte as char } else { '.' });
+        }
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_check_range() {
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    // 0x1000..0x3000 is mapped, the second page read-only
+    let flags_of = |page: u64| match page {
+        0x1000 => Some(writable),
+        0x2000 => Some(PageTableFlags::PRESENT),
+        _ => None,
+    };
+    let check = |addr, len, width, write| check_range(addr, len, width, write, flags_of);
+
+    assert_eq!(check(0x1000, 0x2000, Width::Byte, false), Ok(()));
+    assert_eq!(check(0x1ff0, 0x20, Width::Dword, false), Ok(()));
+    assert_eq!(check(0x1000, 0x1000, Width::Qword, true), Ok(()));
+    assert_eq!(check(0x1ffc, 8, Width::Dword, true), Err(AccessError::ReadOnly(0x2000)));
+    assert_eq!(check(0x2ff0, 0x20, Width::Byte, false), Err(AccessError::NotMapped(0x3000)));
+    assert_eq!(check(0x0fff, 1, Width::Byte, false), Err(AccessError::NotMapped(0x0fff)));
+    assert_eq!(check(0x1002, 4, Width::Dword, false), Err(AccessError::Misaligned(0x1002)));
+    assert_eq!(check(0x1000, 6, Width::Dword, false), Err(AccessError::InvalidLength));
+    assert_eq!(check(0x1000, 0, Width::Byte, false), Err(AccessError::InvalidLength));
+    assert_eq!(
+        check(u64::MAX, 2, Width::Byte, false),
+        Err(AccessError::InvalidAddress(u64::MAX))
+    );
+    assert_eq!(
+        check(0x0000_8000_0000_0000, 1, Width::Byte, false),
+        Err(AccessError::InvalidAddress(0x0000_8000_0000_0000))
+    );
+
+    assert_eq!(Width::Word.max(), 0xffff);
+    assert_eq!(Width::Qword.max(), u64::MAX);
+
+    assert_eq!(
+        hexdump(0x1000, b"Hello, world!\n\0\x7fskyos"),
+        "0000000000001000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 7f  \
+         |Hello, world!...|\n\
+         0000000000001010  73 6b 79 6f 73                                    |skyos|\n"
+    );
+}
@@ -0,0 +1,123 @@
+//! Idle loop and idle time accounting.
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::task;
+
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+static BUSY_TICKS: AtomicU64 = AtomicU64::new(0);
+/// The part of `BUSY_TICKS` that interrupted user mode code.
+static USER_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Set while the cpu is halted waiting for work.
+static IN_IDLE: AtomicBool = AtomicBool::new(false);
+static USE_MWAIT: AtomicBool = AtomicBool::new(false);
+/// Address armed by `monitor`, only needed to give `mwait` something to watch.
+static MONITOR_LINE: AtomicU64 = AtomicU64::new(0);
+
+/// Detects whether monitor/mwait can be used instead of hlt.
+pub fn init() {
+    let features = unsafe { __cpuid(1) };
+    USE_MWAIT.store(features.ecx & (1 << 3) != 0, Ordering::Relaxed);
+}
+
+pub fn mwait_supported() -> bool {
+    USE_MWAIT.load(Ordering::Relaxed)
+}
+
+/// Turns the use of monitor/mwait on or off. Has no effect if the cpu doesn't support it.
+pub fn set_mwait(enabled: bool) {
+    let supported = unsafe { __cpuid(1) }.ecx & (1 << 3) != 0;
+    USE_MWAIT.store(enabled && supported, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IdleStats {
+    pub idle_ticks: u64,
+    /// user and kernel ticks together
+    pub busy_ticks: u64,
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+}
+
+impl IdleStats {
+    /// Percentage of ticks spent doing work.
+    pub fn load_percent(&self) -> u64 {
+        let total = self.idle_ticks + self.busy_ticks;
+        if total == 0 {
+            0
+        } else {
+            self.busy_ticks * 100 / total
+        }
+    }
+}
+
+pub fn stats() -> IdleStats {
+    let busy_ticks = BUSY_TICKS.load(Ordering::Relaxed);
+    let user_ticks = USER_TICKS.load(Ordering::Relaxed);
+    IdleStats {
+        idle_ticks: IDLE_TICKS.load(Ordering::Relaxed),
+        busy_ticks,
+        user_ticks,
+        kernel_ticks: busy_ticks - user_ticks,
+    }
+}
+
+/// Returns whether the cpu is halted waiting for work, from the timer interrupt: whether the
+/// interrupted tick was idle.
+pub fn is_idle() -> bool {
+    IN_IDLE.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt to account the interrupted tick. `user` tells whether user
+/// mode code was interrupted.
+pub fn tick(user: bool) {
+    if IN_IDLE.load(Ordering::Relaxed) {
+        IDLE_TICKS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        if user {
+            USER_TICKS.fetch_add(1, Ordering::Relaxed);
+        }
+        BUSY_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Sleeps until the next interrupt.
+///
+/// Must be called with interrupts disabled; they are enabled atomically with going to sleep so
+/// a wake-up can't slip in between, and are disabled again before returning.
+pub fn halt() {
+    debug_assert!(!interrupts::are_enabled());
+    IN_IDLE.store(true, Ordering::Relaxed);
+    if USE_MWAIT.load(Ordering::Relaxed) {
+        unsafe {
+            asm!(
+                "monitor",
+                in("rax") MONITOR_LINE.as_ptr(),
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack)
+            );
+            // sti only takes effect after the next instruction, like with sti; hlt
+            let hint = crate::cpufreq::mwait_hint();
+            asm!("sti", "mwait", in("eax") hint, in("ecx") 0, options(nostack));
+        }
+    } else {
+        interrupts::enable_and_hlt();
+    }
+    interrupts::disable();
+    IN_IDLE.store(false, Ordering::Relaxed);
+}
+
+/// The idle task: runs other tasks while there are any and halts otherwise.
+pub fn idle_loop() -> ! {
+    loop {
+        task::yield_now();
+        interrupts::disable();
+        if !task::has_ready_tasks() {
+            halt();
+        }
+        interrupts::enable();
+    }
+}
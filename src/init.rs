@@ -1,52 +1,449 @@
-use bootloader::BootInfo;
+//! Kernel initialization.
+//!
+//! Every subsystem is a `Step` in `STEPS`, naming the steps that have to run before it. Steps
+//! belong to a stage: `shared_init` runs the early stage, `init_memory` the memory stage and
+//! `init_devices` the device stage. Within a stage the steps run in dependency order, each with
+//! a line on the boot screen showing how long it took or why it failed. Steps depending on a
+//! failed step are skipped.
+use core::time::Duration;
+
+use bootloader::{bootinfo::MemoryMap, BootInfo};
+use spin::{Mutex, Once};
 use x86_64::VirtAddr;
 
 use crate::{
-    allocator, gdt, interrupts,
+    allocator,
+    bootreport::{self, Kind},
+    config, cpufreq, debugcon,
+    drivers::{
+        self,
+        ps2::{self, Ps2Error},
+    },
+    error::{KError, KResult},
+    gdt, idle, interrupts, jobs,
+    klog::Level,
+    klogln_at,
     mem::{self, BootInfoFrameAllocator},
-    print, println, vga_buffer, VERSION,
+    memprotect, net,
+    pci::PCIManager,
+    print, print_error, print_ok, println, quirks, screenshot, suspend, sysconf, task, taskmgr,
+    theme, thermal, time, vfs, vga_buffer, VERSION,
 };
 
-pub fn init_memory(boot_info: &'static BootInfo) {
-    print_init_start("Memory");
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { mem::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    print_init_end("Memory");
-    print_init_start("Heap");
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
-    print_init_end("Heap");
+pub type InitResult = KResult<()>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Interrupts and the devices needed to boot, before there is a heap.
+    Early,
+    /// Memory management and everything needing the heap.
+    Memory,
+    /// Drivers and device discovery.
+    Devices,
 }
 
-pub fn print_init_start(name: &str) {
-    print!("Initializing {name}...");
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Early => "early",
+            Self::Memory => "memory",
+            Self::Devices => "devices",
+        }
+    }
 }
 
-pub fn print_init_end(name: &str) {
-    for _ in (0..(vga_buffer::BUFFER_WIDTH - 20).saturating_sub(name.len())).map(|_| ' ') {
-        print!(" ");
+pub struct Step {
+    pub name: &'static str,
+    pub stage: Stage,
+    /// steps that have to be done before this one
+    pub after: &'static [&'static str],
+    pub run: fn() -> InitResult,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    /// Running or waiting for its dependencies, seeing it again means there is a cycle.
+    Visiting,
+    Done,
+    Failed,
+}
+
+const STEPS: &[Step] = &[
+    Step {
+        name: "interrupts",
+        stage: Stage::Early,
+        after: &[],
+        run: init_idt,
+    },
+    Step {
+        name: "gdt",
+        stage: Stage::Early,
+        after: &[],
+        run: init_gdt,
+    },
+    Step {
+        name: "Timer",
+        stage: Stage::Early,
+        after: &["interrupts"],
+        run: init_timer,
+    },
+    Step {
+        name: "Idle",
+        stage: Stage::Early,
+        after: &[],
+        run: init_idle,
+    },
+    Step {
+        name: "CPU frequency",
+        stage: Stage::Early,
+        after: &["Idle"],
+        run: init_cpufreq,
+    },
+    Step {
+        name: "PS/2 controller",
+        stage: Stage::Early,
+        after: &["interrupts"],
+        run: init_ps2,
+    },
+    Step {
+        name: "Hardware interrupts",
+        stage: Stage::Early,
+        after: &["interrupts", "gdt", "Timer"],
+        run: init_pics,
+    },
+    Step {
+        name: "Memory",
+        stage: Stage::Memory,
+        after: &[],
+        run: init_mem,
+    },
+    Step {
+        name: "A20",
+        stage: Stage::Memory,
+        after: &["Memory"],
+        run: init_a20,
+    },
+    Step {
+        name: "Memory protection",
+        stage: Stage::Memory,
+        after: &["Memory"],
+        run: init_memprotect,
+    },
+    Step {
+        name: "Heap",
+        stage: Stage::Memory,
+        after: &["Memory"],
+        run: init_heap,
+    },
+    Step {
+        name: "Suspend",
+        stage: Stage::Memory,
+        after: &["Heap"],
+        run: init_suspend,
+    },
+    Step {
+        name: "Scheduler",
+        stage: Stage::Memory,
+        after: &["Heap", "Hardware interrupts"],
+        run: init_scheduler,
+    },
+    Step {
+        name: "VFS",
+        stage: Stage::Memory,
+        after: &["Heap"],
+        run: init_vfs,
+    },
+    Step {
+        name: "Task manager",
+        stage: Stage::Memory,
+        after: &["Scheduler"],
+        run: init_taskmgr,
+    },
+    Step {
+        name: "Screenshots",
+        stage: Stage::Memory,
+        after: &["Scheduler", "VFS"],
+        run: init_screenshot,
+    },
+    Step {
+        name: "Jobs",
+        stage: Stage::Memory,
+        after: &["Scheduler"],
+        run: init_jobs,
+    },
+    Step {
+        name: "Thermal",
+        stage: Stage::Memory,
+        after: &["Scheduler"],
+        run: init_thermal,
+    },
+    Step {
+        name: "Network",
+        stage: Stage::Memory,
+        after: &["Scheduler"],
+        run: init_net,
+    },
+    Step {
+        name: "Drivers",
+        stage: Stage::Devices,
+        after: &["Heap"],
+        run: init_drivers,
+    },
+    Step {
+        name: "PCI",
+        stage: Stage::Devices,
+        after: &["Memory", "Scheduler", "Drivers"],
+        run: init_pci,
+    },
+    Step {
+        name: "Root file system",
+        stage: Stage::Devices,
+        after: &["VFS", "PCI"],
+        run: init_root_fs,
+    },
+    Step {
+        name: "System config",
+        stage: Stage::Devices,
+        after: &["VFS", "Jobs", "PCI"],
+        run: init_sysconf,
+    },
+];
+
+static STATES: Mutex<[State; STEPS.len()]> = Mutex::new([State::Pending; STEPS.len()]);
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+/// The memory map of `BOOT_INFO`, cleaned up by `quirks::sanitize_memory_map`.
+static MEMORY_MAP: Once<MemoryMap> = Once::new();
+
+fn init_idt() -> InitResult {
+    interrupts::init_idt();
+    Ok(())
+}
+
+fn init_gdt() -> InitResult {
+    gdt::init();
+    Ok(())
+}
+
+fn init_timer() -> InitResult {
+    time::init();
+    Ok(())
+}
+
+fn init_idle() -> InitResult {
+    idle::init();
+    Ok(())
+}
+
+fn init_cpufreq() -> InitResult {
+    cpufreq::init();
+    Ok(())
+}
+
+fn init_ps2() -> InitResult {
+    match ps2::init() {
+        Ok(_) => Ok(()),
+        Err(Ps2Error::NotPresent) => {
+            klogln_at!(Level::Warn, "ps2: no controller, no PS/2 keyboard");
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn init_pics() -> InitResult {
+    unsafe { interrupts::PICS.lock().initialize() };
+    x86_64::instructions::interrupts::enable();
+    Ok(())
+}
+
+fn init_mem() -> InitResult {
+    let boot_info = BOOT_INFO.r#try().ok_or(KError::InvalidArgument)?;
+    MEMORY_MAP.call_once(|| quirks::sanitize_memory_map(&boot_info.memory_map));
+    unsafe { mem::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    Ok(())
+}
+
+fn memory_map() -> KResult<&'static MemoryMap> {
+    MEMORY_MAP.r#try().ok_or(KError::InvalidArgument)
+}
+
+fn init_a20() -> InitResult {
+    quirks::check_a20()
+}
+
+fn init_memprotect() -> InitResult {
+    memprotect::init(memory_map()?);
+    Ok(())
+}
+
+fn init_heap() -> InitResult {
+    let boot_info = BOOT_INFO.r#try().ok_or(KError::InvalidArgument)?;
+    let mut mapper = unsafe { mem::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(memory_map()?) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)?;
+    mem::set_frame_allocator(frame_allocator);
+    Ok(())
+}
+
+fn init_suspend() -> InitResult {
+    suspend::init();
+    Ok(())
+}
+
+fn init_scheduler() -> InitResult {
+    task::init();
+    Ok(())
+}
+
+fn init_thermal() -> InitResult {
+    thermal::init();
+    Ok(())
+}
+
+fn init_net() -> InitResult {
+    net::init();
+    Ok(())
+}
+
+fn init_vfs() -> InitResult {
+    vfs::init();
+    Ok(())
+}
+
+fn init_taskmgr() -> InitResult {
+    taskmgr::init();
+    Ok(())
+}
+
+fn init_screenshot() -> InitResult {
+    screenshot::init();
+    Ok(())
+}
+
+fn init_jobs() -> InitResult {
+    jobs::init();
+    Ok(())
+}
+
+fn init_drivers() -> InitResult {
+    drivers::init();
+    Ok(())
+}
+
+fn init_pci() -> InitResult {
+    PCIManager::new().scan();
+    Ok(())
+}
+
+fn init_root_fs() -> InitResult {
+    Ok(vfs::mount_root_disk()?)
+}
+
+fn init_sysconf() -> InitResult {
+    sysconf::load()
+}
+
+fn find(name: &str) -> usize {
+    STEPS
+        .iter()
+        .position(|step| step.name == name)
+        .unwrap_or_else(|| panic!("unknown init step {name}"))
+}
+
+/// Returns whether the step called `name` ran and succeeded.
+pub fn done(name: &str) -> bool {
+    STATES.lock()[find(name)] == State::Done
+}
+
+/// Runs every step of `stage` that hasn't run yet.
+pub fn run_stage(stage: Stage) {
+    let mut states = STATES.lock();
+    bootreport::measure(Kind::Stage, stage.name(), || {
+        for i in 0..STEPS.len() {
+            if STEPS[i].stage == stage {
+                visit(i, stage, &mut states);
+            }
+        }
+    });
+}
+
+/// Runs the dependencies of step `i`, then the step itself. Returns whether it succeeded.
+fn visit(i: usize, stage: Stage, states: &mut [State; STEPS.len()]) -> bool {
+    let step = &STEPS[i];
+    match states[i] {
+        State::Done => return true,
+        State::Failed => return false,
+        State::Visiting => panic!("init step {} depends on itself", step.name),
+        State::Pending if step.stage > stage => {
+            panic!("init step {} is needed before its stage", step.name)
+        }
+        State::Pending => {}
     }
-    println!("[ok]");
+
+    states[i] = State::Visiting;
+    for dep in step.after {
+        if !visit(find(dep), stage, states) {
+            states[i] = State::Failed;
+            print_init_start(step.name);
+            print_error!(" skipped: needs {dep}");
+            return false;
+        }
+    }
+
+    print_init_start(step.name);
+    let start = time::uptime();
+    let result = bootreport::measure(Kind::Step, step.name, step.run);
+    match result {
+        Ok(()) => print_init_end(step.name, time::uptime() - start),
+        Err(e) => print_error!(" failed: {e}"),
+    }
+    states[i] = if result.is_ok() {
+        State::Done
+    } else {
+        State::Failed
+    };
+    result.is_ok()
 }
 
-pub fn init_<F>(f: F, name: &str)
-where
-    F: Fn() -> (),
-{
-    print_init_start(name);
-    f();
-    print_init_end(name);
+fn print_init_start(name: &str) {
+    print!("Initializing {name}...");
+}
+
+fn print_init_end(name: &str, elapsed: Duration) {
+    for _ in 0..(vga_buffer::BUFFER_WIDTH - 28).saturating_sub(name.len()) {
+        print!(" ");
+    }
+    print!("{:>5}ms ", elapsed.as_millis());
+    print_ok!("[ok]");
 }
 
 pub fn shared_init() {
+    bootreport::start();
+    config::init();
+    debugcon::init();
+    theme::init();
     println!("SkyOS v{}", VERSION);
 
-    // interrupts
-    init_(interrupts::init_idt, "interrupts");
-    init_(gdt::init, "gdt");
-    init_(
-        || unsafe { interrupts::PICS.lock().initialize() },
-        "Hardware interrupts",
-    );
-    x86_64::instructions::interrupts::enable();
+    run_stage(Stage::Early);
+    vga_buffer::init();
+}
+
+pub fn init_memory(boot_info: &'static BootInfo) {
+    BOOT_INFO.call_once(|| boot_info);
+    run_stage(Stage::Memory);
+}
+
+/// Loads the drivers and scans for devices. Needs `init_memory` first.
+pub fn init_devices() {
+    run_stage(Stage::Devices);
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_steps_depend_on_earlier_stages() {
+    for step in STEPS {
+        for dep in step.after {
+            assert!(STEPS[find(dep)].stage <= step.stage);
+        }
+    }
 }
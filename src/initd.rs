@@ -0,0 +1,292 @@
+//! The init task and service supervision.
+//!
+//! Once the kernel has booted, init makes sure the root file system is mounted, starts the
+//! shell and the services in `/etc/init.d`, then waits for `poweroff`. Every file in
+//! `/etc/init.d` defines a service named after the file, with `key=value` lines like
+//! `/etc/system.conf`:
+//!
+//! - `command`: command line run as the service, required
+//! - `restart`: `always`, `on-failure` (the default) or `never`
+//!
+//! A service fails when its command returns an error and crashes when its task is killed
+//! before the command finishes. Each service has a supervisor task that restarts it as its
+//! policy says, waiting twice as long after every failure in a row, up to `BACKOFF_MAX`.
+//!
+//! Shutting down stops the services with `Signal::Term`, kills what is left after
+//! `STOP_TIMEOUT`, syncs the file systems and powers off.
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    cmdline,
+    error::{KError, KResult},
+    klog::Level,
+    klogln_at, power, println,
+    signal::Signal,
+    sync::Event,
+    sysconf,
+    task::{self, TaskId},
+    time, timer, vfs,
+};
+
+pub const INIT_D: &str = "/etc/init.d";
+
+/// Delay before the first restart, doubled for every further failure in a row.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(64);
+/// A service that ran this long before exiting starts over at `BACKOFF_MIN`.
+const STABLE: Duration = Duration::from_secs(30);
+/// How long services get to exit after `Signal::Term`.
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    Always,
+    OnFailure,
+    Never,
+}
+
+impl Restart {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "always" => Some(Self::Always),
+            "on-failure" => Some(Self::OnFailure),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::OnFailure => "on-failure",
+            Self::Never => "never",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// waiting to be restarted
+    Backoff,
+    /// exited and not restarted
+    Stopped,
+    /// failed or crashed and not restarted
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Service {
+    pub name: String,
+    pub command: String,
+    pub restart: Restart,
+    pub state: State,
+    /// task of the current run
+    pub task: Option<TaskId>,
+    pub restarts: u32,
+}
+
+// how a run of a service ended
+const RUNNING: u8 = 0;
+const SUCCEEDED: u8 = 1;
+const FAILED: u8 = 2;
+
+static SERVICES: Mutex<Vec<Service>> = Mutex::new(Vec::new());
+static SHUTDOWN: Event = Event::new();
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// id of the init task, 0 until it runs
+static INIT_TASK: AtomicU64 = AtomicU64::new(0);
+
+/// Parses a service definition, returns its command line and restart policy.
+pub fn parse(text: &str) -> KResult<(String, Restart)> {
+    let pairs = sysconf::parse(text).map_err(|_| KError::InvalidArgument)?;
+    let mut command = None;
+    let mut restart = Restart::OnFailure;
+    for (_, key, value) in pairs {
+        match key {
+            "command" => command = Some(value.to_string()),
+            "restart" => restart = Restart::parse(value).ok_or(KError::InvalidArgument)?,
+            _ => return Err(KError::InvalidArgument),
+        }
+    }
+    let command = command.filter(|command| !command.is_empty());
+    Ok((command.ok_or(KError::InvalidArgument)?, restart))
+}
+
+/// Starts the init task. Requires the scheduler, the VFS and the devices.
+pub fn start() {
+    let id = task::spawn("init", run);
+    INIT_TASK.store(id.0, Ordering::Release);
+}
+
+fn run() {
+    // mounting fails if booting already mounted the root, which is fine
+    vfs::init();
+    cmdline::start();
+    for (name, command, restart) in read_services() {
+        let index = without_interrupts(|| {
+            let mut services = SERVICES.lock();
+            services.push(Service {
+                name: name.clone(),
+                command,
+                restart,
+                state: State::Running,
+                task: None,
+                restarts: 0,
+            });
+            services.len() - 1
+        });
+        task::spawn(&format!("supervise {}", name), move || supervise(index));
+    }
+    SHUTDOWN.wait();
+    shut_down();
+}
+
+/// Reads the service definitions, skipping broken ones.
+fn read_services() -> Vec<(String, String, Restart)> {
+    let Ok(entries) = vfs::read_dir(INIT_D) else {
+        return Vec::new();
+    };
+    let mut services = Vec::new();
+    for entry in entries {
+        let path = format!("{}/{}", INIT_D, entry.name);
+        let parsed = vfs::read(&path)
+            .map_err(KError::from)
+            .and_then(|data| parse(&String::from_utf8_lossy(&data)));
+        match parsed {
+            Ok((command, restart)) => services.push((entry.name, command, restart)),
+            Err(e) => klogln_at!(Level::Warn, "init: {}: {}", path, e),
+        }
+    }
+    services
+}
+
+fn update(index: usize, f: impl FnOnce(&mut Service)) {
+    without_interrupts(|| f(&mut SERVICES.lock()[index]));
+}
+
+/// Runs a service until its restart policy or a shutdown says to stop.
+fn supervise(index: usize) {
+    let mut failures: u32 = 0;
+    loop {
+        let service = without_interrupts(|| SERVICES.lock()[index].clone());
+        let status = Arc::new(AtomicU8::new(RUNNING));
+        let started = time::uptime();
+        let (command, run_status) = (service.command.clone(), status.clone());
+        let task = task::spawn(&service.name, move || {
+            let ok = cmdline::run_line(&command);
+            run_status.store(if ok { SUCCEEDED } else { FAILED }, Ordering::Release);
+        });
+        update(index, |service| {
+            service.task = Some(task);
+            service.state = State::Running;
+        });
+        task::wait_for_exit(|| task::has_exited(task));
+
+        let status = status.load(Ordering::Acquire);
+        let restart = match service.restart {
+            Restart::Always => true,
+            Restart::OnFailure => status != SUCCEEDED,
+            Restart::Never => false,
+        };
+        if STOPPING.load(Ordering::Acquire) || !restart {
+            let state = if status == SUCCEEDED {
+                State::Stopped
+            } else {
+                State::Failed
+            };
+            update(index, |service| service.state = state);
+            return;
+        }
+
+        if time::uptime() - started >= STABLE {
+            failures = 0;
+        }
+        let delay = BACKOFF_MIN
+            .checked_mul(1 << failures.min(16))
+            .map_or(BACKOFF_MAX, |delay| delay.min(BACKOFF_MAX));
+        failures += 1;
+        let how = if status == RUNNING {
+            "crashed"
+        } else {
+            "exited"
+        };
+        klogln_at!(
+            Level::Warn,
+            "init: {} {}, restarting in {}s",
+            service.name,
+            how,
+            delay.as_secs()
+        );
+        update(index, |service| {
+            service.state = State::Backoff;
+            service.restarts += 1;
+        });
+        timer::sleep(delay);
+        if STOPPING.load(Ordering::Acquire) {
+            update(index, |service| service.state = State::Stopped);
+            return;
+        }
+    }
+}
+
+/// Returns every service, in the order they were started.
+pub fn list() -> Vec<Service> {
+    without_interrupts(|| SERVICES.lock().clone())
+}
+
+/// Shuts the system down. With init running it does the shutdown and this returns, otherwise
+/// the caller does.
+pub fn poweroff() {
+    if INIT_TASK.load(Ordering::Acquire) != 0 {
+        SHUTDOWN.signal();
+    } else {
+        shut_down();
+    }
+}
+
+fn shut_down() -> ! {
+    STOPPING.store(true, Ordering::Release);
+    println!("Stopping services...");
+    let tasks: Vec<TaskId> = list().iter().filter_map(|service| service.task).collect();
+    for task in &tasks {
+        let _ = task::send_signal(*task, Signal::Term);
+    }
+    let deadline = time::uptime() + STOP_TIMEOUT;
+    while time::uptime() < deadline && !tasks.iter().all(|task| task::has_exited(*task)) {
+        timer::sleep(Duration::from_millis(50));
+    }
+    for task in tasks {
+        if !task::has_exited(task) {
+            klogln_at!(Level::Warn, "init: killing task {}", task.0);
+            let _ = task::kill(task);
+        }
+    }
+
+    println!("Syncing file systems...");
+    if let Err(e) = vfs::sync() {
+        println!("sync failed: {}", KError::from(e));
+    }
+    println!("Powering off");
+    power::poweroff();
+}
+
+#[test_case]
+fn test_parse_service() {
+    let (command, restart) = parse("command=echo hi # greet\nrestart=always\n").unwrap();
+    assert_eq!((command.as_str(), restart), ("echo hi", Restart::Always));
+    assert_eq!(parse("restart=never\n"), Err(KError::InvalidArgument));
+    assert_eq!(
+        parse("command=x\nrestart=sometimes\n"),
+        Err(KError::InvalidArgument)
+    );
+}
@@ -0,0 +1,88 @@
+//! Initial ramdisk.
+//!
+//! The initrd is a cpio (newc) or ustar archive embedded into the image at build time, see
+//! `build.rs`, or the first module of a loader that passes modules, see `boot`. It's optionally
+//! compressed with `lz4` (frame or legacy format). With `root=initrd`, the default whenever
+//! there is one, its contents become the root file system until real disks are available.
+//! `root=ram` starts with an empty root instead, and `root=/dev/sdX[N]` starts with the initrd
+//! and switches to the ext2 file system of that disk or partition once the devices are up.
+use alloc::{sync::Arc, vec::Vec};
+use spin::Once;
+
+use crate::{
+    allocator,
+    archive::{self, Archive},
+    boot, bootargs, compress,
+    drivers::ramdisk::RamDisk,
+    klog::Level,
+    klogln_at,
+    vfs::{FileSystem, RamFs},
+};
+
+static INITRD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd"));
+/// The decompressed initrd, empty if it couldn't be decompressed.
+static UNPACKED: Once<Vec<u8>> = Once::new();
+
+/// Returns the archive as loaded, the first boot module or else the embedded one.
+fn packed() -> &'static [u8] {
+    boot::modules()
+        .next()
+        .and_then(boot::module_data)
+        .unwrap_or(INITRD)
+}
+
+/// Returns the raw initrd, decompressed if needed. `None` if there is none or it can't be
+/// decompressed. Needs the heap if the initrd is compressed.
+pub fn data() -> Option<&'static [u8]> {
+    let packed = packed();
+    if !compress::is_lz4(packed) {
+        return (!packed.is_empty()).then_some(packed);
+    }
+    // leaves room on the heap for the unpacked files
+    let max_unpacked = allocator::heap_size() / 2;
+    let data = UNPACKED.call_once(|| match compress::decompress(packed, max_unpacked) {
+        Ok(data) => {
+            klogln_at!(Level::Info, "initrd: decompressed {} to {} bytes", packed.len(), data.len());
+            data
+        }
+        Err(e) => {
+            klogln_at!(Level::Warn, "initrd: can't decompress: {:?}", e);
+            Vec::new()
+        }
+    });
+    (!data.is_empty()).then_some(data.as_slice())
+}
+
+/// Returns the initrd as a read-only block device.
+pub fn disk() -> Option<RamDisk<'static>> {
+    data().map(RamDisk::new)
+}
+
+fn initrd_root() -> Option<Arc<dyn FileSystem>> {
+    let fs = RamFs::new();
+    let result = Archive::new(disk()?)
+        .and_then(|mut archive| archive::extract(&mut archive, &fs, "/"));
+    match result {
+        Ok(count) => klogln_at!(Level::Info, "initrd: unpacked {} entries", count),
+        Err(e) => {
+            klogln_at!(Level::Warn, "initrd: invalid archive: {:?}", e);
+            return None;
+        }
+    }
+    Some(Arc::new(fs))
+}
+
+/// Returns the root file system selected by the `root=` boot argument.
+pub fn root() -> Arc<dyn FileSystem> {
+    let root = match bootargs::get("root") {
+        None | Some("initrd") => initrd_root(),
+        Some("ram") => None,
+        // until the disks are found, see `vfs::mount_root_disk`
+        Some(disk) if disk.starts_with("/dev/") => initrd_root(),
+        Some(other) => {
+            klogln_at!(Level::Warn, "root: unsupported device {}, using initrd", other);
+            initrd_root()
+        }
+    };
+    root.unwrap_or_else(|| Arc::new(RamFs::new()))
+}
@@ -1,109 +1,165 @@
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
 use x86_64::{
     instructions::{interrupts::without_interrupts, port::Port},
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    structures::idt::{
+        Entry, HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    },
+    PrivilegeLevel, VirtAddr,
 };
 
-use crate::{cmdline::CMD_LINE, gdt, print, println};
+use crate::{error::KError, gdt, println, syscall};
 
 macro_rules! handler {
-    ($name: tt) => {
+    ($name: tt, $vector: expr) => {
         extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
-            println!(
-                concat!("EXCEPTION: ", stringify!($name), ": {:?}"),
-                stack_frame
-            )
+            count($vector);
+            handle_exception($vector, &stack_frame, None);
         }
     };
-    ($name: tt, $other: expr) => {
+    ($name: tt, $vector: expr, error_code) => {
         extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, value: u64) {
-            println!(
-                concat!("EXCEPTION: ", stringify!($name), ": {:?}; {}"),
-                stack_frame, value
-            )
+            count($vector);
+            handle_exception($vector, &stack_frame, Some(value));
         }
     };
 }
 
+/// The IDT. Entries change after it is loaded when vectors are allocated, which needs no
+/// reload: the cpu reads the entry from memory for every interrupt. Only accessed with
+/// `IDT_LOCK` held.
+struct Idt(UnsafeCell<InterruptDescriptorTable>);
+
+unsafe impl Sync for Idt {}
+
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+    static ref IDT: Idt = Idt(UnsafeCell::new(build_idt()));
+}
 
-        idt.debug.set_handler_fn(debug);
+static IDT_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// The fixed entries: the exceptions, the PIC lines and system calls.
+fn build_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+    idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+    for (irq, handler) in IRQ_STUBS.iter().enumerate() {
+        idt[PIC_1_OFFSET as usize + FIRST_SHARED_IRQ as usize + irq].set_handler_fn(*handler);
+    }
+    unsafe {
+        idt[syscall::SYSCALL_VECTOR as usize]
+            .set_handler_addr(VirtAddr::new(syscall::entry_address()))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+
+    // can arrive anywhere, even while the stack pointer isn't valid
+    unsafe {
         idt.non_maskable_interrupt
-            .set_handler_fn(non_maskable_interrupt);
-        idt.overflow.set_handler_fn(overflow);
-        idt.bound_range_exceeded
-            .set_handler_fn(bound_range_exceeded);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode);
-        idt.device_not_available
-            .set_handler_fn(device_not_available);
-        idt.x87_floating_point.set_handler_fn(x87_floating_point);
-        idt.simd_floating_point.set_handler_fn(simd_floating_point);
-        idt.virtualization.set_handler_fn(virtualization);
-        idt.hv_injection_exception
-            .set_handler_fn(hv_injection_exception);
-        idt.invalid_tss.set_handler_fn(invalid_tss);
-        idt.segment_not_present.set_handler_fn(segment_not_present);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault);
-        idt.alignment_check.set_handler_fn(alignment_check);
-        idt.cp_protection_exception
-            .set_handler_fn(cp_protection_exception);
-        idt.vmm_communication_exception
-            .set_handler_fn(vmm_communication_exception);
-        idt.security_exception.set_handler_fn(security_exception);
-        idt.divide_error.set_handler_fn(divide_error);
-
-        idt
-    };
+            .set_handler_fn(non_maskable_interrupt)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+
+    idt.debug.set_handler_fn(debug);
+    idt.overflow.set_handler_fn(overflow);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode);
+    idt.device_not_available
+        .set_handler_fn(device_not_available);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point);
+    idt.virtualization.set_handler_fn(virtualization);
+    idt.hv_injection_exception
+        .set_handler_fn(hv_injection_exception);
+    idt.invalid_tss.set_handler_fn(invalid_tss);
+    idt.segment_not_present.set_handler_fn(segment_not_present);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault);
+    idt.alignment_check.set_handler_fn(alignment_check);
+    idt.cp_protection_exception
+        .set_handler_fn(cp_protection_exception);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_exception);
+    idt.security_exception.set_handler_fn(security_exception);
+    idt.divide_error.set_handler_fn(divide_error);
+
+    idt
 }
 
 pub fn init_idt() {
-    IDT.load();
-}
-
-handler!(divide_error);
-handler!(debug);
-handler!(non_maskable_interrupt);
-handler!(overflow);
-handler!(bound_range_exceeded);
-handler!(invalid_opcode);
-handler!(device_not_available);
-handler!(x87_floating_point);
-handler!(simd_floating_point);
-handler!(virtualization);
-handler!(hv_injection_exception);
-handler!(invalid_tss, ());
-handler!(segment_not_present, ());
-handler!(stack_segment_fault, ());
-handler!(general_protection_fault, ());
-handler!(alignment_check, ());
-handler!(cp_protection_exception, ());
-handler!(vmm_communication_exception, ());
-handler!(security_exception, ());
+    update_idt(|idt| unsafe { idt.load_unsafe() });
+}
+
+/// Changes the IDT, with interrupts disabled so no handler sees half an entry.
+fn update_idt(f: impl FnOnce(&mut InterruptDescriptorTable)) {
+    without_interrupts(|| {
+        let _lock = IDT_LOCK.lock();
+        f(unsafe { &mut *IDT.0.get() })
+    });
+}
+
+handler!(divide_error, 0);
+handler!(debug, 1);
+handler!(overflow, 4);
+handler!(bound_range_exceeded, 5);
+handler!(invalid_opcode, 6);
+handler!(device_not_available, 7);
+handler!(x87_floating_point, 16);
+handler!(simd_floating_point, 19);
+handler!(virtualization, 20);
+handler!(hv_injection_exception, 28);
+handler!(invalid_tss, 10, error_code);
+handler!(segment_not_present, 11, error_code);
+handler!(stack_segment_fault, 12, error_code);
+handler!(general_protection_fault, 13, error_code);
+handler!(alignment_check, 17, error_code);
+handler!(cp_protection_exception, 21, error_code);
+handler!(vmm_communication_exception, 29, error_code);
+handler!(security_exception, 30, error_code);
+
+extern "x86-interrupt" fn non_maskable_interrupt(stack_frame: InterruptStackFrame) {
+    count(2);
+    handle_exception(2, &stack_frame, None);
+}
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    count(3);
+    handle_exception(3, &stack_frame, None);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    count(8);
+    handle_exception(8, &stack_frame, Some(error_code));
+    // the stack the fault happened on is unusable, there is nothing to go back to
+    panic!("EXCEPTION: double_fault\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) -> ! {
+    count(18);
+    handle_exception(18, &stack_frame, None);
+    // the state of the cpu is lost, there is nothing to go back to
+    panic!("EXCEPTION: machine_check\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -112,11 +168,155 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
+    count(14);
+    // faults in memory mappings are resolved by mapping the page, which may have to read a file
+    let addr = Cr2::read().as_u64();
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    if stack_frame.cpu_flags & (1 << 9) != 0 {
+        x86_64::instructions::interrupts::enable();
+    }
+    let handled = crate::mmap::handle_fault(addr, write);
+    x86_64::instructions::interrupts::disable();
+    if handled {
+        return;
+    }
+
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    handle_exception(14, &stack_frame, Some(error_code.bits()));
+}
+
+/// What an exception does once its handler ran, see `set_action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExceptionAction {
+    /// print the stack frame and return to the faulting code, which retries the instruction
+    /// for faults
+    Log,
+    /// print the stack frame and terminate the task that caused it, panics in the boot task
+    Kill,
+    Panic,
+    /// print the stack frame and a backtrace, then halt with interrupts off for a debugger
+    /// attached to the emulator
+    Debug,
+}
+
+impl ExceptionAction {
+    pub const ALL: [Self; 4] = [Self::Log, Self::Kill, Self::Panic, Self::Debug];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Log => "log",
+            Self::Kill => "kill",
+            Self::Panic => "panic",
+            Self::Debug => "debug",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+/// The exceptions with a handler, by vector.
+pub const EXCEPTIONS: &[(u8, &str)] = &[
+    (0, "divide_error"),
+    (1, "debug"),
+    (2, "non_maskable_interrupt"),
+    (3, "breakpoint"),
+    (4, "overflow"),
+    (5, "bound_range_exceeded"),
+    (6, "invalid_opcode"),
+    (7, "device_not_available"),
+    (8, "double_fault"),
+    (10, "invalid_tss"),
+    (11, "segment_not_present"),
+    (12, "stack_segment_fault"),
+    (13, "general_protection_fault"),
+    (14, "page_fault"),
+    (16, "x87_floating_point"),
+    (17, "alignment_check"),
+    (18, "machine_check"),
+    (19, "simd_floating_point"),
+    (20, "virtualization"),
+    (21, "cp_protection_exception"),
+    (28, "hv_injection_exception"),
+    (29, "vmm_communication_exception"),
+    (30, "security_exception"),
+];
+
+/// The action of every exception vector, as `ExceptionAction as u8`. Double faults and machine
+/// checks panic and page faults nothing maps stop the machine, the others only log.
+static ACTIONS: [AtomicU8; 32] = {
+    let mut actions = [const { AtomicU8::new(ExceptionAction::Log as u8) }; 32];
+    actions[8] = AtomicU8::new(ExceptionAction::Panic as u8);
+    actions[14] = AtomicU8::new(ExceptionAction::Debug as u8);
+    actions[18] = AtomicU8::new(ExceptionAction::Panic as u8);
+    actions
+};
+
+/// Returns the vector of the exception called `name`, or with that number.
+pub fn exception_vector(name: &str) -> Option<u8> {
+    EXCEPTIONS
+        .iter()
+        .find(|(vector, exception)| *exception == name || name.parse() == Ok(*vector))
+        .map(|&(vector, _)| vector)
+}
+
+pub fn exception_action(vector: u8) -> ExceptionAction {
+    let action = ACTIONS[vector as usize].load(Ordering::Relaxed);
+    ExceptionAction::ALL[action as usize]
+}
+
+/// Sets what the exception `vector` does. Double faults and machine checks can't go back to
+/// the code that caused them, they can only panic or stop for the debugger.
+pub fn set_action(vector: u8, action: ExceptionAction) -> Result<(), KError> {
+    if !EXCEPTIONS.iter().any(|&(exception, _)| exception == vector) {
+        return Err(KError::InvalidArgument);
+    }
+    let returns = matches!(action, ExceptionAction::Log | ExceptionAction::Kill);
+    if matches!(vector, 8 | 18) && returns {
+        return Err(KError::InvalidArgument);
+    }
+    ACTIONS[vector as usize].store(action as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Runs the action set for the exception `vector`. Only returns for `ExceptionAction::Log`.
+fn handle_exception(vector: u8, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    let name = EXCEPTIONS
+        .iter()
+        .find(|&&(exception, _)| exception == vector)
+        .map_or("unknown", |&(_, name)| name);
+    let code = error_code
+        .map(|code| format!("; {code:#x}"))
+        .unwrap_or_default();
+    let action = exception_action(vector);
+    if vector == 2 {
+        // can arrive while anything is locked, including the screen
+        crate::emergency_println!("EXCEPTION: {name}: {:?}{code}", stack_frame);
+    } else if action != ExceptionAction::Panic {
+        println!("EXCEPTION: {name}: {:?}{code}", stack_frame);
+    }
+    match action {
+        ExceptionAction::Log => {}
+        ExceptionAction::Kill => {
+            let id = crate::task::current_id();
+            if id == crate::task::BOOT_TASK {
+                panic!("EXCEPTION: {name} in the boot task\n{:#?}", stack_frame);
+            }
+            println!("killing task {}", id.0);
+            // like `task::force_kill`, locks the task holds stay locked
+            crate::task::exit();
+        }
+        ExceptionAction::Panic => panic!("EXCEPTION: {name}{code}\n{:#?}", stack_frame),
+        ExceptionAction::Debug => {
+            crate::ksyms::print_backtrace();
+            println!("halted, attach a debugger to inspect the state");
+            x86_64::instructions::interrupts::disable();
+            crate::hlt_loop();
+        }
+    }
 }
 
 #[test_case]
@@ -125,6 +325,31 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
+#[test_case]
+fn test_exception_actions() {
+    assert_eq!(exception_vector("page_fault"), Some(14));
+    assert_eq!(exception_vector("13"), Some(13));
+    assert_eq!(exception_vector("9"), None);
+    assert_eq!(exception_vector("timer"), None);
+    for action in ExceptionAction::ALL {
+        assert_eq!(ExceptionAction::from_name(action.name()), Some(action));
+    }
+
+    assert_eq!(exception_action(3), ExceptionAction::Log);
+    assert_eq!(exception_action(8), ExceptionAction::Panic);
+    assert_eq!(
+        set_action(8, ExceptionAction::Log),
+        Err(KError::InvalidArgument)
+    );
+    assert_eq!(
+        set_action(9, ExceptionAction::Panic),
+        Err(KError::InvalidArgument)
+    );
+    assert_eq!(set_action(6, ExceptionAction::Kill), Ok(()));
+    assert_eq!(exception_action(6), ExceptionAction::Kill);
+    set_action(6, ExceptionAction::Log).unwrap();
+}
+
 // ╔═══════════════════════════════════════════╗
 // ║                                           ║
 // ║   H A R D W A R E   I N T E R R U P T S   ║
@@ -154,36 +379,416 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Timer.as_u8());
+    crate::preempt::irq_enter();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    // ring 3 code segment selectors have the requested privilege level 3
+    let user = stack_frame.code_segment & 3 == 3;
+    crate::time::tick();
+    crate::idle::tick(user);
+    crate::cpufreq::tick();
+    crate::profile::tick(stack_frame.instruction_pointer.as_u64());
+    crate::timer::tick();
+    crate::task::tick(user);
+    crate::preempt::irq_exit();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+    use crate::keyboard::Layout;
+    use pc_keyboard::{HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
 
     lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
+        static ref KEYBOARD: Mutex<Keyboard<Layout, ScancodeSet1>> = Mutex::new(
+            Keyboard::new(Layout, ScancodeSet1, HandleControl::MapLettersToUnicode)
         );
     }
 
+    count(InterruptIndex::Keyboard.as_u8());
+    crate::preempt::irq_enter();
     let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
 
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            without_interrupts(|| CMD_LINE.lock().process_key(key));
-        }
+        without_interrupts(|| crate::keyboard::process_event(&mut keyboard, key_event));
     }
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    crate::preempt::irq_exit();
+}
+
+/// The PIC lines below this have fixed handlers (timer, keyboard and the cascade).
+const FIRST_SHARED_IRQ: u8 = 3;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+
+const NO_HANDLERS: Vec<fn()> = Vec::new();
+/// Handlers registered by drivers, indexed by IRQ line.
+static IRQ_HANDLERS: spin::Mutex<[Vec<fn()>; 16]> = spin::Mutex::new([NO_HANDLERS; 16]);
+
+macro_rules! irq_stub {
+    ($name: ident, $irq: expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_irq($irq);
+        }
+    };
+}
+
+irq_stub!(irq3, 3);
+irq_stub!(irq4, 4);
+irq_stub!(irq5, 5);
+irq_stub!(irq6, 6);
+irq_stub!(irq7, 7);
+irq_stub!(irq8, 8);
+irq_stub!(irq9, 9);
+irq_stub!(irq10, 10);
+irq_stub!(irq11, 11);
+irq_stub!(irq12, 12);
+irq_stub!(irq13, 13);
+irq_stub!(irq14, 14);
+irq_stub!(irq15, 15);
+
+const IRQ_STUBS: [HandlerFunc; 13] = [
+    irq3, irq4, irq5, irq6, irq7, irq8, irq9, irq10, irq11, irq12, irq13, irq14, irq15,
+];
+
+fn dispatch_irq(irq: u8) {
+    count(PIC_1_OFFSET + irq);
+    crate::preempt::irq_enter();
+    for handler in IRQ_HANDLERS.lock()[irq as usize].iter() {
+        handler();
+    }
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
+    crate::preempt::irq_exit();
+}
+
+/// The interrupt masks of both PICs, which they lose while the machine sleeps.
+pub fn pic_masks() -> [u8; 2] {
+    without_interrupts(|| {
+        let _pics = PICS.lock();
+        unsafe { [Port::new(PIC_1_DATA).read(), Port::new(PIC_2_DATA).read()] }
+    })
+}
+
+/// Initializes the PICs again after a reset, with the masks from `pic_masks`.
+pub fn restore_pics(masks: [u8; 2]) {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            pics.initialize();
+            Port::new(PIC_1_DATA).write(masks[0]);
+            Port::new(PIC_2_DATA).write(masks[1]);
+        }
+    })
+}
+
+/// Runs `handler` on every interrupt of the PIC line `irq` and unmasks the line. Lines can be
+/// shared, so handlers have to check whether their device actually raised the interrupt.
+pub fn register_irq(irq: u8, handler: fn()) {
+    assert!((FIRST_SHARED_IRQ..16).contains(&irq), "IRQ {irq} can't be registered");
+    without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize].push(handler);
+        let _pics = PICS.lock();
+        unsafe {
+            let mut master = Port::<u8>::new(PIC_1_DATA);
+            let mut slave = Port::<u8>::new(PIC_2_DATA);
+            if irq < 8 {
+                let mask = master.read();
+                master.write(mask & !(1 << irq));
+            } else {
+                let mask = slave.read();
+                slave.write(mask & !(1 << (irq - 8)));
+                // the slave is chained to line 2
+                let mask = master.read();
+                master.write(mask & !(1 << 2));
+            }
+        }
+    });
+}
+
+// ╔═══════════════════════════════════════════╗
+// ║                                           ║
+// ║     D Y N A M I C   V E C T O R S         ║
+// ║                                           ║
+// ╚═══════════════════════════════════════════╝
+
+/// Vectors `alloc_vector` hands out, between the PIC lines and the vectors the local APIC
+/// reserves. The system call vector in between is never handed out.
+pub const DYNAMIC_VECTORS: Range<u8> = 0x30..0xf0;
+
+/// Handler of every allocated vector as a `fn()`, 0 for free ones.
+static VECTOR_HANDLERS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+/// Names of the allocated vectors, for `/proc/interrupts`.
+static VECTOR_NAMES: spin::Mutex<BTreeMap<u8, &'static str>> = spin::Mutex::new(BTreeMap::new());
+
+/// The IDT entry of every dynamic vector: counts the interrupt and runs its handler.
+extern "x86-interrupt" fn trampoline<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    count(VECTOR);
+    dispatch_vector(VECTOR);
+}
+
+macro_rules! trampolines {
+    ($high: literal) => {
+        [
+            trampoline::<{ $high << 4 }>,
+            trampoline::<{ $high << 4 | 0x1 }>,
+            trampoline::<{ $high << 4 | 0x2 }>,
+            trampoline::<{ $high << 4 | 0x3 }>,
+            trampoline::<{ $high << 4 | 0x4 }>,
+            trampoline::<{ $high << 4 | 0x5 }>,
+            trampoline::<{ $high << 4 | 0x6 }>,
+            trampoline::<{ $high << 4 | 0x7 }>,
+            trampoline::<{ $high << 4 | 0x8 }>,
+            trampoline::<{ $high << 4 | 0x9 }>,
+            trampoline::<{ $high << 4 | 0xa }>,
+            trampoline::<{ $high << 4 | 0xb }>,
+            trampoline::<{ $high << 4 | 0xc }>,
+            trampoline::<{ $high << 4 | 0xd }>,
+            trampoline::<{ $high << 4 | 0xe }>,
+            trampoline::<{ $high << 4 | 0xf }>,
+        ]
+    };
+}
+
+/// The trampolines of `DYNAMIC_VECTORS`, 16 vectors per row.
+const TRAMPOLINES: [[HandlerFunc; 16]; 12] = [
+    trampolines!(0x3),
+    trampolines!(0x4),
+    trampolines!(0x5),
+    trampolines!(0x6),
+    trampolines!(0x7),
+    trampolines!(0x8),
+    trampolines!(0x9),
+    trampolines!(0xa),
+    trampolines!(0xb),
+    trampolines!(0xc),
+    trampolines!(0xd),
+    trampolines!(0xe),
+];
+
+fn dispatch_vector(vector: u8) {
+    let handler = VECTOR_HANDLERS[vector as usize].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+}
+
+/// Allocates a free vector from `DYNAMIC_VECTORS` that runs `handler`, for MSI, local APIC
+/// or software interrupts, and returns it. `None` if all are taken.
+///
+/// The handler runs with interrupts disabled and has to acknowledge the interrupt itself,
+/// unlike with `register_irq` nothing sends an end of interrupt to a controller.
+pub fn alloc_vector(name: &'static str, handler: fn()) -> Option<u8> {
+    let vector = DYNAMIC_VECTORS
+        .filter(|&vector| vector != syscall::SYSCALL_VECTOR)
+        .find(|&vector| {
+            VECTOR_HANDLERS[vector as usize]
+                .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })?;
+    without_interrupts(|| VECTOR_NAMES.lock().insert(vector, name));
+    let index = (vector - DYNAMIC_VECTORS.start) as usize;
+    let trampoline = TRAMPOLINES[index / 16][index % 16];
+    update_idt(|idt| {
+        idt[vector as usize].set_handler_fn(trampoline);
+    });
+    Some(vector)
+}
+
+/// Gives back a vector from `alloc_vector`. Raising it afterwards is a general protection
+/// fault, like for any vector without an entry.
+pub fn free_vector(vector: u8) {
+    assert!(
+        DYNAMIC_VECTORS.contains(&vector) && vector != syscall::SYSCALL_VECTOR,
+        "vector {vector:#x} isn't dynamic"
+    );
+    update_idt(|idt| idt[vector as usize] = Entry::missing());
+    without_interrupts(|| VECTOR_NAMES.lock().remove(&vector));
+    VECTOR_HANDLERS[vector as usize].store(0, Ordering::Release);
+}
+
+/// The allocated vectors with their names.
+pub fn allocated_vectors() -> Vec<(u8, &'static str)> {
+    without_interrupts(|| {
+        VECTOR_NAMES
+            .lock()
+            .iter()
+            .map(|(&vector, &name)| (vector, name))
+            .collect()
+    })
+}
+
+#[test_case]
+fn test_alloc_vector() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn handler() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let first = alloc_vector("test", handler).unwrap();
+    let second = alloc_vector("test", handler).unwrap();
+    assert_ne!(first, second);
+    for vector in [first, second] {
+        assert!(DYNAMIC_VECTORS.contains(&vector));
+        assert_ne!(vector, syscall::SYSCALL_VECTOR);
+        assert!(allocated_vectors().contains(&(vector, "test")));
+    }
+
+    let calls = CALLS.load(Ordering::Relaxed);
+    dispatch_vector(first);
+    assert_eq!(CALLS.load(Ordering::Relaxed), calls + 1);
+
+    free_vector(first);
+    dispatch_vector(first);
+    assert_eq!(CALLS.load(Ordering::Relaxed), calls + 1);
+    assert!(!allocated_vectors().contains(&(first, "test")));
+    // the lowest free vector is handed out first
+    assert_eq!(alloc_vector("test", handler), Some(first));
+    free_vector(first);
+    free_vector(second);
+}
+
+#[test_case]
+fn test_int_reaches_allocated_vector() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn handler() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+    let fired = |vector| {
+        vector_stats()
+            .into_iter()
+            .find(|stat| stat.vector == vector)
+            .map(|stat| stat.count)
+    };
+
+    // `int` takes the vector as an immediate, nothing else allocates vectors in tests
+    let vector = alloc_vector("int", handler).unwrap();
+    assert_eq!(vector, DYNAMIC_VECTORS.start);
+    let before = fired(vector).unwrap();
+    unsafe { core::arch::asm!("int {}", const DYNAMIC_VECTORS.start) };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(fired(vector), Some(before + 1));
+
+    free_vector(vector);
+    assert_eq!(fired(vector), None);
+}
+
+// ╔═══════════════════════════════════════════╗
+// ║                                           ║
+// ║          S T A T I S T I C S              ║
+// ║                                           ║
+// ╚═══════════════════════════════════════════╝
+
+/// Interrupts taken, by vector.
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+/// `time::ticks` when the vector last fired, by vector.
+static LAST_TICKS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Called first thing by every handler. Only touches atomics, so it's safe in any context.
+fn count(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+    LAST_TICKS[vector as usize].store(crate::time::ticks(), Ordering::Relaxed);
+}
+
+/// How often a vector fired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorStat {
+    pub vector: u8,
+    pub name: String,
+    pub count: u64,
+    /// uptime when it last fired, `None` if it never did
+    pub last: Option<Duration>,
+}
+
+/// The vectors with a counting handler: the exceptions, the PIC lines, then the allocated
+/// vectors.
+pub fn vector_stats() -> Vec<VectorStat> {
+    let irqs = (0..16).map(|irq| {
+        let name = match irq {
+            0 => String::from("timer"),
+            1 => String::from("keyboard"),
+            _ => format!("irq{irq}"),
+        };
+        (PIC_1_OFFSET + irq, name)
+    });
+    EXCEPTIONS
+        .iter()
+        .map(|&(vector, name)| (vector, String::from(name)))
+        .chain(irqs)
+        // the cascade never reaches the cpu
+        .filter(|&(vector, _)| vector != PIC_1_OFFSET + 2)
+        .chain(
+            allocated_vectors()
+                .into_iter()
+                .map(|(vector, name)| (vector, String::from(name))),
+        )
+        .map(|(vector, name)| {
+            let count = COUNTS[vector as usize].load(Ordering::Relaxed);
+            let last = LAST_TICKS[vector as usize].load(Ordering::Relaxed);
+            VectorStat {
+                vector,
+                name,
+                count,
+                last: (count > 0).then(|| crate::time::ticks_to_duration(last)),
+            }
+        })
+        .collect()
+}
+
+/// A line per vector with its count, the uptime in seconds when it last fired and its name.
+pub fn format_stats(stats: &[VectorStat]) -> String {
+    let mut out = String::from("VEC      COUNT        LAST NAME\n");
+    for stat in stats {
+        let last = match stat.last {
+            Some(last) => format!("{}.{:03}", last.as_secs(), last.subsec_millis()),
+            None => String::from("-"),
+        };
+        let _ = writeln!(out, "{:>3} {:>10} {:>11} {}", stat.vector, stat.count, last, stat.name);
+    }
+    out
+}
+
+/// Contents of `/proc/interrupts`.
+pub fn interrupts_file() -> String {
+    format_stats(&vector_stats())
+}
+
+#[test_case]
+fn test_counts_exceptions() {
+    let breakpoints = || {
+        vector_stats()
+            .into_iter()
+            .find(|stat| stat.name == "breakpoint")
+            .unwrap()
+    };
+    let before = breakpoints();
+    x86_64::instructions::interrupts::int3();
+    let after = breakpoints();
+    assert_eq!(after.vector, 3);
+    assert_eq!(after.count, before.count + 1);
+    assert!(after.last.is_some());
+
+    let stats = [VectorStat {
+        vector: 32,
+        name: String::from("timer"),
+        count: 1500,
+        last: Some(Duration::from_millis(1499)),
+    }];
+    assert_eq!(
+        format_stats(&stats),
+        "VEC      COUNT        LAST NAME\n 32       1500       1.499 timer\n"
+    );
 }
@@ -0,0 +1,193 @@
+//! Shell jobs, commands running as their own tasks.
+//!
+//! The command line starts every command as a job. While a foreground job runs the shell waits
+//! for it and Ctrl+C interrupts the job, background jobs (`cmd &`) run next to the command
+//! line. A monitor task notices when jobs exit and lets the shell continue.
+//!
+//! A job can be a pipeline, `a | b` runs `a` and `b` as tasks with the stdout of `a` connected
+//! to the stdin of `b` by a pipe.
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    cmdline::{self, args},
+    fd::{self, File},
+    pipe, println,
+    signal::Signal,
+    sync::WaitQueue,
+    task::{self, TaskId},
+};
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// number shown by `jobs` and accepted by `fg`
+    pub number: usize,
+    /// the last task of the pipeline, the job is done when it exits
+    pub task: TaskId,
+    /// every task of the pipeline, in order
+    pub stages: Vec<TaskId>,
+    pub command: String,
+}
+
+struct Jobs {
+    list: Vec<Job>,
+    foreground: Option<usize>,
+}
+
+static JOBS: Mutex<Jobs> = Mutex::new(Jobs {
+    list: Vec::new(),
+    foreground: None,
+});
+/// Woken when the foreground job is done.
+static FOREGROUND_DONE: WaitQueue = WaitQueue::new();
+
+/// Starts the task that reaps finished jobs. Requires the scheduler.
+pub fn init() {
+    task::spawn("jobs", || loop {
+        let mut finished = Vec::new();
+        task::wait_for_exit(|| {
+            finished = take_finished();
+            !finished.is_empty()
+        });
+        for (job, foreground) in finished {
+            if foreground {
+                FOREGROUND_DONE.wake_all();
+            } else {
+                println!("[{}] Done    {}", job.number, job.command);
+            }
+        }
+    });
+}
+
+/// Removes the jobs whose task has exited, returns them with whether they were in the
+/// foreground.
+fn take_finished() -> Vec<(Job, bool)> {
+    without_interrupts(|| {
+        let mut jobs = JOBS.lock();
+        let mut finished = Vec::new();
+        let mut i = 0;
+        while i < jobs.list.len() {
+            if task::has_exited(jobs.list[i].task) {
+                let job = jobs.list.remove(i);
+                let foreground = jobs.foreground == Some(job.number);
+                if foreground {
+                    jobs.foreground = None;
+                }
+                finished.push((job, foreground));
+            } else {
+                i += 1;
+            }
+        }
+        finished
+    })
+}
+
+/// Runs the command `name` as a new job. `name` must be a command known to the command line.
+pub fn start(name: &str, args: Vec<&str>, background: bool) -> Job {
+    start_pipeline(vec![(name, args)], background)
+}
+
+/// Runs a pipeline of commands as a new job, each with its stdout connected to the stdin of the
+/// next one. The commands must be known to the command line.
+pub fn start_pipeline(commands: Vec<(&str, Vec<&str>)>, background: bool) -> Job {
+    let mut command = String::new();
+    for (i, (name, args)) in commands.iter().enumerate() {
+        if i > 0 {
+            command.push_str(" | ");
+        }
+        command.push_str(name);
+        for arg in args {
+            command.push(' ');
+            command.push_str(&args::quote(arg));
+        }
+    }
+
+    without_interrupts(|| {
+        // the tasks only start running once interrupts are back on, after their stdio is set up
+        let mut stages = Vec::new();
+        let mut stdin = None;
+        for (i, (name, args)) in commands.iter().enumerate() {
+            let owned: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+            let cmd = String::from(*name);
+            let task = task::spawn(name, move || {
+                let args = owned.iter().map(String::as_str).collect();
+                cmdline::run_cmd(&cmd, args);
+            });
+            if let Some(reader) = stdin.take() {
+                let _ = fd::set(task, fd::STDIN, Arc::new(File::PipeReader(reader)));
+            }
+            if i + 1 < commands.len() {
+                let (reader, writer) = pipe::pipe();
+                let _ = fd::set(task, fd::STDOUT, Arc::new(File::PipeWriter(writer)));
+                stdin = Some(reader);
+            }
+            stages.push(task);
+        }
+        let mut jobs = JOBS.lock();
+        let number = (1..).find(|n| jobs.list.iter().all(|job| job.number != *n)).unwrap();
+        let job = Job {
+            number,
+            task: *stages.last().expect("empty pipeline"),
+            stages,
+            command,
+        };
+        jobs.list.push(job.clone());
+        if !background {
+            jobs.foreground = Some(number);
+        }
+        job
+    })
+}
+
+/// Returns all running jobs, oldest first.
+pub fn list() -> Vec<Job> {
+    without_interrupts(|| JOBS.lock().list.clone())
+}
+
+/// Returns whether a job occupies the command line.
+pub fn has_foreground() -> bool {
+    without_interrupts(|| JOBS.lock().foreground.is_some())
+}
+
+/// Blocks until no job is in the foreground.
+pub fn wait_foreground() {
+    FOREGROUND_DONE.wait_until(|| JOBS.lock().foreground.is_none());
+}
+
+/// Sends `signal` to every task of the foreground job, returns false if there is none. Safe to
+/// call from interrupt context.
+pub fn signal_foreground(signal: Signal) -> bool {
+    // no allocating here, the interrupted code might hold the heap
+    without_interrupts(|| {
+        let jobs = JOBS.lock();
+        let Some(number) = jobs.foreground else {
+            return false;
+        };
+        match jobs.list.iter().find(|job| job.number == number) {
+            Some(job) => job.stages.iter().fold(false, |sent, task| {
+                task::send_signal(*task, signal).is_ok() || sent
+            }),
+            None => false,
+        }
+    })
+}
+
+/// Moves a job to the foreground and continues it if it was stopped. Without a number the
+/// newest job is picked.
+pub fn foreground(number: Option<usize>) -> Option<Job> {
+    let job = without_interrupts(|| {
+        let mut jobs = JOBS.lock();
+        let job = match number {
+            Some(number) => jobs.list.iter().find(|job| job.number == number),
+            None => jobs.list.last(),
+        }
+        .cloned()?;
+        jobs.foreground = Some(job.number);
+        Some(job)
+    })?;
+    for task in &job.stages {
+        let _ = task::send_signal(*task, Signal::Cont);
+    }
+    Some(job)
+}
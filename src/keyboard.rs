@@ -0,0 +1,284 @@
+//! Keyboard input routing.
+//!
+//! Keys normally go to the console TTY. A full-screen program can grab the keyboard,
+//! after which keys are queued until it reads them with `read_key`. Grabs nest: the task that
+//! grabbed last gets the keys until it releases the keyboard again, or exits.
+//!
+//! Hotkeys registered with `register_hotkey` are checked before that, so they work whoever has
+//! the keyboard. The key that triggered a hotkey isn't delivered.
+//!
+//! Every keyboard decodes keys with `Layout`, which maps them like the layout chosen with
+//! `set_layout`. Only the US layout is built in, the others are loaded from the root file
+//! system, see `keymap`.
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, KeyboardLayout,
+    ScancodeSet,
+};
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    error::{KError, KResult},
+    keymap::{self, Keymap},
+    klog::Level,
+    klogln_at,
+    sync::WaitQueue,
+    task::{self, TaskId},
+    tty, vfs,
+};
+
+/// Keys beyond this are dropped until the reader catches up.
+const QUEUE_SIZE: usize = 64;
+
+/// Tasks that grabbed the keyboard, the last one receives the keys.
+static GRABS: Mutex<Vec<TaskId>> = Mutex::new(Vec::new());
+static QUEUE: Mutex<VecDeque<DecodedKey>> = Mutex::new(VecDeque::new());
+static READERS: WaitQueue = WaitQueue::new();
+
+static CTRL: AtomicBool = AtomicBool::new(false);
+static ALT: AtomicBool = AtomicBool::new(false);
+static SHIFT: AtomicBool = AtomicBool::new(false);
+
+/// Modifier keys, combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Self = Self(0);
+    pub const CTRL: Self = Self(1);
+    pub const ALT: Self = Self(2);
+    pub const SHIFT: Self = Self(4);
+
+    fn current() -> Self {
+        let held = |flag: &AtomicBool, modifier: Self| {
+            if flag.load(Ordering::Relaxed) {
+                modifier
+            } else {
+                Self::NONE
+            }
+        };
+        held(&CTRL, Self::CTRL) | held(&ALT, Self::ALT) | held(&SHIFT, Self::SHIFT)
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// The layout built into the kernel.
+pub const BUILTIN_LAYOUT: &str = "us";
+
+/// The layout chosen with `set_layout` and its name, `None` for the built-in one.
+static KEYMAP: RwLock<Option<(String, Keymap)>> = RwLock::new(None);
+
+/// Maps keys like the layout chosen with `set_layout`.
+pub struct Layout;
+
+impl KeyboardLayout for Layout {
+    fn map_keycode(
+        keycode: KeyCode,
+        modifiers: &pc_keyboard::Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        // never waits, keys are decoded in interrupt handlers
+        if let Some(keymap) = KEYMAP.try_read() {
+            let key = keymap
+                .as_ref()
+                .and_then(|(_, keymap)| keymap.map(keycode, modifiers, handle_ctrl));
+            if let Some(key) = key {
+                return key;
+            }
+        }
+        layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl)
+    }
+}
+
+/// Switches every keyboard to the layout called `name`, `BUILTIN_LAYOUT` or one in
+/// `keymap::DIR`. Keeps the current layout if that can't be loaded.
+pub fn set_layout(name: &str) -> KResult<()> {
+    if name == BUILTIN_LAYOUT {
+        without_interrupts(|| *KEYMAP.write() = None);
+        return Ok(());
+    }
+    if name.is_empty() || name.contains('/') {
+        return Err(KError::InvalidArgument);
+    }
+    let path = keymap::path(name);
+    let data = vfs::read(&path)?;
+    let keymap = Keymap::parse(&String::from_utf8_lossy(&data)).map_err(|line| {
+        klogln_at!(Level::Warn, "{}:{}: invalid key", path, line);
+        KError::InvalidArgument
+    })?;
+    without_interrupts(|| *KEYMAP.write() = Some((String::from(name), keymap)));
+    Ok(())
+}
+
+pub fn layout() -> String {
+    without_interrupts(|| match &*KEYMAP.read() {
+        Some((name, _)) => name.clone(),
+        None => String::from(BUILTIN_LAYOUT),
+    })
+}
+
+/// Called from the keyboard interrupt with interrupts disabled, so it must not block. Anything
+/// longer should wake a task, like `taskmgr::open` does.
+pub type HotkeyFn = fn();
+
+struct Hotkey {
+    modifiers: Modifiers,
+    key: KeyCode,
+    callback: HotkeyFn,
+}
+
+static HOTKEYS: Mutex<Vec<Hotkey>> = Mutex::new(Vec::new());
+
+/// Calls `callback` whenever `key` is pressed with exactly `modifiers` held. Fails with
+/// `AlreadyExists` if the combination is taken.
+pub fn register_hotkey(modifiers: Modifiers, key: KeyCode, callback: HotkeyFn) -> KResult<()> {
+    without_interrupts(|| {
+        let mut hotkeys = HOTKEYS.lock();
+        if hotkeys.iter().any(|h| h.modifiers == modifiers && h.key == key) {
+            return Err(KError::AlreadyExists);
+        }
+        hotkeys.push(Hotkey {
+            modifiers,
+            key,
+            callback,
+        });
+        Ok(())
+    })
+}
+
+pub fn unregister_hotkey(modifiers: Modifiers, key: KeyCode) {
+    without_interrupts(|| {
+        HOTKEYS
+            .lock()
+            .retain(|h| h.modifiers != modifiers || h.key != key)
+    });
+}
+
+fn find_hotkey(modifiers: Modifiers, key: KeyCode) -> Option<HotkeyFn> {
+    HOTKEYS
+        .lock()
+        .iter()
+        .find(|h| h.modifiers == modifiers && h.key == key)
+        .map(|h| h.callback)
+}
+
+/// Routes keys to the current task instead of the console TTY.
+pub fn grab() {
+    without_interrupts(|| {
+        QUEUE.lock().clear();
+        GRABS.lock().push(task::current_id());
+    });
+}
+
+/// Ends the current task's grab, keys go back to whoever had them before.
+pub fn release() {
+    let current = task::current_id();
+    without_interrupts(|| {
+        let mut grabs = GRABS.lock();
+        if let Some(pos) = grabs.iter().rposition(|id| *id == current) {
+            grabs.remove(pos);
+        }
+        QUEUE.lock().clear();
+    });
+    READERS.wake_all();
+}
+
+/// Drops the grabs of an exiting task, which can't release them itself anymore.
+pub fn release_task(task: TaskId) {
+    without_interrupts(|| {
+        let mut grabs = GRABS.lock();
+        let owned = grabs.last() == Some(&task);
+        grabs.retain(|id| *id != task);
+        if owned {
+            QUEUE.lock().clear();
+        }
+    });
+    READERS.wake_all();
+}
+
+pub fn is_grabbed() -> bool {
+    without_interrupts(|| !GRABS.lock().is_empty())
+}
+
+fn owner() -> Option<TaskId> {
+    GRABS.lock().last().copied()
+}
+
+/// Tracks modifiers and runs hotkeys. Returns true if the event triggered one, in which case
+/// the key shouldn't be delivered.
+pub fn handle_event(event: &KeyEvent) -> bool {
+    let down = event.state == KeyState::Down;
+    match event.code {
+        KeyCode::ControlLeft | KeyCode::ControlRight => CTRL.store(down, Ordering::Relaxed),
+        KeyCode::AltLeft | KeyCode::AltRight => ALT.store(down, Ordering::Relaxed),
+        KeyCode::ShiftLeft | KeyCode::ShiftRight => SHIFT.store(down, Ordering::Relaxed),
+        code if down => {
+            // the lock is released before the call, so hotkeys can (un)register hotkeys
+            if let Some(callback) = find_hotkey(Modifiers::current(), code) {
+                callback();
+                return true;
+            }
+        }
+        _ => {}
+    }
+    false
+}
+
+/// Decodes a key event from any keyboard and routes the key. Called with interrupts disabled.
+pub fn process_event<L, S>(keyboard: &mut Keyboard<L, S>, event: KeyEvent)
+where
+    L: KeyboardLayout,
+    S: ScancodeSet,
+{
+    let hotkey = handle_event(&event);
+    if let Some(key) = keyboard.process_keyevent(event) {
+        if !hotkey {
+            handle_key(key);
+        }
+    }
+}
+
+/// Called from the keyboard interrupt with every decoded key.
+pub fn handle_key(key: DecodedKey) {
+    if owner().is_none() {
+        tty::CONSOLE.input(key);
+        return;
+    }
+    let mut queue = QUEUE.lock();
+    if queue.len() < QUEUE_SIZE {
+        queue.push_back(key);
+    }
+    drop(queue);
+    READERS.wake_all_boosted();
+}
+
+/// Returns a pending key for the current task without blocking.
+pub fn try_read_key() -> Option<DecodedKey> {
+    let current = task::current_id();
+    without_interrupts(|| {
+        if owner() != Some(current) {
+            return None;
+        }
+        QUEUE.lock().pop_front()
+    })
+}
+
+/// Blocks until a key is pressed. Only returns while the current task holds the keyboard.
+pub fn read_key() -> DecodedKey {
+    let mut key = None;
+    READERS.wait_until(|| {
+        key = try_read_key();
+        key.is_some()
+    });
+    key.unwrap()
+}
@@ -0,0 +1,228 @@
+//! Keyboard layouts.
+//!
+//! Only the US layout is built in. The others are text files in `/usr/share/keymaps` on the
+//! root file system, `<name>.map`, listing the keys that differ from the US layout, one per
+//! line: `<key> <normal> <shift> [<altgr>]`. A key is named by what it types on a US keyboard
+//! without shift, or `space`. Characters are written as themselves, as `U+20AC` or as `-` for
+//! none, and `#` starts a comment, so `#` itself has to be written `U+0023`. Caps lock shifts
+//! the keys that type a letter.
+use alloc::{format, string::String, vec::Vec};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Modifiers};
+
+pub const DIR: &str = "/usr/share/keymaps";
+
+/// The characters a key types without and with shift and with AltGr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    normal: Option<char>,
+    shift: Option<char>,
+    altgr: Option<char>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    keys: Vec<(KeyCode, Entry)>,
+}
+
+/// Returns the file the layout called `name` is loaded from.
+pub fn path(name: &str) -> String {
+    format!("{DIR}/{name}.map")
+}
+
+/// Returns the key that types `name` on a US keyboard, see the module documentation.
+fn key_code(name: &str) -> Option<KeyCode> {
+    if name == "space" {
+        return Some(KeyCode::Spacebar);
+    }
+    let mut chars = name.chars();
+    let (Some(c), None) = (chars.next(), chars.next()) else {
+        return None;
+    };
+    Some(match c {
+        'a' => KeyCode::A,
+        'b' => KeyCode::B,
+        'c' => KeyCode::C,
+        'd' => KeyCode::D,
+        'e' => KeyCode::E,
+        'f' => KeyCode::F,
+        'g' => KeyCode::G,
+        'h' => KeyCode::H,
+        'i' => KeyCode::I,
+        'j' => KeyCode::J,
+        'k' => KeyCode::K,
+        'l' => KeyCode::L,
+        'm' => KeyCode::M,
+        'n' => KeyCode::N,
+        'o' => KeyCode::O,
+        'p' => KeyCode::P,
+        'q' => KeyCode::Q,
+        'r' => KeyCode::R,
+        's' => KeyCode::S,
+        't' => KeyCode::T,
+        'u' => KeyCode::U,
+        'v' => KeyCode::V,
+        'w' => KeyCode::W,
+        'x' => KeyCode::X,
+        'y' => KeyCode::Y,
+        'z' => KeyCode::Z,
+        '1' => KeyCode::Key1,
+        '2' => KeyCode::Key2,
+        '3' => KeyCode::Key3,
+        '4' => KeyCode::Key4,
+        '5' => KeyCode::Key5,
+        '6' => KeyCode::Key6,
+        '7' => KeyCode::Key7,
+        '8' => KeyCode::Key8,
+        '9' => KeyCode::Key9,
+        '0' => KeyCode::Key0,
+        '`' => KeyCode::BackTick,
+        '-' => KeyCode::Minus,
+        '=' => KeyCode::Equals,
+        '[' => KeyCode::BracketSquareLeft,
+        ']' => KeyCode::BracketSquareRight,
+        '\\' => KeyCode::BackSlash,
+        ';' => KeyCode::SemiColon,
+        '\'' => KeyCode::Quote,
+        ',' => KeyCode::Comma,
+        '.' => KeyCode::Fullstop,
+        '/' => KeyCode::Slash,
+        _ => return None,
+    })
+}
+
+/// Parses a character of an entry, `Err` if it isn't one.
+fn parse_char(word: &str) -> Result<Option<char>, ()> {
+    if word == "-" {
+        return Ok(None);
+    }
+    if let Some(hex) = word.strip_prefix("U+") {
+        return u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .map(Some)
+            .ok_or(());
+    }
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Some(c)),
+        _ => Err(()),
+    }
+}
+
+impl Keymap {
+    /// Parses a keymap file. Returns the number of the first invalid line on error, starting
+    /// at 1.
+    pub fn parse(text: &str) -> Result<Self, usize> {
+        let mut keys: Vec<(KeyCode, Entry)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (key, chars) = match words[..] {
+                [] => continue,
+                [key, ref chars @ ..] if (2..=3).contains(&chars.len()) => (key, chars),
+                _ => return Err(i + 1),
+            };
+            let code = key_code(key).ok_or(i + 1)?;
+            let char_at = |at: usize| chars.get(at).map_or(Ok(None), |word| parse_char(word));
+            let entry = match (char_at(0), char_at(1), char_at(2)) {
+                (Ok(normal), Ok(shift), Ok(altgr)) => Entry {
+                    normal,
+                    shift,
+                    altgr,
+                },
+                _ => return Err(i + 1),
+            };
+            keys.retain(|(other, _)| *other != code);
+            keys.push((code, entry));
+        }
+        Ok(Self { keys })
+    }
+
+    /// Decodes `keycode`, `None` if the keymap leaves it as in the US layout.
+    pub fn map(
+        &self,
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> Option<DecodedKey> {
+        let (_, entry) = self.keys.iter().find(|(code, _)| *code == keycode)?;
+        let letter = entry.normal.is_some_and(char::is_alphabetic);
+        let shifted = if letter {
+            modifiers.is_caps()
+        } else {
+            modifiers.is_shifted()
+        };
+        let c = if modifiers.alt_gr {
+            entry.altgr
+        } else if shifted {
+            entry.shift
+        } else {
+            entry.normal
+        };
+        let Some(c) = c else {
+            return Some(DecodedKey::RawKey(keycode));
+        };
+        if handle_ctrl == HandleControl::MapLettersToUnicode
+            && modifiers.is_ctrl()
+            && c.is_ascii_alphabetic()
+        {
+            // Ctrl+A is U+0001 and so on
+            let control = c.to_ascii_lowercase() as u8 - b'a' + 1;
+            return Some(DecodedKey::Unicode(control as char));
+        }
+        Some(DecodedKey::Unicode(c))
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse() {
+    let keymap =
+        Keymap::parse("# azerty\nq a A\n2 é 2 ~\n\n3 \" 3 U+0023\n; m M # comment\n").unwrap();
+    let mut modifiers = Modifiers {
+        lshift: false,
+        rshift: false,
+        lctrl: false,
+        rctrl: false,
+        numlock: false,
+        capslock: false,
+        alt_gr: false,
+    };
+    let map = |keycode, modifiers: &Modifiers| {
+        keymap.map(keycode, modifiers, HandleControl::MapLettersToUnicode)
+    };
+    assert_eq!(map(KeyCode::Q, &modifiers), Some(DecodedKey::Unicode('a')));
+    assert_eq!(
+        map(KeyCode::Key2, &modifiers),
+        Some(DecodedKey::Unicode('é'))
+    );
+    assert_eq!(map(KeyCode::W, &modifiers), None);
+    modifiers.capslock = true;
+    assert_eq!(
+        map(KeyCode::SemiColon, &modifiers),
+        Some(DecodedKey::Unicode('M'))
+    );
+    assert_eq!(
+        map(KeyCode::Key3, &modifiers),
+        Some(DecodedKey::Unicode('"'))
+    );
+    modifiers.capslock = false;
+    modifiers.alt_gr = true;
+    assert_eq!(
+        map(KeyCode::Key3, &modifiers),
+        Some(DecodedKey::Unicode('#'))
+    );
+    assert_eq!(
+        map(KeyCode::Q, &modifiers),
+        Some(DecodedKey::RawKey(KeyCode::Q))
+    );
+    modifiers.alt_gr = false;
+    modifiers.lctrl = true;
+    assert_eq!(
+        map(KeyCode::Q, &modifiers),
+        Some(DecodedKey::Unicode('\u{1}'))
+    );
+
+    assert_eq!(Keymap::parse("q a A\nq\n").err(), Some(2));
+    assert_eq!(Keymap::parse("F1 a A\n").err(), Some(1));
+    assert_eq!(Keymap::parse("q ab A\n").err(), Some(1));
+}
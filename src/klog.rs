@@ -0,0 +1,133 @@
+//! Kernel log ring buffer.
+//!
+//! Messages are kept in a fixed-size ring, the oldest bytes get overwritten once it is full.
+//! `klogln!` always logs, `klogln_at!` only logs messages at or below the `loglevel` tunable.
+use alloc::string::String;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{config, debugcon};
+
+pub use config::LOG_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "error" | "0" => Some(Self::Error),
+            "warn" | "1" => Some(Self::Warn),
+            "info" | "2" => Some(Self::Info),
+            "debug" | "3" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+/// Returns whether messages at `level` get logged.
+pub fn enabled(level: Level) -> bool {
+    level <= config::get().log_level
+}
+
+struct LogRing {
+    buf: [u8; LOG_SIZE],
+    /// total number of bytes ever written, the write position is `written % LOG_SIZE`
+    written: u64,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_SIZE],
+            written: 0,
+        }
+    }
+
+    /// Oldest position still available in the ring.
+    fn oldest(&self) -> u64 {
+        self.written.saturating_sub(LOG_SIZE as u64)
+    }
+}
+
+impl Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.buf[(self.written % LOG_SIZE as u64) as usize] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+static LOG: Mutex<LogRing> = Mutex::new(LogRing::new());
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    without_interrupts(|| {
+        let _ = LOG.lock().write_fmt(args);
+    });
+    debugcon::_print(args);
+}
+
+/// Returns the current end of the log, to be passed to `read_since` later.
+pub fn position() -> u64 {
+    without_interrupts(|| LOG.lock().written)
+}
+
+/// Returns everything logged since `pos`, or since the oldest retained byte if `pos` was
+/// already overwritten.
+pub fn read_since(pos: u64) -> String {
+    without_interrupts(|| {
+        let log = LOG.lock();
+        let start = pos.max(log.oldest());
+        let mut out = String::with_capacity((log.written - start.min(log.written)) as usize);
+        for i in start..log.written {
+            out.push(log.buf[(i % LOG_SIZE as u64) as usize] as char);
+        }
+        out
+    })
+}
+
+/// Returns the whole retained log.
+pub fn read_all() -> String {
+    read_since(0)
+}
+
+/// Writes to the kernel log ring.
+#[macro_export]
+macro_rules! klog {
+    ($($arg:tt)*) => ($crate::klog::_log(format_args!($($arg)*)));
+}
+
+/// Writes to the kernel log ring, appending a newline.
+#[macro_export]
+macro_rules! klogln {
+    () => ($crate::klog!("\n"));
+    ($($arg:tt)*) => ($crate::klog::_log(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Writes a line to the kernel log ring if `$level` is enabled.
+#[macro_export]
+macro_rules! klogln_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::klog::enabled($level) {
+            $crate::klogln!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,186 @@
+//! Kernel symbol table and backtraces.
+//!
+//! The image reserves the `.ksyms` section, which `tools/ksyms.py` fills with the symbols of
+//! the linked kernel after every build. Until it ran, the table is empty and `resolve` always
+//! returns `None`.
+//!
+//! Layout of the table (little endian):
+//! - header: magic `SKYKSYM1`, symbol count (u32), offset of the name data (u32)
+//! - one entry per symbol, sorted by address: address (u64), offset of its name (u32), padding
+//! - names, front coded: length of the prefix shared with the previous name (u8), length of
+//!   the remaining suffix (u8), suffix bytes. Every `RESTART_INTERVAL`th name is stored in full.
+use core::arch::asm;
+use core::fmt;
+use core::ptr::addr_of;
+
+use crate::println;
+
+pub const KSYMS_SIZE: usize = 256 * 1024;
+pub const MAGIC: &[u8; 8] = b"SKYKSYM1";
+pub const MAX_NAME: usize = 255;
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+const RESTART_INTERVAL: usize = 16;
+/// Maximum number of frames printed in a backtrace.
+const MAX_FRAMES: usize = 32;
+
+#[used]
+#[link_section = ".ksyms"]
+static mut KSYMS: [u8; KSYMS_SIZE] = {
+    let mut table = [0; KSYMS_SIZE];
+    let mut i = 0;
+    while i < MAGIC.len() {
+        table[i] = MAGIC[i];
+        i += 1;
+    }
+    table
+};
+
+fn table() -> &'static [u8] {
+    // Only ever written by the host tool, the kernel never modifies it.
+    unsafe { &*addr_of!(KSYMS) }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// Returns the number of symbols in the table.
+pub fn count() -> usize {
+    let data = table();
+    if &data[..8] != MAGIC {
+        return 0;
+    }
+    (read_u32(data, 8) as usize).min((KSYMS_SIZE - HEADER_SIZE) / ENTRY_SIZE)
+}
+
+fn address(index: usize) -> u64 {
+    read_u64(table(), HEADER_SIZE + index * ENTRY_SIZE)
+}
+
+/// A resolved symbol. The name is copied out of the table so no allocation is needed, which
+/// keeps resolving usable from the panic handler.
+#[derive(Clone, Copy)]
+pub struct Symbol {
+    name: [u8; MAX_NAME],
+    len: usize,
+    /// start address of the symbol
+    pub addr: u64,
+    /// offset of the resolved address into the symbol
+    pub offset: u64,
+}
+
+impl Symbol {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("<invalid>")
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name(), self.offset)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:#x})", self, self.addr)
+    }
+}
+
+/// Decodes the name of symbol `index` into `symbol`.
+fn decode_name(index: usize, symbol: &mut Symbol) -> Option<()> {
+    let data = table();
+    let names = read_u32(data, 12) as usize;
+    for i in index - index % RESTART_INTERVAL..=index {
+        let at = names + read_u32(data, HEADER_SIZE + i * ENTRY_SIZE + 8) as usize;
+        let prefix = *data.get(at)? as usize;
+        let suffix = *data.get(at + 1)? as usize;
+        let bytes = data.get(at + 2..at + 2 + suffix)?;
+        if prefix > symbol.len || prefix + suffix > MAX_NAME {
+            return None;
+        }
+        symbol.name[prefix..prefix + suffix].copy_from_slice(bytes);
+        symbol.len = prefix + suffix;
+    }
+    Some(())
+}
+
+/// Finds the symbol containing `addr`.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    let count = count();
+    if count == 0 || addr < address(0) {
+        return None;
+    }
+    // last symbol starting at or before addr
+    let (mut low, mut high) = (0, count);
+    while high - low > 1 {
+        let mid = (low + high) / 2;
+        if address(mid) <= addr {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    let mut symbol = Symbol {
+        name: [0; MAX_NAME],
+        len: 0,
+        addr: address(low),
+        offset: addr - address(low),
+    };
+    decode_name(low, &mut symbol)?;
+    Some(symbol)
+}
+
+/// Returns the address of the symbol called `name`. Scans the whole table, so it is only meant
+/// for rare lookups like linking modules.
+pub fn lookup(name: &str) -> Option<u64> {
+    let mut symbol = Symbol {
+        name: [0; MAX_NAME],
+        len: 0,
+        addr: 0,
+        offset: 0,
+    };
+    (0..count()).find_map(|i| {
+        decode_name(i, &mut symbol)?;
+        (symbol.name() == name).then(|| address(i))
+    })
+}
+
+/// Calls `f` with the return address of every frame on the current stack, innermost first.
+///
+/// Relies on frame pointers, which the target spec forces on.
+pub fn walk_stack<F: FnMut(u64)>(mut f: F) {
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    for _ in 0..MAX_FRAMES {
+        // the first frame of every task has a zero frame pointer
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next, ret) = unsafe { (frame.read(), frame.add(1).read()) };
+        if ret == 0 {
+            break;
+        }
+        f(ret);
+        // stacks grow down, so a caller's frame is always above ours
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Prints a symbolized backtrace of the current stack.
+pub fn print_backtrace() {
+    println!("Backtrace:");
+    walk_stack(|addr| match resolve(addr) {
+        Some(symbol) => println!("  {:#018x} {}", addr, symbol),
+        None => println!("  {:#018x} ?", addr),
+    });
+}
@@ -5,16 +5,74 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+pub mod error;
+pub mod config;
+pub mod device;
 pub mod drivers;
+pub mod net;
 pub mod pci;
+pub mod acpi;
 pub mod mem;
+pub mod mmap;
+pub mod memprotect;
 pub mod gdt;
 pub mod interrupts;
 pub mod serial;
 pub mod vga_buffer;
+pub mod font;
 pub mod allocator;
 pub mod ext;
+pub mod fault;
 pub mod cmdline;
+pub mod task;
+pub mod preempt;
+pub mod sync;
+pub mod futex;
+pub mod pipe;
+pub mod fd;
+pub mod tty;
+pub mod time;
+pub mod clock;
+pub mod tsc;
+pub mod timer;
+pub mod idle;
+pub mod cpufreq;
+pub mod klog;
+pub mod syscall;
+pub mod syscall_table;
+pub mod ksyms;
+pub mod module;
+pub mod exec;
+pub mod profile;
+pub mod bench;
+pub mod vfs;
+pub mod keyboard;
+pub mod keymap;
+pub mod editor;
+pub mod boot;
+pub mod bootargs;
+pub mod initrd;
+pub mod archive;
+pub mod fileshare;
+pub mod compress;
+pub mod crypto;
+pub mod debugcon;
+pub mod hwdebug;
+pub mod early_console;
+pub mod theme;
+pub mod bootreport;
+pub mod taskmgr;
+pub mod screenshot;
+pub mod signal;
+pub mod jobs;
+pub mod sysconf;
+pub mod initd;
+pub mod power;
+pub mod suspend;
+pub mod thermal;
+pub mod quirks;
+pub mod selftest;
+pub mod testing;
 mod init;
 pub use init::*;
 
@@ -23,10 +81,21 @@ use core::panic::PanicInfo;
 extern crate alloc;
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::BootInfo;
 
+/// A test for `test_runner`, see `testing`.
 pub trait Testable {
     fn run(&self) -> ();
+
+    fn name(&self) -> &'static str;
+
+    fn subsystem(&self) -> &'static str {
+        testing::subsystem_of(self.name())
+    }
+
+    fn fixtures(&self) -> &'static [testing::Fixture] {
+        &[]
+    }
 }
 
 pub fn hlt_loop() -> ! {
@@ -40,17 +109,16 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
         self();
-        serial_println!("[ok]");
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
     }
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
+    testing::run(tests);
     exit_qemu(QemuExitCode::Success);
 }
 
@@ -63,7 +131,7 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 
 
 #[cfg(test)]
-entry_point!(test_kernel_main);
+kernel_entry!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
@@ -6,32 +6,34 @@
 
 extern crate alloc;
 
-use bootloader::{entry_point, BootInfo};
-use skyos::pci::PCIManager;
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
-use skyos::cmdline::CMD_LINE;
 use skyos::vga_buffer::enable_cursor;
-use skyos::{hlt_loop, init_memory, println, shared_init};
-use x86_64::instructions::interrupts::without_interrupts;
+use skyos::idle::idle_loop;
+use skyos::{initd, init_devices, init_memory, println, shared_init};
 
 fn run(boot_info: &'static BootInfo) {
     enable_cursor();
     shared_init();
     init_memory(boot_info);
-    
-    PCIManager::new().scan();
+    init_devices();
 
-    without_interrupts(|| CMD_LINE.lock().init());
+    initd::start();
 
-    hlt_loop();
+    idle_loop();
 }
 
 fn panic_handler(info: &PanicInfo) -> ! {
+    skyos::vga_buffer::unlock_for_panic();
     println!("{info}");
+    if let Some(test) = skyos::selftest::running() {
+        println!("selftest {test} failed");
+    }
+    skyos::ksyms::print_backtrace();
     skyos::hlt_loop();
 }
 
-entry_point!(kernel_main);
+skyos::kernel_entry!(kernel_main);
 
 #[allow(unreachable_code)]
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
@@ -1,20 +1,176 @@
+use alloc::vec::Vec;
 use core::cell::LazyCell;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::{
+    instructions::interrupts::without_interrupts,
     structures::paging::{
-        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB
+        mapper::{FlagUpdateError, MapToError, Translate, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+/// Where the bootloader mapped the physical memory, 0 until `init` was called.
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 pub unsafe fn init(offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYS_MEM_OFFSET.store(offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(offset);
     OffsetPageTable::new(level_4_table, offset)
 }
 
+/// Returns the virtual address a physical address is reachable at through the physical
+/// memory mapping, `None` before `init`.
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    match PHYS_MEM_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset + addr.as_u64())),
+    }
+}
+
+/// Like `phys_to_virt`, but also checks that the page is actually mapped. The bootloader only
+/// maps the memory listed in the memory map, which leaves out most device memory.
+pub fn phys_to_mapped_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    let virt = phys_to_virt(addr)?;
+    let offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    // Only reads the page tables, nothing else holds on to them after boot.
+    let table = unsafe { init(offset) };
+    match table.translate_addr(virt) {
+        Some(phys) if phys == addr => Some(virt),
+        _ => None,
+    }
+}
+
+/// Returns the physical address backing a virtual address, e.g. for handing heap memory to a
+/// device doing DMA.
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    let offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    if offset.as_u64() == 0 {
+        return None;
+    }
+    let table = unsafe { init(offset) };
+    table.translate_addr(addr)
+}
+
+/// Changes whether the pages covering `start..start + len` are writable. Only works for memory
+/// the kernel mapped itself in 4 KiB pages, like the heap. Writable pages are not executable
+/// and read-only ones are, so loaded code can run once it can't be changed anymore.
+pub fn set_writable(start: VirtAddr, len: usize, writable: bool) -> Result<(), FlagUpdateError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let mut table = unsafe { init(offset) };
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE | crate::memprotect::no_execute();
+    }
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::containing_address(start + (len - 1));
+    for page in Page::range_inclusive(first, last) {
+        unsafe { table.update_flags(page, flags)?.flush() };
+    }
+    Ok(())
+}
+
 pub const PAGE_SIZE: usize = 4096;
 
+/// Frames for memory mapped after boot: those the boot allocator didn't hand out while
+/// setting up the heap, plus the ones given back with `free_frame`.
+struct Frames {
+    boot: Option<BootInfoFrameAllocator>,
+    free: Vec<PhysFrame>,
+}
+
+static FRAMES: Mutex<Frames> = Mutex::new(Frames {
+    boot: None,
+    free: Vec::new(),
+});
+
+/// Hands the boot frame allocator over to `alloc_frame`, once the heap is mapped.
+pub fn set_frame_allocator(allocator: BootInfoFrameAllocator) {
+    without_interrupts(|| FRAMES.lock().boot = Some(allocator));
+}
+
+/// Returns an unused frame. Its contents are undefined.
+pub fn alloc_frame() -> Option<PhysFrame> {
+    without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        match frames.free.pop() {
+            Some(frame) => Some(frame),
+            None => frames.boot.as_mut()?.allocate_frame(),
+        }
+    })
+}
+
+/// Gives back a frame from `alloc_frame` that isn't mapped anywhere anymore.
+pub fn free_frame(frame: PhysFrame) {
+    without_interrupts(|| FRAMES.lock().free.push(frame));
+}
+
+/// Allocates the frames for page tables with `alloc_frame`.
+struct GlobalFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        alloc_frame()
+    }
+}
+
+fn page_table() -> OffsetPageTable<'static> {
+    let offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    unsafe { init(offset) }
+}
+
+/// Maps `page` to `frame` in the active page table.
+pub fn map_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    without_interrupts(|| {
+        unsafe { page_table().map_to(page, frame, flags, &mut GlobalFrames)? }.flush();
+        Ok(())
+    })
+}
+
+/// Unmaps `page`, returning the frame it was mapped to. `None` if it wasn't mapped.
+pub fn unmap_page(page: Page) -> Option<PhysFrame> {
+    without_interrupts(|| {
+        let (frame, flush) = page_table().unmap(page).ok()?;
+        flush.flush();
+        Some(frame)
+    })
+}
+
+pub fn update_page_flags(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    without_interrupts(|| {
+        unsafe { page_table().update_flags(page, flags)? }.flush();
+        Ok(())
+    })
+}
+
+/// Returns the frame `page` is mapped to.
+pub fn translate_page(page: Page) -> Option<PhysFrame> {
+    page_table().translate_page(page).ok()
+}
+
+/// Returns the flags of the page containing `addr`, `None` if it isn't mapped. Unlike
+/// `translate_page` this also works for huge pages.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    if PHYS_MEM_OFFSET.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    match page_table().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -54,11 +210,11 @@ impl BootInfoFrameAllocator {
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) {
-        let me = BootInfoFrameAllocator {
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        };
+        }
     }
 
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
@@ -0,0 +1,224 @@
+//! Kernel memory protection.
+//!
+//! The bootloader maps the kernel image with the permissions of its ELF segments: code
+//! read-only and executable, read-only data read-only, data writable, and neither kind of data
+//! executable. Memory the kernel maps itself is never writable and executable at once: the heap
+//! and anonymous mappings are not executable, and module code is made read-only before it
+//! runs, see `mem::set_writable`.
+//!
+//! `init` makes the cpu enforce that, NXE in EFER enables the no-execute bit and WP in CR0
+//! makes read-only pages read-only in ring 0 too. It then takes execute permission from every
+//! other writable page, like the mapping of all physical memory the bootloader sets up.
+//! `regions` walks the page tables for the `memprotect` command, to check the result.
+use alloc::vec::Vec;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Once;
+use x86_64::{
+    instructions::{interrupts::without_interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{page_table::PageTableEntry, PageTable, PageTableFlags},
+    PhysAddr,
+};
+
+use crate::{allocator, mem, mmap};
+
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Kernel,
+    KernelStack,
+    Heap,
+    Mmap,
+    /// the mapping of all physical memory
+    PhysicalMemory,
+    Other,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Kernel => "kernel",
+            Self::KernelStack => "kernel stack",
+            Self::Heap => "heap",
+            Self::Mmap => "mmap",
+            Self::PhysicalMemory => "physical memory",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Consecutive pages with the same permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub size: u64,
+    pub writable: bool,
+    pub executable: bool,
+    pub user: bool,
+    pub kind: Kind,
+}
+
+impl Region {
+    /// Permissions like `/proc/<pid>/maps` shows them.
+    pub fn permissions(&self) -> [char; 4] {
+        [
+            'r',
+            if self.writable { 'w' } else { '-' },
+            if self.executable { 'x' } else { '-' },
+            if self.user { 'u' } else { '-' },
+        ]
+    }
+}
+
+/// Returns whether the no-execute bit of page table entries is enabled.
+pub fn nx_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Returns whether ring 0 honors read-only pages.
+pub fn wp_enabled() -> bool {
+    Cr0::read().contains(Cr0Flags::WRITE_PROTECT)
+}
+
+/// The flag making a page non-executable, empty if the cpu has no-execute disabled, where the
+/// bit is reserved.
+pub fn no_execute() -> PageTableFlags {
+    if nx_enabled() {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Enables NXE and WP and removes execute permission from writable pages outside the kernel
+/// image. Requires `mem::init`.
+pub fn init(memory_map: &'static MemoryMap) {
+    MEMORY_MAP.call_once(|| memory_map);
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    walk(&mut |start, size, flags, entry| {
+        let writable = flags.contains(PageTableFlags::WRITABLE);
+        let executable = !flags.contains(PageTableFlags::NO_EXECUTE);
+        // a writable and executable kernel segment is the linker's doing, leave it running
+        if writable && executable && kind_of(start, size, entry.addr()) != Kind::Kernel {
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+    });
+    tlb::flush_all();
+}
+
+/// Returns whether the `size` bytes at `addr` overlap a region of `kind`. Huge pages, like the
+/// identity mapping the Multiboot2 entry runs the kernel from, can start before it.
+fn in_region(addr: PhysAddr, size: u64, kind: MemoryRegionType) -> bool {
+    let Some(map) = MEMORY_MAP.r#try() else {
+        return false;
+    };
+    map.iter().any(|region| {
+        region.region_type == kind
+            && region.range.start_addr() < addr.as_u64() + size
+            && addr.as_u64() < region.range.end_addr()
+    })
+}
+
+fn kind_of(start: u64, size: u64, frame: PhysAddr) -> Kind {
+    let heap =
+        allocator::HEAP_START as u64..(allocator::HEAP_START + allocator::heap_size()) as u64;
+    let physical = mem::phys_to_virt(PhysAddr::new(0)).map(|offset| {
+        let end = MEMORY_MAP
+            .r#try()
+            .and_then(|map| map.iter().map(|region| region.range.end_addr()).max())
+            .unwrap_or(0);
+        offset.as_u64()..offset.as_u64() + end
+    });
+    if heap.contains(&start) {
+        Kind::Heap
+    } else if (mmap::MMAP_START..mmap::MMAP_END).contains(&start) {
+        Kind::Mmap
+    } else if physical.is_some_and(|physical| physical.contains(&start)) {
+        Kind::PhysicalMemory
+    } else if in_region(frame, size, MemoryRegionType::Kernel) {
+        Kind::Kernel
+    } else if in_region(frame, size, MemoryRegionType::KernelStack) {
+        Kind::KernelStack
+    } else {
+        Kind::Other
+    }
+}
+
+/// Calls `f` with the address, size, effective flags and entry of every mapped page. Flags are
+/// effective as the cpu sees them: writable and user accessible only if every level says so,
+/// not executable if any level says so.
+fn walk(f: &mut impl FnMut(u64, u64, PageTableFlags, &mut PageTableEntry)) {
+    let Some(table) = mem::phys_to_virt(Cr3::read().0.start_address()) else {
+        return;
+    };
+    let table = unsafe { &mut *table.as_mut_ptr::<PageTable>() };
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    // like the functions of `mem`, so nothing changes the tables underneath
+    without_interrupts(|| walk_table(table, 4, 0, inherited, f));
+}
+
+fn walk_table(
+    table: &mut PageTable,
+    level: u32,
+    base: u64,
+    inherited: PageTableFlags,
+    f: &mut impl FnMut(u64, u64, PageTableFlags, &mut PageTableEntry),
+) {
+    let size = 1u64 << (12 + 9 * (level - 1));
+    for (i, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let mut start = base + i as u64 * size;
+        if level == 4 && i >= 256 {
+            // the upper half, sign extended
+            start |= 0xffff_0000_0000_0000;
+        }
+        let both = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let effective =
+            (inherited & flags & both) | ((inherited | flags) & PageTableFlags::NO_EXECUTE);
+        if level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            f(start, size, effective, entry);
+            continue;
+        }
+        if let Some(next) = mem::phys_to_virt(entry.addr()) {
+            let next = unsafe { &mut *next.as_mut_ptr::<PageTable>() };
+            walk_table(next, level - 1, start, effective, f);
+        }
+    }
+}
+
+/// Returns the mapped memory, ordered by address.
+pub fn regions() -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+    walk(&mut |start, size, flags, entry| {
+        let region = Region {
+            start,
+            size,
+            writable: flags.contains(PageTableFlags::WRITABLE),
+            executable: !flags.contains(PageTableFlags::NO_EXECUTE) || !nx_enabled(),
+            user: flags.contains(PageTableFlags::USER_ACCESSIBLE),
+            kind: kind_of(start, size, entry.addr()),
+        };
+        match regions.last_mut() {
+            Some(last)
+                if last.start + last.size == start
+                    && (last.writable, last.executable, last.user, last.kind)
+                        == (region.writable, region.executable, region.user, region.kind) =>
+            {
+                last.size += size
+            }
+            _ => regions.push(region),
+        }
+    });
+    regions
+}
@@ -0,0 +1,453 @@
+//! Memory mappings.
+//!
+//! `map` reserves address space in a region set aside for mappings. Pages are only backed by
+//! memory once they are touched: the page fault handler allocates a frame and fills it with
+//! zeros or the file's contents. All tasks share one address space, so every task sees every
+//! mapping, but mappings belong to the task that made them and go away when it exits.
+//!
+//! File pages are kept in a page cache shared by every mapping of the file. Read-only mappings
+//! map the cached frame, writable ones get a private copy, so writes never reach the file.
+use alloc::{collections::BTreeMap, collections::BTreeSet, string::String, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    error::{KError, KResult},
+    ext::FileType,
+    mem::{self, PAGE_SIZE},
+    memprotect,
+    task::{self, TaskId},
+    vfs,
+};
+
+/// Address space for mappings, 1 TiB.
+pub const MMAP_START: u64 = 0x5555_0000_0000;
+pub const MMAP_END: u64 = 0x5655_0000_0000;
+
+pub use crate::syscall_table::{
+    MAP_ANONYMOUS, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE,
+};
+
+#[derive(Debug, Clone)]
+enum Backing {
+    Anonymous,
+    /// `offset` is where the mapping starts in the file, a multiple of the page size
+    File {
+        path: String,
+        offset: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Mapping {
+    start: u64,
+    pages: u64,
+    prot: u64,
+    owner: TaskId,
+    backing: Backing,
+    /// pages currently mapped to a frame of the page cache, by index into the mapping
+    cached: BTreeSet<u64>,
+}
+
+impl Mapping {
+    fn end(&self) -> u64 {
+        self.start + self.pages * PAGE_SIZE as u64
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    /// Returns the part of the mapping from `from` to `to`, both page aligned and inside it.
+    fn slice(&self, from: u64, to: u64) -> Self {
+        let first = (from - self.start) / PAGE_SIZE as u64;
+        let pages = (to - from) / PAGE_SIZE as u64;
+        let backing = match &self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { path, offset } => Backing::File {
+                path: path.clone(),
+                offset: offset + first * PAGE_SIZE as u64,
+            },
+        };
+        Self {
+            start: from,
+            pages,
+            prot: self.prot,
+            owner: self.owner,
+            backing,
+            cached: self
+                .cached
+                .range(first..first + pages)
+                .map(|page| page - first)
+                .collect(),
+        }
+    }
+
+    fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.prot & PROT_WRITE != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.prot & PROT_EXEC == 0 {
+            flags |= memprotect::no_execute();
+        }
+        flags
+    }
+}
+
+struct CachedPage {
+    frame: PhysFrame,
+    /// number of mapped pages using the frame
+    users: usize,
+}
+
+struct State {
+    /// sorted by start address
+    mappings: Vec<Mapping>,
+    /// file pages by path and page index into the file
+    cache: BTreeMap<(String, u64), CachedPage>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    mappings: Vec::new(),
+    cache: BTreeMap::new(),
+});
+
+fn page(addr: u64) -> Page {
+    Page::containing_address(VirtAddr::new(addr))
+}
+
+fn frame_bytes(frame: PhysFrame) -> &'static mut [u8] {
+    let virt = mem::phys_to_virt(frame.start_address()).expect("memory isn't initialized");
+    unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr(), PAGE_SIZE) }
+}
+
+fn page_count(len: u64) -> KResult<u64> {
+    if len == 0 {
+        return Err(KError::InvalidArgument);
+    }
+    Ok(len.div_ceil(PAGE_SIZE as u64))
+}
+
+/// Returns the start of the first gap of `pages` pages.
+fn find_gap(mappings: &[Mapping], pages: u64) -> Option<u64> {
+    let len = pages.checked_mul(PAGE_SIZE as u64)?;
+    let mut start = MMAP_START;
+    for mapping in mappings {
+        if mapping.start - start >= len {
+            break;
+        }
+        start = mapping.end();
+    }
+    (MMAP_END - start >= len).then_some(start)
+}
+
+fn insert(state: &mut State, mapping: Mapping) {
+    let pos = state
+        .mappings
+        .iter()
+        .position(|m| m.start > mapping.start)
+        .unwrap_or(state.mappings.len());
+    state.mappings.insert(pos, mapping);
+}
+
+fn map(len: u64, prot: u64, backing: Backing) -> KResult<u64> {
+    let pages = page_count(len)?;
+    let owner = task::current_id();
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        let start = find_gap(&state.mappings, pages).ok_or(KError::OutOfMemory)?;
+        insert(
+            &mut state,
+            Mapping {
+                start,
+                pages,
+                prot,
+                owner,
+                backing,
+                cached: BTreeSet::new(),
+            },
+        );
+        Ok(start)
+    })
+}
+
+/// Maps `len` bytes of zeroed memory. Returns the address of the mapping.
+pub fn map_anonymous(len: u64, prot: u64) -> KResult<u64> {
+    map(len, prot, Backing::Anonymous)
+}
+
+/// Maps `len` bytes of the file at `path` from `offset` on, which has to be page aligned.
+/// Bytes beyond the end of the file read as zero.
+pub fn map_file(path: &str, offset: u64, len: u64, prot: u64) -> KResult<u64> {
+    if offset % PAGE_SIZE as u64 != 0 {
+        return Err(KError::InvalidArgument);
+    }
+    let path = vfs::normalize(path)?;
+    if matches!(vfs::metadata(&path)?.file_type, FileType::Directory) {
+        return Err(KError::IsDirectory);
+    }
+    map(len, prot, Backing::File { path, offset })
+}
+
+/// Removes the part of the current task's mappings between `start` and `end`, returning the
+/// removed pieces. The pages stay mapped.
+fn carve(state: &mut State, owner: TaskId, start: u64, end: u64) -> Vec<Mapping> {
+    let mut removed = Vec::new();
+    let mut i = 0;
+    while i < state.mappings.len() {
+        let mapping = &state.mappings[i];
+        if mapping.owner != owner || mapping.end() <= start || mapping.start >= end {
+            i += 1;
+            continue;
+        }
+        let mapping = state.mappings.remove(i);
+        let (from, to) = (mapping.start.max(start), mapping.end().min(end));
+        if mapping.start < from {
+            state.mappings.insert(i, mapping.slice(mapping.start, from));
+            i += 1;
+        }
+        if to < mapping.end() {
+            state.mappings.insert(i, mapping.slice(to, mapping.end()));
+            i += 1;
+        }
+        removed.push(mapping.slice(from, to));
+    }
+    removed
+}
+
+/// Drops one user of a page cache frame, freeing the frame when it was the last one.
+fn release_cached(state: &mut State, key: (String, u64)) {
+    if let Some(page) = state.cache.get_mut(&key) {
+        page.users -= 1;
+        if page.users == 0 {
+            mem::free_frame(page.frame);
+            state.cache.remove(&key);
+        }
+    }
+}
+
+fn cache_key(mapping: &Mapping, index: u64) -> Option<(String, u64)> {
+    match &mapping.backing {
+        Backing::File { path, offset } => Some((path.clone(), offset / PAGE_SIZE as u64 + index)),
+        Backing::Anonymous => None,
+    }
+}
+
+/// Unmaps the pages of `mapping` and frees their frames.
+fn release(state: &mut State, mapping: &Mapping) {
+    for index in 0..mapping.pages {
+        let Some(frame) = mem::unmap_page(page(mapping.start + index * PAGE_SIZE as u64)) else {
+            continue;
+        };
+        match cache_key(mapping, index).filter(|_| mapping.cached.contains(&index)) {
+            Some(key) => release_cached(state, key),
+            None => mem::free_frame(frame),
+        }
+    }
+}
+
+fn check_range(addr: u64, len: u64) -> KResult<u64> {
+    let end = addr.checked_add(page_count(len)? * PAGE_SIZE as u64);
+    match end {
+        Some(end) if addr % PAGE_SIZE as u64 == 0 && addr >= MMAP_START && end <= MMAP_END => {
+            Ok(end)
+        }
+        _ => Err(KError::InvalidArgument),
+    }
+}
+
+/// Unmaps the current task's mappings between `addr` and `addr + len`.
+pub fn unmap(addr: u64, len: u64) -> KResult<()> {
+    let end = check_range(addr, len)?;
+    let owner = task::current_id();
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        for mapping in carve(&mut state, owner, addr, end) {
+            release(&mut state, &mapping);
+        }
+    });
+    Ok(())
+}
+
+/// Changes the protection of the current task's mappings between `addr` and `addr + len`.
+/// Pages shared with the page cache that become writable get a private copy.
+pub fn protect(addr: u64, len: u64, prot: u64) -> KResult<()> {
+    let end = check_range(addr, len)?;
+    let owner = task::current_id();
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        let mut result = Ok(());
+        for mut mapping in carve(&mut state, owner, addr, end) {
+            mapping.prot = prot;
+            for index in 0..mapping.pages {
+                if let Err(e) = reprotect(&mut state, &mut mapping, index) {
+                    result = Err(e);
+                }
+            }
+            insert(&mut state, mapping);
+        }
+        result
+    })
+}
+
+/// Applies the protection of `mapping` to its page `index`, if that is mapped.
+fn reprotect(state: &mut State, mapping: &mut Mapping, index: u64) -> KResult<()> {
+    let page = page(mapping.start + index * PAGE_SIZE as u64);
+    let Some(frame) = mem::translate_page(page) else {
+        return Ok(());
+    };
+    let key = cache_key(mapping, index).filter(|_| mapping.cached.contains(&index));
+    if mapping.prot == PROT_NONE {
+        // the page faults in again once it is accessible, private contents are lost
+        mem::unmap_page(page);
+        mapping.cached.remove(&index);
+        match key {
+            Some(key) => release_cached(state, key),
+            None => mem::free_frame(frame),
+        }
+        return Ok(());
+    }
+    match key {
+        Some(key) if mapping.prot & PROT_WRITE != 0 => {
+            let copy = mem::alloc_frame().ok_or(KError::OutOfMemory)?;
+            frame_bytes(copy).copy_from_slice(frame_bytes(frame));
+            mem::unmap_page(page);
+            mem::map_page(page, copy, mapping.flags())?;
+            mapping.cached.remove(&index);
+            release_cached(state, key);
+            Ok(())
+        }
+        _ => mem::update_page_flags(page, mapping.flags()).map_err(|_| KError::InvalidArgument),
+    }
+}
+
+/// Fills `frame` with page `index` of the file at `path`.
+fn read_file_page(path: &str, index: u64, frame: PhysFrame) -> KResult<()> {
+    let data = vfs::read(path)?;
+    let bytes = frame_bytes(frame);
+    bytes.fill(0);
+    let start = (index as usize * PAGE_SIZE).min(data.len());
+    let end = (start + PAGE_SIZE).min(data.len());
+    bytes[..end - start].copy_from_slice(&data[start..end]);
+    Ok(())
+}
+
+/// Returns the page cache frame for `key`, reading it from the file on a miss. Takes a user.
+fn cached_frame(key: &(String, u64)) -> KResult<PhysFrame> {
+    let hit = without_interrupts(|| {
+        STATE.lock().cache.get_mut(key).map(|page| {
+            page.users += 1;
+            page.frame
+        })
+    });
+    if let Some(frame) = hit {
+        return Ok(frame);
+    }
+    // the file is read without holding the lock, reading may block
+    let frame = mem::alloc_frame().ok_or(KError::OutOfMemory)?;
+    if let Err(e) = read_file_page(&key.0, key.1, frame) {
+        mem::free_frame(frame);
+        return Err(e);
+    }
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        if let Some(page) = state.cache.get_mut(key) {
+            // someone else read it in the meantime
+            mem::free_frame(frame);
+            page.users += 1;
+            return Ok(page.frame);
+        }
+        state
+            .cache
+            .insert(key.clone(), CachedPage { frame, users: 1 });
+        Ok(frame)
+    })
+}
+
+/// Called by the page fault handler. Populates the page containing `addr` if it belongs to a
+/// mapping that allows the access, returning false otherwise.
+pub fn handle_fault(addr: u64, write: bool) -> bool {
+    populate(addr, write).is_ok()
+}
+
+fn populate(addr: u64, write: bool) -> KResult<()> {
+    if !(MMAP_START..MMAP_END).contains(&addr) {
+        return Err(KError::BadAddress);
+    }
+    let page = page(addr);
+    let (mapping, index) = without_interrupts(|| {
+        let state = STATE.lock();
+        let mapping = state.mappings.iter().find(|m| m.contains(addr))?;
+        let index = (page.start_address().as_u64() - mapping.start) / PAGE_SIZE as u64;
+        Some((mapping.clone(), index))
+    })
+    .ok_or(KError::BadAddress)?;
+    if mapping.prot == PROT_NONE || (write && mapping.prot & PROT_WRITE == 0) {
+        return Err(KError::BadAddress);
+    }
+    if mem::translate_page(page).is_some() {
+        // a protection fault on a present page the mapping allows can't be fixed up
+        return Err(KError::BadAddress);
+    }
+
+    let key = cache_key(&mapping, index);
+    let shared = key.is_some() && mapping.prot & PROT_WRITE == 0;
+    let frame = match &key {
+        Some(key) if shared => cached_frame(key)?,
+        Some(key) => {
+            let frame = mem::alloc_frame().ok_or(KError::OutOfMemory)?;
+            read_file_page(&key.0, key.1, frame)?;
+            frame
+        }
+        None => {
+            let frame = mem::alloc_frame().ok_or(KError::OutOfMemory)?;
+            frame_bytes(frame).fill(0);
+            frame
+        }
+    };
+
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        // the mapping may have changed while the file was read
+        let current = state
+            .mappings
+            .iter_mut()
+            .find(|m| m.contains(addr) && m.start == mapping.start && m.prot == mapping.prot);
+        let result = match current {
+            Some(current) if mem::translate_page(page).is_none() => {
+                let mapped = mem::map_page(page, frame, mapping.flags());
+                if mapped.is_ok() && shared {
+                    current.cached.insert(index);
+                }
+                mapped.map_err(KError::from)
+            }
+            _ => Err(KError::Busy),
+        };
+        if result.is_err() {
+            match key.filter(|_| shared) {
+                Some(key) => release_cached(&mut state, key),
+                None => mem::free_frame(frame),
+            }
+        }
+        // a concurrent fault that already mapped the page fixed it up as well
+        match result {
+            Err(KError::Busy) if mem::translate_page(page).is_some() => Ok(()),
+            result => result,
+        }
+    })
+}
+
+/// Unmaps every mapping of a task. Called when it exits.
+pub fn release_task(owner: TaskId) {
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        for mapping in carve(&mut state, owner, MMAP_START, MMAP_END) {
+            release(&mut state, &mapping);
+        }
+    });
+}
@@ -0,0 +1,470 @@
+//! Loadable kernel modules.
+//!
+//! A module is a relocatable ELF object (`ET_REL`), like the output of `rustc --emit=obj` or
+//! `gcc -c`. Loading copies its allocated sections to page-aligned heap memory, code and
+//! read-only data first and writable data on pages of their own after it. Relocations are
+//! applied against the module's own symbols and, for undefined ones, the kernel symbol table,
+//! so a module can call every kernel function exported with `#[no_mangle]`. Once relocated,
+//! the code and read-only data pages are mapped read-only.
+//!
+//! The kernel is linked far away from the heap, so calls into it don't fit the 32 bit
+//! displacements of the small code model. Every undefined symbol gets a slot holding its
+//! address followed by a jump through it, which out-of-range calls and GOT relative loads use.
+//!
+//! Modules should be built for the kernel's target with the static relocation model, GOT
+//! relative relocations only work for symbols of the kernel.
+//!
+//! A module has to define `module_init`, an `extern "C" fn() -> i64` returning 0 or a negated
+//! error number, and may define `module_fini`, an `extern "C" fn()` called on unload.
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, Layout},
+    string::String,
+    vec,
+    vec::Vec,
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+use crate::{
+    error::KResult,
+    klog::Level,
+    klogln_at, ksyms,
+    mem::{self, PAGE_SIZE},
+    vfs,
+};
+
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const SHN_COMMON: u16 = 0xfff2;
+const STB_WEAK: u8 = 2;
+
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_GOTPCREL: u32 = 9;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+const R_X86_64_GOTPCRELX: u32 = 41;
+const R_X86_64_REX_GOTPCRELX: u32 = 42;
+
+/// A slot is the symbol's address followed by `jmp [rip - 14]`, which jumps through it.
+const SLOT_SIZE: usize = 16;
+const SLOT_JUMP: [u8; 6] = [0xff, 0x25, 0xf2, 0xff, 0xff, 0xff];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleError {
+    /// not a relocatable x86_64 ELF object, or a damaged one
+    BadFormat,
+    UnsupportedRelocation(u32),
+    /// a relocated value doesn't fit its field
+    Overflow,
+    /// a symbol is neither defined in the module nor in the kernel
+    UndefinedSymbol,
+    NoInit,
+    /// `module_init` returned this error number
+    InitFailed(i64),
+    AlreadyLoaded,
+    NotLoaded,
+    OutOfMemory,
+}
+
+type Result<T> = core::result::Result<T, ModuleError>;
+
+fn read<const N: usize>(data: &[u8], at: usize) -> Result<[u8; N]> {
+    data.get(at..at + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(ModuleError::BadFormat)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16> {
+    read(data, at).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32> {
+    read(data, at).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], at: usize) -> Result<u64> {
+    read(data, at).map(u64::from_le_bytes)
+}
+
+/// Returns the NUL terminated string at `at` in a string table.
+fn read_name(strtab: &[u8], at: usize) -> Result<&str> {
+    let bytes = strtab.get(at..).ok_or(ModuleError::BadFormat)?;
+    let len = bytes
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(ModuleError::BadFormat)?;
+    core::str::from_utf8(&bytes[..len]).map_err(|_| ModuleError::BadFormat)
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.next_multiple_of(align.max(1))
+}
+
+struct Section {
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+    align: usize,
+}
+
+impl Section {
+    fn parse(data: &[u8], at: usize) -> Result<Self> {
+        Ok(Self {
+            kind: read_u32(data, at + 4)?,
+            flags: read_u64(data, at + 8)?,
+            offset: read_u64(data, at + 24)? as usize,
+            size: read_u64(data, at + 32)? as usize,
+            link: read_u32(data, at + 40)? as usize,
+            info: read_u32(data, at + 44)? as usize,
+            align: read_u64(data, at + 48)? as usize,
+        })
+    }
+
+    fn is_alloc(&self) -> bool {
+        self.flags & SHF_ALLOC != 0
+    }
+
+    /// The section's contents in the file, empty for `.bss` like sections.
+    fn data<'a>(&self, file: &'a [u8]) -> Result<&'a [u8]> {
+        if self.kind == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        file.get(self.offset..self.offset + self.size)
+            .ok_or(ModuleError::BadFormat)
+    }
+}
+
+struct Symbol<'a> {
+    name: &'a str,
+    section: u16,
+    value: u64,
+    weak: bool,
+}
+
+/// Page-aligned heap memory holding a module or a program, see `exec`. The first `protected`
+/// bytes are read-only.
+pub(crate) struct Region {
+    ptr: *mut u8,
+    layout: Layout,
+    protected: usize,
+}
+
+// The region is owned by its module and only freed through it.
+unsafe impl Send for Region {}
+
+impl Region {
+    pub(crate) fn new(size: usize) -> Result<Self> {
+        let layout =
+            Layout::from_size_align(size, PAGE_SIZE).map_err(|_| ModuleError::BadFormat)?;
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(ModuleError::OutOfMemory);
+        }
+        Ok(Self {
+            ptr,
+            layout,
+            protected: 0,
+        })
+    }
+
+    pub(crate) fn addr(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    pub(crate) fn bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+
+    pub(crate) fn protect(&mut self, len: usize) -> Result<()> {
+        mem::set_writable(VirtAddr::new(self.addr()), len, false)
+            .map_err(|_| ModuleError::OutOfMemory)?;
+        self.protected = len;
+        Ok(())
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        // the heap has to be writable again before it gets reused
+        let _ = mem::set_writable(VirtAddr::new(self.addr()), self.protected, true);
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+}
+
+pub struct Module {
+    name: String,
+    region: Region,
+    fini: Option<extern "C" fn()>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub addr: u64,
+    pub size: usize,
+}
+
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+/// Links the object file in `file` into new memory. Returns the memory and the addresses of
+/// `module_init` and `module_fini`.
+fn link(file: &[u8]) -> Result<(Region, u64, Option<u64>)> {
+    if !file.starts_with(b"\x7fELF\x02\x01")
+        || read_u16(file, 16)? != ET_REL
+        || read_u16(file, 18)? != EM_X86_64
+    {
+        return Err(ModuleError::BadFormat);
+    }
+    let section_table = read_u64(file, 0x28)? as usize;
+    let count = read_u16(file, 0x3c)? as usize;
+    let sections = (0..count)
+        .map(|i| Section::parse(file, section_table + i * SECTION_HEADER_SIZE))
+        .collect::<Result<Vec<_>>>()?;
+
+    let symtab = sections
+        .iter()
+        .find(|section| section.kind == SHT_SYMTAB)
+        .ok_or(ModuleError::BadFormat)?;
+    let strtab = sections
+        .get(symtab.link)
+        .ok_or(ModuleError::BadFormat)?
+        .data(file)?;
+    let symbols = symtab
+        .data(file)?
+        .chunks_exact(SYMBOL_SIZE)
+        .map(|entry| {
+            Ok(Symbol {
+                name: read_name(strtab, read_u32(entry, 0)? as usize)?,
+                weak: entry[4] >> 4 == STB_WEAK,
+                section: read_u16(entry, 6)?,
+                value: read_u64(entry, 8)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // code and read-only data, then the slots, then writable data on their own pages
+    let undefined = symbols
+        .iter()
+        .skip(1)
+        .filter(|s| s.section == SHN_UNDEF)
+        .count();
+    let mut offsets = vec![None; sections.len()];
+    let (mut size, mut slots_at, mut protected) = (0, 0, 0);
+    for writable in [false, true] {
+        if writable {
+            slots_at = align_up(size, SLOT_SIZE);
+            protected = align_up(slots_at + undefined * SLOT_SIZE, PAGE_SIZE);
+            size = protected;
+        }
+        for (i, section) in sections.iter().enumerate() {
+            if section.is_alloc() && (section.flags & SHF_WRITE != 0) == writable {
+                if section.align > PAGE_SIZE {
+                    return Err(ModuleError::BadFormat);
+                }
+                size = align_up(size, section.align);
+                offsets[i] = Some(size);
+                size += section.size;
+            }
+        }
+    }
+    let mut region = Region::new(align_up(size, PAGE_SIZE).max(PAGE_SIZE))?;
+    let base = region.addr();
+
+    for (section, offset) in sections.iter().zip(&offsets) {
+        if let Some(offset) = offset {
+            let data = section.data(file)?;
+            region.bytes()[*offset..*offset + data.len()].copy_from_slice(data);
+        }
+    }
+
+    let mut values = Vec::with_capacity(symbols.len());
+    let mut slots = vec![None; symbols.len()];
+    let mut next_slot = slots_at;
+    for (i, symbol) in symbols.iter().enumerate() {
+        let value = match symbol.section {
+            _ if i == 0 => 0,
+            SHN_UNDEF => {
+                let addr = match ksyms::lookup(symbol.name) {
+                    Some(addr) => addr,
+                    None if symbol.weak => 0,
+                    None => {
+                        klogln_at!(Level::Warn, "module: undefined symbol {}", symbol.name);
+                        return Err(ModuleError::UndefinedSymbol);
+                    }
+                };
+                let slot = &mut region.bytes()[next_slot..next_slot + SLOT_SIZE];
+                slot[..8].copy_from_slice(&addr.to_le_bytes());
+                slot[8..14].copy_from_slice(&SLOT_JUMP);
+                slots[i] = Some(base + next_slot as u64);
+                next_slot += SLOT_SIZE;
+                addr
+            }
+            SHN_ABS => symbol.value,
+            SHN_COMMON => return Err(ModuleError::BadFormat),
+            section => match offsets.get(section as usize) {
+                Some(Some(offset)) => base + (*offset as u64) + symbol.value,
+                // symbols in sections that aren't loaded, like debug info
+                Some(None) => 0,
+                None => return Err(ModuleError::BadFormat),
+            },
+        };
+        values.push(value);
+    }
+
+    for section in sections.iter().filter(|section| section.kind == SHT_RELA) {
+        let (Some(target), Some(Some(target_offset))) =
+            (sections.get(section.info), offsets.get(section.info))
+        else {
+            continue;
+        };
+        for entry in section.data(file)?.chunks_exact(RELA_SIZE) {
+            let offset = read_u64(entry, 0)? as usize;
+            let info = read_u64(entry, 8)?;
+            let addend = read_u64(entry, 16)?;
+            let (symbol, kind) = ((info >> 32) as usize, info as u32);
+            let s = *values.get(symbol).ok_or(ModuleError::BadFormat)?;
+            let slot = slots.get(symbol).copied().flatten();
+            let at = target_offset + offset;
+            let p = base + at as u64;
+            let fits = |width| offset + width <= target.size;
+
+            let relative = |s: u64| s.wrapping_add(addend).wrapping_sub(p) as i64;
+            let (bytes, width): ([u8; 8], usize) = match kind {
+                R_X86_64_NONE => continue,
+                R_X86_64_64 => (s.wrapping_add(addend).to_le_bytes(), 8),
+                R_X86_64_PC64 => (relative(s).to_le_bytes(), 8),
+                R_X86_64_PC32 | R_X86_64_PLT32 => {
+                    let mut value = relative(s);
+                    if i32::try_from(value).is_err() {
+                        // too far away for a direct call, go through the jump in the slot
+                        let jump = slot.ok_or(ModuleError::Overflow)? + 8;
+                        value = relative(jump);
+                    }
+                    let value = i32::try_from(value).map_err(|_| ModuleError::Overflow)?;
+                    ((value as i64).to_le_bytes(), 4)
+                }
+                R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX => {
+                    let value = relative(slot.ok_or(ModuleError::BadFormat)?);
+                    let value = i32::try_from(value).map_err(|_| ModuleError::Overflow)?;
+                    ((value as i64).to_le_bytes(), 4)
+                }
+                R_X86_64_32 => {
+                    let value =
+                        u32::try_from(s.wrapping_add(addend)).map_err(|_| ModuleError::Overflow)?;
+                    ((value as u64).to_le_bytes(), 4)
+                }
+                R_X86_64_32S => {
+                    let value = i32::try_from(s.wrapping_add(addend) as i64)
+                        .map_err(|_| ModuleError::Overflow)?;
+                    ((value as i64).to_le_bytes(), 4)
+                }
+                other => return Err(ModuleError::UnsupportedRelocation(other)),
+            };
+            if !fits(width) {
+                return Err(ModuleError::BadFormat);
+            }
+            region.bytes()[at..at + width].copy_from_slice(&bytes[..width]);
+        }
+    }
+
+    let find = |name: &str| {
+        symbols
+            .iter()
+            .zip(&values)
+            .find(|(symbol, _)| {
+                symbol.name == name && symbol.section != SHN_UNDEF && symbol.section != SHN_ABS
+            })
+            .map(|(_, value)| *value)
+    };
+    let init = find("module_init").ok_or(ModuleError::NoInit)?;
+    let fini = find("module_fini");
+    region.protect(protected)?;
+    Ok((region, init, fini))
+}
+
+/// Loads the module at `path` and runs its `module_init`. The module is named after the file.
+pub fn load(path: &str) -> KResult<()> {
+    let name = vfs::split_parent(path).1;
+    let name = String::from(name.strip_suffix(".o").unwrap_or(name));
+    if without_interrupts(|| MODULES.lock().iter().any(|module| module.name == name)) {
+        return Err(ModuleError::AlreadyLoaded.into());
+    }
+    let file = vfs::read(path)?;
+    let (region, init, fini) = link(&file)?;
+
+    let init: extern "C" fn() -> i64 = unsafe { core::mem::transmute(init) };
+    let result = init();
+    if result != 0 {
+        return Err(ModuleError::InitFailed(result).into());
+    }
+    let fini = fini.map(|fini| unsafe { core::mem::transmute::<u64, extern "C" fn()>(fini) });
+    klogln_at!(
+        Level::Info,
+        "module: loaded {} at {:#x}",
+        name,
+        region.addr()
+    );
+    without_interrupts(|| MODULES.lock().push(Module { name, region, fini }));
+    Ok(())
+}
+
+/// Runs the `module_fini` of the module called `name` and frees it.
+pub fn unload(name: &str) -> KResult<()> {
+    let module = without_interrupts(|| {
+        let mut modules = MODULES.lock();
+        let pos = modules.iter().position(|module| module.name == name)?;
+        Some(modules.remove(pos))
+    })
+    .ok_or(ModuleError::NotLoaded)?;
+    if let Some(fini) = module.fini {
+        fini();
+    }
+    klogln_at!(Level::Info, "module: unloaded {}", name);
+    Ok(())
+}
+
+pub fn list() -> Vec<ModuleInfo> {
+    without_interrupts(|| {
+        MODULES
+            .lock()
+            .iter()
+            .map(|module| ModuleInfo {
+                name: module.name.clone(),
+                addr: module.region.addr(),
+                size: module.region.layout.size(),
+            })
+            .collect()
+    })
+}
+
+#[test_case]
+fn test_rejects_non_objects() {
+    assert_eq!(link(b"not an elf file").err(), Some(ModuleError::BadFormat));
+    let mut header = [0u8; 64];
+    header[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    // ET_EXEC instead of ET_REL
+    header[16] = 2;
+    header[18] = EM_X86_64 as u8;
+    assert_eq!(link(&header).err(), Some(ModuleError::BadFormat));
+}
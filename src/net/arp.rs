@@ -0,0 +1,283 @@
+//! ARP (RFC 826) and the neighbor cache.
+//!
+//! Packets to an address that isn't in the cache wait on its entry while requests are sent, up
+//! to `MAX_REQUESTS` of them `REQUEST_INTERVAL` apart, after which the address is given up on
+//! and the packets are dropped. Resolved entries are used for `REACHABLE_TIME`, the next packet
+//! after that resolves the address again. Unasked answers only refresh existing entries.
+use alloc::{collections::BTreeMap, collections::VecDeque, string::String, vec::Vec};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{
+    ethernet::{self, MacAddr, ETHERTYPE_ARP, ETHERTYPE_IPV4},
+    Interface, Ipv4Addr,
+};
+use crate::{error::KResult, time};
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+
+const REACHABLE_TIME: Duration = Duration::from_secs(60);
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REQUESTS: u32 = 3;
+/// Packets waiting on an address before the oldest are dropped.
+const MAX_PENDING: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Requests are being sent.
+    Incomplete,
+    Reachable,
+}
+
+struct Entry {
+    interface: Interface,
+    mac: Option<MacAddr>,
+    state: State,
+    /// when a reachable entry expires or the next request is due
+    deadline: Duration,
+    requests: u32,
+    /// IP packets sent once the address is resolved
+    pending: VecDeque<Vec<u8>>,
+}
+
+/// An entry of the neighbor cache.
+#[derive(Debug, Clone)]
+pub struct Neighbor {
+    pub addr: Ipv4Addr,
+    pub mac: Option<MacAddr>,
+    pub device: String,
+    pub state: State,
+    /// time until the entry expires or the next request is sent
+    pub expires_in: Duration,
+}
+
+static CACHE: Mutex<BTreeMap<Ipv4Addr, Entry>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Packet {
+    op: u16,
+    sender_mac: MacAddr,
+    sender_addr: Ipv4Addr,
+    target_mac: MacAddr,
+    target_addr: Ipv4Addr,
+}
+
+fn build(packet: &Packet) -> Vec<u8> {
+    let mut data = Vec::with_capacity(PACKET_SIZE);
+    data.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    data.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    data.extend_from_slice(&[6, 4]);
+    data.extend_from_slice(&packet.op.to_be_bytes());
+    data.extend_from_slice(&packet.sender_mac.0);
+    data.extend_from_slice(&packet.sender_addr.0);
+    data.extend_from_slice(&packet.target_mac.0);
+    data.extend_from_slice(&packet.target_addr.0);
+    data
+}
+
+fn parse(data: &[u8]) -> Option<Packet> {
+    if data.len() < PACKET_SIZE
+        || data[..4] != [0, HARDWARE_ETHERNET as u8, 0x08, 0x00]
+        || data[4..6] != [6, 4]
+    {
+        return None;
+    }
+    Some(Packet {
+        op: u16::from_be_bytes([data[6], data[7]]),
+        sender_mac: MacAddr(data[8..14].try_into().unwrap()),
+        sender_addr: Ipv4Addr(data[14..18].try_into().unwrap()),
+        target_mac: MacAddr(data[18..24].try_into().unwrap()),
+        target_addr: Ipv4Addr(data[24..28].try_into().unwrap()),
+    })
+}
+
+/// Broadcasts a request for the address of `addr`.
+fn request(interface: &Interface, addr: Ipv4Addr) -> KResult<()> {
+    let request = Packet {
+        op: OP_REQUEST,
+        sender_mac: interface.device.mac().unwrap_or(MacAddr::ZERO),
+        sender_addr: interface.addr,
+        target_mac: MacAddr::ZERO,
+        target_addr: addr,
+    };
+    ethernet::send(
+        interface,
+        MacAddr::BROADCAST,
+        ETHERTYPE_ARP,
+        &build(&request),
+    )
+}
+
+enum Resolution {
+    Known(MacAddr),
+    /// the packet waits for the requests already sent
+    Waiting,
+    /// the packet waits for the first request
+    New,
+}
+
+/// Sends an IP packet to the neighbor `next_hop`, once its address is resolved.
+pub(super) fn output(
+    interface: &Interface,
+    next_hop: Ipv4Addr,
+    mut packet: Vec<u8>,
+) -> KResult<()> {
+    if next_hop == Ipv4Addr::BROADCAST || next_hop == interface.broadcast() {
+        return ethernet::send(interface, MacAddr::BROADCAST, ETHERTYPE_IPV4, &packet);
+    }
+    let now = time::uptime();
+    let resolution = without_interrupts(|| {
+        let mut cache = CACHE.lock();
+        if let Some(entry) = cache.get_mut(&next_hop) {
+            match (entry.state, entry.mac) {
+                (State::Reachable, Some(mac)) if now < entry.deadline => {
+                    return Resolution::Known(mac)
+                }
+                (State::Incomplete, _) => {
+                    if entry.pending.len() >= MAX_PENDING {
+                        entry.pending.pop_front();
+                    }
+                    entry.pending.push_back(core::mem::take(&mut packet));
+                    return Resolution::Waiting;
+                }
+                _ => {}
+            }
+        }
+        let entry = Entry {
+            interface: interface.clone(),
+            mac: None,
+            state: State::Incomplete,
+            deadline: now + REQUEST_INTERVAL,
+            requests: 1,
+            pending: VecDeque::from([core::mem::take(&mut packet)]),
+        };
+        cache.insert(next_hop, entry);
+        Resolution::New
+    });
+    match resolution {
+        Resolution::Known(mac) => ethernet::send(interface, mac, ETHERTYPE_IPV4, &packet),
+        Resolution::Waiting => Ok(()),
+        // the first request goes out right away
+        Resolution::New => request(interface, next_hop),
+    }
+}
+
+/// Handles an ARP packet received on `interface`, whose device has the address `mac`.
+pub(super) fn input(interface: &Interface, mac: MacAddr, data: &[u8]) {
+    let Some(packet) = parse(data) else {
+        return;
+    };
+    if packet.sender_addr == Ipv4Addr::UNSPECIFIED {
+        return;
+    }
+    let for_us = packet.target_addr == interface.addr;
+    let now = time::uptime();
+    let pending = without_interrupts(|| {
+        let mut cache = CACHE.lock();
+        if !for_us && !cache.contains_key(&packet.sender_addr) {
+            return VecDeque::new();
+        }
+        let entry = cache.entry(packet.sender_addr).or_insert_with(|| Entry {
+            interface: interface.clone(),
+            mac: None,
+            state: State::Incomplete,
+            deadline: now,
+            requests: 0,
+            pending: VecDeque::new(),
+        });
+        entry.interface = interface.clone();
+        entry.mac = Some(packet.sender_mac);
+        entry.state = State::Reachable;
+        entry.deadline = now + REACHABLE_TIME;
+        entry.requests = 0;
+        core::mem::take(&mut entry.pending)
+    });
+    for ip_packet in pending {
+        let _ = ethernet::send(interface, packet.sender_mac, ETHERTYPE_IPV4, &ip_packet);
+    }
+
+    if for_us && packet.op == OP_REQUEST {
+        let reply = Packet {
+            op: OP_REPLY,
+            sender_mac: mac,
+            sender_addr: interface.addr,
+            target_mac: packet.sender_mac,
+            target_addr: packet.sender_addr,
+        };
+        let _ = ethernet::send(interface, packet.sender_mac, ETHERTYPE_ARP, &build(&reply));
+    }
+}
+
+/// Sends the requests that are due, drops expired entries and unresolvable addresses.
+pub(super) fn tick() {
+    let now = time::uptime();
+    let requests = without_interrupts(|| {
+        let mut requests = Vec::new();
+        CACHE.lock().retain(|addr, entry| {
+            if now < entry.deadline {
+                return true;
+            }
+            if entry.state == State::Reachable || entry.requests >= MAX_REQUESTS {
+                return false;
+            }
+            entry.requests += 1;
+            entry.deadline = now + REQUEST_INTERVAL;
+            requests.push((entry.interface.clone(), *addr));
+            true
+        });
+        requests
+    });
+    for (interface, addr) in requests {
+        let _ = request(&interface, addr);
+    }
+}
+
+/// Lists the neighbor cache.
+pub fn neighbors() -> Vec<Neighbor> {
+    let now = time::uptime();
+    without_interrupts(|| {
+        CACHE
+            .lock()
+            .iter()
+            .map(|(addr, entry)| Neighbor {
+                addr: *addr,
+                mac: entry.mac,
+                device: String::from(entry.interface.name()),
+                state: entry.state,
+                expires_in: entry.deadline.saturating_sub(now),
+            })
+            .collect()
+    })
+}
+
+/// Removes the entry for `addr`. Returns whether there was one.
+pub fn remove(addr: Ipv4Addr) -> bool {
+    without_interrupts(|| CACHE.lock().remove(&addr).is_some())
+}
+
+/// Empties the cache, returns how many entries it had.
+pub fn flush() -> usize {
+    without_interrupts(|| core::mem::take(&mut *CACHE.lock()).len())
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_build_and_parse() {
+    let request = Packet {
+        op: OP_REQUEST,
+        sender_mac: MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+        sender_addr: Ipv4Addr([10, 0, 2, 15]),
+        target_mac: MacAddr::ZERO,
+        target_addr: Ipv4Addr([10, 0, 2, 2]),
+    };
+    let data = build(&request);
+    assert_eq!(data.len(), PACKET_SIZE);
+    assert_eq!(&data[..8], &[0, 1, 0x08, 0, 6, 4, 0, 1]);
+    assert_eq!(parse(&data), Some(request));
+    let mut ipv6 = data;
+    ipv6[2] = 0x86;
+    assert_eq!(parse(&ipv6), None);
+}
@@ -0,0 +1,107 @@
+//! Ethernet II framing, for devices that have a MAC address.
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use super::{arp, ipv4, Checksums, Interface};
+use crate::error::{KError, KResult};
+
+pub const HEADER_SIZE: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: Self = Self([0xff; 6]);
+    pub const ZERO: Self = Self([0; 6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = KError;
+
+    fn from_str(s: &str) -> Result<Self, KError> {
+        let mut addr = [0; 6];
+        let mut parts = s.split(':');
+        for byte in addr.iter_mut() {
+            let part = parts.next().ok_or(KError::InvalidArgument)?;
+            *byte = u8::from_str_radix(part, 16).map_err(|_| KError::InvalidArgument)?;
+        }
+        if parts.next().is_some() {
+            return Err(KError::InvalidArgument);
+        }
+        Ok(Self(addr))
+    }
+}
+
+/// Builds a frame. The device adds the preamble and the frame check sequence.
+pub fn build(dst: MacAddr, src: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&src.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Splits a frame into destination, source, ethertype and payload.
+pub fn parse(frame: &[u8]) -> Option<(MacAddr, MacAddr, u16, &[u8])> {
+    if frame.len() < HEADER_SIZE {
+        return None;
+    }
+    let dst = MacAddr(frame[..6].try_into().unwrap());
+    let src = MacAddr(frame[6..12].try_into().unwrap());
+    let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+    Some((dst, src, ethertype, &frame[HEADER_SIZE..]))
+}
+
+/// Sends `payload` from `interface` to `dst`.
+pub fn send(interface: &Interface, dst: MacAddr, ethertype: u16, payload: &[u8]) -> KResult<()> {
+    let src = interface.device.mac().unwrap_or(MacAddr::ZERO);
+    interface.transmit(&build(dst, src, ethertype, payload))
+}
+
+/// Handles a frame received on `interface`, whose device has the address `mac`. The checksums
+/// in `verified` were checked by the device.
+pub fn input(interface: &Interface, mac: MacAddr, frame: &[u8], verified: Checksums) {
+    let Some((dst, _, ethertype, payload)) = parse(frame) else {
+        interface.stats.rx_errors.inc();
+        return;
+    };
+    if dst != mac && dst != MacAddr::BROADCAST {
+        return;
+    }
+    match ethertype {
+        ETHERTYPE_IPV4 => ipv4::input(interface, payload, verified),
+        ETHERTYPE_ARP => arp::input(interface, mac, payload),
+        _ => {}
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_build_and_parse() {
+    let src: MacAddr = "52:54:00:12:34:56".parse().unwrap();
+    assert_eq!(src, MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]));
+    assert_eq!(alloc::format!("{}", src), "52:54:00:12:34:56");
+    assert!("52:54:00:12:34".parse::<MacAddr>().is_err());
+
+    let frame = build(MacAddr::BROADCAST, src, ETHERTYPE_ARP, b"payload");
+    assert_eq!(frame.len(), HEADER_SIZE + 7);
+    assert_eq!(
+        parse(&frame),
+        Some((MacAddr::BROADCAST, src, ETHERTYPE_ARP, &b"payload"[..]))
+    );
+    assert_eq!(parse(&frame[..10]), None);
+}
@@ -0,0 +1,193 @@
+//! A small HTTP/1.1 client.
+//!
+//! Only `GET` over plain HTTP to hosts given by address, as there is neither TLS nor a resolver.
+//! The whole response is read until the server closes the connection.
+use alloc::{format, string::String, vec::Vec};
+use core::time::Duration;
+
+use super::{tcp::TcpStream, Ipv4Addr, SocketAddr};
+use crate::error::KError;
+
+/// Largest response accepted.
+const MAX_RESPONSE: usize = 16 * 1024 * 1024;
+/// How long the server may stay silent.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    /// Not an `http://` URL with an IPv4 address as host.
+    BadUrl,
+    Net(KError),
+    /// The response couldn't be parsed or was cut short.
+    BadResponse,
+    /// The server answered with something but `200 OK`.
+    Status(u16, String),
+    TooBig,
+}
+
+impl From<KError> for HttpError {
+    fn from(error: KError) -> Self {
+        Self::Net(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url<'a> {
+    pub addr: SocketAddr,
+    /// with the query, always starts with `/`
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    /// Parses `http://<address>[:<port>][/<path>]`.
+    pub fn parse(url: &'a str) -> Result<Self, HttpError> {
+        let rest = url.strip_prefix("http://").ok_or(HttpError::BadUrl)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| HttpError::BadUrl)?),
+            None => (authority, 80),
+        };
+        let host: Ipv4Addr = host.parse().map_err(|_| HttpError::BadUrl)?;
+        Ok(Self {
+            addr: SocketAddr::new(host, port),
+            path,
+        })
+    }
+}
+
+/// Fetches `url` and returns the body.
+pub fn get(url: &str) -> Result<Vec<u8>, HttpError> {
+    let url = Url::parse(url)?;
+    let stream = TcpStream::connect(url.addr)?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: skyos\r\nConnection: close\r\n\r\n",
+        url.path, url.addr
+    );
+    stream.write(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let len = stream.read(&mut buf, Some(READ_TIMEOUT))?;
+        if len == 0 {
+            break;
+        }
+        if response.len() + len > MAX_RESPONSE {
+            return Err(HttpError::TooBig);
+        }
+        response.extend_from_slice(&buf[..len]);
+    }
+    parse_response(&response)
+}
+
+/// Returns the body of a complete response, if its status is `200`.
+pub fn parse_response(response: &[u8]) -> Result<Vec<u8>, HttpError> {
+    let head_len = find(response, b"\r\n\r\n").ok_or(HttpError::BadResponse)?;
+    let head = core::str::from_utf8(&response[..head_len]).map_err(|_| HttpError::BadResponse)?;
+    let body = &response[head_len + 4..];
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().ok_or(HttpError::BadResponse)?;
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/1."))
+    {
+        return Err(HttpError::BadResponse);
+    }
+    let status: u16 = parts
+        .next()
+        .and_then(|status| status.parse().ok())
+        .ok_or(HttpError::BadResponse)?;
+    if status != 200 {
+        let reason = parts.next().unwrap_or("");
+        return Err(HttpError::Status(status, String::from(reason)));
+    }
+
+    let mut chunked = false;
+    let mut content_length = None;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(HttpError::BadResponse)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            let len: usize = value.parse().map_err(|_| HttpError::BadResponse)?;
+            content_length = Some(len);
+        }
+    }
+
+    match (chunked, content_length) {
+        (true, _) => decode_chunked(body),
+        (false, Some(len)) => body
+            .get(..len)
+            .map(|body| body.to_vec())
+            .ok_or(HttpError::BadResponse),
+        (false, None) => Ok(body.to_vec()),
+    }
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`. Trailers are ignored.
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, HttpError> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_len = find(body, b"\r\n").ok_or(HttpError::BadResponse)?;
+        let line = core::str::from_utf8(&body[..line_len]).map_err(|_| HttpError::BadResponse)?;
+        // chunk extensions follow a semicolon
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| HttpError::BadResponse)?;
+        body = &body[line_len + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = body.get(..size).ok_or(HttpError::BadResponse)?;
+        decoded.extend_from_slice(chunk);
+        if body.get(size..size + 2) != Some(&b"\r\n"[..]) {
+            return Err(HttpError::BadResponse);
+        }
+        body = &body[size + 2..];
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse_url() {
+    let url = Url::parse("http://10.0.2.2:8000/files/a.txt?x=1").unwrap();
+    assert_eq!(url.addr, SocketAddr::new(Ipv4Addr([10, 0, 2, 2]), 8000));
+    assert_eq!(url.path, "/files/a.txt?x=1");
+    assert_eq!(Url::parse("http://127.0.0.1").unwrap().path, "/");
+    assert_eq!(Url::parse("http://127.0.0.1").unwrap().addr.port, 80);
+    assert_eq!(Url::parse("https://127.0.0.1/"), Err(HttpError::BadUrl));
+    assert_eq!(Url::parse("http://example.com/"), Err(HttpError::BadUrl));
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse_response() {
+    let plain = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, and more";
+    assert_eq!(parse_response(plain), Ok(b"hello".to_vec()));
+
+    let chunked = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n\
+                    4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n";
+    assert_eq!(
+        parse_response(chunked),
+        Ok(b"Wikipedia in \r\n\r\nchunks.".to_vec())
+    );
+
+    let missing = b"HTTP/1.0 404 Not Found\r\n\r\n";
+    assert_eq!(
+        parse_response(missing),
+        Err(HttpError::Status(404, String::from("Not Found")))
+    );
+    let short = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello";
+    assert_eq!(parse_response(short), Err(HttpError::BadResponse));
+    let truncated = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWi";
+    assert_eq!(parse_response(truncated), Err(HttpError::BadResponse));
+}
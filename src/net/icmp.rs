@@ -0,0 +1,206 @@
+//! ICMP, answering echo requests, and raw sockets for tools like `ping`.
+//!
+//! Every valid message received is copied to every open `IcmpSocket`, including the echo
+//! requests this stack answers itself. Sockets pick out what they are waiting for.
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{
+    checksum, ipv4,
+    stats::{SocketInfo, ICMP},
+    Inbox, Ipv4Addr, SocketAddr,
+};
+use crate::error::KResult;
+
+pub const ECHO_REPLY: u8 = 0;
+pub const DEST_UNREACHABLE: u8 = 3;
+pub const ECHO_REQUEST: u8 = 8;
+pub const TIME_EXCEEDED: u8 = 11;
+
+pub const HEADER_SIZE: usize = 8;
+/// Messages a socket holds before dropping the oldest.
+const QUEUE_SIZE: usize = 64;
+
+/// A received message.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub from: Ipv4Addr,
+    /// TTL of the packet it came in
+    pub ttl: u8,
+    pub kind: u8,
+    pub code: u8,
+    /// the second half of the header, its meaning depends on the kind
+    pub rest: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    /// Identifier and sequence number of an echo request or reply.
+    pub fn echo_id(&self) -> Option<(u16, u16)> {
+        match self.kind {
+            ECHO_REQUEST | ECHO_REPLY => Some(echo_fields(self.rest)),
+            _ => None,
+        }
+    }
+
+    /// For errors, the header and the start of the payload of the packet that caused it.
+    pub fn original(&self) -> Option<(ipv4::Header, &[u8])> {
+        match self.kind {
+            DEST_UNREACHABLE | TIME_EXCEEDED => ipv4::parse_quoted(&self.data),
+            _ => None,
+        }
+    }
+
+    /// Identifier and sequence number of the echo request an error is about.
+    pub fn original_echo_id(&self) -> Option<(u16, u16)> {
+        let (header, payload) = self.original()?;
+        if header.protocol != ipv4::PROTOCOL_ICMP
+            || payload.len() < HEADER_SIZE
+            || payload[0] != ECHO_REQUEST
+        {
+            return None;
+        }
+        Some(echo_fields(payload[4..8].try_into().unwrap()))
+    }
+}
+
+fn echo_fields(rest: [u8; 4]) -> (u16, u16) {
+    (
+        u16::from_be_bytes([rest[0], rest[1]]),
+        u16::from_be_bytes([rest[2], rest[3]]),
+    )
+}
+
+static SOCKETS: Mutex<BTreeMap<u32, Arc<Inbox<Message>>>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Sends ICMP messages and receives a copy of every one arriving.
+pub struct IcmpSocket {
+    id: u32,
+    inbox: Arc<Inbox<Message>>,
+}
+
+impl IcmpSocket {
+    pub fn open() -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let inbox = Inbox::new(QUEUE_SIZE);
+        without_interrupts(|| SOCKETS.lock().insert(id, inbox.clone()));
+        Self { id, inbox }
+    }
+
+    /// A number telling this socket's echo requests apart from those of other sockets.
+    pub fn echo_identifier(&self) -> u16 {
+        self.id as u16
+    }
+
+    /// Sends a message made by `build` to `dst`, in a packet with the given TTL.
+    pub fn send_to(&self, message: &[u8], dst: Ipv4Addr, ttl: u8) -> KResult<()> {
+        ICMP.out_msgs.inc();
+        ipv4::send_with_ttl(dst, ipv4::PROTOCOL_ICMP, ttl, message)
+    }
+
+    /// Returns the next message, blocking until one arrives. Fails with `TimedOut` if none came
+    /// within `timeout`.
+    pub fn recv(&self, timeout: Option<Duration>) -> KResult<Message> {
+        self.inbox.pop(timeout)
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        without_interrupts(|| SOCKETS.lock().remove(&self.id));
+    }
+}
+
+/// Lists the open sockets, with the protocol as their port.
+pub fn sockets() -> Vec<SocketInfo> {
+    let inboxes: Vec<_> = without_interrupts(|| SOCKETS.lock().values().cloned().collect());
+    inboxes
+        .into_iter()
+        .map(|inbox| SocketInfo {
+            local: SocketAddr::new(Ipv4Addr::UNSPECIFIED, ipv4::PROTOCOL_ICMP as u16),
+            remote: SocketAddr::new(Ipv4Addr::UNSPECIFIED, 0),
+            state: None,
+            recv_queue: inbox.len(),
+            send_queue: 0,
+            drops: inbox.dropped.get(),
+        })
+        .collect()
+}
+
+/// Builds a message with a type, code, the rest of the header and data.
+pub fn build(kind: u8, code: u8, rest: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + data.len());
+    message.extend_from_slice(&[kind, code, 0, 0]);
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(data);
+    let sum = checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    message
+}
+
+/// Builds an echo request.
+pub fn echo_request(id: u16, seq: u16, data: &[u8]) -> Vec<u8> {
+    let [a, b] = id.to_be_bytes();
+    let [c, d] = seq.to_be_bytes();
+    build(ECHO_REQUEST, 0, [a, b, c, d], data)
+}
+
+pub(super) fn input(header: &ipv4::Header, message: &[u8]) {
+    ICMP.in_msgs.inc();
+    if message.len() < HEADER_SIZE || checksum(message) != 0 {
+        ICMP.in_errors.inc();
+        return;
+    }
+    if message[0] == ECHO_REQUEST && message[1] == 0 {
+        ICMP.in_echos.inc();
+        let rest = message[4..8].try_into().unwrap();
+        let reply = build(ECHO_REPLY, 0, rest, &message[HEADER_SIZE..]);
+        ICMP.out_msgs.inc();
+        ICMP.out_echo_reps.inc();
+        // nobody to tell if this fails
+        let _ = ipv4::send(header.src, ipv4::PROTOCOL_ICMP, &reply);
+    }
+
+    let inboxes: Vec<_> = without_interrupts(|| SOCKETS.lock().values().cloned().collect());
+    for inbox in inboxes {
+        inbox.push(Message {
+            from: header.src,
+            ttl: header.ttl,
+            kind: message[0],
+            code: message[1],
+            rest: message[4..8].try_into().unwrap(),
+            data: message[HEADER_SIZE..].to_vec(),
+        });
+    }
+}
+
+#[test_case]
+fn test_original_echo_id() {
+    let request = echo_request(0x1234, 7, b"probe");
+    let header = ipv4::Header {
+        src: Ipv4Addr([10, 0, 2, 15]),
+        dst: Ipv4Addr([8, 8, 8, 8]),
+        protocol: ipv4::PROTOCOL_ICMP,
+        ttl: 1,
+    };
+    // a router quotes the IP header and the first 8 bytes of the payload
+    let packet = ipv4::build(&header, &request);
+    let error = Message {
+        from: Ipv4Addr([10, 0, 2, 2]),
+        ttl: 64,
+        kind: TIME_EXCEEDED,
+        code: 0,
+        rest: [0; 4],
+        data: packet[..ipv4::HEADER_SIZE + HEADER_SIZE].to_vec(),
+    };
+    assert_eq!(error.echo_id(), None);
+    assert_eq!(error.original_echo_id(), Some((0x1234, 7)));
+    assert_eq!(
+        error.original().map(|(header, _)| header.dst),
+        Some(header.dst)
+    );
+}
@@ -0,0 +1,185 @@
+//! IPv4 headers, sending and dispatching received packets.
+//!
+//! Options are skipped, fragments dropped and nothing is forwarded.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{checksum, icmp, stats::IP, tcp, udp, Checksums, Interface, Ipv4Addr};
+use crate::error::{KError, KResult};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_SIZE: usize = 20;
+pub const DEFAULT_TTL: u8 = 64;
+
+const VERSION_IHL: u8 = 0x45;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+}
+
+/// Splits a packet into its header and payload. `None` for anything malformed and fragments.
+pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+    parse_checked(packet, false)
+}
+
+/// Like `parse`, skipping the header checksum if the device `verified` it.
+fn parse_checked(packet: &[u8], verified: bool) -> Option<(Header, &[u8])> {
+    let (header, _) = parse_quoted(packet)?;
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    let flags = u16::from_be_bytes([packet[6], packet[7]]);
+    if total_len < header_len
+        || total_len > packet.len()
+        || flags & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0
+        || (!verified && checksum(&packet[..header_len]) != 0)
+    {
+        return None;
+    }
+    Some((header, &packet[header_len..total_len]))
+}
+
+/// Splits the start of a packet quoted in an ICMP error into its header and what is left of the
+/// payload. Only the header itself has to be complete.
+pub fn parse_quoted(packet: &[u8]) -> Option<(Header, &[u8])> {
+    let version_ihl = *packet.first()?;
+    let header_len = (version_ihl & 0xf) as usize * 4;
+    if version_ihl >> 4 != 4 || header_len < HEADER_SIZE || packet.len() < header_len {
+        return None;
+    }
+    let header = Header {
+        src: Ipv4Addr(packet[12..16].try_into().unwrap()),
+        dst: Ipv4Addr(packet[16..20].try_into().unwrap()),
+        protocol: packet[9],
+        ttl: packet[8],
+    };
+    Some((header, &packet[header_len..]))
+}
+
+/// Builds a packet with `header` around `payload`.
+pub fn build(header: &Header, payload: &[u8]) -> Vec<u8> {
+    build_packet(header, payload, true)
+}
+
+/// Builds a packet, leaving the header checksum zero for the device unless `with_checksum`.
+fn build_packet(header: &Header, payload: &[u8], with_checksum: bool) -> Vec<u8> {
+    let total_len = (HEADER_SIZE + payload.len()) as u16;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&[VERSION_IHL, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[header.ttl, header.protocol, 0, 0]);
+    packet.extend_from_slice(&header.src.0);
+    packet.extend_from_slice(&header.dst.0);
+    if with_checksum {
+        let sum = checksum(&packet);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Returns the address packets to `dst` are sent from.
+pub fn source_for(dst: Ipv4Addr) -> KResult<Ipv4Addr> {
+    Ok(super::route(dst)?.addr)
+}
+
+/// Largest payload that can be sent to `dst` in one packet.
+pub fn max_payload(dst: Ipv4Addr) -> KResult<usize> {
+    Ok(super::route(dst)?.device.mtu() - HEADER_SIZE)
+}
+
+/// Sends `payload` to `dst`. TCP and UDP segments carry a partial checksum, which is completed
+/// here unless the device does it.
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> KResult<()> {
+    send_with_ttl(dst, protocol, DEFAULT_TTL, payload)
+}
+
+pub fn send_with_ttl(dst: Ipv4Addr, protocol: u8, ttl: u8, payload: &[u8]) -> KResult<()> {
+    IP.out_requests.inc();
+    let interface = super::route(dst).inspect_err(|_| IP.out_no_routes.inc())?;
+    if HEADER_SIZE + payload.len() > interface.device.mtu() {
+        return Err(KError::MessageTooLong);
+    }
+    let header = Header {
+        src: interface.addr,
+        dst,
+        protocol,
+        ttl,
+    };
+    // the checksums the device doesn't insert are computed here
+    let offload = interface.device.checksum_offload().tx;
+    let mut packet = build_packet(&header, payload, !offload.ipv4);
+    if !offload.covers(protocol) {
+        super::finish_checksum(protocol, &mut packet[HEADER_SIZE..]);
+    }
+    super::output(&interface, dst, packet)
+}
+
+/// Handles a packet received on `interface`. The checksums in `verified` were checked by the
+/// device.
+pub fn input(interface: &Interface, packet: &[u8], verified: Checksums) {
+    IP.in_receives.inc();
+    let Some((header, payload)) = parse_checked(packet, verified.ipv4) else {
+        IP.in_hdr_errors.inc();
+        return;
+    };
+    let for_us = super::is_local(header.dst)
+        || header.dst == Ipv4Addr::BROADCAST
+        || header.dst == interface.broadcast();
+    if !for_us {
+        return;
+    }
+    IP.in_delivers.inc();
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::input(&header, payload),
+        PROTOCOL_TCP => tcp::input(&header, payload, verified.tcp),
+        PROTOCOL_UDP => udp::input(&header, payload, verified.udp),
+        _ => {}
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_build_and_parse() {
+    let header = Header {
+        src: Ipv4Addr([10, 0, 2, 15]),
+        dst: Ipv4Addr([10, 0, 2, 2]),
+        protocol: PROTOCOL_UDP,
+        ttl: 7,
+    };
+    let packet = build(&header, b"payload");
+    assert_eq!(packet.len(), HEADER_SIZE + 7);
+    assert_eq!(parse(&packet), Some((header, &b"payload"[..])));
+
+    // padding after the packet is ignored, a broken checksum or a fragment isn't
+    let mut padded = packet.clone();
+    padded.extend_from_slice(&[0; 10]);
+    assert_eq!(parse(&padded).map(|(_, payload)| payload.len()), Some(7));
+    let mut corrupted = packet.clone();
+    corrupted[8] ^= 1;
+    assert_eq!(parse(&corrupted), None);
+    let mut fragment = packet;
+    fragment[6] |= 0x20;
+    fragment[10..12].fill(0);
+    let sum = checksum(&fragment[..HEADER_SIZE]);
+    fragment[10..12].copy_from_slice(&sum.to_be_bytes());
+    assert_eq!(parse(&fragment), None);
+
+    // an ICMP error only quotes the start of a packet
+    let quoted = &padded[..HEADER_SIZE + 4];
+    assert_eq!(parse(quoted), None);
+    assert_eq!(parse_quoted(quoted), Some((header, &b"payl"[..])));
+}
@@ -0,0 +1,449 @@
+//! IPv4 networking.
+//!
+//! Every `NetDevice` is an interface with one address. Devices hand received packets to
+//! `receive`, which queues them for the `net` task, so protocol code never runs in a driver or
+//! while a sender holds locks. Packets are sent from the caller's task through `ipv4::send`,
+//! which picks the interface by longest prefix match, falling back to one with a gateway.
+//! Devices with a MAC address carry Ethernet frames, the next hop is resolved through the ARP
+//! cache. Timers of the protocols run in the `net-timer` task.
+//!
+//! The loopback interface `lo` carries bare IP packets for `127.0.0.0/8` and the addresses of
+//! every other interface, so sockets can talk to each other without any hardware.
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    device::Bus,
+    error::{KError, KResult},
+    sync::{Event, WaitQueue},
+    task, timer,
+};
+
+pub mod arp;
+pub mod device;
+pub mod ethernet;
+pub mod http;
+pub mod icmp;
+pub mod ipv4;
+pub mod pcap;
+pub mod sntp;
+pub mod stats;
+pub mod tcp;
+pub mod udp;
+
+pub use device::{ChecksumOffload, Checksums, Loopback, NetDevice};
+use stats::InterfaceStats;
+
+/// Packets waiting for the `net` task before new ones are dropped.
+const RX_QUEUE_SIZE: usize = 256;
+/// How often the `net-timer` task runs.
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Self = Self([0, 0, 0, 0]);
+    pub const LOCALHOST: Self = Self([127, 0, 0, 1]);
+    pub const BROADCAST: Self = Self([255, 255, 255, 255]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(addr: u32) -> Self {
+        Self(addr.to_be_bytes())
+    }
+
+    /// Whether the address lies in the network `net`/`prefix`.
+    pub fn in_subnet(self, net: Ipv4Addr, prefix: u8) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32u32.saturating_sub(prefix as u32))
+            .unwrap_or(0);
+        self.to_u32() & mask == net.to_u32() & mask
+    }
+
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = KError;
+
+    fn from_str(s: &str) -> KResult<Self> {
+        let mut addr = [0; 4];
+        let mut parts = s.split('.');
+        for byte in addr.iter_mut() {
+            let part = parts.next().ok_or(KError::InvalidArgument)?;
+            *byte = part.parse().map_err(|_| KError::InvalidArgument)?;
+        }
+        if parts.next().is_some() {
+            return Err(KError::InvalidArgument);
+        }
+        Ok(Self(addr))
+    }
+}
+
+/// An address and a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SocketAddr {
+    pub addr: Ipv4Addr,
+    pub port: u16,
+}
+
+impl SocketAddr {
+    pub fn new(addr: Ipv4Addr, port: u16) -> Self {
+        Self { addr, port }
+    }
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)
+    }
+}
+
+/// A device with its address.
+#[derive(Clone)]
+pub struct Interface {
+    pub device: Arc<dyn NetDevice>,
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+    /// Router for addresses outside the subnet.
+    pub gateway: Option<Ipv4Addr>,
+    pub stats: Arc<InterfaceStats>,
+}
+
+impl Interface {
+    pub fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    /// The broadcast address of the subnet.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        let host_mask = u32::MAX.checked_shr(self.prefix as u32).unwrap_or(0);
+        Ipv4Addr::from_u32(self.addr.to_u32() | host_mask)
+    }
+
+    /// Hands a frame, or a bare IP packet, to the device.
+    pub fn transmit(&self, packet: &[u8]) -> KResult<()> {
+        pcap::tap(self, packet);
+        let result = self.device.transmit(packet);
+        match result {
+            Ok(()) => {
+                self.stats.tx_packets.inc();
+                self.stats.tx_bytes.add(packet.len() as u64);
+            }
+            Err(_) => self.stats.tx_errors.inc(),
+        }
+        result
+    }
+}
+
+/// Received items waiting to be read from a socket. The oldest are dropped once `limit` wait.
+struct Inbox<T> {
+    items: Mutex<VecDeque<T>>,
+    readable: WaitQueue,
+    limit: usize,
+    /// items dropped because `limit` were waiting
+    dropped: stats::Counter,
+}
+
+impl<T: Send + 'static> Inbox<T> {
+    fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            items: Mutex::new(VecDeque::new()),
+            readable: WaitQueue::new(),
+            limit,
+            dropped: stats::Counter::new(),
+        })
+    }
+
+    /// Queues `item`. Returns whether the oldest item was dropped to make room.
+    fn push(&self, item: T) -> bool {
+        let dropped = without_interrupts(|| {
+            let mut items = self.items.lock();
+            let full = items.len() >= self.limit;
+            if full {
+                items.pop_front();
+            }
+            items.push_back(item);
+            full
+        });
+        if dropped {
+            self.dropped.inc();
+        }
+        self.readable.wake_all();
+        dropped
+    }
+
+    fn len(&self) -> usize {
+        without_interrupts(|| self.items.lock().len())
+    }
+
+    /// Takes the oldest item, blocking until one arrives. Fails with `TimedOut` if none came
+    /// within `timeout`.
+    fn pop(self: &Arc<Self>, timeout: Option<Duration>) -> KResult<T> {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let timer = timeout.map(|timeout| {
+            let (inbox, timed_out) = (self.clone(), timed_out.clone());
+            timer::schedule_in(timeout, move || {
+                timed_out.store(true, Ordering::Release);
+                inbox.readable.wake_all();
+            })
+        });
+        let mut item = None;
+        self.readable.wait_until(|| {
+            item = self.items.lock().pop_front();
+            item.is_some() || timed_out.load(Ordering::Acquire)
+        });
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
+        item.ok_or(KError::TimedOut)
+    }
+}
+
+static INTERFACES: Mutex<Vec<Interface>> = Mutex::new(Vec::new());
+/// Received packets with the device and the checksums it verified.
+static RX_QUEUE: Mutex<VecDeque<(String, Vec<u8>, Checksums)>> = Mutex::new(VecDeque::new());
+static RX_READY: Event = Event::new();
+static STARTED: Once<()> = Once::new();
+
+/// Brings up the loopback interface and starts the `net` and `net-timer` tasks. Requires the
+/// scheduler.
+pub fn init() {
+    STARTED.call_once(|| {
+        add_interface(Arc::new(Loopback), Ipv4Addr::LOCALHOST, 8, None);
+        let lo = crate::device::register("lo", Bus::Virtual, None, Vec::new());
+        crate::device::bind(lo, "loopback");
+        task::spawn("net", || loop {
+            RX_READY.wait_and_reset();
+            poll();
+        });
+        task::spawn("net-timer", || loop {
+            timer::sleep(TICK);
+            tcp::tick();
+            arp::tick();
+        });
+    });
+}
+
+/// Adds an interface for `device`, with `gateway` routing everything outside its subnet.
+pub fn add_interface(
+    device: Arc<dyn NetDevice>,
+    addr: Ipv4Addr,
+    prefix: u8,
+    gateway: Option<Ipv4Addr>,
+) {
+    let interface = Interface {
+        device,
+        addr,
+        prefix,
+        gateway,
+        stats: Arc::new(InterfaceStats::new()),
+    };
+    without_interrupts(|| INTERFACES.lock().push(interface));
+}
+
+pub fn interfaces() -> Vec<Interface> {
+    without_interrupts(|| INTERFACES.lock().clone())
+}
+
+/// Whether `addr` belongs to this machine.
+pub fn is_local(addr: Ipv4Addr) -> bool {
+    addr.is_loopback() || interfaces().iter().any(|interface| interface.addr == addr)
+}
+
+/// Returns the interface packets to `dst` leave through, the loopback interface for local
+/// addresses and the first one with a gateway for addresses outside every subnet.
+pub fn route(dst: Ipv4Addr) -> KResult<Interface> {
+    let interfaces = interfaces();
+    let local = is_local(dst);
+    let direct = interfaces
+        .iter()
+        .filter(|interface| {
+            if local {
+                interface.addr.is_loopback()
+            } else {
+                dst.in_subnet(interface.addr, interface.prefix)
+            }
+        })
+        .max_by_key(|interface| interface.prefix);
+    direct
+        .or_else(|| {
+            interfaces
+                .iter()
+                .find(|interface| interface.gateway.is_some())
+        })
+        .cloned()
+        .ok_or(KError::NetworkUnreachable)
+}
+
+/// Sends an IP packet to `dst` through `interface`, in a frame to the next hop if the device has
+/// a hardware address.
+fn output(interface: &Interface, dst: Ipv4Addr, packet: Vec<u8>) -> KResult<()> {
+    if interface.device.mac().is_none() {
+        return interface.transmit(&packet);
+    }
+    let next_hop = match interface.gateway {
+        Some(gateway)
+            if !dst.in_subnet(interface.addr, interface.prefix) && dst != Ipv4Addr::BROADCAST =>
+        {
+            gateway
+        }
+        _ => dst,
+    };
+    arp::output(interface, next_hop, packet)
+}
+
+/// Queues a packet received by `device` for the `net` task. Drops it if too many are waiting.
+/// Safe to call from interrupt handlers.
+pub fn receive(device: &str, packet: Vec<u8>) {
+    receive_checked(device, packet, Checksums::NONE);
+}
+
+/// Like `receive`, for a packet of which the device verified the checksums in `verified`.
+pub fn receive_checked(device: &str, packet: Vec<u8>, verified: Checksums) {
+    let queued = without_interrupts(|| {
+        let mut queue = RX_QUEUE.lock();
+        if queue.len() >= RX_QUEUE_SIZE {
+            return false;
+        }
+        queue.push_back((String::from(device), packet, verified));
+        true
+    });
+    if queued {
+        RX_READY.signal();
+    } else if let Some(interface) = find_interface(device) {
+        interface.stats.rx_dropped.inc();
+    }
+}
+
+fn find_interface(device: &str) -> Option<Interface> {
+    interfaces()
+        .into_iter()
+        .find(|interface| interface.name() == device)
+}
+
+/// Handles every queued packet.
+pub fn poll() {
+    while let Some((device, packet, verified)) = without_interrupts(|| RX_QUEUE.lock().pop_front())
+    {
+        let Some(interface) = find_interface(&device) else {
+            continue;
+        };
+        let verified = verified.intersect(interface.device.checksum_offload().rx);
+        interface.stats.rx_packets.inc();
+        interface.stats.rx_bytes.add(packet.len() as u64);
+        pcap::tap(&interface, &packet);
+        match interface.device.mac() {
+            Some(mac) => ethernet::input(&interface, mac, &packet, verified),
+            None => ipv4::input(&interface, &packet, verified),
+        }
+    }
+}
+
+/// Adds `data` to a running internet checksum (RFC 1071).
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Folds a running checksum into the value for the header.
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The internet checksum of `data`. Checking data including its checksum gives 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+fn pseudo_header_add(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let sum = checksum_add(checksum_add(0, &src.0), &dst.0);
+    sum + protocol as u32 + len as u32
+}
+
+/// The checksum of a UDP or TCP `segment` over the pseudo header and the segment, with its
+/// checksum field included.
+fn transport_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let sum = pseudo_header_add(src, dst, protocol, segment.len());
+    checksum_finish(checksum_add(sum, segment))
+}
+
+/// The sum of the pseudo header of a `len` bytes long segment, folded but not inverted. A
+/// segment carrying it in its checksum field only needs the sum over the segment to be complete.
+fn partial_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u16 {
+    !checksum_finish(pseudo_header_add(src, dst, protocol, len))
+}
+
+/// Offset of the checksum field in a segment of `protocol`, for the protocols whose checksum
+/// may be left partial.
+pub fn checksum_offset(protocol: u8) -> Option<usize> {
+    match protocol {
+        ipv4::PROTOCOL_TCP => Some(16),
+        ipv4::PROTOCOL_UDP => Some(6),
+        _ => None,
+    }
+}
+
+/// Completes the partial checksum of a TCP or UDP `segment`, what a device offering checksum
+/// offload does otherwise.
+pub fn finish_checksum(protocol: u8, segment: &mut [u8]) {
+    let Some(offset) = checksum_offset(protocol) else {
+        return;
+    };
+    let sum = match checksum(segment) {
+        // 0 means no checksum for UDP, it's sent as all ones instead
+        0 if protocol == ipv4::PROTOCOL_UDP => 0xffff,
+        sum => sum,
+    };
+    segment[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_checksum() {
+    // the example of RFC 1071
+    let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+    assert_eq!(checksum(&data), !0xddf2);
+    let mut with_checksum = data.to_vec();
+    with_checksum.extend_from_slice(&checksum(&data).to_be_bytes());
+    assert_eq!(checksum(&with_checksum), 0);
+    // odd lengths are padded with a zero
+    assert_eq!(checksum(&[0x12]), !0x1200);
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse_addresses() {
+    let addr: Ipv4Addr = "10.0.2.15".parse().unwrap();
+    assert_eq!(addr, Ipv4Addr([10, 0, 2, 15]));
+    assert!(addr.in_subnet(Ipv4Addr([10, 0, 2, 0]), 24));
+    assert!(!addr.in_subnet(Ipv4Addr([10, 0, 3, 0]), 24));
+    assert!(addr.in_subnet(Ipv4Addr::UNSPECIFIED, 0));
+    assert!("10.0.2".parse::<Ipv4Addr>().is_err());
+    assert!("10.0.2.256".parse::<Ipv4Addr>().is_err());
+    assert!("10.0.2.1.1".parse::<Ipv4Addr>().is_err());
+}
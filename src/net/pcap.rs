@@ -0,0 +1,207 @@
+//! Packet capture into a ring buffer, dumped in the pcap format.
+//!
+//! One device is captured at a time, as a pcap file has a single link type: Ethernet for devices
+//! with a hardware address, bare IP for the others. Frames are copied as they are handed to the
+//! device and as the `net` task takes them from the receive queue, cut to `SNAPLEN`. Once the
+//! ring holds its limit in bytes the oldest frames are dropped. A stopped capture keeps its
+//! frames until the next one starts. On the loopback interface every packet shows up twice, sent
+//! and received.
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::Interface;
+use crate::{
+    clock,
+    error::{KError, KResult},
+    time,
+};
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const VERSION: (u16, u16) = (2, 4);
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const FILE_HEADER_SIZE: usize = 24;
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// Bytes of a frame kept.
+pub const SNAPLEN: usize = 2048;
+/// Bytes of frames the ring holds unless told otherwise.
+pub const DEFAULT_LIMIT: usize = 1024 * 1024;
+
+struct Record {
+    /// Unix time, or the uptime while the clock isn't set
+    time: Duration,
+    /// length on the wire, the data may be cut short
+    len: usize,
+    data: Vec<u8>,
+}
+
+struct Capture {
+    device: String,
+    link_type: u32,
+    limit: usize,
+    /// bytes of frame data in `records`
+    size: usize,
+    records: VecDeque<Record>,
+    dropped: u64,
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+/// Whether frames are captured, checked before taking the lock.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The state of the capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub device: String,
+    pub running: bool,
+    pub frames: usize,
+    pub bytes: usize,
+    pub limit: usize,
+    /// frames dropped to make room
+    pub dropped: u64,
+}
+
+/// Starts capturing on `interface`, holding up to `limit` bytes of frames. Throws away what an
+/// earlier capture left. Fails with `Busy` while a capture is running.
+pub fn start(interface: &Interface, limit: usize) -> KResult<()> {
+    let link_type = match interface.device.mac() {
+        Some(_) => LINKTYPE_ETHERNET,
+        None => LINKTYPE_RAW,
+    };
+    without_interrupts(|| {
+        let mut capture = CAPTURE.lock();
+        if RUNNING.load(Ordering::Relaxed) {
+            return Err(KError::Busy);
+        }
+        *capture = Some(Capture {
+            device: String::from(interface.name()),
+            link_type,
+            limit,
+            size: 0,
+            records: VecDeque::new(),
+            dropped: 0,
+        });
+        RUNNING.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// Stops the capture, keeping the frames for `dump`. Returns whether one was running.
+pub fn stop() -> bool {
+    without_interrupts(|| {
+        let _capture = CAPTURE.lock();
+        RUNNING.swap(false, Ordering::Relaxed)
+    })
+}
+
+/// The state of the running or last capture, `None` if there never was one.
+pub fn status() -> Option<Status> {
+    without_interrupts(|| {
+        let capture = CAPTURE.lock();
+        let capture = capture.as_ref()?;
+        Some(Status {
+            device: capture.device.clone(),
+            running: RUNNING.load(Ordering::Relaxed),
+            frames: capture.records.len(),
+            bytes: capture.size,
+            limit: capture.limit,
+            dropped: capture.dropped,
+        })
+    })
+}
+
+/// Copies a frame sent or received on `interface` if it is being captured.
+pub(super) fn tap(interface: &Interface, frame: &[u8]) {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    let now = clock::now().unwrap_or_else(time::uptime);
+    let data = frame[..frame.len().min(SNAPLEN)].to_vec();
+    without_interrupts(|| {
+        let mut capture = CAPTURE.lock();
+        let Some(capture) = capture.as_mut() else {
+            return;
+        };
+        if !RUNNING.load(Ordering::Relaxed) || capture.device != interface.name() {
+            return;
+        }
+        while capture.size + data.len() > capture.limit {
+            let Some(oldest) = capture.records.pop_front() else {
+                // larger than the whole ring
+                capture.dropped += 1;
+                return;
+            };
+            capture.size -= oldest.data.len();
+            capture.dropped += 1;
+        }
+        capture.size += data.len();
+        capture.records.push_back(Record {
+            time: now,
+            len: frame.len(),
+            data,
+        });
+    });
+}
+
+/// Returns the captured frames as a pcap file, `None` if there never was a capture.
+pub fn dump() -> Option<Vec<u8>> {
+    without_interrupts(|| {
+        let capture = CAPTURE.lock();
+        let capture = capture.as_ref()?;
+        let records_size: usize = capture
+            .records
+            .iter()
+            .map(|record| RECORD_HEADER_SIZE + record.data.len())
+            .sum();
+        let mut file = Vec::with_capacity(FILE_HEADER_SIZE + records_size);
+        write_file_header(&mut file, capture.link_type);
+        for record in &capture.records {
+            write_record(&mut file, record);
+        }
+        Some(file)
+    })
+}
+
+fn write_file_header(file: &mut Vec<u8>, link_type: u32) {
+    file.extend_from_slice(&MAGIC.to_le_bytes());
+    file.extend_from_slice(&VERSION.0.to_le_bytes());
+    file.extend_from_slice(&VERSION.1.to_le_bytes());
+    // time zone offset and timestamp accuracy
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&(SNAPLEN as u32).to_le_bytes());
+    file.extend_from_slice(&link_type.to_le_bytes());
+}
+
+fn write_record(file: &mut Vec<u8>, record: &Record) {
+    file.extend_from_slice(&(record.time.as_secs() as u32).to_le_bytes());
+    file.extend_from_slice(&record.time.subsec_micros().to_le_bytes());
+    file.extend_from_slice(&(record.data.len() as u32).to_le_bytes());
+    file.extend_from_slice(&(record.len as u32).to_le_bytes());
+    file.extend_from_slice(&record.data);
+}
+
+#[test_case]
+fn test_write_record() {
+    let mut file = Vec::new();
+    write_file_header(&mut file, LINKTYPE_RAW);
+    assert_eq!(file.len(), FILE_HEADER_SIZE);
+    assert_eq!(&file[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(&file[20..24], &[101, 0, 0, 0]);
+
+    let record = Record {
+        time: Duration::new(1_700_000_000, 1_500_000),
+        len: 3000,
+        data: alloc::vec![7; SNAPLEN],
+    };
+    write_record(&mut file, &record);
+    let header = &file[FILE_HEADER_SIZE..FILE_HEADER_SIZE + RECORD_HEADER_SIZE];
+    assert_eq!(header[..4], 1_700_000_000u32.to_le_bytes());
+    assert_eq!(header[4..8], 1500u32.to_le_bytes());
+    assert_eq!(header[8..12], (SNAPLEN as u32).to_le_bytes());
+    assert_eq!(header[12..16], 3000u32.to_le_bytes());
+    assert_eq!(file.len(), FILE_HEADER_SIZE + RECORD_HEADER_SIZE + SNAPLEN);
+}
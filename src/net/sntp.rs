@@ -0,0 +1,146 @@
+//! SNTP client (RFC 4330) setting the wall clock.
+//!
+//! Configured with `ntp=<address>` in the system configuration. The server is queried right away
+//! and then every `SYNC_INTERVAL`, the clock measures the drift of the timer from how far it
+//! went off in between.
+use core::time::Duration;
+
+use super::{udp::UdpSocket, Ipv4Addr, SocketAddr};
+use crate::{
+    clock::{self, DateTime},
+    error::{KError, KResult},
+    klog::Level,
+    klogln_at, task, time, timer,
+};
+
+pub const PORT: u16 = 123;
+
+const PACKET_SIZE: usize = 48;
+/// Version 4, client mode.
+const CLIENT_HEADER: u8 = (4 << 3) | 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+/// Seconds from the NTP epoch, 1900, to the Unix epoch.
+const UNIX_OFFSET: u64 = 2_208_988_800;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const SYNC_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Starts the task keeping the clock in sync with `server`.
+pub fn start(server: Ipv4Addr) {
+    task::spawn("sntp", move || loop {
+        let interval = match query(server) {
+            Ok(now) => {
+                let offset = clock::sync(now);
+                let date = DateTime::from_unix(now.as_secs());
+                match offset {
+                    Some(offset) => klogln_at!(
+                        Level::Info,
+                        "sntp: {}, off by {}ms, drift {}ppm",
+                        date,
+                        offset / 1_000_000,
+                        clock::drift_ppm()
+                    ),
+                    None => klogln_at!(Level::Info, "sntp: clock set to {}", date),
+                }
+                SYNC_INTERVAL
+            }
+            Err(e) => {
+                klogln_at!(Level::Warn, "sntp: {}: {}", server, e);
+                RETRY_INTERVAL
+            }
+        };
+        timer::sleep(interval);
+    });
+}
+
+/// Asks `server` for the time. Returns the Unix time at the moment of returning, corrected by
+/// half the round trip.
+pub fn query(server: Ipv4Addr) -> KResult<Duration> {
+    let socket = UdpSocket::bind(0)?;
+    // the server echoes this back, telling its answer apart from a stale one
+    let sent = time::uptime();
+    let mut request = [0; PACKET_SIZE];
+    request[0] = CLIENT_HEADER;
+    request[40..48].copy_from_slice(&to_timestamp(sent));
+    socket.send_to(&request, SocketAddr::new(server, PORT))?;
+
+    let deadline = sent + TIMEOUT;
+    loop {
+        let remaining = deadline
+            .checked_sub(time::uptime())
+            .ok_or(KError::TimedOut)?;
+        let datagram = socket.recv_from(Some(remaining))?;
+        if datagram.from.addr != server {
+            continue;
+        }
+        let received = time::uptime();
+        if let Some(now) = parse_response(&datagram.data, sent, received) {
+            return Ok(now + (time::uptime() - received));
+        }
+    }
+}
+
+/// Splits a duration into an NTP timestamp, seconds and a binary fraction.
+fn to_timestamp(time: Duration) -> [u8; 8] {
+    let fraction = ((time.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    let mut timestamp = [0; 8];
+    timestamp[..4].copy_from_slice(&(time.as_secs() as u32).to_be_bytes());
+    timestamp[4..].copy_from_slice(&(fraction as u32).to_be_bytes());
+    timestamp
+}
+
+fn from_timestamp(timestamp: &[u8]) -> Duration {
+    let secs = u32::from_be_bytes(timestamp[..4].try_into().unwrap());
+    let fraction = u32::from_be_bytes(timestamp[4..8].try_into().unwrap());
+    let nanos = (fraction as u64 * 1_000_000_000) >> 32;
+    Duration::new(secs as u64, nanos as u32)
+}
+
+/// The Unix time at uptime `received` from an answer to the request sent at uptime `sent`.
+/// `None` if it isn't one, or the server isn't synchronized itself.
+fn parse_response(packet: &[u8], sent: Duration, received: Duration) -> Option<Duration> {
+    if packet.len() < PACKET_SIZE {
+        return None;
+    }
+    let leap = packet[0] >> 6;
+    let mode = packet[0] & 7;
+    let stratum = packet[1];
+    if mode != MODE_SERVER || leap == LEAP_UNSYNCHRONIZED || stratum == 0 {
+        return None;
+    }
+    if packet[24..32] != to_timestamp(sent) {
+        return None;
+    }
+    let server_received = from_timestamp(&packet[32..40]);
+    let server_sent = from_timestamp(&packet[40..48]);
+    let server_time = server_sent.checked_sub(Duration::from_secs(UNIX_OFFSET))?;
+    // the time spent on the way, without the time the server took to answer
+    let round_trip = (received - sent).saturating_sub(server_sent.saturating_sub(server_received));
+    Some(server_time + round_trip / 2)
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse_response() {
+    let sent = Duration::from_millis(1500);
+    let received = Duration::from_millis(1540);
+    let unix = Duration::from_secs(1_700_000_000);
+    let ntp = unix + Duration::from_secs(UNIX_OFFSET);
+
+    let mut packet = [0; PACKET_SIZE];
+    packet[0] = (4 << 3) | MODE_SERVER;
+    packet[1] = 2;
+    packet[24..32].copy_from_slice(&to_timestamp(sent));
+    packet[32..40].copy_from_slice(&to_timestamp(ntp));
+    packet[40..48].copy_from_slice(&to_timestamp(ntp + Duration::from_millis(10)));
+    // 40ms round trip of which the server took 10ms
+    let now = parse_response(&packet, sent, received).unwrap();
+    let expected = unix + Duration::from_millis(25);
+    assert!(now.abs_diff(expected) < Duration::from_micros(1));
+
+    // an answer to another request, or from an unsynchronized server
+    assert_eq!(parse_response(&packet, received, received), None);
+    packet[1] = 0;
+    assert_eq!(parse_response(&packet, sent, received), None);
+}
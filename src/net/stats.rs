@@ -0,0 +1,270 @@
+//! Counters of the interfaces and protocols, and the files of `/proc/net`.
+//!
+//! Counters only go up and are bumped without locks, so a snapshot of several of them may be
+//! off by the packets in flight. The files follow the layout of Linux's, with the columns that
+//! don't apply left out.
+use alloc::{format, string::String, vec, vec::Vec};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{arp, ethernet::MacAddr, icmp, tcp, udp, SocketAddr};
+
+/// A counter that only goes up.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Declares a struct of counters, with their names as Linux reports them.
+macro_rules! counters {
+    (
+        $(#[$meta:meta])*
+        $name:ident { $($(#[$field_meta:meta])* $field:ident: $label:literal),* $(,)? }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $($(#[$field_meta])* pub $field: Counter,)*
+        }
+
+        impl $name {
+            pub const fn new() -> Self {
+                Self {
+                    $($field: Counter::new(),)*
+                }
+            }
+
+            /// The counters with their names.
+            pub fn fields(&self) -> Vec<(&'static str, u64)> {
+                vec![$(($label, self.$field.get()),)*]
+            }
+        }
+    };
+}
+
+counters!(
+    /// Traffic through an interface.
+    InterfaceStats {
+        rx_packets: "RX-OK",
+        rx_bytes: "RX-bytes",
+        /// malformed frames
+        rx_errors: "RX-ERR",
+        /// packets the `net` task had no room for
+        rx_dropped: "RX-DRP",
+        tx_packets: "TX-OK",
+        tx_bytes: "TX-bytes",
+        /// packets the device failed to send
+        tx_errors: "TX-ERR",
+    }
+);
+
+counters!(IpStats {
+    in_receives: "InReceives",
+    in_hdr_errors: "InHdrErrors",
+    in_delivers: "InDelivers",
+    out_requests: "OutRequests",
+    out_no_routes: "OutNoRoutes",
+});
+
+counters!(IcmpStats {
+    in_msgs: "InMsgs",
+    in_errors: "InErrors",
+    in_echos: "InEchos",
+    out_msgs: "OutMsgs",
+    out_echo_reps: "OutEchoReps",
+});
+
+counters!(TcpStats {
+    active_opens: "ActiveOpens",
+    passive_opens: "PassiveOpens",
+    in_segs: "InSegs",
+    out_segs: "OutSegs",
+    retrans_segs: "RetransSegs",
+    in_errs: "InErrs",
+    out_rsts: "OutRsts",
+});
+
+counters!(UdpStats {
+    in_datagrams: "InDatagrams",
+    no_ports: "NoPorts",
+    in_errors: "InErrors",
+    out_datagrams: "OutDatagrams",
+    /// datagrams dropped because a socket's queue was full
+    rcvbuf_errors: "RcvbufErrors",
+});
+
+pub static IP: IpStats = IpStats::new();
+pub static ICMP: IcmpStats = IcmpStats::new();
+pub static TCP: TcpStats = TcpStats::new();
+pub static UDP: UdpStats = UdpStats::new();
+
+/// The protocol counters by protocol name.
+pub fn protocols() -> [(&'static str, Vec<(&'static str, u64)>); 4] {
+    [
+        ("Ip", IP.fields()),
+        ("Icmp", ICMP.fields()),
+        ("Tcp", TCP.fields()),
+        ("Udp", UDP.fields()),
+    ]
+}
+
+/// A socket, as listed by `netstat`.
+#[derive(Debug, Clone)]
+pub struct SocketInfo {
+    pub local: SocketAddr,
+    /// unspecified for sockets that aren't connected
+    pub remote: SocketAddr,
+    /// `None` for sockets without connections
+    pub state: Option<tcp::State>,
+    /// bytes, or for datagram sockets datagrams, waiting to be read
+    pub recv_queue: usize,
+    /// bytes not yet acknowledged by the peer
+    pub send_queue: usize,
+    /// received items dropped because the queue was full
+    pub drops: u64,
+}
+
+/// Generates `/proc/net/dev`.
+pub fn dev_file() -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Inter-|   Receive                            |  Transmit"
+    );
+    let _ = writeln!(
+        out,
+        " face |bytes    packets errs drop|bytes    packets errs"
+    );
+    for interface in super::interfaces() {
+        let stats = &interface.stats;
+        let _ = writeln!(
+            out,
+            "{:>6}: {:>8} {:>7} {:>4} {:>4} {:>8} {:>7} {:>4}",
+            interface.name(),
+            stats.rx_bytes.get(),
+            stats.rx_packets.get(),
+            stats.rx_errors.get(),
+            stats.rx_dropped.get(),
+            stats.tx_bytes.get(),
+            stats.tx_packets.get(),
+            stats.tx_errors.get()
+        );
+    }
+    out
+}
+
+/// Generates `/proc/net/snmp`, a line of names and a line of values for every protocol.
+pub fn snmp_file() -> String {
+    let mut out = String::new();
+    for (protocol, fields) in protocols() {
+        let _ = write!(out, "{}:", protocol);
+        for (name, _) in &fields {
+            let _ = write!(out, " {}", name);
+        }
+        let _ = write!(out, "\n{}:", protocol);
+        for (_, value) in &fields {
+            let _ = write!(out, " {}", value);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// An address as Linux writes it in the socket files, in hex and in host byte order.
+fn hex_addr(addr: SocketAddr) -> String {
+    format!("{:08X}:{:04X}", u32::from_le_bytes(addr.addr.0), addr.port)
+}
+
+fn sockets_file(sockets: Vec<SocketInfo>) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "  sl  local_address rem_address   st tx_queue rx_queue drops"
+    );
+    for (i, socket) in sockets.into_iter().enumerate() {
+        // unconnected sockets are closed, as far as TCP's states go
+        let state = socket.state.map_or(7, tcp::State::code);
+        let _ = writeln!(
+            out,
+            "{:>4}: {} {} {:02X} {:08X}:{:08X} {}",
+            i,
+            hex_addr(socket.local),
+            hex_addr(socket.remote),
+            state,
+            socket.send_queue,
+            socket.recv_queue,
+            socket.drops
+        );
+    }
+    out
+}
+
+/// Generates `/proc/net/tcp`.
+pub fn tcp_file() -> String {
+    sockets_file(tcp::sockets())
+}
+
+/// Generates `/proc/net/udp`.
+pub fn udp_file() -> String {
+    sockets_file(udp::sockets())
+}
+
+/// Generates `/proc/net/raw`, where the port is the protocol.
+pub fn raw_file() -> String {
+    sockets_file(icmp::sockets())
+}
+
+/// Generates `/proc/net/arp`.
+pub fn arp_file() -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "IP address       HW type     Flags       HW address            Mask     Device"
+    );
+    for neighbor in arp::neighbors() {
+        let flags = match neighbor.state {
+            arp::State::Reachable => 0x2,
+            arp::State::Incomplete => 0x0,
+        };
+        let _ = writeln!(
+            out,
+            "{:<16} 0x1         {:<#11x} {:<21} *        {}",
+            format!("{}", neighbor.addr),
+            flags,
+            format!("{}", neighbor.mac.unwrap_or(MacAddr::ZERO)),
+            neighbor.device
+        );
+    }
+    out
+}
+
+#[test_case]
+fn test_snmp_file() {
+    let counters = UdpStats::new();
+    counters.no_ports.add(3);
+    assert_eq!(counters.fields()[1], ("NoPorts", 3));
+
+    let file = snmp_file();
+    let mut lines = file.lines();
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("Ip: InReceives InHdrErrors"));
+    let values = lines.next().unwrap();
+    assert_eq!(values.split(' ').count(), IP.fields().len() + 1);
+    assert_eq!(file.lines().count(), 2 * protocols().len());
+}
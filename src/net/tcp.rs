@@ -0,0 +1,888 @@
+//! TCP connections.
+//!
+//! Kept small: there is no congestion control, segments arriving out of order are dropped for
+//! the peer to send again, and once the retransmission timer fires everything unacknowledged is
+//! sent again (go-back-N). The only option understood is the maximum segment size.
+//!
+//! Segments are handled in the `net` task, retransmissions and expired connections in the
+//! `net-timer` task. Sockets block on the connection's wait queue until its state lets them go on.
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{
+    ipv4, partial_checksum,
+    stats::{SocketInfo, TCP},
+    transport_checksum, Inbox, Ipv4Addr, SocketAddr,
+};
+use crate::{
+    error::{KError, KResult},
+    sync::WaitQueue,
+    time, timer, tsc,
+};
+
+pub const HEADER_SIZE: usize = 20;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Bytes of the send and of the receive buffer of every connection. Also the largest window
+/// offered, as there is no window scaling.
+const BUFFER_SIZE: usize = 32 * 1024;
+/// Segment size assumed if the peer doesn't say.
+const DEFAULT_MSS: usize = 536;
+/// Connections waiting to be accepted before further ones are ignored.
+const BACKLOG: usize = 16;
+/// The first retransmission timeout, it doubles with every retry.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRIES: u32 = 5;
+/// How long a closed connection keeps its port to catch late segments.
+const TIME_WAIT: Duration = Duration::from_secs(2);
+/// How long a dropped socket may take to finish closing.
+const LINGER: Duration = Duration::from_secs(30);
+/// Ports handed out to outgoing connections.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Only reported for listeners, connections never are in it.
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl State {
+    /// The name `netstat` shows.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Listen => "LISTEN",
+            Self::SynSent => "SYN_SENT",
+            Self::SynReceived => "SYN_RECV",
+            Self::Established => "ESTABLISHED",
+            Self::FinWait1 => "FIN_WAIT1",
+            Self::FinWait2 => "FIN_WAIT2",
+            Self::CloseWait => "CLOSE_WAIT",
+            Self::Closing => "CLOSING",
+            Self::LastAck => "LAST_ACK",
+            Self::TimeWait => "TIME_WAIT",
+            Self::Closed => "CLOSE",
+        }
+    }
+
+    /// The number Linux gives the state in `/proc/net/tcp`.
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Established => 1,
+            Self::SynSent => 2,
+            Self::SynReceived => 3,
+            Self::FinWait1 => 4,
+            Self::FinWait2 => 5,
+            Self::TimeWait => 6,
+            Self::Closed => 7,
+            Self::CloseWait => 8,
+            Self::LastAck => 9,
+            Self::Listen => 10,
+            Self::Closing => 11,
+        }
+    }
+}
+
+/// A parsed segment, or one to be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Sequence numbers taken by the segment, SYN and FIN count as one.
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+/// Segments to send once the connection is unlocked, with their destination.
+type Outgoing = Vec<(Ipv4Addr, Vec<u8>)>;
+
+/// The transmission control block, everything known about a connection.
+struct Tcb {
+    state: State,
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// oldest unacknowledged sequence number
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    /// largest segment the peer accepts
+    mss: usize,
+    /// data from `snd_una` on, first what was sent but not acknowledged, then what wasn't sent
+    send: VecDeque<u8>,
+    /// whether the socket was closed, a FIN follows the data in `send`
+    fin_queued: bool,
+    rcv_nxt: u32,
+    recv: VecDeque<u8>,
+    fin_received: bool,
+    error: Option<KError>,
+    retransmit_at: Option<Duration>,
+    retries: u32,
+    /// when the connection is given up on, for `TimeWait` and dropped sockets
+    expires: Option<Duration>,
+}
+
+impl Tcb {
+    fn new(state: State, local: SocketAddr, remote: SocketAddr) -> Self {
+        let iss = tsc::read() as u32;
+        Self {
+            state,
+            local,
+            remote,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            send: VecDeque::new(),
+            fin_queued: false,
+            rcv_nxt: 0,
+            recv: VecDeque::new(),
+            fin_received: false,
+            error: None,
+            retransmit_at: None,
+            retries: 0,
+            expires: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (BUFFER_SIZE - self.recv.len()) as u16
+    }
+
+    /// Largest segment this side accepts, so the peer's segments fit the interface.
+    fn local_mss(&self) -> u16 {
+        let mtu_payload = ipv4::max_payload(self.remote.addr).unwrap_or(DEFAULT_MSS);
+        mtu_payload
+            .saturating_sub(HEADER_SIZE)
+            .min(u16::MAX as usize) as u16
+    }
+
+    fn segment(&self, seq: u32, flags: u8, data: &[u8]) -> (Ipv4Addr, Vec<u8>) {
+        let segment = Segment {
+            src_port: self.local.port,
+            dst_port: self.remote.port,
+            seq,
+            ack: if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window: self.window(),
+            mss: (flags & SYN != 0).then(|| self.local_mss()),
+            data,
+        };
+        (
+            self.remote.addr,
+            build(self.local.addr, self.remote.addr, &segment),
+        )
+    }
+
+    fn ack_segment(&self) -> (Ipv4Addr, Vec<u8>) {
+        self.segment(self.snd_nxt, ACK, &[])
+    }
+
+    fn retransmit_timeout(&self) -> Duration {
+        RETRANSMIT_TIMEOUT * (1 << self.retries)
+    }
+
+    fn enter(&mut self, state: State) {
+        self.state = state;
+        if state == State::TimeWait {
+            self.expires = Some(time::uptime() + TIME_WAIT);
+        }
+    }
+
+    fn fail(&mut self, error: KError) {
+        self.error = Some(error);
+        self.state = State::Closed;
+        self.send.clear();
+        self.retransmit_at = None;
+    }
+
+    /// Sends whatever is due: the SYN, data that fits the peer's window and the FIN.
+    fn output(&mut self, out: &mut Outgoing) {
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                if self.snd_nxt == self.snd_una {
+                    let flags = match self.state {
+                        State::SynSent => SYN,
+                        _ => SYN | ACK,
+                    };
+                    out.push(self.segment(self.snd_una, flags, &[]));
+                    self.snd_nxt = self.snd_una.wrapping_add(1);
+                }
+            }
+            State::Established
+            | State::CloseWait
+            | State::FinWait1
+            | State::Closing
+            | State::LastAck => {
+                let window_end = self.snd_una.wrapping_add(self.snd_wnd);
+                loop {
+                    let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                    let room = window_end.wrapping_sub(self.snd_nxt) as i32;
+                    if sent >= self.send.len() || room <= 0 {
+                        break;
+                    }
+                    let len = (self.send.len() - sent).min(self.mss).min(room as usize);
+                    let data: Vec<u8> = self.send.range(sent..sent + len).copied().collect();
+                    out.push(self.segment(self.snd_nxt, ACK | PSH, &data));
+                    self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+                }
+                let fin_seq = self.snd_una.wrapping_add(self.send.len() as u32);
+                if self.fin_queued && self.snd_nxt == fin_seq {
+                    out.push(self.segment(fin_seq, FIN | ACK, &[]));
+                    self.snd_nxt = fin_seq.wrapping_add(1);
+                }
+            }
+            State::Listen | State::FinWait2 | State::TimeWait | State::Closed => {}
+        }
+        if self.snd_nxt != self.snd_una && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(time::uptime() + self.retransmit_timeout());
+        }
+    }
+
+    /// Handles the acknowledgment of everything before `ack`.
+    fn acknowledge(&mut self, ack: u32) {
+        let acked = ack.wrapping_sub(self.snd_una);
+        if acked == 0 || acked > self.snd_nxt.wrapping_sub(self.snd_una) {
+            return;
+        }
+        let data = (acked as usize).min(self.send.len());
+        self.send.drain(..data);
+        self.snd_una = ack;
+        self.retries = 0;
+        self.retransmit_at = None;
+        if self.snd_nxt != self.snd_una {
+            self.retransmit_at = Some(time::uptime() + self.retransmit_timeout());
+        }
+        // the rest acknowledges the FIN
+        if data < acked as usize {
+            match self.state {
+                State::FinWait1 => self.enter(State::FinWait2),
+                State::Closing => self.enter(State::TimeWait),
+                State::LastAck => self.enter(State::Closed),
+                _ => {}
+            }
+        }
+    }
+
+    fn input_syn_sent(&mut self, segment: &Segment, out: &mut Outgoing) {
+        let acceptable = segment.flags & ACK != 0 && segment.ack == self.snd_nxt;
+        if segment.flags & ACK != 0 && !acceptable {
+            if segment.flags & RST == 0 {
+                out.push(reset(self.local, self.remote, segment));
+            }
+            return;
+        }
+        if segment.flags & RST != 0 {
+            if acceptable {
+                self.fail(KError::ConnectionRefused);
+            }
+            return;
+        }
+        if segment.flags & SYN != 0 && acceptable {
+            self.rcv_nxt = segment.seq.wrapping_add(1);
+            self.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+            self.mss = self.mss.min(self.local_mss() as usize);
+            self.snd_una = segment.ack;
+            self.snd_wnd = segment.window as u32;
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.enter(State::Established);
+            out.push(self.ack_segment());
+        }
+    }
+
+    /// Handles a segment for this connection.
+    fn input(&mut self, segment: &Segment, out: &mut Outgoing) {
+        if self.state == State::SynSent {
+            return self.input_syn_sent(segment, out);
+        }
+        if segment.flags & RST != 0 {
+            if segment.seq == self.rcv_nxt {
+                self.fail(match self.state {
+                    State::SynReceived => KError::ConnectionRefused,
+                    _ => KError::ConnectionReset,
+                });
+            }
+            return;
+        }
+        if segment.seq != self.rcv_nxt {
+            // a retransmission or out of order, tell the peer what is expected next
+            if segment.len() > 0 {
+                out.push(self.ack_segment());
+            }
+            return;
+        }
+        if segment.flags & ACK == 0 {
+            return;
+        }
+        if self.state == State::SynReceived {
+            if segment.ack != self.snd_nxt {
+                out.push(reset(self.local, self.remote, segment));
+                return;
+            }
+            self.snd_una = segment.ack;
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.enter(State::Established);
+        }
+        self.acknowledge(segment.ack);
+        self.snd_wnd = segment.window as u32;
+
+        let mut need_ack = false;
+        let mut complete = true;
+        let receiving = matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        );
+        if receiving && !segment.data.is_empty() {
+            let len = segment.data.len().min(BUFFER_SIZE - self.recv.len());
+            self.recv.extend(&segment.data[..len]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+            complete = len == segment.data.len();
+            need_ack = true;
+        }
+        if receiving && complete && segment.flags & FIN != 0 {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            need_ack = true;
+            match self.state {
+                State::Established => self.enter(State::CloseWait),
+                State::FinWait1 => self.enter(State::Closing),
+                _ => self.enter(State::TimeWait),
+            }
+        }
+        if need_ack {
+            out.push(self.ack_segment());
+        }
+        self.output(out);
+    }
+
+    /// Queues the FIN after the data still to be sent.
+    fn close(&mut self, out: &mut Outgoing) {
+        match self.state {
+            State::Established => self.enter(State::FinWait1),
+            State::CloseWait => self.enter(State::LastAck),
+            State::SynSent | State::SynReceived => {
+                self.enter(State::Closed);
+                return;
+            }
+            _ => return,
+        }
+        self.fin_queued = true;
+        self.output(out);
+    }
+}
+
+struct Connection {
+    /// local port and peer, the key in `CONNECTIONS`
+    key: (u16, SocketAddr),
+    tcb: Mutex<Tcb>,
+    /// woken whenever the state changes
+    changed: WaitQueue,
+}
+
+impl Connection {
+    fn new(tcb: Tcb) -> Arc<Self> {
+        Arc::new(Self {
+            key: (tcb.local.port, tcb.remote),
+            tcb: Mutex::new(tcb),
+            changed: WaitQueue::new(),
+        })
+    }
+
+    /// Runs `f` on the control block, then sends what it queued and wakes the waiting tasks.
+    fn update<T>(&self, f: impl FnOnce(&mut Tcb, &mut Outgoing) -> T) -> T {
+        let mut out = Vec::new();
+        let (result, closed) = without_interrupts(|| {
+            let mut tcb = self.tcb.lock();
+            let result = f(&mut tcb, &mut out);
+            (result, tcb.state == State::Closed)
+        });
+        transmit(out);
+        if closed {
+            without_interrupts(|| CONNECTIONS.lock().remove(&self.key));
+        }
+        self.changed.wake_all();
+        result
+    }
+
+    /// Blocks until `cond` holds for the control block. Fails with `TimedOut` if it didn't within
+    /// `timeout`.
+    fn wait(
+        self: &Arc<Self>,
+        timeout: Option<Duration>,
+        mut cond: impl FnMut(&mut Tcb) -> bool,
+    ) -> KResult<()> {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let timer = timeout.map(|timeout| {
+            let (connection, timed_out) = (self.clone(), timed_out.clone());
+            timer::schedule_in(timeout, move || {
+                timed_out.store(true, Ordering::Release);
+                connection.changed.wake_all();
+            })
+        });
+        let mut done = false;
+        self.changed.wait_until(|| {
+            done = cond(&mut self.tcb.lock());
+            done || timed_out.load(Ordering::Acquire)
+        });
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
+        if done {
+            Ok(())
+        } else {
+            Err(KError::TimedOut)
+        }
+    }
+}
+
+static CONNECTIONS: Mutex<BTreeMap<(u16, SocketAddr), Arc<Connection>>> =
+    Mutex::new(BTreeMap::new());
+static LISTENERS: Mutex<BTreeMap<u16, Arc<Inbox<TcpStream>>>> = Mutex::new(BTreeMap::new());
+
+fn port_in_use(port: u16) -> bool {
+    LISTENERS.lock().contains_key(&port)
+        || CONNECTIONS.lock().keys().any(|(local, _)| *local == port)
+}
+
+/// Retransmits what is overdue and closes expired connections.
+pub(super) fn tick() {
+    let now = time::uptime();
+    let connections: Vec<_> = without_interrupts(|| CONNECTIONS.lock().values().cloned().collect());
+    for connection in connections {
+        connection.update(|tcb, out| {
+            if tcb.expires.is_some_and(|expires| now >= expires) {
+                tcb.enter(State::Closed);
+            } else if tcb.retransmit_at.is_some_and(|at| now >= at) {
+                tcb.retries += 1;
+                if tcb.retries > MAX_RETRIES {
+                    tcb.fail(KError::TimedOut);
+                } else {
+                    tcb.snd_nxt = tcb.snd_una;
+                    tcb.retransmit_at = None;
+                    let sent = out.len();
+                    tcb.output(out);
+                    TCP.retrans_segs.add((out.len() - sent) as u64);
+                }
+            }
+        });
+    }
+}
+
+/// A connection.
+pub struct TcpStream {
+    connection: Arc<Connection>,
+}
+
+impl TcpStream {
+    /// Connects to `to`. Fails with `ConnectionRefused` if nothing listens there, or `TimedOut`
+    /// if the peer didn't answer.
+    pub fn connect(to: SocketAddr) -> KResult<Self> {
+        let local = ipv4::source_for(to.addr)?;
+        let connection = without_interrupts(|| {
+            let port = EPHEMERAL_PORTS
+                .clone()
+                .find(|port| !port_in_use(*port))
+                .ok_or(KError::AddressInUse)?;
+            let tcb = Tcb::new(State::SynSent, SocketAddr::new(local, port), to);
+            let connection = Connection::new(tcb);
+            CONNECTIONS
+                .lock()
+                .insert(connection.key, connection.clone());
+            Ok::<_, KError>(connection)
+        })?;
+        TCP.active_opens.inc();
+        connection.update(|tcb, out| tcb.output(out));
+
+        let mut result = Ok(());
+        connection.wait(None, |tcb| {
+            if let Some(error) = tcb.error {
+                result = Err(error);
+            }
+            tcb.state != State::SynSent
+        })?;
+        result.map(|()| Self { connection })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        without_interrupts(|| self.connection.tcb.lock().local)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.key.1
+    }
+
+    /// Reads what was received, blocking until there is something. Returns 0 once the peer
+    /// closed its side, fails with `TimedOut` if nothing came within `timeout`.
+    pub fn read(&self, buf: &mut [u8], timeout: Option<Duration>) -> KResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut result = Ok(0);
+        let mut window_opened = false;
+        self.connection.wait(timeout, |tcb| {
+            if !tcb.recv.is_empty() {
+                // the peer stops sending once the window gets this small
+                let small = tcb.mss.min(BUFFER_SIZE / 2);
+                let was_small = (tcb.window() as usize) < small;
+                let len = buf.len().min(tcb.recv.len());
+                for (dst, src) in buf.iter_mut().zip(tcb.recv.drain(..len)) {
+                    *dst = src;
+                }
+                window_opened = was_small && tcb.window() as usize >= small;
+                result = Ok(len);
+            } else if let Some(error) = tcb.error {
+                result = Err(error);
+            } else if !tcb.fin_received && tcb.state != State::Closed {
+                return false;
+            }
+            true
+        })?;
+        if window_opened {
+            // the peer may be waiting for room
+            self.connection
+                .update(|tcb, out| out.push(tcb.ack_segment()));
+        }
+        result
+    }
+
+    /// Sends all of `data`, blocking while the send buffer is full.
+    pub fn write(&self, data: &[u8]) -> KResult<usize> {
+        let mut written = 0;
+        while written < data.len() {
+            let mut result = Ok(());
+            let mut out = Vec::new();
+            self.connection.wait(None, |tcb| {
+                if let Some(error) = tcb.error {
+                    result = Err(error);
+                    return true;
+                }
+                if tcb.fin_queued || !matches!(tcb.state, State::Established | State::CloseWait) {
+                    result = Err(KError::NotConnected);
+                    return true;
+                }
+                let len = (BUFFER_SIZE - tcb.send.len()).min(data.len() - written);
+                if len == 0 {
+                    return false;
+                }
+                tcb.send.extend(&data[written..written + len]);
+                written += len;
+                tcb.output(&mut out);
+                true
+            })?;
+            transmit(out);
+            result?;
+        }
+        Ok(written)
+    }
+
+    /// Closes the sending side, the peer reads the end of the stream once it got everything
+    /// before.
+    pub fn shutdown(&self) {
+        self.connection.update(|tcb, out| tcb.close(out));
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.connection.update(|tcb, out| {
+            tcb.close(out);
+            tcb.expires.get_or_insert(time::uptime() + LINGER);
+        });
+    }
+}
+
+/// Accepts connections on a port.
+pub struct TcpListener {
+    port: u16,
+    backlog: Arc<Inbox<TcpStream>>,
+}
+
+impl TcpListener {
+    /// Listens on `port` on every interface. Fails with `AddressInUse` if it is taken.
+    pub fn bind(port: u16) -> KResult<Self> {
+        let backlog = Inbox::new(BACKLOG);
+        without_interrupts(|| {
+            if port_in_use(port) {
+                return Err(KError::AddressInUse);
+            }
+            LISTENERS.lock().insert(port, backlog.clone());
+            Ok(())
+        })?;
+        Ok(Self { port, backlog })
+    }
+
+    /// Returns the next connection, blocking until one comes in. Fails with `TimedOut` if none
+    /// did within `timeout`.
+    pub fn accept(&self, timeout: Option<Duration>) -> KResult<TcpStream> {
+        self.backlog.pop(timeout)
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        without_interrupts(|| LISTENERS.lock().remove(&self.port));
+    }
+}
+
+/// Lists the listeners and connections.
+pub fn sockets() -> Vec<SocketInfo> {
+    let (listeners, connections): (Vec<_>, Vec<_>) = without_interrupts(|| {
+        let listeners = LISTENERS
+            .lock()
+            .iter()
+            .map(|(port, backlog)| (*port, backlog.clone()))
+            .collect();
+        let connections = CONNECTIONS.lock().values().cloned().collect();
+        (listeners, connections)
+    });
+    let listeners = listeners.into_iter().map(|(port, backlog)| SocketInfo {
+        local: SocketAddr::new(Ipv4Addr::UNSPECIFIED, port),
+        remote: SocketAddr::new(Ipv4Addr::UNSPECIFIED, 0),
+        state: Some(State::Listen),
+        recv_queue: backlog.len(),
+        send_queue: 0,
+        drops: backlog.dropped.get(),
+    });
+    let connections = connections.into_iter().map(|connection| {
+        without_interrupts(|| {
+            let tcb = connection.tcb.lock();
+            SocketInfo {
+                local: tcb.local,
+                remote: tcb.remote,
+                state: Some(tcb.state),
+                recv_queue: tcb.recv.len(),
+                send_queue: tcb.send.len(),
+                drops: 0,
+            }
+        })
+    });
+    listeners.chain(connections).collect()
+}
+
+fn transmit(out: Outgoing) {
+    for (dst, segment) in out {
+        TCP.out_segs.inc();
+        if segment[13] & RST != 0 {
+            TCP.out_rsts.inc();
+        }
+        // lost segments are sent again
+        let _ = ipv4::send(dst, ipv4::PROTOCOL_TCP, &segment);
+    }
+}
+
+/// The reset answering `segment`, which doesn't belong to any connection.
+fn reset(local: SocketAddr, remote: SocketAddr, segment: &Segment) -> (Ipv4Addr, Vec<u8>) {
+    let (seq, ack, flags) = match segment.flags & ACK {
+        0 => (0, segment.seq.wrapping_add(segment.len()), RST | ACK),
+        _ => (segment.ack, 0, RST),
+    };
+    let reset = Segment {
+        src_port: local.port,
+        dst_port: remote.port,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        data: &[],
+    };
+    (remote.addr, build(local.addr, remote.addr, &reset))
+}
+
+/// Builds a segment with a partial checksum for `ipv4::send` to complete.
+fn build(src: Ipv4Addr, dst: Ipv4Addr, segment: &Segment) -> Vec<u8> {
+    let options_len = if segment.mss.is_some() { 4 } else { 0 };
+    let data_offset = ((HEADER_SIZE + options_len) / 4) as u8;
+    let mut packet = Vec::with_capacity(HEADER_SIZE + options_len + segment.data.len());
+    packet.extend_from_slice(&segment.src_port.to_be_bytes());
+    packet.extend_from_slice(&segment.dst_port.to_be_bytes());
+    packet.extend_from_slice(&segment.seq.to_be_bytes());
+    packet.extend_from_slice(&segment.ack.to_be_bytes());
+    packet.extend_from_slice(&[data_offset << 4, segment.flags]);
+    packet.extend_from_slice(&segment.window.to_be_bytes());
+    // checksum and urgent pointer
+    packet.extend_from_slice(&[0; 4]);
+    if let Some(mss) = segment.mss {
+        packet.extend_from_slice(&[OPTION_MSS, 4]);
+        packet.extend_from_slice(&mss.to_be_bytes());
+    }
+    packet.extend_from_slice(segment.data);
+    let sum = partial_checksum(src, dst, ipv4::PROTOCOL_TCP, packet.len());
+    packet[16..18].copy_from_slice(&sum.to_be_bytes());
+    packet
+}
+
+/// Parses a segment, of which the device checked the checksum if `verified`.
+fn parse<'a>(header: &ipv4::Header, packet: &'a [u8], verified: bool) -> Option<Segment<'a>> {
+    if packet.len() < HEADER_SIZE
+        || (!verified
+            && transport_checksum(header.src, header.dst, ipv4::PROTOCOL_TCP, packet) != 0)
+    {
+        return None;
+    }
+    let data_offset = (packet[12] >> 4) as usize * 4;
+    if data_offset < HEADER_SIZE || data_offset > packet.len() {
+        return None;
+    }
+    let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().unwrap());
+    Some(Segment {
+        src_port: u16::from_be_bytes([packet[0], packet[1]]),
+        dst_port: u16::from_be_bytes([packet[2], packet[3]]),
+        seq: u32_at(4),
+        ack: u32_at(8),
+        flags: packet[13],
+        window: u16::from_be_bytes([packet[14], packet[15]]),
+        mss: parse_mss(&packet[HEADER_SIZE..data_offset]),
+        data: &packet[data_offset..],
+    })
+}
+
+fn parse_mss(mut options: &[u8]) -> Option<u16> {
+    loop {
+        match options {
+            [] | [OPTION_END, ..] => return None,
+            [OPTION_NOP, rest @ ..] => options = rest,
+            [kind, len, ..] => {
+                let len = *len as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    return Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+            [_] => return None,
+        }
+    }
+}
+
+pub(super) fn input(header: &ipv4::Header, packet: &[u8], verified: bool) {
+    let Some(segment) = parse(header, packet, verified) else {
+        TCP.in_errs.inc();
+        return;
+    };
+    TCP.in_segs.inc();
+    let local = SocketAddr::new(header.dst, segment.dst_port);
+    let remote = SocketAddr::new(header.src, segment.src_port);
+    let connection = without_interrupts(|| CONNECTIONS.lock().get(&(local.port, remote)).cloned());
+
+    if let Some(connection) = connection {
+        let accepted = connection.update(|tcb, out| {
+            let before = tcb.state;
+            tcb.input(&segment, out);
+            before == State::SynReceived && !matches!(tcb.state, State::SynReceived | State::Closed)
+        });
+        if accepted {
+            let stream = TcpStream { connection };
+            let backlog = without_interrupts(|| LISTENERS.lock().get(&local.port).cloned());
+            // without a listener any more, dropping the stream closes it
+            if let Some(backlog) = backlog {
+                backlog.push(stream);
+            }
+        }
+        return;
+    }
+
+    let backlog = without_interrupts(|| LISTENERS.lock().get(&local.port).cloned());
+    match backlog {
+        Some(backlog) if segment.flags & (SYN | ACK | RST) == SYN => {
+            if backlog.len() >= BACKLOG {
+                return;
+            }
+            let mut tcb = Tcb::new(State::SynReceived, local, remote);
+            tcb.rcv_nxt = segment.seq.wrapping_add(1);
+            tcb.snd_wnd = segment.window as u32;
+            tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+            tcb.mss = tcb.mss.min(tcb.local_mss() as usize);
+            let connection = Connection::new(tcb);
+            TCP.passive_opens.inc();
+            without_interrupts(|| {
+                CONNECTIONS
+                    .lock()
+                    .insert(connection.key, connection.clone())
+            });
+            connection.update(|tcb, out| tcb.output(out));
+        }
+        _ if segment.flags & RST == 0 => transmit(vec![reset(local, remote, &segment)]),
+        _ => {}
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_build_and_parse() {
+    let header = ipv4::Header {
+        src: Ipv4Addr([10, 0, 2, 15]),
+        dst: Ipv4Addr([10, 0, 2, 2]),
+        protocol: ipv4::PROTOCOL_TCP,
+        ttl: ipv4::DEFAULT_TTL,
+    };
+    let syn = Segment {
+        src_port: 49152,
+        dst_port: 80,
+        seq: 0xfffffffe,
+        ack: 0,
+        flags: SYN,
+        window: 1024,
+        mss: Some(1460),
+        data: &[],
+    };
+    let mut packet = build(header.src, header.dst, &syn);
+    assert_eq!(packet.len(), HEADER_SIZE + 4);
+    // the checksum is partial until completed, by a device or in software
+    assert_eq!(parse(&header, &packet, false), None);
+    assert_eq!(parse(&header, &packet, true), Some(syn.clone()));
+    super::finish_checksum(ipv4::PROTOCOL_TCP, &mut packet);
+    assert_eq!(parse(&header, &packet, false), Some(syn.clone()));
+    assert_eq!(syn.len(), 1);
+
+    let data = Segment {
+        flags: ACK | PSH,
+        mss: None,
+        data: b"GET /",
+        ..syn
+    };
+    let mut packet = build(header.src, header.dst, &data);
+    super::finish_checksum(ipv4::PROTOCOL_TCP, &mut packet);
+    assert_eq!(parse(&header, &packet, false), Some(data));
+    packet[HEADER_SIZE] ^= 1;
+    assert_eq!(parse(&header, &packet, false), None);
+
+    assert_eq!(
+        parse_mss(&[OPTION_NOP, OPTION_NOP, OPTION_MSS, 4, 0x05, 0xb4]),
+        Some(1460)
+    );
+    assert_eq!(parse_mss(&[OPTION_MSS, 9, 0, 0]), None);
+}
@@ -0,0 +1,167 @@
+//! UDP sockets.
+//!
+//! A socket is bound to a local port on every interface. Received datagrams queue up on the
+//! socket until read, the oldest ones are dropped once `QUEUE_SIZE` are waiting.
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{
+    ipv4, partial_checksum,
+    stats::{SocketInfo, UDP},
+    transport_checksum, Inbox, Ipv4Addr, SocketAddr,
+};
+use crate::error::{KError, KResult};
+
+pub const HEADER_SIZE: usize = 8;
+/// Datagrams a socket holds before dropping the oldest.
+const QUEUE_SIZE: usize = 64;
+/// Ports handed out by `bind(0)`.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub from: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Arc<Inbox<Datagram>>>> = Mutex::new(BTreeMap::new());
+
+pub struct UdpSocket {
+    port: u16,
+    inbox: Arc<Inbox<Datagram>>,
+}
+
+impl UdpSocket {
+    /// Binds to `port`, any free port for 0. Fails with `AddressInUse` if it is taken.
+    pub fn bind(port: u16) -> KResult<Self> {
+        let inbox = Inbox::new(QUEUE_SIZE);
+        let port = without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            let port = match port {
+                0 => EPHEMERAL_PORTS
+                    .clone()
+                    .find(|port| !sockets.contains_key(port))
+                    .ok_or(KError::AddressInUse)?,
+                port if sockets.contains_key(&port) => return Err(KError::AddressInUse),
+                port => port,
+            };
+            sockets.insert(port, inbox.clone());
+            Ok(port)
+        })?;
+        Ok(Self { port, inbox })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` as one datagram.
+    pub fn send_to(&self, data: &[u8], to: SocketAddr) -> KResult<usize> {
+        if HEADER_SIZE + data.len() > ipv4::max_payload(to.addr)? {
+            return Err(KError::MessageTooLong);
+        }
+        let src = ipv4::source_for(to.addr)?;
+        let datagram = build(SocketAddr::new(src, self.port), to, data);
+        ipv4::send(to.addr, ipv4::PROTOCOL_UDP, &datagram)?;
+        UDP.out_datagrams.inc();
+        Ok(data.len())
+    }
+
+    /// Returns the next datagram, blocking until one arrives. Fails with `TimedOut` if none
+    /// came within `timeout`.
+    pub fn recv_from(&self, timeout: Option<Duration>) -> KResult<Datagram> {
+        self.inbox.pop(timeout)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        without_interrupts(|| SOCKETS.lock().remove(&self.port));
+    }
+}
+
+/// Lists the bound sockets.
+pub fn sockets() -> Vec<SocketInfo> {
+    let sockets: Vec<_> = without_interrupts(|| {
+        SOCKETS
+            .lock()
+            .iter()
+            .map(|(port, inbox)| (*port, inbox.clone()))
+            .collect()
+    });
+    sockets
+        .into_iter()
+        .map(|(port, inbox)| SocketInfo {
+            local: SocketAddr::new(Ipv4Addr::UNSPECIFIED, port),
+            remote: SocketAddr::new(Ipv4Addr::UNSPECIFIED, 0),
+            state: None,
+            recv_queue: inbox.len(),
+            send_queue: 0,
+            drops: inbox.dropped.get(),
+        })
+        .collect()
+}
+
+fn datagram_checksum(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) -> u16 {
+    transport_checksum(src, dst, ipv4::PROTOCOL_UDP, datagram)
+}
+
+/// Builds a datagram from `from` to `to`, with a partial checksum for `ipv4::send` to complete.
+pub fn build(from: SocketAddr, to: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let len = (HEADER_SIZE + data.len()) as u16;
+    let mut datagram = Vec::with_capacity(len as usize);
+    datagram.extend_from_slice(&from.port.to_be_bytes());
+    datagram.extend_from_slice(&to.port.to_be_bytes());
+    datagram.extend_from_slice(&len.to_be_bytes());
+    let sum = partial_checksum(from.addr, to.addr, ipv4::PROTOCOL_UDP, len as usize);
+    datagram.extend_from_slice(&sum.to_be_bytes());
+    datagram.extend_from_slice(data);
+    datagram
+}
+
+/// Handles a datagram, of which the device checked the checksum if `verified`.
+pub(super) fn input(header: &ipv4::Header, datagram: &[u8], verified: bool) {
+    if datagram.len() < HEADER_SIZE {
+        UDP.in_errors.inc();
+        return;
+    }
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        UDP.in_errors.inc();
+        return;
+    }
+    let datagram = &datagram[..len];
+    let has_checksum = datagram[6..8] != [0, 0];
+    if has_checksum && !verified && datagram_checksum(header.src, header.dst, datagram) != 0 {
+        UDP.in_errors.inc();
+        return;
+    }
+    let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let Some(inbox) = without_interrupts(|| SOCKETS.lock().get(&dst_port).cloned()) else {
+        UDP.no_ports.inc();
+        return;
+    };
+    UDP.in_datagrams.inc();
+    let dropped = inbox.push(Datagram {
+        from: SocketAddr::new(header.src, src_port),
+        data: datagram[HEADER_SIZE..].to_vec(),
+    });
+    if dropped {
+        UDP.rcvbuf_errors.inc();
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_checksum() {
+    let from = SocketAddr::new(Ipv4Addr([10, 0, 2, 15]), 1234);
+    let to = SocketAddr::new(Ipv4Addr([10, 0, 2, 2]), 53);
+    let mut datagram = build(from, to, b"odd");
+    assert_eq!(datagram.len(), HEADER_SIZE + 3);
+    super::finish_checksum(ipv4::PROTOCOL_UDP, &mut datagram);
+    assert_eq!(datagram_checksum(from.addr, to.addr, &datagram), 0);
+    // delivered to the wrong address, the pseudo header doesn't match
+    assert_ne!(datagram_checksum(from.addr, from.addr, &datagram), 0);
+}
@@ -1,13 +1,106 @@
 use alloc::{format, string::String, vec::Vec};
-use x86_64::instructions::port::Port;
+use core::ops::RangeInclusive;
+use core::ptr;
+use spin::{Mutex, Once};
+use x86_64::{instructions::port::Port, PhysAddr};
 
 use crate::{
-    drivers::{on_plug, PhysicalDevice}, mem::PAGE_SIZE, println
+    acpi,
+    device::{self, Bus, DeviceId},
+    drivers::{on_plug, PhysicalDevice},
+    mem::{self, PAGE_SIZE},
+    println,
 };
 
 pub const CONFIG_ADDRESS: u16 = 0xCF8;
 pub const CONFIG_DATA: u16 = 0xCFC;
 
+/// Header type of a general device.
+pub const HEADER_GENERAL: u8 = 0x00;
+/// Header type of a PCI-to-PCI bridge.
+pub const HEADER_PCI_BRIDGE: u8 = 0x01;
+/// Header type of a PCI-to-CardBus bridge.
+pub const HEADER_CARDBUS_BRIDGE: u8 = 0x02;
+/// Register of a PCI-to-PCI bridge holding its primary, secondary and subordinate bus numbers.
+const BRIDGE_BUS_REG: u8 = 0x6;
+
+/// Size of the configuration space of a function reachable through ECAM, the port based access
+/// only reaches the first 256 bytes.
+pub const CONFIG_SPACE_SIZE: u16 = 0x1000;
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 0x100;
+
+/// The memory mapped configuration space (PCIe ECAM) of segment 0.
+#[derive(Debug, Clone, Copy)]
+struct Ecam {
+    /// virtual address of the configuration space of `start_bus`
+    base: u64,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+static ECAM: Once<Option<Ecam>> = Once::new();
+/// Bus, device and function of everything `PCIManager::scan` found, bridges before the
+/// functions behind them.
+static FUNCTIONS: Mutex<Vec<(u8, u8, u8)>> = Mutex::new(Vec::new());
+
+/// Looks for ECAM in the ACPI MCFG table, the configuration space is accessed through ports
+/// 0xCF8/0xCFC if there is none or it isn't mapped. Called by the first `PCIManager::scan`.
+pub fn init() {
+    ECAM.call_once(find_ecam);
+    if has_ecam() {
+        println!("PCI: using ECAM");
+    }
+}
+
+/// Whether the extended configuration space (offsets from 0x100 on) is reachable.
+pub fn has_ecam() -> bool {
+    matches!(ECAM.r#try(), Some(Some(_)))
+}
+
+fn find_ecam() -> Option<Ecam> {
+    let mcfg = acpi::find_table(b"MCFG")?;
+    // the entries follow the header and 8 reserved bytes
+    let mut offset = acpi::SDT_HEADER_SIZE + 8;
+    while let Some(base) = mcfg.read::<u64>(offset) {
+        let segment: u16 = mcfg.read(offset + 8)?;
+        let start_bus: u8 = mcfg.read(offset + 10)?;
+        let end_bus: u8 = mcfg.read(offset + 11)?;
+        offset += 16;
+        if segment != 0 || end_bus < start_bus {
+            continue;
+        }
+        // The region usually isn't part of the bootloader's physical memory mapping, only use
+        // it if it is mapped completely.
+        let size = ((end_bus - start_bus) as u64 + 1) << 20;
+        let mapped = (0..size)
+            .step_by(PAGE_SIZE)
+            .all(|off| mem::phys_to_mapped_virt(PhysAddr::new(base + off)).is_some());
+        if !mapped {
+            println!("PCI: ECAM at {:#x} is not mapped, using port I/O", base);
+            return None;
+        }
+        return Some(Ecam {
+            base: mem::phys_to_virt(PhysAddr::new(base))?.as_u64(),
+            start_bus,
+            end_bus,
+        });
+    }
+    None
+}
+
+/// Returns the address of a register in the memory mapped configuration space.
+fn ecam_address(bus: u8, slot: u8, func: u8, offset: u16) -> Option<*mut u32> {
+    let ecam = (*ECAM.r#try()?)?;
+    if bus < ecam.start_bus || bus > ecam.end_bus {
+        return None;
+    }
+    let off = ((bus - ecam.start_bus) as u64) << 20
+        | (slot as u64) << 15
+        | (func as u64) << 12
+        | (offset & 0xffc) as u64;
+    Some((ecam.base + off) as *mut u32)
+}
+
 macro_rules! get_pci_addr {
     ($bus: expr, $slot: expr, $func: expr, $offset: expr) => {
         (($bus as u32) << 16)
@@ -26,9 +119,7 @@ pub fn read_u16(bus: u8, slot: u8, func: u8, offset: u8) -> u16 {
 }
 
 pub fn read_u32(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
-    let addr = get_pci_addr!(bus, slot, func, offset * 4);
-    unsafe { Port::new(CONFIG_ADDRESS).write(addr) };
-    unsafe { Port::new(CONFIG_DATA).read() }
+    read_config(bus, slot, func, offset as u16 * 4)
 }
 
 pub fn write_u16(bus: u8, slot: u8, func: u8, offset: u8, data: u16) {
@@ -40,13 +131,110 @@ pub fn write_u16(bus: u8, slot: u8, func: u8, offset: u8, data: u16) {
 }
 
 pub fn write_u32(bus: u8, slot: u8, func: u8, offset: u8, data: u32) {
-    let addr = get_pci_addr!(bus, slot, func, offset * 4);
+    write_config(bus, slot, func, offset as u16 * 4, data);
+}
+
+/// Reads the 32 bit register at byte `offset` of the configuration space. Without ECAM the
+/// extended configuration space reads as 0xffffffff.
+pub fn read_config(bus: u8, slot: u8, func: u8, offset: u16) -> u32 {
+    if offset >= CONFIG_SPACE_SIZE {
+        return 0xffffffff;
+    }
+    if let Some(addr) = ecam_address(bus, slot, func, offset) {
+        return unsafe { ptr::read_volatile(addr) };
+    }
+    if offset >= LEGACY_CONFIG_SPACE_SIZE {
+        return 0xffffffff;
+    }
+    let addr = get_pci_addr!(bus, slot, func, offset);
+    unsafe { Port::new(CONFIG_ADDRESS).write(addr) };
+    unsafe { Port::new(CONFIG_DATA).read() }
+}
+
+/// Writes the 32 bit register at byte `offset` of the configuration space. Without ECAM writes
+/// to the extended configuration space are dropped.
+pub fn write_config(bus: u8, slot: u8, func: u8, offset: u16, data: u32) {
+    if offset >= CONFIG_SPACE_SIZE {
+        return;
+    }
+    if let Some(addr) = ecam_address(bus, slot, func, offset) {
+        unsafe { ptr::write_volatile(addr, data) };
+        return;
+    }
+    if offset >= LEGACY_CONFIG_SPACE_SIZE {
+        return;
+    }
+    let addr = get_pci_addr!(bus, slot, func, offset);
     unsafe {
         Port::new(CONFIG_ADDRESS).write(addr);
         Port::new(CONFIG_DATA).write(data)
     };
 }
 
+/// Size of the configuration space `read_config` reaches.
+pub fn config_space_size() -> u16 {
+    if has_ecam() {
+        CONFIG_SPACE_SIZE
+    } else {
+        LEGACY_CONFIG_SPACE_SIZE
+    }
+}
+
+/// Parses a function address the way lspci prints it, `bus:device.function` in hex, e.g.
+/// `00:1f.2`.
+pub fn parse_bdf(bdf: &str) -> Option<(u8, u8, u8)> {
+    let (bus, rest) = bdf.split_once(':')?;
+    let (device, func) = rest.split_once('.')?;
+    let bus = u8::from_str_radix(bus, 16).ok()?;
+    let device = u8::from_str_radix(device, 16).ok().filter(|&device| device < 32)?;
+    let func = u8::from_str_radix(func, 16).ok().filter(|&func| func < 8)?;
+    Some((bus, device, func))
+}
+
+/// Whether a function answers at the address, absent ones read as all ones.
+pub fn function_exists(bus: u8, slot: u8, func: u8) -> bool {
+    read_config(bus, slot, func, 0) & 0xffff != 0xffff
+}
+
+/// Returns `len` bytes of the configuration space from byte `offset` on, read with
+/// `read_dword` a register at a time.
+fn config_bytes(offset: u16, len: u16, read_dword: impl Fn(u16) -> u32) -> Vec<u8> {
+    let end = offset as usize + len as usize;
+    let mut bytes = Vec::with_capacity(len as usize);
+    for reg in (offset & !3..end as u16).step_by(4) {
+        let dword = read_dword(reg).to_le_bytes();
+        let from = (offset.max(reg) - reg) as usize;
+        let to = (end - reg as usize).min(4);
+        bytes.extend_from_slice(&dword[from..to]);
+    }
+    bytes
+}
+
+/// Reads `len` bytes of the configuration space from byte `offset` on.
+pub fn read_config_bytes(bus: u8, slot: u8, func: u8, offset: u16, len: u16) -> Vec<u8> {
+    config_bytes(offset, len, |reg| read_config(bus, slot, func, reg))
+}
+
+/// Replaces the `width` bytes at `offset` in the register `dword` with the low bytes of
+/// `value`.
+fn merge_config(dword: u32, offset: u16, width: u16, value: u32) -> u32 {
+    let shift = (offset & 3) * 8;
+    let mask = (u64::MAX >> (64 - 8 * width)) as u32;
+    dword & !(mask << shift) | (value & mask) << shift
+}
+
+/// Writes a naturally aligned value of 1, 2 or 4 bytes at byte `offset` of the configuration
+/// space. Narrower writes read the register first and write the merged value back, which also
+/// writes back the other bytes of the register.
+pub fn write_config_value(bus: u8, slot: u8, func: u8, offset: u16, width: u16, value: u32) {
+    let reg = offset & !3;
+    let dword = match width {
+        4 => value,
+        _ => merge_config(read_config(bus, slot, func, reg), offset, width, value),
+    };
+    write_config(bus, slot, func, reg, dword);
+}
+
 /// Reads PCI configuration and writes it into `buf`.
 ///
 /// Arguments:
@@ -79,6 +267,31 @@ fn write_data(bus: u8, device: u8, func: u8, off: usize, buf: &[u32]) {
     }
 }
 
+/// The configuration space headers saved by `save_config`.
+pub struct SavedConfig(Vec<((u8, u8, u8), [u32; 16])>);
+
+/// Saves the header of every function found, which is lost when the machine sleeps.
+pub fn save_config() -> SavedConfig {
+    let functions = FUNCTIONS.lock();
+    let headers = functions.iter().map(|&(bus, device, func)| {
+        let mut data = [0; 16];
+        read_data(bus, device, func, 0, &mut data);
+        ((bus, device, func), data)
+    });
+    SavedConfig(headers.collect())
+}
+
+/// Writes back the headers saved by `save_config`. The command register goes last, so nothing
+/// is decoded before the BARs are back.
+pub fn restore_config(saved: &SavedConfig) {
+    for &((bus, device, func), data) in &saved.0 {
+        // IDs, class and revision are read only
+        write_data(bus, device, func, 3, &data[3..]);
+        // the status bits are cleared by writing ones
+        write_u32(bus, device, func, 0x1, data[1] & 0xffff);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum BARType {
     /// The base register is 32 bits wide.
@@ -337,6 +550,22 @@ impl PCIDevice {
         // Clear the Multi-Function flag
         self.header_type & 0b01111111
     }
+
+    /// Tells whether the device is a PCI-to-PCI bridge.
+    #[inline(always)]
+    pub fn is_bridge(&self) -> bool {
+        self.get_header_type() == HEADER_PCI_BRIDGE
+    }
+
+    /// Returns the buses behind a PCI-to-PCI bridge, from its secondary to its subordinate bus.
+    pub fn get_bridged_buses(&self) -> Option<RangeInclusive<u8>> {
+        if !self.is_bridge() {
+            return None;
+        }
+        let secondary = ((self.info[2] >> 8) & 0xff) as u8;
+        let subordinate = ((self.info[2] >> 16) & 0xff) as u8;
+        Some(secondary..=subordinate)
+    }
 }
 
 impl PhysicalDevice for PCIDevice {
@@ -399,6 +628,31 @@ impl PhysicalDevice for PCIDevice {
     fn unique_identifier(&self) -> &str {
         &self.unique_identifier
     }
+
+    fn read_config(&self, offset: u16) -> u32 {
+        read_config(self.bus, self.device, self.function, offset)
+    }
+
+    fn write_config(&self, offset: u16, value: u32) {
+        write_config(self.bus, self.device, self.function, offset, value)
+    }
+}
+
+/// Adds a function to the device tree, with the attributes Linux has in sysfs.
+fn register(dev: &PCIDevice, parent: Option<DeviceId>) -> DeviceId {
+    let mut attributes = Vec::from([
+        ("vendor", format!("0x{:04x}", dev.vendor_id)),
+        ("device", format!("0x{:04x}", dev.device_id)),
+        (
+            "class",
+            format!("0x{:02x}{:02x}{:02x}", dev.class, dev.subclass, dev.prog_if),
+        ),
+        ("revision", format!("0x{:02x}", dev.revision_id)),
+    ]);
+    if let Some(irq) = dev.get_interrupt_line() {
+        attributes.push(("irq", format!("{irq}")));
+    }
+    device::register(&dev.unique_identifier, Bus::Pci, parent, attributes)
 }
 
 /// This manager handles every devices connected to the PCI bus.
@@ -419,53 +673,81 @@ impl PCIManager {
 
     /// Scans for PCI devices and registers them on the manager.
     ///
+    /// Buses are found by following PCI-to-PCI bridges from bus 0. Bus numbers assigned by the
+    /// firmware are kept, bridges without usable ones get the next free numbers.
+    ///
     /// If the PCI has already been scanned, this function does nothing.
     pub fn scan(&mut self) {
         // Avoid calling `on_plug` twice for the same devices
         if !self.devices.is_empty() {
             return;
         }
+        init();
+
+        let mut next_bus = 1;
+        let header_type = ((read_u32(0, 0, 0, 3) >> 16) & 0xff) as u8;
+        if header_type & 0x80 == 0 {
+            // Single host controller
+            self.scan_bus(0, None, &mut next_bus);
+            return;
+        }
+        // Multiple host controllers, function `n` is responsible for bus `n`
+        for func in 0..8 {
+            if read_u32(0, 0, func, 0) & 0xffff == 0xffff {
+                continue;
+            }
+            next_bus = next_bus.max(func as u16 + 1);
+            self.scan_bus(func, None, &mut next_bus);
+        }
+    }
+
+    /// Scans a bus, whose devices are registered below the bridge `parent`.
+    fn scan_bus(&mut self, bus: u8, parent: Option<DeviceId>, next_bus: &mut u16) {
+        for device in 0..32 {
+            let vendor_id = read_u32(bus, device, 0, 0) & 0xffff;
+            // If the device doesn't exist, ignore
+            if vendor_id == 0xffff {
+                continue;
+            }
+
+            // Reading device's PCI data
+            let mut data: [u32; 16] = [0; 16];
+            read_data(bus, device, 0, 0, &mut data);
+
+            let header_type = ((data[3] >> 16) & 0xff) as u8;
+            let max_functions_count = {
+                if header_type & 0x80 != 0 {
+                    // Multi-function device
+                    8
+                } else {
+                    // Single-function device
+                    1
+                }
+            };
 
-        for bus in 0..=255 {
-            for device in 0..32 {
-                let vendor_id = read_u32(bus, device, 0, 0) & 0xffff;
-                // If the device doesn't exist, ignore
+            // Iterating on every functions of the device
+            for func in 0..max_functions_count {
+                let vendor_id = read_u32(bus, device, func, 0) & 0xffff;
+                // If the function doesn't exist, ignore
                 if vendor_id == 0xffff {
                     continue;
                 }
 
-                // Reading device's PCI data
-                let mut data: [u32; 16] = [0; 16];
-                read_data(bus, device, 0, 0, &mut data);
-
-                let header_type = ((data[3] >> 16) & 0xff) as u8;
-                let max_functions_count = {
-                    if header_type & 0x80 != 0 {
-                        // Multi-function device
-                        8
-                    } else {
-                        // Single-function device
-                        1
-                    }
-                };
+                // Reading function's PCI data
+                read_data(bus, device, func, 0, &mut data);
+
+                // Enabling I/O space for BARs
+                data[1] |= 0b1;
+                write_u32(bus, device, func, 0x1, data[1]);
 
-                // Iterating on every functions of the device
-                for func in 0..max_functions_count {
-                    let vendor_id = read_u32(bus, device, func, 0) & 0xffff;
-                    // If the function doesn't exist, ignore
-                    if vendor_id == 0xffff {
+                let dev = PCIDevice::new(bus, device, func, &data);
+                match dev.get_header_type() {
+                    HEADER_GENERAL => {}
+                    HEADER_PCI_BRIDGE => {
+                        self.scan_bridge(dev, parent, next_bus);
                         continue;
                     }
-
-                    // Reading function's PCI data
-                    read_data(bus, device, func, 0, &mut data);
-
-                    // Enabling I/O space for BARs
-                    data[1] |= 0b1;
-                    write_u32(bus, device, func, 0x1, data[1]);
-
-                    let dev = PCIDevice::new(bus, device, func, &data);
-                    if dev.header_type != 0 {
+                    _ => {
                         println!(
                             "Found enp{}s{}f{} ht: {:x} ({:x} {:x}; {:x}); skipping",
                             dev.bus,
@@ -478,25 +760,69 @@ impl PCIManager {
                         );
                         continue;
                     }
-
-                    println!(
-                        "Found enp{}s{}f{} {:x} {:x} ({:x} {:x}; {:x})",
-                        dev.bus,
-                        dev.device,
-                        dev.function,
-                        dev.vendor_id,
-                        dev.device_id,
-                        dev.class,
-                        dev.subclass,
-                        dev.prog_if
-                    );
-                    on_plug(&dev);
-                    self.devices.push(dev);
                 }
+
+                println!(
+                    "Found enp{}s{}f{} {:x} {:x} ({:x} {:x}; {:x})",
+                    dev.bus,
+                    dev.device,
+                    dev.function,
+                    dev.vendor_id,
+                    dev.device_id,
+                    dev.class,
+                    dev.subclass,
+                    dev.prog_if
+                );
+                let id = register(&dev, parent);
+                FUNCTIONS.lock().push((bus, device, func));
+                on_plug(&dev, id);
+                self.devices.push(dev);
             }
         }
     }
 
+    /// Programs the bus numbers of a PCI-to-PCI bridge and scans the buses behind it.
+    fn scan_bridge(&mut self, dev: PCIDevice, parent: Option<DeviceId>, next_bus: &mut u16) {
+        let (bus, device, func) = (dev.bus, dev.device, dev.function);
+        let buses = read_u32(bus, device, func, BRIDGE_BUS_REG);
+        let mut secondary = ((buses >> 8) & 0xff) as u16;
+        // Keep the firmware's number unless it's unset or already taken
+        if secondary <= bus as u16 || secondary < *next_bus {
+            secondary = *next_bus;
+        }
+        if secondary > 0xff {
+            println!("Found bridge enp{}s{}f{}; out of bus numbers", bus, device, func);
+            return;
+        }
+        *next_bus = secondary + 1;
+
+        // Forward memory and I/O accesses and let devices behind the bridge master the bus
+        let command = read_u32(bus, device, func, 0x1) | 0b111;
+        write_u32(bus, device, func, 0x1, command);
+        // Until the buses behind the bridge are known, it forwards everything above secondary
+        let keep = buses & 0xff000000;
+        let numbers = |subordinate: u16| {
+            keep | (subordinate as u32) << 16 | (secondary as u32) << 8 | bus as u32
+        };
+        write_u32(bus, device, func, BRIDGE_BUS_REG, numbers(0xff));
+
+        let index = self.devices.len();
+        let id = register(&dev, parent);
+        FUNCTIONS.lock().push((bus, device, func));
+        on_plug(&dev, id);
+        self.devices.push(dev);
+        self.scan_bus(secondary as u8, Some(id), next_bus);
+
+        let subordinate = *next_bus - 1;
+        write_u32(bus, device, func, BRIDGE_BUS_REG, numbers(subordinate));
+        let dev = &mut self.devices[index];
+        dev.info[2] = read_u32(bus, device, func, BRIDGE_BUS_REG);
+        println!(
+            "Found bridge enp{}s{}f{} {:x} {:x}; buses {}-{}",
+            bus, device, func, dev.vendor_id, dev.device_id, secondary, subordinate
+        );
+    }
+
     /// Returns the list of PCI devices.
     ///
     /// If the PCI hasn't been scanned, the function returns an empty vector.
@@ -505,3 +831,26 @@ impl PCIManager {
         &self.devices
     }
 }
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_config_access() {
+    assert_eq!(parse_bdf("00:1f.2"), Some((0, 0x1f, 2)));
+    assert_eq!(parse_bdf("ff:00.7"), Some((0xff, 0, 7)));
+    assert_eq!(parse_bdf("00:20.0"), None);
+    assert_eq!(parse_bdf("00:01.8"), None);
+    assert_eq!(parse_bdf("100:01.0"), None);
+    assert_eq!(parse_bdf("00.01:0"), None);
+
+    // register n reads as 0x{n}3{n}2{n}1{n}0, so byte b is (b / 4) << 4 | b % 4
+    let read = |reg: u16| {
+        let n = (reg / 4) as u32;
+        u32::from_le_bytes([0, 1, 2, 3].map(|b| (n << 4 | b) as u8))
+    };
+    assert_eq!(config_bytes(0, 4, read), [0x00, 0x01, 0x02, 0x03]);
+    assert_eq!(config_bytes(2, 5, read), [0x02, 0x03, 0x10, 0x11, 0x12]);
+    assert_eq!(config_bytes(0x3d, 1, read), [0xf1]);
+
+    assert_eq!(merge_config(0x1122_3344, 2, 2, 0xaabb), 0xaabb_3344);
+    assert_eq!(merge_config(0x1122_3344, 1, 1, 0x1ff), 0x1122_ff44);
+    assert_eq!(merge_config(0x1122_3344, 0, 4, 0xdead_beef), 0xdead_beef);
+}
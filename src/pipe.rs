@@ -0,0 +1,124 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a bounded byte buffer with a read end and a write end. Readers block while the
+//! pipe is empty, writers while it is full. Once every write end is gone reads return end of
+//! file, once every read end is gone writes fail with `BrokenPipe` and the writer gets
+//! `Signal::Pipe`.
+use alloc::{collections::VecDeque, sync::Arc};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    error::{KError, KResult},
+    signal::Signal,
+    sync::WaitQueue,
+    task,
+};
+
+/// Bytes a pipe holds before writers block.
+pub const PIPE_SIZE: usize = 4096;
+
+struct Buffer {
+    data: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+struct Pipe {
+    buffer: Mutex<Buffer>,
+    /// woken when data arrives or the last writer goes away
+    readable: WaitQueue,
+    /// woken when space frees up or the last reader goes away
+    writable: WaitQueue,
+}
+
+pub struct PipeReader(Arc<Pipe>);
+
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Creates a pipe, returns its read and write end.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(Buffer {
+            data: VecDeque::with_capacity(PIPE_SIZE),
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+impl PipeReader {
+    /// Reads at most `buf.len()` bytes, blocking until some data is available. Returns 0 at
+    /// end of file.
+    pub fn read(&self, buf: &mut [u8]) -> KResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let mut read = 0;
+        pipe.readable.wait_until(|| {
+            let mut buffer = pipe.buffer.lock();
+            if buffer.data.is_empty() {
+                return buffer.writers == 0;
+            }
+            read = buffer.data.len().min(buf.len());
+            for (dest, byte) in buf.iter_mut().zip(buffer.data.drain(..read)) {
+                *dest = byte;
+            }
+            true
+        });
+        if read > 0 {
+            pipe.writable.wake_all();
+        }
+        Ok(read)
+    }
+}
+
+impl PipeWriter {
+    /// Writes all of `buf`, blocking while the pipe is full. If the read ends go away midway,
+    /// returns what was written so far.
+    pub fn write(&self, buf: &[u8]) -> KResult<usize> {
+        let pipe = &self.0;
+        let mut written = 0;
+        let mut broken = false;
+        while written < buf.len() && !broken {
+            let mut chunk = 0;
+            pipe.writable.wait_until(|| {
+                let mut buffer = pipe.buffer.lock();
+                if buffer.readers == 0 {
+                    broken = true;
+                    return true;
+                }
+                chunk = (PIPE_SIZE - buffer.data.len()).min(buf.len() - written);
+                buffer.data.extend(&buf[written..written + chunk]);
+                chunk > 0
+            });
+            if chunk > 0 {
+                written += chunk;
+                pipe.readable.wake_all();
+            }
+        }
+        if broken && written == 0 {
+            let _ = task::send_signal(task::current_id(), Signal::Pipe);
+            return Err(KError::BrokenPipe);
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        without_interrupts(|| self.0.buffer.lock().readers -= 1);
+        self.0.writable.wake_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        without_interrupts(|| self.0.buffer.lock().writers -= 1);
+        self.0.readable.wake_all();
+    }
+}
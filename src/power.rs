@@ -0,0 +1,171 @@
+//! Turning the machine off.
+//!
+//! ACPI powers off by writing the S5 sleep type to the PM1 control registers. The sleep type
+//! comes from the `\_S5` package in the DSDT, which is found by scanning the AML for it rather
+//! than interpreting it, like most small kernels do. Emulators that ignore that get their
+//! private shutdown ports tried as well. `suspend` enters S3 the same way.
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::PhysAddr;
+
+use crate::{acpi, hlt_loop};
+
+const SLP_EN: u16 = 1 << 13;
+const SLP_TYP_MASK: u16 = 7 << 10;
+const SCI_EN: u16 = 1;
+/// In the PM1 status registers, set when the machine woke up.
+const WAK_STS: u16 = 1 << 15;
+
+// FADT field offsets
+const FADT_FIRMWARE_CTRL: usize = 36;
+const FADT_SMI_CMD: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_EVT: usize = 56;
+const FADT_PM1B_EVT: usize = 60;
+const FADT_PM1A_CNT: usize = 64;
+const FADT_PM1B_CNT: usize = 68;
+const FADT_X_FIRMWARE_CTRL: usize = 132;
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+/// Returns SLP_TYPa and SLP_TYPb of the sleep state `name`, like `_S5_`, from the DSDT.
+fn sleep_types(dsdt: &acpi::Table, name: &[u8; 4]) -> Option<(u16, u16)> {
+    let len = dsdt.header.length as usize;
+    let found = (acpi::SDT_HEADER_SIZE..len.saturating_sub(4))
+        .find(|i| dsdt.read::<[u8; 4]>(*i) == Some(*name))?;
+    // a definition, `Name (_S5, Package...)` or `Name (\_S5, ...)`
+    let name_op = dsdt.read::<u8>(found - 1)? == AML_NAME_OP
+        || (dsdt.read::<u8>(found - 1)? == b'\\' && dsdt.read::<u8>(found - 2)? == AML_NAME_OP);
+    if !name_op || dsdt.read::<u8>(found + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+    // the package length takes 1 to 4 bytes, the top two bits of the first count the rest
+    let lead: u8 = dsdt.read(found + 5)?;
+    let mut offset = found + 5 + 1 + (lead >> 6) as usize;
+    // number of elements
+    offset += 1;
+    let mut element = || {
+        if dsdt.read::<u8>(offset)? == AML_BYTE_PREFIX {
+            offset += 1;
+        }
+        let value = dsdt.read::<u8>(offset)? as u16;
+        offset += 1;
+        Some(value)
+    };
+    let a = element()?;
+    let b = element()?;
+    Some((a, b))
+}
+
+/// The PM1 registers and what to write to them to enter a sleep state.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SleepState {
+    pm1a_cnt: u16,
+    /// 0 if there is no second register block, as on most machines
+    pm1b_cnt: u16,
+    pm1a_evt: u16,
+    pm1b_evt: u16,
+    slp_typ_a: u16,
+    slp_typ_b: u16,
+}
+
+impl SleepState {
+    /// Finds the sleep state `name`, like `_S3_`, and makes sure ACPI is enabled. `None` if the
+    /// machine doesn't have it.
+    pub(crate) fn find(name: &[u8; 4]) -> Option<Self> {
+        let fadt = acpi::find_table(b"FACP")?;
+        let dsdt = acpi::dsdt()?;
+        let (slp_typ_a, slp_typ_b) = sleep_types(&dsdt, name)?;
+        let state = Self {
+            pm1a_cnt: fadt.read::<u32>(FADT_PM1A_CNT)? as u16,
+            pm1b_cnt: fadt.read::<u32>(FADT_PM1B_CNT)? as u16,
+            pm1a_evt: fadt.read::<u32>(FADT_PM1A_EVT)? as u16,
+            pm1b_evt: fadt.read::<u32>(FADT_PM1B_EVT)? as u16,
+            slp_typ_a,
+            slp_typ_b,
+        };
+        if state.pm1a_cnt == 0 {
+            return None;
+        }
+        enable_acpi(&fadt, state.pm1a_cnt);
+        Some(state)
+    }
+
+    /// Clears the wake status, which tells whether the machine slept.
+    pub(crate) fn clear_wake_status(&self) {
+        for evt in [self.pm1a_evt, self.pm1b_evt] {
+            if evt != 0 {
+                unsafe { Port::<u16>::new(evt).write(WAK_STS) };
+            }
+        }
+    }
+
+    /// Whether the machine woke up since `clear_wake_status`.
+    pub(crate) fn woke(&self) -> bool {
+        self.pm1a_evt != 0 && unsafe { Port::<u16>::new(self.pm1a_evt).read() } & WAK_STS != 0
+    }
+
+    /// Writes the sleep type and sets SLP_EN. Returns if the machine didn't go to sleep, or
+    /// once it woke up from a state that keeps running the CPU, which S3 and S5 don't.
+    pub(crate) fn enter(&self) {
+        let write = |port: u16, slp_typ: u16| unsafe {
+            let mut cnt = Port::<u16>::new(port);
+            let value = cnt.read() & !SLP_TYP_MASK;
+            cnt.write(value | slp_typ << 10 | SLP_EN);
+        };
+        write(self.pm1a_cnt, self.slp_typ_a);
+        if self.pm1b_cnt != 0 {
+            write(self.pm1b_cnt, self.slp_typ_b);
+        }
+    }
+}
+
+/// Asks the firmware to hand over to ACPI if it is still in legacy mode.
+fn enable_acpi(fadt: &acpi::Table, pm1a_cnt: u16) -> Option<()> {
+    let mut pm1a = Port::<u16>::new(pm1a_cnt);
+    if unsafe { pm1a.read() } & SCI_EN != 0 {
+        return Some(());
+    }
+    let smi_cmd: u32 = fadt.read(FADT_SMI_CMD)?;
+    let enable: u8 = fadt.read(FADT_ACPI_ENABLE)?;
+    if smi_cmd != 0 && enable != 0 {
+        unsafe { Port::<u8>::new(smi_cmd as u16).write(enable) };
+        for _ in 0..1_000_000 {
+            if unsafe { pm1a.read() } & SCI_EN != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    Some(())
+}
+
+/// The physical address of the FACS, which holds the waking vector.
+pub(crate) fn facs() -> Option<PhysAddr> {
+    let fadt = acpi::find_table(b"FACP")?;
+    let addr = match fadt.read::<u64>(FADT_X_FIRMWARE_CTRL) {
+        Some(addr) if addr != 0 => addr,
+        _ => fadt.read::<u32>(FADT_FIRMWARE_CTRL)? as u64,
+    };
+    (addr != 0).then(|| PhysAddr::new(addr))
+}
+
+/// Tries to enter S5 through ACPI. Returns if that didn't work.
+fn acpi_poweroff() -> Option<()> {
+    SleepState::find(b"_S5_")?.enter();
+    Some(())
+}
+
+/// Turns the machine off. Halts forever if nothing worked.
+pub fn poweroff() -> ! {
+    interrupts::disable();
+    acpi_poweroff();
+    unsafe {
+        // QEMU, Bochs and older QEMU, VirtualBox
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xb004).write(0x2000);
+        Port::<u16>::new(0x4004).write(0x3400);
+    }
+    hlt_loop();
+}
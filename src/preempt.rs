@@ -0,0 +1,121 @@
+//! Preemption control and atomic context tracking.
+//!
+//! `disable` and `enable` nest and keep the timer from switching away from the current task in
+//! between, without masking interrupts. A preemption that comes due meanwhile happens at the
+//! outermost `enable`. Interrupt handlers mark themselves with `irq_enter` and `irq_exit`.
+//!
+//! Both counters describe the running task: the scheduler saves them on every switch and
+//! restores those of the next task, so a task switched away from inside an interrupt handler
+//! finds itself still inside it when it resumes.
+//!
+//! Code with preemption disabled or in an interrupt handler is atomic and must not block.
+//! `might_sleep` checks that in debug builds and panics on scheduling while atomic.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::task;
+
+/// There is one cpu, so these are its counters.
+static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// The timer wanted to preempt while preemption was disabled.
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Counters of a task that is switched out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    preempt: usize,
+    irq: usize,
+}
+
+/// Keeps the current task on the cpu until the matching `enable`.
+pub fn disable() {
+    PREEMPT_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undoes a `disable`. The outermost one does the preemption the timer asked for meanwhile.
+pub fn enable() {
+    let previous = PREEMPT_COUNT.fetch_sub(1, Ordering::Relaxed);
+    assert_ne!(previous, 0, "preempt::enable without disable");
+    if previous == 1 && !in_interrupt() && NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        task::yield_now();
+    }
+}
+
+/// Runs `f` with preemption disabled.
+pub fn without_preemption<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    disable();
+    let ret = f();
+    enable();
+    ret
+}
+
+/// Returns whether the timer may switch away from the current task.
+pub fn is_enabled() -> bool {
+    PREEMPT_COUNT.load(Ordering::Relaxed) == 0
+}
+
+/// Called by the scheduler when it wanted to preempt but `is_enabled` said no.
+pub fn request_resched() {
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+}
+
+/// Called at the start of an interrupt handler.
+pub fn irq_enter() {
+    IRQ_DEPTH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called at the end of an interrupt handler.
+pub fn irq_exit() {
+    IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn in_interrupt() -> bool {
+    IRQ_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// Returns whether the current code must not block.
+pub fn in_atomic() -> bool {
+    !is_enabled() || in_interrupt()
+}
+
+/// Checks that the caller is allowed to block or give up the cpu. Called on the way into the
+/// scheduler, does nothing in release builds.
+#[track_caller]
+pub fn might_sleep() {
+    if cfg!(debug_assertions) {
+        assert!(!in_interrupt(), "sleeping in interrupt context");
+        assert!(
+            is_enabled(),
+            "scheduling while atomic, preempt count {}",
+            PREEMPT_COUNT.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Takes the counters of the task being switched out. Called by the scheduler with interrupts
+/// disabled.
+pub fn save() -> Counts {
+    Counts {
+        preempt: PREEMPT_COUNT.load(Ordering::Relaxed),
+        irq: IRQ_DEPTH.load(Ordering::Relaxed),
+    }
+}
+
+/// Installs the counters of the task being switched in.
+pub fn restore(counts: Counts) {
+    PREEMPT_COUNT.store(counts.preempt, Ordering::Relaxed);
+    IRQ_DEPTH.store(counts.irq, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_nesting() {
+    assert!(!in_atomic());
+    disable();
+    without_preemption(|| assert_eq!(save().preempt, 2));
+    assert!(in_atomic());
+    enable();
+    assert!(!in_atomic());
+}
@@ -0,0 +1,109 @@
+//! Sampling profiler driven by the timer interrupt.
+//!
+//! While running, every `interval`th tick records the interrupted instruction pointer and task
+//! into a ring buffer. `report` aggregates the samples by symbol.
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{ksyms, task};
+
+/// Number of samples kept, older ones are overwritten.
+pub const MAX_SAMPLES: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub rip: u64,
+    pub task: task::TaskId,
+}
+
+struct Samples {
+    buf: [Sample; MAX_SAMPLES],
+    /// total number of samples taken since the last start
+    taken: usize,
+}
+
+static SAMPLES: Mutex<Samples> = Mutex::new(Samples {
+    buf: [Sample {
+        rip: 0,
+        task: task::BOOT_TASK,
+    }; MAX_SAMPLES],
+    taken: 0,
+});
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static INTERVAL: AtomicU64 = AtomicU64::new(1);
+static COUNTDOWN: AtomicU64 = AtomicU64::new(1);
+
+/// Discards old samples and starts sampling every `interval` ticks.
+pub fn start(interval: u64) {
+    let interval = interval.max(1);
+    without_interrupts(|| {
+        SAMPLES.lock().taken = 0;
+        INTERVAL.store(interval, Ordering::Relaxed);
+        COUNTDOWN.store(interval, Ordering::Relaxed);
+        RUNNING.store(true, Ordering::Relaxed);
+    });
+}
+
+pub fn stop() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt with the interrupted instruction pointer.
+pub fn tick(rip: u64) {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    if COUNTDOWN.fetch_sub(1, Ordering::Relaxed) > 1 {
+        return;
+    }
+    COUNTDOWN.store(INTERVAL.load(Ordering::Relaxed), Ordering::Relaxed);
+
+    let task = task::current_id();
+    let mut samples = SAMPLES.lock();
+    let slot = samples.taken % MAX_SAMPLES;
+    samples.buf[slot] = Sample { rip, task };
+    samples.taken += 1;
+}
+
+/// Returns the retained samples, oldest first.
+pub fn samples() -> Vec<Sample> {
+    without_interrupts(|| {
+        let samples = SAMPLES.lock();
+        let start = samples.taken.saturating_sub(MAX_SAMPLES);
+        (start..samples.taken)
+            .map(|i| samples.buf[i % MAX_SAMPLES])
+            .collect()
+    })
+}
+
+/// Samples that hit the same symbol.
+#[derive(Debug, Clone)]
+pub struct ReportEntry {
+    /// symbol name, or the raw address if it couldn't be resolved
+    pub name: String,
+    pub samples: usize,
+}
+
+/// Aggregates the retained samples by symbol, most hit first.
+pub fn report() -> Vec<ReportEntry> {
+    let mut by_symbol: BTreeMap<u64, ReportEntry> = BTreeMap::new();
+    for sample in samples() {
+        let (key, name) = match ksyms::resolve(sample.rip) {
+            Some(symbol) => (symbol.addr, String::from(symbol.name())),
+            None => (sample.rip, alloc::format!("{:#x}", sample.rip)),
+        };
+        by_symbol
+            .entry(key)
+            .or_insert(ReportEntry { name, samples: 0 })
+            .samples += 1;
+    }
+    let mut entries: Vec<ReportEntry> = by_symbol.into_values().collect();
+    entries.sort_by(|a, b| b.samples.cmp(&a.samples));
+    entries
+}
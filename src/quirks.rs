@@ -0,0 +1,211 @@
+//! Checks and workarounds for real machines.
+//!
+//! Emulators hand the kernel a tidy machine, real firmware doesn't always. The memory map can
+//! list regions out of order or overlapping each other, and the A20 line should be enabled by
+//! the loader but nothing guarantees it. What's found is logged and worked around where
+//! possible, instead of surfacing later as memory corruption.
+//!
+//! A missing PS/2 controller, common on machines with only USB, is handled by `ps2::init`.
+use core::fmt;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+    boot::Regions,
+    error::{KError, KResult},
+    klog::Level,
+    klogln_at, mem,
+};
+
+/// Address in frame zero the A20 test writes to, above the BIOS data area.
+const A20_TEST: u64 = 0x500;
+/// System control port A, bit 1 enables A20.
+const FAST_A20_PORT: u16 = 0x92;
+
+/// Something wrong with the memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    /// A region ending where it starts or before.
+    Empty { start: u64 },
+    /// A region listed after one that starts higher up.
+    Unsorted { start: u64 },
+    /// Two regions sharing memory. Usable memory loses against anything else.
+    Overlap {
+        start: u64,
+        end: u64,
+        kinds: (MemoryRegionType, MemoryRegionType),
+    },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Empty { start } => write!(f, "empty region at {:#x}", start),
+            Self::Unsorted { start } => write!(f, "region at {:#x} out of order", start),
+            Self::Overlap { start, end, kinds } => write!(
+                f,
+                "{:?} and {:?} overlap at {:#x}-{:#x}",
+                kinds.0, kinds.1, start, end
+            ),
+        }
+    }
+}
+
+fn bounds(region: &MemoryRegion) -> (u64, u64) {
+    (region.range.start_addr(), region.range.end_addr())
+}
+
+/// Calls `report` with everything wrong with `regions`.
+pub fn check_memory_map(regions: &[MemoryRegion], report: &mut impl FnMut(Anomaly)) {
+    for (i, region) in regions.iter().enumerate() {
+        let (start, end) = bounds(region);
+        if start >= end {
+            report(Anomaly::Empty { start });
+            continue;
+        }
+        if i > 0 && bounds(&regions[i - 1]).0 > start {
+            report(Anomaly::Unsorted { start });
+        }
+        for other in &regions[..i] {
+            let (other_start, other_end) = bounds(other);
+            if other_start < end && start < other_end {
+                report(Anomaly::Overlap {
+                    start: start.max(other_start),
+                    end: end.min(other_end),
+                    kinds: (other.region_type, region.region_type),
+                });
+            }
+        }
+    }
+}
+
+/// Returns `map` sorted, with overlapping usable regions merged and everything else cut out of
+/// them, so no frame is handed out twice or while in use. Logs what was wrong with it.
+pub fn sanitize_memory_map(map: &MemoryMap) -> MemoryMap {
+    let mut anomalies = 0;
+    check_memory_map(map, &mut |anomaly| {
+        klogln_at!(Level::Warn, "memory map: {}", anomaly);
+        anomalies += 1;
+    });
+    if anomalies > 0 {
+        klogln_at!(Level::Warn, "memory map: fixed {} anomalies", anomalies);
+    }
+
+    let mut regions = Regions::new();
+    for region in map.iter() {
+        let (start, end) = bounds(region);
+        if region.region_type == MemoryRegionType::Usable {
+            regions.add(start, end, region.region_type);
+        }
+    }
+    regions.merge_usable();
+    for region in map.iter() {
+        let (start, end) = bounds(region);
+        if region.region_type != MemoryRegionType::Usable {
+            regions.reserve(start, end, region.region_type);
+        }
+    }
+    regions.into_memory_map()
+}
+
+/// Returns whether the A20 line is enabled, `None` if it can't be tested. Writes to frame zero
+/// and checks whether it shows up a MiB higher, which it does with A20 disabled. Requires
+/// `mem::init`.
+pub fn a20_enabled() -> Option<bool> {
+    let low = mem::phys_to_mapped_virt(PhysAddr::new(A20_TEST))?.as_mut_ptr::<u32>();
+    let high = mem::phys_to_mapped_virt(PhysAddr::new(A20_TEST + 0x10_0000))?.as_ptr::<u32>();
+    unsafe {
+        let saved = low.read_volatile();
+        let before = high.read_volatile();
+        low.write_volatile(!before);
+        let aliased = high.read_volatile() != before;
+        low.write_volatile(saved);
+        Some(!aliased)
+    }
+}
+
+/// Checks that A20 is enabled, enables it through the fast A20 gate if not.
+pub fn check_a20() -> KResult<()> {
+    match a20_enabled() {
+        Some(true) => Ok(()),
+        None => {
+            klogln_at!(Level::Warn, "a20: low memory isn't mapped, can't check");
+            Ok(())
+        }
+        Some(false) => {
+            klogln_at!(
+                Level::Warn,
+                "a20: disabled, enabling through port {:#x}",
+                FAST_A20_PORT
+            );
+            let mut port = Port::<u8>::new(FAST_A20_PORT);
+            // bit 0 resets the machine
+            unsafe {
+                let value = port.read();
+                port.write((value | 2) & !1);
+            }
+            if a20_enabled() == Some(true) {
+                Ok(())
+            } else {
+                klogln_at!(
+                    Level::Error,
+                    "a20: can't be enabled, odd megabytes are aliased"
+                );
+                Err(KError::Unsupported)
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_sanitize_memory_map() {
+    use bootloader::bootinfo::FrameRange;
+
+    let mut map = MemoryMap::new();
+    let mut add = |start, end, region_type| {
+        map.add_region(MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        })
+    };
+    add(0x10_0000, 0x80_0000, MemoryRegionType::Usable);
+    add(0x1000, 0x9_f000, MemoryRegionType::Usable);
+    add(0x70_0000, 0x90_0000, MemoryRegionType::Usable);
+    add(0x20_0000, 0x30_0000, MemoryRegionType::Reserved);
+
+    let mut found = [None; 4];
+    let mut count = 0;
+    check_memory_map(&map, &mut |anomaly| {
+        found[count] = Some(anomaly);
+        count += 1;
+    });
+    assert_eq!(count, 4);
+    assert_eq!(found[0], Some(Anomaly::Unsorted { start: 0x1000 }));
+    assert_eq!(
+        found[1],
+        Some(Anomaly::Overlap {
+            start: 0x70_0000,
+            end: 0x80_0000,
+            kinds: (MemoryRegionType::Usable, MemoryRegionType::Usable),
+        })
+    );
+    assert_eq!(found[2], Some(Anomaly::Unsorted { start: 0x20_0000 }));
+
+    let sanitized = sanitize_memory_map(&map);
+    let mut regions = sanitized
+        .iter()
+        .map(|region| (bounds(region), region.region_type));
+    let usable = MemoryRegionType::Usable;
+    assert_eq!(regions.next(), Some(((0x1000, 0x9_f000), usable)));
+    assert_eq!(regions.next(), Some(((0x10_0000, 0x20_0000), usable)));
+    assert_eq!(
+        regions.next(),
+        Some(((0x20_0000, 0x30_0000), MemoryRegionType::Reserved))
+    );
+    assert_eq!(regions.next(), Some(((0x30_0000, 0x90_0000), usable)));
+    assert_eq!(regions.next(), None);
+    let mut clean = 0;
+    check_memory_map(&sanitized, &mut |_| clean += 1);
+    assert_eq!(clean, 0);
+}
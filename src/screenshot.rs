@@ -0,0 +1,63 @@
+//! Screenshots of the text console.
+//!
+//! PrintScreen saves the screen to `/screenshot<n>.scr`, the `screenshot` command to a given
+//! path; see `Snapshot::to_bytes` for the format. `show` displays a screenshot until a key is
+//! pressed. Saving happens in its own task, as the hotkey runs in the keyboard interrupt.
+use alloc::format;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pc_keyboard::KeyCode;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    ext::Errno,
+    keyboard::{self, Modifiers},
+    klog::Level,
+    klogln_at,
+    sync::Event,
+    task, vfs,
+    vga_buffer::{Snapshot, WRITER},
+};
+
+static REQUESTED: Event = Event::new();
+/// Number of the next screenshot taken with the hotkey.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    // only fails if something else took the key
+    let _ = keyboard::register_hotkey(Modifiers::NONE, KeyCode::PrintScreen, request);
+    task::spawn("screenshot", || loop {
+        REQUESTED.wait_and_reset();
+        let path = format!("/screenshot{}.scr", NEXT.fetch_add(1, Ordering::Relaxed));
+        match save(&path) {
+            Ok(()) => klogln_at!(Level::Info, "screenshot: saved {}", path),
+            Err(e) => klogln_at!(Level::Warn, "screenshot: can't save {}: {:?}", path, e),
+        }
+    });
+}
+
+fn request() {
+    REQUESTED.signal();
+}
+
+/// Saves the current screen contents to `path`.
+pub fn save(path: &str) -> Result<(), Errno> {
+    let snapshot = without_interrupts(|| WRITER.lock().snapshot());
+    vfs::write(path, &snapshot.to_bytes())
+}
+
+/// Shows the screenshot at `path` until a key is pressed, then puts the screen back.
+pub fn show(path: &str) -> Result<(), Errno> {
+    let data = vfs::read(path)?;
+    let screenshot = Snapshot::from_bytes(&data).ok_or(Errno::InvalidFileImage)?;
+    keyboard::grab();
+    let screen = without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let screen = writer.snapshot();
+        writer.restore(&screenshot);
+        screen
+    });
+    keyboard::read_key();
+    without_interrupts(|| WRITER.lock().restore(&screen));
+    keyboard::release();
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! Runs unit tests on a booted kernel, for the `selftest` command.
+//!
+//! `cargo test` runs every `#[test_case]` in QEMU and reports through the serial port and the
+//! `isa-debug-exit` device, neither of which real hardware has. The tests listed in `TESTS`
+//! only check parsers, encoders and tables, so they can also run after boot without disturbing
+//! the running system. They are marked `#[cfg_attr(test, test_case)]` so they are built into
+//! every kernel and still run with `cargo test`.
+//!
+//! Tests fail by panicking and panics can't be caught, so a failing test halts the machine. The
+//! panic handler names the test that was running, see `running`.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{print, println, time};
+
+/// Pairs each test with its name, the path of its module and the function.
+#[cfg(not(test))]
+macro_rules! tests {
+    ($($($module:ident)::+ => $test:ident),* $(,)?) => {
+        &[$((
+            concat!(stringify!($($module)::+), "::", stringify!($test)),
+            crate::$($module::)+$test as fn(),
+        )),*]
+    };
+}
+
+/// The tests that are safe to run after boot, by name.
+#[cfg(not(test))]
+static TESTS: &[(&str, fn())] = tests![
+    bench => test_percentiles,
+    bench => test_workload_names,
+    clock => test_date_time,
+    cmdline::alias => test_expand,
+    cmdline::args => test_tokenize,
+    cmdline::calc => test_eval,
+    cmdline::command => test_check_args,
+    compress::lz4 => test_lz4_roundtrip,
+    config => test_parse_size,
+    crypto::blake2 => test_blake2s,
+    crypto::crc32 => test_crc32,
+    crypto::sha256 => test_sha256,
+    drivers::mbr => test_parse,
+    error => test_errno_roundtrip,
+    font => test_parse_map,
+    hwdebug => test_check_range,
+    init => test_steps_depend_on_earlier_stages,
+    keymap => test_parse,
+    net => test_checksum,
+    net => test_parse_addresses,
+    net::arp => test_build_and_parse,
+    net::ethernet => test_build_and_parse,
+    net::http => test_parse_response,
+    net::http => test_parse_url,
+    net::ipv4 => test_build_and_parse,
+    net::sntp => test_parse_response,
+    net::tcp => test_build_and_parse,
+    net::udp => test_checksum,
+    pci => test_config_access,
+    signal => test_signal_set_order,
+    syscall_table => test_table,
+    sysconf => test_parse,
+    theme => test_theme_pack_roundtrip,
+    thermal => test_critical,
+    thermal => test_scan_zones,
+];
+
+/// `cargo test` runs the tests itself, and moves them where they can't be named.
+#[cfg(test)]
+static TESTS: &[(&str, fn())] = &[];
+
+/// Index into `TESTS` of the test running now, `usize::MAX` if none is.
+static RUNNING: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The names of the tests.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    TESTS.iter().map(|(name, _)| *name)
+}
+
+/// The name of the test running now. A panic while one is running means it failed.
+pub fn running() -> Option<&'static str> {
+    TESTS
+        .get(RUNNING.load(Ordering::Relaxed))
+        .map(|(name, _)| *name)
+}
+
+/// Runs the tests whose names start with `prefix`, printing each with the time it took, and
+/// returns how many ran.
+pub fn run(prefix: &str) -> usize {
+    let mut count = 0;
+    let started = time::uptime();
+    for (i, (name, test)) in TESTS.iter().enumerate() {
+        if !name.starts_with(prefix) {
+            continue;
+        }
+        print!("{name}... ");
+        let start = time::uptime();
+        RUNNING.store(i, Ordering::Relaxed);
+        test();
+        RUNNING.store(usize::MAX, Ordering::Relaxed);
+        println!("ok ({} ms)", (time::uptime() - start).as_millis());
+        count += 1;
+    }
+    if count > 0 {
+        let elapsed = time::uptime() - started;
+        println!("{count} tests passed in {} ms", elapsed.as_millis());
+    }
+    count
+}
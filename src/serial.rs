@@ -20,6 +20,7 @@ pub fn _print(args: ::core::fmt::Arguments) {
             .write_fmt(args)
             .expect("Printing to serial failed")
     });
+    crate::debugcon::_print(args);
 }
 
 /// Prints to the host through the serial interface.
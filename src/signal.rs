@@ -0,0 +1,145 @@
+//! Signals, asynchronous notifications sent to tasks.
+//!
+//! A signal is queued on the target task and delivered the next time that task passes a safe
+//! point (see `task::handle_signals`). `Kill` and `Stop` can't be caught, every other signal
+//! either runs the handler installed with `task::set_signal_handler` or its default action.
+use core::fmt;
+
+/// The signals a task can receive. The numbers match the usual unix ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Signal {
+    /// interrupt from the keyboard (Ctrl+C)
+    Int = 2,
+    Kill = 9,
+    Usr1 = 10,
+    Usr2 = 12,
+    /// write to a pipe nobody reads from anymore
+    Pipe = 13,
+    Term = 15,
+    Cont = 18,
+    Stop = 19,
+}
+
+/// What happens when a signal without a handler is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Terminate,
+    Stop,
+    Continue,
+    Ignore,
+}
+
+/// Every signal, in the order pending signals are delivered.
+pub const ALL: [Signal; 8] = [
+    Signal::Kill,
+    Signal::Stop,
+    Signal::Cont,
+    Signal::Int,
+    Signal::Term,
+    Signal::Pipe,
+    Signal::Usr1,
+    Signal::Usr2,
+];
+
+/// Function run in the context of the receiving task.
+pub type Handler = fn(Signal);
+
+impl Signal {
+    pub fn from_number(number: u64) -> Option<Self> {
+        ALL.into_iter().find(|signal| *signal as u64 == number)
+    }
+
+    /// Parses a signal name like `INT` or `SIGINT`, or a signal number.
+    pub fn parse(name: &str) -> Option<Self> {
+        if let Ok(number) = name.parse() {
+            return Self::from_number(number);
+        }
+        let name = name.strip_prefix("SIG").unwrap_or(name);
+        ALL.into_iter().find(|signal| signal.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Int => "INT",
+            Self::Kill => "KILL",
+            Self::Usr1 => "USR1",
+            Self::Usr2 => "USR2",
+            Self::Pipe => "PIPE",
+            Self::Term => "TERM",
+            Self::Cont => "CONT",
+            Self::Stop => "STOP",
+        }
+    }
+
+    pub fn default_action(self) -> Action {
+        match self {
+            Self::Int | Self::Kill | Self::Pipe | Self::Term => Action::Terminate,
+            Self::Stop => Action::Stop,
+            Self::Cont => Action::Continue,
+            Self::Usr1 | Self::Usr2 => Action::Ignore,
+        }
+    }
+
+    /// Whether a task can install a handler for this signal.
+    pub fn is_catchable(self) -> bool {
+        !matches!(self, Self::Kill | Self::Stop)
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SIG{}", self.name())
+    }
+}
+
+/// A set of signals, used for the signals pending on a task.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SignalSet(u32);
+
+impl SignalSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, signal: Signal) -> bool {
+        self.0 & signal.bit() != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= signal.bit();
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !signal.bit();
+    }
+
+    /// Removes and returns the signal that should be delivered first.
+    pub fn take_next(&mut self) -> Option<Signal> {
+        let signal = ALL.into_iter().find(|signal| self.contains(*signal))?;
+        self.remove(signal);
+        Some(signal)
+    }
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_signal_set_order() {
+    let mut set = SignalSet::empty();
+    set.insert(Signal::Usr1);
+    set.insert(Signal::Kill);
+    set.insert(Signal::Int);
+    assert_eq!(set.take_next(), Some(Signal::Kill));
+    assert_eq!(set.take_next(), Some(Signal::Int));
+    assert_eq!(set.take_next(), Some(Signal::Usr1));
+    assert!(set.is_empty());
+    assert_eq!(Signal::parse("SIGTERM"), Some(Signal::Term));
+    assert_eq!(Signal::parse("9"), Some(Signal::Kill));
+}
@@ -0,0 +1,304 @@
+//! Suspend to RAM (ACPI S3).
+//!
+//! A prototype, only tried on QEMU. The machine goes to sleep by writing the `\_S3` sleep type
+//! to the PM1 control registers, like `power` does for S5. That turns the CPU off: when the
+//! machine wakes up, the firmware starts it in real mode at the waking vector in the FACS. It
+//! points to the wakeup code below, which switches through protected mode into long mode on the
+//! kernel's page tables and returns from the `skyos_suspend` call that went to sleep. The code
+//! has to run below 1 MiB in a page mapped at its physical address, which `init` sets aside.
+//!
+//! Before going to sleep, drivers stop their devices with `Driver::suspend` and the PCI
+//! configuration space headers are saved. After waking up, the GDT, IDT, PICs and PIT are set
+//! up again, the headers are written back and the drivers resume. Without an AML interpreter
+//! the `_PTS` and `_WAK` methods aren't run. Ticks don't advance while the machine sleeps, so
+//! the uptime leaves the time slept out.
+use core::arch::{asm, global_asm};
+use core::ptr;
+use spin::Once;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::error::{KError, KResult};
+use crate::klog::Level;
+use crate::power::{self, SleepState};
+use crate::{cpufreq, drivers, gdt, interrupts, klogln_at, mem, pci, time};
+
+/// The real mode code has to be below this.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+// FACS field offsets
+const FACS_LENGTH: usize = 4;
+const FACS_WAKING_VECTOR: usize = 12;
+const FACS_X_WAKING_VECTOR: usize = 24;
+
+/// The sleep type of S3, if the machine has it.
+static S3: Once<SleepState> = Once::new();
+/// The frame the wakeup code is copied to, mapped at its physical address.
+static WAKE_FRAME: Once<PhysFrame> = Once::new();
+
+// The firmware jumps to the waking vector with CS set to its paragraph, so the code finds out
+// where it runs from CS and only uses addresses relative to `skyos_wake_start` until paging is
+// on. The fields at the end are filled in by `sleep`.
+global_asm!(
+    r#"
+    .code16
+    .global skyos_wake_start
+skyos_wake_start:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+    movzwl %ax, %ebx
+    shll $4, %ebx
+    leal (skyos_wake_gdt - skyos_wake_start)(%ebx), %eax
+    movl %eax, (skyos_wake_gdtr - skyos_wake_start + 2)
+    leal (skyos_wake_protected - skyos_wake_start)(%ebx), %eax
+    movl %eax, (skyos_wake_protected_ptr - skyos_wake_start)
+    leal (skyos_wake_long - skyos_wake_start)(%ebx), %eax
+    movl %eax, (skyos_wake_long_ptr - skyos_wake_start)
+    lgdtl (skyos_wake_gdtr - skyos_wake_start)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl *(skyos_wake_protected_ptr - skyos_wake_start)
+
+    .code32
+skyos_wake_protected:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (skyos_wake_cr3 - skyos_wake_start)(%ebx), %eax
+    movl %eax, %cr3
+    movl $0xc0000080, %ecx
+    movl (skyos_wake_efer - skyos_wake_start)(%ebx), %eax
+    xorl %edx, %edx
+    wrmsr
+    movl %cr0, %eax
+    orl $0x80000000, %eax
+    movl %eax, %cr0
+    ljmpl *(skyos_wake_long_ptr - skyos_wake_start)(%ebx)
+
+    .code64
+skyos_wake_long:
+    movl %ebx, %ebx
+    movq (skyos_wake_rsp - skyos_wake_start)(%rbx), %rsp
+    jmpq *(skyos_wake_entry - skyos_wake_start)(%rbx)
+
+    .balign 8
+skyos_wake_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+skyos_wake_gdtr:
+    .word 31
+    .long 0
+skyos_wake_protected_ptr:
+    .long 0
+    .word 0x08
+skyos_wake_long_ptr:
+    .long 0
+    .word 0x18
+    .balign 8
+    .global skyos_wake_cr3
+skyos_wake_cr3:
+    .quad 0
+    .global skyos_wake_efer
+skyos_wake_efer:
+    .quad 0
+    .global skyos_wake_rsp
+skyos_wake_rsp:
+    .quad 0
+    .global skyos_wake_entry
+skyos_wake_entry:
+    .quad 0
+    .global skyos_wake_end
+skyos_wake_end:
+
+    .global skyos_suspend
+skyos_suspend:
+    push %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    pushfq
+    movq %rsp, (%rdi)
+    callq *%rsi
+    popfq
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    xorl %eax, %eax
+    ret
+
+    .global skyos_resume
+skyos_resume:
+    popfq
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    movl $1, %eax
+    ret
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static skyos_wake_start: u8;
+    static skyos_wake_cr3: u8;
+    static skyos_wake_efer: u8;
+    static skyos_wake_rsp: u8;
+    static skyos_wake_entry: u8;
+    static skyos_wake_end: u8;
+    /// Saves the callee-saved registers and the flags on the stack, stores the stack pointer in
+    /// `rsp` and calls `enter`. Returns false if `enter` returns, and true when the wakeup code
+    /// jumps to `skyos_resume` with the stored stack pointer.
+    fn skyos_suspend(rsp: *mut u64, enter: extern "C" fn()) -> bool;
+    fn skyos_resume();
+}
+
+/// Offset of a symbol of the wakeup code from its start.
+fn wake_offset(symbol: *const u8) -> usize {
+    symbol as usize - ptr::addr_of!(skyos_wake_start) as usize
+}
+
+/// Looks for S3 and sets aside a page below 1 MiB for the wakeup code. Frames are handed out
+/// from the lowest up, so this has to run soon after the heap is set up.
+pub fn init() {
+    let Some(state) = SleepState::find(b"_S3_") else {
+        klogln_at!(Level::Info, "suspend: the firmware has no S3");
+        return;
+    };
+    let Some(frame) = mem::alloc_frame() else {
+        return;
+    };
+    if frame.start_address().as_u64() >= LOW_MEMORY_END {
+        mem::free_frame(frame);
+        klogln_at!(Level::Warn, "suspend: no free page below 1 MiB, no S3");
+        return;
+    }
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if mem::map_page(page, frame, flags).is_err() && mem::translate_page(page) != Some(frame) {
+        mem::free_frame(frame);
+        klogln_at!(Level::Warn, "suspend: can't map the wakeup code, no S3");
+        return;
+    }
+    S3.call_once(|| state);
+    WAKE_FRAME.call_once(|| frame);
+}
+
+/// Puts the machine to sleep until something wakes it up, like `system_wakeup` in the QEMU
+/// monitor. Fails with `Unsupported` if there's no S3, with the error of a driver that couldn't
+/// suspend, or with `Io` if the machine didn't go to sleep.
+pub fn suspend() -> KResult<()> {
+    // only set if there is S3
+    let frame = *WAKE_FRAME.r#try().ok_or(KError::Unsupported)?;
+    let facs = power::facs()
+        .and_then(mem::phys_to_mapped_virt)
+        .ok_or(KError::Unsupported)?;
+    // the wakeup code loads CR3 while still in 32 bit mode
+    if Cr3::read().0.start_address().as_u64() >= 1 << 32 {
+        return Err(KError::Unsupported);
+    }
+
+    drivers::suspend_all()?;
+    let config = pci::save_config();
+    let masks = interrupts::pic_masks();
+    let woke = without_interrupts(|| unsafe {
+        let cr0 = Cr0::read_raw();
+        let cr4 = Cr4::read_raw();
+        let woke = sleep(frame, facs);
+        if woke {
+            Cr4::write_raw(cr4);
+            Cr0::write_raw(cr0);
+            gdt::reload();
+            interrupts::init_idt();
+            interrupts::restore_pics(masks);
+            time::init();
+            cpufreq::resume();
+        }
+        woke
+    });
+    if woke {
+        pci::restore_config(&config);
+    }
+    drivers::resume_all();
+    match woke {
+        true => Ok(()),
+        false => Err(KError::Io),
+    }
+}
+
+/// Copies the wakeup code to `frame`, points the waking vector in the FACS at it and goes to
+/// sleep. Returns whether the machine slept.
+unsafe fn sleep(frame: PhysFrame, facs: VirtAddr) -> bool {
+    let start = ptr::addr_of!(skyos_wake_start);
+    let len = wake_offset(ptr::addr_of!(skyos_wake_end));
+    // mapped at its physical address by `init`
+    let base = frame.start_address().as_u64() as *mut u8;
+    ptr::copy_nonoverlapping(start, base, len);
+    let field = |symbol: *const u8| base.add(wake_offset(symbol)) as *mut u64;
+    let (cr3, _) = Cr3::read();
+    field(ptr::addr_of!(skyos_wake_cr3)).write(cr3.start_address().as_u64());
+    let efer = Efer::read_raw() & !EferFlags::LONG_MODE_ACTIVE.bits();
+    field(ptr::addr_of!(skyos_wake_efer)).write(efer);
+    field(ptr::addr_of!(skyos_wake_entry)).write(skyos_resume as usize as u64);
+
+    let facs = facs.as_mut_ptr::<u8>();
+    let waking_vector = facs.add(FACS_WAKING_VECTOR) as *mut u32;
+    ptr::write_volatile(waking_vector, frame.start_address().as_u64() as u32);
+    // the 64 bit vector exists since version 1 and wins if it is set
+    if ptr::read_volatile(facs.add(FACS_LENGTH) as *const u32) >= 32 {
+        ptr::write_volatile(facs.add(FACS_X_WAKING_VECTOR) as *mut u64, 0);
+    }
+
+    skyos_suspend(field(ptr::addr_of!(skyos_wake_rsp)), enter_s3)
+}
+
+/// Called by `skyos_suspend` once everything is saved.
+extern "C" fn enter_s3() {
+    let Some(state) = S3.r#try() else {
+        return;
+    };
+    state.clear_wake_status();
+    // the caches are lost while sleeping
+    unsafe { asm!("wbinvd", options(nostack)) };
+    state.enter();
+    // a machine that went to sleep doesn't get here, give it some time to
+    for _ in 0..10_000_000 {
+        if state.woke() {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_wake_code_fits() {
+    let len = wake_offset(ptr::addr_of!(skyos_wake_end));
+    assert!(len <= mem::PAGE_SIZE);
+    // written as u64 by `sleep`
+    for field in [
+        ptr::addr_of!(skyos_wake_cr3),
+        ptr::addr_of!(skyos_wake_efer),
+        ptr::addr_of!(skyos_wake_rsp),
+        ptr::addr_of!(skyos_wake_entry),
+    ] {
+        assert_eq!(wake_offset(field) % 8, 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Blocking primitives built on top of the scheduler.
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+/// Also used by code that should only depend on `sync`, like the ext2 locks, which run hosted
+/// in the fuzzer.
+pub use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::task::{self, TaskId};
+
+/// A list of tasks waiting for a condition to become true.
+///
+/// Waking is safe from interrupt context, so drivers can signal completion straight from their
+/// interrupt handlers.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current task until `cond` returns true.
+    ///
+    /// `cond` is evaluated with interrupts disabled, so a wake-up can't get lost between the
+    /// check and going to sleep.
+    pub fn wait_until<F>(&self, mut cond: F)
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            let done = without_interrupts(|| {
+                if cond() {
+                    return true;
+                }
+                self.waiters.lock().push_back(task::current_id());
+                task::block_current();
+                false
+            });
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// Wakes the task that has been waiting the longest. Returns whether a task was woken.
+    pub fn wake_one(&self) -> bool {
+        let waiter = without_interrupts(|| self.waiters.lock().pop_front());
+        match waiter {
+            Some(id) => {
+                task::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting task.
+    pub fn wake_all(&self) {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for id in waiters {
+            task::wake(id);
+        }
+    }
+
+    /// Wakes every waiting task with the highest priority, see `task::wake_boosted`. For
+    /// queues of tasks waiting for user input.
+    pub fn wake_all_boosted(&self) {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for id in waiters {
+            task::wake_boosted(id);
+        }
+    }
+}
+
+/// A flag tasks can wait on until another task or an interrupt handler signals it.
+///
+/// The event stays signaled until `reset` is called.
+pub struct Event {
+    signaled: AtomicBool,
+    queue: WaitQueue,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            signaled: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Signals the event and wakes everyone waiting on it.
+    pub fn signal(&self) {
+        self.signaled.store(true, Ordering::Release);
+        self.queue.wake_all();
+    }
+
+    pub fn reset(&self) {
+        self.signaled.store(false, Ordering::Release);
+    }
+
+    pub fn is_signaled(&self) -> bool {
+        self.signaled.load(Ordering::Acquire)
+    }
+
+    /// Blocks the current task until the event is signaled.
+    pub fn wait(&self) {
+        self.queue.wait_until(|| self.is_signaled());
+    }
+
+    /// Waits for the event and resets it, so every signal is consumed once.
+    pub fn wait_and_reset(&self) {
+        self.queue
+            .wait_until(|| self.signaled.swap(false, Ordering::AcqRel));
+    }
+}
@@ -0,0 +1,330 @@
+//! System call entry and dispatch.
+//!
+//! System calls are made with `int 0x80`. The number goes in rax, the arguments in rdi, rsi,
+//! rdx, r10, r8 and r9, and the result is returned in rax. Errors are returned as negative
+//! error numbers, see `KError`. The numbers and arguments are defined in `syscall_table`.
+use core::arch::{asm, global_asm};
+use core::fmt;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+use crate::{
+    error::{KError, KResult},
+    ext::{AccessFlags, Credentials},
+    fd::{self, File, VfsFile},
+    futex, klogln, mmap, pipe,
+    signal::Signal,
+    task::{self, TaskId},
+    time, timer, vfs,
+};
+
+/// Interrupt vector used for system calls.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Longest path a system call accepts, including the terminating NUL.
+pub const PATH_MAX: usize = 4096;
+
+pub use crate::syscall_table::*;
+
+pub const EPERM: i64 = KError::NotPermitted.errno();
+pub const ESRCH: i64 = KError::NoSuchTask.errno();
+pub const EBADF: i64 = KError::BadFileDescriptor.errno();
+pub const EAGAIN: i64 = KError::WouldBlock.errno();
+pub const EFAULT: i64 = KError::BadAddress.errno();
+pub const EINVAL: i64 = KError::InvalidArgument.errno();
+pub const ENOSYS: i64 = KError::NotImplemented.errno();
+pub const ETIMEDOUT: i64 = KError::TimedOut.errno();
+
+/// Registers saved by the entry stub, followed by the frame pushed by the cpu.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// The cpu aligns the stack before pushing its 5 word frame, the 15 pushed registers bring it
+// back to a 16 byte boundary for the call.
+global_asm!(
+    r#"
+.global skyos_syscall_entry
+skyos_syscall_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call skyos_syscall_handler
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#
+);
+
+extern "C" {
+    fn skyos_syscall_entry();
+}
+
+/// Address of the entry stub, to be put into the IDT.
+pub fn entry_address() -> u64 {
+    skyos_syscall_entry as usize as u64
+}
+
+#[no_mangle]
+extern "C" fn skyos_syscall_handler(frame: &mut SyscallFrame) {
+    // the gate disabled interrupts, restore what the caller had
+    if frame.rflags & (1 << 9) != 0 {
+        interrupts::enable();
+    }
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = dispatch(frame.rax, args) as u64;
+    task::handle_signals();
+    interrupts::disable();
+}
+
+fn name(nr: u64) -> &'static str {
+    find(nr).map_or("unknown", |syscall| syscall.name)
+}
+
+/// Number of arguments shown when tracing a system call.
+fn arg_count(nr: u64) -> usize {
+    find(nr).map_or(6, |syscall| syscall.args.len())
+}
+
+struct Args<'a>(&'a [u64]);
+
+impl fmt::Display for Args<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, arg) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{:#x}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs system call `nr`, logging it if the current task is being traced.
+pub fn dispatch(nr: u64, args: [u64; 6]) -> i64 {
+    let id = task::current_id();
+    let traced = task::is_traced(id);
+    if traced {
+        klogln!("[{}] {}({}) ...", id.0, name(nr), Args(&args[..arg_count(nr)]));
+    }
+    let result = handle(nr, args).unwrap_or_else(|e| -e.errno());
+    if traced {
+        klogln!("[{}] {} = {}", id.0, name(nr), result);
+    }
+    result
+}
+
+fn handle(nr: u64, args: [u64; 6]) -> KResult<i64> {
+    match nr {
+        SYS_EXIT => {
+            if task::current_id() == task::BOOT_TASK {
+                return Err(KError::NotPermitted);
+            }
+            task::exit()
+        }
+        SYS_WRITE => sys_write(args[0], args[1], args[2]),
+        SYS_GETPID => Ok(task::current_id().0 as i64),
+        SYS_YIELD => {
+            task::yield_now();
+            Ok(0)
+        }
+        SYS_SLEEP => {
+            timer::sleep(Duration::from_millis(args[0]));
+            Ok(0)
+        }
+        SYS_UPTIME => Ok(time::uptime().as_millis() as i64),
+        SYS_KILL => sys_kill(args[0], args[1]),
+        SYS_MMAP => sys_mmap(args[1], args[2], args[3], args[4], args[5]),
+        SYS_MUNMAP => {
+            mmap::unmap(args[0], args[1])?;
+            Ok(0)
+        }
+        SYS_MPROTECT => {
+            mmap::protect(args[0], args[1], args[2])?;
+            Ok(0)
+        }
+        SYS_FUTEX => sys_futex(args[0], args[1], args[2], args[3]),
+        SYS_READ => sys_read(args[0], args[1], args[2]),
+        SYS_PIPE => sys_pipe(args[0]),
+        SYS_CLOSE => {
+            fd::close(args[0] as usize)?;
+            Ok(0)
+        }
+        SYS_OPEN => sys_open(args[0], args[1]),
+        SYS_DUP2 => Ok(fd::dup2(args[0] as usize, args[1] as usize)? as i64),
+        SYS_LSEEK => Ok(fd::get(args[0] as usize)?.seek(args[1] as i64, args[2])? as i64),
+        SYS_ACCESS => sys_access(args[0], args[1]),
+        _ => Err(KError::NotImplemented),
+    }
+}
+
+fn sys_kill(id: u64, signal: u64) -> KResult<i64> {
+    let signal = Signal::from_number(signal).ok_or(KError::InvalidArgument)?;
+    task::send_signal(TaskId(id), signal)?;
+    Ok(0)
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)`. The address hint is ignored. Files opened
+/// through the VFS can be mapped, pipes and TTYs can't.
+fn sys_mmap(len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> KResult<i64> {
+    if flags & mmap::MAP_ANONYMOUS != 0 {
+        return Ok(mmap::map_anonymous(len, prot)? as i64);
+    }
+    match &*fd::get(fd as usize)? {
+        File::Vfs(file) if file.is_readable() => {
+            Ok(mmap::map_file(file.path(), offset, len, prot)? as i64)
+        }
+        File::Vfs(_) => Err(KError::AccessDenied),
+        _ => Err(KError::NoDevice),
+    }
+}
+
+/// `futex(addr, op, val, timeout)`. `FUTEX_WAIT` blocks while the word at `addr` is `val`, for
+/// at most `timeout` milliseconds unless it is 0. `FUTEX_WAKE` wakes up to `val` waiters and
+/// returns how many were woken.
+fn sys_futex(addr: u64, op: u64, val: u64, timeout: u64) -> KResult<i64> {
+    match op {
+        futex::FUTEX_WAIT => {
+            let timeout = (timeout != 0).then(|| Duration::from_millis(timeout));
+            futex::wait(addr, val as u32, timeout)?;
+            Ok(0)
+        }
+        futex::FUTEX_WAKE => Ok(futex::wake(addr, val as usize)? as i64),
+        _ => Err(KError::InvalidArgument),
+    }
+}
+
+/// Returns the buffer a system call was passed.
+fn user_buffer<'a>(buf: u64, len: u64) -> KResult<&'a mut [u8]> {
+    if buf == 0 {
+        return Err(KError::BadAddress);
+    }
+    if len > isize::MAX as u64 {
+        return Err(KError::InvalidArgument);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len as usize) })
+}
+
+/// Returns the NUL-terminated string a system call was passed.
+fn user_str<'a>(ptr: u64) -> KResult<&'a str> {
+    if ptr == 0 {
+        return Err(KError::BadAddress);
+    }
+    let ptr = ptr as *const u8;
+    let len = (0..PATH_MAX)
+        .find(|i| unsafe { ptr.add(*i).read() } == 0)
+        .ok_or(KError::NameTooLong)?;
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+    core::str::from_utf8(bytes).map_err(|_| KError::InvalidArgument)
+}
+
+/// `open(path, flags, mode)` opens a file in the VFS, see the `O_*` flags in `fd`. There are no
+/// permissions, so the mode is ignored.
+fn sys_open(path: u64, flags: u64) -> KResult<i64> {
+    let file = VfsFile::open(user_str(path)?, flags)?;
+    Ok(fd::install(File::Vfs(file))? as i64)
+}
+
+/// `access(path, mode)` checks the permissions of a file. Tasks have no credentials yet and
+/// check as the superuser, so only read-only file systems refuse anything but `X_OK`.
+fn sys_access(path: u64, mode: u64) -> KResult<i64> {
+    if mode & !(R_OK | W_OK | X_OK) != 0 {
+        return Err(KError::InvalidArgument);
+    }
+    let mode = AccessFlags::from(mode as u8);
+    vfs::access(user_str(path)?, mode, &Credentials::ROOT)?;
+    Ok(0)
+}
+
+fn sys_read(fd: u64, buf: u64, len: u64) -> KResult<i64> {
+    let file = fd::get(fd as usize)?;
+    Ok(file.read(user_buffer(buf, len)?)? as i64)
+}
+
+fn sys_write(fd: u64, buf: u64, len: u64) -> KResult<i64> {
+    let file = fd::get(fd as usize)?;
+    Ok(file.write(user_buffer(buf, len)?)? as i64)
+}
+
+/// `pipe(fds)` stores the read and the write end of a new pipe in `fds`, two 32 bit integers.
+fn sys_pipe(fds: u64) -> KResult<i64> {
+    if fds == 0 || fds % 4 != 0 {
+        return Err(KError::BadAddress);
+    }
+    let (reader, writer) = pipe::pipe();
+    let read_fd = fd::install(File::PipeReader(reader))?;
+    let write_fd = match fd::install(File::PipeWriter(writer)) {
+        Ok(fd) => fd,
+        Err(e) => {
+            fd::close(read_fd)?;
+            return Err(e);
+        }
+    };
+    unsafe { (fds as *mut [i32; 2]).write([read_fd as i32, write_fd as i32]) };
+    Ok(0)
+}
+
+/// Makes a system call from kernel code.
+pub fn syscall(nr: u64, args: [u64; 6]) -> i64 {
+    let result: u64;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") nr => result,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
+        );
+    }
+    result as i64
+}
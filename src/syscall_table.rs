@@ -0,0 +1,200 @@
+//! The system call numbers and their arguments.
+//!
+//! This table is the only place system calls are numbered. The kernel's dispatcher and
+//! tracing use it through `syscall`, and `userspace/sys` generates its Rust wrappers and the
+//! C header `skyos/syscalls.h` from it at build time, so user programs can't end up with
+//! numbers or arguments the kernel doesn't agree with. Numbers are never reused or changed,
+//! new system calls go at the end.
+//!
+//! A system call is made with `int 0x80`, the number in rax and the arguments in rdi, rsi, rdx,
+//! r10, r8 and r9, in the order listed here. The result comes back in rax, negative error
+//! numbers for errors. Every other register is preserved. The flags and values arguments take
+//! are defined here as well, see `CONSTANTS`.
+//!
+//! The build scripts include this file on their own, so it can only use `core`.
+
+/// How an argument is passed, which decides its type in the generated wrappers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// an integer, like flags or a count of milliseconds
+    Int,
+    /// a signed integer, like a file offset
+    Signed,
+    /// the size of a buffer
+    Size,
+    /// a file descriptor
+    Fd,
+    /// memory the kernel only reads
+    Ptr,
+    /// memory the kernel writes to
+    MutPtr,
+    /// a NUL-terminated string of at most `PATH_MAX` bytes
+    Str,
+}
+
+impl ArgKind {
+    /// The type of the argument in C.
+    pub const fn c_type(self) -> &'static str {
+        match self {
+            Self::Int => "unsigned long",
+            Self::Signed => "long",
+            Self::Size => "size_t",
+            Self::Fd => "int",
+            Self::Ptr => "const void *",
+            Self::MutPtr => "void *",
+            Self::Str => "const char *",
+        }
+    }
+
+    /// The type of the argument in Rust.
+    pub const fn rust_type(self) -> &'static str {
+        match self {
+            Self::Int => "u64",
+            Self::Signed => "i64",
+            Self::Size => "usize",
+            Self::Fd => "i32",
+            Self::Ptr => "*const u8",
+            Self::MutPtr => "*mut u8",
+            Self::Str => "*const u8",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+}
+
+/// A system call, see `SYSCALLS`.
+#[derive(Debug, Clone, Copy)]
+pub struct Syscall {
+    pub nr: u64,
+    pub name: &'static str,
+    pub args: &'static [Arg],
+    /// what it does and returns, copied into the generated documentation
+    pub doc: &'static str,
+}
+
+/// Defines a `SYS_*` constant for every system call and lists them all in `SYSCALLS`.
+macro_rules! syscalls {
+    ($(
+        $(#[doc = $doc:literal])*
+        $nr:literal $const:ident $name:ident($($arg:ident: $kind:ident),*);
+    )*) => {
+        $(pub const $const: u64 = $nr;)*
+
+        /// Every system call, ordered by number.
+        pub const SYSCALLS: &[Syscall] = &[$(Syscall {
+            nr: $nr,
+            name: stringify!($name),
+            args: &[$(Arg { name: stringify!($arg), kind: ArgKind::$kind }),*],
+            doc: concat!($($doc, "\n"),*),
+        }),*];
+    };
+}
+
+syscalls! {
+    /// Ends the calling task. Not allowed for the boot task.
+    0 SYS_EXIT exit(status: Int);
+    /// Writes up to `len` bytes of `buf` to `fd`, returns how many were written.
+    1 SYS_WRITE write(fd: Fd, buf: Ptr, len: Size);
+    /// Returns the id of the calling task.
+    2 SYS_GETPID getpid();
+    /// Lets other tasks run.
+    3 SYS_YIELD yield();
+    /// Blocks for `millis` milliseconds.
+    4 SYS_SLEEP sleep(millis: Int);
+    /// Returns the milliseconds since boot.
+    5 SYS_UPTIME uptime();
+    /// Sends signal `signal` to task `pid`.
+    6 SYS_KILL kill(pid: Int, signal: Int);
+    /// Maps `len` bytes of `fd` from `offset`, or anonymous memory with `MAP_ANONYMOUS`, and
+    /// returns the address. The address hint is ignored.
+    7 SYS_MMAP mmap(addr: Ptr, len: Size, prot: Int, flags: Int, fd: Fd, offset: Int);
+    /// Unmaps `addr..addr + len`.
+    8 SYS_MUNMAP munmap(addr: MutPtr, len: Size);
+    /// Changes the protection of `addr..addr + len` to `prot`.
+    9 SYS_MPROTECT mprotect(addr: MutPtr, len: Size, prot: Int);
+    /// `FUTEX_WAIT` blocks while the word at `addr` is `val`, for at most `timeout`
+    /// milliseconds unless it is 0. `FUTEX_WAKE` wakes up to `val` waiters and returns how many
+    /// were woken.
+    10 SYS_FUTEX futex(addr: MutPtr, op: Int, val: Int, timeout: Int);
+    /// Reads up to `len` bytes from `fd` into `buf`, returns how many were read.
+    11 SYS_READ read(fd: Fd, buf: MutPtr, len: Size);
+    /// Stores the read and the write end of a new pipe in `fds`, two 32 bit integers.
+    12 SYS_PIPE pipe(fds: MutPtr);
+    /// Closes `fd`.
+    13 SYS_CLOSE close(fd: Fd);
+    /// Opens `path` with the `O_*` flags and returns the file descriptor. The mode is ignored.
+    14 SYS_OPEN open(path: Str, flags: Int, mode: Int);
+    /// Makes `new` refer to the file of `old` and returns `new`.
+    15 SYS_DUP2 dup2(old: Fd, new: Fd);
+    /// Moves the offset of `fd` relative to `whence` and returns the new offset. `SEEK_DATA`
+    /// and `SEEK_HOLE` move it to the first data or hole from `offset` on.
+    16 SYS_LSEEK lseek(fd: Fd, offset: Signed, whence: Int);
+    /// Checks whether the caller may access `path` as `mode` asks, `F_OK` or any of `R_OK`,
+    /// `W_OK` and `X_OK`, without opening it.
+    17 SYS_ACCESS access(path: Str, mode: Int);
+}
+
+/// Defines the constants system calls take as arguments and lists them all in `CONSTANTS`.
+macro_rules! constants {
+    ($($name:ident = $value:literal;)*) => {
+        $(pub const $name: u64 = $value;)*
+
+        /// Every constant with its name.
+        pub const CONSTANTS: &[(&str, u64)] = &[$((stringify!($name), $value)),*];
+    };
+}
+
+// the values Linux uses
+constants! {
+    O_RDONLY = 0;
+    O_WRONLY = 1;
+    O_RDWR = 2;
+    O_CREAT = 0o100;
+    O_EXCL = 0o200;
+    O_TRUNC = 0o1000;
+    O_APPEND = 0o2000;
+
+    SEEK_SET = 0;
+    SEEK_CUR = 1;
+    SEEK_END = 2;
+    SEEK_DATA = 3;
+    SEEK_HOLE = 4;
+
+    PROT_NONE = 0;
+    PROT_READ = 1;
+    PROT_WRITE = 2;
+    PROT_EXEC = 4;
+
+    MAP_SHARED = 0x01;
+    MAP_PRIVATE = 0x02;
+    MAP_ANONYMOUS = 0x20;
+
+    FUTEX_WAIT = 0;
+    FUTEX_WAKE = 1;
+
+    F_OK = 0;
+    X_OK = 1;
+    W_OK = 2;
+    R_OK = 4;
+}
+
+/// Returns system call `nr`.
+pub fn find(nr: u64) -> Option<&'static Syscall> {
+    SYSCALLS.get(nr as usize).filter(|syscall| syscall.nr == nr)
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_table() {
+    for (i, syscall) in SYSCALLS.iter().enumerate() {
+        assert_eq!(syscall.nr, i as u64, "{} is out of order", syscall.name);
+        assert!(syscall.args.len() <= 6, "{} takes too many", syscall.name);
+        assert!(!syscall.doc.is_empty());
+    }
+    assert_eq!(find(SYS_LSEEK).map(|syscall| syscall.name), Some("lseek"));
+    assert_eq!(find(SYS_MMAP).map(|syscall| syscall.args.len()), Some(6));
+    assert!(find(SYSCALLS.len() as u64).is_none());
+}
@@ -0,0 +1,138 @@
+//! System configuration file.
+//!
+//! `/etc/system.conf` is read from the root file system at the end of boot. Every line is a
+//! `key=value` pair, `#` starts a comment. The known keys are:
+//!
+//! - `keymap`: keyboard layout, `us` or one in `/usr/share/keymaps`, see `keymap`
+//! - `font`: console font, a file in `/usr/share/consolefonts` or a path, see `font`
+//! - `theme`: console theme, unless one was given on the kernel command line
+//! - `hostname`: shown in the prompt and in `/proc/hostname`
+//! - `autostart`: script run in its own task once booted, one command per line
+//! - `ntp`: address of an NTP server the clock is kept in sync with
+use alloc::{string::String, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    bootargs, cmdline,
+    error::{KError, KResult},
+    font, keyboard,
+    klog::Level,
+    klogln_at, net, task, theme, vfs,
+};
+
+pub const PATH: &str = "/etc/system.conf";
+
+static HOSTNAME: Mutex<String> = Mutex::new(String::new());
+
+/// Returns the `key=value` pairs in `text`, with the line they are on. Lines that are neither
+/// empty, a comment nor a pair are returned as an error with their line number.
+pub fn parse(text: &str) -> Result<Vec<(usize, &str, &str)>, usize> {
+    let mut pairs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = match line.split_once('#') {
+            Some((line, _)) => line,
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or(i + 1)?;
+        pairs.push((i + 1, key.trim(), value.trim()));
+    }
+    Ok(pairs)
+}
+
+/// Reads and applies the configuration file. A missing file isn't an error, invalid entries
+/// are logged and skipped.
+pub fn load() -> KResult<()> {
+    let data = match vfs::read(PATH) {
+        Ok(data) => data,
+        Err(e) => {
+            let e = KError::from(e);
+            return if e == KError::NotFound { Ok(()) } else { Err(e) };
+        }
+    };
+    let text = String::from_utf8_lossy(&data);
+    let pairs = parse(&text).map_err(|line| {
+        klogln_at!(Level::Warn, "{}:{}: expected key=value", PATH, line);
+        KError::InvalidArgument
+    })?;
+    for (line, key, value) in pairs {
+        if let Err(e) = apply(key, value) {
+            klogln_at!(Level::Warn, "{}:{}: {}: {}", PATH, line, key, e);
+        }
+    }
+    Ok(())
+}
+
+fn apply(key: &str, value: &str) -> KResult<()> {
+    match key {
+        "keymap" => keyboard::set_layout(value),
+        "font" => font::load_file(&font::path(value)),
+        "theme" if bootargs::get("theme").is_some() => Ok(()),
+        "theme" => {
+            theme::set(theme::find(value).ok_or(KError::InvalidArgument)?);
+            Ok(())
+        }
+        "hostname" => {
+            set_hostname(value);
+            Ok(())
+        }
+        "ntp" => {
+            net::sntp::start(value.parse()?);
+            Ok(())
+        }
+        "autostart" => {
+            let path = String::from(value);
+            task::spawn("autostart", move || {
+                if let Err(e) = run_script(&path) {
+                    klogln_at!(Level::Warn, "autostart: {}: {}", path, e);
+                }
+            });
+            Ok(())
+        }
+        _ => Err(KError::InvalidArgument),
+    }
+}
+
+/// Runs every line of the script at `path` as a command. Empty lines and lines starting with
+/// `#` are skipped.
+pub fn run_script(path: &str) -> KResult<()> {
+    let data = vfs::read(path)?;
+    for line in String::from_utf8_lossy(&data).lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        cmdline::run_line(line);
+    }
+    Ok(())
+}
+
+pub fn hostname() -> String {
+    without_interrupts(|| HOSTNAME.lock().clone())
+}
+
+pub fn set_hostname(name: &str) {
+    without_interrupts(|| {
+        let mut hostname = HOSTNAME.lock();
+        hostname.clear();
+        hostname.push_str(name);
+    });
+}
+
+/// Contents of `/proc/hostname`.
+pub fn hostname_file() -> String {
+    let mut name = hostname();
+    name.push('\n');
+    name
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_parse() {
+    let pairs = parse("# comment\nkeymap = uk\n\nhostname=sky # trailing\n").unwrap();
+    assert_eq!(pairs, [(2, "keymap", "uk"), (4, "hostname", "sky")]);
+    assert_eq!(parse("keymap=us\ngarbage\n"), Err(2));
+}
@@ -0,0 +1,803 @@
+//! Preemptive multilevel feedback queue scheduler for kernel tasks.
+//!
+//! Every task owns a heap-allocated stack. Switching happens either when a task gives up the
+//! CPU (`yield_now`, `block_current`, `exit`) or when its time slice runs out on a timer tick.
+//! The context that booted the kernel is registered as task 0 and doubles as the idle task.
+//!
+//! Tasks have one of `LEVELS` priority levels, level 0 being the highest. The highest level
+//! with a ready task runs round-robin and preempts lower levels as soon as one of its tasks
+//! becomes ready. Slices get twice as long with every level down. A task that uses up its
+//! slice drops a level, one that blocks before keeps its level, so tasks that mostly wait stay
+//! on top of ones that compute. Tasks woken by console input jump to level 0 to keep typing
+//! responsive, and every `BOOST_PERIOD` all tasks go back to level 0 so nothing starves.
+use alloc::{
+    boxed::Box, collections::BTreeMap, collections::VecDeque, format, string::String, vec, vec::Vec,
+};
+use core::arch::global_asm;
+use core::fmt::Write;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::{
+    config, exec, fd, idle, keyboard, mmap, preempt,
+    signal::{Action, Handler, Signal, SignalSet},
+    sync::WaitQueue,
+    time,
+};
+
+/// Size of the stack given to every spawned task.
+pub const STACK_SIZE: usize = 4096 * 4;
+
+/// Number of priority levels.
+pub const LEVELS: usize = 4;
+/// Timer ticks between moving every task back to the highest level.
+pub const BOOST_PERIOD: u64 = time::TICK_HZ;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(pub u64);
+
+/// The id of the context that booted the kernel.
+pub const BOOT_TASK: TaskId = TaskId(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    /// waiting on a wait queue
+    Blocked,
+    /// waiting for a timer, see `sleep_current`
+    Sleeping,
+    /// stopped by `Signal::Stop` until it receives `Signal::Cont`
+    Stopped,
+    Dead,
+}
+
+struct Task {
+    name: String,
+    state: TaskState,
+    /// saved stack pointer while the task is switched out
+    rsp: u64,
+    /// `None` for the boot task, which runs on the bootloader's stack
+    stack: Option<Box<[u8]>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// log the system calls made by this task
+    traced: bool,
+    /// preemption and interrupt nesting, saved while the task is switched out
+    preempt: preempt::Counts,
+    /// priority level, 0 is the highest
+    level: usize,
+    /// task that spawned this one, the boot task for itself
+    parent: TaskId,
+    /// `time::ticks` when the task was created
+    started: u64,
+    /// timer ticks spent running, not counting halts while nothing could run
+    ticks: u64,
+    /// the part of `ticks` spent in user mode
+    user_ticks: u64,
+    /// signals delivered at the task's next safe point
+    pending: SignalSet,
+    /// installed signal handlers, indexed by signal number
+    handlers: [Option<Handler>; 32],
+}
+
+struct Scheduler {
+    tasks: BTreeMap<TaskId, Box<Task>>,
+    /// ready tasks of every level
+    ready: [VecDeque<TaskId>; LEVELS],
+    current: TaskId,
+    next_id: u64,
+    slice: u64,
+    /// context switches since boot
+    switches: u64,
+    /// `time::ticks` of the next priority boost
+    next_boost: u64,
+}
+
+const NO_TASKS: VecDeque<TaskId> = VecDeque::new();
+
+/// Timer ticks a task of `level` runs before it is preempted.
+fn slice_of(level: usize) -> u64 {
+    config::get().quantum_ticks << level
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            ready: [NO_TASKS; LEVELS],
+            current: BOOT_TASK,
+            next_id: 1,
+            slice: config::QUANTUM_TICKS,
+            switches: 0,
+            next_boost: BOOST_PERIOD,
+        }
+    }
+
+    fn is_initialized(&self) -> bool {
+        !self.tasks.is_empty()
+    }
+
+    /// Frees dead tasks, except the current one whose stack is still in use.
+    fn reap(&mut self) {
+        let current = self.current;
+        self.tasks
+            .retain(|id, task| task.state != TaskState::Dead || *id == current);
+    }
+
+    /// Queues a task that became ready behind the others of its level.
+    fn make_ready(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.state = TaskState::Ready;
+            self.ready[task.level].push_back(id);
+        }
+    }
+
+    fn is_ready(&self, id: TaskId) -> bool {
+        id != self.current
+            && matches!(self.tasks.get(&id), Some(task) if task.state == TaskState::Ready)
+    }
+
+    /// Returns the next ready task that isn't the current one, from the highest level.
+    fn pick_next(&mut self) -> Option<TaskId> {
+        for level in 0..LEVELS {
+            while let Some(id) = self.ready[level].pop_front() {
+                if self.is_ready(id) {
+                    return Some(id);
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns whether a task above `level` is ready, dropping queue entries of tasks that
+    /// aren't anymore.
+    fn ready_above(&mut self, level: usize) -> bool {
+        for level in 0..level {
+            while let Some(&id) = self.ready[level].front() {
+                if self.is_ready(id) {
+                    return true;
+                }
+                self.ready[level].pop_front();
+            }
+        }
+        false
+    }
+
+    /// Moves every task to the highest level, keeping the order of the ready ones.
+    fn boost(&mut self) {
+        for task in self.tasks.values_mut() {
+            task.level = 0;
+        }
+        for level in 1..LEVELS {
+            let queue = core::mem::take(&mut self.ready[level]);
+            self.ready[0].extend(queue);
+        }
+    }
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+/// Woken whenever a task exits.
+static EXITED: WaitQueue = WaitQueue::new();
+
+global_asm!(
+    r#"
+.global skyos_switch_stack
+skyos_switch_stack:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdi], rsp
+    mov rsp, rsi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+extern "C" {
+    /// Saves the callee-saved registers on the current stack, stores the stack pointer in
+    /// `old_rsp` and resumes the context saved on `new_rsp`.
+    fn skyos_switch_stack(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// Registers the running context as the boot task. Requires the heap.
+pub fn init() {
+    without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        if sched.is_initialized() {
+            return;
+        }
+        sched.tasks.insert(
+            BOOT_TASK,
+            Box::new(Task {
+                name: String::from("kernel"),
+                state: TaskState::Running,
+                rsp: 0,
+                stack: None,
+                entry: None,
+                traced: false,
+                preempt: preempt::Counts::default(),
+                level: 0,
+                parent: BOOT_TASK,
+                started: 0,
+                ticks: 0,
+                user_ticks: 0,
+                pending: SignalSet::empty(),
+                handlers: [None; 32],
+            }),
+        );
+        sched.current = BOOT_TASK;
+    });
+}
+
+/// Creates a new task running `f` and queues it for execution. The task inherits the open files
+/// of the current task.
+pub fn spawn<F>(name: &str, f: F) -> TaskId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let stack_top = (stack.as_mut_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+
+    // Initial frame popped by `skyos_switch_stack`:
+    // rflags, r15, r14, r13, r12, rbx, rbp, return address.
+    // The return address sits 16 bytes below the top, so that the trampoline starts with
+    // the stack aligned as if it had been called.
+    let rsp = stack_top - 16 - 7 * 8;
+    unsafe {
+        let frame = rsp as *mut u64;
+        // interrupts enabled
+        frame.write(0x202);
+        for i in 1..7 {
+            frame.add(i).write(0);
+        }
+        frame.add(7).write(task_trampoline as usize as u64);
+    }
+
+    without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let parent = sched.current;
+        let id = TaskId(sched.next_id);
+        sched.next_id += 1;
+        sched.tasks.insert(
+            id,
+            Box::new(Task {
+                name: String::from(name),
+                state: TaskState::Ready,
+                rsp,
+                stack: Some(stack),
+                entry: Some(Box::new(f)),
+                traced: false,
+                preempt: preempt::Counts::default(),
+                level: 0,
+                parent,
+                started: time::ticks(),
+                ticks: 0,
+                user_ticks: 0,
+                pending: SignalSet::empty(),
+                handlers: [None; 32],
+            }),
+        );
+        sched.ready[0].push_back(id);
+        drop(sched);
+        // before the task can run, interrupts are still off
+        fd::inherit(parent, id);
+        id
+    })
+}
+
+extern "C" fn task_trampoline() -> ! {
+    let entry = without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let current = sched.current;
+        sched.tasks.get_mut(&current).and_then(|task| task.entry.take())
+    });
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Returns the id of the running task.
+pub fn current_id() -> TaskId {
+    without_interrupts(|| SCHEDULER.lock().current)
+}
+
+/// Returns the name of a task.
+pub fn name_of(id: TaskId) -> Option<String> {
+    without_interrupts(|| SCHEDULER.lock().tasks.get(&id).map(|task| task.name.clone()))
+}
+
+/// Returns the state of a task, or `None` if it doesn't exist (anymore).
+pub fn state_of(id: TaskId) -> Option<TaskState> {
+    without_interrupts(|| SCHEDULER.lock().tasks.get(&id).map(|task| task.state))
+}
+
+/// Snapshot of a task for listings.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: String,
+    pub state: TaskState,
+    /// priority level, 0 is the highest
+    pub level: usize,
+    pub parent: TaskId,
+    /// `time::ticks` when the task was created
+    pub started: u64,
+    /// timer ticks spent running
+    pub ticks: u64,
+    /// the part of `ticks` spent in user mode
+    pub user_ticks: u64,
+    /// size of the task's own stack, 0 for the boot task
+    pub stack_size: usize,
+    /// signals not delivered yet
+    pub pending: SignalSet,
+}
+
+/// Returns all tasks, ordered by id.
+pub fn list() -> Vec<TaskInfo> {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .tasks
+            .iter()
+            .map(|(id, task)| TaskInfo {
+                id: *id,
+                name: task.name.clone(),
+                state: task.state,
+                level: task.level,
+                parent: task.parent,
+                started: task.started,
+                ticks: task.ticks,
+                user_ticks: task.user_ticks,
+                stack_size: task.stack.as_ref().map_or(0, |stack| stack.len()),
+                pending: task.pending,
+            })
+            .collect()
+    })
+}
+
+/// Generates `/proc/stat`, cpu times are in timer ticks rather than Linux's hundredths of a
+/// second. There is one cpu and no nice, I/O wait or interrupt time.
+pub fn stat_file() -> String {
+    let cpu = idle::stats();
+    let (switches, spawned) = without_interrupts(|| {
+        let sched = SCHEDULER.lock();
+        (sched.switches, sched.next_id - 1)
+    });
+    let tasks = list();
+    let count = |f: fn(TaskState) -> bool| tasks.iter().filter(|task| f(task.state)).count();
+    let times = format!(
+        "{} 0 {} {} 0 0 0",
+        cpu.user_ticks, cpu.kernel_ticks, cpu.idle_ticks
+    );
+    let mut out = String::new();
+    let _ = writeln!(out, "cpu  {}", times);
+    let _ = writeln!(out, "cpu0 {}", times);
+    let _ = writeln!(out, "ctxt {}", switches);
+    let _ = writeln!(out, "processes {}", spawned);
+    let running = count(|state| matches!(state, TaskState::Running | TaskState::Ready));
+    let _ = writeln!(out, "procs_running {}", running);
+    let blocked = count(|state| state == TaskState::Blocked);
+    let _ = writeln!(out, "procs_blocked {}", blocked);
+    out
+}
+
+/// Generates `/proc/<id>/stat` of a task, with Linux's fields up to the start time. Times are
+/// in timer ticks, the priority is the level, fields that don't apply are 0.
+pub fn stat_file_of(id: TaskId) -> Option<String> {
+    let task = list().into_iter().find(|task| task.id == id)?;
+    let state = match task.state {
+        TaskState::Running | TaskState::Ready => 'R',
+        TaskState::Blocked | TaskState::Sleeping => 'S',
+        TaskState::Stopped => 'T',
+        TaskState::Dead => 'Z',
+    };
+    Some(format!(
+        "{} ({}) {} {} 0 0 0 0 0 0 0 0 0 {} {} 0 0 {} 0 1 0 {}\n",
+        id.0,
+        task.name,
+        state,
+        task.parent.0,
+        task.user_ticks,
+        task.ticks - task.user_ticks,
+        task.level,
+        task.started
+    ))
+}
+
+/// Returns whether the system calls of a task are being logged.
+pub fn is_traced(id: TaskId) -> bool {
+    without_interrupts(|| matches!(SCHEDULER.lock().tasks.get(&id), Some(task) if task.traced))
+}
+
+/// Turns system call tracing for a task on or off.
+pub fn set_traced(id: TaskId, traced: bool) {
+    without_interrupts(|| {
+        if let Some(task) = SCHEDULER.lock().tasks.get_mut(&id) {
+            task.traced = traced;
+        }
+    });
+}
+
+/// Returns whether a task has exited, or never existed.
+pub fn has_exited(id: TaskId) -> bool {
+    matches!(state_of(id), None | Some(TaskState::Dead))
+}
+
+/// Blocks until `cond` returns true, checking it again every time a task exits.
+pub fn wait_for_exit<F>(cond: F)
+where
+    F: FnMut() -> bool,
+{
+    EXITED.wait_until(cond);
+}
+
+/// Returns whether any task is waiting for the cpu.
+pub fn has_ready_tasks() -> bool {
+    without_interrupts(|| {
+        let sched = SCHEDULER.lock();
+        sched.ready.iter().flatten().any(|id| sched.is_ready(*id))
+    })
+}
+
+/// Switches to the next ready task, if there is one.
+///
+/// Must be called with interrupts disabled. If the current task is blocked and nothing else
+/// can run, the cpu is halted until an interrupt makes a task runnable again.
+fn schedule() {
+    loop {
+        let switch = {
+            let mut guard = SCHEDULER.lock();
+            let sched = &mut *guard;
+            if !sched.is_initialized() {
+                return;
+            }
+            sched.reap();
+
+            let prev = sched.current;
+            match sched.pick_next() {
+                Some(next) => {
+                    let prev_task = sched.tasks.get(&prev).expect("current task vanished");
+                    if prev_task.state == TaskState::Running {
+                        sched.make_ready(prev);
+                    }
+                    // Dead tasks stay around until the next switch reaps them.
+                    let prev_task = sched.tasks.get_mut(&prev).unwrap();
+                    prev_task.preempt = preempt::save();
+                    let old_rsp = &mut prev_task.rsp as *mut u64;
+                    let next_task = sched.tasks.get_mut(&next).unwrap();
+                    next_task.state = TaskState::Running;
+                    preempt::restore(next_task.preempt);
+                    let (new_rsp, level) = (next_task.rsp, next_task.level);
+                    sched.current = next;
+                    sched.switches += 1;
+                    sched.slice = slice_of(level);
+                    Some((old_rsp, new_rsp))
+                }
+                None => match sched.tasks.get(&prev).map(|task| (task.state, task.level)) {
+                    Some((TaskState::Running, level)) => {
+                        sched.slice = slice_of(level);
+                        return;
+                    }
+                    _ => None,
+                },
+            }
+        };
+
+        match switch {
+            Some((old_rsp, new_rsp)) => {
+                unsafe { skyos_switch_stack(old_rsp, new_rsp) };
+                return;
+            }
+            // Nothing can run right now, wait for an interrupt to wake someone up.
+            None => idle::halt(),
+        }
+    }
+}
+
+/// Gives up the rest of the current time slice.
+pub fn yield_now() {
+    preempt::might_sleep();
+    without_interrupts(schedule);
+    handle_signals();
+}
+
+/// Marks the current task as blocked and switches away until `wake` is called for it.
+#[track_caller]
+pub fn block_current() {
+    block_as(TaskState::Blocked);
+}
+
+/// Like `block_current`, for tasks waiting for a timer rather than another task or a device.
+#[track_caller]
+pub fn sleep_current() {
+    block_as(TaskState::Sleeping);
+}
+
+#[track_caller]
+fn block_as(state: TaskState) {
+    preempt::might_sleep();
+    without_interrupts(|| {
+        {
+            let mut sched = SCHEDULER.lock();
+            if !sched.is_initialized() {
+                drop(sched);
+                // Without a scheduler there's nobody to switch to; wait for an interrupt.
+                idle::halt();
+                return;
+            }
+            let current = sched.current;
+            if let Some(task) = sched.tasks.get_mut(&current) {
+                task.state = state;
+            }
+        }
+        schedule();
+    });
+    handle_signals();
+}
+
+/// Makes a blocked or sleeping task runnable again. Safe to call from interrupt context.
+pub fn wake(id: TaskId) {
+    wake_at(id, None);
+}
+
+/// Like `wake`, also moving the task to the highest level. For waking tasks that interact
+/// with the user.
+pub fn wake_boosted(id: TaskId) {
+    wake_at(id, Some(0));
+}
+
+fn wake_at(id: TaskId, level: Option<usize>) {
+    without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let sched = &mut *guard;
+        let current = sched.current;
+        let Some(task) = sched.tasks.get_mut(&id) else {
+            return;
+        };
+        if !matches!(task.state, TaskState::Blocked | TaskState::Sleeping) {
+            return;
+        }
+        if let Some(level) = level {
+            task.level = level;
+        }
+        if id == current {
+            // still on the cpu, halted in `schedule`
+            task.state = TaskState::Running;
+        } else {
+            sched.make_ready(id);
+        }
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    NoSuchTask,
+    /// the boot task doubles as the idle task and can't go away
+    BootTask,
+}
+
+/// Queues a signal for a task. Safe to call from interrupt context.
+///
+/// The signal is delivered the next time the task passes a safe point: yielding, blocking or
+/// returning from a system call. A blocked task is woken up to get there, a stopped one is
+/// resumed by `Cont` and `Kill`. Tasks that never pass a safe point have to be stopped with
+/// `force_kill`.
+pub fn send_signal(id: TaskId, signal: Signal) -> Result<(), SignalError> {
+    if id == BOOT_TASK {
+        return Err(SignalError::BootTask);
+    }
+    without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let sched = &mut *guard;
+        let current = sched.current;
+        let task = sched.tasks.get_mut(&id).ok_or(SignalError::NoSuchTask)?;
+        match (signal, task.state) {
+            (_, TaskState::Dead) => return Err(SignalError::NoSuchTask),
+            (Signal::Stop, _) => task.pending.remove(Signal::Cont),
+            (Signal::Cont, _) => task.pending.remove(Signal::Stop),
+            _ => {}
+        }
+        task.pending.insert(signal);
+        let wake = match task.state {
+            TaskState::Blocked | TaskState::Sleeping => signal != Signal::Cont,
+            TaskState::Stopped => matches!(signal, Signal::Cont | Signal::Kill),
+            _ => false,
+        };
+        if wake {
+            if id == current {
+                // still on the cpu, halted in `schedule`
+                task.state = TaskState::Running;
+            } else {
+                sched.make_ready(id);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Terminates a task at its next safe point, see `send_signal`.
+pub fn kill(id: TaskId) -> Result<(), SignalError> {
+    send_signal(id, Signal::Kill)?;
+    if id == current_id() {
+        exit();
+    }
+    Ok(())
+}
+
+/// Terminates a task right away, wherever it is.
+///
+/// Nothing on the task's stack is dropped and locks it holds stay locked, so this is only a
+/// last resort for tasks `kill` can't reach.
+pub fn force_kill(id: TaskId) -> Result<(), SignalError> {
+    if id == BOOT_TASK {
+        return Err(SignalError::BootTask);
+    }
+    let is_current = without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let current = sched.current;
+        let task = sched.tasks.get_mut(&id).ok_or(SignalError::NoSuchTask)?;
+        task.state = TaskState::Dead;
+        task.entry = None;
+        Ok(current == id)
+    })?;
+    if is_current {
+        exit();
+    }
+    release_task_resources(id);
+    EXITED.wake_all();
+    Ok(())
+}
+
+/// Installs a handler for a signal sent to the current task, `None` restores the default
+/// action. Handlers run at the safe point the signal is delivered at, often with interrupts
+/// disabled, so they should only do a little work.
+///
+/// Panics for `Kill` and `Stop`, which can't be caught.
+pub fn set_signal_handler(signal: Signal, handler: Option<Handler>) {
+    assert!(signal.is_catchable(), "{} can't be caught", signal);
+    without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let current = sched.current;
+        if let Some(task) = sched.tasks.get_mut(&current) {
+            task.handlers[signal as usize] = handler;
+        }
+    });
+}
+
+/// Returns whether a signal is waiting to be delivered to the current task.
+///
+/// Lets long-running loops that never pass a safe point notice Ctrl+C.
+pub fn has_pending_signals() -> bool {
+    without_interrupts(|| {
+        let sched = SCHEDULER.lock();
+        matches!(sched.tasks.get(&sched.current), Some(task) if !task.pending.is_empty())
+    })
+}
+
+/// Delivers the pending signals of the current task. Must only be called where the task
+/// holds no locks.
+pub fn handle_signals() {
+    loop {
+        let next = without_interrupts(|| {
+            let mut sched = SCHEDULER.lock();
+            let current = sched.current;
+            let task = sched.tasks.get_mut(&current)?;
+            let signal = task.pending.take_next()?;
+            Some((signal, task.handlers[signal as usize]))
+        });
+        let Some((signal, handler)) = next else {
+            return;
+        };
+        match (handler, signal.default_action()) {
+            (Some(handler), _) => handler(signal),
+            (None, Action::Terminate) => exit(),
+            (None, Action::Stop) => stop_current(),
+            (None, Action::Continue | Action::Ignore) => {}
+        }
+    }
+}
+
+/// Switches away from the current task until it receives `Cont` or `Kill`.
+fn stop_current() {
+    without_interrupts(|| {
+        {
+            let mut sched = SCHEDULER.lock();
+            let current = sched.current;
+            match sched.tasks.get_mut(&current) {
+                Some(task) if !task.pending.contains(Signal::Kill) => {
+                    task.state = TaskState::Stopped;
+                }
+                _ => return,
+            }
+        }
+        schedule();
+    });
+}
+
+/// Closes the files of a task that is exiting or was killed, unmaps its memory mappings, frees
+/// the programs it ran and drops its keyboard grabs.
+fn release_task_resources(id: TaskId) {
+    fd::release_task(id);
+    mmap::release_task(id);
+    exec::release_task(id);
+    keyboard::release_task(id);
+}
+
+/// Terminates the current task, closing its files, unmapping its memory mappings and freeing
+/// the programs it ran.
+pub fn exit() -> ! {
+    release_task_resources(current_id());
+    without_interrupts(|| {
+        {
+            let mut sched = SCHEDULER.lock();
+            let current = sched.current;
+            assert_ne!(current, BOOT_TASK, "the boot task can't exit");
+            if let Some(task) = sched.tasks.get_mut(&current) {
+                task.state = TaskState::Dead;
+                task.entry = None;
+            }
+        }
+        // still with interrupts disabled, a dead task that gets preempted never runs again
+        EXITED.wake_all();
+    });
+    loop {
+        without_interrupts(schedule);
+    }
+}
+
+/// Called on every timer tick, charges the tick to the current task and preempts it once its
+/// slice is used up or a task above it became ready. `user` tells whether the tick interrupted
+/// user mode code.
+///
+/// Must be called from the timer interrupt, after the end of interrupt was signaled.
+pub fn tick(user: bool) {
+    let preempt = {
+        let mut guard = SCHEDULER.lock();
+        let sched = &mut *guard;
+        if !sched.is_initialized() {
+            return;
+        }
+        if time::ticks() >= sched.next_boost {
+            sched.boost();
+            sched.next_boost = time::ticks() + BOOST_PERIOD;
+        }
+        let current = sched.current;
+        let Some(task) = sched.tasks.get_mut(&current) else {
+            return;
+        };
+        // a halted cpu is idle, not running whoever is current
+        if !idle::is_idle() {
+            task.ticks += 1;
+            if user {
+                task.user_ticks += 1;
+            }
+        }
+        sched.slice = sched.slice.saturating_sub(1);
+        if sched.slice == 0 && task.state == TaskState::Running {
+            task.level = (task.level + 1).min(LEVELS - 1);
+        }
+        let level = task.level;
+        if sched.slice == 0 && !sched.ready_above(level + 1) {
+            // nothing else at its level or above, keep going with a new slice
+            sched.slice = slice_of(level);
+        }
+        sched.slice == 0 || sched.ready_above(level)
+    };
+    if preempt && !preempt::is_enabled() {
+        preempt::request_resched();
+    } else if preempt {
+        schedule();
+    }
+}
@@ -0,0 +1,210 @@
+//! Task manager, opened with Ctrl+Alt+Del.
+//!
+//! Lists all tasks with their cpu usage and lets the user kill one. It runs as its own task
+//! that sleeps until the hotkey is pressed, so it still works while the shell is busy.
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    keyboard::{self, Modifiers},
+    sync::Event,
+    signal::Signal,
+    task::{self, SignalError, TaskId, TaskInfo, TaskState},
+    time, timer,
+    vga_buffer::{BoxStyle, Color, ColorCode, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER},
+};
+
+/// How often the cpu usage is recalculated.
+const REFRESH: Duration = Duration::from_millis(1000);
+/// How often the keyboard is polled in between.
+const POLL: Duration = Duration::from_millis(50);
+const FIRST_TASK_ROW: usize = 3;
+const LAST_TASK_ROW: usize = BUFFER_HEIGHT - 3;
+
+static OPEN: Event = Event::new();
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Starts the task manager task. Requires the scheduler.
+pub fn init() {
+    // only fails if something else took the combination
+    let _ = keyboard::register_hotkey(Modifiers::CTRL | Modifiers::ALT, KeyCode::Delete, open);
+    task::spawn("taskmgr", || loop {
+        OPEN.wait_and_reset();
+        ACTIVE.store(true, Ordering::SeqCst);
+        show();
+        ACTIVE.store(false, Ordering::SeqCst);
+    });
+}
+
+/// Opens the task manager. Safe to call from interrupt context.
+pub fn open() {
+    if !ACTIVE.load(Ordering::SeqCst) {
+        OPEN.signal();
+    }
+}
+
+/// Cpu usage in percent over the last refresh interval.
+struct Usage {
+    last_ticks: BTreeMap<TaskId, u64>,
+    last_time: u64,
+    percent: BTreeMap<TaskId, u64>,
+}
+
+impl Usage {
+    fn new(tasks: &[TaskInfo]) -> Self {
+        Self {
+            last_ticks: tasks.iter().map(|task| (task.id, task.ticks)).collect(),
+            last_time: time::ticks(),
+            percent: BTreeMap::new(),
+        }
+    }
+
+    fn update(&mut self, tasks: &[TaskInfo]) {
+        let now = time::ticks();
+        let elapsed = (now - self.last_time).max(1);
+        self.percent = tasks
+            .iter()
+            .map(|task| {
+                let last = self.last_ticks.get(&task.id).copied().unwrap_or(0);
+                (task.id, (task.ticks - last) * 100 / elapsed)
+            })
+            .collect();
+        self.last_ticks = tasks.iter().map(|task| (task.id, task.ticks)).collect();
+        self.last_time = now;
+    }
+}
+
+fn state_name(task: &TaskInfo) -> &'static str {
+    if task.pending.contains(Signal::Kill) {
+        return "killing";
+    }
+    match task.state {
+        TaskState::Ready => "ready",
+        TaskState::Running => "running",
+        TaskState::Blocked => "blocked",
+        TaskState::Sleeping => "sleeping",
+        TaskState::Stopped => "stopped",
+        TaskState::Dead => "dead",
+    }
+}
+
+fn pad(text: &mut String, width: usize) {
+    while text.chars().count() < width {
+        text.push(' ');
+    }
+}
+
+fn draw(tasks: &[TaskInfo], usage: &Usage, selected: usize, message: &str) {
+    let normal = ColorCode::new(Color::White, Color::Blue);
+    let highlight = ColorCode::new(Color::Blue, Color::LightGray);
+    let inner = BUFFER_WIDTH - 2;
+    let visible = LAST_TASK_ROW - FIRST_TASK_ROW + 1;
+    let first = selected.saturating_sub(visible - 1);
+
+    let mut lines: Vec<(String, ColorCode)> = Vec::with_capacity(BUFFER_HEIGHT - 2);
+    let mut line = format!(
+        " {:>4}  {:<24} {:<8} {:>3} {:>4} {:>10} {:>7}",
+        "ID", "NAME", "STATE", "PRI", "CPU", "TIME", "STACK"
+    );
+    lines.push((String::new(), normal));
+    lines.push((line.clone(), normal));
+    for (i, task) in tasks.iter().enumerate().skip(first).take(visible) {
+        let time = time::ticks_to_duration(task.ticks);
+        line = format!(
+            " {:>4}  {:<24.24} {:<8} {:>3} {:>3}% {:>8}.{} {:>6}K",
+            task.id.0,
+            task.name,
+            state_name(task),
+            task.level,
+            usage.percent.get(&task.id).copied().unwrap_or(0),
+            time.as_secs(),
+            time.subsec_millis() / 100,
+            task.stack_size / 1024,
+        );
+        lines.push((line, if i == selected { highlight } else { normal }));
+    }
+    while lines.len() < BUFFER_HEIGHT - 4 {
+        lines.push((String::new(), normal));
+    }
+    lines.push((format!(" {}", message), normal));
+    lines.push((
+        String::from(" Up/Down select   k kill   f force kill   q close"),
+        normal,
+    ));
+
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.draw_box(0, 0, BUFFER_WIDTH, BUFFER_HEIGHT, BoxStyle::Double);
+        writer.write_at(0, 2, " Task Manager ");
+        for (row, (text, color)) in lines.iter_mut().enumerate() {
+            pad(text, inner);
+            writer.write_at_colored(row + 1, 1, text, *color);
+        }
+    });
+}
+
+fn kill(task: &TaskInfo, force: bool) -> String {
+    if task.id == task::current_id() {
+        return String::from("can't kill the task manager");
+    }
+    let result = if force {
+        task::force_kill(task.id)
+    } else {
+        task::kill(task.id)
+    };
+    match result {
+        Ok(()) if force => format!("killed {} ({})", task.name, task.id.0),
+        Ok(()) => format!("asked {} ({}) to exit", task.name, task.id.0),
+        Err(SignalError::BootTask) => String::from("the kernel task can't be killed"),
+        Err(SignalError::NoSuchTask) => String::from("task already exited"),
+    }
+}
+
+/// Waits up to `REFRESH` for a key.
+fn wait_key() -> Option<DecodedKey> {
+    let deadline = time::ticks() + time::duration_to_ticks(REFRESH);
+    while time::ticks() < deadline {
+        if let Some(key) = keyboard::try_read_key() {
+            return Some(key);
+        }
+        timer::sleep(POLL);
+    }
+    None
+}
+
+fn show() {
+    keyboard::grab();
+    let snapshot = without_interrupts(|| WRITER.lock().snapshot());
+    let mut tasks = task::list();
+    let mut usage = Usage::new(&tasks);
+    let mut selected = 0;
+    let mut message = String::new();
+
+    loop {
+        selected = selected.min(tasks.len().saturating_sub(1));
+        draw(&tasks, &usage, selected, &message);
+        match wait_key() {
+            None => {
+                tasks = task::list();
+                usage.update(&tasks);
+                continue;
+            }
+            Some(DecodedKey::RawKey(KeyCode::ArrowUp)) => selected = selected.saturating_sub(1),
+            Some(DecodedKey::RawKey(KeyCode::ArrowDown)) => selected += 1,
+            Some(DecodedKey::Unicode(key @ ('k' | 'f'))) => {
+                if let Some(task) = tasks.get(selected) {
+                    message = kill(task, key == 'f');
+                }
+            }
+            Some(DecodedKey::Unicode('q' | '\x1b')) => break,
+            Some(_) => {}
+        }
+        tasks = task::list();
+    }
+
+    without_interrupts(|| WRITER.lock().restore(&snapshot));
+    keyboard::release();
+}
@@ -0,0 +1,187 @@
+//! Test registry for `cargo test`.
+//!
+//! Every test belongs to a subsystem. A plain `#[test_case]` function belongs to the module it
+//! is in below the crate (`skyos::net::arp::test_build_and_parse` to `net`), or to the test
+//! binary for the tests in `tests/` (`ext2::reads_the_image` to `ext2`). Tests that need a
+//! different subsystem or a fixture are declared with `unit_test!`:
+//!
+//! ```rust,ignore
+//! #[test_case]
+//! const MOUNTS: Test = skyos::unit_test!(mounts, "ext2", Heap, Ext2);
+//! ```
+//!
+//! The runner sets the fixtures up before the test, in the order given, and tears them down in
+//! the opposite order after it passed. The `tests=` boot argument picks the subsystems to run,
+//! separated by commas, e.g. `SKYOS_CMDLINE="tests=ext2,net" cargo test`. Without it every
+//! test runs.
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::{
+    bootargs, drivers::ramdisk::MemDisk, ext::Ext2, init, serial_print, serial_println, time,
+    Testable,
+};
+
+/// A 64 KiB ext2 file system holding `/hello.txt`, see tests/ext2.rs.
+const EXT2_IMAGE: &[u8] = include_bytes!("../tests/ext2.img");
+
+/// The file system mounted by `Fixture::Ext2`.
+static EXT2: Mutex<Option<Ext2<MemDisk>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fixture {
+    /// the heap is set up, `init_memory` ran
+    Heap,
+    /// a fresh copy of tests/ext2.img is mounted, see `ext2`
+    Ext2,
+    /// the loopback interface is up and the network tasks run
+    NetLoopback,
+    /// time is frozen and only passes through `time::step`
+    VirtualTime,
+}
+
+impl Fixture {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Heap => "heap",
+            Self::Ext2 => "ext2",
+            Self::NetLoopback => "net-loopback",
+            Self::VirtualTime => "virtual-time",
+        }
+    }
+
+    fn set_up(self) {
+        match self {
+            Self::Heap => assert!(init::done("Heap"), "no heap, init_memory didn't run"),
+            Self::Ext2 => {
+                assert!(init::done("Heap"), "mounting ext2 needs the heap");
+                let ext2 = Ext2::new(MemDisk::new(EXT2_IMAGE.to_vec()))
+                    .expect("tests/ext2.img doesn't mount");
+                *EXT2.lock() = Some(ext2);
+            }
+            Self::NetLoopback => {
+                assert!(init::done("Scheduler"), "the network needs the scheduler");
+                crate::net::init();
+            }
+            Self::VirtualTime => time::freeze(),
+        }
+    }
+
+    fn tear_down(self) {
+        match self {
+            Self::Heap | Self::NetLoopback => {}
+            Self::Ext2 => *EXT2.lock() = None,
+            Self::VirtualTime => time::thaw(),
+        }
+    }
+}
+
+/// The file system of `Fixture::Ext2`.
+pub fn ext2() -> Ext2<MemDisk> {
+    EXT2.lock()
+        .clone()
+        .expect("the test doesn't declare the ext2 fixture")
+}
+
+/// A test with its subsystem and fixtures, see `unit_test!`.
+pub struct Test {
+    pub name: &'static str,
+    pub subsystem: &'static str,
+    pub fixtures: &'static [Fixture],
+    pub run: fn(),
+}
+
+impl Testable for Test {
+    fn run(&self) {
+        (self.run)()
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn subsystem(&self) -> &'static str {
+        self.subsystem
+    }
+
+    fn fixtures(&self) -> &'static [Fixture] {
+        self.fixtures
+    }
+}
+
+/// Declares a `Test` for `#[test_case]` running the function `$test` in `$subsystem`, with the
+/// fixtures named after it.
+#[macro_export]
+macro_rules! unit_test {
+    ($test:ident, $subsystem:literal $(, $fixture:ident)* $(,)?) => {
+        $crate::testing::Test {
+            name: concat!(module_path!(), "::", stringify!($test)),
+            subsystem: $subsystem,
+            fixtures: &[$($crate::testing::Fixture::$fixture),*],
+            run: $test,
+        }
+    };
+}
+
+/// The subsystem of the test called `name`: the first module below the crate, or the crate
+/// for tests at its top level.
+pub fn subsystem_of(name: &'static str) -> &'static str {
+    let mut parts = name.split("::");
+    let krate = parts.next().unwrap_or(name);
+    let module = parts.next();
+    match (module, parts.next()) {
+        (Some(module), Some(_)) if krate == "skyos" => module,
+        _ => krate,
+    }
+}
+
+/// The subsystems picked by `tests=`, `None` for all of them.
+fn selected() -> Option<Vec<&'static str>> {
+    bootargs::get("tests").map(|groups| {
+        groups
+            .split(',')
+            .filter(|group| !group.is_empty())
+            .collect()
+    })
+}
+
+/// Runs the tests of the subsystems picked on the command line and returns how many ran.
+pub fn run(tests: &[&dyn Testable]) -> usize {
+    let selected = selected();
+    let tests: Vec<_> = tests
+        .iter()
+        .filter(|test| {
+            selected
+                .as_ref()
+                .map_or(true, |groups| groups.contains(&test.subsystem()))
+        })
+        .collect();
+    match &selected {
+        Some(groups) => {
+            serial_println!("Running {} tests in {}", tests.len(), groups.join(", "));
+        }
+        None => {
+            serial_println!("Running {} tests", tests.len());
+        }
+    }
+
+    for test in &tests {
+        serial_print!("[{}] {}...\t", test.subsystem(), test.name());
+        for fixture in test.fixtures() {
+            fixture.set_up();
+        }
+        test.run();
+        for fixture in test.fixtures().iter().rev() {
+            fixture.tear_down();
+        }
+        serial_println!("[ok]");
+    }
+    tests.len()
+}
+
+#[test_case]
+fn test_subsystem_of() {
+    assert_eq!(subsystem_of("skyos::net::arp::test_build_and_parse"), "net");
+    assert_eq!(subsystem_of("skyos::test_top_level"), "skyos");
+    assert_eq!(subsystem_of("ext2::reads_the_image"), "ext2");
+}
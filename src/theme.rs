@@ -0,0 +1,162 @@
+//! Console colors.
+//!
+//! The theme is picked with the `theme=<name>` boot argument, single colors can be overridden
+//! with `fg=`, `bg=`, `error=`, `ok=` and `prompt=`. The `theme` command changes it at runtime.
+//! It is kept in an atomic so even the early console and interrupt handlers can read it.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bootargs;
+use crate::vga_buffer::{self, Color, ColorCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub fg: Color,
+    pub bg: Color,
+    /// foreground of error messages
+    pub error: Color,
+    /// foreground of success messages
+    pub ok: Color,
+    /// foreground of the command line prompt
+    pub prompt: Color,
+}
+
+impl Theme {
+    pub const fn normal(&self) -> ColorCode {
+        ColorCode::new(self.fg, self.bg)
+    }
+
+    pub const fn error_color(&self) -> ColorCode {
+        ColorCode::new(self.error, self.bg)
+    }
+
+    pub const fn ok_color(&self) -> ColorCode {
+        ColorCode::new(self.ok, self.bg)
+    }
+
+    pub const fn prompt_color(&self) -> ColorCode {
+        ColorCode::new(self.prompt, self.bg)
+    }
+
+    const fn pack(self) -> u64 {
+        self.fg as u64
+            | (self.bg as u64) << 8
+            | (self.error as u64) << 16
+            | (self.ok as u64) << 24
+            | (self.prompt as u64) << 32
+    }
+
+    fn unpack(packed: u64) -> Self {
+        let color = |shift: u64| Color::from_u8((packed >> shift) as u8 & 0xf);
+        Self {
+            fg: color(0),
+            bg: color(8),
+            error: color(16),
+            ok: color(24),
+            prompt: color(32),
+        }
+    }
+
+    /// Sets one of the colors by its name as used by the boot arguments.
+    pub fn set(&mut self, part: &str, color: Color) -> bool {
+        match part {
+            "fg" => self.fg = color,
+            "bg" => self.bg = color,
+            "error" => self.error = color,
+            "ok" => self.ok = color,
+            "prompt" => self.prompt = color,
+            _ => return false,
+        }
+        true
+    }
+}
+
+pub const DEFAULT: Theme = Theme {
+    fg: Color::White,
+    bg: Color::Black,
+    error: Color::LightRed,
+    ok: Color::LightGreen,
+    prompt: Color::LightCyan,
+};
+
+pub const THEMES: &[(&str, Theme)] = &[
+    ("default", DEFAULT),
+    (
+        "light",
+        Theme {
+            fg: Color::Black,
+            bg: Color::LightGray,
+            error: Color::Red,
+            ok: Color::Green,
+            prompt: Color::Blue,
+        },
+    ),
+    (
+        "blue",
+        Theme {
+            fg: Color::White,
+            bg: Color::Blue,
+            error: Color::Yellow,
+            ok: Color::LightGreen,
+            prompt: Color::LightCyan,
+        },
+    ),
+    (
+        "green",
+        Theme {
+            fg: Color::LightGreen,
+            bg: Color::Black,
+            error: Color::LightRed,
+            ok: Color::White,
+            prompt: Color::Green,
+        },
+    ),
+    (
+        "mono",
+        Theme {
+            fg: Color::LightGray,
+            bg: Color::Black,
+            error: Color::White,
+            ok: Color::LightGray,
+            prompt: Color::White,
+        },
+    ),
+];
+
+static THEME: AtomicU64 = AtomicU64::new(DEFAULT.pack());
+
+/// Applies the theme given on the kernel command line.
+pub fn init() {
+    let mut theme = bootargs::get("theme").and_then(find).unwrap_or(DEFAULT);
+    for part in ["fg", "bg", "error", "ok", "prompt"] {
+        if let Some(color) = bootargs::get(part).and_then(Color::parse) {
+            theme.set(part, color);
+        }
+    }
+    THEME.store(theme.pack(), Ordering::Relaxed);
+}
+
+pub fn current() -> Theme {
+    Theme::unpack(THEME.load(Ordering::Relaxed))
+}
+
+/// Returns the theme called `name`.
+pub fn find(name: &str) -> Option<Theme> {
+    THEMES
+        .iter()
+        .find(|(theme, _)| theme.eq_ignore_ascii_case(name))
+        .map(|(_, theme)| *theme)
+}
+
+/// Switches to `theme`, recoloring what is on the screen in the old normal colors.
+pub fn set(theme: Theme) {
+    let old = current();
+    THEME.store(theme.pack(), Ordering::Relaxed);
+    vga_buffer::recolor(old.normal(), theme.normal());
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_theme_pack_roundtrip() {
+    for (_, theme) in THEMES {
+        assert_eq!(Theme::unpack(theme.pack()), *theme);
+    }
+}
@@ -0,0 +1,348 @@
+//! Temperature sensors and the emergency shutdown.
+//!
+//! Intel CPUs have a digital thermal sensor per core and one for the package, which report how
+//! many degrees they are below TjMax, the temperature the CPU starts throttling at. ACPI thermal
+//! zones are found by scanning the DSDT and SSDTs for `ThermalZone` definitions rather than
+//! interpreting the AML, like `power` finds the sleep types. So only a `_TMP` or `_CRT` that is
+//! a constant, a `Name` or a method returning a number, can be read; zones measured through the
+//! embedded controller show up without a temperature.
+//!
+//! A task reads the sensors every `POLL_INTERVAL`. Once one reaches its critical temperature,
+//! TjMax for the CPU and `_CRT` for a zone unless the `thermcrit=` boot argument overrides
+//! them, the machine is shut down like by `poweroff`.
+use alloc::{format, string::String, vec::Vec};
+use core::arch::x86_64::__cpuid;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+
+use crate::klog::Level;
+use crate::{acpi, config, cpufreq, initd, klogln_at, task, timer};
+
+const IA32_THERM_STATUS: u32 = 0x19c;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1a2;
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1b1;
+const READING_VALID: u64 = 1 << 31;
+/// TjMax of CPUs without `MSR_TEMPERATURE_TARGET`.
+const DEFAULT_TJ_MAX: i32 = 100;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_QWORD_PREFIX: u8 = 0x0e;
+const AML_METHOD_OP: u8 = 0x14;
+const AML_DUAL_NAME_PREFIX: u8 = 0x2e;
+const AML_MULTI_NAME_PREFIX: u8 = 0x2f;
+const AML_EXT_OP_PREFIX: u8 = 0x5b;
+const AML_THERMAL_ZONE_OP: u8 = 0x85;
+const AML_RETURN_OP: u8 = 0xa4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    /// the digital thermal sensor of the core
+    Core,
+    Package,
+    /// an ACPI thermal zone with a constant temperature, if any
+    Zone(Option<i32>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Sensor {
+    name: String,
+    source: Source,
+    /// degrees Celsius
+    critical: Option<i32>,
+}
+
+/// A sensor's temperatures in degrees Celsius.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reading {
+    pub name: String,
+    /// `None` if the sensor can't be read
+    pub celsius: Option<i32>,
+    /// the machine is shut down at this temperature
+    pub critical: Option<i32>,
+}
+
+impl Reading {
+    pub fn is_critical(&self) -> bool {
+        match (self.celsius, self.critical) {
+            (Some(celsius), Some(critical)) => celsius >= critical,
+            _ => false,
+        }
+    }
+}
+
+static SENSORS: Once<Vec<Sensor>> = Once::new();
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Finds the sensors and starts watching them if any can be read. Requires the scheduler.
+pub fn init() {
+    let sensors = SENSORS.call_once(find_sensors);
+    let readable = sensors
+        .iter()
+        .any(|sensor| sensor.source != Source::Zone(None));
+    if readable {
+        task::spawn("thermal", || loop {
+            timer::sleep(POLL_INTERVAL);
+            check();
+        });
+    }
+}
+
+fn find_sensors() -> Vec<Sensor> {
+    let mut sensors = Vec::new();
+    let caps = cpufreq::capabilities();
+    if caps.intel && caps.thermal_sensor {
+        let critical = Some(tj_max());
+        sensors.push(Sensor {
+            name: String::from("cpu"),
+            source: Source::Core,
+            critical,
+        });
+        if caps.package_thermal_sensor {
+            sensors.push(Sensor {
+                name: String::from("package"),
+                source: Source::Package,
+                critical,
+            });
+        }
+    }
+    let tables = acpi::dsdt().into_iter().chain(acpi::find_tables(b"SSDT"));
+    for table in tables {
+        let Some(bytes) = table.bytes() else {
+            continue;
+        };
+        sensors.extend(scan_zones(&bytes[acpi::SDT_HEADER_SIZE..]));
+    }
+    sensors
+}
+
+fn tj_max() -> i32 {
+    // the MSR came with Nehalem, like CPUID leaf 0xb
+    if unsafe { __cpuid(0) }.eax < 0xb {
+        return DEFAULT_TJ_MAX;
+    }
+    let target = unsafe { Msr::new(MSR_TEMPERATURE_TARGET).read() };
+    match (target >> 16 & 0xff) as i32 {
+        0 => DEFAULT_TJ_MAX,
+        tj_max => tj_max,
+    }
+}
+
+/// Reads a digital thermal sensor, which counts degrees below TjMax.
+fn read_dts(msr: u32, tj_max: i32) -> Option<i32> {
+    let status = unsafe { Msr::new(msr).read() };
+    (status & READING_VALID != 0).then(|| tj_max - (status >> 16 & 0x7f) as i32)
+}
+
+/// Reads every sensor. The critical temperatures are the ones set by `thermcrit=`, if it is.
+pub fn readings() -> Vec<Reading> {
+    let Some(sensors) = SENSORS.r#try() else {
+        return Vec::new();
+    };
+    let critical = config::get().thermal_critical;
+    sensors
+        .iter()
+        .map(|sensor| {
+            let tj_max = sensor.critical.unwrap_or(DEFAULT_TJ_MAX);
+            let celsius = match sensor.source {
+                Source::Core => read_dts(IA32_THERM_STATUS, tj_max),
+                Source::Package => read_dts(IA32_PACKAGE_THERM_STATUS, tj_max),
+                Source::Zone(celsius) => celsius,
+            };
+            Reading {
+                name: sensor.name.clone(),
+                celsius,
+                critical: critical.or(sensor.critical),
+            }
+        })
+        .collect()
+}
+
+/// Shuts down once a sensor reached its critical temperature.
+fn check() {
+    let Some(reading) = readings().into_iter().find(Reading::is_critical) else {
+        return;
+    };
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    klogln_at!(
+        Level::Error,
+        "thermal: {} reached {} C, critical is {} C, shutting down",
+        reading.name,
+        reading.celsius.unwrap_or_default(),
+        reading.critical.unwrap_or_default()
+    );
+    initd::poweroff();
+}
+
+/// Contents of `/proc/thermal`, a line per sensor.
+pub fn thermal_file() -> String {
+    let mut out = String::new();
+    for reading in readings() {
+        let celsius = match reading.celsius {
+            Some(celsius) => format!("{celsius} C"),
+            None => String::from("unknown"),
+        };
+        let _ = write!(out, "{}: {celsius}", reading.name);
+        if let Some(critical) = reading.critical {
+            let _ = write!(out, ", critical {critical} C");
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Tenths of a Kelvin, the unit of ACPI, to degrees Celsius.
+fn decikelvin_to_celsius(decikelvin: u64) -> i32 {
+    (decikelvin as i64 - 2732).div_euclid(10) as i32
+}
+
+/// The package length at the start of `aml` and the number of bytes it takes. The length counts
+/// its own bytes.
+fn pkg_length(aml: &[u8]) -> Option<(usize, usize)> {
+    let lead = *aml.first()?;
+    let follow = (lead >> 6) as usize;
+    if follow == 0 {
+        return Some(((lead & 0x3f) as usize, 1));
+    }
+    let mut len = (lead & 0x0f) as usize;
+    for i in 0..follow {
+        len |= (*aml.get(1 + i)? as usize) << (4 + 8 * i);
+    }
+    Some((len, 1 + follow))
+}
+
+/// The last segment of the name string at the start of `aml` and the bytes the string takes.
+fn name_string(aml: &[u8]) -> Option<([u8; 4], usize)> {
+    let mut i = 0;
+    while matches!(aml.get(i)?, b'\\' | b'^') {
+        i += 1;
+    }
+    let segments = match *aml.get(i)? {
+        AML_DUAL_NAME_PREFIX => {
+            i += 1;
+            2
+        }
+        AML_MULTI_NAME_PREFIX => {
+            i += 2;
+            *aml.get(i - 1)? as usize
+        }
+        _ => 1,
+    };
+    let end = i + segments * 4;
+    let segment: [u8; 4] = aml.get(end.checked_sub(4)?..end)?.try_into().ok()?;
+    let valid = |byte: &u8| byte.is_ascii_uppercase() || byte.is_ascii_digit() || *byte == b'_';
+    (segment.iter().all(valid) && !segment[0].is_ascii_digit()).then_some((segment, end))
+}
+
+/// The integer constant at the start of `aml`.
+fn integer(aml: &[u8]) -> Option<u64> {
+    let bytes = |n: usize| {
+        let bytes = aml.get(1..1 + n)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | *byte as u64),
+        )
+    };
+    match *aml.first()? {
+        AML_ZERO_OP => Some(0),
+        AML_ONE_OP => Some(1),
+        AML_BYTE_PREFIX => bytes(1),
+        AML_WORD_PREFIX => bytes(2),
+        AML_DWORD_PREFIX => bytes(4),
+        AML_QWORD_PREFIX => bytes(8),
+        _ => None,
+    }
+}
+
+/// The value of the object `name` in `body` if it is constant: `Name (_CRT, 3732)` or
+/// `Method (_CRT) { Return (3732) }`.
+fn constant(body: &[u8], name: &[u8; 4]) -> Option<u64> {
+    let mut found = (1..body.len().saturating_sub(4)).filter(|&i| &body[i..i + 4] == name);
+    found.find_map(|i| {
+        if body[i - 1] == AML_NAME_OP {
+            return integer(&body[i + 4..]);
+        }
+        // the method op is followed by a package length of 1 to 4 bytes
+        let method = (1..=4).any(|len| {
+            let Some(start) = i.checked_sub(1 + len) else {
+                return false;
+            };
+            body[start] == AML_METHOD_OP
+                && pkg_length(&body[start + 1..]).map(|(_, n)| n) == Some(len)
+        });
+        // after the name come the method flags
+        match (method, body.get(i + 5)) {
+            (true, Some(&AML_RETURN_OP)) => integer(&body[i + 6..]),
+            _ => None,
+        }
+    })
+}
+
+/// The thermal zones defined in `aml`, the definition block of a DSDT or SSDT.
+fn scan_zones(aml: &[u8]) -> Vec<Sensor> {
+    let starts = (0..aml.len().saturating_sub(1))
+        .filter(|&i| aml[i] == AML_EXT_OP_PREFIX && aml[i + 1] == AML_THERMAL_ZONE_OP);
+    starts.filter_map(|i| zone(&aml[i + 2..])).collect()
+}
+
+/// The thermal zone whose package length starts `aml`.
+fn zone(aml: &[u8]) -> Option<Sensor> {
+    let (len, len_bytes) = pkg_length(aml)?;
+    let zone = aml.get(..len)?;
+    let (name, name_bytes) = name_string(zone.get(len_bytes..)?)?;
+    let body = &zone[len_bytes + name_bytes..];
+    let name = core::str::from_utf8(&name).ok()?.trim_end_matches('_');
+    Some(Sensor {
+        name: String::from(name),
+        source: Source::Zone(constant(body, b"_TMP").map(decikelvin_to_celsius)),
+        critical: constant(body, b"_CRT").map(decikelvin_to_celsius),
+    })
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_scan_zones() {
+    #[rustfmt::skip]
+    let aml = [
+        // Scope (\_SB) { }
+        0x10, 0x06, b'\\', b'_', b'S', b'B', b'_',
+        // ThermalZone (TZ0_) {
+        0x5b, 0x85, 0x18, b'T', b'Z', b'0', b'_',
+        //   Name (_CRT, 3732)
+        0x08, b'_', b'C', b'R', b'T', 0x0b, 0x94, 0x0e,
+        //   Method (_TMP) { Return (3000) }
+        0x14, 0x0a, b'_', b'T', b'M', b'P', 0x00, 0xa4, 0x0b, 0xb8, 0x0b,
+        // }
+    ];
+    let zones = scan_zones(&aml);
+    assert_eq!(zones.len(), 1);
+    assert_eq!(zones[0].name, "TZ0");
+    assert_eq!(zones[0].source, Source::Zone(Some(26)));
+    assert_eq!(zones[0].critical, Some(100));
+}
+
+#[cfg_attr(test, test_case)]
+pub(crate) fn test_critical() {
+    let mut reading = Reading {
+        name: String::from("cpu"),
+        celsius: Some(99),
+        critical: Some(100),
+    };
+    assert!(!reading.is_critical());
+    reading.celsius = Some(100);
+    assert!(reading.is_critical());
+    reading.critical = None;
+    assert!(!reading.is_critical());
+}
@@ -0,0 +1,119 @@
+//! Monotonic tick counter driven by the PIT.
+//!
+//! Tests can freeze the counter with `freeze`, after which it only moves when stepped with
+//! `step`. The timer wheel, the scheduler's priority boosts and TCP's retransmissions all go by
+//! `ticks`, so timeouts fire exactly when the test steps past them, however slow QEMU is.
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{task, timer, tsc};
+
+/// Frequency of the timer interrupt.
+pub const TICK_HZ: u64 = 1000;
+/// Input clock of the programmable interval timer.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Added to `TICKS`, so the counter goes on from where it was frozen after `thaw`.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+static FROZEN: AtomicBool = AtomicBool::new(false);
+/// `ticks` while frozen.
+static VIRTUAL_TICKS: AtomicU64 = AtomicU64::new(0);
+/// How often `step` yields per tick at most, so a task that never blocks can't hang it.
+const STEP_YIELDS: usize = 16;
+
+/// Programs PIT channel 0 to fire the timer interrupt `TICK_HZ` times per second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICK_HZ) as u16;
+    unsafe {
+        // channel 0, lobyte/hibyte, mode 3 (square wave)
+        Port::<u8>::new(PIT_COMMAND).write(0x36);
+        let mut data = Port::<u8>::new(PIT_CHANNEL0);
+        data.write((divisor & 0xff) as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called from the timer interrupt.
+pub fn tick() {
+    if TICKS.fetch_add(1, Ordering::Relaxed) == 0 {
+        tsc::start_calibration();
+    }
+}
+
+/// Returns the number of timer ticks since boot.
+pub fn ticks() -> u64 {
+    if FROZEN.load(Ordering::Acquire) {
+        VIRTUAL_TICKS.load(Ordering::Relaxed)
+    } else {
+        TICKS.load(Ordering::Relaxed).wrapping_add(OFFSET.load(Ordering::Relaxed))
+    }
+}
+
+/// Returns whether the counter is frozen, see `freeze`.
+pub fn is_frozen() -> bool {
+    FROZEN.load(Ordering::Acquire)
+}
+
+/// Stops `ticks` at its current value. The timer interrupt still fires, but time only passes
+/// through `step`. For tests.
+pub fn freeze() {
+    without_interrupts(|| {
+        if !is_frozen() {
+            VIRTUAL_TICKS.store(ticks(), Ordering::Relaxed);
+            FROZEN.store(true, Ordering::Release);
+        }
+    });
+}
+
+/// Lets `ticks` follow the timer interrupt again, starting from the frozen value.
+pub fn thaw() {
+    without_interrupts(|| {
+        if is_frozen() {
+            let offset = VIRTUAL_TICKS
+                .load(Ordering::Relaxed)
+                .wrapping_sub(TICKS.load(Ordering::Relaxed));
+            OFFSET.store(offset, Ordering::Relaxed);
+            FROZEN.store(false, Ordering::Release);
+        }
+    });
+}
+
+/// Advances the frozen counter by `duration`, one tick at a time. Timers due on a tick fire
+/// before the next one, and the tasks they wake get to run before the next tick.
+///
+/// Panics if the counter isn't frozen.
+pub fn step(duration: Duration) {
+    assert!(is_frozen(), "stepping time that isn't frozen");
+    for _ in 0..duration_to_ticks(duration) {
+        without_interrupts(|| {
+            VIRTUAL_TICKS.fetch_add(1, Ordering::Relaxed);
+            timer::tick();
+        });
+        for _ in 0..STEP_YIELDS {
+            if !task::has_ready_tasks() {
+                break;
+            }
+            task::yield_now();
+        }
+    }
+}
+
+/// Returns the time elapsed since the timer was started.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// Converts a duration to ticks, rounding up so waits are never shorter than requested.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = 1_000_000_000 / TICK_HZ as u128;
+    ((duration.as_nanos() + nanos_per_tick - 1) / nanos_per_tick) as u64
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * (1_000_000_000 / TICK_HZ))
+}
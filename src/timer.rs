@@ -0,0 +1,172 @@
+//! Hierarchical timer wheel for scheduled callbacks.
+//!
+//! Level 0 has one slot per tick, every higher level covers `SLOTS` times the range of the
+//! previous one. When a lower level wraps around, the matching slot of the next level is
+//! cascaded down. Callbacks run from the timer interrupt, so they should only do short work
+//! like signaling an `Event` or waking a task.
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{task, time};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+struct Timer {
+    id: u64,
+    expires: u64,
+    callback: Callback,
+}
+
+struct TimerWheel {
+    levels: [[Vec<Timer>; SLOTS]; LEVELS],
+    /// timers too far in the future for the wheel, reinserted whenever the last level wraps
+    overflow: Vec<Timer>,
+    /// the tick the wheel was last advanced to
+    now: u64,
+}
+
+impl TimerWheel {
+    fn new() -> Self {
+        Self {
+            levels: core::array::from_fn(|_| core::array::from_fn(|_| Vec::new())),
+            overflow: Vec::new(),
+            now: time::ticks(),
+        }
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        // A timer goes on the lowest level whose current rotation still contains its expiry,
+        // so its slot is guaranteed to be visited (or cascaded) before the level wraps.
+        for level in 0..LEVELS {
+            if (timer.expires ^ self.now) >> (SLOT_BITS * (level as u32 + 1)) == 0 {
+                let slot = (timer.expires >> (SLOT_BITS * level as u32)) & SLOT_MASK;
+                self.levels[level][slot as usize].push(timer);
+                return;
+            }
+        }
+        self.overflow.push(timer);
+    }
+
+    fn cancel(&mut self, id: u64) -> bool {
+        let lists = self.levels.iter_mut().flatten().chain(core::iter::once(&mut self.overflow));
+        for list in lists {
+            if let Some(pos) = list.iter().position(|timer| timer.id == id) {
+                list.swap_remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Advances the wheel by one tick, returning the callbacks that are due.
+    fn advance(&mut self) -> Vec<Callback> {
+        self.now += 1;
+
+        // cascade the higher levels whose range starts at this tick
+        for level in 1..=LEVELS {
+            if self.now & ((1 << (SLOT_BITS * level as u32)) - 1) != 0 {
+                break;
+            }
+            let timers = if level == LEVELS {
+                core::mem::take(&mut self.overflow)
+            } else {
+                let slot = (self.now >> (SLOT_BITS * level as u32)) & SLOT_MASK;
+                core::mem::take(&mut self.levels[level][slot as usize])
+            };
+            for timer in timers {
+                self.insert(timer);
+            }
+        }
+
+        let slot = &mut self.levels[0][(self.now & SLOT_MASK) as usize];
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < slot.len() {
+            if slot[i].expires <= self.now {
+                due.push(slot.swap_remove(i).callback);
+            } else {
+                i += 1;
+            }
+        }
+        due
+    }
+}
+
+lazy_static! {
+    static ref WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A scheduled callback that can still be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle(u64);
+
+impl TimerHandle {
+    /// Cancels the timer. Returns false if it already fired or was cancelled before.
+    pub fn cancel(self) -> bool {
+        without_interrupts(|| WHEEL.lock().cancel(self.0))
+    }
+}
+
+/// Runs `callback` from the timer interrupt once `duration` has elapsed.
+pub fn schedule_in<F>(duration: Duration, callback: F) -> TimerHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    // always wait at least one full tick
+    let delay = time::duration_to_ticks(duration).max(1);
+    without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let expires = wheel.now + delay;
+        wheel.insert(Timer {
+            id,
+            expires,
+            callback: Box::new(callback),
+        });
+    });
+    TimerHandle(id)
+}
+
+/// Blocks the current task for at least `duration`.
+pub fn sleep(duration: Duration) {
+    let done = Arc::new(AtomicBool::new(false));
+    let (id, fired) = (task::current_id(), done.clone());
+    schedule_in(duration, move || {
+        fired.store(true, Ordering::Release);
+        task::wake(id);
+    });
+    // checked with interrupts off, so the timer can't fire between the check and sleeping
+    while without_interrupts(|| {
+        let sleep = !done.load(Ordering::Acquire);
+        if sleep {
+            task::sleep_current();
+        }
+        sleep
+    }) {}
+}
+
+/// Called from the timer interrupt after the tick counter was advanced.
+pub fn tick() {
+    let due = {
+        let mut wheel = WHEEL.lock();
+        let mut due = Vec::new();
+        while wheel.now < time::ticks() {
+            due.append(&mut wheel.advance());
+        }
+        due
+    };
+    for callback in due {
+        callback();
+    }
+}
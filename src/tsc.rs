@@ -0,0 +1,54 @@
+//! Time stamp counter.
+//!
+//! The counter is sampled on the first timer tick, its frequency is the number of cycles since
+//! then divided by the time the PIT says has passed.
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time;
+
+/// Ticks after which the measured frequency is used, before that it is just an estimate.
+const CALIBRATION_TICKS: u64 = 1000;
+/// Ticks needed before there is an estimate at all.
+const MIN_TICKS: u64 = 50;
+
+static START_TSC: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Called on the first timer tick.
+pub(crate) fn start_calibration() {
+    START_TSC.store(read(), Ordering::Relaxed);
+}
+
+/// Returns the cycles per second, or `None` if the timer hasn't run long enough to tell.
+pub fn frequency() -> Option<u64> {
+    let frequency = FREQUENCY.load(Ordering::Relaxed);
+    if frequency != 0 {
+        return Some(frequency);
+    }
+    let start = START_TSC.load(Ordering::Relaxed);
+    let (now, ticks) = without_interrupts(|| (read(), time::ticks()));
+    // the start was sampled on tick 1
+    let elapsed = ticks.saturating_sub(1);
+    if start == 0 || elapsed < MIN_TICKS {
+        return None;
+    }
+    let frequency = ((now - start) as u128 * time::TICK_HZ as u128 / elapsed as u128) as u64;
+    if elapsed >= CALIBRATION_TICKS {
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+    }
+    Some(frequency)
+}
+
+pub fn cycles_to_duration(cycles: u64) -> Option<Duration> {
+    let frequency = frequency()?;
+    Some(Duration::from_nanos(
+        (cycles as u128 * 1_000_000_000 / frequency as u128) as u64,
+    ))
+}
@@ -0,0 +1,260 @@
+//! Terminals, the layer between the keyboard and the tasks reading input.
+//!
+//! A TTY runs every key through its line discipline before readers see it. In canonical mode
+//! input is collected into a line that can be edited with backspace and ^U, and only becomes
+//! readable once enter or ^D is pressed. In raw mode every key is readable right away, which is
+//! what full-screen programs want. Echo and ^C are switched separately, see `Settings`.
+//!
+//! Keys arrive in interrupt context through `Tty::input`, readers block in `Tty::read`.
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    error::{KError, KResult},
+    jobs, print, println,
+    signal::Signal,
+    sync::WaitQueue,
+};
+
+/// Bytes of input a TTY buffers. Keys beyond this are dropped until the reader catches up.
+pub const MAX_INPUT: usize = 4096;
+
+const CTRL_C: char = '\x03';
+const CTRL_D: char = '\x04';
+const CTRL_U: char = '\x15';
+const BACKSPACE: char = '\x08';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// collect input into editable lines
+    pub canonical: bool,
+    /// print what is typed
+    pub echo: bool,
+    /// ^C interrupts the foreground job instead of being read as a byte
+    pub signals: bool,
+}
+
+impl Settings {
+    /// What the command line and line-oriented programs use.
+    pub const COOKED: Self = Self {
+        canonical: true,
+        echo: true,
+        signals: true,
+    };
+    /// Every key is passed through as typed.
+    pub const RAW: Self = Self {
+        canonical: false,
+        echo: false,
+        signals: false,
+    };
+}
+
+struct State {
+    settings: Settings,
+    /// the line being edited in canonical mode
+    line: String,
+    /// bytes ready to be read
+    input: VecDeque<u8>,
+    /// ^D was pressed on an empty line, the next read returns end of file
+    eof: bool,
+    /// ^C was pressed without a foreground job, readers return `Interrupted`
+    interrupted: bool,
+}
+
+pub struct Tty {
+    state: Mutex<State>,
+    readers: WaitQueue,
+}
+
+/// The TTY of the text console, fed by the keyboard.
+pub static CONSOLE: Tty = Tty::new();
+
+impl Tty {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                settings: Settings::COOKED,
+                line: String::new(),
+                input: VecDeque::new(),
+                eof: false,
+                interrupted: false,
+            }),
+            readers: WaitQueue::new(),
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        without_interrupts(|| self.state.lock().settings)
+    }
+
+    /// Changes the settings. Leaving canonical mode makes the line being edited readable.
+    pub fn set_settings(&self, settings: Settings) {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if !settings.canonical {
+                let line = core::mem::take(&mut state.line);
+                push_input(&mut state.input, line.as_bytes());
+            }
+            state.settings = settings;
+        });
+        self.readers.wake_all();
+    }
+
+    /// Runs a key through the line discipline. Called from the keyboard interrupt.
+    pub fn input(&self, key: DecodedKey) {
+        let interrupt = without_interrupts(|| {
+            let mut state = self.state.lock();
+            let settings = state.settings;
+            match key {
+                DecodedKey::Unicode(CTRL_C) if settings.signals => {
+                    state.line.clear();
+                    if settings.echo {
+                        println!("^C");
+                    }
+                    return true;
+                }
+                DecodedKey::Unicode(char) if settings.canonical => edit_line(&mut state, char),
+                DecodedKey::Unicode(char) => {
+                    let mut bytes = [0; 4];
+                    push_input(&mut state.input, char.encode_utf8(&mut bytes).as_bytes());
+                    if settings.echo {
+                        print!("{}", char);
+                    }
+                }
+                // keys without a character only mean something to full-screen programs
+                DecodedKey::RawKey(code) if !settings.canonical => {
+                    if let Some(sequence) = escape_sequence(code) {
+                        push_input(&mut state.input, sequence.as_bytes());
+                    }
+                }
+                DecodedKey::RawKey(_) => {}
+            }
+            false
+        });
+        // outside the lock, delivering the signal takes the scheduler's
+        if interrupt && !jobs::signal_foreground(Signal::Int) {
+            without_interrupts(|| self.state.lock().interrupted = true);
+        }
+        self.readers.wake_all_boosted();
+    }
+
+    /// Reads at most `buf.len()` bytes, blocking until some input is ready. In canonical mode
+    /// a read stops at the end of a line. Returns 0 at end of file and fails with `Interrupted`
+    /// if ^C was pressed while nobody was in the foreground.
+    pub fn read(&self, buf: &mut [u8]) -> KResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut result = Ok(0);
+        self.readers.wait_until(|| {
+            let mut state = self.state.lock();
+            if core::mem::take(&mut state.interrupted) {
+                result = Err(KError::Interrupted);
+                return true;
+            }
+            if state.input.is_empty() {
+                return core::mem::take(&mut state.eof);
+            }
+            let canonical = state.settings.canonical;
+            let mut read = 0;
+            while read < buf.len() {
+                let Some(byte) = state.input.pop_front() else {
+                    break;
+                };
+                buf[read] = byte;
+                read += 1;
+                if canonical && byte == b'\n' {
+                    break;
+                }
+            }
+            result = Ok(read);
+            true
+        });
+        result
+    }
+
+    /// Reads a line, including the newline unless it ended with ^D.
+    pub fn read_line(&self) -> KResult<String> {
+        let mut line = Vec::new();
+        let mut buf = [0; 128];
+        loop {
+            let read = self.read(&mut buf)?;
+            line.extend_from_slice(&buf[..read]);
+            if read == 0 || line.ends_with(b"\n") {
+                return Ok(String::from_utf8_lossy(&line).into_owned());
+            }
+        }
+    }
+
+    /// Writes output to the screen.
+    pub fn write(&self, buf: &[u8]) -> usize {
+        for &byte in buf {
+            print!("{}", byte as char);
+        }
+        buf.len()
+    }
+}
+
+/// Canonical mode editing.
+fn edit_line(state: &mut State, char: char) {
+    let echo = state.settings.echo;
+    match char {
+        '\n' => {
+            state.line.push('\n');
+            let line = core::mem::take(&mut state.line);
+            push_input(&mut state.input, line.as_bytes());
+            if echo {
+                print!("\n");
+            }
+        }
+        CTRL_D if state.line.is_empty() => state.eof = true,
+        CTRL_D => {
+            let line = core::mem::take(&mut state.line);
+            push_input(&mut state.input, line.as_bytes());
+        }
+        BACKSPACE => {
+            if state.line.pop().is_some() && echo {
+                print!("\x08");
+            }
+        }
+        CTRL_U => {
+            for _ in state.line.drain(..) {
+                if echo {
+                    print!("\x08");
+                }
+            }
+        }
+        char if !char.is_control() => {
+            if state.input.len() + state.line.len() < MAX_INPUT {
+                state.line.push(char);
+                if echo {
+                    print!("{}", char);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn push_input(input: &mut VecDeque<u8>, bytes: &[u8]) {
+    let room = MAX_INPUT.saturating_sub(input.len());
+    input.extend(&bytes[..bytes.len().min(room)]);
+}
+
+/// The sequence a VT100 sends for a key, for reading keys without a character in raw mode.
+fn escape_sequence(code: KeyCode) -> Option<&'static str> {
+    Some(match code {
+        KeyCode::ArrowUp => "\x1b[A",
+        KeyCode::ArrowDown => "\x1b[B",
+        KeyCode::ArrowRight => "\x1b[C",
+        KeyCode::ArrowLeft => "\x1b[D",
+        KeyCode::Home => "\x1b[H",
+        KeyCode::End => "\x1b[F",
+        KeyCode::Delete => "\x1b[3~",
+        KeyCode::PageUp => "\x1b[5~",
+        KeyCode::PageDown => "\x1b[6~",
+        _ => return None,
+    })
+}
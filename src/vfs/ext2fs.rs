@@ -0,0 +1,157 @@
+//! An ext2 file system from `ext` in the VFS, mounted with the `mount` command.
+use alloc::{format, string::String, vec, vec::Vec};
+
+use super::{FileSystem, Metadata, VfsEntry, VfsResult};
+use crate::ext::{
+    AccessFlags, Credentials, Errno, Ext2, Extent, FileType, OpenOptions, Quota, QuotaKind,
+    QuotaLimits, StatFs, RWS,
+};
+
+pub struct Ext2Fs<T: RWS>(Ext2<T>);
+
+impl<T: RWS> Ext2Fs<T> {
+    pub fn new(ext2: Ext2<T>) -> Self {
+        Self(ext2)
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+impl<T: RWS + Send + Sync> FileSystem for Ext2Fs<T> {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<Metadata> {
+        let stat = self.0.stat(path)?;
+        Ok(Metadata {
+            file_type: FileType::from((stat.type_and_perms & 0xf000) as u16),
+            size: stat.size,
+        })
+    }
+
+    fn read(&self, path: &str) -> VfsResult<Vec<u8>> {
+        let mut file = self.0.clone().open(path)?;
+        let mut data = Vec::new();
+        let mut buf = vec![0; 4096];
+        loop {
+            let read = file.read(&mut buf)? as usize;
+            if read == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf[..read]);
+        }
+    }
+
+    fn write(&self, path: &str, mut data: &[u8]) -> VfsResult<()> {
+        let mut file = self.0.clone().create(path)?;
+        while !data.is_empty() {
+            match file.write(data)? as usize {
+                0 => return Err(Errno::OutOfSpace),
+                written => data = &data[written..],
+            }
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let mut file = self.0.clone().open(path)?;
+        Ok(file.read_at(offset, buf)? as usize)
+    }
+
+    fn write_at(&self, path: &str, mut offset: u64, mut data: &[u8]) -> VfsResult<()> {
+        let mut file = OpenOptions::new().write(true).open(path, self.0.clone())?;
+        while !data.is_empty() {
+            match file.write_at(offset, data)? {
+                0 => return Err(Errno::OutOfSpace),
+                written => {
+                    offset += written;
+                    data = &data[written as usize..];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, path: &str, size: u64) -> VfsResult<()> {
+        let mut file = OpenOptions::new().write(true).open(path, self.0.clone())?;
+        file.set_len(size)
+    }
+
+    fn allocate(&self, path: &str, offset: u64, len: u64) -> VfsResult<()> {
+        let mut file = OpenOptions::new().write(true).open(path, self.0.clone())?;
+        file.fallocate(offset, len)
+    }
+
+    fn read_dir(&self, path: &str) -> VfsResult<Vec<VfsEntry>> {
+        let mut entries = Vec::new();
+        for entry in self.0.read_dir(path)? {
+            let name = entry.name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let metadata = self.metadata(&join(path, &name))?;
+            entries.push(VfsEntry { name, metadata });
+        }
+        Ok(entries)
+    }
+
+    fn create_dir(&self, path: &str) -> VfsResult<()> {
+        self.0.clone().create_dir(path)
+    }
+
+    fn remove(&self, path: &str) -> VfsResult<()> {
+        if path == "/" {
+            return Err(Errno::AccessError);
+        }
+        match self.metadata(path)?.file_type {
+            FileType::Directory => self.0.clone().remove_dir(path),
+            _ => self.0.clone().remove_file(path),
+        }
+    }
+
+    fn sync(&self) -> VfsResult<()> {
+        self.0.sync()
+    }
+
+    fn set_quota_tracking(&self, on: bool) -> VfsResult<()> {
+        match on {
+            true => self.0.quota_on(),
+            false => self.0.quota_off(),
+        }
+    }
+
+    fn quotas(&self, kind: QuotaKind) -> VfsResult<Vec<Quota>> {
+        self.0.quotas(kind)
+    }
+
+    fn set_quota(&self, kind: QuotaKind, id: u16, limits: QuotaLimits) -> VfsResult<()> {
+        self.0.set_quota(kind, id, limits)
+    }
+
+    fn extents(&self, path: &str) -> VfsResult<Vec<Extent>> {
+        self.0.extents(path)
+    }
+
+    fn statfs(&self) -> VfsResult<StatFs> {
+        Ok(self.0.statfs())
+    }
+
+    fn seek_data(&self, path: &str, offset: u64) -> VfsResult<u64> {
+        self.0.clone().open(path)?.seek_data(offset)
+    }
+
+    fn seek_hole(&self, path: &str, offset: u64) -> VfsResult<u64> {
+        self.0.clone().open(path)?.seek_hole(offset)
+    }
+
+    fn access(&self, path: &str, mode: AccessFlags, credentials: &Credentials) -> VfsResult<()> {
+        self.0.access(path, mode, credentials)
+    }
+}
@@ -0,0 +1,348 @@
+//! Virtual file system layer.
+//!
+//! File systems are mounted on absolute paths and looked up by the longest matching mount
+//! point. Paths handed to a `FileSystem` are relative to its mount point, normalized and always
+//! start with `/`.
+use alloc::{string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::drivers::ahci_driver;
+use crate::ext::{
+    AccessFlags, Credentials, Errno, Ext2, Extent, FileType, Quota, QuotaKind, QuotaLimits, StatFs,
+};
+use crate::klog::Level;
+use crate::{bootargs, initrd, klogln_at};
+
+mod ext2fs;
+mod ninep;
+mod procfs;
+mod ramfs;
+mod sysfs;
+pub use ext2fs::Ext2Fs;
+pub use ninep::{Channel, NinePFs};
+pub use procfs::ProcFs;
+pub use ramfs::RamFs;
+pub use sysfs::SysFs;
+
+pub type VfsResult<T> = Result<T, Errno>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct VfsEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Name of the file system type, like `ramfs`.
+    fn name(&self) -> &str;
+    fn metadata(&self, path: &str) -> VfsResult<Metadata>;
+    /// Reads a whole file.
+    fn read(&self, path: &str) -> VfsResult<Vec<u8>>;
+    /// Replaces the contents of a file, creating it if it doesn't exist.
+    fn write(&self, path: &str, data: &[u8]) -> VfsResult<()>;
+    /// Reads from `offset` of a file, returns how much was read. File systems that can't read
+    /// part of a file read all of it.
+    fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let data = self.read(path)?;
+        let data = data.get(offset as usize..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+    /// Writes `data` at `offset` of an existing file, past the end leaves a hole of zeros. File
+    /// systems that can't write part of a file replace all of it.
+    fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> VfsResult<()> {
+        let mut contents = self.read(path)?;
+        let (start, end) = (offset as usize, offset as usize + data.len());
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(data);
+        self.write(path, &contents)
+    }
+    /// Truncates or extends a file to `size` bytes, extending with zeros. File systems that
+    /// can't resize a file rewrite all of it.
+    fn truncate(&self, path: &str, size: u64) -> VfsResult<()> {
+        let mut contents = self.read(path)?;
+        contents.resize(size as usize, 0);
+        self.write(path, &contents)
+    }
+    /// Reserves the space for `offset..offset + len` of a file, extending it with zeros to
+    /// `offset + len` if it is shorter. File systems without blocks only extend it.
+    fn allocate(&self, path: &str, offset: u64, len: u64) -> VfsResult<()> {
+        let end = offset.checked_add(len).ok_or(Errno::FileTooBig)?;
+        match self.metadata(path)?.size {
+            size if size < end => self.truncate(path, end),
+            _ => Ok(()),
+        }
+    }
+    fn read_dir(&self, path: &str) -> VfsResult<Vec<VfsEntry>>;
+    fn create_dir(&self, path: &str) -> VfsResult<()>;
+    /// Removes a file or an empty directory.
+    fn remove(&self, path: &str) -> VfsResult<()>;
+    /// Writes everything out to stable media.
+    fn sync(&self) -> VfsResult<()> {
+        Ok(())
+    }
+    /// Turns disk quotas on or off.
+    fn set_quota_tracking(&self, _on: bool) -> VfsResult<()> {
+        Err(Errno::Unsupported)
+    }
+    /// Usage and limits of the users or groups that have any.
+    fn quotas(&self, _kind: QuotaKind) -> VfsResult<Vec<Quota>> {
+        Err(Errno::Unsupported)
+    }
+    fn set_quota(&self, _kind: QuotaKind, _id: u16, _limits: QuotaLimits) -> VfsResult<()> {
+        Err(Errno::Unsupported)
+    }
+    /// The runs of consecutive blocks a file is stored in.
+    fn extents(&self, _path: &str) -> VfsResult<Vec<Extent>> {
+        Err(Errno::Unsupported)
+    }
+    /// The size of the file system and how much of it is free.
+    fn statfs(&self) -> VfsResult<StatFs> {
+        Err(Errno::Unsupported)
+    }
+    /// The first offset from `offset` on that isn't in a hole of a file, `lseek` with
+    /// `SEEK_DATA`. File systems without holes have data up to the end of every file.
+    fn seek_data(&self, path: &str, offset: u64) -> VfsResult<u64> {
+        match self.metadata(path)?.size {
+            size if offset < size => Ok(offset),
+            _ => Err(Errno::PastEndOfFile),
+        }
+    }
+    /// The first offset from `offset` on that is in a hole of a file or at its end, `lseek`
+    /// with `SEEK_HOLE`.
+    fn seek_hole(&self, path: &str, offset: u64) -> VfsResult<u64> {
+        match self.metadata(path)?.size {
+            size if offset < size => Ok(size),
+            _ => Err(Errno::PastEndOfFile),
+        }
+    }
+    /// Checks whether `credentials` allow the accesses in `mode` to a file, without opening
+    /// it. File systems without permissions only check that it exists.
+    fn access(&self, path: &str, _mode: AccessFlags, _credentials: &Credentials) -> VfsResult<()> {
+        self.metadata(path).map(|_| ())
+    }
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Mounts the root file system selected by the `root=` boot argument. Requires the heap.
+pub fn init() {
+    // fails if the root is already mounted, which is fine
+    let _ = mount("/", initrd::root());
+    let _ = mount("/proc", Arc::new(ProcFs));
+    let _ = mount("/sys", Arc::new(SysFs));
+}
+
+/// Mounts the ext2 file system of the disk or partition given as `root=/dev/sdX[N]` as the
+/// root, in place of the initrd the kernel booted with. The mounts below the root stay. Does
+/// nothing for other roots. Requires the devices.
+pub fn mount_root_disk() -> VfsResult<()> {
+    let Some(device) = bootargs::get("root").filter(|root| root.starts_with("/dev/")) else {
+        return Ok(());
+    };
+    let fs = Ext2Fs::new(Ext2::new(ahci_driver::open(device)?)?);
+    without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        match mounts.iter_mut().find(|mount| mount.path == "/") {
+            Some(mount) => mount.fs = Arc::new(fs),
+            None => mounts.push(Mount {
+                path: String::from("/"),
+                fs: Arc::new(fs),
+            }),
+        }
+    });
+    klogln_at!(Level::Info, "root: mounted {}", device);
+    Ok(())
+}
+
+/// Resolves `.` and `..` and duplicate slashes. Fails for relative paths.
+pub fn normalize(path: &str) -> VfsResult<String> {
+    if !path.starts_with('/') {
+        return Err(Errno::NotFound);
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for part in parts {
+        normalized.push('/');
+        normalized.push_str(part);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Splits a normalized path into its parent directory and file name.
+pub fn split_parent(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => ("/", path),
+    }
+}
+
+fn is_below(path: &str, mount_point: &str) -> bool {
+    mount_point == "/"
+        || path == mount_point
+        || (path.starts_with(mount_point) && path.as_bytes()[mount_point.len()] == b'/')
+}
+
+/// Mounts `fs` at `path`. The directory doesn't have to exist in the parent file system.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> VfsResult<()> {
+    let path = normalize(path)?;
+    without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(Errno::AlreadyExists);
+        }
+        mounts.push(Mount { path, fs });
+        Ok(())
+    })
+}
+
+/// Syncs the file system at `path` and unmounts it.
+pub fn unmount(path: &str) -> VfsResult<()> {
+    let path = normalize(path)?;
+    let fs = without_interrupts(|| {
+        MOUNTS
+            .lock()
+            .iter()
+            .find(|mount| mount.path == path)
+            .map(|mount| mount.fs.clone())
+    });
+    fs.ok_or(Errno::NotFound)?.sync()?;
+    without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|mount| mount.path != path && is_below(&mount.path, &path)) {
+            return Err(Errno::AccessError);
+        }
+        let pos = mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(Errno::NotFound)?;
+        mounts.remove(pos);
+        Ok(())
+    })
+}
+
+/// Syncs every mounted file system, returning the first error.
+pub fn sync() -> VfsResult<()> {
+    let filesystems: Vec<_> =
+        without_interrupts(|| MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect());
+    filesystems.iter().map(|fs| fs.sync()).fold(Ok(()), Result::and)
+}
+
+/// Returns the mount points and the type of their file systems.
+pub fn mounts() -> Vec<(String, String)> {
+    without_interrupts(|| {
+        MOUNTS
+            .lock()
+            .iter()
+            .map(|mount| (mount.path.clone(), String::from(mount.fs.name())))
+            .collect()
+    })
+}
+
+/// Finds the file system responsible for `path` and the path relative to it.
+pub fn resolve(path: &str) -> VfsResult<(Arc<dyn FileSystem>, String)> {
+    let path = normalize(path)?;
+    let (mount_point, fs) = without_interrupts(|| {
+        MOUNTS
+            .lock()
+            .iter()
+            .filter(|mount| is_below(&path, &mount.path))
+            .max_by_key(|mount| mount.path.len())
+            .map(|mount| (mount.path.clone(), mount.fs.clone()))
+            .ok_or(Errno::NotFound)
+    })?;
+    let relative = if mount_point == "/" {
+        path
+    } else {
+        let rest = &path[mount_point.len()..];
+        if rest.is_empty() {
+            String::from("/")
+        } else {
+            String::from(rest)
+        }
+    };
+    Ok((fs, relative))
+}
+
+pub fn metadata(path: &str) -> VfsResult<Metadata> {
+    let (fs, path) = resolve(path)?;
+    fs.metadata(&path)
+}
+
+pub fn access(path: &str, mode: AccessFlags, credentials: &Credentials) -> VfsResult<()> {
+    let (fs, path) = resolve(path)?;
+    fs.access(&path, mode, credentials)
+}
+
+pub fn seek_data(path: &str, offset: u64) -> VfsResult<u64> {
+    let (fs, path) = resolve(path)?;
+    fs.seek_data(&path, offset)
+}
+
+pub fn seek_hole(path: &str, offset: u64) -> VfsResult<u64> {
+    let (fs, path) = resolve(path)?;
+    fs.seek_hole(&path, offset)
+}
+
+pub fn read(path: &str) -> VfsResult<Vec<u8>> {
+    let (fs, path) = resolve(path)?;
+    fs.read(&path)
+}
+
+pub fn write(path: &str, data: &[u8]) -> VfsResult<()> {
+    let (fs, path) = resolve(path)?;
+    fs.write(&path, data)
+}
+
+pub fn truncate(path: &str, size: u64) -> VfsResult<()> {
+    let (fs, path) = resolve(path)?;
+    fs.truncate(&path, size)
+}
+
+pub fn allocate(path: &str, offset: u64, len: u64) -> VfsResult<()> {
+    let (fs, path) = resolve(path)?;
+    fs.allocate(&path, offset, len)
+}
+
+pub fn read_dir(path: &str) -> VfsResult<Vec<VfsEntry>> {
+    let (fs, path) = resolve(path)?;
+    fs.read_dir(&path)
+}
+
+pub fn create_dir(path: &str) -> VfsResult<()> {
+    let (fs, path) = resolve(path)?;
+    fs.create_dir(&path)
+}
+
+pub fn remove(path: &str) -> VfsResult<()> {
+    let (fs, path) = resolve(path)?;
+    fs.remove(&path)
+}
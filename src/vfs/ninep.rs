@@ -0,0 +1,507 @@
+//! A 9P2000.L client, for directories the host shares with QEMU's `-virtfs`.
+//!
+//! Requests go out one at a time over a `Channel`, the virtio transport is in
+//! `drivers::virtio::ninep`. Every operation walks a new fid from the root to its path and clunks
+//! it when done, so nothing stays open on the host between calls and nothing is cached.
+use alloc::{string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{split_parent, FileSystem, Metadata, VfsEntry, VfsResult};
+use crate::error::KError;
+use crate::ext::{Errno, FileType};
+
+pub const VERSION: &str = "9P2000.L";
+
+// message types, the response to each is the next number
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const HEADER_SIZE: usize = 7;
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+const ROOT_FID: u32 = 0;
+/// Most names a single walk may contain.
+const MAX_WALK: usize = 16;
+/// Size of the headers of read and write messages, the rest of a message is data.
+const IO_HEADER_SIZE: u32 = 24;
+const QID_SIZE: usize = 13;
+/// Mode, size and everything else `getattr` can return cheaply.
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// flags and modes as on Linux
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_TRUNC: u32 = 0o1000;
+const AT_REMOVEDIR: u32 = 0x200;
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+/// Carries messages to the server.
+pub trait Channel: Send + Sync {
+    /// Size of the largest message in either direction.
+    fn max_message(&self) -> usize;
+    /// Sends `request` and receives the response into `response`, returns its length.
+    fn transact(&self, request: &[u8], response: &mut [u8]) -> VfsResult<usize>;
+}
+
+/// A message being built, the size is filled in by `finish`.
+struct Request(Vec<u8>);
+
+impl Request {
+    fn new(kind: u8) -> Self {
+        Self::with_tag(kind, 0)
+    }
+
+    fn with_tag(kind: u8, tag: u16) -> Self {
+        let mut data = vec![0; 4];
+        data.push(kind);
+        data.extend_from_slice(&tag.to_le_bytes());
+        Self(data)
+    }
+
+    fn kind(&self) -> u8 {
+        self.0[4]
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(self, value: &str) -> Self {
+        let mut request = self.u16(value.len() as u16);
+        request.0.extend_from_slice(value.as_bytes());
+        request
+    }
+
+    fn bytes(mut self, data: &[u8]) -> Self {
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Reads the fields of a response, failing with `UnknownIO` where it's too short.
+struct Response<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> VfsResult<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(Errno::UnknownIO);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> VfsResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> VfsResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> VfsResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> VfsResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> VfsResult<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn skip_qid(&mut self) -> VfsResult<()> {
+        self.take(QID_SIZE).map(drop)
+    }
+}
+
+/// Translates the Linux error number of an `Rlerror`.
+fn errno(code: u32) -> Errno {
+    match KError::from_errno(code as i64) {
+        Some(KError::NotFound) => Errno::NotFound,
+        Some(KError::NotPermitted | KError::AccessDenied) => Errno::AccessError,
+        Some(KError::AlreadyExists) => Errno::AlreadyExists,
+        Some(KError::NotDirectory) => Errno::NotDirectory,
+        Some(KError::IsDirectory) => Errno::IsDirectory,
+        Some(KError::FileTooBig) => Errno::FileTooBig,
+        Some(KError::NoSpace) => Errno::OutOfSpace,
+        Some(KError::ReadOnlyFs) => Errno::ReadOnlyFs,
+        Some(KError::QuotaExceeded) => Errno::QuotaExceeded,
+        Some(KError::NameTooLong) => Errno::NameTooLong,
+        Some(KError::NotImplemented | KError::Unsupported) => Errno::Unsupported,
+        _ => Errno::UnknownIO,
+    }
+}
+
+pub struct NinePFs<C: Channel> {
+    channel: C,
+    msize: u32,
+    next_fid: AtomicU32,
+}
+
+impl<C: Channel> NinePFs<C> {
+    /// Agrees on the protocol version with the server and attaches to the root of its share.
+    pub fn attach(channel: C) -> VfsResult<Self> {
+        let msize = channel.max_message() as u32;
+        let mut fs = Self {
+            channel,
+            msize,
+            next_fid: AtomicU32::new(ROOT_FID + 1),
+        };
+        let request = Request::with_tag(TVERSION, NOTAG).u32(msize).str(VERSION);
+        let body = fs.call(request)?;
+        let mut response = Response::new(&body);
+        fs.msize = response.u32()?.min(msize);
+        if response.str()? != VERSION {
+            return Err(Errno::Unsupported);
+        }
+        // as root, the server decides what that's allowed to do
+        let request = Request::new(TATTACH)
+            .u32(ROOT_FID)
+            .u32(NOFID)
+            .str("root")
+            .str("")
+            .u32(0);
+        fs.call(request)?;
+        Ok(fs)
+    }
+
+    /// Sends a request, returns the body of the response.
+    fn call(&self, request: Request) -> VfsResult<Vec<u8>> {
+        let kind = request.kind();
+        let mut response = vec![0; self.msize as usize];
+        let len = self.channel.transact(&request.finish(), &mut response)?;
+        let mut header = Response::new(&response[..len]);
+        let size = header.u32()? as usize;
+        let response_kind = header.u8()?;
+        if size < HEADER_SIZE || size > len {
+            return Err(Errno::UnknownIO);
+        }
+        let body = &response[HEADER_SIZE..size];
+        match response_kind {
+            RLERROR => Err(errno(Response::new(body).u32()?)),
+            _ if response_kind == kind + 1 => Ok(body.to_vec()),
+            _ => Err(Errno::UnknownIO),
+        }
+    }
+
+    /// Walks a new fid from `from` along `path`.
+    fn walk_from(&self, from: u32, path: &str) -> VfsResult<u32> {
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        let fid = self.next_fid.fetch_add(1, Ordering::Relaxed);
+        let mut chunks: Vec<&[&str]> = names.chunks(MAX_WALK).collect();
+        if chunks.is_empty() {
+            // walking nowhere clones the fid
+            chunks.push(&[]);
+        }
+        let mut from = from;
+        for chunk in chunks {
+            let mut request = Request::new(TWALK)
+                .u32(from)
+                .u32(fid)
+                .u16(chunk.len() as u16);
+            for name in chunk {
+                request = request.str(name);
+            }
+            // a walk stopping early returns fewer qids and doesn't move the fid
+            let result = self.call(request).and_then(|body| {
+                if Response::new(&body).u16()? as usize == chunk.len() {
+                    Ok(())
+                } else {
+                    Err(Errno::NotFound)
+                }
+            });
+            if let Err(e) = result {
+                if from == fid {
+                    self.clunk(fid);
+                }
+                return Err(e);
+            }
+            from = fid;
+        }
+        Ok(fid)
+    }
+
+    /// Runs `f` on a fid for `path`, clunking it afterwards.
+    fn with_fid<T>(&self, path: &str, f: impl FnOnce(u32) -> VfsResult<T>) -> VfsResult<T> {
+        let fid = self.walk_from(ROOT_FID, path)?;
+        let result = f(fid);
+        self.clunk(fid);
+        result
+    }
+
+    fn clunk(&self, fid: u32) {
+        // the fid is gone even if this fails
+        let _ = self.call(Request::new(TCLUNK).u32(fid));
+    }
+
+    fn getattr(&self, fid: u32) -> VfsResult<Metadata> {
+        let body = self.call(Request::new(TGETATTR).u32(fid).u64(GETATTR_BASIC))?;
+        let mut response = Response::new(&body);
+        // valid mask, qid, mode, uid, gid, link count, device, size
+        response.u64()?;
+        response.skip_qid()?;
+        let mode = response.u32()?;
+        response.take(4 + 4 + 8 + 8)?;
+        Ok(Metadata {
+            file_type: FileType::from((mode & 0xf000) as u16),
+            size: response.u64()?,
+        })
+    }
+
+    /// Opens the file `fid` points to, returns the largest read or write to do at once.
+    fn lopen(&self, fid: u32, flags: u32) -> VfsResult<u32> {
+        let body = self.call(Request::new(TLOPEN).u32(fid).u32(flags))?;
+        self.io_size(&body)
+    }
+
+    /// Creates and opens `name` in the directory `fid` points to, which then points to the file.
+    fn lcreate(&self, fid: u32, name: &str, flags: u32) -> VfsResult<u32> {
+        let request = Request::new(TLCREATE)
+            .u32(fid)
+            .str(name)
+            .u32(flags)
+            .u32(FILE_MODE)
+            .u32(0);
+        let body = self.call(request)?;
+        self.io_size(&body)
+    }
+
+    /// Reads the I/O unit of an `Rlopen` or `Rlcreate`, limited to what fits into a message.
+    fn io_size(&self, body: &[u8]) -> VfsResult<u32> {
+        let mut response = Response::new(body);
+        response.skip_qid()?;
+        let max = self.msize - IO_HEADER_SIZE;
+        Ok(match response.u32()? {
+            0 => max,
+            iounit => iounit.min(max),
+        })
+    }
+
+    fn write_all(&self, fid: u32, count: u32, data: &[u8]) -> VfsResult<()> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let chunk = &data[offset..data.len().min(offset + count as usize)];
+            let request = Request::new(TWRITE)
+                .u32(fid)
+                .u64(offset as u64)
+                .u32(chunk.len() as u32)
+                .bytes(chunk);
+            match Response::new(&self.call(request)?).u32()? {
+                0 => return Err(Errno::OutOfSpace),
+                written => offset += written as usize,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C: Channel> FileSystem for NinePFs<C> {
+    fn name(&self) -> &str {
+        "9p"
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<Metadata> {
+        self.with_fid(path, |fid| self.getattr(fid))
+    }
+
+    fn read(&self, path: &str) -> VfsResult<Vec<u8>> {
+        self.with_fid(path, |fid| {
+            let count = self.lopen(fid, O_RDONLY)?;
+            let mut data = Vec::new();
+            loop {
+                let request = Request::new(TREAD)
+                    .u32(fid)
+                    .u64(data.len() as u64)
+                    .u32(count);
+                let body = self.call(request)?;
+                let mut response = Response::new(&body);
+                let len = response.u32()? as usize;
+                if len == 0 {
+                    return Ok(data);
+                }
+                data.extend_from_slice(response.take(len)?);
+            }
+        })
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> VfsResult<()> {
+        let (fid, opened) = match self.walk_from(ROOT_FID, path) {
+            Ok(fid) => (fid, self.lopen(fid, O_WRONLY | O_TRUNC)),
+            Err(Errno::NotFound) => {
+                let (parent, name) = split_parent(path);
+                let fid = self.walk_from(ROOT_FID, parent)?;
+                (fid, self.lcreate(fid, name, O_WRONLY | O_TRUNC))
+            }
+            Err(e) => return Err(e),
+        };
+        let result = opened.and_then(|count| self.write_all(fid, count, data));
+        self.clunk(fid);
+        result
+    }
+
+    fn read_dir(&self, path: &str) -> VfsResult<Vec<VfsEntry>> {
+        self.with_fid(path, |fid| {
+            let count = self.lopen(fid, O_RDONLY)?;
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            loop {
+                let request = Request::new(TREADDIR).u32(fid).u64(offset).u32(count);
+                let body = self.call(request)?;
+                let mut response = Response::new(&body);
+                let len = response.u32()? as usize;
+                if len == 0 {
+                    return Ok(entries);
+                }
+                let mut response = Response::new(response.take(len)?);
+                while !response.is_empty() {
+                    // qid, offset of the next entry, type, name
+                    response.skip_qid()?;
+                    offset = response.u64()?;
+                    response.u8()?;
+                    let name = response.str()?;
+                    if name == "." || name == ".." {
+                        continue;
+                    }
+                    let child = self.walk_from(fid, &name)?;
+                    let metadata = self.getattr(child);
+                    self.clunk(child);
+                    entries.push(VfsEntry {
+                        name,
+                        metadata: metadata?,
+                    });
+                }
+            }
+        })
+    }
+
+    fn create_dir(&self, path: &str) -> VfsResult<()> {
+        let (parent, name) = split_parent(path);
+        self.with_fid(parent, |fid| {
+            let request = Request::new(TMKDIR).u32(fid).str(name).u32(DIR_MODE).u32(0);
+            self.call(request).map(drop)
+        })
+    }
+
+    fn remove(&self, path: &str) -> VfsResult<()> {
+        if path == "/" {
+            return Err(Errno::AccessError);
+        }
+        let flags = match self.metadata(path)?.file_type {
+            FileType::Directory => AT_REMOVEDIR,
+            _ => 0,
+        };
+        let (parent, name) = split_parent(path);
+        self.with_fid(parent, |fid| {
+            let request = Request::new(TUNLINKAT).u32(fid).str(name).u32(flags);
+            self.call(request).map(drop)
+        })
+    }
+}
+
+/// Answers requests with canned responses and keeps the requests.
+#[cfg(test)]
+struct Script {
+    responses: spin::Mutex<Vec<Vec<u8>>>,
+    requests: spin::Mutex<Vec<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl Channel for Script {
+    fn max_message(&self) -> usize {
+        4096
+    }
+
+    fn transact(&self, request: &[u8], response: &mut [u8]) -> VfsResult<usize> {
+        self.requests.lock().push(request.to_vec());
+        let next = self.responses.lock().remove(0);
+        response[..next.len()].copy_from_slice(&next);
+        Ok(next.len())
+    }
+}
+
+#[test_case]
+fn test_attach_and_stat() {
+    let reply = |kind: u8| Request::new(kind + 1);
+    let getattr = reply(TGETATTR)
+        .u64(GETATTR_BASIC)
+        .bytes(&[0; QID_SIZE])
+        .u32(0o100644)
+        .bytes(&[0; 4 + 4 + 8 + 8])
+        .u64(1234);
+    let responses = vec![
+        Request::with_tag(TVERSION + 1, NOTAG)
+            .u32(8192)
+            .str(VERSION)
+            .finish(),
+        reply(TATTACH).bytes(&[0; QID_SIZE]).finish(),
+        reply(TWALK).u16(2).bytes(&[0; 2 * QID_SIZE]).finish(),
+        getattr.finish(),
+        reply(TCLUNK).finish(),
+        // the second name doesn't exist
+        reply(TWALK).u16(1).bytes(&[0; QID_SIZE]).finish(),
+        Request::new(RLERROR).u32(2).finish(),
+    ];
+    let script = Script {
+        responses: spin::Mutex::new(responses),
+        requests: spin::Mutex::new(Vec::new()),
+    };
+    let fs = NinePFs::attach(script).unwrap();
+    assert_eq!(fs.msize, 4096);
+
+    let metadata = fs.metadata("/etc/motd").unwrap();
+    assert_eq!(metadata.file_type, FileType::RegularFile);
+    assert_eq!(metadata.size, 1234);
+    let walk = Request::new(TWALK)
+        .u32(ROOT_FID)
+        .u32(1)
+        .u16(2)
+        .str("etc")
+        .str("motd")
+        .finish();
+    assert_eq!(fs.channel.requests.lock()[2], walk);
+
+    assert!(matches!(fs.metadata("/etc/nope"), Err(Errno::NotFound)));
+    assert!(matches!(fs.read("/"), Err(Errno::NotFound)));
+}
@@ -0,0 +1,156 @@
+//! File system of generated files describing the kernel, mounted on `/proc`.
+//!
+//! Every read generates the contents anew. Nothing can be written. Besides the files in the
+//! root, every task has a directory named after its id with files describing it, and `net` holds
+//! the state of the network stack.
+use alloc::{format, string::String, vec::Vec};
+
+use super::{FileSystem, Metadata, VfsEntry, VfsResult};
+use crate::ext::{Errno, FileType};
+use crate::task::{self, TaskId};
+use crate::{bootreport, cpufreq, interrupts, net::stats, sysconf, thermal};
+
+/// The files in the root of the file system and the functions generating them.
+const FILES: &[(&str, fn() -> String)] = &[
+    ("bootinfo", bootreport::report),
+    ("cpufreq", cpufreq::cpufreq_file),
+    ("hostname", sysconf::hostname_file),
+    ("interrupts", interrupts::interrupts_file),
+    ("stat", task::stat_file),
+    ("thermal", thermal::thermal_file),
+];
+
+/// The files in `net`.
+const NET_FILES: &[(&str, fn() -> String)] = &[
+    ("arp", stats::arp_file),
+    ("dev", stats::dev_file),
+    ("raw", stats::raw_file),
+    ("snmp", stats::snmp_file),
+    ("tcp", stats::tcp_file),
+    ("udp", stats::udp_file),
+];
+
+/// The files in the directory of every task.
+const TASK_FILES: &[(&str, fn(TaskId) -> Option<String>)] = &[("stat", task::stat_file_of)];
+
+pub struct ProcFs;
+
+enum Node {
+    Root,
+    Net,
+    Task(TaskId),
+    File(String),
+}
+
+fn find(path: &str) -> VfsResult<Node> {
+    let path = path.strip_prefix('/').ok_or(Errno::NotFound)?;
+    if path.is_empty() {
+        return Ok(Node::Root);
+    }
+    if let Some((_, generate)) = FILES.iter().find(|(file, _)| *file == path) {
+        return Ok(Node::File(generate()));
+    }
+    let (dir, file) = path.split_once('/').unwrap_or((path, ""));
+    if dir == "net" {
+        if file.is_empty() {
+            return Ok(Node::Net);
+        }
+        return NET_FILES
+            .iter()
+            .find(|(name, _)| *name == file)
+            .map(|(_, generate)| Node::File(generate()))
+            .ok_or(Errno::NotFound);
+    }
+    let id = dir.parse().map(TaskId).map_err(|_| Errno::NotFound)?;
+    if task::state_of(id).is_none() {
+        return Err(Errno::NotFound);
+    }
+    if file.is_empty() {
+        return Ok(Node::Task(id));
+    }
+    TASK_FILES
+        .iter()
+        .find(|(name, _)| *name == file)
+        .and_then(|(_, generate)| generate(id))
+        .map(Node::File)
+        .ok_or(Errno::NotFound)
+}
+
+fn file_entry(name: &str, contents: &str) -> VfsEntry {
+    VfsEntry {
+        name: String::from(name),
+        metadata: Metadata {
+            file_type: FileType::RegularFile,
+            size: contents.len() as u64,
+        },
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &str {
+        "procfs"
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<Metadata> {
+        Ok(match find(path)? {
+            Node::Root | Node::Net | Node::Task(_) => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+            Node::File(contents) => Metadata {
+                file_type: FileType::RegularFile,
+                size: contents.len() as u64,
+            },
+        })
+    }
+
+    fn read(&self, path: &str) -> VfsResult<Vec<u8>> {
+        match find(path)? {
+            Node::File(contents) => Ok(contents.into_bytes()),
+            _ => Err(Errno::IsDirectory),
+        }
+    }
+
+    fn write(&self, _: &str, _: &[u8]) -> VfsResult<()> {
+        Err(Errno::AccessError)
+    }
+
+    fn read_dir(&self, path: &str) -> VfsResult<Vec<VfsEntry>> {
+        match find(path)? {
+            Node::Root => {
+                let files = FILES
+                    .iter()
+                    .map(|(name, generate)| file_entry(name, &generate()));
+                let dirs = task::list()
+                    .into_iter()
+                    .map(|task| format!("{}", task.id.0))
+                    .chain([String::from("net")])
+                    .map(|name| VfsEntry {
+                        name,
+                        metadata: Metadata {
+                            file_type: FileType::Directory,
+                            size: 0,
+                        },
+                    });
+                Ok(files.chain(dirs).collect())
+            }
+            Node::Net => Ok(NET_FILES
+                .iter()
+                .map(|(name, generate)| file_entry(name, &generate()))
+                .collect()),
+            Node::Task(id) => Ok(TASK_FILES
+                .iter()
+                .filter_map(|(name, generate)| Some(file_entry(name, &generate(id)?)))
+                .collect()),
+            Node::File(_) => Err(Errno::NotDirectory),
+        }
+    }
+
+    fn create_dir(&self, _: &str) -> VfsResult<()> {
+        Err(Errno::AccessError)
+    }
+
+    fn remove(&self, _: &str) -> VfsResult<()> {
+        Err(Errno::AccessError)
+    }
+}
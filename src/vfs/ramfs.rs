@@ -0,0 +1,154 @@
+//! File system kept entirely in memory.
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::ops::Bound;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{split_parent, FileSystem, Metadata, VfsEntry, VfsResult};
+use crate::ext::{Errno, FileType};
+
+enum Node {
+    File(Vec<u8>),
+    Directory,
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Self::File(data) => Metadata {
+                file_type: FileType::RegularFile,
+                size: data.len() as u64,
+            },
+            Self::Directory => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+        }
+    }
+}
+
+/// Nodes are stored by their full path, so a directory listing is a range scan.
+pub struct RamFs {
+    nodes: Mutex<BTreeMap<String, Node>>,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::from("/"), Node::Directory);
+        Self {
+            nodes: Mutex::new(nodes),
+        }
+    }
+
+    fn with_nodes<R>(&self, f: impl FnOnce(&mut BTreeMap<String, Node>) -> R) -> R {
+        without_interrupts(|| f(&mut self.nodes.lock()))
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn check_parent(nodes: &BTreeMap<String, Node>, path: &str) -> VfsResult<()> {
+    let (parent, name) = split_parent(path);
+    if name.is_empty() {
+        return Err(Errno::StringEmpty);
+    }
+    match nodes.get(parent) {
+        Some(Node::Directory) => Ok(()),
+        Some(Node::File(_)) => Err(Errno::NotDirectory),
+        None => Err(Errno::NotFound),
+    }
+}
+
+/// Returns whether `path` is a direct child of the directory `dir`.
+fn is_child(dir: &str, path: &str) -> bool {
+    path != dir && split_parent(path).0 == dir
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &str {
+        "ramfs"
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<Metadata> {
+        self.with_nodes(|nodes| nodes.get(path).map(Node::metadata).ok_or(Errno::NotFound))
+    }
+
+    fn read(&self, path: &str) -> VfsResult<Vec<u8>> {
+        self.with_nodes(|nodes| match nodes.get(path) {
+            Some(Node::File(data)) => Ok(data.clone()),
+            Some(Node::Directory) => Err(Errno::IsDirectory),
+            None => Err(Errno::NotFound),
+        })
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> VfsResult<()> {
+        self.with_nodes(|nodes| match nodes.get_mut(path) {
+            Some(Node::File(contents)) => {
+                contents.clear();
+                contents.extend_from_slice(data);
+                Ok(())
+            }
+            Some(Node::Directory) => Err(Errno::IsDirectory),
+            None => {
+                check_parent(nodes, path)?;
+                nodes.insert(String::from(path), Node::File(Vec::from(data)));
+                Ok(())
+            }
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> VfsResult<Vec<VfsEntry>> {
+        self.with_nodes(|nodes| {
+            match nodes.get(path) {
+                Some(Node::Directory) => {}
+                Some(Node::File(_)) => return Err(Errno::NotDirectory),
+                None => return Err(Errno::NotFound),
+            }
+            Ok(nodes
+                .range::<str, _>((Bound::Included(path), Bound::Unbounded))
+                .skip(1)
+                .take_while(|(child, _)| child.starts_with(path))
+                .filter(|(child, _)| is_child(path, child))
+                .map(|(child, node)| VfsEntry {
+                    name: String::from(split_parent(child).1),
+                    metadata: node.metadata(),
+                })
+                .collect())
+        })
+    }
+
+    fn create_dir(&self, path: &str) -> VfsResult<()> {
+        self.with_nodes(|nodes| {
+            if nodes.contains_key(path) {
+                return Err(Errno::AlreadyExists);
+            }
+            check_parent(nodes, path)?;
+            nodes.insert(String::from(path), Node::Directory);
+            Ok(())
+        })
+    }
+
+    fn remove(&self, path: &str) -> VfsResult<()> {
+        self.with_nodes(|nodes| {
+            if path == "/" {
+                return Err(Errno::AccessError);
+            }
+            match nodes.get(path) {
+                Some(Node::Directory) => {
+                    if nodes.keys().any(|child| is_child(path, child)) {
+                        return Err(Errno::AccessError);
+                    }
+                }
+                Some(Node::File(_)) => {}
+                None => return Err(Errno::NotFound),
+            }
+            nodes.remove(path);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,126 @@
+//! File system showing the device tree of `device`, mounted on `/sys`.
+//!
+//! `devices` holds a directory for every device at the top of the tree. The directory of a
+//! device holds a file for each of its attributes, like `vendor` or `state`, and the
+//! directories of the devices below it. Like `/proc`, everything is generated on every access
+//! and nothing can be written.
+use alloc::{format, string::String, vec::Vec};
+
+use super::{FileSystem, Metadata, VfsEntry, VfsResult};
+use crate::device::{self, Device};
+use crate::ext::{Errno, FileType};
+
+pub struct SysFs;
+
+enum Node {
+    Root,
+    Devices,
+    Device(Device),
+    File(String),
+}
+
+fn find(path: &str) -> VfsResult<Node> {
+    let path = path.strip_prefix('/').ok_or(Errno::NotFound)?;
+    let mut parts = path.split('/').filter(|part| !part.is_empty());
+    match parts.next() {
+        None => return Ok(Node::Root),
+        Some("devices") => {}
+        Some(_) => return Err(Errno::NotFound),
+    }
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, dirs)) = parts.split_last() else {
+        return Ok(Node::Devices);
+    };
+    if let Some(device) = device::lookup(parts.iter().copied()) {
+        return Ok(Node::Device(device));
+    }
+    // the last part is an attribute of the device before it
+    let device = match dirs {
+        [] => None,
+        dirs => device::lookup(dirs.iter().copied()),
+    };
+    device
+        .and_then(|device| attribute(&device, last))
+        .map(Node::File)
+        .ok_or(Errno::NotFound)
+}
+
+fn attribute(device: &Device, name: &str) -> Option<String> {
+    device
+        .all_attributes()
+        .into_iter()
+        .find(|(attribute, _)| *attribute == name)
+        .map(|(_, value)| format!("{value}\n"))
+}
+
+fn dir_entry(name: String) -> VfsEntry {
+    VfsEntry {
+        name,
+        metadata: Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        },
+    }
+}
+
+/// The attributes of `device` and the devices below it, or the top devices.
+fn device_entries(device: Option<&Device>) -> Vec<VfsEntry> {
+    let attributes = device.map(Device::all_attributes).unwrap_or_default();
+    let files = attributes.into_iter().map(|(name, value)| VfsEntry {
+        name: String::from(name),
+        metadata: Metadata {
+            file_type: FileType::RegularFile,
+            size: value.len() as u64 + 1,
+        },
+    });
+    let children = device::children(device.map(|device| device.id));
+    let dirs = children.into_iter().map(|child| dir_entry(child.name));
+    files.chain(dirs).collect()
+}
+
+impl FileSystem for SysFs {
+    fn name(&self) -> &str {
+        "sysfs"
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<Metadata> {
+        Ok(match find(path)? {
+            Node::Root | Node::Devices | Node::Device(_) => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+            Node::File(contents) => Metadata {
+                file_type: FileType::RegularFile,
+                size: contents.len() as u64,
+            },
+        })
+    }
+
+    fn read(&self, path: &str) -> VfsResult<Vec<u8>> {
+        match find(path)? {
+            Node::File(contents) => Ok(contents.into_bytes()),
+            _ => Err(Errno::IsDirectory),
+        }
+    }
+
+    fn write(&self, _: &str, _: &[u8]) -> VfsResult<()> {
+        Err(Errno::AccessError)
+    }
+
+    fn read_dir(&self, path: &str) -> VfsResult<Vec<VfsEntry>> {
+        match find(path)? {
+            Node::Root => Ok(Vec::from([dir_entry(String::from("devices"))])),
+            Node::Devices => Ok(device_entries(None)),
+            Node::Device(device) => Ok(device_entries(Some(&device))),
+            Node::File(_) => Err(Errno::NotDirectory),
+        }
+    }
+
+    fn create_dir(&self, _: &str) -> VfsResult<()> {
+        Err(Errno::AccessError)
+    }
+
+    fn remove(&self, _: &str) -> VfsResult<()> {
+        Err(Errno::AccessError)
+    }
+}
@@ -1,12 +1,17 @@
+use alloc::vec::Vec;
 use core::fmt::{Arguments, Result, Write};
 
 use core::iter::Iterator;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
 use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::{config, debugcon, early_console, font, theme};
+use crate::drivers::speaker;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -29,14 +34,57 @@ pub enum Color {
     White = 15,
 }
 
+const COLOR_NAMES: [(Color, &str); 16] = [
+    (Color::Black, "black"),
+    (Color::Blue, "blue"),
+    (Color::Green, "green"),
+    (Color::Cyan, "cyan"),
+    (Color::Red, "red"),
+    (Color::Magenta, "magenta"),
+    (Color::Brown, "brown"),
+    (Color::LightGray, "lightgray"),
+    (Color::DarkGray, "darkgray"),
+    (Color::LightBlue, "lightblue"),
+    (Color::LightGreen, "lightgreen"),
+    (Color::LightCyan, "lightcyan"),
+    (Color::LightRed, "lightred"),
+    (Color::Pink, "pink"),
+    (Color::Yellow, "yellow"),
+    (Color::White, "white"),
+];
+
+impl Color {
+    /// Returns the color with the low 4 bits of `value` as its number.
+    pub fn from_u8(value: u8) -> Self {
+        COLOR_NAMES[(value & 0xf) as usize].0
+    }
+
+    /// Parses a color name like `lightblue`, case insensitive.
+    pub fn parse(name: &str) -> Option<Self> {
+        COLOR_NAMES
+            .iter()
+            .find(|(_, color)| color.eq_ignore_ascii_case(name))
+            .map(|(color, _)| *color)
+    }
+
+    pub fn name(self) -> &'static str {
+        COLOR_NAMES[self as usize].1
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(fg: Color, bg: Color) -> Self {
+    pub const fn new(fg: Color, bg: Color) -> Self {
         Self((bg as u8) << 4 | fg as u8)
     }
+
+    /// Returns the attribute byte as stored in video memory.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -46,14 +94,106 @@ struct ScreenChar {
     color: ColorCode,
 }
 
+impl ScreenChar {
+    /// In 512 glyph mode, bit 3 of the attribute selects glyphs 256 to 511.
+    fn new(glyph: u16, color: ColorCode) -> Self {
+        let color = if font::is_512() {
+            ColorCode(color.0 & !0x08 | ((glyph >> 8) as u8 & 1) << 3)
+        } else {
+            color
+        };
+        Self {
+            ascii_character: glyph as u8,
+            color,
+        }
+    }
+}
+
 pub const BUFFER_HEIGHT: usize = 25;
 pub const BUFFER_WIDTH: usize = 80;
 
+/// Whether printing `\x07` beeps the pc speaker.
+static BELL_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Line style used by `draw_box`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxStyle {
+    Single,
+    Double,
+    /// solid block characters
+    Block,
+}
+
+impl BoxStyle {
+    /// Returns the horizontal, vertical, top left, top right, bottom left and bottom right chars.
+    fn chars(self) -> [char; 6] {
+        match self {
+            Self::Single => ['─', '│', '┌', '┐', '└', '┘'],
+            Self::Double => ['═', '║', '╔', '╗', '╚', '╝'],
+            Self::Block => ['█'; 6],
+        }
+    }
+}
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Saved screen contents, see `Writer::snapshot`.
+pub struct Snapshot {
+    chars: Vec<ScreenChar>,
+    row_pos: usize,
+    column_pos: usize,
+}
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"SCRN";
+
+impl Snapshot {
+    /// Serializes the snapshot: the magic `SCRN`, then width, height, cursor column and cursor
+    /// row as one byte each, then a glyph and an attribute byte for every cell, row by row,
+    /// like in video memory.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.chars.len() * 2);
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&[
+            BUFFER_WIDTH as u8,
+            BUFFER_HEIGHT as u8,
+            self.column_pos as u8,
+            self.row_pos as u8,
+        ]);
+        for char in &self.chars {
+            bytes.extend_from_slice(&[char.ascii_character, char.color.0]);
+        }
+        bytes
+    }
+
+    /// Parses what `to_bytes` produced, `None` if it isn't a snapshot of this screen size.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let &[width, height, column, row] = bytes.strip_prefix(SNAPSHOT_MAGIC)?.get(..4)? else {
+            return None;
+        };
+        let cells = bytes.get(8..)?;
+        if (width as usize, height as usize) != (BUFFER_WIDTH, BUFFER_HEIGHT)
+            || cells.len() != BUFFER_WIDTH * BUFFER_HEIGHT * 2
+        {
+            return None;
+        }
+        let chars = cells
+            .chunks_exact(2)
+            .map(|cell| ScreenChar {
+                ascii_character: cell[0],
+                color: ColorCode(cell[1]),
+            })
+            .collect();
+        Some(Self {
+            chars,
+            row_pos: (row as usize).min(BUFFER_HEIGHT - 1),
+            column_pos: (column as usize).min(BUFFER_WIDTH - 1),
+        })
+    }
+}
+
 pub struct Writer {
     column_pos: usize,
     cur_color: ColorCode,
@@ -63,7 +203,7 @@ pub struct Writer {
 
 impl Writer {
     fn clear(&mut self) {
-        let blank = ColorCode::new(Color::White, Color::Black);
+        let blank = theme::current().normal();
         for row in 0..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 self.buffer.chars[row][col].write(ScreenChar { ascii_character: b' ', color: blank });
@@ -78,16 +218,21 @@ impl Writer {
     }
 
     pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            b'\t' => self.column_pos += 4,
+        self.write_glyph(byte as u16);
+    }
+
+    /// Like `write_byte`, but with glyphs above 255 in 512 glyph mode.
+    fn write_glyph(&mut self, glyph: u16) {
+        match glyph {
+            0x0a /* \n */ => self.new_line(),
+            0x09 /* \t */ => self.column_pos += 4,
             0x8 /* \b (backspace) */ => {
                 if self.column_pos > 0 {
                     self.column_pos -= 1;
                     self.buffer.chars[self.row_pos][self.column_pos].write(ScreenChar { ascii_character: b' ', color: self.cur_color });
                 }
             }
-            byte => {
+            glyph => {
                 if self.column_pos >= BUFFER_WIDTH {
                     self.new_line();
                 }
@@ -95,11 +240,7 @@ impl Writer {
                 let row = self.row_pos;
                 let col = self.column_pos;
                 
-                let color = self.cur_color;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color,
-                });
+                self.buffer.chars[row][col].write(ScreenChar::new(glyph, self.cur_color));
                 self.column_pos += 1;
             }
         }
@@ -133,26 +274,113 @@ impl Writer {
         }
     }
 
+    fn put(&mut self, row: usize, col: usize, char: char) {
+        self.put_colored(row, col, char, self.cur_color);
+    }
+
+    fn put_colored(&mut self, row: usize, col: usize, char: char, color: ColorCode) {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(ScreenChar::new(font::glyph(char), color));
+        }
+    }
+
+    /// Like `write_at`, but in `color` instead of the current color.
+    pub fn write_at_colored(&mut self, row: usize, col: usize, str: &str, color: ColorCode) {
+        for (i, char) in str.chars().enumerate() {
+            self.put_colored(row, col + i, char, color);
+        }
+    }
+
+    /// Copies the screen contents and cursor position, to be put back with `restore`.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut chars = Vec::with_capacity(BUFFER_WIDTH * BUFFER_HEIGHT);
+        for row in self.buffer.chars.iter() {
+            chars.extend(row.iter().map(|char| char.read()));
+        }
+        Snapshot {
+            chars,
+            row_pos: self.row_pos,
+            column_pos: self.column_pos,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        for (i, char) in snapshot.chars.iter().enumerate() {
+            self.buffer.chars[i / BUFFER_WIDTH][i % BUFFER_WIDTH].write(*char);
+        }
+        self.row_pos = snapshot.row_pos;
+        self.column_pos = snapshot.column_pos;
+        set_cursor(self.column_pos, self.row_pos);
+    }
+
+    /// Writes `str` starting at `row`/`col` without moving the cursor or scrolling.
+    ///
+    /// Control characters aren't interpreted and output past the end of the row is cut off.
+    pub fn write_at(&mut self, row: usize, col: usize, str: &str) {
+        for (i, char) in str.chars().enumerate() {
+            self.put(row, col + i, char);
+        }
+    }
+
+    /// Draws the outline of a `width` by `height` box with its top left corner at `x`/`y`.
+    ///
+    /// Parts outside the screen are clipped. The cursor isn't moved.
+    pub fn draw_box(&mut self, x: usize, y: usize, width: usize, height: usize, style: BoxStyle) {
+        if width < 2 || height < 2 {
+            return;
+        }
+        let [horizontal, vertical, top_left, top_right, bottom_left, bottom_right] = style.chars();
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        for col in x + 1..right {
+            self.put(y, col, horizontal);
+            self.put(bottom, col, horizontal);
+        }
+        for row in y + 1..bottom {
+            self.put(row, x, vertical);
+            self.put(row, right, vertical);
+        }
+        self.put(y, x, top_left);
+        self.put(y, right, top_right);
+        self.put(bottom, x, bottom_left);
+        self.put(bottom, right, bottom_right);
+    }
+
     pub fn write_str(&mut self, str: &str) {
-        for byte in str.chars().map(|char| transform_char(char)) {
-            self.write_byte(byte);
+        for char in str.chars() {
+            if char == '\x07' {
+                if BELL_ENABLED.load(Ordering::Relaxed) {
+                    speaker::beep(config::get().bell_frequency, config::BELL_DURATION);
+                }
+                continue;
+            }
+            self.write_glyph(font::glyph(char));
         }
     }
 }
 
 lazy_static! {
+    /// Continues where the early console left the cursor.
     pub static ref WRITER: Mutex<Writer> = Mutex::new({
-        let mut writer = Writer {
-            column_pos: 0,
-            row_pos: 0,
-            cur_color: ColorCode::new(Color::White, Color::Black),
+        let pos = cursor_position().min(BUFFER_WIDTH * BUFFER_HEIGHT - 1);
+        Writer {
+            column_pos: pos % BUFFER_WIDTH,
+            row_pos: pos / BUFFER_WIDTH,
+            cur_color: theme::current().normal(),
             buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-        };
-        writer.clear();
-        writer
+        }
     });
 }
 
+/// Set once `WRITER` took over from the early console.
+static WRITER_READY: AtomicBool = AtomicBool::new(false);
+
+/// Switches `print!` from the early console to `WRITER`.
+pub fn init() {
+    BELL_ENABLED.store(config::get().bell, Ordering::Relaxed);
+    interrupts::without_interrupts(|| drop(WRITER.lock()));
+    WRITER_READY.store(true, Ordering::Release);
+}
+
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> Result {
         self.write_str(s);
@@ -171,9 +399,176 @@ macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
 }
 
+/// Prints to the screen in an emergency (NMI, double fault), bypassing `WRITER`.
+#[macro_export]
+macro_rules! emergency_println {
+    ($($arg:tt)*) => (
+        $crate::vga_buffer::_print_emergency(format_args!("{}\n", format_args!($($arg)*)))
+    );
+}
+
 #[doc(hidden)]
 pub fn _print(args: Arguments) {
-    interrupts::without_interrupts(|| WRITER.lock().write_fmt(args).unwrap());
+    if !WRITER_READY.load(Ordering::Acquire) {
+        return early_console::_print(args);
+    }
+    interrupts::without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => {
+            writer.flush_pending();
+            writer.write_fmt(args).unwrap();
+        }
+        // Only code we interrupted can hold the writer, waiting for it would deadlock.
+        None => {
+            let _ = PendingWriter.write_fmt(args);
+        }
+    });
+    debugcon::_print(args);
+}
+
+const PENDING_SIZE: usize = 1024;
+
+/// Output that couldn't be printed because the writer was held, already translated to
+/// glyphs. Filled without locks and printed by the next `_print` that gets the writer; there
+/// is a single CPU, so everything written is complete once the interrupted holder runs again.
+static PENDING: [AtomicU16; PENDING_SIZE] = [const { AtomicU16::new(0) }; PENDING_SIZE];
+/// total number of glyphs ever put into `PENDING`
+static PENDING_WRITTEN: AtomicUsize = AtomicUsize::new(0);
+/// total number of glyphs printed from `PENDING`
+static PENDING_READ: AtomicUsize = AtomicUsize::new(0);
+
+struct PendingWriter;
+
+impl Write for PendingWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        for char in s.chars().filter(|char| *char != '\x07') {
+            let pos = PENDING_WRITTEN.fetch_add(1, Ordering::Relaxed);
+            PENDING[pos % PENDING_SIZE].store(font::glyph(char), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+impl Writer {
+    /// Prints the output queued while the writer was held, the oldest is lost if more than
+    /// `PENDING_SIZE` glyphs piled up.
+    fn flush_pending(&mut self) {
+        let end = PENDING_WRITTEN.load(Ordering::Relaxed);
+        let start = PENDING_READ.load(Ordering::Relaxed);
+        if start == end {
+            return;
+        }
+        for pos in start.max(end.saturating_sub(PENDING_SIZE))..end {
+            self.write_glyph(PENDING[pos % PENDING_SIZE].load(Ordering::Relaxed));
+        }
+        PENDING_READ.store(end, Ordering::Relaxed);
+    }
+}
+
+/// Writes straight to video memory at the hardware cursor, in the given color.
+pub(crate) struct DirectWriter(pub ColorCode);
+
+impl Write for DirectWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        let buffer = 0xb8000 as *mut ScreenChar;
+        let color = self.0;
+        let mut pos = cursor_position().min(BUFFER_WIDTH * BUFFER_HEIGHT - 1);
+        for char in s.chars() {
+            if char == '\n' {
+                pos += BUFFER_WIDTH - pos % BUFFER_WIDTH;
+            } else {
+                let screen_char = ScreenChar::new(font::glyph(char), color);
+                unsafe { buffer.add(pos).write_volatile(screen_char) };
+                pos += 1;
+            }
+            if pos >= BUFFER_WIDTH * BUFFER_HEIGHT {
+                unsafe {
+                    let screen = BUFFER_WIDTH * BUFFER_HEIGHT;
+                    for i in BUFFER_WIDTH..screen {
+                        buffer.add(i - BUFFER_WIDTH).write_volatile(buffer.add(i).read_volatile());
+                    }
+                    let blank = ScreenChar { ascii_character: b' ', color };
+                    for i in screen - BUFFER_WIDTH..screen {
+                        buffer.add(i).write_volatile(blank);
+                    }
+                }
+                pos -= BUFFER_WIDTH;
+            }
+        }
+        set_cursor(pos % BUFFER_WIDTH, pos / BUFFER_WIDTH);
+        Ok(())
+    }
+}
+
+/// Prints without taking any lock, for code that may have interrupted a holder of `WRITER`
+/// and can't wait for it, like NMI handlers. The writer's position isn't updated, later output
+/// may overwrite this.
+#[doc(hidden)]
+pub fn _print_emergency(args: Arguments) {
+    let _ = DirectWriter(theme::current().error_color()).write_fmt(args);
+    debugcon::_print(args);
+}
+
+/// Releases `WRITER` if it is held, so a panic can still print. The holder never runs again.
+pub fn unlock_for_panic() {
+    if WRITER.try_lock().is_none() {
+        unsafe { WRITER.force_unlock() };
+    }
+}
+
+/// Prints a line in the theme's error color.
+#[macro_export]
+macro_rules! print_error {
+    ($($arg:tt)*) => ($crate::vga_buffer::_print_colored(
+        $crate::theme::current().error_color(),
+        format_args!("{}\n", format_args!($($arg)*)),
+    ));
+}
+
+/// Prints a line in the theme's success color.
+#[macro_export]
+macro_rules! print_ok {
+    ($($arg:tt)*) => ($crate::vga_buffer::_print_colored(
+        $crate::theme::current().ok_color(),
+        format_args!("{}\n", format_args!($($arg)*)),
+    ));
+}
+
+/// Prints in `color`, then goes back to the current color.
+#[doc(hidden)]
+pub fn _print_colored(color: ColorCode, args: Arguments) {
+    if !WRITER_READY.load(Ordering::Acquire) {
+        return early_console::_print(args);
+    }
+    interrupts::without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => {
+            writer.flush_pending();
+            let previous = writer.cur_color;
+            writer.cur_color = color;
+            writer.write_fmt(args).unwrap();
+            writer.cur_color = previous;
+        }
+        None => {
+            let _ = PendingWriter.write_fmt(args);
+        }
+    });
+    debugcon::_print(args);
+}
+
+/// Replaces the color `old` with `new` on the whole screen and for further output.
+pub fn recolor(old: ColorCode, new: ColorCode) {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for row in writer.buffer.chars.iter_mut() {
+            for char in row.iter_mut() {
+                let mut screen_char = char.read();
+                if screen_char.color == old {
+                    screen_char.color = new;
+                    char.write(screen_char);
+                }
+            }
+        }
+        writer.cur_color = new;
+    });
 }
 
 pub fn set_color(new_color: ColorCode) {
@@ -196,6 +591,31 @@ pub fn set_bg(new_color: Color) {
     });
 }
 
+pub fn write_at(row: usize, col: usize, str: &str) {
+    interrupts::without_interrupts(|| WRITER.lock().write_at(row, col, str));
+}
+
+pub fn draw_box(x: usize, y: usize, width: usize, height: usize, style: BoxStyle) {
+    interrupts::without_interrupts(|| WRITER.lock().draw_box(x, y, width, height, style));
+}
+
+/// Shows `text` highlighted across the top row, for the progress of long running commands.
+/// Output scrolling up overwrites it like any other line.
+pub fn set_status(text: &str) {
+    let color = ColorCode::new(Color::Black, Color::LightGray);
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let chars = text.chars().chain(core::iter::repeat(' '));
+        for (col, char) in chars.take(BUFFER_WIDTH).enumerate() {
+            writer.put_colored(0, col, char, color);
+        }
+    });
+}
+
+pub fn set_bell(enabled: bool) {
+    BELL_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
 pub fn disable_cursor() {
     unsafe {
         Port::new(0x3d4).write(0x0a_u8);
@@ -218,6 +638,19 @@ pub fn enable_cursor() {
     }
 }
 
+/// Returns the hardware cursor's offset into the screen.
+fn cursor_position() -> usize {
+    let mut porta = Port::new(0x3d4);
+    let mut portb = Port::<u8>::new(0x3d5);
+    unsafe {
+        porta.write(0x0f_u8);
+        let low = portb.read() as usize;
+        porta.write(0x0e_u8);
+        let high = portb.read() as usize;
+        high << 8 | low
+    }
+}
+
 pub fn set_cursor(x: usize, y: usize) {
     // if x >= BUFFER_WIDTH || y >= BUFFER_HEIGHT {
     //     return disable_cursor();
@@ -235,60 +668,42 @@ pub fn set_cursor(x: usize, y: usize) {
     }
 }
 
+/// Glyphs of the code page 437 control characters, 0x00 to 0x1f.
+const CP437_LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼', //
+];
+
+/// Upper half of code page 437, 0x80 to 0xff.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}', //
+];
+
+/// Maps a character to its code page 437 byte, unknown characters become `■`.
 pub fn transform_char(char: char) -> u8 {
     match char {
         '\n' | '\t'| '\x08' | ('\x20'..='\x7e') => char as u8,
-        '•' => 0x07,
-        '░' => 0xb0,
-        '▒' => 0xb1,
-        '▓' => 0xb2,
-        '│' => 0xb3,
-        '┤' => 0xb4,
-        '╡' => 0xb5,
-        '╢' => 0xb6,
-        '╖' => 0xb7,
-        '╕' => 0xb8,
-        '╣' => 0xb9,
-        '║' => 0xba,
-        '╗' => 0xbb,
-        '╝' => 0xbc,
-        '╜' => 0xbd,
-        '╛' => 0xbe,
-        '┐' => 0xbf,
-        '└' => 0xc0,
-        '┴' => 0xc1,
-        '┬' => 0xc2,
-        '├' => 0xc3,
-        '─' => 0xc4,
-        '┼' => 0xc5,
-        '╞' => 0xc6,
-        '╟' => 0xc7,
-        '╚' => 0xc8,
-        '╔' => 0xc9,
-        '╩' => 0xca,
-        '╦' => 0xcb,
-        '╠' => 0xcc,
-        '═' => 0xcd,
-        '╬' => 0xce,
-        '╧' => 0xcf,
-        '╨' => 0xd0,
-        '╤' => 0xd1,
-        '╥' => 0xd2,
-        '╙' => 0xd3,
-        '╘' => 0xd4,
-        '╒' => 0xd5,
-        '╓' => 0xd6,
-        '╫' => 0xd7,
-        '╪' => 0xd8,
-        '┘' => 0xd9,
-        '┌' => 0xda,
-        '█' => 0xdb,
-        '▄' => 0xdc,
-        '▌' => 0xdd,
-        '▐' => 0xde,
-        '▀' => 0xdf,
-        '■' => 0xfe,
-        _ => 0xfe,
+        '⌂' => 0x7f,
+        // look-alikes that share a glyph
+        'β' => 0xe1,
+        'μ' => 0xe6,
+        '∈' => 0xee,
+        _ => {
+            if let Some(pos) = CP437_HIGH.iter().position(|c| *c == char) {
+                0x80 + pos as u8
+            } else if let Some(pos) = CP437_LOW[1..].iter().position(|c| *c == char) {
+                1 + pos as u8
+            } else {
+                0xfe
+            }
+        }
     }
 }
 
@@ -316,3 +731,42 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_transform_char_cp437() {
+    assert_eq!(transform_char('a'), b'a');
+    assert_eq!(transform_char('é'), 0x82);
+    assert_eq!(transform_char('☺'), 0x01);
+    assert_eq!(transform_char('╬'), 0xce);
+    assert_eq!(transform_char('\u{a0}'), 0xff);
+    assert_eq!(transform_char('€'), 0xfe);
+}
+
+#[test_case]
+fn test_write_at_keeps_cursor() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let (row, col) = (writer.row_pos, writer.column_pos);
+        writer.write_at(0, BUFFER_WIDTH - 2, "xyz");
+        assert_eq!(writer.buffer.chars[0][BUFFER_WIDTH - 2].read().ascii_character, b'x');
+        assert_eq!(writer.buffer.chars[0][BUFFER_WIDTH - 1].read().ascii_character, b'y');
+        assert_eq!((writer.row_pos, writer.column_pos), (row, col));
+    });
+}
+
+#[test_case]
+fn test_print_while_writer_held() {
+    let s = "printed while the writer was held";
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        // what an interrupt handler would do, this must not deadlock
+        print!("\n{}", s);
+        drop(writer);
+        println!();
+        let writer = WRITER.lock();
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    });
+}
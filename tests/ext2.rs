@@ -0,0 +1,381 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::drivers::ramdisk::MemDisk;
+use skyos::ext::{AccessFlags, Credentials, Errno, Ext2, QuotaKind, RWS};
+use skyos::testing::{self, Test};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+/// A 64 KiB ext2 file system made by `mke2fs -b 1024 -N 16 -O none,filetype`, holding
+/// `/hello.txt`.
+const IMAGE: &[u8] = include_bytes!("ext2.img");
+const HELLO: &[u8] = b"hello, ext2\n";
+const BLOCK_SIZE: usize = 1024;
+const SUPERBLOCK: usize = 1024;
+/// the descriptor of the only block group
+const GROUP: usize = 2048;
+const ROOT_INODE: u32 = 2;
+
+fn le32(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+fn set_le32(image: &mut [u8], offset: usize, value: u32) {
+    image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_le16(image: &mut [u8], offset: usize, value: u16) {
+    image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Offset of inode `n` in the image.
+fn inode(image: &[u8], n: u32) -> usize {
+    let table = le32(image, GROUP + 8) as usize;
+    let size = u16::from_le_bytes([image[SUPERBLOCK + 88], image[SUPERBLOCK + 89]]) as usize;
+    table * BLOCK_SIZE + (n as usize - 1) * size
+}
+
+/// The first data block of inode `n`.
+fn first_block(image: &[u8], n: u32) -> usize {
+    le32(image, inode(image, n) + 40) as usize
+}
+
+/// Offset of the directory entry of `name` in the root directory.
+fn root_entry(image: &[u8], name: &[u8]) -> usize {
+    let root = first_block(image, ROOT_INODE) * BLOCK_SIZE;
+    let block = &image[root..root + BLOCK_SIZE];
+    let at = block.windows(name.len()).position(|window| window == name);
+    root + at.unwrap() - 8
+}
+
+fn mount(image: Vec<u8>) -> Ext2<MemDisk> {
+    Ext2::new(MemDisk::new(image)).unwrap()
+}
+
+fn read_hello(ext2: &mut Ext2<MemDisk>) -> Vec<u8> {
+    let mut file = ext2.open("/hello.txt").unwrap();
+    let mut buf = [0; 64];
+    let read = file.read(&mut buf).unwrap() as usize;
+    buf[..read].to_vec()
+}
+
+#[test_case]
+const READS_THE_IMAGE: Test = skyos::unit_test!(reads_the_image, "ext2", Ext2);
+
+fn reads_the_image() {
+    let mut ext2 = testing::ext2();
+    assert_eq!(read_hello(&mut ext2), HELLO);
+}
+
+#[test_case]
+const REJECTS_BROKEN_SUPERBLOCKS: Test = skyos::unit_test!(rejects_broken_superblocks, "ext2");
+
+fn rejects_broken_superblocks() {
+    let fields = [
+        // blocks per group
+        (SUPERBLOCK + 32, 0),
+        // inodes per group
+        (SUPERBLOCK + 40, 0),
+        // log2 of the block size - 10
+        (SUPERBLOCK + 24, 30),
+        // blocks, more groups than of inodes
+        (SUPERBLOCK + 4, u32::MAX),
+    ];
+    for (offset, value) in fields {
+        let mut image = IMAGE.to_vec();
+        set_le32(&mut image, offset, value);
+        let ext2 = Ext2::new(MemDisk::new(image));
+        assert!(matches!(ext2, Err(Errno::InvalidFileImage)));
+    }
+}
+
+#[test_case]
+const MISSING_PARENTS_ARE_ERRORS: Test =
+    skyos::unit_test!(missing_parents_are_errors, "ext2", Ext2);
+
+fn missing_parents_are_errors() {
+    let mut ext2 = testing::ext2();
+    assert!(ext2.remove_file("/missing/file").is_err());
+    assert!(ext2.rename("/hello.txt", "/missing/hello.txt").is_err());
+    assert!(ext2.link("/hello.txt", "/missing/hello.txt").is_err());
+    assert!(ext2.symlink("/hello.txt", "/missing/hello.txt").is_err());
+    assert_eq!(read_hello(&mut ext2), HELLO);
+}
+
+#[test_case]
+const BROKEN_DIRECTORY_ENTRIES_END_THE_DIRECTORY: Test =
+    skyos::unit_test!(broken_directory_entries_end_the_directory, "ext2");
+
+fn broken_directory_entries_end_the_directory() {
+    let mut image = IMAGE.to_vec();
+    // a record length of 0 used to loop forever
+    let dot_dot = root_entry(&image, b"..");
+    set_le16(&mut image, dot_dot + 4, 0);
+    let mut ext2 = mount(image);
+    assert!(ext2.read_dir("/").unwrap().len() <= 2);
+    assert!(ext2.open("/hello.txt").is_err());
+}
+
+#[test_case]
+const ENTRIES_POINTING_PAST_THE_INODES_ARE_ERRORS: Test =
+    skyos::unit_test!(entries_pointing_past_the_inodes_are_errors, "ext2");
+
+fn entries_pointing_past_the_inodes_are_errors() {
+    let mut image = IMAGE.to_vec();
+    let hello = root_entry(&image, b"hello.txt");
+    set_le32(&mut image, hello, 1000);
+    let mut ext2 = mount(image);
+    assert!(ext2.open("/hello.txt").is_err());
+    assert!(ext2.remove_file("/hello.txt").is_err());
+}
+
+#[test_case]
+const FREEING_A_FREE_BLOCK_IS_AN_ERROR: Test =
+    skyos::unit_test!(freeing_a_free_block_is_an_error, "ext2");
+
+fn freeing_a_free_block_is_an_error() {
+    let mut image = IMAGE.to_vec();
+    let hello_inode = le32(&image, root_entry(&image, b"hello.txt"));
+    let block = first_block(&image, hello_inode) - 1;
+    let bitmap = le32(&image, GROUP) as usize * BLOCK_SIZE;
+    image[bitmap + block / 8] &= !(1 << (block % 8));
+    let mut ext2 = mount(image);
+    assert!(matches!(
+        ext2.remove_file("/hello.txt"),
+        Err(Errno::InvalidFileImage)
+    ));
+}
+
+#[test_case]
+const STATFS_FOLLOWS_THE_SUPERBLOCK: Test =
+    skyos::unit_test!(statfs_follows_the_superblock, "ext2");
+
+fn statfs_follows_the_superblock() {
+    let mut image = IMAGE.to_vec();
+    // blocks reserved for the superuser
+    set_le32(&mut image, SUPERBLOCK + 8, 10);
+    let mut ext2 = mount(image);
+    let before = ext2.statfs();
+    assert_eq!(before.block_size, BLOCK_SIZE as u64);
+    assert_eq!((before.blocks, before.free_blocks), (64, 41));
+    assert_eq!(before.available_blocks, 31);
+    assert_eq!((before.inodes, before.free_inodes), (16, 4));
+    assert_eq!(before.name_max, 255);
+
+    let mut file = ext2.create("/new.txt").unwrap();
+    file.write(&[1; 3 * BLOCK_SIZE]).unwrap();
+    // blocks reserved for the file are given back on close
+    drop(file);
+    let after = ext2.statfs();
+    assert_eq!(after.free_blocks, before.free_blocks - 3);
+    assert_eq!(after.free_inodes, before.free_inodes - 1);
+}
+
+#[test_case]
+const ACCESS_CHECKS_THE_PERMISSION_BITS: Test =
+    skyos::unit_test!(access_checks_the_permission_bits, "ext2");
+
+fn access_checks_the_permission_bits() {
+    let mut ext2 = mount(IMAGE.to_vec());
+    let root = Credentials::ROOT;
+    let user = |uid, gid, groups| Credentials { uid, gid, groups };
+    let (owner, member) = (user(1000, 1000, vec![]), user(2000, 100, vec![]));
+    let (supplementary, stranger) = (user(2000, 2000, vec![100]), user(2000, 2000, vec![]));
+    let (read, write) = (AccessFlags::READ, AccessFlags::WRITE);
+    let (exec, exists) = (AccessFlags::EXECUTE, AccessFlags::EXISTS);
+
+    // 0644, owned by root
+    assert!(ext2
+        .access("/hello.txt", AccessFlags::from(6), &root)
+        .is_ok());
+    let result = ext2.access("/hello.txt", exec, &root);
+    assert!(matches!(result, Err(Errno::AccessError)));
+    assert!(ext2.access("/", exec, &root).is_ok());
+    assert!(ext2.access("/missing", exists, &root).is_err());
+    let result = ext2.access("/hello.txt/x", exists, &root);
+    assert!(matches!(result, Err(Errno::NotDirectory)));
+
+    ext2.chown("/hello.txt", 1000, 100).unwrap();
+    ext2.chmod("/hello.txt", 0o640).unwrap();
+    assert!(ext2
+        .access("/hello.txt", AccessFlags::from(6), &owner)
+        .is_ok());
+    assert!(ext2.access("/hello.txt", read, &member).is_ok());
+    assert!(ext2.access("/hello.txt", read, &supplementary).is_ok());
+    assert!(ext2.access("/hello.txt", write, &member).is_err());
+    assert!(ext2.access("/hello.txt", read, &stranger).is_err());
+    assert!(ext2.access("/hello.txt", exists, &stranger).is_ok());
+
+    // a directory only root can search
+    ext2.create_dir("/private").unwrap();
+    ext2.create("/private/file").unwrap();
+    ext2.chmod("/private", 0o700).unwrap();
+    let result = ext2.access("/private/file", exists, &owner);
+    assert!(matches!(result, Err(Errno::AccessError)));
+    let dir = ext2.open_dir("/private").unwrap();
+    assert!(ext2.access_at(&dir, "file", read, &root).is_ok());
+    assert!(ext2.access_at(&dir, "file", read, &owner).is_err());
+
+    let ext2 = Ext2::new_read_only(MemDisk::new(IMAGE.to_vec())).unwrap();
+    let result = ext2.access("/hello.txt", write, &root);
+    assert!(matches!(result, Err(Errno::ReadOnlyFs)));
+    assert!(ext2.access("/hello.txt", read, &root).is_ok());
+}
+
+#[test_case]
+const DIR_HANDLES_LOOK_NAMES_UP_IN_THEIR_DIRECTORY: Test =
+    skyos::unit_test!(dir_handles_look_names_up_in_their_directory, "ext2");
+
+fn dir_handles_look_names_up_in_their_directory() {
+    let mut ext2 = mount(IMAGE.to_vec());
+    let root = ext2.root_dir();
+    let sub = ext2.create_dir_at(&root, "sub").unwrap();
+    let mut file = ext2.create_at(&sub, "file").unwrap();
+    file.write(b"relative").unwrap();
+    assert_eq!(ext2.open_dir("/sub").unwrap(), sub);
+    assert_eq!(ext2.stat_at(&sub, "file").unwrap().size, 8);
+    let mut buf = [0; 16];
+    file = ext2.open_at(&sub, "file").unwrap();
+    assert_eq!(file.read(&mut buf).unwrap(), 8);
+    assert_eq!(&buf[..8], b"relative");
+    // names aren't looked up from the root
+    let result = ext2.open_at(&sub, "hello.txt");
+    assert!(matches!(result, Err(Errno::NoEntry)));
+
+    assert_eq!(ext2.open_dir_at(&sub, ".").unwrap(), sub);
+    let parent = ext2.open_dir_at(&sub, "..").unwrap();
+    assert_eq!(parent, root);
+    assert_eq!(ext2.stat_at(&parent, "hello.txt").unwrap().size, 12);
+
+    let result = ext2.open_dir_at(&sub, "missing");
+    assert!(matches!(result, Err(Errno::NoEntry)));
+    assert!(matches!(ext2.stat_at(&sub, "missing"), Err(Errno::NoEntry)));
+    let result = ext2.open_dir_at(&sub, "file");
+    assert!(matches!(result, Err(Errno::NotDirectory)));
+    let result = ext2.open_at(&root, "sub/file");
+    assert!(matches!(result, Err(Errno::IllegalCharacter)));
+}
+
+#[test_case]
+const HOLES_READ_AS_ZEROS_AND_ARE_FOUND_BY_SEEKING: Test =
+    skyos::unit_test!(holes_read_as_zeros_and_are_found_by_seeking, "ext2");
+
+fn holes_read_as_zeros_and_are_found_by_seeking() {
+    let mut image = IMAGE.to_vec();
+    // /hello.txt grows to 3 blocks and 5 bytes, the 4th block is its first again
+    let hello = inode(&image, le32(&image, root_entry(&image, b"hello.txt")));
+    let first = le32(&image, hello + 40);
+    set_le32(&mut image, hello + 4, 3 * BLOCK_SIZE as u32 + 5);
+    set_le32(&mut image, hello + 40 + 3 * 4, first);
+    let mut ext2 = mount(image);
+    let mut file = ext2.open("/hello.txt").unwrap();
+    let block = BLOCK_SIZE as u64;
+
+    let mut buf = [0xff; BLOCK_SIZE];
+    assert_eq!(file.read_at(block, &mut buf).unwrap(), block);
+    assert!(buf.iter().all(|byte| *byte == 0));
+    assert_eq!(file.read_at(3 * block, &mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], &HELLO[..5]);
+
+    assert_eq!(file.seek_data(0).unwrap(), 0);
+    assert_eq!(file.seek_hole(10).unwrap(), block);
+    assert_eq!(file.seek_data(block + 1).unwrap(), 3 * block);
+    assert_eq!(file.seek_hole(3 * block).unwrap(), 3 * block + 5);
+    let result = file.seek_data(3 * block + 5);
+    assert!(matches!(result, Err(Errno::PastEndOfFile)));
+}
+
+#[test_case]
+const POINTERS_PAST_THE_END_ARE_NOT_HOLES: Test =
+    skyos::unit_test!(pointers_past_the_end_are_not_holes, "ext2");
+
+fn pointers_past_the_end_are_not_holes() {
+    let mut image = IMAGE.to_vec();
+    // the image has 64 blocks
+    let hello = inode(&image, le32(&image, root_entry(&image, b"hello.txt")));
+    set_le32(&mut image, hello + 40, 1000);
+    let mut ext2 = mount(image);
+    let mut file = ext2.open("/hello.txt").unwrap();
+
+    let mut buf = [0; 12];
+    assert!(matches!(file.read(&mut buf), Err(Errno::BadBlock)));
+    assert!(matches!(file.seek_data(0), Err(Errno::BadBlock)));
+    assert!(matches!(file.seek_hole(0), Err(Errno::BadBlock)));
+}
+
+#[test_case]
+const FILES_GROW_WITH_HOLES_AND_FALLOCATE_ALLOCATES: Test =
+    skyos::unit_test!(files_grow_with_holes_and_fallocate_allocates, "ext2");
+
+fn files_grow_with_holes_and_fallocate_allocates() {
+    let mut ext2 = mount(IMAGE.to_vec());
+    let free = ext2.statfs().free_blocks;
+    let mut file = ext2.create("/log").unwrap();
+    let block = BLOCK_SIZE as u64;
+    // 1 KiB blocks are 2 sectors
+    let sizes = |ext2: &Ext2<MemDisk>| {
+        let stat = ext2.stat("/log").unwrap();
+        (stat.size, stat.number_blocks)
+    };
+
+    file.fallocate(0, 3 * block).unwrap();
+    assert_eq!(sizes(&ext2), (3 * block, 6));
+    file.write_at(0, &[1; 2 * BLOCK_SIZE]).unwrap();
+
+    // the data before the new end stays, the rest of its block is zeroed
+    file.set_len(1500).unwrap();
+    assert_eq!(sizes(&ext2), (1500, 4));
+    file.set_len(5 * block).unwrap();
+    assert_eq!(sizes(&ext2), (5 * block, 4));
+    let mut buf = [0xff; BLOCK_SIZE];
+    assert_eq!(file.read_at(1000, &mut buf).unwrap(), block);
+    assert!(buf[..500].iter().all(|byte| *byte == 1));
+    assert!(buf[500..].iter().all(|byte| *byte == 0));
+
+    assert_eq!(file.write_at(8 * block, b"x").unwrap(), 1);
+    assert_eq!(sizes(&ext2), (8 * block + 1, 6));
+    assert_eq!(file.seek_hole(0).unwrap(), 2 * block);
+    drop(file);
+    assert_eq!(ext2.statfs().free_blocks, free - 3);
+}
+
+#[test_case]
+const OVERSIZED_QUOTA_TABLES_LEAVE_QUOTAS_OFF: Test =
+    skyos::unit_test!(oversized_quota_tables_leave_quotas_off, "ext2");
+
+fn oversized_quota_tables_leave_quotas_off() {
+    let mut image = IMAGE.to_vec();
+    // inodes 3 and 4 hold the tables, regular files of almost 4 GiB without blocks
+    for n in [3, 4] {
+        let at = inode(&image, n);
+        set_le16(&mut image, at, 0o100600);
+        set_le32(&mut image, at + 4, 0xffff_fff0);
+        set_le32(&mut image, at + 28, 0);
+    }
+    let ext2 = mount(image);
+    let result = ext2.quotas(QuotaKind::User);
+    assert!(matches!(result, Err(Errno::Unsupported)));
+}
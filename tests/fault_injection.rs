@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::drivers::ramdisk::MemDisk;
+use skyos::ext::{Errno, Ext2, RWS};
+use skyos::fault::{self, Point};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+/// A 64 KiB ext2 file system made by `mke2fs -b 1024 -N 16 -O none,filetype`, holding
+/// `/hello.txt`.
+const IMAGE: &[u8] = include_bytes!("ext2.img");
+const HELLO: &[u8] = b"hello, ext2\n";
+
+fn mount() -> Ext2<MemDisk> {
+    fault::clear();
+    Ext2::new(MemDisk::new(IMAGE.to_vec())).unwrap()
+}
+
+fn read_hello(ext2: &mut Ext2<MemDisk>) -> Vec<u8> {
+    let mut file = ext2.open("/hello.txt").unwrap();
+    let mut buf = [0; 64];
+    let read = file.read(&mut buf).unwrap() as usize;
+    buf[..read].to_vec()
+}
+
+#[test_case]
+fn mount_without_faults() {
+    let mut ext2 = mount();
+    assert_eq!(read_hello(&mut ext2), HELLO);
+}
+
+#[test_case]
+fn mount_fails_on_read_errors() {
+    fault::clear();
+    fault::set(Point::Read, 1);
+    let disk = MemDisk::new(IMAGE.to_vec());
+    assert!(matches!(Ext2::new(disk), Err(Errno::UnknownIO)));
+    assert!(fault::injected(Point::Read) > 0);
+    fault::clear();
+}
+
+#[test_case]
+fn read_errors_are_returned() {
+    let mut ext2 = mount();
+    fault::set(Point::Read, 1);
+    assert!(ext2.open("/hello.txt").is_err());
+    assert!(ext2.read_dir("/").is_err());
+    assert!(ext2.remove_file("/hello.txt").is_err());
+    fault::clear();
+    assert_eq!(read_hello(&mut ext2), HELLO);
+}
+
+#[test_case]
+fn short_reads_are_errors() {
+    let mut ext2 = mount();
+    let mut file = ext2.open("/hello.txt").unwrap();
+    fault::set(Point::ShortRead, 1);
+    assert!(file.read(&mut [0; 64]).is_err());
+    fault::clear();
+}
+
+#[test_case]
+fn write_errors_are_returned() {
+    let mut ext2 = mount();
+    let mut file = ext2.create("/new.txt").unwrap();
+    fault::set(Point::Write, 1);
+    assert!(file.write(b"lost").is_err());
+    assert!(ext2.create_dir("/dir").is_err());
+    assert!(ext2.create("/other.txt").is_err());
+    fault::clear();
+    // what was there before is still readable
+    assert_eq!(read_hello(&mut ext2), HELLO);
+}
+
+#[test_case]
+fn failed_allocations_are_out_of_space() {
+    let mut ext2 = mount();
+    let mut file = ext2.create("/new.txt").unwrap();
+    fault::set(Point::Alloc, 1);
+    assert!(matches!(file.write(b"data"), Err(Errno::OutOfSpace)));
+    assert!(matches!(ext2.create("/other.txt"), Err(Errno::OutOfSpace)));
+    assert!(fault::injected(Point::Alloc) >= 2);
+    fault::clear();
+    assert_eq!(file.write(b"data").unwrap(), 4);
+}
+
+#[test_case]
+fn random_faults_are_repeatable() {
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        fault::clear();
+        fault::seed(42);
+        fault::set(Point::Write, 3);
+        let run: Vec<bool> = (0..32).map(|_| fault::fail(Point::Write)).collect();
+        assert!(run.contains(&true) && run.contains(&false));
+        runs.push(run);
+    }
+    assert_eq!(runs[0], runs[1]);
+    fault::clear();
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::fd::{
+    self, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, SEEK_DATA, SEEK_HOLE,
+    SEEK_SET,
+};
+use skyos::sync::Event;
+use skyos::syscall::{self, syscall};
+use skyos::{task, vfs};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+fn open(path: &[u8], flags: u64) -> i64 {
+    syscall(syscall::SYS_OPEN, [path.as_ptr() as u64, flags, 0, 0, 0, 0])
+}
+
+fn write(fd: i64, data: &[u8]) -> i64 {
+    let args = [fd as u64, data.as_ptr() as u64, data.len() as u64, 0, 0, 0];
+    syscall(syscall::SYS_WRITE, args)
+}
+
+fn read(fd: i64, buf: &mut [u8]) -> i64 {
+    let args = [fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0];
+    syscall(syscall::SYS_READ, args)
+}
+
+fn close(fd: i64) -> i64 {
+    syscall(syscall::SYS_CLOSE, [fd as u64, 0, 0, 0, 0, 0])
+}
+
+fn access(path: &[u8], mode: u64) -> i64 {
+    let args = [path.as_ptr() as u64, mode, 0, 0, 0, 0];
+    syscall(syscall::SYS_ACCESS, args)
+}
+
+#[test_case]
+fn open_creates_writes_and_reads() {
+    let fd = open(b"/fd-test.txt\0", O_RDWR | O_CREAT | O_TRUNC);
+    assert!(fd > 2);
+    assert_eq!(write(fd, b"hello "), 6);
+    assert_eq!(write(fd, b"world"), 5);
+    assert_eq!(vfs::read("/fd-test.txt").unwrap(), b"hello world");
+
+    let seek = [fd as u64, 6, SEEK_SET, 0, 0, 0];
+    assert_eq!(syscall(syscall::SYS_LSEEK, seek), 6);
+    let mut buf = [0; 16];
+    assert_eq!(read(fd, &mut buf), 5);
+    assert_eq!(&buf[..5], b"world");
+    assert_eq!(read(fd, &mut buf), 0);
+    assert_eq!(close(fd), 0);
+}
+
+#[test_case]
+fn open_respects_flags() {
+    assert_eq!(open(b"/missing.txt\0", O_RDONLY), -2);
+    vfs::write("/flags.txt", b"abc").unwrap();
+    assert_eq!(open(b"/flags.txt\0", O_WRONLY | O_CREAT | O_EXCL), -17);
+
+    let fd = open(b"/flags.txt\0", O_WRONLY | O_APPEND);
+    assert_eq!(write(fd, b"def"), 3);
+    let mut buf = [0; 4];
+    assert_eq!(read(fd, &mut buf), -syscall::EBADF);
+    assert_eq!(close(fd), 0);
+    assert_eq!(vfs::read("/flags.txt").unwrap(), b"abcdef");
+}
+
+#[test_case]
+fn files_without_holes_are_data_to_the_end() {
+    vfs::write("/dense.txt", b"0123456789").unwrap();
+    let fd = open(b"/dense.txt\0", O_RDONLY);
+    let seek = |offset, whence| syscall(syscall::SYS_LSEEK, [fd as u64, offset, whence, 0, 0, 0]);
+    assert_eq!(seek(4, SEEK_DATA), 4);
+    assert_eq!(seek(4, SEEK_HOLE), 10);
+    assert_eq!(seek(10, SEEK_DATA), -6);
+    assert_eq!(close(fd), 0);
+}
+
+#[test_case]
+fn access_checks_without_opening() {
+    vfs::write("/access.txt", b"abc").unwrap();
+    assert_eq!(access(b"/access.txt\0", syscall::F_OK), 0);
+    assert_eq!(access(b"/access.txt\0", syscall::R_OK | syscall::W_OK), 0);
+    assert_eq!(access(b"/missing.txt\0", syscall::F_OK), -2);
+    assert_eq!(access(b"/access.txt\0", 8), -syscall::EINVAL);
+}
+
+#[test_case]
+fn dup2_shares_the_open_file() {
+    vfs::write("/dup.txt", b"0123456789").unwrap();
+    let fd = open(b"/dup.txt\0", O_RDONLY);
+    assert_eq!(syscall(syscall::SYS_DUP2, [fd as u64, 20, 0, 0, 0, 0]), 20);
+    let mut buf = [0; 4];
+    assert_eq!(read(fd, &mut buf), 4);
+    // the offset is shared
+    assert_eq!(read(20, &mut buf), 4);
+    assert_eq!(&buf, b"4567");
+    assert_eq!(close(fd), 0);
+    assert_eq!(read(20, &mut buf), 2);
+    assert_eq!(close(20), 0);
+    assert_eq!(syscall(syscall::SYS_DUP2, [fd as u64, 21, 0, 0, 0, 0]), -syscall::EBADF);
+}
+
+#[test_case]
+fn spawned_tasks_inherit_files() {
+    static DONE: Event = Event::new();
+    let fd = open(b"/inherited.txt\0", O_WRONLY | O_CREAT | O_TRUNC);
+    task::spawn("child", move || {
+        assert_eq!(write(fd, b"from the child"), 14);
+        DONE.signal();
+    });
+    DONE.wait();
+    assert_eq!(close(fd), 0);
+    assert_eq!(vfs::read("/inherited.txt").unwrap(), b"from the child");
+    assert!(fd::get(fd as usize).is_err());
+}
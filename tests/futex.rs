@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+use skyos::error::KError;
+use skyos::futex::{self, FUTEX_WAIT, FUTEX_WAKE};
+use skyos::syscall::{self, syscall};
+use skyos::task;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+fn addr(word: &AtomicU32) -> u64 {
+    word as *const AtomicU32 as u64
+}
+
+#[test_case]
+fn wait_on_changed_value_fails() {
+    static WORD: AtomicU32 = AtomicU32::new(1);
+    assert_eq!(futex::wait(addr(&WORD), 0, None), Err(KError::WouldBlock));
+    let result = syscall(syscall::SYS_FUTEX, [addr(&WORD), FUTEX_WAIT, 0, 0, 0, 0]);
+    assert_eq!(result, -syscall::EAGAIN);
+}
+
+#[test_case]
+fn wait_times_out() {
+    static WORD: AtomicU32 = AtomicU32::new(0);
+    let timeout = Some(Duration::from_millis(10));
+    assert_eq!(futex::wait(addr(&WORD), 0, timeout), Err(KError::TimedOut));
+}
+
+#[test_case]
+fn wake_without_waiters_wakes_nobody() {
+    static WORD: AtomicU32 = AtomicU32::new(0);
+    assert_eq!(futex::wake(addr(&WORD), 1), Ok(0));
+    assert_eq!(futex::wake(addr(&WORD) + 1, 1), Err(KError::InvalidArgument));
+}
+
+#[test_case]
+fn wake_wakes_at_most_count_waiters() {
+    static WORD: AtomicU32 = AtomicU32::new(0);
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+    for _ in 0..3 {
+        task::spawn("waiter", || {
+            let timeout = Some(Duration::from_millis(200));
+            if futex::wait(addr(&WORD), 0, timeout).is_ok() {
+                WOKEN.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+    }
+    task::yield_now();
+
+    let woken = syscall(syscall::SYS_FUTEX, [addr(&WORD), FUTEX_WAKE, 2, 0, 0, 0]);
+    assert_eq!(woken, 2);
+    for _ in 0..10 {
+        task::yield_now();
+    }
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 2);
+    assert_eq!(futex::wake(addr(&WORD), usize::MAX), Ok(1));
+}
+
+/// A lock that only makes system calls when contended: 0 is unlocked, 1 locked and 2 locked
+/// with waiters.
+#[test_case]
+fn futex_lock_excludes() {
+    static LOCK: AtomicU32 = AtomicU32::new(0);
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn lock() {
+        if LOCK.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            return;
+        }
+        while LOCK.swap(2, Ordering::Acquire) != 0 {
+            syscall(syscall::SYS_FUTEX, [addr(&LOCK), FUTEX_WAIT, 2, 0, 0, 0]);
+        }
+    }
+
+    fn unlock() {
+        if LOCK.swap(0, Ordering::Release) == 2 {
+            syscall(syscall::SYS_FUTEX, [addr(&LOCK), FUTEX_WAKE, 1, 0, 0, 0]);
+        }
+    }
+
+    for _ in 0..4 {
+        task::spawn("locker", || {
+            for _ in 0..50 {
+                lock();
+                let value = COUNTER.load(Ordering::Relaxed);
+                task::yield_now();
+                COUNTER.store(value + 1, Ordering::Relaxed);
+                unlock();
+            }
+            DONE.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    while DONE.load(Ordering::SeqCst) < 4 {
+        task::yield_now();
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 200);
+}
@@ -8,7 +8,7 @@ extern crate alloc;
 
 use alloc::{boxed::Box, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use skyos::allocator::HEAP_SIZE;
+use skyos::allocator::heap_size;
 use core::panic::PanicInfo;
 
 entry_point!(main);
@@ -47,7 +47,7 @@ fn large_vec() {
 
 #[test_case]
 fn many_boxes() {
-    for i in 0..HEAP_SIZE {
+    for i in 0..heap_size() {
         let x = Box::new(i);
         assert_eq!(*x, i);
     }
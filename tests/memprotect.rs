@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::memprotect::{self, Kind};
+use skyos::mmap;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn nx_and_wp_are_enabled() {
+    assert!(memprotect::nx_enabled());
+    assert!(memprotect::wp_enabled());
+}
+
+#[test_case]
+fn only_the_kernel_image_may_be_writable_and_executable() {
+    let regions = memprotect::regions();
+    assert!(!regions.is_empty());
+    for region in regions {
+        if region.kind != Kind::Kernel {
+            assert!(!(region.writable && region.executable), "{:?}", region);
+        }
+    }
+}
+
+#[test_case]
+fn heap_and_mappings_are_not_executable() {
+    let value = Box::new(42u64);
+    let heap = &*value as *const u64 as u64;
+    let addr = mmap::map_anonymous(4096, mmap::PROT_READ | mmap::PROT_WRITE).unwrap();
+    unsafe { (addr as *mut u64).write_volatile(1) };
+
+    let regions = memprotect::regions();
+    for addr in [heap, addr] {
+        let region = regions
+            .iter()
+            .find(|region| (region.start..region.start + region.size).contains(&addr))
+            .unwrap();
+        assert!(region.writable && !region.executable, "{:?}", region);
+    }
+    mmap::unmap(addr, 4096).unwrap();
+}
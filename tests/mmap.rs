@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::mmap::{self, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use skyos::syscall::{self, syscall};
+use skyos::vfs;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn anonymous_mapping_is_zeroed_and_writable() {
+    let addr = mmap::map_anonymous(3 * 4096, PROT_READ | PROT_WRITE).unwrap();
+    let memory = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 3 * 4096) };
+    assert!(memory.iter().all(|byte| *byte == 0));
+    memory[5000] = 42;
+    assert_eq!(memory[5000], 42);
+    mmap::unmap(addr, 3 * 4096).unwrap();
+}
+
+#[test_case]
+fn file_mapping_reads_the_file() {
+    vfs::write("/mapped.txt", b"hello from a file").unwrap();
+    let addr = mmap::map_file("/mapped.txt", 0, 4096, PROT_READ).unwrap();
+    let memory = unsafe { core::slice::from_raw_parts(addr as *const u8, 4096) };
+    assert_eq!(&memory[..17], b"hello from a file");
+    assert_eq!(memory[17], 0);
+
+    // a writable copy doesn't change the file or other mappings
+    mmap::protect(addr, 4096, PROT_READ | PROT_WRITE).unwrap();
+    unsafe { (addr as *mut u8).write(b'j') };
+    assert_eq!(vfs::read("/mapped.txt").unwrap()[0], b'h');
+    mmap::unmap(addr, 4096).unwrap();
+}
+
+#[test_case]
+fn unmapping_part_of_a_mapping_keeps_the_rest() {
+    let addr = mmap::map_anonymous(2 * 4096, PROT_READ | PROT_WRITE).unwrap();
+    mmap::unmap(addr, 4096).unwrap();
+    unsafe { ((addr + 4096) as *mut u8).write(7) };
+    assert_eq!(unsafe { ((addr + 4096) as *const u8).read() }, 7);
+    mmap::unmap(addr + 4096, 4096).unwrap();
+}
+
+#[test_case]
+fn mmap_syscall_maps_memory_and_files() {
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let addr = syscall(syscall::SYS_MMAP, [0, 4096, PROT_READ, flags, 0, 0]);
+    assert!(addr >= mmap::MMAP_START as i64);
+    assert_eq!(syscall(syscall::SYS_MUNMAP, [addr as u64, 4096, 0, 0, 0, 0]), 0);
+    let unopened = syscall(syscall::SYS_MMAP, [0, 4096, PROT_READ, MAP_PRIVATE, 42, 0]);
+    assert_eq!(unopened, -syscall::EBADF);
+
+    vfs::write("/syscall-mapped.txt", b"mapped by fd").unwrap();
+    let path = b"/syscall-mapped.txt\0";
+    let fd = syscall(syscall::SYS_OPEN, [path.as_ptr() as u64, 0, 0, 0, 0, 0]);
+    assert!(fd >= 0);
+    let addr = syscall(syscall::SYS_MMAP, [0, 4096, PROT_READ, MAP_PRIVATE, fd as u64, 0]);
+    assert!(addr >= mmap::MMAP_START as i64);
+    let memory = unsafe { core::slice::from_raw_parts(addr as *const u8, 12) };
+    assert_eq!(memory, b"mapped by fd");
+    assert_eq!(syscall(syscall::SYS_MUNMAP, [addr as u64, 4096, 0, 0, 0, 0]), 0);
+    assert_eq!(syscall(syscall::SYS_CLOSE, [fd as u64, 0, 0, 0, 0, 0]), 0);
+}
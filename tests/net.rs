@@ -0,0 +1,326 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use skyos::error::{KError, KResult};
+use skyos::net::{
+    self, arp,
+    ethernet::{self, MacAddr, ETHERTYPE_ARP, ETHERTYPE_IPV4},
+    http,
+    icmp::{self, IcmpSocket},
+    ipv4, pcap, stats,
+    tcp::{self, TcpListener, TcpStream},
+    udp::{self, UdpSocket},
+    Ipv4Addr, NetDevice, SocketAddr,
+};
+use skyos::testing::Test;
+use skyos::{task, time, timer};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+const TIMEOUT: Option<Duration> = Some(Duration::from_secs(1));
+
+#[test_case]
+const UDP_OVER_LOOPBACK: Test = skyos::unit_test!(udp_over_loopback, "net", NetLoopback);
+
+fn udp_over_loopback() {
+    let server = UdpSocket::bind(7).unwrap();
+    let client = UdpSocket::bind(0).unwrap();
+    let to = SocketAddr::new(Ipv4Addr::LOCALHOST, server.local_port());
+    assert_eq!(client.send_to(b"hello", to), Ok(5));
+
+    let request = server.recv_from(TIMEOUT).unwrap();
+    assert_eq!(request.data, b"hello");
+    assert_eq!(
+        request.from,
+        SocketAddr::new(Ipv4Addr::LOCALHOST, client.local_port())
+    );
+    assert_eq!(server.send_to(&request.data, request.from), Ok(5));
+    assert_eq!(client.recv_from(TIMEOUT).unwrap().data, b"hello");
+}
+
+#[test_case]
+fn bound_port_is_in_use() {
+    let socket = UdpSocket::bind(5000).unwrap();
+    assert!(matches!(UdpSocket::bind(5000), Err(KError::AddressInUse)));
+    drop(socket);
+    assert!(UdpSocket::bind(5000).is_ok());
+}
+
+#[test_case]
+fn receive_times_out() {
+    let socket = UdpSocket::bind(0).unwrap();
+    let result = socket.recv_from(Some(Duration::from_millis(50)));
+    assert!(matches!(result, Err(KError::TimedOut)));
+}
+
+#[test_case]
+const RECEIVE_TIMES_OUT_IN_VIRTUAL_TIME: Test =
+    skyos::unit_test!(receive_times_out_in_virtual_time, "net", NetLoopback, VirtualTime);
+
+fn receive_times_out_in_virtual_time() {
+    let socket = UdpSocket::bind(0).unwrap();
+    task::spawn("stepper", || time::step(Duration::from_millis(50)));
+    let result = socket.recv_from(Some(Duration::from_millis(50)));
+    assert!(matches!(result, Err(KError::TimedOut)));
+}
+
+#[test_case]
+fn unroutable_address_fails() {
+    let socket = UdpSocket::bind(0).unwrap();
+    let to = SocketAddr::new(Ipv4Addr([10, 9, 9, 9]), 7);
+    assert_eq!(socket.send_to(b"lost", to), Err(KError::NetworkUnreachable));
+}
+
+#[test_case]
+fn oversized_datagram_fails() {
+    let socket = UdpSocket::bind(0).unwrap();
+    let to = SocketAddr::new(Ipv4Addr::LOCALHOST, 7);
+    let data = vec![0; 70000];
+    assert_eq!(socket.send_to(&data, to), Err(KError::MessageTooLong));
+}
+
+#[test_case]
+fn echo_request_is_answered() {
+    let socket = IcmpSocket::open();
+    let id = socket.echo_identifier();
+    let request = icmp::echo_request(id, 1, b"ping");
+    socket
+        .send_to(&request, Ipv4Addr::LOCALHOST, ipv4::DEFAULT_TTL)
+        .unwrap();
+
+    // the socket sees its own request first, then the reply
+    let seen = socket.recv(TIMEOUT).unwrap();
+    assert_eq!(
+        (seen.kind, seen.echo_id()),
+        (icmp::ECHO_REQUEST, Some((id, 1)))
+    );
+    let reply = socket.recv(TIMEOUT).unwrap();
+    assert_eq!(reply.kind, icmp::ECHO_REPLY);
+    assert_eq!(reply.echo_id(), Some((id, 1)));
+    assert_eq!(reply.from, Ipv4Addr::LOCALHOST);
+    assert_eq!(reply.data, b"ping");
+}
+
+#[test_case]
+fn traffic_is_counted() {
+    let lo = net::interfaces()
+        .into_iter()
+        .find(|interface| interface.name() == "lo")
+        .unwrap();
+    let sent = lo.stats.tx_packets.get();
+    let no_ports = stats::UDP.no_ports.get();
+
+    let socket = UdpSocket::bind(0).unwrap();
+    let to = SocketAddr::new(Ipv4Addr::LOCALHOST, 7002);
+    assert_eq!(socket.send_to(b"nobody", to), Ok(6));
+    let listed = udp::sockets()
+        .into_iter()
+        .find(|info| info.local.port == socket.local_port())
+        .unwrap();
+    assert_eq!(listed.recv_queue, 0);
+    // the datagram is handled by the `net` task
+    for _ in 0..100 {
+        if stats::UDP.no_ports.get() > no_ports {
+            break;
+        }
+        timer::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(stats::UDP.no_ports.get(), no_ports + 1);
+    assert!(lo.stats.tx_packets.get() > sent);
+    assert!(lo.stats.rx_packets.get() > 0);
+
+    let listener = TcpListener::bind(7003).unwrap();
+    let listed = tcp::sockets()
+        .into_iter()
+        .find(|info| info.local.port == 7003)
+        .unwrap();
+    assert_eq!(listed.state, Some(tcp::State::Listen));
+    assert!(stats::tcp_file().contains("00000000:1B5B 00000000:0000 0A"));
+    drop(listener);
+}
+
+#[test_case]
+fn capture_is_dumped_as_pcap() {
+    let lo = net::interfaces()
+        .into_iter()
+        .find(|interface| interface.name() == "lo")
+        .unwrap();
+    pcap::start(&lo, pcap::DEFAULT_LIMIT).unwrap();
+    assert_eq!(pcap::start(&lo, pcap::DEFAULT_LIMIT), Err(KError::Busy));
+    let socket = UdpSocket::bind(0).unwrap();
+    let to = SocketAddr::new(Ipv4Addr::LOCALHOST, socket.local_port());
+    socket.send_to(b"captured", to).unwrap();
+    socket.recv_from(TIMEOUT).unwrap();
+    assert!(pcap::stop());
+
+    let file = pcap::dump().unwrap();
+    // little endian magic, bare IP packets
+    assert_eq!(&file[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(&file[20..24], &[101, 0, 0, 0]);
+    let len = ipv4::HEADER_SIZE + udp::HEADER_SIZE + 8;
+    let record = &file[24..];
+    assert_eq!(record[8..12], (len as u32).to_le_bytes());
+    assert_eq!(&record[16 + len - 8..16 + len], b"captured");
+    // sent and received
+    assert_eq!(pcap::status().unwrap().frames, 2);
+}
+
+/// Reads until the peer closes the connection.
+fn read_to_end(stream: &TcpStream) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0; 1000];
+    loop {
+        match stream.read(&mut buf, TIMEOUT).unwrap() {
+            0 => return data,
+            len => data.extend_from_slice(&buf[..len]),
+        }
+    }
+}
+
+#[test_case]
+fn tcp_over_loopback() {
+    let listener = TcpListener::bind(7000).unwrap();
+    task::spawn("echo", move || {
+        let stream = listener.accept(TIMEOUT).unwrap();
+        let data = read_to_end(&stream);
+        stream.write(&data).unwrap();
+    });
+
+    let stream = TcpStream::connect(SocketAddr::new(Ipv4Addr::LOCALHOST, 7000)).unwrap();
+    assert_eq!(stream.peer_addr().port, 7000);
+    // more than fits the buffers and the window at once
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    assert_eq!(stream.write(&data), Ok(data.len()));
+    stream.shutdown();
+    assert_eq!(read_to_end(&stream), data);
+    assert_eq!(stream.write(b"late"), Err(KError::NotConnected));
+}
+
+#[test_case]
+fn closed_port_refuses_connection() {
+    let result = TcpStream::connect(SocketAddr::new(Ipv4Addr::LOCALHOST, 7001));
+    assert!(matches!(result, Err(KError::ConnectionRefused)));
+}
+
+#[test_case]
+fn http_get_decodes_chunked_body() {
+    let listener = TcpListener::bind(8080).unwrap();
+    task::spawn("httpd", move || {
+        let stream = listener.accept(TIMEOUT).unwrap();
+        let mut buf = [0; 1000];
+        let len = stream.read(&mut buf, TIMEOUT).unwrap();
+        assert!(buf[..len].starts_with(b"GET /file HTTP/1.1\r\n"));
+        stream
+            .write(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n")
+            .unwrap();
+    });
+
+    assert_eq!(http::get("http://127.0.0.1:8080/file").unwrap(), b"hello");
+}
+
+/// An Ethernet device keeping the frames sent through it.
+struct Wire {
+    sent: Mutex<Vec<Vec<u8>>>,
+}
+
+const WIRE_MAC: MacAddr = MacAddr([0x52, 0x54, 0, 0, 0, 1]);
+
+impl NetDevice for Wire {
+    fn name(&self) -> &str {
+        "wire0"
+    }
+
+    fn mtu(&self) -> usize {
+        1500
+    }
+
+    fn mac(&self) -> Option<MacAddr> {
+        Some(WIRE_MAC)
+    }
+
+    fn transmit(&self, frame: &[u8]) -> KResult<()> {
+        self.sent.lock().push(frame.to_vec());
+        Ok(())
+    }
+}
+
+#[test_case]
+fn packets_wait_for_address_resolution() {
+    let wire = Arc::new(Wire {
+        sent: Mutex::new(Vec::new()),
+    });
+    net::add_interface(wire.clone(), Ipv4Addr([10, 1, 0, 1]), 24, None);
+    let neighbor = Ipv4Addr([10, 1, 0, 2]);
+    let neighbor_mac = MacAddr([0x52, 0x54, 0, 0, 0, 2]);
+
+    let socket = UdpSocket::bind(0).unwrap();
+    assert_eq!(
+        socket.send_to(b"hello", SocketAddr::new(neighbor, 7)),
+        Ok(5)
+    );
+    // only a request goes out, the datagram waits for the answer
+    let request = wire.sent.lock().pop().unwrap();
+    let (dst, src, ethertype, payload) = ethernet::parse(&request).unwrap();
+    assert_eq!(
+        (dst, src, ethertype),
+        (MacAddr::BROADCAST, WIRE_MAC, ETHERTYPE_ARP)
+    );
+    assert_eq!(&payload[24..28], &neighbor.0);
+    assert!(wire.sent.lock().is_empty());
+
+    let mut reply = vec![0, 1, 0x08, 0, 6, 4, 0, 2];
+    reply.extend_from_slice(&neighbor_mac.0);
+    reply.extend_from_slice(&neighbor.0);
+    reply.extend_from_slice(&WIRE_MAC.0);
+    reply.extend_from_slice(&[10, 1, 0, 1]);
+    net::receive(
+        "wire0",
+        ethernet::build(WIRE_MAC, neighbor_mac, ETHERTYPE_ARP, &reply),
+    );
+
+    // the `net` task handles the answer and sends the datagram
+    let datagram = (0..100)
+        .find_map(|_| {
+            let frame = wire.sent.lock().pop();
+            if frame.is_none() {
+                timer::sleep(Duration::from_millis(10));
+            }
+            frame
+        })
+        .unwrap();
+    let (dst, _, ethertype, packet) = ethernet::parse(&datagram).unwrap();
+    assert_eq!((dst, ethertype), (neighbor_mac, ETHERTYPE_IPV4));
+    let (header, _) = ipv4::parse(packet).unwrap();
+    assert_eq!(header.dst, neighbor);
+
+    let entry = arp::neighbors()
+        .into_iter()
+        .find(|entry| entry.addr == neighbor)
+        .unwrap();
+    assert_eq!(entry.mac, Some(neighbor_mac));
+    assert_eq!(entry.state, arp::State::Reachable);
+    assert!(arp::remove(neighbor));
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::error::KError;
+use skyos::pipe::{self, PIPE_SIZE};
+use skyos::sync::Event;
+use skyos::syscall::{self, syscall};
+use skyos::task;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn read_returns_end_of_file_without_writers() {
+    let (reader, writer) = pipe::pipe();
+    assert_eq!(writer.write(b"abc"), Ok(3));
+    drop(writer);
+    let mut buf = [0; 2];
+    assert_eq!(reader.read(&mut buf), Ok(2));
+    assert_eq!(&buf, b"ab");
+    assert_eq!(reader.read(&mut buf), Ok(1));
+    assert_eq!(reader.read(&mut buf), Ok(0));
+}
+
+#[test_case]
+fn write_without_readers_fails() {
+    let (reader, writer) = pipe::pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"lost"), Err(KError::BrokenPipe));
+}
+
+#[test_case]
+fn full_pipe_blocks_writer_until_read() {
+    static DONE: Event = Event::new();
+    let (reader, writer) = pipe::pipe();
+    task::spawn("writer", move || {
+        let data = vec![7u8; 3 * PIPE_SIZE];
+        assert_eq!(writer.write(&data), Ok(data.len()));
+        DONE.signal();
+    });
+
+    let mut total = 0;
+    let mut buf = [0; 1000];
+    loop {
+        match reader.read(&mut buf).unwrap() {
+            0 => break,
+            read => {
+                assert!(buf[..read].iter().all(|byte| *byte == 7));
+                total += read;
+            }
+        }
+    }
+    assert!(DONE.is_signaled());
+    assert_eq!(total, 3 * PIPE_SIZE);
+}
+
+#[test_case]
+fn pipe_syscalls_use_file_descriptors() {
+    let mut fds = [0i32; 2];
+    assert_eq!(syscall(syscall::SYS_PIPE, [fds.as_mut_ptr() as u64, 0, 0, 0, 0, 0]), 0);
+    let [read_fd, write_fd] = fds.map(|fd| fd as u64);
+    assert!(read_fd > 2 && write_fd > 2);
+
+    let message = b"through the pipe";
+    let len = message.len() as u64;
+    let written = syscall(syscall::SYS_WRITE, [write_fd, message.as_ptr() as u64, len, 0, 0, 0]);
+    assert_eq!(written, len as i64);
+    assert_eq!(syscall(syscall::SYS_CLOSE, [write_fd, 0, 0, 0, 0, 0]), 0);
+
+    let mut buf = [0u8; 32];
+    let ptr = buf.as_mut_ptr() as u64;
+    assert_eq!(syscall(syscall::SYS_READ, [read_fd, ptr, 32, 0, 0, 0]), len as i64);
+    assert_eq!(&buf[..message.len()], message);
+    assert_eq!(syscall(syscall::SYS_READ, [read_fd, ptr, 32, 0, 0, 0]), 0);
+    assert_eq!(syscall(syscall::SYS_CLOSE, [read_fd, 0, 0, 0, 0, 0]), 0);
+    assert_eq!(syscall(syscall::SYS_CLOSE, [read_fd, 0, 0, 0, 0, 0]), -syscall::EBADF);
+}
+
+#[test_case]
+fn pipeline_job_connects_stages() {
+    let job = skyos::jobs::start_pipeline(vec![("echo", vec!["piped"]), ("cat", vec![])], true);
+    assert_eq!(job.stages.len(), 2);
+    assert_eq!(job.command, "echo piped | cat");
+    task::wait_for_exit(|| job.stages.iter().all(|id| task::has_exited(*id)));
+}
@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use skyos::{exit_qemu, preempt, serial_print, serial_println, timer, QemuExitCode};
+
+entry_point!(run);
+
+fn run(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    serial_print!("sleep_while_atomic::sleep_with_preemption_disabled...\t");
+    preempt::disable();
+    timer::sleep(Duration::from_millis(1));
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    skyos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    skyos::hlt_loop();
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::syscall::{self, syscall};
+use skyos::{klog, task};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn getpid_returns_current_task() {
+    let pid = syscall(syscall::SYS_GETPID, [0; 6]);
+    assert_eq!(pid as u64, task::current_id().0);
+}
+
+#[test_case]
+fn unknown_syscall_fails() {
+    assert_eq!(syscall(0xffff, [0; 6]), -syscall::ENOSYS);
+}
+
+#[test_case]
+fn traced_syscalls_are_logged() {
+    let id = task::current_id();
+    let start = klog::position();
+    task::set_traced(id, true);
+    syscall(syscall::SYS_WRITE, [7, 0, 0, 0, 0, 0]);
+    task::set_traced(id, false);
+    syscall(syscall::SYS_GETPID, [0; 6]);
+
+    let log = klog::read_since(start);
+    assert!(log.contains("write(0x7, 0x0, 0x0)"));
+    assert!(log.contains("write = -9"));
+    assert!(!log.contains("getpid"));
+}
@@ -0,0 +1,246 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use skyos::sync::{Event, WaitQueue};
+use skyos::testing::Test;
+use skyos::{task, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawned_task_runs() {
+    let done = Arc::new(Event::new());
+    let signal = done.clone();
+    task::spawn("test", move || signal.signal());
+    done.wait();
+    assert!(done.is_signaled());
+}
+
+#[test_case]
+fn wait_queue_wakes_all() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static GO: Event = Event::new();
+
+    for _ in 0..3 {
+        task::spawn("waiter", || {
+            GO.wait();
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+            QUEUE.wake_all();
+        });
+    }
+    GO.signal();
+    QUEUE.wait_until(|| COUNTER.load(Ordering::SeqCst) == 3);
+}
+
+#[test_case]
+fn sleep_waits_for_timer() {
+    let start = skyos::time::ticks();
+    skyos::timer::sleep(core::time::Duration::from_millis(20));
+    assert!(skyos::time::ticks() - start >= skyos::time::duration_to_ticks(core::time::Duration::from_millis(20)));
+}
+
+#[test_case]
+fn cancelled_timer_does_not_fire() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    let handle = skyos::timer::schedule_in(core::time::Duration::from_millis(5), || {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    });
+    assert!(handle.cancel());
+    skyos::timer::sleep(core::time::Duration::from_millis(10));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+const TIMER_FIRES_WHEN_STEPPED: Test =
+    skyos::unit_test!(timer_fires_when_stepped, "tasks", VirtualTime);
+
+fn timer_fires_when_stepped() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    let start = time::ticks();
+    skyos::timer::schedule_in(Duration::from_millis(5), || {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    });
+    // real time passes, virtual time doesn't
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    assert_eq!(time::ticks(), start);
+    time::step(Duration::from_millis(4));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+    time::step(Duration::from_millis(1));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    assert_eq!(time::ticks(), start + 5);
+}
+
+#[test_case]
+fn killed_task_exits_at_safe_point() {
+    static NEVER: Event = Event::new();
+    let id = task::spawn("victim", || NEVER.wait());
+    task::yield_now();
+    assert_eq!(task::state_of(id), Some(task::TaskState::Blocked));
+
+    task::kill(id).unwrap();
+    for _ in 0..10 {
+        task::yield_now();
+    }
+    assert!(matches!(task::state_of(id), None | Some(task::TaskState::Dead)));
+    assert_eq!(task::kill(task::BOOT_TASK), Err(task::SignalError::BootTask));
+}
+
+#[test_case]
+fn stopped_task_resumes_on_cont() {
+    use skyos::signal::Signal;
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = task::spawn("looper", || loop {
+        COUNTER.fetch_add(1, Ordering::SeqCst);
+        task::yield_now();
+    });
+    task::yield_now();
+    task::send_signal(id, Signal::Stop).unwrap();
+    task::yield_now();
+    assert_eq!(task::state_of(id), Some(task::TaskState::Stopped));
+    let stopped_at = COUNTER.load(Ordering::SeqCst);
+    task::yield_now();
+    assert_eq!(COUNTER.load(Ordering::SeqCst), stopped_at);
+
+    task::send_signal(id, Signal::Cont).unwrap();
+    task::yield_now();
+    assert!(COUNTER.load(Ordering::SeqCst) > stopped_at);
+    task::send_signal(id, Signal::Int).unwrap();
+    for _ in 0..10 {
+        task::yield_now();
+    }
+    assert!(matches!(task::state_of(id), None | Some(task::TaskState::Dead)));
+}
+
+#[test_case]
+fn signal_handler_runs_in_task() {
+    use skyos::signal::Signal;
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+    static READY: Event = Event::new();
+    static NEVER: Event = Event::new();
+    fn on_usr1(_: Signal) {
+        HANDLED.fetch_add(1, Ordering::SeqCst);
+    }
+    let id = task::spawn("handler", || {
+        task::set_signal_handler(Signal::Usr1, Some(on_usr1));
+        READY.signal();
+        NEVER.wait();
+    });
+    READY.wait();
+    task::send_signal(id, Signal::Usr1).unwrap();
+    task::yield_now();
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+    // the handler doesn't end the wait
+    assert_eq!(task::state_of(id), Some(task::TaskState::Blocked));
+    task::kill(id).unwrap();
+}
+
+#[test_case]
+fn finished_job_is_reaped() {
+    use alloc::vec;
+    let job = skyos::jobs::start("echo", vec!["job"], true);
+    task::wait_for_exit(|| task::has_exited(job.task));
+    for _ in 0..10 {
+        task::yield_now();
+    }
+    assert!(skyos::jobs::list().iter().all(|j| j.number != job.number));
+}
+
+#[test_case]
+fn cpu_time_shows_in_proc_stat() {
+    use alloc::{format, string::String};
+    use skyos::vfs::{FileSystem, ProcFs};
+    static SPUN: Event = Event::new();
+    static DONE: Event = Event::new();
+
+    let id = task::spawn("spinner", || {
+        let start = skyos::time::ticks();
+        while skyos::time::ticks() < start + 5 {
+            core::hint::spin_loop();
+        }
+        SPUN.signal();
+        DONE.wait();
+    });
+    SPUN.wait();
+    task::yield_now();
+
+    let stat = String::from_utf8(ProcFs.read(&format!("/{}/stat", id.0)).unwrap()).unwrap();
+    let prefix = format!("{} (spinner) S 0 ", id.0);
+    assert!(stat.starts_with(&prefix), "{}", stat);
+    let fields: alloc::vec::Vec<&str> = stat.split_whitespace().collect();
+    // utime and stime, everything runs in the kernel
+    assert_eq!(fields[13], "0");
+    assert!(fields[14].parse::<u64>().unwrap() >= 1);
+
+    let system = String::from_utf8(ProcFs.read("/stat").unwrap()).unwrap();
+    assert!(system.starts_with("cpu  0 0 "), "{}", system);
+    assert!(system.lines().any(|line| line.starts_with("ctxt ")));
+    let entries = ProcFs.read_dir("/").unwrap();
+    assert!(entries
+        .iter()
+        .any(|entry| entry.name == format!("{}", id.0)));
+
+    DONE.signal();
+    task::wait_for_exit(|| task::has_exited(id));
+}
+
+#[test_case]
+fn sleeping_task_shows_as_sleeping() {
+    let id = task::spawn("sleeper", || {
+        skyos::timer::sleep(core::time::Duration::from_millis(50))
+    });
+    task::yield_now();
+    assert_eq!(task::state_of(id), Some(task::TaskState::Sleeping));
+    task::wait_for_exit(|| task::has_exited(id));
+}
+
+#[test_case]
+fn busy_task_drops_below_waiting_ones() {
+    use core::sync::atomic::AtomicBool;
+    use core::time::Duration;
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let id = task::spawn("spinner", || {
+        while !STOP.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    });
+    let level_of = |id| task::list().into_iter().find(|t| t.id == id).unwrap().level;
+    // every priority boost puts it back on top for a moment
+    let mut demoted = false;
+    for _ in 0..20 {
+        skyos::timer::sleep(Duration::from_millis(5));
+        if level_of(id) > 0 {
+            demoted = true;
+            break;
+        }
+    }
+    STOP.store(true, Ordering::SeqCst);
+    task::wait_for_exit(|| task::has_exited(id));
+    assert!(demoted);
+}
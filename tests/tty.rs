@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use pc_keyboard::{DecodedKey, KeyCode};
+use skyos::error::KError;
+use skyos::sync::Event;
+use skyos::tty::{self, Settings, Tty};
+use skyos::{keyboard, task};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+fn type_str(tty: &Tty, text: &str) {
+    for char in text.chars() {
+        tty.input(DecodedKey::Unicode(char));
+    }
+}
+
+#[test_case]
+fn canonical_mode_edits_lines() {
+    static TTY: Tty = Tty::new();
+    type_str(&TTY, "lx\x08s -l\n");
+    type_str(&TTY, "gone\x15cat\n");
+    let mut buf = [0; 32];
+    assert_eq!(TTY.read(&mut buf), Ok(6));
+    assert_eq!(&buf[..6], b"ls -l\n");
+    assert_eq!(TTY.read(&mut buf), Ok(4));
+    assert_eq!(&buf[..4], b"cat\n");
+}
+
+#[test_case]
+fn ctrl_d_ends_input() {
+    static TTY: Tty = Tty::new();
+    type_str(&TTY, "partial\x04\x04");
+    let mut buf = [0; 32];
+    assert_eq!(TTY.read(&mut buf), Ok(7));
+    assert_eq!(TTY.read(&mut buf), Ok(0));
+}
+
+#[test_case]
+fn ctrl_c_interrupts_reader_without_foreground_job() {
+    static TTY: Tty = Tty::new();
+    type_str(&TTY, "discarded\x03");
+    let mut buf = [0; 32];
+    assert_eq!(TTY.read(&mut buf), Err(KError::Interrupted));
+}
+
+#[test_case]
+fn raw_mode_passes_keys_through() {
+    static TTY: Tty = Tty::new();
+    TTY.set_settings(Settings::RAW);
+    type_str(&TTY, "q\x03");
+    TTY.input(DecodedKey::RawKey(KeyCode::ArrowUp));
+    let mut buf = [0; 32];
+    assert_eq!(TTY.read(&mut buf), Ok(5));
+    assert_eq!(&buf[..5], b"q\x03\x1b[A");
+}
+
+#[test_case]
+fn killed_grabber_gives_the_keys_back() {
+    static NEVER: Event = Event::new();
+    let id = task::spawn("grabber", || {
+        keyboard::grab();
+        NEVER.wait();
+    });
+    task::yield_now();
+    assert!(keyboard::is_grabbed());
+
+    task::kill(id).unwrap();
+    task::wait_for_exit(|| task::has_exited(id));
+    assert!(!keyboard::is_grabbed());
+    for char in "ls\n".chars() {
+        keyboard::handle_key(DecodedKey::Unicode(char));
+    }
+    let mut buf = [0; 32];
+    assert_eq!(tty::CONSOLE.read(&mut buf), Ok(3));
+    assert_eq!(&buf[..3], b"ls\n");
+}
@@ -0,0 +1,191 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(skyos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{format, sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use skyos::ext::{Errno, FileType};
+use skyos::archive::{self, Archive, EntryKind};
+use skyos::bench::{FsBench, Workload, RANDOM_CHUNK};
+use skyos::device::{self, Bus};
+use skyos::drivers::ramdisk::RamDisk;
+use skyos::vfs::{self, FileSystem, RamFs, SysFs};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    skyos::shared_init();
+    skyos::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    skyos::test_panic_handler(info)
+}
+
+#[test_case]
+fn normalize_paths() {
+    assert_eq!(vfs::normalize("/a//b/./c/../d/").unwrap(), "/a/b/d");
+    assert_eq!(vfs::normalize("/..").unwrap(), "/");
+    assert!(vfs::normalize("relative").is_err());
+}
+
+#[test_case]
+fn write_read_and_list() {
+    vfs::create_dir("/etc").unwrap();
+    vfs::write("/etc/motd", b"hello").unwrap();
+    assert_eq!(vfs::read("/etc/motd").unwrap(), b"hello");
+
+    let entries = vfs::read_dir("/etc").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "motd");
+    assert_eq!(entries[0].metadata.size, 5);
+    assert!(matches!(vfs::read("/etc"), Err(Errno::IsDirectory)));
+    assert!(matches!(vfs::remove("/etc"), Err(Errno::AccessError)));
+}
+
+#[test_case]
+fn mounts_shadow_parent() {
+    vfs::mount("/mnt", Arc::new(RamFs::new())).unwrap();
+    vfs::write("/mnt/file", b"x").unwrap();
+    assert!(vfs::read("/file").is_err());
+    assert_eq!(vfs::metadata("/mnt").unwrap().file_type, FileType::Directory);
+    vfs::unmount("/mnt").unwrap();
+    assert!(vfs::read("/mnt/file").is_err());
+}
+
+fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let header = format!(
+        "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+        0, mode, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0
+    );
+    archive.extend_from_slice(header.as_bytes());
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    while archive.len() % 4 != 0 {
+        archive.push(0);
+    }
+    archive.extend_from_slice(data);
+    while archive.len() % 4 != 0 {
+        archive.push(0);
+    }
+}
+
+#[test_case]
+fn unpack_cpio_archive() {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, ".", 0o040755, b"");
+    cpio_entry(&mut archive, "etc/init.conf", 0o100644, b"shell");
+    cpio_entry(&mut archive, "TRAILER!!!", 0, b"");
+
+    let entries = Archive::new(RamDisk::new(&archive)).unwrap().entries().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].path, "/etc/init.conf");
+    assert_eq!(entries[0].kind, EntryKind::File);
+
+    let fs = RamFs::new();
+    let mut archive = Archive::new(RamDisk::new(&archive)).unwrap();
+    assert_eq!(archive::extract(&mut archive, &fs, "/").unwrap(), 1);
+    assert_eq!(fs.metadata("/etc").unwrap().file_type, FileType::Directory);
+    assert_eq!(fs.read("/etc/init.conf").unwrap(), b"shell");
+}
+
+#[test_case]
+fn list_ustar_archive() {
+    let mut archive = vec![0u8; 512 * 4];
+    let header = &mut archive[..512];
+    header[..9].copy_from_slice(b"hello.txt");
+    header[100..107].copy_from_slice(b"0000644");
+    header[124..135].copy_from_slice(b"00000000005");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    archive[512..517].copy_from_slice(b"world");
+
+    let mut archive = Archive::new(RamDisk::new(&archive)).unwrap();
+    let entry = archive.next_entry().unwrap().unwrap();
+    assert_eq!(entry.path, "/hello.txt");
+    assert_eq!(entry.mode, 0o644);
+    assert_eq!(archive.read(&entry).unwrap(), b"world");
+    assert!(archive.next_entry().unwrap().is_none());
+}
+
+#[test_case]
+fn oversized_archive_entries_are_errors() {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, "big", 0o120777, b"target");
+    // the size of the link target, then the size of the name
+    archive[54..62].copy_from_slice(b"fffffff0");
+    let result = Archive::new(RamDisk::new(&archive)).unwrap().next_entry();
+    assert!(matches!(result, Err(Errno::InvalidFileImage)));
+    archive[54..62].copy_from_slice(b"00000006");
+    archive[94..102].copy_from_slice(b"fffffff0");
+    let result = Archive::new(RamDisk::new(&archive)).unwrap().next_entry();
+    assert!(matches!(result, Err(Errno::InvalidFileImage)));
+
+    let mut archive = vec![0u8; 512 * 2];
+    let header = &mut archive[..512];
+    header[..3].copy_from_slice(b"big");
+    header[124..135].copy_from_slice(b"77777777777");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    let mut archive = Archive::new(RamDisk::new(&archive)).unwrap();
+    let entry = archive.next_entry().unwrap().unwrap();
+    assert!(matches!(archive.read(&entry), Err(Errno::InvalidFileImage)));
+}
+
+#[test_case]
+fn partial_reads_and_writes() {
+    let fs = RamFs::new();
+    fs.write("/file", b"hello").unwrap();
+    fs.write_at("/file", 7, b"world").unwrap();
+    assert_eq!(fs.read("/file").unwrap(), b"hello\0\0world");
+
+    let mut buf = [0; 4];
+    assert_eq!(fs.read_at("/file", 2, &mut buf).unwrap(), 4);
+    assert_eq!(&buf, b"llo\0");
+    assert_eq!(fs.read_at("/file", 20, &mut buf).unwrap(), 0);
+}
+
+#[test_case]
+fn fsbench_cleans_up() {
+    let fs = Arc::new(RamFs::new());
+    let mut bench = FsBench::new(fs.clone(), "/", 3 * RANDOM_CHUNK as u64, 5).unwrap();
+    // the reads fill the file themselves
+    let report = bench.run(Workload::SequentialRead).unwrap();
+    assert_eq!(report.bytes, 3 * RANDOM_CHUNK as u64);
+    assert_eq!(bench.run(Workload::RandomWrite).unwrap().latencies.len(), 3);
+    assert_eq!(bench.run(Workload::Create).unwrap().latencies.len(), 5);
+    assert_eq!(fs.read_dir("/fsbench.tmp").unwrap().len(), 6);
+    assert_eq!(bench.run(Workload::Delete).unwrap().latencies.len(), 5);
+
+    bench.finish().unwrap();
+    assert!(fs.read_dir("/").unwrap().is_empty());
+}
+
+#[test_case]
+fn sysfs_shows_device_tree() {
+    let bridge = device::register("bridge", Bus::Virtual, None, Vec::new());
+    let attributes = vec![("vendor", "0x1234".into())];
+    let disk = device::register("disk", Bus::Virtual, Some(bridge), attributes);
+    device::bind(disk, "test-driver");
+
+    let fs = SysFs;
+    let entries = fs.read_dir("/devices/bridge").unwrap();
+    assert!(entries.iter().any(|entry| entry.name == "disk"));
+    assert_eq!(fs.metadata("/devices/bridge/disk").unwrap().file_type, FileType::Directory);
+    assert_eq!(fs.read("/devices/bridge/disk/vendor").unwrap(), b"0x1234\n");
+    assert_eq!(fs.read("/devices/bridge/disk/driver").unwrap(), b"test-driver\n");
+    assert_eq!(fs.read("/devices/bridge/disk/state").unwrap(), b"bound\n");
+    assert!(matches!(fs.read("/devices/bridge/nothing"), Err(Errno::NotFound)));
+
+    device::unregister(bridge);
+    assert!(fs.metadata("/devices/bridge").is_err());
+}
@@ -0,0 +1,124 @@
+#!/usr/bin/env python3
+"""Moves files between the host and the guest through a shared disk image.
+
+Usage: fileshare.py create <image> <size in MiB>
+       fileshare.py pack <image> <directory>
+       fileshare.py unpack <image> <directory>
+       fileshare.py ls <image>
+
+The format is described in src/fileshare.rs. Attach the image as a second disk, for example
+with `-drive file=share.img,format=raw,if=none,id=share -device ide-hd,drive=share,bus=ahci.1`,
+and use the `fileshare` command in the guest. `pack` and `create` keep the image's size so
+the guest has room to export into it.
+"""
+import os
+import struct
+import sys
+import zlib
+
+MAGIC = b"SKYSHARE"
+RECORD_MAGIC = b"SREC"
+VERSION = 1
+SECTOR_SIZE = 512
+KIND_FILE = 1
+KIND_DIRECTORY = 2
+
+
+def align(n):
+    return -(-n // SECTOR_SIZE) * SECTOR_SIZE
+
+
+def record(path, kind, data=b""):
+    path = path.encode()
+    header = RECORD_MAGIC + struct.pack("<IIIQ", kind, len(path), zlib.crc32(data), len(data))
+    body = header + path + data
+    return body + bytes(align(len(body)) - len(body))
+
+
+def header(count):
+    header = MAGIC + struct.pack("<II", VERSION, count)
+    return header + bytes(SECTOR_SIZE - len(header))
+
+
+def build(root):
+    records = []
+    for directory, dirs, files in os.walk(root):
+        dirs.sort()
+        relative = os.path.relpath(directory, root)
+        if relative != ".":
+            records.append(record(relative.replace(os.sep, "/"), KIND_DIRECTORY))
+        for name in sorted(files):
+            path = os.path.join(directory, name)
+            with open(path, "rb") as f:
+                data = f.read()
+            name = os.path.relpath(path, root).replace(os.sep, "/")
+            records.append(record(name, KIND_FILE, data))
+    return header(len(records)) + b"".join(records)
+
+
+def parse(image):
+    if image[:8] != MAGIC:
+        sys.exit("fileshare: not a share")
+    version, count = struct.unpack_from("<II", image, 8)
+    if version != VERSION:
+        sys.exit(f"fileshare: unsupported version {version}")
+    pos = SECTOR_SIZE
+    for _ in range(count):
+        magic = image[pos:pos + 4]
+        kind, path_len, crc, size = struct.unpack_from("<IIIQ", image, pos + 4)
+        if magic != RECORD_MAGIC or kind not in (KIND_FILE, KIND_DIRECTORY):
+            sys.exit(f"fileshare: broken record at {pos:#x}")
+        path = image[pos + 24:pos + 24 + path_len].decode()
+        if any(part in ("", ".", "..") for part in path.split("/")):
+            sys.exit(f"fileshare: invalid path {path!r}")
+        data = image[pos + 24 + path_len:pos + 24 + path_len + size]
+        if zlib.crc32(data) != crc:
+            sys.exit(f"fileshare: {path} is corrupted")
+        yield path, kind, data
+        pos = align(pos + 24 + path_len + size)
+
+
+def read_image(path):
+    with open(path, "rb") as f:
+        return f.read()
+
+
+def write_image(path, image):
+    size = os.path.getsize(path) if os.path.exists(path) else 0
+    if size and len(image) > size:
+        sys.exit(f"fileshare: needs {len(image)} bytes, the image has {size}")
+    with open(path, "r+b" if size else "wb") as f:
+        f.write(image)
+
+
+def main():
+    if len(sys.argv) < 3:
+        sys.exit(__doc__)
+    command, image = sys.argv[1], sys.argv[2]
+    if command == "create" and len(sys.argv) == 4:
+        with open(image, "wb") as f:
+            f.truncate(int(sys.argv[3]) << 20)
+        write_image(image, header(0))
+    elif command == "pack" and len(sys.argv) == 4:
+        write_image(image, build(sys.argv[3]))
+    elif command == "unpack" and len(sys.argv) == 4:
+        for path, kind, data in parse(read_image(image)):
+            path = os.path.join(sys.argv[3], *path.split("/"))
+            if kind == KIND_DIRECTORY:
+                os.makedirs(path, exist_ok=True)
+            else:
+                os.makedirs(os.path.dirname(path), exist_ok=True)
+                with open(path, "wb") as f:
+                    f.write(data)
+    elif command == "ls" and len(sys.argv) == 3:
+        for path, kind, data in parse(read_image(image)):
+            if kind == KIND_DIRECTORY:
+                print(f"{path}/")
+            else:
+                print(f"{path:<32} {len(data):>8}")
+    else:
+        sys.exit(__doc__)
+
+
+if __name__ == "__main__":
+    main()
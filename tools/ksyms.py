@@ -0,0 +1,88 @@
+#!/usr/bin/env python3
+"""Fills the `.ksyms` section of a linked kernel with its symbol table.
+
+Usage: ksyms.py <kernel elf>
+
+The format is described in src/ksyms.rs. Symbols are read with `nm`, which has to be
+binutils or llvm-nm (set $NM to override).
+"""
+import os
+import struct
+import subprocess
+import sys
+
+MAGIC = b"SKYKSYM1"
+SECTION = b".ksyms"
+MAX_NAME = 255
+RESTART_INTERVAL = 16
+
+
+def find_section(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit("ksyms: not a 64 bit elf file")
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    strtab_offset, = struct.unpack_from("<Q", elf, shoff + shstrndx * shentsize + 0x18)
+    for i in range(shnum):
+        header = shoff + i * shentsize
+        name, = struct.unpack_from("<I", elf, header)
+        end = elf.index(b"\0", strtab_offset + name)
+        if elf[strtab_offset + name:end] == SECTION:
+            offset, size = struct.unpack_from("<QQ", elf, header + 0x18)
+            return offset, size
+    sys.exit("ksyms: the kernel has no .ksyms section")
+
+
+def read_symbols(path):
+    nm = os.environ.get("NM", "nm")
+    output = subprocess.run(
+        [nm, "--defined-only", "--numeric-sort", "--demangle", path],
+        check=True, capture_output=True, text=True,
+    ).stdout
+    symbols = {}
+    for line in output.splitlines():
+        parts = line.split(" ", 2)
+        if len(parts) != 3 or parts[1] not in "tTwW":
+            continue
+        addr = int(parts[0], 16)
+        name = parts[2].encode()[:MAX_NAME]
+        # strip the hash suffix of legacy mangled names
+        if len(name) > 19 and name[-19:-16] == b"::h":
+            name = name[:-19]
+        symbols.setdefault(addr, name)
+    return sorted(symbols.items())
+
+
+def build_table(symbols):
+    entries = bytearray()
+    names = bytearray()
+    previous = b""
+    for i, (addr, name) in enumerate(symbols):
+        prefix = 0
+        if i % RESTART_INTERVAL != 0:
+            while prefix < min(len(name), len(previous)) and name[prefix] == previous[prefix]:
+                prefix += 1
+        entries += struct.pack("<QII", addr, len(names), 0)
+        names += struct.pack("<BB", prefix, len(name) - prefix) + name[prefix:]
+        previous = name
+    header = MAGIC + struct.pack("<II", len(symbols), 16 + len(entries))
+    return header + entries + names
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+    offset, size = find_section(elf)
+    table = build_table(read_symbols(path))
+    if len(table) > size:
+        sys.exit(f"ksyms: table needs {len(table)} bytes, increase KSYMS_SIZE ({size})")
+    elf[offset:offset + size] = table + bytes(size - len(table))
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,135 @@
+#!/usr/bin/env python3
+"""Builds a bootable disk image with the kernel, the userspace programs and an ext2 root.
+
+Usage: mkimage.py [--debug] [--size <MiB>] [--output <image>] [--run]
+
+The image starts with the boot image of `cargo bootimage`, the boot loader and the kernel,
+padded to 1 MiB. Then follows the root file system, an ext2 partition listed in the boot
+sector's MBR. The kernel is built with `root=/dev/sda1` on its command line (plus
+$SKYOS_CMDLINE) and mounts the partition as `/` once the disks are found. The root holds the
+contents of `fs/` and the programs of `userspace/` in `/bin`.
+
+`--run` boots the image in QEMU as the only disk. To run the tests with it attached, set
+`SKYOS_DISK=<image>` for `cargo test`, see tools/run.sh. Needs `mke2fs` from e2fsprogs.
+"""
+import os
+import shutil
+import struct
+import subprocess
+import sys
+import tempfile
+
+ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+TARGET = "x86_64-unknown-none"
+USER_TARGET = "x86_64-skyos"
+PROGRAMS = ["hello", "cat", "sh"]
+
+SECTOR_SIZE = 512
+PARTITION_START = 1024 * 1024
+TABLE_OFFSET = 446
+SIGNATURE_OFFSET = 510
+LINUX_PARTITION = 0x83
+
+
+def usage():
+    sys.exit(__doc__.strip().splitlines()[2])
+
+
+def cargo(args, cwd, env=None):
+    subprocess.run(["cargo"] + args, cwd=cwd, env=env, check=True)
+
+
+def build_kernel(profile):
+    env = dict(os.environ)
+    env["SKYOS_CMDLINE"] = " ".join(filter(None, ["root=/dev/sda1", env.get("SKYOS_CMDLINE")]))
+    flags = ["--release"] if profile == "release" else []
+    # the symbol table goes into the kernel before the boot image is made from it
+    cargo(["build"] + flags, ROOT, env)
+    kernel = os.path.join(ROOT, "target", TARGET, profile, "skyos")
+    subprocess.run([sys.executable, os.path.join(ROOT, "tools", "ksyms.py"), kernel], check=True)
+    cargo(["bootimage"] + flags, ROOT, env)
+    return os.path.join(ROOT, "target", TARGET, profile, "bootimage-skyos.bin")
+
+
+def build_programs(profile):
+    userspace = os.path.join(ROOT, "userspace")
+    cargo(["build"] + (["--release"] if profile == "release" else []), userspace)
+    return [os.path.join(userspace, "target", USER_TARGET, profile, name) for name in PROGRAMS]
+
+
+def make_root(directory, programs, size, output):
+    shutil.copytree(os.path.join(ROOT, "fs"), directory, dirs_exist_ok=True)
+    os.makedirs(os.path.join(directory, "bin"), exist_ok=True)
+    for program in programs:
+        shutil.copy(program, os.path.join(directory, "bin"))
+    # the features src/ext supports
+    subprocess.run(
+        ["mke2fs", "-q", "-F", "-t", "ext2", "-b", "1024", "-I", "256", "-O", "none,filetype",
+         "-L", "skyos", "-d", directory, output, "%dM" % size],
+        check=True,
+    )
+
+
+def partition_entry(first, sectors):
+    # no CHS addresses, only LBA is used
+    return struct.pack("<B3sB3sII", 0, b"\xff\xff\xff", LINUX_PARTITION, b"\xff\xff\xff",
+                       first, sectors)
+
+
+def assemble(boot, root, output):
+    with open(boot, "rb") as f:
+        image = bytearray(f.read())
+    if len(image) > PARTITION_START:
+        sys.exit("mkimage: the boot image is larger than %d bytes" % PARTITION_START)
+    if image[SIGNATURE_OFFSET:SIGNATURE_OFFSET + 2] != b"\x55\xaa":
+        sys.exit("mkimage: %s has no boot signature" % boot)
+    if any(image[TABLE_OFFSET:SIGNATURE_OFFSET]):
+        sys.exit("mkimage: the boot sector of %s has no room for a partition table" % boot)
+    with open(root, "rb") as f:
+        partition = f.read()
+    image[TABLE_OFFSET:TABLE_OFFSET + 16] = partition_entry(
+        PARTITION_START // SECTOR_SIZE, len(partition) // SECTOR_SIZE)
+    image.extend(bytes(PARTITION_START - len(image)))
+    with open(output, "wb") as f:
+        f.write(image)
+        f.write(partition)
+
+
+def run(image):
+    subprocess.run(
+        ["qemu-system-x86_64", "-drive", "id=root,file=%s,if=none,format=raw" % image,
+         "-device", "ahci,id=ahci", "-device", "ide-hd,drive=root,bus=ahci.0",
+         "-nic", "user,model=virtio-net-pci", "-serial", "stdio"],
+        check=True,
+    )
+
+
+def main(args):
+    profile, size, run_it = "release", 32, False
+    output = os.path.join(ROOT, "target", "skyos.img")
+    while args:
+        arg = args.pop(0)
+        if arg == "--debug":
+            profile = "debug"
+        elif arg == "--run":
+            run_it = True
+        elif arg == "--size" and args:
+            size = int(args.pop(0))
+        elif arg == "--output" and args:
+            output = args.pop(0)
+        else:
+            usage()
+
+    boot = build_kernel(profile)
+    programs = build_programs(profile)
+    with tempfile.TemporaryDirectory() as tmp:
+        root = os.path.join(tmp, "root.img")
+        make_root(os.path.join(tmp, "root"), programs, size, root)
+        assemble(boot, root, output)
+    print("mkimage: wrote %s" % output)
+    if run_it:
+        run(output)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Cargo runner: embeds the symbol table into the kernel, then boots it with bootimage.
+# $SKYOS_DISK attaches a disk image, like the one tools/mkimage.py builds, on its own AHCI
+# controller. It's /dev/sda in tests, which have no other disks.
+set -e
+python3 "$(dirname "$0")/ksyms.py" "$1"
+if [ -n "$SKYOS_DISK" ]; then
+    exec bootimage runner "$@" \
+        -drive "id=root,file=$SKYOS_DISK,if=none,format=raw" \
+        -device ahci,id=rootahci -device ide-hd,drive=root,bus=rootahci.0
+fi
+exec bootimage runner "$@"
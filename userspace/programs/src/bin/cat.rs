@@ -0,0 +1,46 @@
+//! Copies files, or stdin without arguments, to stdout.
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec;
+use skyos_rt::io::{self, STDIN, STDOUT};
+use skyos_rt::{eprintln, sys, Args};
+
+skyos_rt::entry!(main);
+
+fn copy(fd: i32) -> io::Result<()> {
+    let mut buf = vec![0; 4096];
+    loop {
+        match io::read(fd, &mut buf)? {
+            0 => return Ok(()),
+            read => io::write_all(STDOUT, &buf[..read])?,
+        }
+    }
+}
+
+fn main(args: Args) -> i32 {
+    if args.len() < 2 {
+        return match copy(STDIN) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("cat: {e}");
+                1
+            }
+        };
+    }
+    let mut status = 0;
+    for path in args.iter().skip(1) {
+        let result = io::open(path, sys::O_RDONLY).and_then(|fd| {
+            let result = copy(fd);
+            let _ = io::close(fd);
+            result
+        });
+        if let Err(e) = result {
+            eprintln!("cat: {path}: {e}");
+            status = 1;
+        }
+    }
+    status
+}
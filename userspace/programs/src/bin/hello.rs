@@ -0,0 +1,15 @@
+//! Greets with its arguments.
+#![no_std]
+#![no_main]
+
+use skyos_rt::{println, Args};
+
+skyos_rt::entry!(main);
+
+fn main(args: Args) -> i32 {
+    println!("Hello from {}!", args.program());
+    for (i, arg) in args.iter().enumerate().skip(1) {
+        println!("argument {i}: {arg}");
+    }
+    0
+}
@@ -0,0 +1,66 @@
+//! A shell with a few builtins.
+//!
+//! There is no system call to start programs yet, so it can't run any, only its builtins.
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use skyos_rt::io::{self, STDIN};
+use skyos_rt::{eprintln, print, println, sys, Args};
+
+skyos_rt::entry!(main);
+
+/// Reads a line from stdin into `line`, without the newline. Returns false at the end of the
+/// input.
+fn read_line(line: &mut Vec<u8>) -> bool {
+    line.clear();
+    let mut byte = [0];
+    loop {
+        match io::read(STDIN, &mut byte) {
+            Ok(0) | Err(_) => return !line.is_empty(),
+            Ok(_) if byte[0] == b'\n' => return true,
+            Ok(_) => line.push(byte[0]),
+        }
+    }
+}
+
+/// Runs a builtin, returns the exit status to leave the shell with for `exit`.
+fn run(args: &[&str]) -> Option<i32> {
+    match args {
+        [] => {}
+        ["exit"] => return Some(0),
+        ["exit", status] => return Some(status.parse().unwrap_or(1)),
+        ["echo", words @ ..] => println!("{}", words.join(" ")),
+        ["pid"] => println!("{}", unsafe { sys::getpid() }),
+        ["uptime"] => println!("{} ms", unsafe { sys::uptime() }),
+        ["sleep", millis] => match millis.parse() {
+            Ok(millis) => unsafe {
+                sys::sleep(millis);
+            },
+            Err(_) => eprintln!("sleep: invalid time {millis}"),
+        },
+        ["help"] => println!("builtins: echo exit help pid sleep uptime"),
+        [name, ..] => eprintln!("sh: {name}: not found"),
+    }
+    None
+}
+
+fn main(_: Args) -> i32 {
+    let mut line = Vec::new();
+    loop {
+        print!("$ ");
+        if !read_line(&mut line) {
+            return 0;
+        }
+        let Ok(line) = core::str::from_utf8(&line) else {
+            eprintln!("sh: input isn't UTF-8");
+            continue;
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if let Some(status) = run(&args) {
+            return status;
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! The heap, a bump allocator on anonymous `mmap` memory.
+//!
+//! Small allocations are carved out of chunks of `CHUNK_SIZE` bytes, and memory only goes back
+//! to a chunk when its latest allocation is freed. That suits programs that run briefly. Large
+//! allocations get a mapping of their own, which is unmapped when they are freed. Everything is
+//! unmapped by the kernel when the program's task exits.
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::ptr;
+
+use crate::sys;
+
+const PAGE_SIZE: usize = 4096;
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Allocations above this size are mapped on their own.
+const LARGE: usize = CHUNK_SIZE / 4;
+
+struct Heap {
+    next: Cell<usize>,
+    end: Cell<usize>,
+}
+
+// programs have a single thread
+unsafe impl Sync for Heap {}
+
+#[global_allocator]
+static HEAP: Heap = Heap {
+    next: Cell::new(0),
+    end: Cell::new(0),
+};
+
+/// Maps `len` bytes of zeroed memory, null if that fails.
+fn map(len: usize) -> *mut u8 {
+    let prot = sys::PROT_READ | sys::PROT_WRITE;
+    let flags = sys::MAP_PRIVATE | sys::MAP_ANONYMOUS;
+    let addr = unsafe { sys::mmap(ptr::null(), len, prot, flags, -1, 0) };
+    if addr < 0 {
+        return ptr::null_mut();
+    }
+    addr as *mut u8
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() > PAGE_SIZE {
+            return ptr::null_mut();
+        }
+        if layout.size() > LARGE {
+            return map(layout.size().next_multiple_of(PAGE_SIZE));
+        }
+        let mut start = self.next.get().next_multiple_of(layout.align());
+        if self.next.get() == 0 || start + layout.size() > self.end.get() {
+            let chunk = map(CHUNK_SIZE);
+            if chunk.is_null() {
+                return ptr::null_mut();
+            }
+            self.end.set(chunk as usize + CHUNK_SIZE);
+            start = (chunk as usize).next_multiple_of(layout.align());
+        }
+        self.next.set(start + layout.size());
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.size() > LARGE {
+            sys::munmap(ptr, layout.size().next_multiple_of(PAGE_SIZE));
+        } else if ptr as usize + layout.size() == self.next.get() {
+            self.next.set(ptr as usize);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Files and printing.
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::sys;
+
+pub const STDIN: i32 = 0;
+pub const STDOUT: i32 = 1;
+pub const STDERR: i32 = 2;
+
+/// An error number returned by a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(pub i32);
+
+impl Error {
+    pub const NOT_FOUND: Self = Self(2);
+    pub const INVALID_ARGUMENT: Self = Self(22);
+
+    fn message(self) -> Option<&'static str> {
+        Some(match self.0 {
+            1 => "operation not permitted",
+            2 => "no such file or directory",
+            5 => "input/output error",
+            8 => "exec format error",
+            9 => "bad file descriptor",
+            12 => "out of memory",
+            13 => "permission denied",
+            17 => "file exists",
+            20 => "not a directory",
+            21 => "is a directory",
+            22 => "invalid argument",
+            24 => "too many open files",
+            28 => "no space left on device",
+            36 => "file name too long",
+            38 => "function not implemented",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.message() {
+            Some(message) => f.write_str(message),
+            None => write!(f, "error {}", self.0),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Turns the result of a system call into a `Result`.
+pub fn check(result: isize) -> Result<usize> {
+    if result < 0 {
+        Err(Error(-result as i32))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+pub fn read(fd: i32, buf: &mut [u8]) -> Result<usize> {
+    check(unsafe { sys::read(fd, buf.as_mut_ptr(), buf.len()) })
+}
+
+pub fn write(fd: i32, buf: &[u8]) -> Result<usize> {
+    check(unsafe { sys::write(fd, buf.as_ptr(), buf.len()) })
+}
+
+pub fn write_all(fd: i32, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match write(fd, buf)? {
+            0 => return Err(Error(5)),
+            written => buf = &buf[written..],
+        }
+    }
+    Ok(())
+}
+
+/// Opens `path` with the `O_*` flags of `sys`.
+pub fn open(path: &str, flags: u64) -> Result<i32> {
+    if path.contains('\0') {
+        return Err(Error::INVALID_ARGUMENT);
+    }
+    let mut c_path = Vec::with_capacity(path.len() + 1);
+    c_path.extend_from_slice(path.as_bytes());
+    c_path.push(0);
+    check(unsafe { sys::open(c_path.as_ptr(), flags, 0) }).map(|fd| fd as i32)
+}
+
+pub fn close(fd: i32) -> Result<()> {
+    check(unsafe { sys::close(fd) }).map(|_| ())
+}
+
+/// Writes formatted text to a file descriptor.
+pub struct Writer(pub i32);
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(self.0, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: i32, args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Writer(fd), args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
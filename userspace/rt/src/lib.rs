@@ -0,0 +1,106 @@
+//! The runtime of skyos programs.
+//!
+//! Provides the entry point the kernel's `exec` calls, a panic handler, a heap for `alloc` and
+//! `print!` like macros, on top of the system calls of `skyos-sys`. A program is `no_std` and
+//! `no_main` and names its main function with `entry!`:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use skyos_rt::{println, Args};
+//!
+//! skyos_rt::entry!(main);
+//!
+//! fn main(args: Args) -> i32 {
+//!     println!("hello from {}", args.program());
+//!     0
+//! }
+//! ```
+//!
+//! It's built for `x86_64-skyos.json`, which links statically linked position independent
+//! executables, the only kind `exec` loads.
+#![no_std]
+
+extern crate alloc;
+
+mod heap;
+pub mod io;
+
+use core::ffi::{c_char, CStr};
+use core::panic::PanicInfo;
+
+pub use skyos_sys as sys;
+
+/// Defines the entry point of the program, which calls `main` with the arguments and returns
+/// what it returns as the exit status.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[no_mangle]
+        pub extern "C" fn _start(argc: usize, argv: *const *const u8) -> i64 {
+            let main: fn($crate::Args) -> i32 = $main;
+            main(unsafe { $crate::Args::new(argc, argv) }) as i64
+        }
+    };
+}
+
+/// The arguments of the program, the first is its name.
+#[derive(Debug, Clone, Copy)]
+pub struct Args {
+    argc: usize,
+    argv: *const *const u8,
+}
+
+impl Args {
+    /// # Safety
+    ///
+    /// `argv` must hold `argc` pointers to NUL-terminated strings that are never freed, as
+    /// passed to the entry point.
+    pub unsafe fn new(argc: usize, argv: *const *const u8) -> Self {
+        Self { argc, argv }
+    }
+
+    pub fn len(&self) -> usize {
+        self.argc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argc == 0
+    }
+
+    /// Returns argument `i`, arguments that aren't UTF-8 are empty.
+    pub fn get(&self, i: usize) -> Option<&'static str> {
+        if i >= self.argc {
+            return None;
+        }
+        let arg = unsafe { CStr::from_ptr(*self.argv.add(i) as *const c_char) };
+        Some(arg.to_str().unwrap_or(""))
+    }
+
+    /// The name the program was run as.
+    pub fn program(&self) -> &'static str {
+        self.get(0).unwrap_or("")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> {
+        let args = *self;
+        (0..self.argc).filter_map(move |i| args.get(i))
+    }
+}
+
+/// Ends the program. The kernel doesn't keep the exit status of a task, only a status returned
+/// from `main` is reported.
+pub fn exit(status: i32) -> ! {
+    unsafe { sys::exit(status as u64) };
+    // only the boot task can't exit, which doesn't run programs
+    loop {
+        unsafe { sys::r#yield() };
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{info}");
+    exit(101)
+}
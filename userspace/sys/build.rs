@@ -0,0 +1,141 @@
+//! Generates the system call wrappers and `skyos/syscalls.h` from the kernel's table.
+use std::fmt::Write;
+use std::{env, fs, path::PathBuf};
+
+#[path = "../../src/syscall_table.rs"]
+#[allow(dead_code)]
+mod syscall_table;
+
+use syscall_table::{Syscall, CONSTANTS, SYSCALLS};
+
+const TABLE: &str = "../../src/syscall_table.rs";
+
+/// Rust keywords used as system call names.
+const KEYWORDS: &[&str] = &["yield"];
+
+fn doc_lines(syscall: &Syscall, prefix: &str) -> String {
+    syscall
+        .doc
+        .lines()
+        .map(|line| format!("{prefix}{}\n", line.trim()))
+        .collect()
+}
+
+fn rust_bindings() -> String {
+    let mut out = String::from("// Generated from src/syscall_table.rs, do not edit.\n");
+    for (name, value) in CONSTANTS {
+        writeln!(out, "pub const {name}: u64 = {value:#x};").unwrap();
+    }
+    for syscall in SYSCALLS {
+        let upper = syscall.name.to_uppercase();
+        writeln!(out, "pub const SYS_{upper}: u64 = {};", syscall.nr).unwrap();
+    }
+    for syscall in SYSCALLS {
+        let name = if KEYWORDS.contains(&syscall.name) {
+            format!("r#{}", syscall.name)
+        } else {
+            syscall.name.to_string()
+        };
+        let params: Vec<String> = syscall
+            .args
+            .iter()
+            .map(|arg| format!("{}: {}", arg.name, arg.kind.rust_type()))
+            .collect();
+        let mut args: Vec<String> = syscall
+            .args
+            .iter()
+            .map(|arg| match arg.kind.rust_type() {
+                "u64" => arg.name.to_string(),
+                _ => format!("{} as u64", arg.name),
+            })
+            .collect();
+        args.resize(6, "0".into());
+        out.push('\n');
+        out.push_str(&doc_lines(syscall, "/// "));
+        out.push_str("///\n/// # Safety\n///\n/// See `syscall`.\n#[inline]\n");
+        writeln!(
+            out,
+            "pub unsafe fn {name}({}) -> isize {{\n    \
+             syscall(SYS_{}, [{}])\n}}",
+            params.join(", "),
+            syscall.name.to_uppercase(),
+            args.join(", ")
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn c_header() -> String {
+    let mut out = String::from(
+        "/* Generated from src/syscall_table.rs, do not edit. */\n\
+         #ifndef SKYOS_SYSCALLS_H\n\
+         #define SKYOS_SYSCALLS_H\n\n\
+         #include <stddef.h>\n\n",
+    );
+    for (name, value) in CONSTANTS {
+        writeln!(out, "#define {name} {value:#x}").unwrap();
+    }
+    out.push('\n');
+    for syscall in SYSCALLS {
+        let upper = syscall.name.to_uppercase();
+        writeln!(out, "#define SYS_{upper} {}", syscall.nr).unwrap();
+    }
+    out.push_str(
+        "\nstatic inline long skyos_syscall(long nr, long a0, long a1, long a2, long a3, long a4,\n\
+         \x20                                long a5)\n\
+         {\n\
+         \x20   register long r10 __asm__(\"r10\") = a3;\n\
+         \x20   register long r8 __asm__(\"r8\") = a4;\n\
+         \x20   register long r9 __asm__(\"r9\") = a5;\n\
+         \x20   __asm__ volatile(\"int $0x80\"\n\
+         \x20                    : \"+a\"(nr)\n\
+         \x20                    : \"D\"(a0), \"S\"(a1), \"d\"(a2), \"r\"(r10), \"r\"(r8), \"r\"(r9)\n\
+         \x20                    : \"memory\");\n\
+         \x20   return nr;\n\
+         }\n",
+    );
+    for syscall in SYSCALLS {
+        let params: Vec<String> = syscall
+            .args
+            .iter()
+            .map(|arg| format!("{} {}", arg.kind.c_type(), arg.name))
+            .collect();
+        let params = if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ").replace("* ", "*")
+        };
+        let mut args: Vec<String> = syscall
+            .args
+            .iter()
+            .map(|arg| format!("(long){}", arg.name))
+            .collect();
+        args.resize(6, "0".into());
+        out.push_str("\n/*\n");
+        out.push_str(&doc_lines(syscall, " * "));
+        out.push_str(" */\n");
+        writeln!(
+            out,
+            "static inline long sys_{}({params})\n{{\n    \
+             return skyos_syscall(SYS_{}, {});\n}}",
+            syscall.name,
+            syscall.name.to_uppercase(),
+            args.join(", ")
+        )
+        .unwrap();
+    }
+    out.push_str("\n#endif\n");
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={TABLE}");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("syscalls.rs"), rust_bindings()).unwrap();
+
+    let include = out_dir.join("include");
+    fs::create_dir_all(include.join("skyos")).unwrap();
+    fs::write(include.join("skyos/syscalls.h"), c_header()).unwrap();
+    println!("cargo:include={}", include.display());
+}
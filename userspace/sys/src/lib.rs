@@ -0,0 +1,34 @@
+//! Raw system calls of skyos.
+//!
+//! The constants and one wrapper per system call are generated from the kernel's
+//! `src/syscall_table.rs` by the build script, which also writes the same as a C header to
+//! `$DEP_SKYOS_INCLUDE/skyos/syscalls.h`. The wrappers return what the kernel does, the result
+//! or a negated error number.
+#![no_std]
+
+use core::arch::asm;
+
+include!(concat!(env!("OUT_DIR"), "/syscalls.rs"));
+
+/// Makes system call `nr` with `args`, see the kernel's `syscall_table` for the convention.
+///
+/// # Safety
+///
+/// The arguments must be valid for the system call, pointers must point to memory of the size
+/// it expects.
+#[inline]
+pub unsafe fn syscall(nr: u64, args: [u64; 6]) -> isize {
+    let result: u64;
+    asm!(
+        "int 0x80",
+        inlateout("rax") nr => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        options(nostack),
+    );
+    result as isize
+}